@@ -1,6 +1,9 @@
 #![allow(unsafe_code)]
 
-use diode::aux::{self, file};
+use diode::{
+    aux::{self, file},
+    durable,
+};
 use std::{
     ffi::{c_char, CStr},
     net::SocketAddr,
@@ -26,6 +29,23 @@ pub unsafe extern "C" fn diode_new_config(
         diode: aux::DiodeSend::Tcp(socket_addr),
         buffer_size: buffer_size as usize,
         hash: false,
+        hash_algorithm: file::hash::Algorithm::Murmur3,
+        resume: false,
+        recursive: false,
+        require_fips_hash: false,
+        quarantine_dir: None,
+        min_free_bytes: 0,
+        min_free_inodes: 0,
+        output_owner: None,
+        output_file_mode: None,
+        overwrite: file::Overwrite::Fail,
+        preserve_owner: false,
+        xattrs: false,
+        manifest_key: None,
+        landlock: false,
+        audit_log: None,
+        audit_sync: durable::Policy::Always,
+        output_template: None,
     });
     Box::into_raw(config)
 }
@@ -82,6 +102,23 @@ pub unsafe extern "C" fn diode_receive_files(
         },
         buffer_size: config.buffer_size,
         hash: false,
+        hash_algorithm: file::hash::Algorithm::Murmur3,
+        resume: false,
+        recursive: false,
+        require_fips_hash: false,
+        quarantine_dir: None,
+        min_free_bytes: 0,
+        min_free_inodes: 0,
+        output_owner: None,
+        output_file_mode: None,
+        overwrite: file::Overwrite::Fail,
+        preserve_owner: false,
+        xattrs: false,
+        manifest_key: None,
+        landlock: false,
+        audit_log: None,
+        audit_sync: durable::Policy::Always,
+        output_template: None,
     };
 
     if ptr_odir.is_null() {
@@ -0,0 +1,218 @@
+//! Append-only log of the transfers that crossed the diode, kept as evidence for security audits
+//!
+//! Each transfer is recorded once it ended, as a single line JSON object containing the
+//! `version` of the record format, the `start` and `end` timestamps of the transfer (seconds
+//! since UNIX epoch), the number of `bytes` delivered and the `outcome` of the transfer,
+//! `success`, `failed` or `aborted`. Depending on the receiver, it also contains the `session`
+//! identifier and the `site` the transfer came from, the `file` received, the `hash_algorithm`
+//! and `hash` of the data delivered, when hashing is enabled, and the `error` which made the
+//! transfer fail.
+//!
+//! Records are appended to the file by a [durable::DurableWriter], each JSON line being framed
+//! by its length and checksum so that a record torn by a crash is detected and truncated when the
+//! log is opened again, and are synchronized to disk according to a [durable::Policy].
+
+use crate::{aux::file::hash, durable, events, metrics};
+use std::{
+    fmt::{self, Write as _},
+    fs, io,
+    os::unix::fs::OpenOptionsExt,
+    path, time,
+};
+
+/// Version of the record format, to be incremented on incompatible changes
+pub const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Outcome {
+    Success,
+    Failed,
+    Aborted,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Success => write!(fmt, "success"),
+            Self::Failed => write!(fmt, "failed"),
+            Self::Aborted => write!(fmt, "aborted"),
+        }
+    }
+}
+
+/// Record of a transfer, filled while it goes on
+///
+/// A record is [Outcome::Failed] until the transfer is known to have succeeded or been aborted.
+pub(crate) struct Record {
+    pub(crate) start: time::SystemTime,
+    pub(crate) session: Option<String>,
+    pub(crate) site: Option<String>,
+    pub(crate) file: Option<String>,
+    pub(crate) bytes: u64,
+    pub(crate) hash: Option<(hash::Algorithm, Vec<u8>)>,
+    pub(crate) outcome: Outcome,
+    pub(crate) error: Option<String>,
+}
+
+impl Record {
+    /// Starts the record of a transfer starting now
+    pub(crate) fn new() -> Self {
+        Self {
+            start: time::SystemTime::now(),
+            session: None,
+            site: None,
+            file: None,
+            bytes: 0,
+            hash: None,
+            outcome: Outcome::Failed,
+            error: None,
+        }
+    }
+
+    fn to_json(&self, end: time::SystemTime) -> String {
+        let timestamp = |at: time::SystemTime| {
+            at.duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        };
+
+        let mut json = format!("{{\"version\":{VERSION}");
+        // writing into a String cannot fail
+        if let Some(session) = &self.session {
+            let _ = write!(json, ",\"session\":{}", events::json_string(session));
+        }
+        if let Some(site) = &self.site {
+            let _ = write!(json, ",\"site\":{}", events::json_string(site));
+        }
+        if let Some(file) = &self.file {
+            let _ = write!(json, ",\"file\":{}", events::json_string(file));
+        }
+        let _ = write!(
+            json,
+            ",\"start\":{:.6},\"end\":{:.6},\"bytes\":{}",
+            timestamp(self.start),
+            timestamp(end),
+            self.bytes
+        );
+        if let Some((algorithm, digest)) = &self.hash {
+            let _ = write!(
+                json,
+                ",\"hash_algorithm\":\"{algorithm}\",\"hash\":\"{}\"",
+                hex(digest)
+            );
+        }
+        let _ = write!(json, ",\"outcome\":\"{}\"", self.outcome);
+        if let Some(error) = &self.error {
+            let _ = write!(json, ",\"error\":{}", events::json_string(error));
+        }
+        json.push('}');
+        json
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) struct AuditLog {
+    writer: durable::DurableWriter,
+}
+
+impl AuditLog {
+    /// Opens the file at `path` to append records to it, creating it readable by its owner only
+    /// if needed, records being synchronized to disk according to `policy`
+    ///
+    /// A file holding no valid record, such as an audit log of a previous version made of bare
+    /// JSON lines, is refused rather than truncated.
+    pub(crate) fn open(path: &path::Path, policy: durable::Policy) -> Result<Self, io::Error> {
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        let replay = durable::replay(path)?;
+        if replay.torn && replay.valid_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "\"{}\" is not an audit log, or was written by a previous version",
+                    path.display()
+                ),
+            ));
+        }
+        let writer = durable::DurableWriter::open(path, policy)?;
+        log::info!(
+            "recording transfers in audit log \"{}\", synchronized with policy {policy}",
+            path.display()
+        );
+        Ok(Self { writer })
+    }
+
+    /// Appends the record of a transfer ending now, returning once it is on disk if the policy
+    /// requires it
+    pub(crate) fn write(&self, record: &Record) {
+        let mut line = record.to_json(time::SystemTime::now());
+        line.push('\n');
+        if let Err(e) = self.writer.append(line.as_bytes()) {
+            metrics::counter("rx_audit_records_failed").inc();
+            log::error!("failed to write audit record: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn record(session: &str) -> Record {
+        Record {
+            session: Some(session.to_string()),
+            bytes: 42,
+            outcome: Outcome::Success,
+            ..Record::new()
+        }
+    }
+
+    #[test]
+    fn records_reopened() {
+        let dir = TempDir::new("audit-records");
+        for policy in [
+            durable::Policy::None,
+            durable::Policy::Records(2),
+            durable::Policy::Always,
+        ] {
+            let path = dir.path().join(policy.to_string());
+            for session in ["1", "2"] {
+                let audit = AuditLog::open(&path, policy).expect("open audit log");
+                audit.write(&record(session));
+            }
+
+            let mode = fs::metadata(&path).expect("metadata").permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            let records = durable::replay(&path).expect("replay").records;
+            assert_eq!(records.len(), 2);
+            for (record, session) in records.iter().zip(["1", "2"]) {
+                let record = std::str::from_utf8(record).expect("JSON record");
+                assert!(
+                    record.starts_with("{\"version\":1,\"session\":"),
+                    "{record}"
+                );
+                assert!(record.contains(&format!("\"session\":\"{session}\"")));
+                assert!(record.ends_with(",\"bytes\":42,\"outcome\":\"success\"}\n"));
+            }
+        }
+    }
+
+    #[test]
+    fn previous_format_refused() {
+        let dir = TempDir::new("audit-previous");
+        let path = dir.path().join("audit.log");
+        let line = "{\"version\":1,\"bytes\":0,\"outcome\":\"failed\"}\n";
+        fs::write(&path, line).expect("write audit log");
+
+        assert!(AuditLog::open(&path, durable::Policy::Always).is_err());
+        assert_eq!(fs::read_to_string(&path).expect("read audit log"), line);
+    }
+}
@@ -0,0 +1,645 @@
+//! Hash algorithms used to check the integrity of transferred files
+//!
+//! The algorithm is chosen by the sender and identified by a byte in both the header and the
+//! footer of the file protocol. Adding an algorithm only requires a new [Algorithm] variant and
+//! the corresponding [Hasher] variant.
+//!
+//! SHA-256 is computed by ring when built with the `ring` feature, by RustCrypto's sha2 when
+//! built with the `sha2` feature, and by a built-in implementation otherwise, see
+//! [SHA256_BACKEND]. [Algorithm::is_fips_approved_algorithm] only tells whether an algorithm is
+//! approved: none of these implementations is a FIPS 140 validated module.
+
+use fasthash::HasherExt;
+use std::{fmt, hash::Hash, str::FromStr};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// No hash is computed, the footer digest is empty
+    None,
+    /// 128 bits MurmurHash3, fast but not cryptographic
+    Murmur3,
+    /// SHA-256 (FIPS 180-4)
+    Sha256,
+    /// BLAKE3, cryptographic and faster than SHA-256 but not FIPS approved
+    Blake3,
+}
+
+impl Algorithm {
+    pub const fn id(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Murmur3 => 1,
+            Self::Sha256 => 2,
+            Self::Blake3 => 3,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::None),
+            1 => Some(Self::Murmur3),
+            2 => Some(Self::Sha256),
+            3 => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Length in bytes of the digests produced by the algorithm
+    pub const fn digest_len(self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Murmur3 => 16,
+            Self::Sha256 | Self::Blake3 => 32,
+        }
+    }
+
+    /// Returns `true` if the algorithm is approved by FIPS 140, whatever the module computing it
+    pub const fn is_fips_approved_algorithm(self) -> bool {
+        matches!(self, Self::Sha256)
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::None => write!(fmt, "none"),
+            Self::Murmur3 => write!(fmt, "murmur3"),
+            Self::Sha256 => write!(fmt, "sha256"),
+            Self::Blake3 => write!(fmt, "blake3"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "murmur3" => Ok(Self::Murmur3),
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(format!("unknown hash algorithm \"{s}\"")),
+        }
+    }
+}
+
+/// Name of the external implementation computing SHA-256, `None` when the built-in one is used
+pub const SHA256_BACKEND: Option<&str> = if cfg!(feature = "ring") {
+    Some("ring")
+} else if cfg!(feature = "sha2") {
+    Some("sha2")
+} else {
+    None
+};
+
+pub enum Hasher {
+    None,
+    Murmur3(fasthash::Murmur3HasherExt),
+    Sha256(Box<Sha256>),
+    Blake3(Box<Blake3>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::None => Self::None,
+            Algorithm::Murmur3 => Self::Murmur3(fasthash::Murmur3HasherExt::default()),
+            Algorithm::Sha256 => Self::Sha256(Box::new(Sha256::new())),
+            Algorithm::Blake3 => Self::Blake3(Box::new(Blake3::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::None => (),
+            // hashing the slice, including its length, as done before algorithm selection
+            Self::Murmur3(hasher) => data.hash(hasher),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::None => Vec::new(),
+            Self::Murmur3(hasher) => hasher.finish_ext().to_le_bytes().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+#[cfg(not(any(feature = "ring", feature = "sha2")))]
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Streaming SHA-256, see FIPS 180-4, computed by the implementation selected at build time
+pub struct Sha256 {
+    #[cfg(feature = "ring")]
+    context: ring::digest::Context,
+    #[cfg(all(feature = "sha2", not(feature = "ring")))]
+    hasher: sha2::Sha256,
+    #[cfg(not(any(feature = "ring", feature = "sha2")))]
+    builtin: BuiltinSha256,
+}
+
+impl Sha256 {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "ring")]
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+            #[cfg(all(feature = "sha2", not(feature = "ring")))]
+            hasher: <sha2::Sha256 as sha2::Digest>::new(),
+            #[cfg(not(any(feature = "ring", feature = "sha2")))]
+            builtin: BuiltinSha256::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        #[cfg(feature = "ring")]
+        self.context.update(data);
+        #[cfg(all(feature = "sha2", not(feature = "ring")))]
+        sha2::Digest::update(&mut self.hasher, data);
+        #[cfg(not(any(feature = "ring", feature = "sha2")))]
+        self.builtin.update(data);
+    }
+
+    pub(crate) fn finalize(self) -> [u8; 32] {
+        #[cfg(feature = "ring")]
+        let digest = self
+            .context
+            .finish()
+            .as_ref()
+            .try_into()
+            .expect("32 bytes digest");
+        #[cfg(all(feature = "sha2", not(feature = "ring")))]
+        let digest = sha2::Digest::finalize(self.hasher)
+            .as_slice()
+            .try_into()
+            .expect("32 bytes digest");
+        #[cfg(not(any(feature = "ring", feature = "sha2")))]
+        let digest = self.builtin.finalize();
+        digest
+    }
+}
+
+/// Built-in streaming SHA-256 implementation, used when no external one is selected
+#[cfg(not(any(feature = "ring", feature = "sha2")))]
+struct BuiltinSha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+#[cfg(not(any(feature = "ring", feature = "sha2")))]
+impl BuiltinSha256 {
+    const fn new() -> Self {
+        Self {
+            state: SHA256_H,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        if 0 < self.block_len {
+            let n = data.len().min(64 - self.block_len);
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len < 64 {
+                return;
+            }
+            let block = self.block;
+            self.compress(&block);
+            self.block_len = 0;
+        }
+
+        let mut chunks = data.chunks_exact(64);
+        for chunk in &mut chunks {
+            self.compress(chunk.try_into().expect("64 bytes chunk"));
+        }
+
+        let remainder = chunks.remainder();
+        self.block[..remainder.len()].copy_from_slice(remainder);
+        self.block_len = remainder.len();
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        let bit_len = self.total_len.wrapping_mul(8);
+
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        let padding_len = if self.block_len < 56 {
+            56 - self.block_len
+        } else {
+            120 - self.block_len
+        };
+        self.update(&padding[..padding_len]);
+        self.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().expect("4 bytes word"));
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+const BLAKE3_BLOCK_LEN: usize = 64;
+const BLAKE3_CHUNK_LEN: usize = 1024;
+const BLAKE3_CHUNK_START: u32 = 1;
+const BLAKE3_CHUNK_END: u32 = 2;
+const BLAKE3_PARENT: u32 = 4;
+const BLAKE3_ROOT: u32 = 8;
+const BLAKE3_MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+fn blake3_g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn blake3_compress(
+    chaining_value: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        chaining_value[0],
+        chaining_value[1],
+        chaining_value[2],
+        chaining_value[3],
+        chaining_value[4],
+        chaining_value[5],
+        chaining_value[6],
+        chaining_value[7],
+        SHA256_H[0],
+        SHA256_H[1],
+        SHA256_H[2],
+        SHA256_H[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut m = *block;
+    for round in 0..7 {
+        blake3_g(&mut state, 0, 4, 8, 12, m[0], m[1]);
+        blake3_g(&mut state, 1, 5, 9, 13, m[2], m[3]);
+        blake3_g(&mut state, 2, 6, 10, 14, m[4], m[5]);
+        blake3_g(&mut state, 3, 7, 11, 15, m[6], m[7]);
+        blake3_g(&mut state, 0, 5, 10, 15, m[8], m[9]);
+        blake3_g(&mut state, 1, 6, 11, 12, m[10], m[11]);
+        blake3_g(&mut state, 2, 7, 8, 13, m[12], m[13]);
+        blake3_g(&mut state, 3, 4, 9, 14, m[14], m[15]);
+        if round < 6 {
+            m = BLAKE3_MSG_PERMUTATION.map(|i| m[i]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn blake3_words(block: &[u8; BLAKE3_BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().expect("4 bytes word"));
+    }
+    words
+}
+
+fn blake3_first_8(words: [u32; 16]) -> [u32; 8] {
+    let mut first = [0; 8];
+    first.copy_from_slice(&words[..8]);
+    first
+}
+
+/// Last compression of a node of the tree, kept to be either chained to its parent or, for the
+/// root node, turned into the digest
+struct Blake3Output {
+    chaining_value: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Blake3Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        blake3_first_8(blake3_compress(
+            &self.chaining_value,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn parent(left: [u32; 8], right: [u32; 8]) -> Self {
+        let mut block = [0; 16];
+        block[..8].copy_from_slice(&left);
+        block[8..].copy_from_slice(&right);
+        Self {
+            chaining_value: SHA256_H,
+            block,
+            counter: 0,
+            block_len: BLAKE3_BLOCK_LEN as u32,
+            flags: BLAKE3_PARENT,
+        }
+    }
+}
+
+/// Streaming BLAKE3 implementation in its default hash mode with a 32 bytes output, see the
+/// BLAKE3 specification
+///
+/// The input is split into chunks of 1024 bytes, whose chaining values are merged in a binary
+/// tree as soon as both children of a node are known, the stack holding the roots of the
+/// complete subtrees.
+pub struct Blake3 {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLAKE3_BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+    stack: Vec<[u32; 8]>,
+}
+
+impl Blake3 {
+    pub(crate) const fn new() -> Self {
+        Self {
+            chaining_value: SHA256_H,
+            chunk_counter: 0,
+            block: [0; BLAKE3_BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    fn chunk_len(&self) -> usize {
+        BLAKE3_BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            BLAKE3_CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn chunk_output(&self) -> Blake3Output {
+        Blake3Output {
+            chaining_value: self.chaining_value,
+            block: blake3_words(&self.block),
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | BLAKE3_CHUNK_END,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.chunk_len() == BLAKE3_CHUNK_LEN {
+                // the chunk is complete, merging the subtrees it completes
+                let mut chaining_value = self.chunk_output().chaining_value();
+                let mut total_chunks = self.chunk_counter + 1;
+                while total_chunks & 1 == 0 {
+                    let left = self.stack.pop().expect("complete subtree");
+                    chaining_value = Blake3Output::parent(left, chaining_value).chaining_value();
+                    total_chunks >>= 1;
+                }
+                self.stack.push(chaining_value);
+
+                self.chaining_value = SHA256_H;
+                self.chunk_counter += 1;
+                self.block = [0; BLAKE3_BLOCK_LEN];
+                self.block_len = 0;
+                self.blocks_compressed = 0;
+            }
+
+            if self.block_len == BLAKE3_BLOCK_LEN {
+                // the block is only compressed once more input follows, the last block of a
+                // chunk being flagged
+                self.chaining_value = blake3_first_8(blake3_compress(
+                    &self.chaining_value,
+                    &blake3_words(&self.block),
+                    self.chunk_counter,
+                    BLAKE3_BLOCK_LEN as u32,
+                    self.start_flag(),
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; BLAKE3_BLOCK_LEN];
+                self.block_len = 0;
+            }
+
+            let n = data.len().min(BLAKE3_BLOCK_LEN - self.block_len);
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+        }
+    }
+
+    pub(crate) fn finalize(self) -> [u8; 32] {
+        let mut output = self.chunk_output();
+        for left in self.stack.iter().rev() {
+            output = Blake3Output::parent(*left, output.chaining_value());
+        }
+
+        let words = blake3_compress(
+            &output.chaining_value,
+            &output.block,
+            output.counter,
+            output.block_len,
+            output.flags | BLAKE3_ROOT,
+        );
+        let mut digest = [0u8; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Digest of `data` fed to `algorithm` in chunks of `chunk_len` bytes
+    fn digest(algorithm: Algorithm, data: &[u8], chunk_len: usize) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        for chunk in data.chunks(chunk_len) {
+            hasher.update(chunk);
+        }
+        hex(&hasher.finalize())
+    }
+
+    #[test]
+    fn sha256_fips_180_vectors() {
+        let vectors: [(&[u8], &str); 3] = [
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (data, expected) in vectors {
+            assert_eq!(digest(Algorithm::Sha256, data, usize::MAX), expected);
+        }
+
+        let million_a = vec![b'a'; 1_000_000];
+        for chunk_len in [1, 63, 64, 65, 4096] {
+            assert_eq!(
+                digest(Algorithm::Sha256, &million_a, chunk_len),
+                "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+            );
+        }
+    }
+
+    #[test]
+    fn blake3_official_vectors() {
+        // hash mode of test_vectors.json from the BLAKE3 reference repository, input byte i
+        // being i % 251, covering an empty input, a partial block, a full chunk, a chunk tree
+        // and a tree with incomplete subtrees
+        let vectors = [
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                31744,
+                "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47",
+            ),
+        ];
+        for (len, expected) in vectors {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            for chunk_len in [1, 63, 64, 65, 1024, usize::MAX] {
+                assert_eq!(
+                    digest(Algorithm::Blake3, &data, chunk_len),
+                    expected,
+                    "{len} bytes fed by {chunk_len}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn algorithm_identifiers() {
+        for algorithm in [
+            Algorithm::None,
+            Algorithm::Murmur3,
+            Algorithm::Sha256,
+            Algorithm::Blake3,
+        ] {
+            assert!(Algorithm::from_id(algorithm.id()) == Some(algorithm));
+            assert!(algorithm.to_string().parse::<Algorithm>() == Ok(algorithm));
+            assert_eq!(
+                Hasher::new(algorithm).finalize().len(),
+                algorithm.digest_len()
+            );
+        }
+        assert!(Algorithm::from_id(4).is_none());
+    }
+
+    #[test]
+    fn fips_approval() {
+        assert!(Algorithm::Sha256.is_fips_approved_algorithm());
+        for algorithm in [Algorithm::None, Algorithm::Murmur3, Algorithm::Blake3] {
+            assert!(!algorithm.is_fips_approved_algorithm());
+        }
+    }
+}
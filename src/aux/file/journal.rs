@@ -0,0 +1,137 @@
+//! Progress of file transfers, persisted to resume them after an interruption
+//!
+//! With `--resume`, `diode-send-file` records in a journal, stored next to each file it sends
+//! (`.<file name>.diode-send-file.journal`), how much of the file was written to `diode-send`.
+//! The journal is made of [durable] records: the first one identifies the file by its length
+//! and modification time, each following one gives the offset reached and the number of chunks
+//! written so far. When the transfer is started again, the bytes before the last recorded offset
+//! are not sent again, as long as the file did not change. The journal is removed once the file
+//! was entirely sent.
+//!
+//! Records are not synchronized to disk for each chunk: after a crash, the journal may be behind
+//! the actual progress, which only makes the resumed transfer start earlier than needed.
+
+use crate::durable;
+use std::{fs, io, path, time};
+
+const JOURNAL_SUFFIX: &str = ".diode-send-file.journal";
+
+const IDENTITY_TAG: u8 = 0;
+const PROGRESS_TAG: u8 = 1;
+
+/// Interval at which the progress records are synchronized to disk
+const SYNC_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// How much of a file was written to `diode-send`
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub offset: u64,
+    pub nb_chunks: u64,
+}
+
+impl Progress {
+    fn serialize(&self) -> Vec<u8> {
+        let mut record = vec![PROGRESS_TAG];
+        record.extend_from_slice(&self.offset.to_le_bytes());
+        record.extend_from_slice(&self.nb_chunks.to_le_bytes());
+        record
+    }
+
+    fn deserialize(record: &[u8]) -> Option<Self> {
+        let (&tag, rest) = record.split_first()?;
+        if tag != PROGRESS_TAG || rest.len() != 16 {
+            return None;
+        }
+        let (offset, nb_chunks) = rest.split_at(8);
+        Some(Self {
+            offset: u64::from_le_bytes(offset.try_into().ok()?),
+            nb_chunks: u64::from_le_bytes(nb_chunks.try_into().ok()?),
+        })
+    }
+}
+
+/// Identity record of the file described by `metadata`
+fn identity(metadata: &fs::Metadata) -> Result<Vec<u8>, io::Error> {
+    let modified = metadata
+        .modified()?
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut record = vec![IDENTITY_TAG];
+    record.extend_from_slice(&metadata.len().to_le_bytes());
+    record.extend_from_slice(&modified.as_secs().to_le_bytes());
+    record.extend_from_slice(&modified.subsec_nanos().to_le_bytes());
+    Ok(record)
+}
+
+pub struct Journal {
+    path: path::PathBuf,
+    writer: durable::DurableWriter,
+}
+
+impl Journal {
+    /// Path of the journal of the file at `file_path`
+    pub fn path_of(file_path: &path::Path) -> Option<path::PathBuf> {
+        let file_name = file_path.file_name()?.to_str()?;
+        Some(file_path.with_file_name(format!(".{file_name}{JOURNAL_SUFFIX}")))
+    }
+
+    /// Whether the file at `file_path` is a journal
+    pub fn is_journal(file_path: &path::Path) -> bool {
+        file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.') && name.ends_with(JOURNAL_SUFFIX))
+    }
+
+    /// Opens the journal of the file at `file_path`, described by `metadata`, returning the
+    /// progress of the interrupted transfer to resume, if any
+    ///
+    /// A journal left by a transfer of another version of the file is discarded.
+    pub fn open(
+        file_path: &path::Path,
+        metadata: &fs::Metadata,
+    ) -> Result<(Self, Option<Progress>), io::Error> {
+        let path = Self::path_of(file_path).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid file name for journal")
+        })?;
+        let identity = identity(metadata)?;
+
+        let (known, progress) = match durable::replay(&path) {
+            Ok(replay) if replay.records.first() == Some(&identity) => (
+                true,
+                replay.records[1..]
+                    .iter()
+                    .rev()
+                    .find_map(|record| Progress::deserialize(record)),
+            ),
+            Ok(_) => {
+                log::warn!(
+                    "discarding journal \"{}\" of another version of the file",
+                    path.display()
+                );
+                fs::remove_file(&path)?;
+                (false, None)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (false, None),
+            Err(e) => return Err(e),
+        };
+
+        let writer = durable::DurableWriter::open(&path, durable::Policy::Interval(SYNC_INTERVAL))?;
+        if !known {
+            writer.append(&identity)?;
+            writer.sync()?;
+        }
+
+        Ok((Self { path, writer }, progress))
+    }
+
+    /// Records that the file was written to `diode-send` up to `progress`
+    pub fn record(&self, progress: Progress) -> Result<(), io::Error> {
+        self.writer.append(&progress.serialize())
+    }
+
+    /// Removes the journal once the file was entirely sent
+    pub fn remove(self) -> Result<(), io::Error> {
+        fs::remove_file(&self.path)
+    }
+}
@@ -0,0 +1,519 @@
+//! Manifests of the files sent in a batch, to reconcile both sides of the diode
+//!
+//! With `--manifest`, once all the files given on its command line were sent, `diode-send-file`
+//! sends a manifest listing the name, length and digest of each of them. It is sent as a regular
+//! file, flagged as a manifest in its header, so that `diode-receive-file` stores it and then
+//! checks each entry against the files of its output directory, reporting those which are
+//! missing or whose content differs. Receivers which do not know about manifests just store it.
+//!
+//! A manifest is a text file:
+//!
+//! ```text
+//! lidi-manifest 1
+//! batch 1718000000-4242
+//! hash sha256
+//! <hex digest> <length> <file name>
+//! ...
+//! hmac-sha256 <hex digest>
+//! ```
+//!
+//! The last line is only present when a key is given: it authenticates all the previous lines
+//! with HMAC-SHA256, so that a receiver sharing the key detects forged or altered manifests.
+
+use crate::aux::file::{self, hash};
+use hmac::{KeyInit, Mac};
+use std::{fmt, fs, io, path, process, thread, time};
+
+const MAGIC: &str = "lidi-manifest 1";
+const SIGNATURE_PREFIX: &str = "hmac-sha256 ";
+
+/// Number of attempts to find the files of a manifest, the last files of a batch possibly being
+/// still stored when the manifest is received
+const NB_LOOKUPS: usize = 10;
+const LOOKUP_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+pub enum Error {
+    Io(io::Error),
+    Syntax(usize, &'static str),
+    /// The file name cannot be listed in a manifest
+    InvalidName(String),
+    /// The manifest is not signed but a key is configured
+    Unsigned,
+    InvalidSignature,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::Syntax(line, reason) => write!(fmt, "manifest, line {line}: {reason}"),
+            Self::InvalidName(name) => {
+                write!(
+                    fmt,
+                    "file name \"{}\" cannot be listed in a manifest",
+                    name.escape_debug()
+                )
+            }
+            Self::Unsigned => write!(fmt, "manifest is not signed"),
+            Self::InvalidSignature => write!(fmt, "invalid manifest signature"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub struct Entry {
+    pub file_name: String,
+    pub file_length: u64,
+    pub hash: Vec<u8>,
+}
+
+pub struct Manifest {
+    pub batch: String,
+    pub hash_algorithm: hash::Algorithm,
+    pub entries: Vec<Entry>,
+}
+
+/// HMAC-SHA256 of `body` with `key`
+fn mac(key: &[u8], body: &str) -> hmac::Hmac<sha2::Sha256> {
+    let mut mac =
+        hmac::Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Manifest {
+    /// Starts the manifest of a new batch, identified by the current time and process
+    pub fn new(hash_algorithm: hash::Algorithm) -> Self {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            batch: format!("{}-{}", now.as_secs(), process::id()),
+            hash_algorithm,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, file_name: String, file_length: u64, hash: Vec<u8>) {
+        self.entries.push(Entry {
+            file_name,
+            file_length,
+            hash,
+        });
+    }
+
+    /// Name under which the manifest is stored by the receiver
+    pub fn file_name(&self) -> String {
+        format!("manifest-{}.txt", self.batch)
+    }
+
+    /// Returns the content of the manifest, signed with `key` if given
+    pub fn serialize(&self, key: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let mut content = format!(
+            "{MAGIC}\nbatch {}\nhash {}\n",
+            self.batch, self.hash_algorithm
+        );
+        for entry in &self.entries {
+            if entry.file_name.contains(['\n', '\r']) {
+                return Err(Error::InvalidName(entry.file_name.clone()));
+            }
+            content.push_str(&format!(
+                "{} {} {}\n",
+                hex(&entry.hash),
+                entry.file_length,
+                entry.file_name
+            ));
+        }
+        if let Some(key) = key {
+            let signature = mac(key, &content).finalize().into_bytes();
+            content.push_str(&format!("{SIGNATURE_PREFIX}{}\n", hex(&signature)));
+        }
+        Ok(content.into_bytes())
+    }
+
+    /// Parses `content`, checking its signature if `key` is given
+    ///
+    /// Returns the manifest along with whether it was signed.
+    pub fn parse(content: &[u8], key: Option<&[u8]>) -> Result<(Self, bool), Error> {
+        let content = std::str::from_utf8(content).map_err(|_| Error::Syntax(0, "not UTF-8"))?;
+
+        // the signature covers every line before it, newlines included
+        let (body, signature) = match content.trim_end_matches('\n').rsplit_once('\n') {
+            Some((body, last)) if last.starts_with(SIGNATURE_PREFIX) => (
+                &content[..body.len() + 1],
+                Some(&last[SIGNATURE_PREFIX.len()..]),
+            ),
+            _ => (content, None),
+        };
+        match (key, signature) {
+            (Some(_), None) => return Err(Error::Unsigned),
+            (Some(key), Some(signature)) => {
+                // compared in constant time, not to tell how much of a forged signature is right
+                let signature = parse_hex(signature).ok_or(Error::InvalidSignature)?;
+                mac(key, body)
+                    .verify_slice(&signature)
+                    .map_err(|_| Error::InvalidSignature)?;
+            }
+            (None, _) => (),
+        }
+
+        let mut lines = body.lines().enumerate().map(|(i, line)| (i + 1, line));
+        if lines.next().map(|(_, line)| line) != Some(MAGIC) {
+            return Err(Error::Syntax(1, "not a manifest"));
+        }
+        let batch = match lines.next() {
+            Some((_, line)) => line.strip_prefix("batch "),
+            None => None,
+        }
+        .ok_or(Error::Syntax(2, "expected batch identifier"))?
+        .to_string();
+        let hash_algorithm = match lines.next() {
+            Some((_, line)) => line.strip_prefix("hash ").and_then(|a| a.parse().ok()),
+            None => None,
+        }
+        .ok_or(Error::Syntax(3, "expected hash algorithm"))?;
+
+        let mut entries = Vec::new();
+        for (line_number, line) in lines {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(hash), Some(file_length), Some(file_name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(Error::Syntax(
+                    line_number,
+                    "expected digest, length and name",
+                ));
+            };
+            entries.push(Entry {
+                file_name: file_name.to_string(),
+                file_length: file_length
+                    .parse()
+                    .map_err(|_| Error::Syntax(line_number, "invalid length"))?,
+                hash: parse_hex(hash).ok_or(Error::Syntax(line_number, "invalid digest"))?,
+            });
+        }
+
+        Ok((
+            Self {
+                batch,
+                hash_algorithm,
+                entries,
+            },
+            signature.is_some(),
+        ))
+    }
+}
+
+/// State of a file listed in a manifest
+enum Check {
+    Ok,
+    Missing,
+    /// The length or the digest of the file differs from the manifest
+    Corrupt(String),
+}
+
+/// Hashes the file at `file_path` as the sender did, by chunks of `buffer_size` bytes
+fn hash_file(
+    file_path: &path::Path,
+    algorithm: hash::Algorithm,
+    buffer_size: usize,
+) -> Result<Vec<u8>, io::Error> {
+    let mut file = fs::File::open(file_path)?;
+    let mut hasher = hash::Hasher::new(algorithm);
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let mut len = 0;
+        while len < buffer.len() {
+            match io::Read::read(&mut file, &mut buffer[len..])? {
+                0 => break,
+                nread => len += nread,
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer[..len]);
+        if len < buffer.len() {
+            break;
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+fn check_entry(
+    output_dir: &path::Path,
+    entry: &Entry,
+    algorithm: hash::Algorithm,
+    buffer_size: usize,
+) -> Result<Check, io::Error> {
+    let file_path = output_dir.join(&entry.file_name);
+    let metadata = match fs::metadata(&file_path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Check::Missing),
+        Err(e) => return Err(e),
+    };
+    if metadata.len() != entry.file_length {
+        return Ok(Check::Corrupt(format!(
+            "{} bytes instead of {}",
+            metadata.len(),
+            entry.file_length
+        )));
+    }
+    if algorithm != hash::Algorithm::None {
+        let hash = hash_file(&file_path, algorithm, buffer_size)?;
+        if hash != entry.hash {
+            return Ok(Check::Corrupt(format!(
+                "{algorithm} digest {} instead of {}",
+                hex(&hash),
+                hex(&entry.hash)
+            )));
+        }
+    }
+    Ok(Check::Ok)
+}
+
+/// Checks the files listed in the manifest stored at `manifest_path` against the files of
+/// `output_dir`, logging the missing and corrupt ones
+pub(crate) fn verify<D>(
+    config: &file::Config<D>,
+    output_dir: &path::Path,
+    manifest_path: &path::Path,
+) -> Result<(), Error> {
+    let content = fs::read(manifest_path)?;
+    let (manifest, signed) = Manifest::parse(&content, config.manifest_key.as_deref())?;
+    if signed && config.manifest_key.is_none() {
+        log::warn!(
+            "manifest of batch {}: signature not checked, no key configured",
+            manifest.batch
+        );
+    }
+
+    let mut pending: Vec<&Entry> = Vec::new();
+    for entry in &manifest.entries {
+        // as when receiving files, only paths below the output directory are looked up
+        let relative = path::Path::new(&entry.file_name)
+            .components()
+            .all(|component| matches!(component, path::Component::Normal(_)));
+        if !relative || entry.file_name.is_empty() {
+            log::error!(
+                "manifest of batch {}: invalid file name \"{}\"",
+                manifest.batch,
+                entry.file_name
+            );
+            continue;
+        }
+        pending.push(entry);
+    }
+
+    let mut nb_ok = 0;
+    let mut nb_corrupt = 0;
+    for attempt in 1..=NB_LOOKUPS {
+        let mut missing = Vec::new();
+        for entry in pending {
+            match check_entry(
+                output_dir,
+                entry,
+                manifest.hash_algorithm,
+                config.buffer_size,
+            )? {
+                Check::Ok => nb_ok += 1,
+                Check::Missing => missing.push(entry),
+                Check::Corrupt(reason) => {
+                    nb_corrupt += 1;
+                    log::error!(
+                        "manifest of batch {}: \"{}\" is corrupt, {reason}",
+                        manifest.batch,
+                        entry.file_name
+                    );
+                }
+            }
+        }
+        pending = missing;
+        if pending.is_empty() || attempt == NB_LOOKUPS {
+            break;
+        }
+        thread::sleep(LOOKUP_INTERVAL);
+    }
+
+    for entry in &pending {
+        log::error!(
+            "manifest of batch {}: \"{}\" is missing",
+            manifest.batch,
+            entry.file_name
+        );
+    }
+
+    let nb_missing = pending.len();
+    let nb_invalid = manifest.entries.len() - nb_ok - nb_corrupt - nb_missing;
+    crate::metrics::counter("rx_manifests_verified").inc();
+    crate::metrics::counter("rx_manifest_files_missing").add(nb_missing as u64);
+    crate::metrics::counter("rx_manifest_files_corrupt").add((nb_corrupt + nb_invalid) as u64);
+
+    let summary = format!(
+        "manifest of batch {}: {nb_ok} of {} file(s) verified, {nb_missing} missing, {} corrupt",
+        manifest.batch,
+        manifest.entries.len(),
+        nb_corrupt + nb_invalid
+    );
+    if nb_ok == manifest.entries.len() {
+        log::info!("{summary}");
+    } else {
+        log::error!("{summary}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, Error, Manifest};
+
+    const KEY: &[u8] = b"manifest key";
+
+    fn manifest() -> Manifest {
+        let mut manifest = Manifest::new(hash::Algorithm::Sha256);
+        manifest.add("report.pdf".to_string(), 1234, vec![0xab; 32]);
+        manifest.add("dir/name with spaces.txt".to_string(), 0, vec![0x01; 32]);
+        manifest
+    }
+
+    fn assert_same(parsed: &Manifest, expected: &Manifest) {
+        assert_eq!(parsed.batch, expected.batch);
+        assert!(parsed.hash_algorithm == expected.hash_algorithm);
+        assert_eq!(parsed.entries.len(), expected.entries.len());
+        for (parsed, expected) in parsed.entries.iter().zip(&expected.entries) {
+            assert_eq!(parsed.file_name, expected.file_name);
+            assert_eq!(parsed.file_length, expected.file_length);
+            assert_eq!(parsed.hash, expected.hash);
+        }
+    }
+
+    fn parse(content: &[u8], key: Option<&[u8]>) -> Result<(Manifest, bool), Error> {
+        Manifest::parse(content, key)
+    }
+
+    #[test]
+    fn round_trip() {
+        let manifest = manifest();
+
+        let unsigned = manifest.serialize(None).unwrap_or_else(|e| panic!("{e}"));
+        let (parsed, signed) = parse(&unsigned, None).unwrap_or_else(|e| panic!("{e}"));
+        assert_same(&parsed, &manifest);
+        assert!(!signed);
+
+        let content = manifest
+            .serialize(Some(KEY))
+            .unwrap_or_else(|e| panic!("{e}"));
+        let (parsed, signed) = parse(&content, Some(KEY)).unwrap_or_else(|e| panic!("{e}"));
+        assert_same(&parsed, &manifest);
+        assert!(signed);
+        // without a key, the signature is reported but not checked
+        let (parsed, signed) = parse(&content, None).unwrap_or_else(|e| panic!("{e}"));
+        assert_same(&parsed, &manifest);
+        assert!(signed);
+
+        let empty = Manifest::new(hash::Algorithm::None);
+        let content = empty.serialize(Some(KEY)).unwrap_or_else(|e| panic!("{e}"));
+        let (parsed, _) = parse(&content, Some(KEY)).unwrap_or_else(|e| panic!("{e}"));
+        assert_same(&parsed, &empty);
+    }
+
+    #[test]
+    fn tampered() {
+        let content = String::from_utf8(
+            manifest()
+                .serialize(Some(KEY))
+                .unwrap_or_else(|e| panic!("{e}")),
+        )
+        .expect("UTF-8");
+
+        let body = content.replace(" 1234 ", " 1235 ");
+        assert_ne!(body, content);
+        assert!(matches!(
+            parse(body.as_bytes(), Some(KEY)),
+            Err(Error::InvalidSignature)
+        ));
+        let renamed = content.replace("report.pdf", "report.exe");
+        assert!(matches!(
+            parse(renamed.as_bytes(), Some(KEY)),
+            Err(Error::InvalidSignature)
+        ));
+        let added = content.replacen('\n', "\nextra\n", 3);
+        assert!(matches!(
+            parse(added.as_bytes(), Some(KEY)),
+            Err(Error::InvalidSignature)
+        ));
+
+        // each digit of the signature matters
+        let (body, signature) = content.trim_end().rsplit_once(' ').expect("signature line");
+        for i in 0..signature.len() {
+            let mut forged = signature.as_bytes().to_vec();
+            forged[i] = if forged[i] == b'0' { b'1' } else { b'0' };
+            let forged = format!("{body} {}\n", String::from_utf8(forged).expect("hex"));
+            assert!(
+                matches!(
+                    parse(forged.as_bytes(), Some(KEY)),
+                    Err(Error::InvalidSignature)
+                ),
+                "digit {i}"
+            );
+        }
+        for signature in ["", "zz", &signature[..62], &format!("{signature}00")] {
+            let forged = format!("{body} {signature}\n");
+            assert!(
+                matches!(
+                    parse(forged.as_bytes(), Some(KEY)),
+                    Err(Error::InvalidSignature)
+                ),
+                "{signature:?}"
+            );
+        }
+
+        assert!(matches!(
+            parse(content.as_bytes(), Some(b"other key")),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn unsigned() {
+        let content = manifest().serialize(None).unwrap_or_else(|e| panic!("{e}"));
+        assert!(matches!(parse(&content, Some(KEY)), Err(Error::Unsigned)));
+        // a signature line not at the end does not sign the manifest
+        let mut content = manifest()
+            .serialize(Some(KEY))
+            .unwrap_or_else(|e| panic!("{e}"));
+        content.extend_from_slice(b"00 1 appended.txt\n");
+        assert!(matches!(parse(&content, Some(KEY)), Err(Error::Unsigned)));
+    }
+
+    #[test]
+    fn invalid_names() {
+        for name in ["two\nlines.txt", "carriage\rreturn.txt", "\n"] {
+            let mut manifest = manifest();
+            manifest.add(name.to_string(), 1, vec![0; 32]);
+            assert!(matches!(
+                manifest.serialize(Some(KEY)),
+                Err(Error::InvalidName(invalid)) if invalid == name
+            ));
+        }
+    }
+}
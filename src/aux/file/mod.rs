@@ -1,19 +1,156 @@
 //! Module for sending/receiving entire files into/from Lidi TCP or Unix sockets
+pub mod hash;
+pub mod journal;
+pub mod manifest;
+pub mod pending;
 pub mod protocol;
 pub mod receive;
 pub mod send;
+pub mod watch;
 
-use std::{fmt, io};
+use crate::{durable, fs_utils};
+use std::{fmt, io, path, str::FromStr};
+
+/// Namespace of the extended attributes sent with the files
+pub(crate) const XATTR_PREFIX: &[u8] = b"user.";
 
 pub struct Config<D> {
     pub diode: D,
     pub buffer_size: usize,
     pub hash: bool,
+    /// Sender side: algorithm used to hash file content when `hash` is set
+    pub hash_algorithm: hash::Algorithm,
+    /// Sender side: journal the progress of each transfer and resume interrupted ones
+    pub resume: bool,
+    /// Sender side: send the files of the directories given, recreating their tree on the
+    /// receiver side
+    pub recursive: bool,
+    /// Receiver side: reject files not hashed with a FIPS approved algorithm
+    pub require_fips_hash: bool,
+    /// Receiver side: directory where rejected files are stored instead of being discarded
+    pub quarantine_dir: Option<path::PathBuf>,
+    /// Receiver side: warn when the output filesystem has less free space than this value
+    pub min_free_bytes: u64,
+    /// Receiver side: warn when the output filesystem has less free inodes than this value
+    pub min_free_inodes: u64,
+    /// Receiver side: owner given to received files instead of the user running the receiver
+    pub output_owner: Option<Owner>,
+    /// Receiver side: mode given to received files instead of the mode sent with them
+    pub output_file_mode: Option<u32>,
+    /// Receiver side: what to do when a received file already exists
+    pub overwrite: Overwrite,
+    /// Receiver side: give received files the owner sent with them, unless `output_owner` is set
+    pub preserve_owner: bool,
+    /// Send the extended attributes of the user namespace with the files, and apply them to
+    /// received files
+    pub xattrs: bool,
+    /// Key used to sign the manifests of batches, and to authenticate received ones
+    pub manifest_key: Option<Vec<u8>>,
+    /// Receiver side: restrict filesystem writes to the output directory, and the quarantine
+    /// directory if any, with Landlock once listening sockets are bound. The restriction applies
+    /// to the thread calling `receive_files` until it exits.
+    pub landlock: bool,
+    /// Receiver side: file where to append a record of every file received, see
+    /// [crate::audit]
+    pub audit_log: Option<path::PathBuf>,
+    /// Receiver side: when records of the audit log are synchronized to disk
+    pub audit_sync: durable::Policy,
+    /// Receiver side: directory below the output directory where files are stored, `{site}`
+    /// being replaced by the site identifier of the sender and `{date}` by the local date, see
+    /// [receive::check_output_template]. Each connection must then start with the session header
+    /// written by the receiver of the diode, see [crate::receive::header].
+    pub output_template: Option<String>,
+}
+
+/// Policy applied when a received file already exists in the output directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overwrite {
+    /// The received file is discarded
+    Fail,
+    /// The existing file is replaced
+    Overwrite,
+    /// The received file is stored under the first free name made of its name and a numeric
+    /// suffix
+    Rename,
+}
+
+impl fmt::Display for Overwrite {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Fail => write!(fmt, "fail"),
+            Self::Overwrite => write!(fmt, "overwrite"),
+            Self::Rename => write!(fmt, "rename"),
+        }
+    }
+}
+
+impl FromStr for Overwrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "overwrite" => Ok(Self::Overwrite),
+            "rename" => Ok(Self::Rename),
+            _ => Err(format!("unknown overwrite policy \"{s}\"")),
+        }
+    }
+}
+
+/// Owner and group of a file, `None` keeping the current one
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        if let Some(uid) = self.uid {
+            write!(fmt, "{uid}")?;
+        }
+        if let Some(gid) = self.gid {
+            write!(fmt, ":{gid}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Owner {
+    type Err = String;
+
+    /// Parses `user`, `user:group` or `:group`, users and groups being given by name or by
+    /// numeric identifier
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = s.split_once(':').unwrap_or((s, ""));
+        let uid = match user {
+            "" => None,
+            user => Some(
+                user.parse()
+                    .or_else(|_| fs_utils::user_id(user))
+                    .map_err(|e| e.to_string())?,
+            ),
+        };
+        let gid = match group {
+            "" => None,
+            group => Some(
+                group
+                    .parse()
+                    .or_else(|_| fs_utils::group_id(group))
+                    .map_err(|e| e.to_string())?,
+            ),
+        };
+        if uid.is_none() && gid.is_none() {
+            return Err(format!("invalid owner \"{s}\", expected user:group"));
+        }
+        Ok(Self { uid, gid })
+    }
 }
 
 pub enum Error {
     Io(io::Error),
     Diode(protocol::Error),
+    Manifest(manifest::Error),
     Other(String),
 }
 
@@ -22,6 +159,7 @@ impl fmt::Display for Error {
         match self {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::Diode(e) => write!(fmt, "diode error: {e}"),
+            Self::Manifest(e) => write!(fmt, "manifest error: {e}"),
             Self::Other(e) => write!(fmt, "error: {e}"),
         }
     }
@@ -38,3 +176,45 @@ impl From<protocol::Error> for Error {
         Self::Diode(e)
     }
 }
+
+impl From<manifest::Error> for Error {
+    fn from(e: manifest::Error) -> Self {
+        Self::Manifest(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Owner;
+
+    #[test]
+    fn owner_parsing() {
+        let owner = |uid, gid| Ok(Owner { uid, gid });
+        assert_eq!("root:root".parse(), owner(Some(0), Some(0)));
+        assert_eq!("65534:65534".parse(), owner(Some(65534), Some(65534)));
+        assert_eq!("root".parse(), owner(Some(0), None));
+        assert_eq!(":root".parse(), owner(None, Some(0)));
+        assert_eq!("1000:root".parse(), owner(Some(1000), Some(0)));
+
+        assert_eq!(
+            "".parse::<Owner>(),
+            Err("invalid owner \"\", expected user:group".to_string())
+        );
+        assert!(":".parse::<Owner>().is_err());
+        assert_eq!(
+            "lidi-no-such-user:root".parse::<Owner>(),
+            Err("unknown user \"lidi-no-such-user\"".to_string())
+        );
+        assert_eq!(
+            "root:lidi-no-such-group".parse::<Owner>(),
+            Err("unknown group \"lidi-no-such-group\"".to_string())
+        );
+
+        for owner in ["0:0", "0", ":0"] {
+            assert_eq!(
+                owner.parse::<Owner>().map(|owner| owner.to_string()),
+                Ok(owner.to_string())
+            );
+        }
+    }
+}
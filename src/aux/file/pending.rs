@@ -0,0 +1,328 @@
+//! Sent files kept in a pending directory during a grace period before being removed
+//!
+//! Once handed to `diode-send`, the data of a file may still be lost, for instance if the
+//! gateway restarts before it was sent on the UDP link. Sent files are therefore moved to a
+//! pending directory, where they remain available to be sent again, and are only deleted (or
+//! moved to a sent directory) once the grace period has elapsed.
+//!
+//! The time each file was sent is recorded in a ledger stored in the pending directory, so that
+//! the grace period is honored across restarts. The ledger is a text file with one line per
+//! pending file, made of the time the file was sent (in seconds since the Unix epoch) and its
+//! name separated by a space. It is written before files are moved, so that a crash can leave an
+//! entry without its file, which is then ignored, but never a pending file without its entry.
+
+use crate::aux::file;
+use std::{
+    fs,
+    io::{self, Write},
+    path, time,
+};
+
+const LEDGER_FILE_NAME: &str = ".diode-send-file.ledger";
+
+struct Entry {
+    sent_at: u64,
+    file_name: String,
+}
+
+pub struct Pending {
+    dir: path::PathBuf,
+    sent_dir: Option<path::PathBuf>,
+    grace: time::Duration,
+    entries: Vec<Entry>,
+}
+
+impl Pending {
+    /// Loads the ledger of the pending directory `dir`, expired files being deleted or, if
+    /// `sent_dir` is given, moved to it
+    pub fn open(
+        dir: &path::Path,
+        sent_dir: Option<&path::Path>,
+        grace: time::Duration,
+    ) -> Result<Self, file::Error> {
+        for dir in [Some(dir), sent_dir].into_iter().flatten() {
+            if !dir.is_dir() {
+                return Err(file::Error::Other(format!(
+                    "'{}' is not a directory",
+                    dir.display()
+                )));
+            }
+        }
+
+        let entries = match fs::read_to_string(dir.join(LEDGER_FILE_NAME)) {
+            Ok(ledger) => ledger
+                .lines()
+                .filter_map(|line| {
+                    let entry = line.split_once(' ').and_then(|(sent_at, file_name)| {
+                        Some(Entry {
+                            sent_at: sent_at.parse().ok()?,
+                            file_name: file_name.to_string(),
+                        })
+                    });
+                    if entry.is_none() {
+                        log::warn!("ignoring invalid ledger line \"{line}\"");
+                    }
+                    entry
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            sent_dir: sent_dir.map(path::Path::to_path_buf),
+            grace,
+            entries,
+        })
+    }
+
+    /// Moves the sent file at `file_path` to the pending directory, recording the time it was sent
+    pub fn add(&mut self, file_path: &path::Path) -> Result<(), file::Error> {
+        let file_name = file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.contains('\n'))
+            .ok_or_else(|| {
+                file::Error::Other(format!(
+                    "cannot record '{}' in the ledger",
+                    file_path.display()
+                ))
+            })?
+            .to_string();
+
+        let pending_path = self.dir.join(&file_name);
+        if self
+            .entries
+            .iter()
+            .any(|entry| entry.file_name == file_name)
+            || pending_path.exists()
+        {
+            return Err(file::Error::Other(format!(
+                "'{}' is already pending, keeping '{}'",
+                pending_path.display(),
+                file_path.display()
+            )));
+        }
+
+        self.entries.push(Entry {
+            sent_at: now(),
+            file_name,
+        });
+        self.save()?;
+
+        if let Err(e) = fs::rename(file_path, &pending_path) {
+            self.entries.pop();
+            self.save()?;
+            return Err(file::Error::Other(format!(
+                "cannot move '{}' to '{}' (the pending directory must be on the same filesystem): {e}",
+                file_path.display(),
+                pending_path.display()
+            )));
+        }
+
+        log::info!(
+            "'{}' pending for {} seconds",
+            pending_path.display(),
+            self.grace.as_secs()
+        );
+        Ok(())
+    }
+
+    /// Deletes or moves the files whose grace period has elapsed, returning how many were removed
+    /// from the pending directory
+    pub fn expire(&mut self) -> Result<usize, file::Error> {
+        let now = now();
+        let grace = self.grace.as_secs();
+        let (expired, entries) = self
+            .entries
+            .drain(..)
+            .partition::<Vec<_>, _>(|entry| entry.sent_at.saturating_add(grace) <= now);
+        self.entries = entries;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut nb_removed = 0;
+        for entry in &expired {
+            let pending_path = self.dir.join(&entry.file_name);
+            let res = match &self.sent_dir {
+                Some(sent_dir) => fs::rename(&pending_path, sent_dir.join(&entry.file_name)),
+                None if pending_path.is_dir() => fs::remove_dir_all(&pending_path),
+                None => fs::remove_file(&pending_path),
+            };
+            match res {
+                Ok(()) => {
+                    nb_removed += 1;
+                    log::debug!("'{}' grace period elapsed", pending_path.display());
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    log::warn!(
+                        "'{}' vanished from pending directory",
+                        pending_path.display()
+                    );
+                }
+                Err(e) => {
+                    log::error!("failed to remove '{}': {e}", pending_path.display());
+                    self.entries.push(Entry {
+                        sent_at: entry.sent_at,
+                        file_name: entry.file_name.clone(),
+                    });
+                }
+            }
+        }
+
+        self.save()?;
+        Ok(nb_removed)
+    }
+
+    /// Number of files still in their grace period
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Writes the ledger to a temporary file synchronized to disk and renamed over the previous
+    /// ledger, so that it is never seen partially written
+    fn save(&self) -> Result<(), io::Error> {
+        let ledger_path = self.dir.join(LEDGER_FILE_NAME);
+        let tmp_path = self.dir.join(format!("{LEDGER_FILE_NAME}.tmp"));
+
+        let mut ledger = fs::File::create(&tmp_path)?;
+        for entry in &self.entries {
+            writeln!(ledger, "{} {}", entry.sent_at, entry.file_name)?;
+        }
+        ledger.sync_all()?;
+        fs::rename(&tmp_path, &ledger_path)?;
+        fs::File::open(&self.dir)?.sync_all()
+    }
+}
+
+fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pending, LEDGER_FILE_NAME};
+    use crate::testing::TempDir;
+    use std::{fs, path, time};
+
+    const GRACE: time::Duration = time::Duration::from_secs(3600);
+
+    fn open(dir: &TempDir, sent_dir: Option<&path::Path>) -> Pending {
+        Pending::open(dir.path(), sent_dir, GRACE).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Makes the files of the ledger of `dir` sent `elapsed` ago
+    fn age_ledger(dir: &TempDir, elapsed: time::Duration) {
+        let ledger_path = dir.path().join(LEDGER_FILE_NAME);
+        let ledger = fs::read_to_string(&ledger_path).expect("read ledger");
+        let aged: String = ledger
+            .lines()
+            .map(|line| {
+                let (sent_at, file_name) = line.split_once(' ').expect("ledger line");
+                let sent_at: u64 = sent_at.parse().expect("send time");
+                format!("{} {file_name}\n", sent_at - elapsed.as_secs())
+            })
+            .collect();
+        fs::write(ledger_path, aged).expect("write ledger");
+    }
+
+    #[test]
+    fn restart_within_grace() {
+        let input = TempDir::new("pending-input");
+        let pending_dir = TempDir::new("pending");
+        let file_path = input.path().join("sent");
+        fs::write(&file_path, b"content").expect("write file");
+
+        let mut pending = open(&pending_dir, None);
+        pending.add(&file_path).unwrap_or_else(|e| panic!("{e}"));
+        assert!(!file_path.exists());
+        assert_eq!(pending.len(), 1);
+        drop(pending);
+
+        // as if diode-send-file was run again before the grace period elapsed: the file is
+        // still available to be sent again
+        age_ledger(&pending_dir, GRACE - time::Duration::from_secs(60));
+        let mut pending = open(&pending_dir, None);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.expire().ok(), Some(0));
+        assert_eq!(
+            fs::read(pending_dir.path().join("sent")).expect("pending file"),
+            b"content"
+        );
+        drop(pending);
+
+        age_ledger(&pending_dir, time::Duration::from_secs(60));
+        let mut pending = open(&pending_dir, None);
+        assert_eq!(pending.expire().ok(), Some(1));
+        assert!(pending.is_empty());
+        assert!(!pending_dir.path().join("sent").exists());
+        assert!(open(&pending_dir, None).is_empty());
+    }
+
+    #[test]
+    fn expired_moved_to_sent() {
+        let input = TempDir::new("pending-input");
+        let pending_dir = TempDir::new("pending");
+        let sent_dir = TempDir::new("pending-sent");
+        let mut pending = open(&pending_dir, Some(sent_dir.path()));
+        for name in ["old", "recent"] {
+            fs::write(input.path().join(name), name).expect("write file");
+            pending
+                .add(&input.path().join(name))
+                .unwrap_or_else(|e| panic!("{e}"));
+            if name == "old" {
+                age_ledger(&pending_dir, GRACE);
+                pending = open(&pending_dir, Some(sent_dir.path()));
+            }
+        }
+
+        assert_eq!(pending.expire().ok(), Some(1));
+        assert_eq!(
+            fs::read(sent_dir.path().join("old")).expect("sent file"),
+            b"old"
+        );
+        assert!(pending_dir.path().join("recent").exists());
+        assert_eq!(open(&pending_dir, Some(sent_dir.path())).len(), 1);
+    }
+
+    #[test]
+    fn ledger_consistency() {
+        let input = TempDir::new("pending-input");
+        let pending_dir = TempDir::new("pending");
+        let file_path = input.path().join("file");
+        fs::write(&file_path, b"first").expect("write file");
+        let mut pending = open(&pending_dir, None);
+        pending.add(&file_path).unwrap_or_else(|e| panic!("{e}"));
+
+        // a file of the same name is kept in place while the first one is pending
+        fs::write(&file_path, b"second").expect("write file");
+        assert!(pending.add(&file_path).is_err());
+        assert_eq!(fs::read(&file_path).expect("kept file"), b"second");
+        assert_eq!(pending.len(), 1);
+
+        // a file that cannot be moved is not recorded
+        assert!(pending.add(&input.path().join("missing")).is_err());
+        assert_eq!(open(&pending_dir, None).len(), 1);
+
+        // entries left without their file by a crash and invalid lines are ignored
+        let ledger_path = pending_dir.path().join(LEDGER_FILE_NAME);
+        let mut ledger = fs::read_to_string(&ledger_path).expect("read ledger");
+        ledger.push_str("0 vanished\ninvalid\n");
+        fs::write(&ledger_path, ledger).expect("write ledger");
+        let mut pending = open(&pending_dir, None);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.expire().ok(), Some(0));
+        assert_eq!(pending.len(), 1);
+
+        assert!(Pending::open(&input.path().join("missing"), None, GRACE).is_err());
+        assert!(Pending::open(pending_dir.path(), Some(&file_path), GRACE).is_err());
+    }
+}
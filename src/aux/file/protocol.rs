@@ -1,14 +1,25 @@
+use crate::{aux::file::hash, fs_utils};
 use std::{
     fmt, io,
     io::{Read, Write},
     string::FromUtf8Error,
+    time,
 };
 
 pub enum Error {
     Io(io::Error),
     StringFormatError(FromUtf8Error),
     InvalidFileSize(usize, usize),
-    InvalidHash(u128, u128),
+    InvalidHash(Vec<u8>, Vec<u8>),
+    UnknownHashAlgorithm(u8),
+    HashAlgorithmMismatch(hash::Algorithm, hash::Algorithm),
+    /// The header has a metadata field which must be understood but is not
+    UnsupportedField(u8),
+    InvalidField(u8),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 
 impl fmt::Display for Error {
@@ -17,7 +28,15 @@ impl fmt::Display for Error {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::StringFormatError(e) => write!(fmt, "string format error: {e}"),
             Self::InvalidFileSize(s1, s2) => write!(fmt, "invalid file size: {s1} != {s2}"),
-            Self::InvalidHash(h1, h2) => write!(fmt, "invalid hash: {h1:x} != {h2:x}"),
+            Self::InvalidHash(h1, h2) => write!(fmt, "invalid hash: {} != {}", hex(h1), hex(h2)),
+            Self::UnknownHashAlgorithm(id) => write!(fmt, "unknown hash algorithm {id}"),
+            Self::HashAlgorithmMismatch(a1, a2) => {
+                write!(fmt, "hash algorithm mismatch: {a1} != {a2}")
+            }
+            Self::UnsupportedField(field) => {
+                write!(fmt, "unsupported mandatory header field {field:#04x}")
+            }
+            Self::InvalidField(field) => write!(fmt, "invalid header field {field:#04x}"),
         }
     }
 }
@@ -34,10 +53,51 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+/// Optional metadata fields follow the fixed part of the header, each one made of a type byte,
+/// a 4-bytes length and its value. Receivers ignore the fields of unknown types, unless
+/// [FIELD_MANDATORY] is set in their type, in which case the file is refused.
+const FIELD_MANDATORY: u8 = 0x80;
+/// Modification time: seconds (8 bytes) and nanoseconds (4 bytes) since the Unix epoch
+const FIELD_MODIFIED: u8 = 1;
+/// Owner: user (4 bytes) and group (4 bytes) identifiers
+const FIELD_OWNER: u8 = 2;
+/// Extended attribute: name length (2 bytes), name and value
+const FIELD_XATTR: u8 = 3;
+/// Manifest of the files previously sent in the batch, see [crate::aux::file::manifest]; empty
+const FIELD_MANIFEST: u8 = 4;
+
+/// Largest total size of the metadata fields of a header
+const MAX_FIELDS_LEN: usize = 1 << 20;
+
 pub(crate) struct Header {
+    /// Path of the file relative to the output directory, its components separated by `/`
     pub(crate) file_name: String,
     pub(crate) mode: u32,
     pub(crate) file_length: u64,
+    /// Offset in the file of the first byte sent, the previous ones having been sent by an
+    /// interrupted transfer
+    pub(crate) offset: u64,
+    pub(crate) hash_algorithm: hash::Algorithm,
+    /// Modification time of the file on the sending side
+    pub(crate) modified: Option<time::SystemTime>,
+    /// User and group identifiers of the owner of the file on the sending side
+    pub(crate) owner: Option<(u32, u32)>,
+    /// Extended attributes of the file
+    pub(crate) xattrs: Vec<fs_utils::Xattr>,
+    /// Whether the file is the manifest of a batch
+    pub(crate) manifest: bool,
+}
+
+fn read_hash_algorithm<R: Read>(r: &mut R) -> Result<hash::Algorithm, Error> {
+    let mut id = [0u8; 1];
+    r.read_exact(&mut id)?;
+    hash::Algorithm::from_id(id[0]).ok_or(Error::UnknownHashAlgorithm(id[0]))
+}
+
+fn write_field(fields: &mut Vec<u8>, field: u8, value: &[u8]) {
+    fields.push(field);
+    fields.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    fields.extend_from_slice(value);
 }
 
 impl Header {
@@ -46,6 +106,40 @@ impl Header {
         w.write_all(self.file_name.as_bytes())?;
         w.write_all(&self.mode.to_le_bytes())?;
         w.write_all(&self.file_length.to_le_bytes())?;
+        w.write_all(&self.offset.to_le_bytes())?;
+        w.write_all(&[self.hash_algorithm.id()])?;
+
+        let mut fields = Vec::new();
+        if let Some(modified) = self.modified {
+            let modified = modified
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let mut value = modified.as_secs().to_le_bytes().to_vec();
+            value.extend_from_slice(&modified.subsec_nanos().to_le_bytes());
+            write_field(&mut fields, FIELD_MODIFIED, &value);
+        }
+        if let Some((uid, gid)) = self.owner {
+            let mut value = uid.to_le_bytes().to_vec();
+            value.extend_from_slice(&gid.to_le_bytes());
+            write_field(&mut fields, FIELD_OWNER, &value);
+        }
+        for (name, xattr) in &self.xattrs {
+            let mut value = (name.len() as u16).to_le_bytes().to_vec();
+            value.extend_from_slice(name);
+            value.extend_from_slice(xattr);
+            write_field(&mut fields, FIELD_XATTR, &value);
+        }
+        if self.manifest {
+            write_field(&mut fields, FIELD_MANIFEST, &[]);
+        }
+        if MAX_FIELDS_LEN < fields.len() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file metadata too large",
+            )));
+        }
+        w.write_all(&(fields.len() as u32).to_le_bytes())?;
+        w.write_all(&fields)?;
         Ok(())
     }
 
@@ -66,29 +160,107 @@ impl Header {
         r.read_exact(&mut file_length)?;
         let file_length = u64::from_le_bytes(file_length);
 
-        Ok(Self {
+        let mut offset = [0u8; 8];
+        r.read_exact(&mut offset)?;
+        let offset = u64::from_le_bytes(offset);
+
+        let hash_algorithm = read_hash_algorithm(r)?;
+
+        let mut header = Self {
             file_name,
             mode,
             file_length,
-        })
+            offset,
+            hash_algorithm,
+            modified: None,
+            owner: None,
+            xattrs: Vec::new(),
+            manifest: false,
+        };
+
+        let mut fields_len = [0u8; 4];
+        r.read_exact(&mut fields_len)?;
+        let fields_len = u32::from_le_bytes(fields_len) as usize;
+        if MAX_FIELDS_LEN < fields_len {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file metadata too large",
+            )));
+        }
+        let mut fields = vec![0; fields_len];
+        r.read_exact(&mut fields)?;
+
+        let mut fields = fields.as_slice();
+        while let Some((&field, rest)) = fields.split_first() {
+            let (len, rest) = rest
+                .split_first_chunk::<4>()
+                .ok_or(Error::InvalidField(field))?;
+            let len = u32::from_le_bytes(*len) as usize;
+            if rest.len() < len {
+                return Err(Error::InvalidField(field));
+            }
+            let (value, rest) = rest.split_at(len);
+            fields = rest;
+            header.read_field(field, value)?;
+        }
+
+        Ok(header)
+    }
+
+    fn read_field(&mut self, field: u8, value: &[u8]) -> Result<(), Error> {
+        let invalid = || Error::InvalidField(field);
+        match field & !FIELD_MANDATORY {
+            FIELD_MODIFIED => {
+                let (secs, nanos) = value.split_first_chunk::<8>().ok_or_else(invalid)?;
+                let nanos: [u8; 4] = nanos.try_into().map_err(|_| invalid())?;
+                self.modified = time::UNIX_EPOCH.checked_add(time::Duration::new(
+                    u64::from_le_bytes(*secs),
+                    u32::from_le_bytes(nanos).min(999_999_999),
+                ));
+            }
+            FIELD_OWNER => {
+                let (uid, gid) = value.split_first_chunk::<4>().ok_or_else(invalid)?;
+                let gid: [u8; 4] = gid.try_into().map_err(|_| invalid())?;
+                self.owner = Some((u32::from_le_bytes(*uid), u32::from_le_bytes(gid)));
+            }
+            FIELD_XATTR => {
+                let (name_len, rest) = value.split_first_chunk::<2>().ok_or_else(invalid)?;
+                let name_len = usize::from(u16::from_le_bytes(*name_len));
+                if rest.len() < name_len {
+                    return Err(invalid());
+                }
+                let (name, xattr) = rest.split_at(name_len);
+                self.xattrs.push((name.to_vec(), xattr.to_vec()));
+            }
+            FIELD_MANIFEST => self.manifest = true,
+            _ if field & FIELD_MANDATORY != 0 => return Err(Error::UnsupportedField(field)),
+            _ => log::debug!("ignoring unknown header field {field:#04x}"),
+        }
+        Ok(())
     }
 }
 
 pub(crate) struct Footer {
-    pub(crate) hash: u128,
+    pub(crate) hash_algorithm: hash::Algorithm,
+    pub(crate) hash: Vec<u8>,
 }
 
 impl Footer {
     pub fn serialize_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
-        w.write_all(&self.hash.to_le_bytes())?;
+        w.write_all(&[self.hash_algorithm.id()])?;
+        w.write_all(&self.hash)?;
         Ok(())
     }
 
     pub fn deserialize_from<R: Read>(r: &mut R) -> Result<Self, Error> {
-        let mut hash = [0u8; 16];
+        let hash_algorithm = read_hash_algorithm(r)?;
+
+        let mut hash = vec![0u8; hash_algorithm.digest_len()];
         r.read_exact(&mut hash)?;
-        let hash = u128::from_le_bytes(hash);
 
-        Ok(Self { hash })
+        Ok(Self {
+            hash_algorithm,
+            hash,
+        })
     }
 }
@@ -1,13 +1,17 @@
-use fasthash::HasherExt;
-
-use crate::aux::{self, file};
+use crate::{
+    audit,
+    aux::{self, file},
+    fs_utils, landlock, localtime, metrics, receive as diode_receive,
+};
 use std::{
-    fs,
-    hash::Hash,
-    io::{Read, Write},
+    ffi, fs,
+    io::{self, Read, Write},
     net,
-    os::unix::{self, fs::PermissionsExt},
-    path, thread,
+    os::unix::{
+        self,
+        fs::{OpenOptionsExt, PermissionsExt},
+    },
+    path, process, thread, time,
 };
 
 pub fn receive_files(
@@ -20,25 +24,54 @@ pub fn receive_files(
         ));
     }
 
-    thread::scope(|scope| -> Result<(), file::Error> {
-        if let Some(from_unix) = &config.diode.from_unix {
-            if from_unix.exists() {
-                return Err(file::Error::Other(format!(
-                    "Unix socket path '{}' already exists",
-                    from_unix.display()
-                )));
-            }
+    if let Some(owner) = &config.output_owner {
+        check_owner(output_dir, owner)?;
+    }
+
+    let unix_server = match &config.diode.from_unix {
+        Some(from_unix) if from_unix.exists() => {
+            return Err(file::Error::Other(format!(
+                "Unix socket path '{}' already exists",
+                from_unix.display()
+            )));
+        }
+        Some(from_unix) => Some(unix::net::UnixListener::bind(from_unix)?),
+        None => None,
+    };
+    let tcp_server = config
+        .diode
+        .from_tcp
+        .map(net::TcpListener::bind)
+        .transpose()?;
 
-            let server = unix::net::UnixListener::bind(from_unix)?;
+    // opened before restricting writes, which does not apply to files already open
+    let audit = config
+        .audit_log
+        .as_deref()
+        .map(|path| audit::AuditLog::open(path, config.audit_sync))
+        .transpose()?;
+    let audit = audit.as_ref();
+
+    // applied before spawning the threads receiving files, which inherit the restriction
+    if config.landlock {
+        let mut dirs = vec![output_dir];
+        dirs.extend(config.quarantine_dir.as_deref());
+        let abi = landlock::restrict_writes(&dirs).map_err(|e| {
+            file::Error::Other(format!("failed to restrict writes with Landlock: {e}"))
+        })?;
+        log::info!("writes restricted to the output directories with Landlock ABI version {abi}");
+    }
+
+    thread::scope(|scope| -> Result<(), file::Error> {
+        if let Some(server) = unix_server {
             thread::Builder::new().spawn_scoped(scope, || {
-                receive_unix_loop(config, output_dir, scope, server)
+                receive_unix_loop(config, audit, output_dir, scope, server)
             })?;
         }
 
-        if let Some(from_tcp) = &config.diode.from_tcp {
-            let server = net::TcpListener::bind(from_tcp)?;
+        if let Some(server) = tcp_server {
             thread::Builder::new().spawn_scoped(scope, || {
-                receive_tcp_loop(config, output_dir, scope, server)
+                receive_tcp_loop(config, audit, output_dir, scope, server)
             })?;
         }
 
@@ -48,6 +81,7 @@ pub fn receive_files(
 
 fn receive_tcp_loop<'a>(
     config: &'a file::Config<aux::DiodeReceive>,
+    audit: Option<&'a audit::AuditLog>,
     output_dir: &'a path::Path,
     scope: &'a thread::Scope<'a, '_>,
     server: net::TcpListener,
@@ -55,15 +89,13 @@ fn receive_tcp_loop<'a>(
     loop {
         let (client, client_addr) = server.accept()?;
         log::info!("new Unix client ({client_addr}) connected");
-        scope.spawn(|| match receive_file(config, client, output_dir) {
-            Ok(total) => log::info!("file received, {total} bytes received"),
-            Err(e) => log::error!("failed to receive file: {e}"),
-        });
+        scope.spawn(move || receive(config, audit, client, output_dir));
     }
 }
 
 fn receive_unix_loop<'a>(
     config: &'a file::Config<aux::DiodeReceive>,
+    audit: Option<&'a audit::AuditLog>,
     output_dir: &'a path::Path,
     scope: &'a thread::Scope<'a, '_>,
     server: unix::net::UnixListener,
@@ -76,56 +108,250 @@ fn receive_unix_loop<'a>(
                 .as_pathname()
                 .map_or("unknown".to_string(), |p| p.display().to_string())
         );
-        scope.spawn(|| match receive_file(config, client, output_dir) {
-            Ok(total) => log::info!("file received, {total} bytes received"),
-            Err(e) => log::error!("failed to receive file: {e}"),
-        });
+        scope.spawn(move || receive(config, audit, client, output_dir));
+    }
+}
+
+/// Receives a file from `client`, recording its transfer in the audit log if any
+fn receive<D>(
+    config: &file::Config<aux::DiodeReceive>,
+    audit: Option<&audit::AuditLog>,
+    client: D,
+    output_dir: &path::Path,
+) where
+    D: Read + Write,
+{
+    let mut record = audit::Record::new();
+    let res = match &config.output_template {
+        None => receive_file(config, client, output_dir, &mut record),
+        Some(template) => receive_templated(config, client, output_dir, template, &mut record),
+    };
+    match res {
+        Ok(received) => {
+            record.outcome = audit::Outcome::Success;
+            log_received(&received);
+        }
+        Err(e) => {
+            log::error!("failed to receive file: {e}");
+            record.error = Some(e.to_string());
+        }
+    }
+    if let Some(audit) = audit {
+        audit.write(&record);
+    }
+}
+
+/// Value of `{site}` in output templates for senders without site identifier
+const UNKNOWN_SITE: &str = "unknown";
+
+/// Checks that the output template `template` gives a relative path below the output directory,
+/// whatever the site identifier and the date it is expanded with
+pub fn check_output_template(template: &str) -> Result<(), String> {
+    let expanded = expand_template(template, "site", "1970-01-01");
+    if expanded.contains(['{', '}']) {
+        return Err(format!(
+            "output template \"{template}\" has an unknown placeholder, expected {{site}} or {{date}}"
+        ));
+    }
+    let expanded = path::Path::new(&expanded);
+    if expanded.as_os_str().is_empty()
+        || !expanded
+            .components()
+            .all(|component| matches!(component, path::Component::Normal(_)))
+    {
+        return Err(format!(
+            "output template \"{template}\" must be a relative path without '.' or '..' components"
+        ));
+    }
+    Ok(())
+}
+
+fn expand_template(template: &str, site_id: &str, date: &str) -> String {
+    template.replace("{site}", site_id).replace("{date}", date)
+}
+
+/// Receives a file from `client`, starting with the session header of the diode receiver, in
+/// the directory given by `template` below `output_dir`
+fn receive_templated<D>(
+    config: &file::Config<aux::DiodeReceive>,
+    mut client: D,
+    output_dir: &path::Path,
+    template: &str,
+    record: &mut audit::Record,
+) -> Result<Received, file::Error>
+where
+    D: Read + Write,
+{
+    let mut header = [0; diode_receive::header::SIZE];
+    client.read_exact(&mut header)?;
+    // site identifiers of valid headers are checked to be usable in paths
+    let header = diode_receive::header::Header::deserialize(&header)
+        .ok_or_else(|| file::Error::Other("invalid session header".to_string()))?;
+    record.site.clone_from(&header.site_id);
+
+    let site_dir = output_dir.join(expand_template(
+        template,
+        header.site_id.as_deref().unwrap_or(UNKNOWN_SITE),
+        &localtime::date(time::SystemTime::now()),
+    ));
+    create_dirs(config, output_dir, &site_dir)?;
+    receive_file(config, client, &site_dir, record)
+}
+
+/// Timings of the reception of a file
+///
+/// The transfer time runs from the first byte of the file received to the file being written
+/// and verified. The diode time is the part of it spent waiting for data, that is the transfer
+/// time minus the time spent writing and hashing.
+struct Received {
+    file_name: String,
+    bytes: usize,
+    first_byte: time::Instant,
+    done: time::Instant,
+    write_time: time::Duration,
+    hash_time: time::Duration,
+}
+
+impl Received {
+    fn transfer_time(&self) -> time::Duration {
+        self.done - self.first_byte
+    }
+
+    fn diode_time(&self) -> time::Duration {
+        self.transfer_time()
+            .saturating_sub(self.write_time + self.hash_time)
+    }
+
+    /// Effective throughput of the transfer, in bytes per second
+    fn rate(&self) -> f64 {
+        let seconds = self.transfer_time().as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 / seconds
     }
 }
 
+fn log_received(received: &Received) {
+    let files = metrics::counter("rx_files_received");
+    files.inc();
+    metrics::counter("rx_files_transfer_microseconds")
+        .add(received.transfer_time().as_micros() as u64);
+    metrics::counter("rx_files_diode_microseconds").add(received.diode_time().as_micros() as u64);
+    metrics::counter("rx_files_write_microseconds").add(received.write_time.as_micros() as u64);
+    metrics::counter("rx_files_hash_microseconds").add(received.hash_time.as_micros() as u64);
+    log::info!(
+        "file received: name=\"{}\" bytes={} transfer_ms={} diode_ms={} write_ms={} hash_ms={} rate_bps={:.0}",
+        received.file_name,
+        received.bytes,
+        received.transfer_time().as_millis(),
+        received.diode_time().as_millis(),
+        received.write_time.as_millis(),
+        received.hash_time.as_millis(),
+        received.rate()
+    );
+    log::info!(
+        "{} file(s), {} bytes written since startup",
+        files.get(),
+        metrics::counter("rx_files_bytes_written").get()
+    );
+}
+
 fn receive_file<D>(
     config: &file::Config<aux::DiodeReceive>,
     mut diode: D,
     output_dir: &path::Path,
-) -> Result<usize, file::Error>
+    record: &mut audit::Record,
+) -> Result<Received, file::Error>
 where
     D: Read + Write,
 {
     let header = file::protocol::Header::deserialize_from(&mut diode)?;
+    let first_byte = time::Instant::now();
+    record.file = Some(header.file_name.clone());
 
     log::debug!("receiving file \"{}\"", header.file_name);
     log::debug!("file size = {}", header.file_length);
 
-    let file_path = path::PathBuf::from(header.file_name);
-    let file_name = file_path
-        .file_name()
-        .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?;
-    let file_path = output_dir.join(path::PathBuf::from(file_name));
+    let file_name = relative_path(&header.file_name)?;
 
-    log::debug!("storing at \"{}\"", file_path.display());
+    // files rejected by the hash policy are still read to the end, to stay aligned with the
+    // stream, and stored in the quarantine directory if any
+    let rejected = config.require_fips_hash && !header.hash_algorithm.is_fips_approved_algorithm();
+    let output_dir = if rejected {
+        log::warn!(
+            "file \"{}\" is hashed with {} which is not FIPS approved, rejecting it",
+            header.file_name,
+            header.hash_algorithm
+        );
+        metrics::counter("rx_files_rejected_hash_policy").inc();
+        config.quarantine_dir.as_deref()
+    } else {
+        Some(output_dir)
+    };
 
-    if file_path.exists() {
+    if header.file_length < header.offset {
         return Err(file::Error::Other(format!(
-            "file \"{}\" already exists",
-            file_path.display()
+            "file \"{}\" resumed at offset {} beyond its length {}",
+            header.file_name, header.offset, header.file_length
         )));
     }
 
-    let mut file = fs::OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&file_path)?;
-
-    log::debug!("setting mode to {}", header.mode);
-    file.set_permissions(fs::Permissions::from_mode(header.mode))?;
+    let file_path = output_dir.map(|output_dir| output_dir.join(&file_name));
+    let mut file = match (output_dir, &file_path) {
+        (Some(output_dir), Some(file_path)) if 0 < header.offset => {
+            Some(resume_file(config, output_dir, file_path, &header)?)
+        }
+        (Some(output_dir), Some(file_path)) => {
+            Some(create_file(config, output_dir, file_path, &header)?)
+        }
+        _ => None,
+    };
 
     let mut buffer = vec![0; config.buffer_size];
     let mut cursor = 0;
-    let mut remaining = header.file_length as usize;
+    let mut remaining = (header.file_length - header.offset) as usize;
+
+    let verify = (config.hash || config.require_fips_hash) && !rejected;
+    let mut hasher = file::hash::Hasher::new(if verify {
+        header.hash_algorithm
+    } else {
+        file::hash::Algorithm::None
+    });
 
-    let mut hasher = fasthash::Murmur3HasherExt::default();
+    // writes are done by chunks of buffer_size bytes, so timing each of them is cheap
+    let mut write_time = time::Duration::ZERO;
+    let mut hash_time = time::Duration::ZERO;
+
+    // the bytes received by the interrupted transfer are hashed from the file, leaving it
+    // positioned at the resume offset
+    if let Some(file) = &mut file {
+        if verify && 0 < header.offset {
+            let start = time::Instant::now();
+            let mut received = file.take(header.offset);
+            loop {
+                match received.read(&mut buffer)? {
+                    0 => break,
+                    nread => hasher.update(&buffer[..nread]),
+                }
+            }
+            hash_time += start.elapsed();
+        }
+    }
+
+    let bytes_written = metrics::counter("rx_files_bytes_written");
+    let mut write = |data: &[u8]| -> Result<(), file::Error> {
+        let start = time::Instant::now();
+        hasher.update(data);
+        let hashed = time::Instant::now();
+        hash_time += hashed - start;
+        if let Some(file) = &mut file {
+            file.write_all(data)?;
+            bytes_written.add(data.len() as u64);
+            write_time += hashed.elapsed();
+        }
+        Ok(())
+    };
 
     loop {
         let end = if remaining >= (config.buffer_size - cursor) {
@@ -136,15 +362,14 @@ where
         match diode.read(&mut buffer[cursor..end])? {
             0 => {
                 if 0 < cursor {
-                    if config.hash {
-                        buffer[..cursor].hash(&mut hasher);
-                    }
-                    file.write_all(&buffer[..cursor])?;
+                    write(&buffer[..cursor])?;
                 }
 
-                file.flush()?;
+                if let Some(file) = &mut file {
+                    file.flush()?;
+                }
 
-                let received = header.file_length as usize - remaining;
+                let received = (header.file_length - header.offset) as usize - remaining;
 
                 let footer = file::protocol::Footer::deserialize_from(&mut diode)?;
 
@@ -153,36 +378,977 @@ where
                     log::debug!("received file size = {received}");
                     return Err(file::Error::Diode(file::protocol::Error::InvalidFileSize(
                         header.file_length as usize,
-                        received,
+                        header.offset as usize + received,
                     )));
                 }
 
-                if config.hash {
-                    let hash = hasher.finish_ext();
-                    log::debug!("expected hash = {}", footer.hash);
-                    log::debug!("computed hash = {hash}");
+                if footer.hash_algorithm != header.hash_algorithm {
+                    return Err(file::Error::Diode(
+                        file::protocol::Error::HashAlgorithmMismatch(
+                            header.hash_algorithm,
+                            footer.hash_algorithm,
+                        ),
+                    ));
+                }
+
+                if rejected {
+                    if let (Some(file), Some(file_path)) = (&file, &file_path) {
+                        store(config, file, file_path, &header)?;
+                    }
+                    return Err(file::Error::Other(match &config.quarantine_dir {
+                        Some(quarantine_dir) => format!(
+                            "file \"{}\" rejected by hash policy, quarantined in \"{}\"",
+                            header.file_name,
+                            quarantine_dir.display()
+                        ),
+                        None => format!(
+                            "file \"{}\" rejected by hash policy, discarded",
+                            header.file_name
+                        ),
+                    }));
+                }
+
+                if verify {
+                    let start = time::Instant::now();
+                    let hash = hasher.finalize();
+                    hash_time += start.elapsed();
+                    log::debug!("hash algorithm = {}", header.hash_algorithm);
                     if footer.hash != hash {
+                        if let Some(file_path) = &file_path {
+                            fs::remove_file(part_path(file_path))?;
+                        }
                         return Err(file::Error::Diode(file::protocol::Error::InvalidHash(
                             hash,
                             footer.hash,
                         )));
                     }
+                    record.hash = Some((header.hash_algorithm, hash));
+                }
+
+                let stored_path = match (&file, &file_path) {
+                    (Some(file), Some(file_path)) => Some(store(config, file, file_path, &header)?),
+                    _ => None,
+                };
+                let done = time::Instant::now();
+
+                // files listed in a manifest are checked once it is stored, which is not
+                // accounted in its transfer time
+                if let (Some(output_dir), Some(stored_path)) = (output_dir, &stored_path) {
+                    if header.manifest {
+                        if let Err(e) = file::manifest::verify(config, output_dir, stored_path) {
+                            metrics::counter("rx_manifests_invalid").inc();
+                            log::error!(
+                                "failed to verify manifest \"{}\": {e}",
+                                stored_path.display()
+                            );
+                        }
+                    }
                 }
 
-                return Ok(received);
+                return Ok(Received {
+                    file_name: header.file_name,
+                    bytes: received,
+                    first_byte,
+                    done,
+                    write_time,
+                    hash_time,
+                });
             }
             nread => {
                 remaining -= nread;
+                record.bytes += nread as u64;
                 if (cursor + nread) < config.buffer_size {
                     cursor += nread;
                     continue;
                 }
-                if config.hash {
-                    buffer.hash(&mut hasher);
-                }
-                file.write_all(&buffer)?;
+                write(&buffer)?;
                 cursor = 0;
             }
         }
     }
 }
+
+/// Temporary path at which the file of path `file_path` is received, hidden from the consumers
+/// of the output directory until it is complete and verified
+fn part_path(file_path: &path::Path) -> path::PathBuf {
+    let mut part_name = ffi::OsString::from(".");
+    part_name.push(file_path.file_name().unwrap_or_default());
+    part_name.push(".part");
+    file_path.with_file_name(part_name)
+}
+
+/// Creates the temporary file of the file at `file_path` in `output_dir` after checking it can
+/// be stored there
+fn create_file(
+    config: &file::Config<aux::DiodeReceive>,
+    output_dir: &path::Path,
+    file_path: &path::Path,
+    header: &file::protocol::Header,
+) -> Result<fs::File, file::Error> {
+    log::debug!("storing at \"{}\"", file_path.display());
+
+    let part_path = part_path(file_path);
+    if let Err(e) = fs_utils::check_path_length(&part_path) {
+        metrics::counter("rx_files_rejected_path").inc();
+        return Err(file::Error::Other(format!(
+            "refusing to store \"{}\": {e}",
+            file_path.display()
+        )));
+    }
+
+    preflight(config, output_dir, header.file_length)?;
+
+    if let Some(parent) = file_path.parent() {
+        create_dirs(config, output_dir, parent)?;
+    }
+
+    if config.overwrite == file::Overwrite::Fail && file_path.exists() {
+        return Err(file::Error::Other(format!(
+            "file \"{}\" already exists",
+            file_path.display()
+        )));
+    }
+
+    if part_path.exists() {
+        log::warn!(
+            "discarding partial file \"{}\" of an interrupted transfer",
+            part_path.display()
+        );
+    }
+
+    // only readable by the receiver until it is complete, its mode being applied when it is
+    // stored under its final name
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&part_path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+
+    let owner = config.output_owner.or_else(|| {
+        header
+            .owner
+            .filter(|_| config.preserve_owner)
+            .map(|(uid, gid)| file::Owner {
+                uid: Some(uid),
+                gid: Some(gid),
+            })
+    });
+    if let Some(owner) = &owner {
+        log::debug!("setting owner to {owner}");
+        unix::fs::fchown(&file, owner.uid, owner.gid)?;
+    }
+
+    if config.xattrs {
+        for (name, value) in &header.xattrs {
+            let printable = String::from_utf8_lossy(name);
+            if !name.starts_with(file::XATTR_PREFIX) {
+                log::warn!(
+                    "ignoring extended attribute \"{printable}\" outside of the user namespace"
+                );
+                continue;
+            }
+            log::debug!("setting extended attribute \"{printable}\"");
+            if let Err(e) = fs_utils::set_xattr(&file, name, value) {
+                log::warn!(
+                    "failed to set extended attribute \"{printable}\" of \"{}\": {e}",
+                    file_path.display()
+                );
+            }
+        }
+    }
+
+    Ok(file)
+}
+
+/// Stores the received `file` at `file_path`, according to the overwrite policy, after applying
+/// its mode and modification time, returning the path where it was stored
+fn store(
+    config: &file::Config<aux::DiodeReceive>,
+    file: &fs::File,
+    file_path: &path::Path,
+    header: &file::protocol::Header,
+) -> Result<path::PathBuf, file::Error> {
+    let mode = config.output_file_mode.unwrap_or(header.mode);
+    log::debug!("setting mode to {mode:o}");
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    if let Some(modified) = header.modified {
+        file.set_modified(modified)?;
+    }
+
+    let part_path = part_path(file_path);
+    let mut target = file_path.to_path_buf();
+    match config.overwrite {
+        file::Overwrite::Overwrite => {
+            fs::rename(&part_path, file_path)?;
+            return Ok(file_path.to_path_buf());
+        }
+        file::Overwrite::Fail => {
+            // linking fails if the file exists, unlike renaming
+            if let Err(e) = fs::hard_link(&part_path, file_path) {
+                fs::remove_file(&part_path)?;
+                return Err(if e.kind() == io::ErrorKind::AlreadyExists {
+                    file::Error::Other(format!("file \"{}\" already exists", file_path.display()))
+                } else {
+                    file::Error::Io(e)
+                });
+            }
+        }
+        file::Overwrite::Rename => {
+            let mut nb = 0;
+            loop {
+                match fs::hard_link(&part_path, &target) {
+                    Ok(()) => break,
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        nb += 1;
+                        let mut target_name =
+                            file_path.file_name().unwrap_or_default().to_os_string();
+                        target_name.push(format!(".{nb}"));
+                        target = file_path.with_file_name(target_name);
+                    }
+                    Err(e) => return Err(file::Error::Io(e)),
+                }
+            }
+            if 0 < nb {
+                log::info!(
+                    "file \"{}\" already exists, stored as \"{}\"",
+                    file_path.display(),
+                    target.display()
+                );
+            }
+        }
+    }
+    fs::remove_file(&part_path)?;
+    Ok(target)
+}
+
+/// Checks that the file name sent by the sender is a relative path without `.` or `..`
+/// components, so that the file is stored under the output directory
+fn relative_path(file_name: &str) -> Result<path::PathBuf, file::Error> {
+    let mut relative = path::PathBuf::new();
+    for component in path::Path::new(file_name).components() {
+        match component {
+            path::Component::Normal(component) => relative.push(component),
+            _ => {
+                metrics::counter("rx_files_rejected_path").inc();
+                return Err(file::Error::Other(format!(
+                    "refusing to store \"{file_name}\": not a relative path"
+                )));
+            }
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(file::Error::Other("empty file name".to_string()));
+    }
+    Ok(relative)
+}
+
+/// Creates the missing directories of `dir_path` below `output_dir`, giving them to the
+/// configured owner
+///
+/// Existing symbolic links are not followed, so that files cannot be stored outside of the
+/// output directory.
+fn create_dirs(
+    config: &file::Config<aux::DiodeReceive>,
+    output_dir: &path::Path,
+    dir_path: &path::Path,
+) -> Result<(), file::Error> {
+    let Ok(relative) = dir_path.strip_prefix(output_dir) else {
+        return Ok(());
+    };
+
+    let mut dir = output_dir.to_path_buf();
+    for component in relative.components() {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => continue,
+            Ok(_) => {
+                return Err(file::Error::Other(format!(
+                    "\"{}\" exists and is not a directory",
+                    dir.display()
+                )));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(file::Error::Io(e)),
+        }
+        log::debug!("creating directory \"{}\"", dir.display());
+        match fs::create_dir(&dir) {
+            Ok(()) => (),
+            // created meanwhile by the reception of another file of the same directory
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(file::Error::Io(e)),
+        }
+        if let Some(owner) = &config.output_owner {
+            unix::fs::chown(&dir, owner.uid, owner.gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the temporary file of the file at `file_path` in `output_dir`, partially received by an
+/// interrupted transfer, to append the rest of it from `header.offset`
+///
+/// Data beyond the offset, received by the interrupted transfer after the last progress recorded
+/// by the sender, is truncated. The owner was applied when the file was created.
+fn resume_file(
+    config: &file::Config<aux::DiodeReceive>,
+    output_dir: &path::Path,
+    file_path: &path::Path,
+    header: &file::protocol::Header,
+) -> Result<fs::File, file::Error> {
+    log::debug!(
+        "resuming \"{}\" at offset {}",
+        file_path.display(),
+        header.offset
+    );
+
+    let part_path = part_path(file_path);
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&part_path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(file::Error::Other(format!(
+                "cannot resume \"{}\": partial file \"{}\" does not exist",
+                file_path.display(),
+                part_path.display()
+            )));
+        }
+        Err(e) => return Err(file::Error::Io(e)),
+    };
+
+    let len = file.metadata()?.len();
+    if len < header.offset {
+        return Err(file::Error::Other(format!(
+            "cannot resume \"{}\" at offset {}: only {len} bytes were received",
+            file_path.display(),
+            header.offset
+        )));
+    }
+
+    preflight(config, output_dir, header.file_length - header.offset)?;
+
+    file.set_len(header.offset)?;
+    metrics::counter("rx_files_resumed").inc();
+
+    Ok(file)
+}
+
+/// Checks at startup that received files can be given to `owner`, by changing the owner of a
+/// probe file created in `output_dir`
+fn check_owner(output_dir: &path::Path, owner: &file::Owner) -> Result<(), file::Error> {
+    let probe = output_dir.join(format!(".diode-owner-check-{}", process::id()));
+    let file = fs::File::create(&probe)?;
+    let res = unix::fs::fchown(&file, owner.uid, owner.gid);
+    drop(file);
+    let _ = fs::remove_file(&probe);
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => Err(file::Error::Other(format!(
+            "cannot give received files to {owner}: the CAP_CHOWN capability is required"
+        ))),
+        Err(e) => Err(file::Error::Other(format!(
+            "cannot give received files to {owner}: {e}"
+        ))),
+    }
+}
+
+/// Checks the output filesystem state before receiving a file of `file_length` bytes, updating
+/// the free space gauges and warning when they are below the configured thresholds
+fn preflight(
+    config: &file::Config<aux::DiodeReceive>,
+    output_dir: &path::Path,
+    file_length: u64,
+) -> Result<(), file::Error> {
+    let stats = fs_utils::statvfs(output_dir)?;
+
+    metrics::gauge("rx_files_free_bytes").set(stats.free_bytes);
+    metrics::gauge("rx_files_free_inodes").set(stats.free_inodes);
+
+    if stats.free_bytes < config.min_free_bytes {
+        log::warn!(
+            "only {} bytes free in \"{}\" (threshold is {})",
+            stats.free_bytes,
+            output_dir.display(),
+            config.min_free_bytes
+        );
+    }
+
+    if stats.free_inodes < config.min_free_inodes {
+        log::warn!(
+            "only {} inodes free in \"{}\" (threshold is {})",
+            stats.free_inodes,
+            output_dir.display(),
+            config.min_free_inodes
+        );
+    }
+
+    if stats.free_inodes == 0 || stats.free_bytes < file_length {
+        metrics::counter("rx_files_rejected_space").inc();
+        return Err(file::Error::Other(format!(
+            "not enough space in \"{}\" to store {file_length} bytes",
+            output_dir.display()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{durable, testing::TempDir};
+    use file::hash::Algorithm;
+    use std::{env, os::unix::fs::MetadataExt};
+
+    fn send_config(hash_algorithm: Algorithm) -> file::Config<aux::DiodeSend> {
+        file::Config {
+            diode: aux::DiodeSend::Unix(path::PathBuf::new()),
+            buffer_size: 1000,
+            hash: hash_algorithm != Algorithm::None,
+            hash_algorithm,
+            resume: false,
+            recursive: false,
+            require_fips_hash: false,
+            quarantine_dir: None,
+            min_free_bytes: 0,
+            min_free_inodes: 0,
+            output_owner: None,
+            output_file_mode: None,
+            overwrite: file::Overwrite::Fail,
+            preserve_owner: false,
+            xattrs: false,
+            manifest_key: None,
+            landlock: false,
+            audit_log: None,
+            audit_sync: durable::Policy::Always,
+            output_template: None,
+        }
+    }
+
+    fn receive_config(
+        require_fips_hash: bool,
+        quarantine_dir: Option<&path::Path>,
+    ) -> file::Config<aux::DiodeReceive> {
+        file::Config {
+            diode: aux::DiodeReceive {
+                from_tcp: None,
+                from_unix: None,
+            },
+            // MurmurHash3 digests depend on the chunks, of the size used by the sender
+            buffer_size: 1000,
+            hash: true,
+            hash_algorithm: Algorithm::Murmur3,
+            resume: false,
+            recursive: false,
+            require_fips_hash,
+            quarantine_dir: quarantine_dir.map(path::Path::to_path_buf),
+            min_free_bytes: 0,
+            min_free_inodes: 0,
+            output_owner: None,
+            output_file_mode: None,
+            overwrite: file::Overwrite::Fail,
+            preserve_owner: false,
+            xattrs: false,
+            manifest_key: None,
+            landlock: false,
+            audit_log: None,
+            audit_sync: durable::Policy::Always,
+            output_template: None,
+        }
+    }
+
+    const CONTENT_LEN: usize = 10_000;
+
+    /// Stream of the file `name` sent with `hash_algorithm`, and its content
+    fn sent(dir: &TempDir, name: &str, hash_algorithm: Algorithm) -> (Vec<u8>, Vec<u8>) {
+        let content = (0..CONTENT_LEN)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let file_path = dir.path().join(name);
+        fs::write(&file_path, &content).expect("write file to send");
+        let mut stream = io::Cursor::new(Vec::new());
+        file::send::send_file_aux(
+            &send_config(hash_algorithm),
+            &mut stream,
+            &file_path,
+            name.to_string(),
+            None,
+        )
+        .map_err(|e| e.to_string())
+        .expect("file sent");
+        (stream.into_inner(), content)
+    }
+
+    fn receive(
+        config: &file::Config<aux::DiodeReceive>,
+        stream: Vec<u8>,
+        output_dir: &path::Path,
+    ) -> Result<Received, file::Error> {
+        receive_file(
+            config,
+            io::Cursor::new(stream),
+            output_dir,
+            &mut audit::Record::new(),
+        )
+    }
+
+    #[test]
+    fn algorithms_round_trip() {
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        for algorithm in [
+            Algorithm::None,
+            Algorithm::Murmur3,
+            Algorithm::Sha256,
+            Algorithm::Blake3,
+        ] {
+            let name = format!("file-{algorithm}");
+            let (stream, content) = sent(&input, &name, algorithm);
+            let received = receive(&receive_config(false, None), stream, output.path())
+                .map_err(|e| e.to_string())
+                .expect("file received");
+            assert_eq!(received.bytes, CONTENT_LEN);
+            assert_eq!(
+                fs::read(output.path().join(&name)).expect("received file"),
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_content_rejected() {
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        for algorithm in [Algorithm::Murmur3, Algorithm::Sha256, Algorithm::Blake3] {
+            let name = format!("file-{algorithm}");
+            let (mut stream, _) = sent(&input, &name, algorithm);
+            // a byte of the content, after the header
+            let len = stream.len();
+            stream[len - 1000] ^= 1;
+            assert!(matches!(
+                receive(&receive_config(false, None), stream, output.path()),
+                Err(file::Error::Diode(file::protocol::Error::InvalidHash(_, _)))
+            ));
+            assert!(!output.path().join(&name).exists());
+            assert!(!part_path(&output.path().join(&name)).exists());
+        }
+    }
+
+    #[test]
+    fn hash_policy_rejection() {
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        let quarantine = TempDir::new("file-quarantine");
+        let config = receive_config(true, Some(quarantine.path()));
+
+        for algorithm in [Algorithm::None, Algorithm::Murmur3, Algorithm::Blake3] {
+            let name = format!("file-{algorithm}");
+            let (stream, content) = sent(&input, &name, algorithm);
+            // the whole file is read, the stream staying aligned on the next one
+            let mut stream = io::Cursor::new(stream);
+            assert!(receive_file(
+                &config,
+                &mut stream,
+                output.path(),
+                &mut audit::Record::new()
+            )
+            .is_err());
+            assert_eq!(stream.position(), stream.get_ref().len() as u64);
+            assert!(!output.path().join(&name).exists());
+            assert_eq!(
+                fs::read(quarantine.path().join(&name)).expect("quarantined file"),
+                content
+            );
+        }
+
+        // without a quarantine directory, rejected files are discarded
+        let (stream, _) = sent(&input, "discarded", Algorithm::Blake3);
+        assert!(receive(&receive_config(true, None), stream, output.path()).is_err());
+        assert!(!output.path().join("discarded").exists());
+
+        // the policy checks the algorithm, diode-receive-file requiring an external
+        // implementation of it at startup
+        let (stream, _) = sent(&input, "sha256", Algorithm::Sha256);
+        assert!(receive(&config, stream, output.path()).is_ok());
+        assert!(output.path().join("sha256").exists());
+    }
+
+    #[test]
+    fn output_template_check() {
+        for template in ["{site}", "{site}/{date}/", "in/{date}-{site}", "fixed"] {
+            assert!(check_output_template(template).is_ok(), "{template}");
+        }
+        for template in [
+            "",
+            "/{site}",
+            "../{site}",
+            "{site}/../x",
+            "./{site}",
+            "{host}",
+        ] {
+            assert!(check_output_template(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn templated_output_directory() {
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        let mut config = receive_config(false, None);
+        config.output_template = Some("{site}/{date}".to_string());
+        let date = localtime::date(time::SystemTime::now());
+
+        for (site_id, dir) in [(Some("site-01"), "site-01"), (None, UNKNOWN_SITE)] {
+            let (stream, content) = sent(&input, "file", Algorithm::Blake3);
+            let header = diode_receive::header::Header {
+                client_id: 1,
+                site_id: site_id.map(str::to_string),
+            };
+            let mut session = header.serialize().to_vec();
+            session.extend_from_slice(&stream);
+
+            let mut record = audit::Record::new();
+            receive_templated(
+                &config,
+                io::Cursor::new(session),
+                output.path(),
+                "{site}/{date}",
+                &mut record,
+            )
+            .map_err(|e| e.to_string())
+            .expect("file received");
+            assert_eq!(record.site.as_deref(), site_id);
+            assert_eq!(
+                fs::read(output.path().join(dir).join(&date).join("file")).expect("received file"),
+                content
+            );
+        }
+
+        // sessions not starting with a valid header are rejected
+        let (stream, _) = sent(&input, "headerless", Algorithm::Blake3);
+        assert!(receive_templated(
+            &config,
+            io::Cursor::new(stream),
+            output.path(),
+            "{site}/{date}",
+            &mut audit::Record::new(),
+        )
+        .is_err());
+    }
+
+    /// Filesystem of `size` mounted for a test, unmounted when dropped
+    struct Tmpfs(TempDir);
+
+    impl Tmpfs {
+        /// Returns `None` when the process may not mount filesystems
+        fn mount(size: &str) -> Option<Self> {
+            let dir = TempDir::new("tmpfs");
+            let mounted = process::Command::new("mount")
+                .args(["-t", "tmpfs", "-o", &format!("size={size}"), "tmpfs"])
+                .arg(dir.path())
+                .stderr(process::Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success());
+            mounted.then_some(Self(dir))
+        }
+    }
+
+    impl Drop for Tmpfs {
+        fn drop(&mut self) {
+            let _ = process::Command::new("umount").arg(self.0.path()).status();
+        }
+    }
+
+    #[test]
+    fn low_space() {
+        let Some(output) = Tmpfs::mount("64k") else {
+            eprintln!("skipped: mounting a tmpfs requires CAP_SYS_ADMIN");
+            return;
+        };
+        let input = TempDir::new("file-input");
+        let config = file::Config {
+            min_free_bytes: 1024 * 1024,
+            min_free_inodes: u64::MAX,
+            ..receive_config(false, None)
+        };
+
+        // below the thresholds, files are still received while space is left
+        let (stream, content) = sent(&input, "small", Algorithm::Murmur3);
+        assert!(receive(&config, stream, output.0.path()).is_ok());
+        assert_eq!(
+            fs::read(output.0.path().join("small")).expect("received file"),
+            content
+        );
+        assert!(metrics::gauge("rx_files_free_bytes").get() <= 64 * 1024);
+
+        // larger than the space left
+        let file_path = input.path().join("large");
+        fs::write(&file_path, vec![1; 100_000]).expect("write file to send");
+        let mut stream = io::Cursor::new(Vec::new());
+        file::send::send_file_aux(
+            &send_config(Algorithm::Murmur3),
+            &mut stream,
+            &file_path,
+            "large".to_string(),
+            None,
+        )
+        .map_err(|e| e.to_string())
+        .expect("file sent");
+        let rejected = metrics::counter("rx_files_rejected_space");
+        let nb_rejected = rejected.get();
+        assert!(receive(&config, stream.into_inner(), output.0.path()).is_err());
+        assert_eq!(rejected.get(), nb_rejected + 1);
+        assert!(!output.0.path().join("large").exists());
+        assert!(!part_path(&output.0.path().join("large")).exists());
+    }
+
+    #[test]
+    fn path_too_long() {
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        let rejected = metrics::counter("rx_files_rejected_path");
+
+        // the temporary file adds 6 bytes to the name, up to NAME_MAX
+        let name = "n".repeat(249);
+        let (stream, _) = sent(&input, &name, Algorithm::Murmur3);
+        assert!(receive(&receive_config(false, None), stream, output.path()).is_ok());
+        assert!(output.path().join(&name).exists());
+
+        let nb_rejected = rejected.get();
+        let name = "n".repeat(250);
+        let (stream, _) = sent(&input, &name, Algorithm::Murmur3);
+        assert!(receive(&receive_config(false, None), stream, output.path()).is_err());
+        assert_eq!(rejected.get(), nb_rejected + 1);
+        assert!(!output.path().join(&name).exists());
+    }
+
+    /// Stream read by chunks of at most `chunk` bytes, each read past `delayed_from` waiting
+    /// `delay` as if data were late from the diode
+    struct Slow {
+        stream: io::Cursor<Vec<u8>>,
+        chunk: usize,
+        delayed_from: u64,
+        delay: time::Duration,
+        waited: time::Duration,
+    }
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.delayed_from <= self.stream.position() {
+                thread::sleep(self.delay);
+                self.waited += self.delay;
+            }
+            let len = buf.len().min(self.chunk);
+            self.stream.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timings_add_up() {
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        let (stream, _) = sent(&input, "timed", Algorithm::Sha256);
+        let mut header = io::Cursor::new(&stream);
+        file::protocol::Header::deserialize_from(&mut header).unwrap_or_else(|e| panic!("{e}"));
+        let mut slow = Slow {
+            delayed_from: header.position(),
+            stream: io::Cursor::new(stream),
+            chunk: 100,
+            delay: time::Duration::from_millis(1),
+            waited: time::Duration::ZERO,
+        };
+
+        let started = time::Instant::now();
+        let received = receive_file(
+            &receive_config(false, None),
+            &mut slow,
+            output.path(),
+            &mut audit::Record::new(),
+        )
+        .map_err(|e| e.to_string())
+        .expect("file received");
+        let elapsed = started.elapsed();
+
+        assert_eq!(received.file_name, "timed");
+        assert_eq!(received.bytes, CONTENT_LEN);
+        // 100 reads of content, then the end of file and the footer
+        assert!(100 < slow.waited.as_millis());
+        assert!(slow.waited <= received.diode_time());
+        assert!(received.transfer_time() <= elapsed);
+        assert_eq!(
+            received.diode_time() + received.write_time + received.hash_time,
+            received.transfer_time()
+        );
+        assert!(!received.write_time.is_zero());
+        assert!(!received.hash_time.is_zero());
+        let rate = received.bytes as f64 / received.transfer_time().as_secs_f64();
+        assert!((received.rate() - rate).abs() < 1e-6);
+
+        // the counters accumulate the timings of every file
+        let counters = [
+            "rx_files_transfer_microseconds",
+            "rx_files_diode_microseconds",
+            "rx_files_write_microseconds",
+            "rx_files_hash_microseconds",
+        ];
+        let before = counters.map(|name| metrics::counter(name).get());
+        log_received(&received);
+        let after = counters.map(|name| metrics::counter(name).get());
+        assert_eq!(
+            after[0] - before[0],
+            received.transfer_time().as_micros() as u64
+        );
+        assert_eq!(
+            after[1] - before[1],
+            received.diode_time().as_micros() as u64
+        );
+        assert_eq!(after[2] - before[2], received.write_time.as_micros() as u64);
+        assert_eq!(after[3] - before[3], received.hash_time.as_micros() as u64);
+    }
+
+    /// Stream recording the owner, group and mode of the temporary file `part` at each read past
+    /// `content_from`, that is while the content is written
+    struct Observed {
+        stream: io::Cursor<Vec<u8>>,
+        content_from: u64,
+        part: path::PathBuf,
+        seen: Vec<(u32, u32, u32)>,
+    }
+
+    impl Read for Observed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.content_from <= self.stream.position() {
+                let metadata = fs::metadata(&self.part)?;
+                self.seen
+                    .push((metadata.uid(), metadata.gid(), metadata.mode() & 0o7777));
+            }
+            let len = buf.len().min(1000);
+            self.stream.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for Observed {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn owner_and_mode_order() {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skipped: giving files to another user requires CAP_CHOWN");
+            return;
+        }
+        let input = TempDir::new("file-input");
+        let output = TempDir::new("file-output");
+        let config = file::Config {
+            output_owner: Some(file::Owner {
+                uid: Some(65534),
+                gid: Some(65534),
+            }),
+            output_file_mode: Some(0o640),
+            ..receive_config(false, None)
+        };
+        assert!(check_owner(output.path(), config.output_owner.as_ref().expect("owner")).is_ok());
+
+        let (stream, content) = sent(&input, "owned", Algorithm::Murmur3);
+        let mut header = io::Cursor::new(&stream);
+        file::protocol::Header::deserialize_from(&mut header).unwrap_or_else(|e| panic!("{e}"));
+        let mut observed = Observed {
+            content_from: header.position(),
+            stream: io::Cursor::new(stream),
+            part: part_path(&output.path().join("owned")),
+            seen: vec![],
+        };
+        receive_file(
+            &config,
+            &mut observed,
+            output.path(),
+            &mut audit::Record::new(),
+        )
+        .map_err(|e| e.to_string())
+        .expect("file received");
+
+        // the temporary file is given to the owner when created, but only readable by it until
+        // the file is complete and verified
+        assert!(!observed.seen.is_empty());
+        assert!(observed
+            .seen
+            .iter()
+            .all(|seen| *seen == (65534, 65534, 0o600)));
+        let file_path = output.path().join("owned");
+        let metadata = fs::metadata(&file_path).expect("received file");
+        assert_eq!(
+            (metadata.uid(), metadata.gid(), metadata.mode() & 0o7777),
+            (65534, 65534, 0o640)
+        );
+        assert_eq!(fs::read(&file_path).expect("received file"), content);
+        assert!(!observed.part.exists());
+    }
+
+    const CHILD_OUTPUT: &str = "LIDI_TEST_OWNER_OUTPUT";
+
+    /// Starts the receiver in a child process without CAP_CHOWN, dropping the privileges of
+    /// root if needed, which must refuse to give received files to root
+    #[test]
+    fn owner_requires_chown() {
+        if let Some(output) = env::var_os(CHILD_OUTPUT) {
+            unsafe {
+                if libc::geteuid() == 0 {
+                    assert_eq!(libc::setgroups(0, std::ptr::null()), 0);
+                    assert_eq!(libc::setgid(65534), 0);
+                    assert_eq!(libc::setuid(65534), 0);
+                }
+            }
+            let config = file::Config {
+                output_owner: Some(file::Owner {
+                    uid: Some(0),
+                    gid: Some(0),
+                }),
+                ..receive_config(false, None)
+            };
+            let e = receive_files(&config, output.as_ref()).expect_err("refused");
+            assert_eq!(
+                e.to_string(),
+                "error: cannot give received files to 0:0: the CAP_CHOWN capability is required"
+            );
+            assert_eq!(fs::read_dir(&output).expect("output").count(), 0);
+            return;
+        }
+
+        let output = TempDir::new("file-output");
+        fs::set_permissions(output.path(), fs::Permissions::from_mode(0o1777))
+            .expect("output directory writable by any user");
+        let status = process::Command::new(env::current_exe().expect("test executable"))
+            .args([
+                "--exact",
+                "aux::file::receive::tests::owner_requires_chown",
+                "--test-threads=1",
+            ])
+            .env(CHILD_OUTPUT, output.path())
+            .stdout(process::Stdio::null())
+            .status()
+            .expect("run child");
+        assert!(status.success());
+    }
+}
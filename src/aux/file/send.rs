@@ -1,13 +1,16 @@
-use fasthash::HasherExt;
-
-use crate::aux::{self, file};
+use crate::{
+    aux::{self, file},
+    fs_utils,
+};
 use std::{
     fs,
-    hash::Hash,
     io::{Read, Write},
     net,
-    os::unix::{self, fs::PermissionsExt},
-    path,
+    os::unix::{
+        self,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path, time,
 };
 
 pub fn send_files(
@@ -15,41 +18,221 @@ pub fn send_files(
     files: &[String],
 ) -> Result<(), file::Error> {
     for file in files {
-        let total = send_file(config, file)?;
+        let total = send_path(config, file, None)?;
         log::info!("file send, {total} bytes sent");
     }
     Ok(())
 }
 
+/// Sends the file at `path` or, if `config.recursive` is set and it is a directory, the files
+/// of its tree, returning the number of bytes sent
+///
+/// The files sent are added to `manifest` if given.
+pub fn send_path(
+    config: &file::Config<aux::DiodeSend>,
+    path: &String,
+    manifest: Option<&mut file::manifest::Manifest>,
+) -> Result<usize, file::Error> {
+    let dir_path = path::Path::new(path);
+    if config.recursive && dir_path.is_dir() {
+        send_dir(config, dir_path, manifest)
+    } else {
+        send_file_named(config, dir_path, manifest)
+    }
+}
+
 pub fn send_file(
     config: &file::Config<aux::DiodeSend>,
     file_path: &String,
 ) -> Result<usize, file::Error> {
+    send_file_named(config, path::Path::new(file_path), None)
+}
+
+/// Sends the file at `file_path` under its base name
+fn send_file_named(
+    config: &file::Config<aux::DiodeSend>,
+    file_path: &path::Path,
+    manifest: Option<&mut file::manifest::Manifest>,
+) -> Result<usize, file::Error> {
+    let file_name = file_path
+        .file_name()
+        .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?
+        .to_os_string()
+        .into_string()
+        .map_err(|_| file::Error::Other("conversion from OsString to String failed".to_string()))?;
+
+    send_file_as(config, file_path, file_name, manifest)
+}
+
+/// Sends the regular files of the tree rooted at `dir_path`, each one named by its path relative
+/// to the parent of `dir_path` so that the tree is recreated under the output directory,
+/// returning the number of bytes sent
+///
+/// Symbolic links and special files are skipped, as well as the journals of `--resume`.
+pub fn send_dir(
+    config: &file::Config<aux::DiodeSend>,
+    dir_path: &path::Path,
+    mut manifest: Option<&mut file::manifest::Manifest>,
+) -> Result<usize, file::Error> {
+    let root = dir_path.file_name().ok_or_else(|| {
+        file::Error::Other(format!("invalid directory \"{}\"", dir_path.display()))
+    })?;
+
+    let mut files = Vec::new();
+    walk(dir_path, path::Path::new(root), &mut files)?;
+    log::info!(
+        "sending {} file(s) of directory \"{}\"",
+        files.len(),
+        dir_path.display()
+    );
+
+    let mut total = 0;
+    for (file_path, file_name) in files {
+        let sent = send_file_as(config, &file_path, file_name, manifest.as_deref_mut())?;
+        log::debug!("\"{}\" sent, {sent} bytes", file_path.display());
+        total += sent;
+    }
+    Ok(total)
+}
+
+/// Lists the regular files of the tree rooted at `dir_path`, in name order, along with their
+/// path relative to `relative`
+fn walk(
+    dir_path: &path::Path,
+    relative: &path::Path,
+    files: &mut Vec<(path::PathBuf, String)>,
+) -> Result<(), file::Error> {
+    let mut entries = fs::read_dir(dir_path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+
+    for entry in entries {
+        let entry_path = entry.path();
+        let relative = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry_path, &relative, files)?;
+        } else if !file_type.is_file() {
+            log::warn!(
+                "skipping \"{}\" which is not a regular file",
+                entry_path.display()
+            );
+        } else if file::journal::Journal::is_journal(&entry_path) {
+            log::debug!("skipping journal \"{}\"", entry_path.display());
+        } else {
+            let file_name = relative.into_os_string().into_string().map_err(|_| {
+                file::Error::Other(format!(
+                    "path \"{}\" is not valid UTF-8",
+                    entry_path.display()
+                ))
+            })?;
+            files.push((entry_path, file_name));
+        }
+    }
+    Ok(())
+}
+
+/// Sends the file at `file_path` under the name `file_name`, adding it to `manifest` if given
+fn send_file_as(
+    config: &file::Config<aux::DiodeSend>,
+    file_path: &path::Path,
+    file_name: String,
+    manifest: Option<&mut file::manifest::Manifest>,
+) -> Result<usize, file::Error> {
+    log::debug!("connecting to {}", config.diode);
+
+    match &config.diode {
+        aux::DiodeSend::Tcp(socket_addr) => {
+            let diode = net::TcpStream::connect(socket_addr)?;
+            send_file_aux(config, diode, file_path, file_name, manifest)
+        }
+        aux::DiodeSend::Unix(path) => {
+            let diode = unix::net::UnixStream::connect(path)?;
+            send_file_aux(config, diode, file_path, file_name, manifest)
+        }
+    }
+}
+
+/// Sends `manifest`, signed with `config.manifest_key` if set, once all its files were sent
+pub fn send_manifest(
+    config: &file::Config<aux::DiodeSend>,
+    manifest: &file::manifest::Manifest,
+) -> Result<usize, file::Error> {
+    let content = manifest.serialize(config.manifest_key.as_deref())?;
     log::debug!("connecting to {}", config.diode);
 
     match &config.diode {
         aux::DiodeSend::Tcp(socket_addr) => {
             let diode = net::TcpStream::connect(socket_addr)?;
-            send_file_aux(config, diode, file_path)
+            send_manifest_aux(config, diode, manifest, &content)
         }
         aux::DiodeSend::Unix(path) => {
             let diode = unix::net::UnixStream::connect(path)?;
-            send_file_aux(config, diode, file_path)
+            send_manifest_aux(config, diode, manifest, &content)
         }
     }
 }
 
-fn send_file_aux<D>(
+fn send_manifest_aux<D>(
     config: &file::Config<aux::DiodeSend>,
     mut diode: D,
-    file_path: &String,
+    manifest: &file::manifest::Manifest,
+    content: &[u8],
 ) -> Result<usize, file::Error>
 where
-    D: Read + Write,
+    D: Write,
 {
-    log::debug!("opening file \"{}\"", file_path);
+    let hash_algorithm = if config.hash {
+        config.hash_algorithm
+    } else {
+        file::hash::Algorithm::None
+    };
 
-    let file_path = path::PathBuf::from(file_path);
+    let header = file::protocol::Header {
+        file_name: manifest.file_name(),
+        mode: 0o644,
+        file_length: content.len() as u64,
+        offset: 0,
+        hash_algorithm,
+        modified: Some(time::SystemTime::now()),
+        owner: None,
+        xattrs: Vec::new(),
+        manifest: true,
+    };
+    header.serialize_to(&mut diode)?;
+
+    // chunked as files are, for the digest to match the receiver's
+    let mut hasher = file::hash::Hasher::new(hash_algorithm);
+    for chunk in content.chunks(config.buffer_size) {
+        hasher.update(chunk);
+        diode.write_all(chunk)?;
+    }
+
+    let footer = file::protocol::Footer {
+        hash_algorithm,
+        hash: hasher.finalize(),
+    };
+    footer.serialize_to(&mut diode)?;
+    diode.flush()?;
+
+    log::info!(
+        "manifest of batch {} sent, {} file(s) listed",
+        manifest.batch,
+        manifest.entries.len()
+    );
+    Ok(content.len())
+}
+
+pub(super) fn send_file_aux<D>(
+    config: &file::Config<aux::DiodeSend>,
+    mut diode: D,
+    file_path: &path::Path,
+    file_name: String,
+    manifest: Option<&mut file::manifest::Manifest>,
+) -> Result<usize, file::Error>
+where
+    D: Read + Write,
+{
+    log::debug!("opening file \"{}\"", file_path.display());
 
     if !file_path.is_file() {
         return Err(file::Error::Other("not a file".to_string()));
@@ -59,66 +242,114 @@ where
         .read(true)
         .write(false)
         .create(false)
-        .open(&file_path)?;
-
-    let file_name = file_path
-        .file_name()
-        .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?
-        .to_os_string()
-        .into_string()
-        .map_err(|_| file::Error::Other("conversion from OsString to String failed".to_string()))?;
+        .open(file_path)?;
 
     log::debug!("file name is \"{file_name}\"");
 
     let metadata = file.metadata()?;
     let permissions = metadata.permissions();
 
+    let hash_algorithm = if config.hash {
+        config.hash_algorithm
+    } else {
+        file::hash::Algorithm::None
+    };
+
+    let mut hasher = file::hash::Hasher::new(hash_algorithm);
+    let mut buffer = vec![0; config.buffer_size];
+
+    let (journal, mut progress) = if config.resume {
+        let (journal, progress) = file::journal::Journal::open(file_path, &metadata)?;
+        (Some(journal), progress.unwrap_or_default())
+    } else {
+        (None, file::journal::Progress::default())
+    };
+
+    if 0 < progress.offset {
+        log::info!(
+            "resuming transfer of \"{file_name}\" at offset {} after {} chunk(s)",
+            progress.offset,
+            progress.nb_chunks
+        );
+        // the bytes already sent are not sent again but still hashed, the receiver hashing its
+        // copy of them
+        let mut skipped = (&mut file).take(progress.offset);
+        loop {
+            match skipped.read(&mut buffer)? {
+                0 => break,
+                nread => hasher.update(&buffer[..nread]),
+            }
+        }
+    }
+
     let header = file::protocol::Header {
         file_name,
         mode: permissions.mode(),
         file_length: metadata.len(),
+        offset: progress.offset,
+        hash_algorithm,
+        modified: metadata.modified().ok(),
+        owner: Some((metadata.uid(), metadata.gid())),
+        xattrs: if config.xattrs {
+            fs_utils::xattrs(&file, file::XATTR_PREFIX)?
+        } else {
+            Vec::new()
+        },
+        manifest: false,
     };
 
     header.serialize_to(&mut diode)?;
 
-    let mut buffer = vec![0; config.buffer_size];
     let mut cursor = 0;
     let mut total = 0;
 
-    let mut hasher = fasthash::Murmur3HasherExt::default();
+    let mut send_chunk = |diode: &mut D, chunk: &[u8]| -> Result<(), file::Error> {
+        total += chunk.len();
+        hasher.update(chunk);
+        diode.write_all(chunk)?;
+        progress.offset += chunk.len() as u64;
+        progress.nb_chunks += 1;
+        if let Some(journal) = &journal {
+            journal.record(progress)?;
+        }
+        Ok(())
+    };
 
     loop {
         match file.read(&mut buffer[cursor..])? {
             0 => {
                 if 0 < cursor {
-                    total += cursor;
-                    if config.hash {
-                        buffer[..cursor].hash(&mut hasher);
-                    }
-                    diode.write_all(&buffer[..cursor])?;
+                    send_chunk(&mut diode, &buffer[..cursor])?;
                 }
-
-                let footer = file::protocol::Footer {
-                    hash: if config.hash { hasher.finish_ext() } else { 0 },
-                };
-
-                footer.serialize_to(&mut diode)?;
-
-                diode.flush()?;
-                return Ok(total);
+                break;
             }
             nread => {
                 if (cursor + nread) < config.buffer_size {
                     cursor += nread;
                     continue;
                 }
-                total += config.buffer_size;
-                if config.hash {
-                    buffer.hash(&mut hasher);
-                }
-                diode.write_all(&buffer)?;
+                send_chunk(&mut diode, &buffer)?;
                 cursor = 0;
             }
         }
     }
+
+    let footer = file::protocol::Footer {
+        hash_algorithm,
+        hash: hasher.finalize(),
+    };
+
+    footer.serialize_to(&mut diode)?;
+
+    diode.flush()?;
+
+    if let Some(journal) = journal {
+        journal.remove()?;
+    }
+
+    if let Some(manifest) = manifest {
+        manifest.add(header.file_name, header.file_length, footer.hash);
+    }
+
+    Ok(total)
 }
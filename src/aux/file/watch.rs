@@ -0,0 +1,137 @@
+//! Watch folder mode of `diode-send-file`
+//!
+//! Files moved into the watched directory are sent, then moved into its `sent` subdirectory, or
+//! into its `failed` subdirectory if their transfer failed. Only entries moved in are picked up,
+//! so that files are never sent while being written: they must be written elsewhere on the same
+//! filesystem, then renamed into the directory. Entries whose name starts with a dot are ignored,
+//! as well as directories unless `--recursive` is set.
+//!
+//! Entries already in the directory when the watch starts, or when events were lost, are picked
+//! up as if they had just been moved in.
+
+use crate::{
+    aux::{self, file},
+    fs_utils, metrics,
+};
+use std::{ffi, fs, io, path};
+
+const SENT_DIR: &str = "sent";
+const FAILED_DIR: &str = "failed";
+
+/// Sends the entries moved into `dir`, forever
+pub fn watch(config: &file::Config<aux::DiodeSend>, dir: &path::Path) -> Result<(), file::Error> {
+    if !dir.is_dir() {
+        return Err(file::Error::Other(format!(
+            "'{}' is not a directory",
+            dir.display()
+        )));
+    }
+    for subdir in [SENT_DIR, FAILED_DIR] {
+        match fs::create_dir(dir.join(subdir)) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => (),
+        }
+    }
+
+    // watching before listing the directory, so that no entry moved in meanwhile is missed
+    let inotify = fs_utils::Inotify::watch_moved_in(dir)?;
+    log::info!("watching '{}'", dir.display());
+    scan(config, dir)?;
+
+    loop {
+        for event in inotify.read()? {
+            match event {
+                fs_utils::WatchEvent::MovedIn(name) => process(config, dir, &name),
+                fs_utils::WatchEvent::Overflow => {
+                    log::warn!("watch events lost, scanning '{}'", dir.display());
+                    scan(config, dir)?;
+                }
+            }
+        }
+    }
+}
+
+/// Processes the entries present in `dir`, in name order
+fn scan(config: &file::Config<aux::DiodeSend>, dir: &path::Path) -> Result<(), file::Error> {
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    names.sort();
+    for name in names {
+        process(config, dir, &name);
+    }
+    Ok(())
+}
+
+/// Sends the entry `name` of `dir`, then moves it into the `sent` or `failed` subdirectory
+fn process(config: &file::Config<aux::DiodeSend>, dir: &path::Path, name: &ffi::OsStr) {
+    if name == SENT_DIR || name == FAILED_DIR || name.as_encoded_bytes().starts_with(b".") {
+        return;
+    }
+    let entry_path = dir.join(name);
+    let Ok(metadata) = fs::symlink_metadata(&entry_path) else {
+        // already moved away
+        return;
+    };
+    if !(metadata.is_file() || (config.recursive && metadata.is_dir())) {
+        log::warn!("ignoring '{}'", entry_path.display());
+        return;
+    }
+
+    let Some(path) = entry_path.to_str() else {
+        log::error!("'{}' is not valid UTF-8", entry_path.display());
+        move_to(dir, FAILED_DIR, name);
+        return;
+    };
+
+    match file::send::send_path(config, &path.to_string(), None) {
+        Ok(total) => {
+            log::info!("'{}' sent, {total} bytes", entry_path.display());
+            metrics::counter("tx_files_sent").inc();
+            move_to(dir, SENT_DIR, name);
+        }
+        Err(e) => {
+            log::error!("failed to send '{}': {e}", entry_path.display());
+            metrics::counter("tx_files_failed").inc();
+            move_to(dir, FAILED_DIR, name);
+        }
+    }
+}
+
+/// Moves the entry `name` of `dir`, along with its journal if any, into the `subdir`
+/// subdirectory, adding a numeric suffix to its name if it is already taken
+fn move_to(dir: &path::Path, subdir: &str, name: &ffi::OsStr) {
+    let mut target_name = name.to_os_string();
+    let mut nb = 0;
+    while dir.join(subdir).join(&target_name).exists() {
+        nb += 1;
+        target_name = name.to_os_string();
+        target_name.push(format!(".{nb}"));
+    }
+
+    let source = dir.join(name);
+    let target = dir.join(subdir).join(&target_name);
+    if let Err(e) = fs::rename(&source, &target) {
+        log::error!(
+            "failed to move '{}' to '{}': {e}",
+            source.display(),
+            target.display()
+        );
+        return;
+    }
+
+    // the journal left by a failed transfer follows its file, to resume it if moved back
+    if let (Some(journal), Some(target_journal)) = (
+        file::journal::Journal::path_of(&source),
+        file::journal::Journal::path_of(&target),
+    ) {
+        match fs::rename(&journal, &target_journal) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => log::error!(
+                "failed to move '{}' to '{}': {e}",
+                journal.display(),
+                target_journal.display()
+            ),
+            _ => (),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 pub mod file;
 pub mod udp;
 
+use crate::scrub;
 use std::{fmt, net, path};
 
 pub enum DiodeSend {
@@ -11,7 +12,7 @@ pub enum DiodeSend {
 impl fmt::Display for DiodeSend {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
-            Self::Tcp(s) => write!(fmt, "TCP {s}"),
+            Self::Tcp(s) => write!(fmt, "TCP {}", scrub::addr(s)),
             Self::Unix(p) => write!(fmt, "Unix {}", p.display()),
         }
     }
@@ -25,7 +26,7 @@ pub struct DiodeReceive {
 impl fmt::Display for DiodeReceive {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         if let Some(from_tcp) = &self.from_tcp {
-            write!(fmt, "TCP {from_tcp}")?;
+            write!(fmt, "TCP {}", scrub::addr(from_tcp))?;
         }
         if let Some(from_unix) = &self.from_unix {
             write!(fmt, "Unix {}", from_unix.display())?;
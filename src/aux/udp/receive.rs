@@ -1,4 +1,7 @@
-use crate::aux::{self, udp};
+use crate::{
+    aux::{self, udp},
+    scrub,
+};
 use std::{
     io::{Read, Write},
     net,
@@ -14,7 +17,7 @@ fn receive_udp<D>(
 where
     D: Read + Write,
 {
-    log::debug!("binding UDP socket to {to_udp_bind}");
+    log::debug!("binding UDP socket to {}", scrub::addr(&to_udp_bind));
 
     let client = net::UdpSocket::bind(to_udp_bind)?;
 
@@ -30,7 +33,7 @@ where
 
         diode.read_exact(&mut buffer[0..header.size])?;
 
-        log::trace!("sending datagram to {to_udp}");
+        log::trace!("sending datagram to {}", scrub::addr(&to_udp));
 
         client.send_to(&buffer[0..header.size], to_udp)?;
     }
@@ -1,4 +1,7 @@
-use crate::aux::{self, udp};
+use crate::{
+    aux::{self, udp},
+    scrub,
+};
 use std::{
     io::{Read, Write},
     net,
@@ -15,7 +18,7 @@ where
 {
     let mut buffer = vec![0; config.buffer_size];
 
-    log::info!("binding UDP socket to {from_udp}");
+    log::info!("binding UDP socket to {}", scrub::addr(&from_udp));
 
     let socket = net::UdpSocket::bind(from_udp)?;
 
@@ -0,0 +1,274 @@
+use clap::{Arg, ArgAction, Command};
+use diode::{impair, loopback};
+use std::{env, net, num::NonZeroU64, time};
+
+fn command_args() -> loopback::Config {
+    let args = Command::new(env!("CARGO_BIN_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(
+            "Runs both ends of the diode in a single process, linked over the loopback interface",
+        )
+        .arg(
+            Arg::new("from_tcp")
+                .long("from_tcp")
+                .value_name("ip:port")
+                .default_value("127.0.0.1:5000")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port to accept TCP clients"),
+        )
+        .arg(
+            Arg::new("to_tcp")
+                .long("to_tcp")
+                .value_name("ip:port")
+                .default_value("127.0.0.1:7000")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port to connect to TCP server"),
+        )
+        .arg(
+            Arg::new("mtu")
+                .long("mtu")
+                .value_name("nb_bytes")
+                .default_value("1500")
+                .value_parser(clap::value_parser!(u16))
+                .help("MTU of the UDP link"),
+        )
+        .arg(
+            Arg::new("nb_clients")
+                .long("nb_clients")
+                .value_name("nb")
+                .default_value("2")
+                .value_parser(clap::value_parser!(u16))
+                .help("Number of simultaneous transfers"),
+        )
+        .arg(
+            Arg::new("nb_encoding_threads")
+                .long("nb_encoding_threads")
+                .value_name("nb")
+                .default_value("2")
+                .value_parser(clap::value_parser!(u8))
+                .help("Number of parallel RaptorQ encoding threads"),
+        )
+        .arg(
+            Arg::new("nb_decoding_threads")
+                .long("nb_decoding_threads")
+                .value_name("nb")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u8))
+                .help("Number of parallel RaptorQ decoding threads"),
+        )
+        .arg(
+            Arg::new("encoding_block_size")
+                .long("encoding_block_size")
+                .value_name("nb_bytes")
+                .default_value("60000") // (mtu * 40), optimal parameter -- to align with other size !
+                .value_parser(clap::value_parser!(u64))
+                .help("Size of RaptorQ block in bytes"),
+        )
+        .arg(
+            Arg::new("repair_block_size")
+                .long("repair_block_size")
+                .value_name("ratior")
+                .default_value("6000") // mtu * 4
+                .value_parser(clap::value_parser!(u32))
+                .help("Size of repair data in bytes"),
+        )
+        .arg(
+            Arg::new("low_latency")
+                .long("low_latency")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["encoding_block_size", "repair_block_size"])
+                .help("Send each block as a single packet, emitted and delivered without delay"),
+        )
+        .arg(
+            Arg::new("low_latency_repair")
+                .long("low_latency_repair")
+                .value_name("nb_packets")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32))
+                .help("Number of repair packets of each block with low_latency"),
+        )
+        .arg(
+            Arg::new("udp_buffer_size")
+                .long("udp_buffer_size")
+                .value_name("nb_bytes")
+                .default_value("1073741823") // i32::MAX / 2
+                .value_parser(clap::value_parser!(u32).range(..1073741824))
+                .help("Size of UDP socket buffers"),
+        )
+        .arg(
+            Arg::new("flush_timeout")
+                .long("flush_timeout")
+                .value_name("nb_milliseconds")
+                .default_value("1000")
+                .value_parser(clap::value_parser!(NonZeroU64))
+                .help("Maximum duration pending data waits before being flushed"),
+        )
+        .arg(
+            Arg::new("heartbeat")
+                .long("heartbeat")
+                .value_name("nb_seconds")
+                .default_value("5")
+                .value_parser(clap::value_parser!(u16))
+                .help("Duration between two emitted heartbeat messages, 0 to disable"),
+        )
+        .arg(
+            Arg::new("bandwidth_limit")
+                .long("bandwidth_limit")
+                .value_name("bandwidth_limit_mbit")
+                .default_value("0")
+                .value_parser(clap::value_parser!(f64))
+                .help("Bandwidth limit in Mbit/s (0 = no limit)"),
+        )
+        .arg(
+            Arg::new("loss")
+                .long("loss")
+                .value_name("percent")
+                .default_value("0")
+                .value_parser(parse_percent)
+                .help("Percentage of UDP packets dropped, outside of bursts"),
+        )
+        .arg(
+            Arg::new("burst_rate")
+                .long("burst_rate")
+                .value_name("percent")
+                .default_value("0")
+                .value_parser(parse_percent)
+                .help("Probability, per UDP packet, that a burst of losses starts"),
+        )
+        .arg(
+            Arg::new("burst_length")
+                .long("burst_length")
+                .value_name("nb_packets")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Mean number of UDP packets of a burst"),
+        )
+        .arg(
+            Arg::new("burst_loss")
+                .long("burst_loss")
+                .value_name("percent")
+                .default_value("100")
+                .value_parser(parse_percent)
+                .help("Percentage of the UDP packets of a burst dropped"),
+        )
+        .arg(
+            Arg::new("duplicate")
+                .long("duplicate")
+                .value_name("percent")
+                .default_value("0")
+                .value_parser(parse_percent)
+                .help("Percentage of UDP packets delivered twice"),
+        )
+        .arg(
+            Arg::new("reorder")
+                .long("reorder")
+                .value_name("percent")
+                .default_value("0")
+                .value_parser(parse_percent)
+                .help("Percentage of UDP packets held back by reorder_delay, the following ones overtaking them"),
+        )
+        .arg(
+            Arg::new("reorder_delay")
+                .long("reorder_delay")
+                .value_name("nb_milliseconds")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64))
+                .help("Delay added to the UDP packets held back"),
+        )
+        .arg(
+            Arg::new("delay")
+                .long("delay")
+                .value_name("nb_milliseconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Delay added to every UDP packet"),
+        )
+        .arg(
+            Arg::new("jitter")
+                .long("jitter")
+                .value_name("nb_microseconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Largest random delay added to each UDP packet on top of delay"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("seed")
+                .value_parser(clap::value_parser!(u64))
+                .help("Seed of the impairments, logged at startup, to impair packets the same way as another run [default: random]"),
+        )
+        .get_matches();
+
+    let from_tcp = *args
+        .get_one::<net::SocketAddr>("from_tcp")
+        .expect("default");
+    let to_tcp = *args.get_one::<net::SocketAddr>("to_tcp").expect("default");
+    let heartbeat = {
+        let hb = *args.get_one::<u16>("heartbeat").expect("default") as u64;
+        (hb != 0).then(|| time::Duration::from_secs(hb))
+    };
+    let bandwidth_limit = {
+        let target_bandwidth_mbps = *args.get_one::<f64>("bandwidth_limit").expect("default");
+        target_bandwidth_mbps * 1_000_000.0 / 8.0
+    };
+    let percent = |name: &str| *args.get_one::<f64>(name).expect("default") / 100.0;
+    let milliseconds =
+        |name: &str| time::Duration::from_millis(*args.get_one::<u64>(name).expect("default"));
+    let burst_rate = percent("burst_rate");
+    let impairment = impair::Config {
+        seed: args.get_one::<u64>("seed").copied(),
+        loss: percent("loss"),
+        burst: (0.0 < burst_rate).then(|| impair::Burst {
+            rate: burst_rate,
+            length: f64::from(*args.get_one::<u32>("burst_length").expect("default")),
+            loss: percent("burst_loss"),
+        }),
+        duplicate: percent("duplicate"),
+        reorder: percent("reorder"),
+        reorder_delay: milliseconds("reorder_delay"),
+        delay: milliseconds("delay"),
+        jitter: time::Duration::from_micros(*args.get_one::<u64>("jitter").expect("default")),
+    };
+
+    loopback::Config {
+        from_tcp,
+        to_tcp,
+        mtu: *args.get_one::<u16>("mtu").expect("default"),
+        nb_clients: *args.get_one::<u16>("nb_clients").expect("default"),
+        encoding_block_size: *args.get_one::<u64>("encoding_block_size").expect("default"),
+        repair_block_size: *args.get_one::<u32>("repair_block_size").expect("default"),
+        udp_buffer_size: *args.get_one::<u32>("udp_buffer_size").expect("default"),
+        nb_encoding_threads: *args.get_one::<u8>("nb_encoding_threads").expect("default"),
+        nb_decoding_threads: *args.get_one::<u8>("nb_decoding_threads").expect("default"),
+        flush_timeout: time::Duration::from_millis(
+            args.get_one::<NonZeroU64>("flush_timeout")
+                .expect("default")
+                .get(),
+        ),
+        low_latency: args
+            .get_flag("low_latency")
+            .then(|| *args.get_one::<u32>("low_latency_repair").expect("default")),
+        heartbeat_interval: heartbeat,
+        bandwidth_limit,
+        impairment,
+    }
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        Ok(_) => Err(format!("percentage \"{s}\" is not between 0 and 100")),
+        Err(e) => Err(format!("invalid percentage \"{s}\": {e}")),
+    }
+}
+
+fn main() {
+    let config = command_args();
+
+    diode::init_logger();
+
+    if let Err(e) = loopback::run(config) {
+        log::error!("{e}");
+    }
+}
@@ -0,0 +1,55 @@
+use clap::{Arg, Command};
+use diode::quarantine;
+use std::{env, path, process, time};
+
+fn main() {
+    let args = Command::new(env!("CARGO_BIN_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Inspects the blocks quarantined by diode-receive")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("decode")
+                .about("Reports the symbols of a quarantined block and tries to decode it again")
+                .arg(
+                    Arg::new("file")
+                        .value_name("file")
+                        .required(true)
+                        .help("Quarantine file written by diode-receive"),
+                ),
+        )
+        .get_matches();
+
+    if let Some(("decode", args)) = args.subcommand() {
+        let file = path::PathBuf::from(args.get_one::<String>("file").expect("required"));
+
+        let block = match quarantine::Block::read(&file) {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!("failed to read {}: {e}", file.display());
+                process::exit(1);
+            }
+        };
+
+        let timestamp = block
+            .timestamp
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        println!("block: {}", block.block_id);
+        println!("timestamp: {timestamp:.3}");
+        println!("mtu: {}", block.geometry.mtu);
+        println!(
+            "encoding block size: {} bytes",
+            block.geometry.encoding_block_size
+        );
+        println!(
+            "repair block size: {} bytes",
+            block.geometry.repair_block_size
+        );
+        println!("forward error correction: {}", block.geometry.fec);
+        println!("symbol size: {} bytes", block.geometry.oti.symbol_size());
+        println!("packets: {}", block.packets.len());
+        println!("{}", block.analyze());
+    }
+}
@@ -1,6 +1,21 @@
 use clap::{Arg, ArgAction, Command};
-use diode::aux::{self, file};
-use std::{env, net, path, str::FromStr};
+use diode::{
+    aux::{self, file},
+    durable,
+};
+use std::{env, fs, net, path, process, str::FromStr};
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode \"{s}\"")),
+    }
+}
+
+fn parse_output_template(s: &str) -> Result<String, String> {
+    file::receive::check_output_template(s)?;
+    Ok(s.to_string())
+}
 
 fn main() {
     let args = Command::new(env!("CARGO_BIN_NAME"))
@@ -34,6 +49,102 @@ fn main() {
                 .value_parser(clap::value_parser!(bool))
                 .help("Verify the hash of file content (default is false)"),
         )
+        .arg(
+            Arg::new("require_fips_hash")
+                .long("require-fips-hash")
+                .action(ArgAction::SetTrue)
+                .help("Reject files not hashed with a FIPS approved algorithm, and verify the hash of accepted ones"),
+        )
+        .arg(
+            Arg::new("output_template")
+                .long("output_template")
+                .value_name("template")
+                .value_parser(parse_output_template)
+                .help("Store files in this subdirectory of the output directory, {site} and {date} being replaced by the site identifier of the sender and the date, the input starting with the session header of diode-receive"),
+        )
+        .arg(
+            Arg::new("quarantine_dir")
+                .long("quarantine_dir")
+                .value_name("dir")
+                .help("Store files rejected by --require-fips-hash in this directory instead of discarding them"),
+        )
+        .arg(
+            Arg::new("min_free_bytes")
+                .long("min_free_bytes")
+                .value_name("nb_bytes")
+                .default_value("104857600") // 100 * 1024 * 1024
+                .value_parser(clap::value_parser!(u64))
+                .help("Warn when the output directory filesystem has less free space"),
+        )
+        .arg(
+            Arg::new("min_free_inodes")
+                .long("min_free_inodes")
+                .value_name("nb")
+                .default_value("1024")
+                .value_parser(clap::value_parser!(u64))
+                .help("Warn when the output directory filesystem has less free inodes"),
+        )
+        .arg(
+            Arg::new("output_owner")
+                .long("output_owner")
+                .value_name("user:group")
+                .value_parser(clap::value_parser!(file::Owner))
+                .help("Owner and group given to received files, by name or identifier (requires CAP_CHOWN)"),
+        )
+        .arg(
+            Arg::new("output_file_mode")
+                .long("output_file_mode")
+                .value_name("octal_mode")
+                .value_parser(parse_mode)
+                .help("Mode given to received files instead of the mode sent with them"),
+        )
+        .arg(
+            Arg::new("overwrite")
+                .long("overwrite")
+                .value_name("fail|overwrite|rename")
+                .default_value("fail")
+                .value_parser(clap::value_parser!(file::Overwrite))
+                .help("Policy applied when a received file already exists: discard it, replace the existing one, or store it under a suffixed name"),
+        )
+        .arg(
+            Arg::new("preserve_owner")
+                .long("preserve_owner")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("output_owner")
+                .help("Give received files the owner and group identifiers they had on the sending side (requires CAP_CHOWN)"),
+        )
+        .arg(
+            Arg::new("xattrs")
+                .long("xattrs")
+                .action(ArgAction::SetTrue)
+                .help("Apply the extended attributes of the user namespace sent with the files"),
+        )
+        .arg(
+            Arg::new("manifest_key")
+                .long("manifest_key")
+                .value_name("file")
+                .help("Refuse manifests not signed with HMAC-SHA256 using the content of this file as key"),
+        )
+        .arg(
+            Arg::new("landlock")
+                .long("landlock")
+                .action(ArgAction::SetTrue)
+                .help("Forbid writes outside of the output and quarantine directories with Landlock once listening"),
+        )
+        .arg(
+            Arg::new("audit_log")
+                .long("audit_log")
+                .value_name("path")
+                .help("Path of a file to append a JSON record of every file received to"),
+        )
+        .arg(
+            Arg::new("audit_sync")
+                .long("audit_sync")
+                .value_name("none|interval:<nb_milliseconds>|records:<nb>|always")
+                .default_value("always")
+                .value_parser(durable::Policy::from_str)
+                .help("When records of the audit log are synchronized to disk"),
+        )
         .arg(
             Arg::new("output_directory")
                 .value_name("dir")
@@ -50,6 +161,28 @@ fn main() {
         .map(|s| path::PathBuf::from_str(s).expect("invalid from_unix parameter"));
     let buffer_size = *args.get_one::<usize>("buffer_size").expect("default");
     let hash = args.get_one::<bool>("hash").copied().expect("default");
+    let require_fips_hash = args.get_flag("require_fips_hash");
+    let quarantine_dir = args
+        .get_one::<String>("quarantine_dir")
+        .map(path::PathBuf::from);
+    let min_free_bytes = *args.get_one::<u64>("min_free_bytes").expect("default");
+    let min_free_inodes = *args.get_one::<u64>("min_free_inodes").expect("default");
+    let output_owner = args.get_one::<file::Owner>("output_owner").copied();
+    let output_file_mode = args.get_one::<u32>("output_file_mode").copied();
+    let overwrite = *args
+        .get_one::<file::Overwrite>("overwrite")
+        .expect("default");
+    let preserve_owner = args.get_flag("preserve_owner");
+    let xattrs = args.get_flag("xattrs");
+    let landlock = args.get_flag("landlock");
+    let audit_log = args.get_one::<String>("audit_log").map(path::PathBuf::from);
+    let audit_sync = *args
+        .get_one::<durable::Policy>("audit_sync")
+        .expect("default");
+    let output_template = args.get_one::<String>("output_template").cloned();
+    let manifest_key = args
+        .get_one::<String>("manifest_key")
+        .map(|path| fs::read(path).expect("failed to read manifest key"));
     let output_directory =
         path::PathBuf::from(args.get_one::<String>("output_directory").expect("default"));
 
@@ -62,10 +195,34 @@ fn main() {
         diode,
         buffer_size,
         hash,
+        hash_algorithm: file::hash::Algorithm::Murmur3,
+        resume: false,
+        recursive: false,
+        require_fips_hash,
+        quarantine_dir,
+        min_free_bytes,
+        min_free_inodes,
+        output_owner,
+        output_file_mode,
+        overwrite,
+        preserve_owner,
+        xattrs,
+        manifest_key,
+        landlock,
+        audit_log,
+        audit_sync,
+        output_template,
     };
 
     diode::init_logger();
 
+    if require_fips_hash && file::hash::SHA256_BACKEND.is_none() {
+        log::error!(
+            "--require-fips-hash needs a SHA-256 implementation built with the ring or sha2 feature"
+        );
+        process::exit(1);
+    }
+
     if let Err(e) = file::receive::receive_files(&config, &output_directory) {
         log::error!("{e}");
     }
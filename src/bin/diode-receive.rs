@@ -1,60 +1,181 @@
-use clap::{Arg, ArgGroup, Command};
-use diode::receive;
+use clap::{error::ErrorKind, Arg, ArgAction, ArgGroup, Command};
+use diode::{
+    aux::file::hash, cgroup, check, config_file, crypto, durable, failover, fec, metrics, protocol,
+    receive, resolver, scrub, sd_notify, server_sink,
+};
 use std::{
     env, fmt,
     io::{self, Write},
     net,
     num::NonZeroU64,
     os::{fd::AsRawFd, unix},
-    path,
+    path, process,
     str::FromStr,
     thread, time,
 };
 
 struct Config {
     from_udp: net::SocketAddr,
-    from_udp_mtu: u16,
+    /// `None` when the MTU is detected from the first packet
+    from_udp_mtu: Option<u16>,
+    from_udp_multicast_interface: Option<String>,
+    stripe_from_udp: Option<net::SocketAddr>,
+    /// Number of repair packets of the single packet blocks of the low latency mode
+    low_latency: Option<u32>,
     nb_clients: u16,
     encoding_block_size: u64,
     repair_block_size: u32,
+    fec: fec::Algorithm,
     udp_buffer_size: u32,
+    udp_poll_mode: receive::PollMode,
+    udp_gro: bool,
+    nb_udp_threads: u8,
+    queue_size: usize,
     flush_timeout: time::Duration,
+    reorder_max_mb: u64,
     nb_decoding_threads: u8,
+    decode_capacity_warning: f64,
+    loss_report_interval: Option<time::Duration>,
     to: ClientConfig,
+    /// Destinations of the channels other than the default channel 0, only TCP and Unix
+    channels: Vec<(protocol::Channel, ClientConfig)>,
     heartbeat: Option<time::Duration>,
+    events_socket: Option<path::PathBuf>,
+    audit_log: Option<path::PathBuf>,
+    audit_sync: durable::Policy,
+    control_socket: Option<path::PathBuf>,
+    metrics: Option<net::SocketAddr>,
+    metrics_log_interval: Option<time::Duration>,
+    log_format: diode::LogFormat,
+    log_file: Option<path::PathBuf>,
+    sd_watchdog: bool,
+    drain_timeout: time::Duration,
+    index_stream: Option<net::SocketAddr>,
+    cgroup: Option<path::PathBuf>,
+    memory_max: Option<u64>,
+    quarantine_dir: Option<path::PathBuf>,
+    quarantine_max_mb: u64,
+    session_header: bool,
+    session_trailer: Option<hash::Algorithm>,
+    store_and_forward: Option<receive::StoreAndForward>,
+    store_and_forward_max_mb: u64,
+    integrity: bool,
+    header_format: protocol::HeaderFormat,
+    psk_file: Option<path::PathBuf>,
+    allow_from: Vec<receive::Network>,
+    sender_config: SenderConfig,
+    force_best_effort: bool,
+    scrub_addresses: scrub::Mode,
+    scrub_key_file: Option<path::PathBuf>,
+    check: bool,
+    check_skip_destination: bool,
+    user: Option<String>,
+    group: Option<String>,
+    chroot: Option<path::PathBuf>,
 }
 
 enum ClientConfig {
-    Tcp(net::SocketAddr),
+    Tcp(resolver::Resolver),
+    Failover(failover::Failover),
     Unix(path::PathBuf),
+    /// Sessions written one after the other to the standard output
+    Stdout,
+    /// Shell command run for each session, reading its data on its standard input
+    Command(String),
+    /// Address, buffer size and buffer policy of the server sink, listening once started
+    Serve(net::SocketAddr, usize, server_sink::Policy),
 }
 
 impl fmt::Display for ClientConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
             Self::Tcp(s) => write!(f, "TCP {s}"),
+            Self::Failover(s) => write!(f, "TCP {s}"),
             Self::Unix(p) => write!(f, "Unix {}", p.display()),
+            Self::Stdout => write!(f, "standard output"),
+            Self::Command(command) => write!(f, "command \"{command}\""),
+            Self::Serve(address, _, _) => {
+                write!(f, "TCP consumers connecting to {}", scrub::addr(address))
+            }
         }
     }
 }
 
+/// How the configuration packets of the sender are used
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SenderConfig {
+    Ignore,
+    /// Wait for a first configuration packet and refuse to start on encoding parameters
+    /// inconsistent with the configuration
+    Check,
+    /// Wait for a first configuration packet and adopt its encoding parameters
+    Auto,
+}
+
+fn parse_sender_config(s: &str) -> Result<SenderConfig, String> {
+    match s {
+        "ignore" => Ok(SenderConfig::Ignore),
+        "check" => Ok(SenderConfig::Check),
+        "auto" => Ok(SenderConfig::Auto),
+        _ => Err(format!("unknown sender configuration mode \"{s}\"")),
+    }
+}
+
+fn parse_mtu(s: &str) -> Result<Option<u16>, String> {
+    if s == "auto" {
+        return Ok(None);
+    }
+    s.parse()
+        .map(Some)
+        .map_err(|e| format!("invalid MTU \"{s}\": {e}"))
+}
+
+/// Murmur3 is not accepted since its digests depend on how the data is split into blocks
+fn parse_trailer_hash(s: &str) -> Result<hash::Algorithm, String> {
+    match hash::Algorithm::from_str(s)? {
+        hash::Algorithm::Murmur3 => Err("murmur3 cannot be used in session trailers".to_string()),
+        algorithm => Ok(algorithm),
+    }
+}
+
 fn command_args() -> Config {
-    let args = Command::new(env!("CARGO_BIN_NAME"))
+    let command = Command::new(env!("CARGO_BIN_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
+        .args_override_self(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("path")
+                .help("TOML file setting parameters by name, command line parameters taking precedence"),
+        )
         .arg(
             Arg::new("from_udp")
                 .long("from_udp")
                 .value_name("ip:port")
                 .default_value("127.0.0.1:6000")
-                .help("IP address and port where to receive UDP packets from diode-send"),
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port where to receive UDP packets from diode-send, joining the group if it is a multicast address"),
+        )
+        .arg(
+            Arg::new("from_udp_multicast_interface")
+                .long("from_udp_multicast_interface")
+                .value_name("ifname")
+                .help("Network interface on which to join from_udp when it is a multicast group, instead of the one chosen by the kernel"),
+        )
+        .arg(
+            Arg::new("stripe_from_udp")
+                .long("stripe_from_udp")
+                .value_name("ip:port")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port where to listen for the UDP packets the sender stripes over a second diode link"),
         )
         .arg(
             Arg::new("from_udp_mtu")
                 .long("from_udp_mtu")
-                .value_name("nb_bytes")
+                .value_name("nb_bytes|auto")
                 .default_value("1500") // mtu
-                .value_parser(clap::value_parser!(u16))
-                .help("MTU of the input UDP link"),
+                .value_parser(parse_mtu)
+                .help("MTU of the input UDP link, auto to detect it from the first packet received"),
         )
         .arg(
             Arg::new("nb_clients")
@@ -72,6 +193,39 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(u8))
                 .help("Number of parallel RaptorQ decoding threads"),
         )
+        .arg(
+            Arg::new("nb_udp_threads")
+                .long("nb_udp_threads")
+                .value_name("nb")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u8))
+                .help("Number of threads receiving datagrams, each on its own socket bound with SO_REUSEPORT"),
+        )
+        .arg(
+            Arg::new("queue_size")
+                .long("queue_size")
+                .value_name("nb_blocks")
+                .default_value("1024") // receive::DEFAULT_QUEUE_SIZE
+                .value_parser(clap::value_parser!(usize))
+                .help("Number of blocks queued between threads and for each transfer, datagrams being dropped when the queues are full"),
+        )
+        .arg(
+            Arg::new("decode_capacity_warning")
+                .long("decode_capacity_warning")
+                .value_name("ratio")
+                .default_value("0.7") // receive::DEFAULT_DECODE_CAPACITY_WARNING
+                .value_parser(clap::value_parser!(f64))
+                .help("Warn when decoding a block takes more than this ratio of the time available to decoding threads for each block, 0 to disable"),
+        )
+        .arg(
+            Arg::new("loss_report_interval")
+                .long("loss_report_interval")
+                .value_name("nb_seconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .conflicts_with("low_latency")
+                .help("Interval between two reports of the packets missing from blocks, with the repair block size recovering them, 0 to disable"),
+        )
         .arg(
             Arg::new("encoding_block_size")
                 .long("encoding_block_size")
@@ -88,6 +242,29 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(u32))
                 .help("Size of repair data in bytes"),
         )
+        .arg(
+            Arg::new("fec")
+                .long("fec")
+                .value_name("raptorq|rs|none")
+                .default_value("raptorq")
+                .value_parser(clap::value_parser!(fec::Algorithm))
+                .help("Forward error correction code of the blocks, the same on both ends: RaptorQ, Reed-Solomon for blocks of at most 256 packets, or none"),
+        )
+        .arg(
+            Arg::new("low_latency")
+                .long("low_latency")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["encoding_block_size", "repair_block_size"])
+                .help("Send each block as a single packet, delivered as soon as it is received"),
+        )
+        .arg(
+            Arg::new("low_latency_repair")
+                .long("low_latency_repair")
+                .value_name("nb_packets")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32))
+                .help("Number of repair packets of each block with low_latency"),
+        )
         .arg(
             Arg::new("udp_buffer_size")
                 .long("udp_buffer_size")
@@ -96,6 +273,20 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(u32).range(..1073741824))
                 .help("Size of UDP socket recv buffer"),
         )
+        .arg(
+            Arg::new("udp_poll_mode")
+                .long("udp_poll_mode")
+                .value_name("blocking|busy[:spin_us]")
+                .default_value("blocking")
+                .value_parser(clap::value_parser!(receive::PollMode))
+                .help("Wait for UDP packets in blocking calls, or poll for them spinning during spin_us microseconds (default 50) before sleeping"),
+        )
+        .arg(
+            Arg::new("udp_gro")
+                .long("udp_gro")
+                .action(ArgAction::SetTrue)
+                .help("Let the kernel coalesce received datagrams (UDP_GRO) to receive them with fewer system calls, when supported"),
+        )
         .arg(
             Arg::new("flush_timeout")
                 .long("flush_timeout")
@@ -104,11 +295,50 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(NonZeroU64))
                 .help("Flush pending data after duration"),
         )
+        .arg(
+            Arg::new("reorder_max_mb")
+                .long("reorder_max_mb")
+                .value_name("nb_megabytes")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Size of the blocks received ahead of a missing one above which it is given up, 0 for no limit"),
+        )
         .arg(
             Arg::new("to_tcp")
                 .long("to_tcp")
-                .value_name("ip:port")
-                .help("IP address and port to connect to TCP server"),
+                .value_name("host:port")
+                .help("Host name or IP address and port to connect to TCP server"),
+        )
+        .arg(
+            Arg::new("to_tcp_resolve_ttl")
+                .long("to_tcp_resolve_ttl")
+                .value_name("nb_seconds")
+                .default_value("60")
+                .value_parser(clap::value_parser!(u64))
+                .help("Duration after which the to_tcp host name is resolved again"),
+        )
+        .arg(
+            Arg::new("to_tcp_failover")
+                .long("to_tcp_failover")
+                .value_name("host:port")
+                .requires("to_tcp")
+                .help("Host name or IP address and port of the TCP server to deliver to while to_tcp is failing"),
+        )
+        .arg(
+            Arg::new("failover_after")
+                .long("failover_after")
+                .value_name("nb_failures")
+                .default_value("3")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Number of consecutive to_tcp failures after which sessions are delivered to to_tcp_failover"),
+        )
+        .arg(
+            Arg::new("failback_after")
+                .long("failback_after")
+                .value_name("nb_seconds")
+                .default_value("30")
+                .value_parser(clap::value_parser!(u64))
+                .help("Interval between checks of to_tcp availability while failed over"),
         )
         .arg(
             Arg::new("to_unix")
@@ -116,10 +346,60 @@ fn command_args() -> Config {
                 .value_name("path")
                 .help("Path of socket to connect to Unix server"),
         )
+        .arg(
+            Arg::new("to_stdout")
+                .long("to_stdout")
+                .action(ArgAction::SetTrue)
+                .help("Write the data of the transfers one after the other to the standard output, requires nb_clients to be 1"),
+        )
+        .arg(
+            Arg::new("to_cmd")
+                .long("to_cmd")
+                .value_name("command")
+                .help("Shell command run for each transfer, reading its data on its standard input"),
+        )
+        .arg(
+            Arg::new("serve_tcp")
+                .long("serve_tcp")
+                .value_name("ip:port")
+                .help("IP address and port to accept TCP connections from consumers, one per transfer"),
+        )
+        .arg(
+            Arg::new("serve_buffer_size")
+                .long("serve_buffer_size")
+                .value_name("nb_bytes")
+                .default_value("67108864") // 64 * 1024 * 1024
+                .value_parser(clap::value_parser!(usize))
+                .help("Maximum data of a transfer buffered while no consumer is connected"),
+        )
+        .arg(
+            Arg::new("serve_buffer_policy")
+                .long("serve_buffer_policy")
+                .value_name("block|drop_oldest")
+                .default_value("block")
+                .value_parser(clap::value_parser!(server_sink::Policy))
+                .help("Behavior when the serve buffer is full"),
+        )
         .group(
             ArgGroup::new("to")
                 .required(true)
-                .args(["to_tcp", "to_unix"]),
+                .args(["to_tcp", "to_unix", "to_stdout", "to_cmd", "serve_tcp"]),
+        )
+        .arg(
+            Arg::new("to_channel_tcp")
+                .long("to_channel_tcp")
+                .value_name("channel:host:port")
+                .action(ArgAction::Append)
+                .value_parser(protocol::parse_channel)
+                .help("Channel (1 to 255) and host name or IP address and port of the TCP server to deliver the transfers of this channel to (may be repeated)"),
+        )
+        .arg(
+            Arg::new("to_channel_unix")
+                .long("to_channel_unix")
+                .value_name("channel:path")
+                .action(ArgAction::Append)
+                .value_parser(protocol::parse_channel)
+                .help("Channel (1 to 255) and path of socket of the Unix server to deliver the transfers of this channel to (may be repeated)"),
         )
         .arg(
             Arg::new("heartbeat")
@@ -129,70 +409,529 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(u16))
                 .help("Maximum duration expected between heartbeat messages, 0 to disable"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("events_socket")
+                .long("events_socket")
+                .value_name("path")
+                .help("Path of Unix socket to publish NDJSON lifecycle events"),
+        )
+        .arg(
+            Arg::new("audit_log")
+                .long("audit_log")
+                .value_name("path")
+                .help("Path of a file to append a JSON record of every transfer to, once it ended"),
+        )
+        .arg(
+            Arg::new("audit_sync")
+                .long("audit_sync")
+                .value_name("none|interval:<nb_milliseconds>|records:<nb>|always")
+                .default_value("always")
+                .value_parser(durable::Policy::from_str)
+                .help("When records of the audit log are synchronized to disk"),
+        )
+        .arg(
+            Arg::new("control_socket")
+                .long("control_socket")
+                .value_name("path")
+                .help("Path of Unix socket to accept administration commands (status_json, set log_level|flush_timeout <value>)"),
+        )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .value_name("ip:port")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port where to serve metrics to Prometheus scrapes"),
+        )
+        .arg(
+            Arg::new("metrics_log_interval")
+                .long("metrics_log_interval")
+                .value_name("nb_seconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Interval between two writes of every metric to the logs, 0 to disable"),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log_format")
+                .value_name("text|json")
+                .default_value("text")
+                .value_parser(clap::value_parser!(diode::LogFormat))
+                .help("Format of the log records, json writing one object per line for log collectors"),
+        )
+        .arg(
+            Arg::new("log_file")
+                .long("log_file")
+                .value_name("path")
+                .help("Path of a file to append the log records to, instead of writing them to the terminal"),
+        )
+        .arg(
+            Arg::new("no_sd_watchdog")
+                .long("no-sd-watchdog")
+                .action(ArgAction::SetTrue)
+                .help("Do not send notifications to the systemd watchdog"),
+        )
+        .arg(
+            Arg::new("drain_timeout")
+                .long("drain_timeout")
+                .value_name("nb_seconds")
+                .default_value("30")
+                .value_parser(clap::value_parser!(u64))
+                .help("Maximum time to wait for transfers in progress to complete on SIGTERM or SIGINT before exiting"),
+        )
+        .arg(
+            Arg::new("index_stream")
+                .long("index_stream")
+                .value_name("ip:port")
+                .help("IP address and port to connect to publish the index of delivered blocks"),
+        )
+        .arg(
+            Arg::new("cgroup")
+                .long("cgroup")
+                .value_name("path")
+                .help("Path of a delegated cgroup v2 directory to place the process in at startup"),
+        )
+        .arg(
+            Arg::new("memory_max")
+                .long("memory_max")
+                .value_name("nb_bytes")
+                .requires("cgroup")
+                .value_parser(cgroup::parse_size)
+                .help("Memory limit of the cgroup, with an optional K, M, G or T suffix"),
+        )
+        .arg(
+            Arg::new("quarantine_dir")
+                .long("quarantine_dir")
+                .value_name("path")
+                .help("Directory where to write the packets of the blocks which cannot be decoded"),
+        )
+        .arg(
+            Arg::new("quarantine_max_mb")
+                .long("quarantine_max_mb")
+                .value_name("nb_megabytes")
+                .default_value("1024")
+                .value_parser(clap::value_parser!(u64))
+                .help("Size of the quarantine directory above which the oldest files are removed"),
+        )
+        .arg(
+            Arg::new("session_header")
+                .long("session_header")
+                .action(ArgAction::SetTrue)
+                .help("Start the output of every session with a header giving the site identifier of the sender"),
+        )
+        .arg(
+            Arg::new("session_trailer")
+                .long("session_trailer")
+                .action(ArgAction::SetTrue)
+                .help("End the output of every session with a trailer giving its status and length"),
+        )
+        .arg(
+            Arg::new("session_trailer_hash")
+                .long("session_trailer_hash")
+                .value_name("none|sha256")
+                .default_value("none")
+                .value_parser(parse_trailer_hash)
+                .requires("session_trailer")
+                .help("Hash algorithm of the session data digest written in the trailer"),
+        )
+        .arg(
+            Arg::new("store_and_forward")
+                .long("store_and_forward")
+                .value_name("memory|disk")
+                .value_parser(["memory", "disk"])
+                .help("Deliver each session only once it completed, holding its data in memory or on disk"),
+        )
+        .arg(
+            Arg::new("store_and_forward_dir")
+                .long("store_and_forward_dir")
+                .value_name("path")
+                .required_if_eq("store_and_forward", "disk")
+                .help("Directory where to hold the data of sessions on disk"),
+        )
+        .arg(
+            Arg::new("store_and_forward_max_mb")
+                .long("store_and_forward_max_mb")
+                .value_name("nb_megabytes")
+                .default_value("64")
+                .value_parser(clap::value_parser!(u64))
+                .requires("store_and_forward")
+                .help("Size of the data of a session above which it is discarded in store and forward mode"),
+        )
+        .arg(
+            Arg::new("integrity")
+                .long("integrity")
+                .action(ArgAction::SetTrue)
+                .help("Verify the checksum ending each block, the sender must also be run with --integrity"),
+        )
+        .arg(
+            Arg::new("wide_block_ids")
+                .long("wide_block_ids")
+                .action(ArgAction::SetTrue)
+                .help("Read 16-bit block identifiers in packet headers, the sender must also be run with --wide_block_ids"),
+        )
+        .arg(
+            Arg::new("psk_file")
+                .long("psk_file")
+                .value_name("path")
+                .help("File containing the pre-shared key decrypting datagrams with XChaCha20-Poly1305, the sender must also be run with it"),
+        )
+        .arg(
+            Arg::new("allow_from")
+                .long("allow_from")
+                .value_name("address/prefix_len")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(receive::Network))
+                .help("Network datagrams are accepted from, in CIDR notation, datagrams from other sources being dropped (may be repeated)"),
+        )
+        .arg(
+            Arg::new("sender_config")
+                .long("sender_config")
+                .value_name("ignore|check|auto")
+                .default_value("ignore")
+                .value_parser(parse_sender_config)
+                .help("Use of the configuration packets of a sender run with --config_broadcast: wait for one and refuse to start on mismatch, or adopt its MTU, block sizes, forward error correction, block ids format and integrity"),
+        )
+        .arg(
+            Arg::new("force_best_effort")
+                .long("force_best_effort")
+                .action(ArgAction::SetTrue)
+                .help("Read the packets of a sender announcing an incompatible protocol version instead of refusing them"),
+        )
+        .arg(
+            Arg::new("version_compat")
+                .long("version_compat")
+                .action(ArgAction::SetTrue)
+                .help("Print the protocol versions this receiver reads, the features and the wire formats, and exit"),
+        )
+        .arg(
+            Arg::new("scrub_addresses")
+                .long("scrub_addresses")
+                .value_name("hash|redact|off")
+                .default_value("off")
+                .value_parser(clap::value_parser!(scrub::Mode))
+                .help("How IP addresses appear in logs and events: keyed hash, network only or as is"),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .action(ArgAction::SetTrue)
+                .help("Check the runtime prerequisites, print the results and exit instead of starting"),
+        )
+        .arg(
+            Arg::new("check_skip_destination")
+                .long("check_skip_destination")
+                .action(ArgAction::SetTrue)
+                .requires("check")
+                .help("Do not connect to the destination and index stream when checking"),
+        )
+        .arg(
+            Arg::new("scrub_key_file")
+                .long("scrub_key_file")
+                .value_name("path")
+                .help("File containing the key of address hashes, a random key being used otherwise"),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .value_name("name")
+                .help("User to switch to once sockets are bound, by name or numeric identifier"),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .value_name("name")
+                .help("Group to switch to once sockets are bound, the primary group of --user by default"),
+        )
+        .arg(
+            Arg::new("chroot")
+                .long("chroot")
+                .value_name("path")
+                .help("Directory to confine the process in once sockets are bound"),
+        );
+    let mut command = config_file::command(command);
+    let args = config_file::args(&command, env::args_os())
+        .unwrap_or_else(|e| command.error(ErrorKind::Io, e).exit());
+    // printed without requiring a destination, like --version
+    let version_compat = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .is_ok_and(|args| args.get_flag("version_compat"));
+    if version_compat {
+        print!("{}", protocol::compatibility_matrix());
+        process::exit(0);
+    }
+    let args = command.get_matches_from(args);
 
-    let from_udp = net::SocketAddr::from_str(args.get_one::<String>("from_udp").expect("default"))
-        .expect("invalid from_udp parameter");
-    let from_udp_mtu = *args.get_one::<u16>("from_udp_mtu").expect("default");
+    let from_udp = *args
+        .get_one::<net::SocketAddr>("from_udp")
+        .expect("default");
+    let from_udp_mtu = *args
+        .get_one::<Option<u16>>("from_udp_mtu")
+        .expect("default");
+    let from_udp_multicast_interface = args
+        .get_one::<String>("from_udp_multicast_interface")
+        .cloned();
+    let stripe_from_udp = args.get_one::<net::SocketAddr>("stripe_from_udp").copied();
     let nb_clients = *args.get_one::<u16>("nb_clients").expect("default");
     let nb_decoding_threads = *args.get_one::<u8>("nb_decoding_threads").expect("default");
+    let decode_capacity_warning = *args
+        .get_one::<f64>("decode_capacity_warning")
+        .expect("default");
+    let loss_report_interval = {
+        let interval = *args
+            .get_one::<u64>("loss_report_interval")
+            .expect("default");
+        (interval != 0).then(|| time::Duration::from_secs(interval))
+    };
     let encoding_block_size = *args.get_one::<u64>("encoding_block_size").expect("default");
     let udp_buffer_size = *args.get_one::<u32>("udp_buffer_size").expect("default");
+    let udp_poll_mode = *args
+        .get_one::<receive::PollMode>("udp_poll_mode")
+        .expect("default");
+    let udp_gro = args.get_flag("udp_gro");
+    let nb_udp_threads = *args.get_one::<u8>("nb_udp_threads").expect("default");
+    let queue_size = *args.get_one::<usize>("queue_size").expect("default");
     let repair_block_size = *args.get_one::<u32>("repair_block_size").expect("default");
+    let fec = *args.get_one::<fec::Algorithm>("fec").expect("default");
+    let low_latency = args
+        .get_flag("low_latency")
+        .then(|| *args.get_one::<u32>("low_latency_repair").expect("default"));
     let flush_timeout = time::Duration::from_millis(
         args.get_one::<NonZeroU64>("flush_timeout")
             .expect("default")
             .get(),
     );
-    let to_tcp = args
-        .get_one::<String>("to_tcp")
-        .map(|s| net::SocketAddr::from_str(s).expect("to_tcp must be of the form ip:port"));
+    let reorder_max_mb = *args.get_one::<u64>("reorder_max_mb").expect("default");
+    let to_tcp_resolve_ttl =
+        time::Duration::from_secs(*args.get_one::<u64>("to_tcp_resolve_ttl").expect("default"));
+    let resolver = |name: &str, s: &str| {
+        if !s
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        {
+            panic!("{name} must be of the form host:port");
+        }
+        resolver::Resolver::new(s.to_string(), to_tcp_resolve_ttl)
+    };
+    let tcp_resolver = |name: &str| args.get_one::<String>(name).map(|s| resolver(name, s));
+    let to_tcp = tcp_resolver("to_tcp");
+    let to_tcp_failover = tcp_resolver("to_tcp_failover");
+    let failover_after = *args.get_one::<u32>("failover_after").expect("default");
+    let failback_after =
+        time::Duration::from_secs(*args.get_one::<u64>("failback_after").expect("default"));
     let to_unix = args
         .get_one::<String>("to_unix")
         .map(|s| path::PathBuf::from_str(s).expect("to_unix must point to a valid path"));
+    let mut channels = Vec::new();
+    for (channel, address) in args
+        .get_many::<(protocol::Channel, String)>("to_channel_tcp")
+        .unwrap_or_default()
+    {
+        let resolver = resolver("to_channel_tcp", address);
+        channels.push((*channel, ClientConfig::Tcp(resolver)));
+    }
+    for (channel, path) in args
+        .get_many::<(protocol::Channel, String)>("to_channel_unix")
+        .unwrap_or_default()
+    {
+        channels.push((*channel, ClientConfig::Unix(path::PathBuf::from(path))));
+    }
 
     let heartbeat = {
         let hb = *args.get_one::<u16>("heartbeat").expect("default") as u64;
         (hb != 0).then(|| time::Duration::from_secs(hb))
     };
 
+    let events_socket = args
+        .get_one::<String>("events_socket")
+        .map(|s| path::PathBuf::from_str(s).expect("events_socket must point to a valid path"));
+    let audit_log = args.get_one::<String>("audit_log").map(path::PathBuf::from);
+    let audit_sync = *args
+        .get_one::<durable::Policy>("audit_sync")
+        .expect("default");
+    let control_socket = args
+        .get_one::<String>("control_socket")
+        .map(|s| path::PathBuf::from_str(s).expect("invalid control_socket parameter"));
+    let metrics = args.get_one::<net::SocketAddr>("metrics").copied();
+    let metrics_log_interval = {
+        let interval = *args
+            .get_one::<u64>("metrics_log_interval")
+            .expect("default");
+        (interval != 0).then(|| time::Duration::from_secs(interval))
+    };
+    let log_format = *args
+        .get_one::<diode::LogFormat>("log_format")
+        .expect("default");
+    let log_file = args.get_one::<String>("log_file").map(path::PathBuf::from);
+
+    let sd_watchdog = !args.get_flag("no_sd_watchdog");
+    let drain_timeout =
+        time::Duration::from_secs(*args.get_one::<u64>("drain_timeout").expect("default"));
+
+    let index_stream = args
+        .get_one::<String>("index_stream")
+        .map(|s| net::SocketAddr::from_str(s).expect("index_stream must be of the form ip:port"));
+
+    let cgroup = args.get_one::<String>("cgroup").map(path::PathBuf::from);
+    let memory_max = args.get_one::<u64>("memory_max").copied();
+
+    let quarantine_dir = args
+        .get_one::<String>("quarantine_dir")
+        .map(path::PathBuf::from);
+    let quarantine_max_mb = *args.get_one::<u64>("quarantine_max_mb").expect("default");
+    let session_header = args.get_flag("session_header");
+    let session_trailer = args.get_flag("session_trailer").then(|| {
+        *args
+            .get_one::<hash::Algorithm>("session_trailer_hash")
+            .expect("default")
+    });
+    let store_and_forward =
+        args.get_one::<String>("store_and_forward")
+            .map(|mode| match mode.as_str() {
+                "disk" => receive::StoreAndForward::Disk(path::PathBuf::from(
+                    args.get_one::<String>("store_and_forward_dir")
+                        .expect("required"),
+                )),
+                _ => receive::StoreAndForward::Memory,
+            });
+    let store_and_forward_max_mb = *args
+        .get_one::<u64>("store_and_forward_max_mb")
+        .expect("default");
+    let integrity = args.get_flag("integrity");
+    let header_format = if args.get_flag("wide_block_ids") {
+        protocol::HeaderFormat::Wide
+    } else {
+        protocol::HeaderFormat::Narrow
+    };
+    let psk_file = args.get_one::<String>("psk_file").map(path::PathBuf::from);
+    let allow_from = args
+        .get_many::<receive::Network>("allow_from")
+        .unwrap_or_default()
+        .copied()
+        .collect();
+    let sender_config = *args
+        .get_one::<SenderConfig>("sender_config")
+        .expect("default");
+    let scrub_addresses = *args
+        .get_one::<scrub::Mode>("scrub_addresses")
+        .expect("default");
+    let scrub_key_file = args
+        .get_one::<String>("scrub_key_file")
+        .map(path::PathBuf::from);
+
+    let serve_tcp = args.get_one::<String>("serve_tcp").map(|s| {
+        let address = net::SocketAddr::from_str(s).expect("serve_tcp must be of the form ip:port");
+        let buffer_size = *args.get_one::<usize>("serve_buffer_size").expect("default");
+        let policy = *args
+            .get_one::<server_sink::Policy>("serve_buffer_policy")
+            .expect("default");
+        (address, buffer_size, policy)
+    });
+
     let to = if let Some(to_tcp) = to_tcp {
-        ClientConfig::Tcp(to_tcp)
+        match to_tcp_failover {
+            Some(to_tcp_failover) => ClientConfig::Failover(failover::Failover::new(
+                to_tcp,
+                to_tcp_failover,
+                failover_after,
+                failback_after,
+            )),
+            None => ClientConfig::Tcp(to_tcp),
+        }
+    } else if let Some((address, buffer_size, policy)) = serve_tcp {
+        ClientConfig::Serve(address, buffer_size, policy)
+    } else if args.get_flag("to_stdout") {
+        ClientConfig::Stdout
+    } else if let Some(command) = args.get_one::<String>("to_cmd") {
+        ClientConfig::Command(command.clone())
     } else {
-        ClientConfig::Unix(to_unix.expect("to_tcp and to_unix are mutually exclusive"))
+        ClientConfig::Unix(to_unix.expect("destinations are mutually exclusive"))
     };
 
     Config {
         from_udp,
         from_udp_mtu,
+        from_udp_multicast_interface,
+        stripe_from_udp,
+        low_latency,
         nb_clients,
         nb_decoding_threads,
+        decode_capacity_warning,
+        loss_report_interval,
         encoding_block_size,
         repair_block_size,
+        fec,
         udp_buffer_size,
+        udp_poll_mode,
+        udp_gro,
+        nb_udp_threads,
+        queue_size,
         flush_timeout,
+        reorder_max_mb,
         to,
+        channels,
         heartbeat,
+        events_socket,
+        audit_log,
+        audit_sync,
+        control_socket,
+        metrics,
+        metrics_log_interval,
+        log_format,
+        log_file,
+        sd_watchdog,
+        drain_timeout,
+        index_stream,
+        cgroup,
+        memory_max,
+        quarantine_dir,
+        quarantine_max_mb,
+        session_header,
+        session_trailer,
+        store_and_forward,
+        store_and_forward_max_mb,
+        integrity,
+        header_format,
+        psk_file,
+        allow_from,
+        sender_config,
+        force_best_effort: args.get_flag("force_best_effort"),
+        scrub_addresses,
+        scrub_key_file,
+        check: args.get_flag("check"),
+        check_skip_destination: args.get_flag("check_skip_destination"),
+        user: args.get_one::<String>("user").cloned(),
+        group: args.get_one::<String>("group").cloned(),
+        chroot: args.get_one::<String>("chroot").map(path::PathBuf::from),
     }
 }
 
 enum Client {
     Tcp(net::TcpStream),
+    Failover(failover::Stream),
     Unix(unix::net::UnixStream),
+    Stdout(io::Stdout),
+    Command(Subprocess),
 }
 
 impl Write for Client {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         match self {
             Self::Tcp(socket) => socket.write(buf),
+            Self::Failover(socket) => socket.write(buf),
             Self::Unix(socket) => socket.write(buf),
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::Command(subprocess) => subprocess.stdin().write(buf),
         }
     }
 
     fn flush(&mut self) -> Result<(), std::io::Error> {
         match self {
             Self::Tcp(socket) => socket.flush(),
+            Self::Failover(socket) => socket.flush(),
             Self::Unix(socket) => socket.flush(),
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::Command(subprocess) => subprocess.stdin().flush(),
         }
     }
 }
@@ -201,53 +940,680 @@ impl AsRawFd for Client {
     fn as_raw_fd(&self) -> i32 {
         match self {
             Self::Tcp(socket) => socket.as_raw_fd(),
+            Self::Failover(socket) => socket.as_raw_fd(),
             Self::Unix(socket) => socket.as_raw_fd(),
+            Self::Stdout(stdout) => stdout.as_raw_fd(),
+            Self::Command(subprocess) => subprocess
+                .0
+                .stdin
+                .as_ref()
+                .map_or(-1, |stdin| stdin.as_raw_fd()),
         }
     }
 }
 
-impl TryFrom<&ClientConfig> for Client {
-    type Error = io::Error;
+/// Command run for a transfer by `/bin/sh`, waited for once its standard input is closed at the
+/// end of the transfer
+struct Subprocess(process::Child);
 
-    fn try_from(config: &ClientConfig) -> Result<Self, Self::Error> {
-        match config {
-            ClientConfig::Tcp(s) => {
-                let client = net::TcpStream::connect(s)?;
-                Ok(Self::Tcp(client))
+impl Subprocess {
+    fn spawn(command: &str) -> Result<Self, io::Error> {
+        let child = process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(process::Stdio::piped())
+            .spawn()?;
+        Ok(Self(child))
+    }
+
+    fn stdin(&mut self) -> &mut process::ChildStdin {
+        self.0.stdin.as_mut().expect("piped standard input")
+    }
+}
+
+impl Drop for Subprocess {
+    fn drop(&mut self) {
+        drop(self.0.stdin.take());
+        match self.0.wait() {
+            Ok(status) if !status.success() => {
+                log::warn!("command (pid {}) exited with {status}", self.0.id());
             }
+            Ok(_) => (),
+            Err(e) => log::warn!("failed to wait for command (pid {}): {e}", self.0.id()),
+        }
+    }
+}
+
+/// Connects to the destination of a new transfer, `sink` being the server sink started for
+/// [ClientConfig::Serve]
+fn connect(to: &ClientConfig, sink: Option<&server_sink::ServerSink>) -> Result<Client, io::Error> {
+    match to {
+        ClientConfig::Tcp(resolver) => {
+            let client = resolver.connect()?;
+            Ok(Client::Tcp(client))
+        }
+        ClientConfig::Failover(failover) => {
+            let client = failover.connect()?;
+            Ok(Client::Failover(client))
+        }
+        ClientConfig::Unix(p) => {
+            let client = unix::net::UnixStream::connect(p)?;
+            Ok(Client::Unix(client))
+        }
+        ClientConfig::Stdout => Ok(Client::Stdout(io::stdout())),
+        ClientConfig::Command(command) => {
+            let client = Subprocess::spawn(command)?;
+            Ok(Client::Command(client))
+        }
+        ClientConfig::Serve(..) => {
+            let client = sink.expect("server sink started").session()?;
+            Ok(Client::Unix(client))
+        }
+    }
+}
+
+/// MTU from which the size of packets is computed on a link of `mtu` bytes, see
+/// [protocol::packet_mtu], leaving room for the encryption overhead
+fn packet_mtu(config: &Config, mtu: u16) -> u16 {
+    let mtu = protocol::packet_mtu(mtu, &config.from_udp);
+    if config.psk_file.is_some() {
+        crypto::unsealed_mtu(mtu)
+    } else {
+        mtu
+    }
+}
+
+/// Checks every runtime prerequisite of `config`, without starting the receiver
+fn prerequisite_checks(config: &Config) -> Vec<check::Check> {
+    let mut checks = vec![check::udp_bind("from_udp", config.from_udp)];
+
+    let (encoding_block_size, repair_block_size) = match config.low_latency {
+        Some(nb_repair_packets) => protocol::single_packet_block_sizes(
+            config.header_format,
+            packet_mtu(config, config.from_udp_mtu.unwrap_or(1500)),
+            nb_repair_packets,
+        ),
+        None => (config.encoding_block_size, config.repair_block_size),
+    };
+    checks.push(check::udp_recv_buffer(
+        "udp_buffer_size",
+        config.udp_buffer_size,
+        2 * (encoding_block_size + u64::from(repair_block_size)),
+    ));
+
+    let skipped = "check_skip_destination is set";
+    match &config.to {
+        ClientConfig::Tcp(_) | ClientConfig::Failover(_) | ClientConfig::Unix(_)
+            if config.check_skip_destination =>
+        {
+            checks.push(check::Check::skipped("destination", skipped));
+        }
+        ClientConfig::Tcp(resolver) => {
+            checks.push(check::connect("to_tcp", resolver, || resolver.connect()));
+        }
+        ClientConfig::Failover(failover) => {
+            checks.push(check::connect("to_tcp_failover", failover, || {
+                failover.connect()
+            }));
+        }
+        ClientConfig::Unix(p) => checks.push(check::connect("to_unix", p.display(), || {
+            unix::net::UnixStream::connect(p)
+        })),
+        ClientConfig::Serve(address, _, _) => checks.push(check::tcp_bind("serve_tcp", *address)),
+        ClientConfig::Stdout | ClientConfig::Command(_) => (),
+    }
+
+    for (channel, to) in &config.channels {
+        let name = format!("channel {channel} destination");
+        checks.push(match to {
+            _ if config.check_skip_destination => check::Check::skipped(name, skipped),
+            ClientConfig::Tcp(resolver) => check::connect(&name, resolver, || resolver.connect()),
             ClientConfig::Unix(p) => {
-                let client = unix::net::UnixStream::connect(p)?;
-                Ok(Self::Unix(client))
+                check::connect(&name, p.display(), || unix::net::UnixStream::connect(p))
+            }
+            _ => unreachable!("channels are delivered to TCP or Unix servers"),
+        });
+    }
+
+    if let Some(index_stream) = config.index_stream {
+        checks.push(if config.check_skip_destination {
+            check::Check::skipped("index_stream", skipped)
+        } else {
+            check::connect("index_stream", index_stream, || {
+                net::TcpStream::connect(index_stream)
+            })
+        });
+    }
+
+    if let Some(events_socket) = &config.events_socket {
+        checks.push(check::unix_bind("events_socket", events_socket));
+    }
+
+    if let Some(control_socket) = &config.control_socket {
+        checks.push(check::unix_bind("control_socket", control_socket));
+    }
+
+    if let Some(metrics) = config.metrics {
+        checks.push(check::tcp_bind("metrics", metrics));
+    }
+
+    if let Some(quarantine_dir) = &config.quarantine_dir {
+        checks.push(check::writable_dir(
+            "quarantine_dir",
+            quarantine_dir,
+            config.quarantine_max_mb * 1024 * 1024,
+        ));
+    }
+
+    if let Some(receive::StoreAndForward::Disk(dir)) = &config.store_and_forward {
+        checks.push(check::writable_dir(
+            "store_and_forward_dir",
+            dir,
+            config.store_and_forward_max_mb * 1024 * 1024,
+        ));
+    }
+
+    if let Some(cgroup) = &config.cgroup {
+        let mut files = vec![cgroup.join("cgroup.procs")];
+        if config.memory_max.is_some() {
+            files.push(cgroup.join("memory.max"));
+            files.push(cgroup.join("memory.high"));
+        }
+        checks.push(check::writable_files("cgroup", &files));
+    }
+
+    if let Some(scrub_key_file) = &config.scrub_key_file {
+        checks.push(check::readable_file("scrub_key_file", scrub_key_file));
+    }
+
+    if let Some(psk_file) = &config.psk_file {
+        checks.push(check::readable_file("psk_file", psk_file));
+    }
+
+    checks
+}
+
+fn enter_cgroup(
+    path: &path::Path,
+    memory_max: Option<u64>,
+) -> Result<cgroup::Cgroup, cgroup::Error> {
+    let cgroup = cgroup::Cgroup::new(path.to_path_buf())?;
+    if let Some(memory_max) = memory_max {
+        cgroup.set_memory_limits(memory_max)?;
+        log::info!("cgroup {cgroup} memory limited to {memory_max} bytes");
+    }
+    cgroup.enter()?;
+    log::info!("process placed in cgroup {cgroup}");
+    Ok(cgroup)
+}
+
+/// Answers an administration command received on the control socket
+fn control_command<F>(receiver: &receive::Receiver<F>, command: &str) -> String {
+    match command {
+        "status_json" => receiver.status_json(),
+        _ => match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["set", name, value] => match set_parameter(receiver, name, value) {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error: {e}"),
+            },
+            _ => format!("error: unknown command \"{command}\""),
+        },
+    }
+}
+
+/// Changes a parameter of the running receiver, `value` being given in the unit of the matching
+/// option
+fn set_parameter<F>(
+    receiver: &receive::Receiver<F>,
+    name: &str,
+    value: &str,
+) -> Result<(), String> {
+    let invalid = |e: String| format!("invalid value \"{value}\" for {name}: {e}");
+    match name {
+        "flush_timeout" => {
+            let ms = NonZeroU64::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            receiver
+                .set_flush_timeout(time::Duration::from_millis(ms.get()))
+                .map_err(|e| e.to_string())
+        }
+        "log_level" => {
+            let level = log::LevelFilter::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            diode::set_log_level(level);
+            Ok(())
+        }
+        _ => Err(format!("unknown parameter \"{name}\"")),
+    }
+}
+
+/// Exits if some sockets passed by the service manager match no configured address
+fn check_activated(activated: &sd_notify::Activated) {
+    let remaining = activated.remaining();
+    if !remaining.is_empty() {
+        for addr in remaining {
+            log::error!("socket {addr} passed by the service manager is not configured");
+        }
+        process::exit(1);
+    }
+}
+
+/// Notifies the service manager that startup is complete, if it expects notifications
+fn notify_ready() {
+    match sd_notify::notify("READY=1") {
+        Ok(true) => log::info!("startup notified to the service manager"),
+        Ok(false) => (),
+        Err(e) => log::warn!("failed to notify startup to the service manager: {e}"),
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, then for the transfers in progress to complete within
+/// `drain_timeout`, before exiting
+fn signal_loop(is_drained: impl Fn() -> bool, drain_timeout: time::Duration) {
+    if let Err(e) = diode::signal::install(&[libc::SIGTERM, libc::SIGINT]) {
+        log::error!("failed to install signal handlers: {e}");
+        return;
+    }
+
+    let mut drain_deadline = None;
+    loop {
+        if diode::signal::take(libc::SIGTERM) | diode::signal::take(libc::SIGINT) {
+            if drain_deadline.is_some() {
+                log::warn!("shutdown requested again, exiting without draining");
+                process::exit(1);
             }
+            notify_stopping();
+            log::info!("draining: waiting for the transfers in progress to complete");
+            drain_deadline = Some(time::Instant::now() + drain_timeout);
         }
+        if let Some(deadline) = drain_deadline {
+            if is_drained() {
+                log::info!("drained, exiting");
+                process::exit(0);
+            }
+            if deadline <= time::Instant::now() {
+                log::warn!(
+                    "transfers still in progress after {} s of draining, exiting",
+                    drain_timeout.as_secs()
+                );
+                process::exit(1);
+            }
+        }
+        thread::sleep(time::Duration::from_millis(200));
+    }
+}
+
+/// Notifies the service manager that the process is shutting down, if it expects notifications
+fn notify_stopping() {
+    if let Err(e) = sd_notify::notify("STOPPING=1") {
+        log::warn!("failed to notify shutdown to the service manager: {e}");
     }
 }
 
+/// Drops privileges once all sockets are bound, if asked to, exiting on failure
+fn init_logger(format: diode::LogFormat, file: Option<&path::Path>, stderr: bool) {
+    if let Err(e) = diode::init_logger_with(format, file, stderr) {
+        eprintln!("failed to open log file: {e}");
+        process::exit(1);
+    }
+}
+
+fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&path::Path>) {
+    if user.is_none() && group.is_none() && chroot.is_none() {
+        return;
+    }
+    if let Err(e) = diode::privileges::drop(user, group, chroot) {
+        log::error!("failed to drop privileges: {e}");
+        process::exit(1);
+    }
+    log::info!("dropped privileges");
+}
+
 fn main() {
     let config = command_args();
+    // taken before any thread is started, since the environment is modified
+    let activated = sd_notify::Activated::from_env();
+
+    // logs must not be mixed with the data of the transfers
+    init_logger(
+        config.log_format,
+        config.log_file.as_deref(),
+        matches!(config.to, ClientConfig::Stdout),
+    );
 
-    diode::init_logger();
+    let mut activated = match activated {
+        Ok(activated) => activated,
+        Err(e) => {
+            log::error!("failed to take the sockets passed by the service manager: {e}");
+            process::exit(1);
+        }
+    };
+
+    if config.check {
+        let passed = check::report(&prerequisite_checks(&config));
+        process::exit(i32::from(!passed));
+    }
+
+    if matches!(config.to, ClientConfig::Stdout) && config.nb_clients != 1 {
+        log::error!(
+            "invalid configuration: to_stdout requires nb_clients to be 1, transfers being written one after the other"
+        );
+        process::exit(1);
+    }
+
+    let mut channels: Vec<protocol::Channel> = config.channels.iter().map(|(c, _)| *c).collect();
+    channels.sort_unstable();
+    if let Some(channel) = channels.windows(2).find(|w| w[0] == w[1]) {
+        log::error!(
+            "invalid configuration: channel {} is given several destinations",
+            channel[0]
+        );
+        process::exit(1);
+    }
+
+    if let Err(e) = scrub::init(config.scrub_addresses, config.scrub_key_file.as_deref()) {
+        log::error!("failed to read address scrubbing key: {e}");
+        return;
+    }
+
+    let psk = match config
+        .psk_file
+        .as_deref()
+        .map(crypto::Key::read)
+        .transpose()
+    {
+        Ok(psk) => psk,
+        Err(e) => {
+            log::error!("failed to read pre-shared key: {e}");
+            return;
+        }
+    };
+
+    // placing the process in its cgroup before any data is allocated
+    let cgroup = match config
+        .cgroup
+        .as_ref()
+        .map(|path| enter_cgroup(path, config.memory_max))
+    {
+        None => None,
+        Some(Ok(cgroup)) => Some(cgroup),
+        Some(Err(e)) => {
+            log::error!("failed to place process in cgroup: {e}");
+            return;
+        }
+    };
+
+    let from_udp_socket = activated.take_udp(config.from_udp);
+    if from_udp_socket.is_some() {
+        log::info!(
+            "using the UDP socket of {} passed by the service manager",
+            scrub::addr(&config.from_udp)
+        );
+    }
+    let bind_from_udp = |socket: Option<net::UdpSocket>| match socket {
+        Some(socket) => Ok(socket),
+        None => receive::bind(
+            config.from_udp,
+            config.from_udp_multicast_interface.as_deref(),
+            1 < config.nb_udp_threads,
+        ),
+    };
+
+    let (sender_config, from_udp_socket) = match config.sender_config {
+        SenderConfig::Ignore => (None, from_udp_socket),
+        SenderConfig::Check | SenderConfig::Auto => {
+            log::info!(
+                "waiting for a configuration packet of the sender on {}",
+                scrub::addr(&config.from_udp)
+            );
+            match bind_from_udp(from_udp_socket).and_then(|socket| {
+                receive::wait_sender_config(socket, &config.allow_from, psk.as_ref())
+            }) {
+                Ok((socket, sender_config)) => (Some(sender_config), Some(socket)),
+                Err(e) => {
+                    log::error!("failed to receive the configuration of the sender: {e}");
+                    return;
+                }
+            }
+        }
+    };
+    let adopted = sender_config.filter(|_| config.sender_config == SenderConfig::Auto);
+
+    let (from_udp_mtu, from_udp_socket) = match (config.from_udp_mtu, sender_config) {
+        (_, Some(sender_config)) if adopted.is_some() => (sender_config.mtu, from_udp_socket),
+        (Some(mtu), _) => (mtu, from_udp_socket),
+        // the configuration packet already told the MTU of the sender
+        (None, Some(sender_config)) => (sender_config.mtu, from_udp_socket),
+        (None, None) => {
+            log::info!(
+                "waiting for a first packet on {} to detect MTU",
+                scrub::addr(&config.from_udp)
+            );
+            match bind_from_udp(from_udp_socket).and_then(|socket| {
+                receive::detect_mtu(
+                    socket,
+                    config.header_format,
+                    psk.is_some(),
+                    &config.allow_from,
+                )
+            }) {
+                Ok((socket, mtu)) => (mtu, Some(socket)),
+                Err(e) => {
+                    log::error!("failed to detect MTU: {e}");
+                    return;
+                }
+            }
+        }
+    };
+
+    let (encoding_block_size, repair_block_size) = match (adopted, config.low_latency) {
+        (Some(sender_config), _) => (
+            sender_config.encoding_block_size(),
+            sender_config.repair_block_size(),
+        ),
+        (None, Some(nb_repair_packets)) => protocol::single_packet_block_sizes(
+            config.header_format,
+            packet_mtu(&config, from_udp_mtu),
+            nb_repair_packets,
+        ),
+        (None, None) => (config.encoding_block_size, config.repair_block_size),
+    };
+    let fec = adopted.map_or(config.fec, |sender_config| sender_config.fec);
+    let header_format = adopted.map_or(config.header_format, |sender_config| {
+        sender_config.header_format()
+    });
+    let integrity = adopted.map_or(config.integrity, |sender_config| {
+        sender_config
+            .features
+            .contains(protocol::Features::INTEGRITY)
+    });
+    if adopted.is_some() {
+        log::info!("adopting the encoding parameters of the sender");
+    }
+
+    let sink = match &config.to {
+        ClientConfig::Serve(address, buffer_size, policy) => {
+            match server_sink::ServerSink::new(*address, *buffer_size, *policy) {
+                Ok(sink) => Some(sink),
+                Err(e) => {
+                    log::error!("failed to listen on {}: {e}", scrub::addr(address));
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
 
     log::info!("sending traffic to {}", config.to);
+    for (channel, to) in &config.channels {
+        log::info!("sending traffic of channel {channel} to {to}");
+    }
+
+    let receive_config = receive::Config {
+        from_udp: config.from_udp,
+        from_udp_mtu,
+        from_udp_socket,
+        from_multicast_interface: config.from_udp_multicast_interface,
+        stripe_from_udp: config.stripe_from_udp,
+        nb_clients: config.nb_clients,
+        encoding_block_size,
+        repair_block_size,
+        fec,
+        udp_buffer_size: config.udp_buffer_size,
+        udp_poll_mode: config.udp_poll_mode,
+        udp_gro: config.udp_gro,
+        nb_udp_threads: config.nb_udp_threads,
+        queue_size: config.queue_size,
+        flush_timeout: config.flush_timeout,
+        reorder_max_bytes: config.reorder_max_mb * 1024 * 1024,
+        low_latency: config.low_latency.is_some(),
+        nb_decoding_threads: config.nb_decoding_threads,
+        decode_capacity_warning: config.decode_capacity_warning,
+        loss_report_interval: config.loss_report_interval,
+        heartbeat_interval: config.heartbeat,
+        events_socket: config.events_socket.clone(),
+        audit_log: config.audit_log.clone(),
+        audit_sync: config.audit_sync,
+        sd_watchdog: config.sd_watchdog,
+        index_stream: config.index_stream,
+        cgroup,
+        quarantine_dir: config.quarantine_dir.clone(),
+        quarantine_max_bytes: config.quarantine_max_mb * 1024 * 1024,
+        session_header: config.session_header,
+        session_trailer: config.session_trailer,
+        store_and_forward: config.store_and_forward.clone(),
+        store_and_forward_max_bytes: config.store_and_forward_max_mb * 1024 * 1024,
+        integrity,
+        header_format,
+        check_sender_config: sender_config.is_some(),
+        force_best_effort: config.force_best_effort,
+        psk,
+        allow_from: config.allow_from.clone(),
+    };
+
+    if let Some(sender_config) = &sender_config {
+        let mismatches = receive_config.sender_config_mismatches(sender_config);
+        if !mismatches.is_empty() {
+            for mismatch in mismatches {
+                log::error!("sender configuration mismatch: {mismatch}");
+            }
+            process::exit(1);
+        }
+    }
 
-    let receiver = receive::Receiver::new(
-        receive::Config {
-            from_udp: config.from_udp,
-            from_udp_mtu: config.from_udp_mtu,
-            nb_clients: config.nb_clients,
-            encoding_block_size: config.encoding_block_size,
-            repair_block_size: config.repair_block_size,
-            udp_buffer_size: config.udp_buffer_size,
-            flush_timeout: config.flush_timeout,
-            nb_decoding_threads: config.nb_decoding_threads,
-            heartbeat_interval: config.heartbeat,
+    let receiver = receive::Receiver::new(receive_config, |channel| match channel {
+        0 => connect(&config.to, sink.as_ref()),
+        channel => match config.channels.iter().find(|(c, _)| *c == channel) {
+            Some((_, to)) => connect(to, None),
+            None => Err(io::Error::other(format!(
+                "no destination for channel {channel}"
+            ))),
         },
-        || Client::try_from(&config.to),
-    );
+    });
+    let receiver = match receiver {
+        Ok(receiver) => receiver,
+        Err(receive::Error::Config(errors)) => {
+            for e in errors {
+                log::error!("invalid configuration: {e}");
+            }
+            process::exit(1);
+        }
+        Err(e) => {
+            log::error!("failed to create diode receiver: {e}");
+            process::exit(1);
+        }
+    };
 
     thread::scope(|scope| {
         if let Err(e) = receiver.start(scope) {
             log::error!("failed to start diode receiver: {e}");
+            return;
         }
+
+        thread::Builder::new()
+            .name("diode-receive-signals".into())
+            .spawn_scoped(scope, || {
+                signal_loop(|| receiver.is_drained(), config.drain_timeout)
+            })
+            .expect("thread spawn");
+
+        if let Some(control_socket) = &config.control_socket {
+            let activated_listener = activated.take_unix(control_socket);
+            if activated_listener.is_none() && control_socket.exists() {
+                log::error!(
+                    "control socket path '{}' already exists",
+                    control_socket.display()
+                );
+                return;
+            }
+
+            log::info!("accepting control commands at {}", control_socket.display());
+
+            let control_listener =
+                match activated_listener.map_or_else(|| diode::control::bind(control_socket), Ok) {
+                    Err(e) => {
+                        log::error!(
+                            "failed to bind control socket {}: {}",
+                            control_socket.display(),
+                            e
+                        );
+                        return;
+                    }
+                    Ok(listener) => listener,
+                };
+
+            thread::Builder::new()
+                .name("diode-receive-control".into())
+                .spawn_scoped(scope, || {
+                    if let Err(e) = diode::control::serve(control_listener, |command| {
+                        control_command(&receiver, command)
+                    }) {
+                        log::error!("control socket error: {e}");
+                    }
+                })
+                .expect("thread spawn");
+        }
+
+        if let Some(metrics_addr) = config.metrics {
+            let metrics_listener = match activated
+                .take_tcp(metrics_addr)
+                .map_or_else(|| net::TcpListener::bind(metrics_addr), Ok)
+            {
+                Err(e) => {
+                    log::error!(
+                        "failed to bind metrics endpoint {}: {e}",
+                        scrub::addr(&metrics_addr)
+                    );
+                    return;
+                }
+                Ok(listener) => listener,
+            };
+            log::info!(
+                "serving metrics at http://{}/metrics",
+                scrub::addr(&metrics_addr)
+            );
+
+            thread::Builder::new()
+                .name("diode-receive-metrics".into())
+                .spawn_scoped(scope, || {
+                    if let Err(e) = metrics::serve(metrics_listener) {
+                        log::error!("metrics endpoint error: {e}");
+                    }
+                })
+                .expect("thread spawn");
+        }
+
+        if let Some(interval) = config.metrics_log_interval {
+            thread::Builder::new()
+                .name("diode-receive-metrics-log".into())
+                .spawn_scoped(scope, move || metrics::log_periodically(interval))
+                .expect("thread spawn");
+        }
+
+        check_activated(&activated);
+        drop_privileges(
+            config.user.as_deref(),
+            config.group.as_deref(),
+            config.chroot.as_deref(),
+        );
+        notify_ready();
     });
 }
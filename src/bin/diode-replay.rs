@@ -0,0 +1,237 @@
+use clap::{Arg, ArgMatches, Command};
+use diode::{pcap, receive, scrub};
+use std::{env, fs, io, net, path, process, thread, time};
+
+/// Longest time a capture waits for a datagram before checking for termination signals
+const SIGNAL_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(200);
+
+/// Largest UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+fn main() {
+    let args = Command::new(env!("CARGO_BIN_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Captures the datagrams of the diode link and replays captures into diode-receive")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("capture")
+                .about("Records the datagrams received on the diode link in a pcap file, until interrupted")
+                .arg(
+                    Arg::new("file")
+                        .value_name("file")
+                        .required(true)
+                        .help("Capture file to write"),
+                )
+                .arg(
+                    Arg::new("from_udp")
+                        .long("from_udp")
+                        .value_name("ip:port")
+                        .required(true)
+                        .value_parser(clap::value_parser!(net::SocketAddr))
+                        .help("IP address and port where to listen for UDP packets from diode-send, in place of diode-receive"),
+                )
+                .arg(
+                    Arg::new("from_udp_multicast_interface")
+                        .long("from_udp_multicast_interface")
+                        .value_name("ifname")
+                        .help("Network interface on which to join from_udp when it is a multicast group, instead of the one chosen by the kernel"),
+                )
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .value_name("nb_datagrams")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Stop once this number of datagrams were captured"),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Sends the UDP datagrams of a pcap file, as paced when they were captured")
+                .arg(
+                    Arg::new("file")
+                        .value_name("file")
+                        .required(true)
+                        .help("Capture file written by diode-replay capture or another tool such as tcpdump"),
+                )
+                .arg(
+                    Arg::new("to_udp")
+                        .long("to_udp")
+                        .value_name("ip:port")
+                        .required(true)
+                        .value_parser(clap::value_parser!(net::SocketAddr))
+                        .help("IP address and port where to send the datagrams to diode-receive"),
+                )
+                .arg(
+                    Arg::new("to_bind")
+                        .long("to_bind")
+                        .value_name("ip:port")
+                        .value_parser(clap::value_parser!(net::SocketAddr))
+                        .help("Binding IP and source port of the datagrams [default: 0.0.0.0:0, or [::]:0 when to_udp is IPv6]"),
+                )
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .value_name("port")
+                        .value_parser(clap::value_parser!(u16))
+                        .help("Only replay the datagrams of the capture sent to this destination port"),
+                )
+                .arg(
+                    Arg::new("speed")
+                        .long("speed")
+                        .value_name("factor")
+                        .default_value("1")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("Acceleration of the original pacing, 0 sending datagrams as fast as possible"),
+                ),
+        )
+        .get_matches();
+
+    diode::init_logger();
+
+    let res = match args.subcommand() {
+        Some(("capture", args)) => capture(args),
+        Some(("replay", args)) => replay(args),
+        _ => unreachable!("subcommand required"),
+    };
+    if let Err(e) = res {
+        log::error!("{e}");
+        process::exit(1);
+    }
+}
+
+fn capture(args: &ArgMatches) -> Result<(), String> {
+    let file = path::PathBuf::from(args.get_one::<String>("file").expect("required"));
+    let from_udp = *args
+        .get_one::<net::SocketAddr>("from_udp")
+        .expect("required");
+    let multicast_interface = args.get_one::<String>("from_udp_multicast_interface");
+    let count = args.get_one::<u64>("count").copied();
+
+    let socket = receive::bind(from_udp, multicast_interface.map(String::as_str), false)
+        .map_err(|e| format!("failed to bind {}: {e}", scrub::addr(&from_udp)))?;
+    socket
+        .set_read_timeout(Some(SIGNAL_CHECK_INTERVAL))
+        .map_err(|e| format!("failed to set socket timeout: {e}"))?;
+    diode::signal::install(&[libc::SIGTERM, libc::SIGINT])
+        .map_err(|e| format!("failed to install signal handlers: {e}"))?;
+
+    let output =
+        fs::File::create(&file).map_err(|e| format!("failed to create {}: {e}", file.display()))?;
+    let mut writer = pcap::Writer::new(io::BufWriter::new(output))
+        .map_err(|e| format!("failed to write {}: {e}", file.display()))?;
+
+    log::info!(
+        "capturing datagrams received at {} in {}",
+        scrub::addr(&from_udp),
+        file.display()
+    );
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let mut nb_datagrams = 0u64;
+    while count.is_none_or(|count| nb_datagrams < count) {
+        if diode::signal::take(libc::SIGTERM) | diode::signal::take(libc::SIGINT) {
+            break;
+        }
+        let (len, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(format!("failed to receive datagram: {e}")),
+        };
+        let datagram = pcap::Datagram {
+            timestamp: time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default(),
+            source,
+            destination: from_udp,
+            payload: buffer[..len].to_vec(),
+        };
+        writer
+            .write(&datagram)
+            .map_err(|e| format!("failed to write {}: {e}", file.display()))?;
+        nb_datagrams += 1;
+    }
+
+    writer
+        .flush()
+        .map_err(|e| format!("failed to write {}: {e}", file.display()))?;
+    log::info!("{nb_datagrams} datagrams captured");
+    Ok(())
+}
+
+fn replay(args: &ArgMatches) -> Result<(), String> {
+    let file = path::PathBuf::from(args.get_one::<String>("file").expect("required"));
+    let to_udp = *args.get_one::<net::SocketAddr>("to_udp").expect("required");
+    let to_bind = args
+        .get_one::<net::SocketAddr>("to_bind")
+        .copied()
+        .unwrap_or_else(|| {
+            let unspecified = if to_udp.is_ipv6() {
+                net::IpAddr::V6(net::Ipv6Addr::UNSPECIFIED)
+            } else {
+                net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED)
+            };
+            net::SocketAddr::new(unspecified, 0)
+        });
+    let port = args.get_one::<u16>("port").copied();
+    let speed = *args.get_one::<f64>("speed").expect("default");
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(format!("invalid speed {speed}"));
+    }
+
+    let input =
+        fs::File::open(&file).map_err(|e| format!("failed to open {}: {e}", file.display()))?;
+    let mut reader = pcap::Reader::new(io::BufReader::new(input))
+        .map_err(|e| format!("failed to read {}: {e}", file.display()))?;
+    let socket = net::UdpSocket::bind(to_bind)
+        .map_err(|e| format!("failed to bind {}: {e}", scrub::addr(&to_bind)))?;
+
+    log::info!(
+        "replaying datagrams of {} to {}",
+        file.display(),
+        scrub::addr(&to_udp)
+    );
+    // timestamp of the first datagram replayed and time it was sent, which the following ones
+    // are paced from
+    let mut start: Option<(time::Duration, time::Instant)> = None;
+    let mut nb_datagrams = 0u64;
+    let mut nb_bytes = 0u64;
+    while let Some(datagram) = reader
+        .read()
+        .map_err(|e| format!("failed to read {}: {e}", file.display()))?
+    {
+        if port.is_some_and(|port| datagram.destination.port() != port) {
+            continue;
+        }
+        let (first_timestamp, started) =
+            *start.get_or_insert_with(|| (datagram.timestamp, time::Instant::now()));
+        if 0.0 < speed {
+            let offset = datagram
+                .timestamp
+                .saturating_sub(first_timestamp)
+                .div_f64(speed);
+            let delay = (started + offset).saturating_duration_since(time::Instant::now());
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+        }
+        socket
+            .send_to(&datagram.payload, to_udp)
+            .map_err(|e| format!("failed to send datagram: {e}"))?;
+        nb_datagrams += 1;
+        nb_bytes += datagram.payload.len() as u64;
+    }
+
+    log::info!(
+        "{nb_datagrams} datagrams ({nb_bytes} bytes) replayed, {} frames of the capture skipped",
+        reader.skipped
+    );
+    Ok(())
+}
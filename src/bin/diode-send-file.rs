@@ -1,6 +1,9 @@
 use clap::{Arg, ArgAction, ArgGroup, Command};
-use diode::aux::{self, file};
-use std::{env, net, path, str::FromStr};
+use diode::{
+    aux::{self, file},
+    durable,
+};
+use std::{env, fs, net, path, str::FromStr, time};
 
 fn main() {
     let args = Command::new(env!("CARGO_BIN_NAME"))
@@ -38,11 +41,85 @@ fn main() {
                 .value_parser(clap::value_parser!(bool))
                 .help("Compute a hash of file content (default is false)"),
         )
+        .arg(
+            Arg::new("hash_algorithm")
+                .long("hash_algorithm")
+                .value_name("murmur3|sha256|blake3")
+                .default_value("murmur3")
+                .value_parser(clap::value_parser!(file::hash::Algorithm))
+                .help("Algorithm used to hash file content, sha256 and blake3 are cryptographic, sha256 is FIPS approved"),
+        )
+        .arg(
+            Arg::new("xattrs")
+                .long("xattrs")
+                .action(ArgAction::SetTrue)
+                .help("Send the extended attributes of the user namespace with the files"),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .action(ArgAction::SetTrue)
+                .default_value("false")
+                .value_parser(clap::value_parser!(bool))
+                .help("Journal the progress of each transfer next to the file and resume interrupted ones (default is false)"),
+        )
+        .arg(
+            Arg::new("recursive")
+                .long("recursive")
+                .action(ArgAction::SetTrue)
+                .default_value("false")
+                .value_parser(clap::value_parser!(bool))
+                .help("Send the files of the directories given and recreate their tree on the receiver side (default is false)"),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .action(ArgAction::SetTrue)
+                .requires("hash")
+                .conflicts_with("watch")
+                .help("Once the files are sent, send a manifest listing their names, lengths and hashes for the receiver to verify the batch"),
+        )
+        .arg(
+            Arg::new("manifest_key")
+                .long("manifest_key")
+                .value_name("file")
+                .requires("manifest")
+                .help("Sign the manifest with HMAC-SHA256, using the content of this file as key"),
+        )
+        .arg(
+            Arg::new("pending_dir")
+                .long("pending_dir")
+                .value_name("dir")
+                .help("Move sent files to this directory, on the same filesystem, until their grace period elapsed"),
+        )
+        .arg(
+            Arg::new("post_send_grace")
+                .long("post_send_grace")
+                .value_name("nb_seconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .requires("pending_dir")
+                .help("Time sent files stay in the pending directory before being deleted"),
+        )
+        .arg(
+            Arg::new("sent_dir")
+                .long("sent_dir")
+                .value_name("dir")
+                .requires("pending_dir")
+                .help("Move files to this directory instead of deleting them once their grace period elapsed"),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .value_name("dir")
+                .conflicts_with_all(["pending_dir", "file"])
+                .help("Send the files moved into this directory, then move them into its sent or failed subdirectory"),
+        )
         .arg(
             Arg::new("file")
                 .action(ArgAction::Append)
                 .allow_hyphen_values(true)
-                .required(true),
+                .required_unless_present("watch"),
         )
         .get_matches();
 
@@ -54,11 +131,27 @@ fn main() {
         .map(|s| path::PathBuf::from_str(s).expect("to_unix must point to a valid path"));
     let buffer_size = *args.get_one::<usize>("buffer_size").expect("default");
     let hash = args.get_one::<bool>("hash").copied().expect("default");
+    let hash_algorithm = *args
+        .get_one::<file::hash::Algorithm>("hash_algorithm")
+        .expect("default");
+    let xattrs = args.get_flag("xattrs");
+    let resume = args.get_one::<bool>("resume").copied().expect("default");
+    let recursive = args.get_one::<bool>("recursive").copied().expect("default");
+    let manifest = args.get_flag("manifest");
+    let manifest_key = args
+        .get_one::<String>("manifest_key")
+        .map(|path| fs::read(path).expect("failed to read manifest key"));
+    let pending_dir = args
+        .get_one::<String>("pending_dir")
+        .map(path::PathBuf::from);
+    let post_send_grace =
+        time::Duration::from_secs(*args.get_one::<u64>("post_send_grace").expect("default"));
+    let sent_dir = args.get_one::<String>("sent_dir").map(path::PathBuf::from);
+    let watch = args.get_one::<String>("watch").map(path::PathBuf::from);
     let files = args
         .get_many("file")
-        .expect("required")
-        .cloned()
-        .collect::<Vec<_>>();
+        .map(|files| files.cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
 
     let diode = if let Some(to_tcp) = to_tcp {
         aux::DiodeSend::Tcp(to_tcp)
@@ -70,11 +163,90 @@ fn main() {
         diode,
         buffer_size,
         hash,
+        hash_algorithm,
+        resume,
+        recursive,
+        require_fips_hash: false,
+        quarantine_dir: None,
+        min_free_bytes: 0,
+        min_free_inodes: 0,
+        output_owner: None,
+        output_file_mode: None,
+        overwrite: file::Overwrite::Fail,
+        preserve_owner: false,
+        xattrs,
+        manifest_key,
+        landlock: false,
+        audit_log: None,
+        audit_sync: durable::Policy::Always,
+        output_template: None,
     };
 
     diode::init_logger();
 
-    if let Err(e) = file::send::send_files(&config, &files) {
-        log::error!("{e}");
+    if let Some(watch) = watch {
+        if let Err(e) = file::watch::watch(&config, &watch) {
+            log::error!("{e}");
+        }
+        return;
+    }
+
+    let mut pending = match pending_dir {
+        None => None,
+        Some(pending_dir) => {
+            match file::pending::Pending::open(&pending_dir, sent_dir.as_deref(), post_send_grace) {
+                Ok(pending) => Some(pending),
+                Err(e) => {
+                    log::error!("failed to open pending directory: {e}");
+                    return;
+                }
+            }
+        }
+    };
+
+    // files of previous runs whose grace period elapsed
+    if let Some(pending) = &mut pending {
+        expire(pending);
+    }
+
+    let mut manifest = manifest.then(|| file::manifest::Manifest::new(hash_algorithm));
+
+    for file in &files {
+        match file::send::send_path(&config, file, manifest.as_mut()) {
+            Ok(total) => log::info!("file send, {total} bytes sent"),
+            Err(e) => {
+                log::error!("{e}");
+                break;
+            }
+        }
+        if let Some(pending) = &mut pending {
+            if let Err(e) = pending.add(path::Path::new(file)) {
+                log::error!("{e}");
+            }
+        }
+    }
+
+    // the manifest lists the files sent, even if the batch was interrupted
+    if let Some(manifest) = &manifest {
+        if let Err(e) = file::send::send_manifest(&config, manifest) {
+            log::error!("failed to send manifest: {e}");
+        }
+    }
+
+    if let Some(pending) = &mut pending {
+        expire(pending);
+    }
+}
+
+fn expire(pending: &mut file::pending::Pending) {
+    match pending.expire() {
+        Ok(0) => (),
+        Ok(nb) => {
+            log::info!("{nb} file(s) removed from pending directory after their grace period")
+        }
+        Err(e) => log::error!("failed to update pending directory: {e}"),
+    }
+    if !pending.is_empty() {
+        log::info!("{} file(s) in their grace period", pending.len());
     }
 }
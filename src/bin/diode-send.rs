@@ -1,11 +1,14 @@
-use clap::{Arg, ArgAction, Command};
-use diode::send;
+use clap::{error::ErrorKind, Arg, ArgAction, Command};
+use diode::{
+    config_file, crypto, fec, metrics, protocol, receive, scrub, sd_notify,
+    send::{self, scan},
+};
 use std::{
-    env,
-    io::Read,
+    collections, env,
+    io::{self, Read},
     net,
     os::{fd::AsRawFd, unix},
-    path,
+    path, process,
     str::FromStr,
     thread, time,
 };
@@ -13,27 +16,86 @@ use std::{
 struct Config {
     from_tcp: net::SocketAddr,
     from_unix: Option<path::PathBuf>,
+    /// Send the standard input as a single transfer, then exit
+    from_stdin: bool,
+    /// Listening sockets of the channels other than the default channel 0
+    channels: Vec<(protocol::Channel, Listen)>,
     flush_timeout: Option<time::Duration>,
     nb_clients: u16,
     encoding_block_size: u64,
     repair_block_size: u32,
+    fec: fec::Algorithm,
+    repair_profile: Option<path::PathBuf>,
     udp_buffer_size: u32,
     nb_encoding_threads: u8,
     to_bind: net::SocketAddr,
+    to_bind_device: Option<String>,
     to_udp: net::SocketAddr,
+    to_udp_extra: Vec<net::SocketAddr>,
     to_udp_mtu: u16,
+    to_udp_multicast_ttl: u8,
+    to_udp_multicast_interface: Option<String>,
+    stripe_to_udp: Option<net::SocketAddr>,
+    stripe_to_bind: net::SocketAddr,
+    stripe_to_bind_device: Option<String>,
     heartbeat: Option<time::Duration>,
+    heartbeat_status: bool,
+    config_broadcast: Option<time::Duration>,
     bandwidth_limit: f64,
+    txtime: bool,
+    udp_gso: bool,
+    control_socket: Option<path::PathBuf>,
+    metrics: Option<net::SocketAddr>,
+    metrics_log_interval: Option<time::Duration>,
+    log_format: diode::LogFormat,
+    log_file: Option<path::PathBuf>,
+    sd_watchdog: bool,
+    drain_timeout: time::Duration,
+    accept_proxy_protocol: Vec<receive::Network>,
+    site_id: Option<String>,
+    scan_policies: Vec<Box<dyn scan::ScanPolicy>>,
+    confirm_flush: bool,
+    target_loss: Option<f64>,
+    allow_insufficient_repair: bool,
+    scrub_addresses: scrub::Mode,
+    scrub_key_file: Option<path::PathBuf>,
+    max_rebinds: u32,
+    backpressure: bool,
+    overload_policy: send::OverloadPolicy,
+    channel_weights: collections::BTreeMap<protocol::Channel, u32>,
+    block_trailer: bool,
+    compress: bool,
+    integrity: bool,
+    header_format: protocol::HeaderFormat,
+    psk_file: Option<path::PathBuf>,
+    client_queue_timeout: Option<time::Duration>,
+    user: Option<String>,
+    group: Option<String>,
+    chroot: Option<path::PathBuf>,
+}
+
+/// Listening socket of a channel
+enum Listen {
+    Tcp(net::SocketAddr),
+    Unix(path::PathBuf),
 }
 
 fn command_args() -> Config {
-    let args = Command::new(env!("CARGO_BIN_NAME"))
+    let command = Command::new(env!("CARGO_BIN_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
+        .args_override_self(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("path")
+                .help("TOML file setting parameters by name, command line parameters taking precedence"),
+        )
         .arg(
             Arg::new("from_tcp")
                 .long("from_tcp")
                 .value_name("ip:port")
                 .default_value("127.0.0.1:5000")
+                .value_parser(clap::value_parser!(net::SocketAddr))
                 .help("IP address and port to accept TCP clients"),
         )
         .arg(
@@ -42,13 +104,36 @@ fn command_args() -> Config {
                 .value_name("path")
                 .help("Path of Unix socket to accept clients"),
         )
+        .arg(
+            Arg::new("from_stdin")
+                .long("from_stdin")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["from_unix", "from_channel_tcp", "from_channel_unix"])
+                .help("Send the standard input as a single transfer instead of accepting clients, exiting once it is emitted"),
+        )
+        .arg(
+            Arg::new("from_channel_tcp")
+                .long("from_channel_tcp")
+                .value_name("channel:ip:port")
+                .action(ArgAction::Append)
+                .value_parser(protocol::parse_channel)
+                .help("Channel (1 to 255) and IP address and port to accept TCP clients whose data is transferred on this channel (may be repeated)"),
+        )
+        .arg(
+            Arg::new("from_channel_unix")
+                .long("from_channel_unix")
+                .value_name("channel:path")
+                .action(ArgAction::Append)
+                .value_parser(protocol::parse_channel)
+                .help("Channel (1 to 255) and path of Unix socket to accept clients whose data is transferred on this channel (may be repeated)"),
+        )
         .arg(
             Arg::new("flush_timeout")
                 .long("flush_timeout")
                 .value_name("nb_milliseconds")
                 .default_value("1000")
                 .value_parser(clap::value_parser!(u64))
-                .help("Flush pending data after duration (0 = no flush)"),
+                .help("Maximum duration pending data waits before being flushed (0 = no flush)"),
         )
         .arg(
             Arg::new("nb_clients")
@@ -82,6 +167,37 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(u32))
                 .help("Size of repair data in bytes"),
         )
+        .arg(
+            Arg::new("repair_profile")
+                .long("repair_profile")
+                .value_name("path")
+                .value_parser(clap::value_parser!(path::PathBuf))
+                .conflicts_with_all(["repair_block_size", "low_latency"])
+                .help("TOML file giving the repair block size by time of day, read again when modified"),
+        )
+        .arg(
+            Arg::new("fec")
+                .long("fec")
+                .value_name("raptorq|rs|none")
+                .default_value("raptorq")
+                .value_parser(clap::value_parser!(fec::Algorithm))
+                .help("Forward error correction code of the blocks, the same on both ends: RaptorQ, Reed-Solomon for blocks of at most 256 packets, or none"),
+        )
+        .arg(
+            Arg::new("low_latency")
+                .long("low_latency")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["encoding_block_size", "repair_block_size", "flush_timeout"])
+                .help("Send each block as a single packet, emitted as soon as data is read from the client"),
+        )
+        .arg(
+            Arg::new("low_latency_repair")
+                .long("low_latency_repair")
+                .value_name("nb_packets")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32))
+                .help("Number of repair packets of each block with low_latency"),
+        )
         .arg(
             Arg::new("udp_buffer_size")
                 .long("udp_buffer_size")
@@ -95,15 +211,57 @@ fn command_args() -> Config {
                 .long("to_bind")
                 .value_name("ip:port")
                 .action(ArgAction::Set)
-                .default_value("0.0.0.0:0")
-                .help("Binding IP for UDP traffic"),
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("Binding IP and source port for UDP traffic, port 0 for an ephemeral port [default: 0.0.0.0:0, or [::]:0 when to_udp is IPv6]"),
+        )
+        .arg(
+            Arg::new("to_bind_device")
+                .long("to_bind_device")
+                .value_name("ifname")
+                .help("Network interface to send UDP traffic from, whatever the routes (requires CAP_NET_RAW)"),
         )
         .arg(
             Arg::new("to_udp")
                 .long("to_udp")
                 .value_name("ip:port")
                 .default_value("127.0.0.1:6000")
-                .help("IP address and port where to send UDP packets to diode-receive"),
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .action(ArgAction::Append)
+                .help("IP address and port where to send UDP packets to diode-receive, repeated to send every packet to several receivers"),
+        )
+        .arg(
+            Arg::new("to_udp_multicast_ttl")
+                .long("to_udp_multicast_ttl")
+                .value_name("nb_hops")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u8))
+                .help("TTL, or hop limit, of UDP packets when to_udp is a multicast group"),
+        )
+        .arg(
+            Arg::new("to_udp_multicast_interface")
+                .long("to_udp_multicast_interface")
+                .value_name("ifname")
+                .help("Network interface to send UDP packets from when to_udp is a multicast group, instead of the one chosen by routes"),
+        )
+        .arg(
+            Arg::new("stripe_to_udp")
+                .long("stripe_to_udp")
+                .value_name("ip:port")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port on a second diode link to stripe UDP packets over, every other packet of each block being sent to it"),
+        )
+        .arg(
+            Arg::new("stripe_to_bind")
+                .long("stripe_to_bind")
+                .value_name("ip:port")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("Binding IP and source port for UDP traffic on the second link [default: 0.0.0.0:0, or [::]:0 when stripe_to_udp is IPv6]"),
+        )
+        .arg(
+            Arg::new("stripe_to_bind_device")
+                .long("stripe_to_bind_device")
+                .value_name("ifname")
+                .help("Network interface to send UDP traffic on the second link from, whatever the routes (requires CAP_NET_RAW)"),
         )
         .arg(
             Arg::new("to_udp_mtu")
@@ -121,6 +279,20 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(u16))
                 .help("Duration between two emitted heartbeat messages, 0 to disable"),
         )
+        .arg(
+            Arg::new("heartbeat_status")
+                .long("heartbeat_status")
+                .action(ArgAction::SetTrue)
+                .help("Carry the uptime, counters and block sizes of the sender in heartbeat messages, for the receiver to detect inconsistent configurations"),
+        )
+        .arg(
+            Arg::new("config_broadcast")
+                .long("config_broadcast")
+                .value_name("nb_seconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u16))
+                .help("Duration between two configuration packets announcing the encoding parameters to the receiver, 0 to disable"),
+        )
         .arg(
             Arg::new("bandwidth_limit")
                 .long("bandwidth_limit")
@@ -129,15 +301,256 @@ fn command_args() -> Config {
                 .value_parser(clap::value_parser!(f64))
                 .help("Set the bandwidth limit for transfer speed between pitcher and catcher in Mbit/s. Use 0 to disable the limit."),
         )
-        .get_matches();
+        .arg(
+            Arg::new("txtime")
+                .long("txtime")
+                .action(ArgAction::SetTrue)
+                .help("Pace datagrams with transmit times (SO_TXTIME) handled by an etf qdisc instead of sleeping, requires --bandwidth_limit"),
+        )
+        .arg(
+            Arg::new("udp_gso")
+                .long("udp_gso")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("txtime")
+                .help("Let the kernel segment batches of datagrams (UDP_SEGMENT) to send them with fewer system calls, when supported"),
+        )
+        .arg(
+            Arg::new("accept_proxy_protocol")
+                .long("accept_proxy_protocol")
+                .value_name("network")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(receive::Network))
+                .help("Read a PROXY protocol (v1 or v2) header at the beginning of TCP connections from load balancers of this network (IP address or CIDR), can be repeated"),
+        )
+        .arg(
+            Arg::new("site_id")
+                .long("site_id")
+                .value_name("id")
+                .help("Identifier of this sender site, carried to the receiver in heartbeat messages (32 bytes max)"),
+        )
+        .arg(
+            Arg::new("control_socket")
+                .long("control_socket")
+                .value_name("path")
+                .help("Path of Unix socket to accept administration commands (pause, resume, pause_clients, resume_clients, announce_config, status, status_json, set <parameter> <value>)"),
+        )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .value_name("ip:port")
+                .value_parser(clap::value_parser!(net::SocketAddr))
+                .help("IP address and port where to serve metrics to Prometheus scrapes"),
+        )
+        .arg(
+            Arg::new("metrics_log_interval")
+                .long("metrics_log_interval")
+                .value_name("nb_seconds")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Interval between two writes of every metric to the logs, 0 to disable"),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log_format")
+                .value_name("text|json")
+                .default_value("text")
+                .value_parser(clap::value_parser!(diode::LogFormat))
+                .help("Format of the log records, json writing one object per line for log collectors"),
+        )
+        .arg(
+            Arg::new("log_file")
+                .long("log_file")
+                .value_name("path")
+                .help("Path of a file to append the log records to, instead of writing them to the terminal"),
+        )
+        .arg(
+            Arg::new("no_sd_watchdog")
+                .long("no-sd-watchdog")
+                .action(ArgAction::SetTrue)
+                .help("Do not send notifications to the systemd watchdog"),
+        )
+        .arg(
+            Arg::new("drain_timeout")
+                .long("drain_timeout")
+                .value_name("nb_seconds")
+                .default_value("30")
+                .value_parser(clap::value_parser!(u64))
+                .help("Maximum time to wait for transfers in progress to complete on SIGTERM or SIGINT before exiting"),
+        )
+        .arg(
+            Arg::new("scan_deny")
+                .long("scan_deny")
+                .value_name("regex")
+                .action(ArgAction::Append)
+                .help("Abort sessions whose content matches this regular expression (may be repeated)"),
+        )
+        .arg(
+            Arg::new("scan_window")
+                .long("scan_window")
+                .value_name("nb_bytes")
+                .default_value("4096")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Maximum length of the content matched by scan_deny expressions"),
+        )
+        .arg(
+            Arg::new("scan_max_bytes")
+                .long("scan_max_bytes")
+                .value_name("nb_bytes")
+                .value_parser(clap::value_parser!(u64))
+                .help("Abort sessions sending more than this number of bytes"),
+        )
+        .arg(
+            Arg::new("backpressure")
+                .long("backpressure")
+                .action(ArgAction::SetTrue)
+                .help("Shrink the receive buffer of clients sockets when blocks accumulate before the UDP link"),
+        )
+        .arg(
+            Arg::new("overload_policy")
+                .long("overload_policy")
+                .value_name("fair|finish_first")
+                .default_value("fair")
+                .value_parser(clap::value_parser!(send::OverloadPolicy))
+                .help("When the UDP link is saturated, slow all sessions down or defer new clients until sessions in progress finish"),
+        )
+        .arg(
+            Arg::new("channel_weight")
+                .long("channel_weight")
+                .value_name("channel:weight")
+                .action(ArgAction::Append)
+                .value_parser(parse_channel_weight)
+                .help("Channel (0 to 255) and its share of the UDP link relative to the other channels, also giving its clients precedence for transfer slots (default weight: 1, may be repeated)"),
+        )
+        .arg(
+            Arg::new("block_trailer")
+                .long("block_trailer")
+                .action(ArgAction::SetTrue)
+                .help("Follow each block with a trailer telling the receiver how many of its packets were emitted"),
+        )
+        .arg(
+            Arg::new("compress")
+                .long("compress")
+                .action(ArgAction::SetTrue)
+                .help("Compress client data with LZ4 when it makes it fit in fewer blocks"),
+        )
+        .arg(
+            Arg::new("integrity")
+                .long("integrity")
+                .action(ArgAction::SetTrue)
+                .help("End each block with a checksum, the receiver must also be run with --integrity"),
+        )
+        .arg(
+            Arg::new("wide_block_ids")
+                .long("wide_block_ids")
+                .action(ArgAction::SetTrue)
+                .help("Carry 16-bit block identifiers in packet headers, the receiver must also be run with --wide_block_ids"),
+        )
+        .arg(
+            Arg::new("psk_file")
+                .long("psk_file")
+                .value_name("path")
+                .help("File containing the pre-shared key encrypting datagrams with XChaCha20-Poly1305, the receiver must also be run with it"),
+        )
+        .arg(
+            Arg::new("confirm_flush")
+                .long("confirm_flush")
+                .action(ArgAction::SetTrue)
+                .help("Close client connections only once all their data was sent on the UDP link"),
+        )
+        .arg(
+            Arg::new("target_loss_percent")
+                .long("target_loss_percent")
+                .value_name("percent")
+                .value_parser(parse_loss_percent)
+                .help("Percentage of lost packets repair packets must compensate, checked at startup"),
+        )
+        .arg(
+            Arg::new("allow_insufficient_repair")
+                .long("allow_insufficient_repair")
+                .action(ArgAction::SetTrue)
+                .requires("target_loss_percent")
+                .help("Only warn when repair packets do not meet target_loss_percent"),
+        )
+        .arg(
+            Arg::new("scrub_addresses")
+                .long("scrub_addresses")
+                .value_name("hash|redact|off")
+                .default_value("off")
+                .value_parser(clap::value_parser!(scrub::Mode))
+                .help("How IP addresses appear in logs and events: keyed hash, network only or as is"),
+        )
+        .arg(
+            Arg::new("scrub_key_file")
+                .long("scrub_key_file")
+                .value_name("path")
+                .help("File containing the key of address hashes, a random key being used otherwise"),
+        )
+        .arg(
+            Arg::new("max_rebinds")
+                .long("max_rebinds")
+                .value_name("nb_attempts")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Number of consecutive failures to bind again a failed listener before exiting"),
+        )
+        .arg(
+            Arg::new("client_queue_timeout")
+                .long("client_queue_timeout")
+                .value_name("nb_milliseconds")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Reject clients waiting longer than this for a transfer slot instead of waiting indefinitely"),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .value_name("name")
+                .help("User to switch to once sockets are bound, by name or numeric identifier"),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .value_name("name")
+                .help("Group to switch to once sockets are bound, the primary group of --user by default"),
+        )
+        .arg(
+            Arg::new("chroot")
+                .long("chroot")
+                .value_name("path")
+                .help("Directory to confine the process in once sockets are bound"),
+        );
+    let mut command = config_file::command(command);
+    let args = config_file::args(&command, env::args_os())
+        .unwrap_or_else(|e| command.error(ErrorKind::Io, e).exit());
+    let args = command.get_matches_from(args);
 
-    let from_tcp = net::SocketAddr::from_str(args.get_one::<String>("from_tcp").expect("default"))
-        .expect("invalid from_tcp parameter");
+    let from_tcp = *args
+        .get_one::<net::SocketAddr>("from_tcp")
+        .expect("default");
     let from_unix = args
         .get_one::<String>("from_unix")
         .map(|s| path::PathBuf::from_str(s).expect("invalid from_unix parameter"));
+    let from_stdin = args.get_flag("from_stdin");
+    let mut channels = Vec::new();
+    for (channel, address) in args
+        .get_many::<(protocol::Channel, String)>("from_channel_tcp")
+        .unwrap_or_default()
+    {
+        let address = net::SocketAddr::from_str(address)
+            .unwrap_or_else(|e| panic!("invalid from_channel_tcp address \"{address}\": {e}"));
+        channels.push((*channel, Listen::Tcp(address)));
+    }
+    for (channel, path) in args
+        .get_many::<(protocol::Channel, String)>("from_channel_unix")
+        .unwrap_or_default()
+    {
+        channels.push((*channel, Listen::Unix(path::PathBuf::from(path))));
+    }
+    let low_latency = args.get_flag("low_latency");
     let flush_timeout_ms = *args.get_one::<u64>("flush_timeout").expect("default");
-    let flush_timeout = if flush_timeout_ms == 0 {
+    let flush_timeout = if low_latency {
+        // data is flushed as soon as it is read
+        Some(time::Duration::ZERO)
+    } else if flush_timeout_ms == 0 {
         None
     } else {
         Some(time::Duration::from_millis(flush_timeout_ms))
@@ -146,36 +559,224 @@ fn command_args() -> Config {
     let nb_encoding_threads = *args.get_one::<u8>("nb_encoding_threads").expect("default");
     let encoding_block_size = *args.get_one::<u64>("encoding_block_size").expect("default");
     let repair_block_size = *args.get_one::<u32>("repair_block_size").expect("default");
+    let fec = *args.get_one::<fec::Algorithm>("fec").expect("default");
+    let repair_profile = args.get_one::<path::PathBuf>("repair_profile").cloned();
     let udp_buffer_size = *args.get_one::<u32>("udp_buffer_size").expect("default");
-    let to_bind = net::SocketAddr::from_str(args.get_one::<String>("to_bind").expect("default"))
-        .expect("invalid to_bind parameter");
-    let to_udp = net::SocketAddr::from_str(args.get_one::<String>("to_udp").expect("default"))
-        .expect("invalid to_udp parameter");
+    let to_bind_device = args.get_one::<String>("to_bind_device").cloned();
+    let mut to_udp_extra = args
+        .get_many::<net::SocketAddr>("to_udp")
+        .expect("default")
+        .copied()
+        .collect::<Vec<_>>();
+    let to_udp = to_udp_extra.remove(0);
+    let unspecified = |to_udp: net::SocketAddr| {
+        let unspecified = if to_udp.is_ipv6() {
+            net::IpAddr::V6(net::Ipv6Addr::UNSPECIFIED)
+        } else {
+            net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED)
+        };
+        net::SocketAddr::new(unspecified, 0)
+    };
+    let to_bind = args
+        .get_one::<net::SocketAddr>("to_bind")
+        .copied()
+        .unwrap_or_else(|| unspecified(to_udp));
+    let stripe_to_udp = args.get_one::<net::SocketAddr>("stripe_to_udp").copied();
+    let stripe_to_bind = args
+        .get_one::<net::SocketAddr>("stripe_to_bind")
+        .copied()
+        .unwrap_or_else(|| unspecified(stripe_to_udp.unwrap_or(to_udp)));
+    let stripe_to_bind_device = args.get_one::<String>("stripe_to_bind_device").cloned();
     let to_udp_mtu = *args.get_one::<u16>("to_udp_mtu").expect("default");
+    let to_udp_multicast_ttl = *args.get_one::<u8>("to_udp_multicast_ttl").expect("default");
+    let to_udp_multicast_interface = args
+        .get_one::<String>("to_udp_multicast_interface")
+        .cloned();
+    let header_format = if args.get_flag("wide_block_ids") {
+        protocol::HeaderFormat::Wide
+    } else {
+        protocol::HeaderFormat::Narrow
+    };
+    let psk_file = args.get_one::<String>("psk_file").map(path::PathBuf::from);
+    let (encoding_block_size, repair_block_size) = if low_latency {
+        let nb_repair_packets = *args.get_one::<u32>("low_latency_repair").expect("default");
+        let packet_mtu = protocol::packet_mtu(to_udp_mtu, &to_udp);
+        protocol::single_packet_block_sizes(
+            header_format,
+            if psk_file.is_some() {
+                crypto::unsealed_mtu(packet_mtu)
+            } else {
+                packet_mtu
+            },
+            nb_repair_packets,
+        )
+    } else {
+        (encoding_block_size, repair_block_size)
+    };
+    let heartbeat_status = args.get_flag("heartbeat_status");
     let heartbeat = {
         let hb = *args.get_one::<u16>("heartbeat").expect("default") as u64;
         (hb != 0).then(|| time::Duration::from_secs(hb))
     };
+    let config_broadcast = {
+        let interval = u64::from(*args.get_one::<u16>("config_broadcast").expect("default"));
+        (interval != 0).then(|| time::Duration::from_secs(interval))
+    };
 
     let bandwidth_limit = {
         let target_bandwidth_mbps = *args.get_one::<f64>("bandwidth_limit").expect("default"); // Target bandwidth in Mbps
         target_bandwidth_mbps * 1_000_000.0 / 8.0 // Convert Mbps to bytes per second
     };
 
+    let txtime = args.get_flag("txtime");
+    let udp_gso = args.get_flag("udp_gso");
+    let accept_proxy_protocol = args
+        .get_many::<receive::Network>("accept_proxy_protocol")
+        .map(|networks| networks.copied().collect())
+        .unwrap_or_default();
+    let site_id = args.get_one::<String>("site_id").cloned();
+    let mut scan_policies: Vec<Box<dyn scan::ScanPolicy>> = Vec::new();
+    if let Some(max_bytes) = args.get_one::<u64>("scan_max_bytes") {
+        scan_policies.push(Box::new(scan::MaxBytesPerSession::new(*max_bytes)));
+    }
+    if let Some(patterns) = args.get_many::<String>("scan_deny") {
+        let patterns = patterns.cloned().collect::<Vec<_>>();
+        let window = *args.get_one::<u64>("scan_window").expect("default");
+        match scan::RegexDeny::new(&patterns, window) {
+            Ok(policy) => scan_policies.push(Box::new(policy)),
+            Err(e) => panic!("invalid scan_deny parameter: {e}"),
+        }
+    }
+    let confirm_flush = args.get_flag("confirm_flush");
+    let block_trailer = args.get_flag("block_trailer");
+    let compress = args.get_flag("compress");
+    let integrity = args.get_flag("integrity");
+    let backpressure = args.get_flag("backpressure");
+    let overload_policy = *args
+        .get_one::<send::OverloadPolicy>("overload_policy")
+        .expect("default");
+    let mut channel_weights = collections::BTreeMap::new();
+    for (channel, weight) in args
+        .get_many::<(protocol::Channel, u32)>("channel_weight")
+        .unwrap_or_default()
+    {
+        if channel_weights.insert(*channel, *weight).is_some() {
+            panic!("channel {channel} is given several weights");
+        }
+    }
+    let target_loss = args
+        .get_one::<f64>("target_loss_percent")
+        .map(|percent| percent / 100.0);
+    let allow_insufficient_repair = args.get_flag("allow_insufficient_repair");
+    let scrub_addresses = *args
+        .get_one::<scrub::Mode>("scrub_addresses")
+        .expect("default");
+    let scrub_key_file = args
+        .get_one::<String>("scrub_key_file")
+        .map(path::PathBuf::from);
+    let max_rebinds = *args.get_one::<u32>("max_rebinds").expect("default");
+    let client_queue_timeout = args
+        .get_one::<u64>("client_queue_timeout")
+        .map(|ms| time::Duration::from_millis(*ms));
+    let control_socket = args
+        .get_one::<String>("control_socket")
+        .map(|s| path::PathBuf::from_str(s).expect("invalid control_socket parameter"));
+    let metrics = args.get_one::<net::SocketAddr>("metrics").copied();
+    let metrics_log_interval = {
+        let interval = *args
+            .get_one::<u64>("metrics_log_interval")
+            .expect("default");
+        (interval != 0).then(|| time::Duration::from_secs(interval))
+    };
+    let log_format = *args
+        .get_one::<diode::LogFormat>("log_format")
+        .expect("default");
+    let log_file = args.get_one::<String>("log_file").map(path::PathBuf::from);
+    let sd_watchdog = !args.get_flag("no_sd_watchdog");
+    let drain_timeout =
+        time::Duration::from_secs(*args.get_one::<u64>("drain_timeout").expect("default"));
+
     Config {
         from_tcp,
         from_unix,
+        from_stdin,
+        channels,
         flush_timeout,
         nb_clients,
         nb_encoding_threads,
         encoding_block_size,
         udp_buffer_size,
         repair_block_size,
+        fec,
+        repair_profile,
         to_bind,
+        to_bind_device,
         to_udp,
+        to_udp_extra,
         to_udp_mtu,
+        to_udp_multicast_ttl,
+        to_udp_multicast_interface,
+        stripe_to_udp,
+        stripe_to_bind,
+        stripe_to_bind_device,
         heartbeat,
+        heartbeat_status,
+        config_broadcast,
         bandwidth_limit,
+        txtime,
+        udp_gso,
+        control_socket,
+        metrics,
+        metrics_log_interval,
+        log_format,
+        log_file,
+        sd_watchdog,
+        drain_timeout,
+        accept_proxy_protocol,
+        site_id,
+        scan_policies,
+        confirm_flush,
+        target_loss,
+        allow_insufficient_repair,
+        scrub_addresses,
+        scrub_key_file,
+        max_rebinds,
+        backpressure,
+        overload_policy,
+        channel_weights,
+        block_trailer,
+        compress,
+        integrity,
+        header_format,
+        psk_file,
+        client_queue_timeout,
+        user: args.get_one::<String>("user").cloned(),
+        group: args.get_one::<String>("group").cloned(),
+        chroot: args.get_one::<String>("chroot").map(path::PathBuf::from),
+    }
+}
+
+fn parse_loss_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(percent) if (0.0..100.0).contains(&percent) => Ok(percent),
+        Ok(_) => Err(format!(
+            "loss percentage \"{s}\" is not between 0 and 100 excluded"
+        )),
+        Err(e) => Err(format!("invalid loss percentage \"{s}\": {e}")),
+    }
+}
+
+fn parse_channel_weight(s: &str) -> Result<(protocol::Channel, u32), String> {
+    let (channel, weight) = s
+        .split_once(':')
+        .ok_or_else(|| format!("\"{s}\" is not of the form channel:weight"))?;
+    let channel = channel
+        .parse::<protocol::Channel>()
+        .map_err(|e| format!("invalid channel \"{channel}\": {e}"))?;
+    match weight.parse::<u32>() {
+        Ok(weight) if 0 < weight => Ok((channel, weight)),
+        Ok(_) => Err(format!("weight of channel {channel} must be at least 1")),
+        Err(e) => Err(format!("invalid weight \"{weight}\": {e}")),
     }
 }
 
@@ -202,22 +803,38 @@ impl AsRawFd for Client {
     }
 }
 
+/// Copies the standard input to `socket`, closing it at end of file so that the transfer ends
+///
+/// The process exits on read errors, so that the transfer is not ended as if it was complete.
+fn stdin_loop(mut socket: unix::net::UnixStream) {
+    match io::copy(&mut io::stdin().lock(), &mut socket) {
+        Ok(nb_bytes) => log::debug!("{nb_bytes} bytes read from standard input"),
+        Err(e) => {
+            log::error!("failed to copy standard input: {e}");
+            process::exit(1);
+        }
+    }
+    if let Err(e) = socket.shutdown(net::Shutdown::Write) {
+        log::error!("failed to end standard input transfer: {e}");
+        process::exit(1);
+    }
+}
+
 fn unix_listener_loop(
     listener: unix::net::UnixListener,
     sender: &send::Sender<Client>,
-    timeout: Option<time::Duration>,
-) {
-    for client in listener.incoming() {
-        match client {
+    channel: protocol::Channel,
+) -> io::Error {
+    loop {
+        // connections wait in the backlog of the socket while clients are paused
+        sender.wait_while_clients_paused();
+        match listener.accept() {
             Err(e) => {
                 log::error!("failed to accept client: {e}");
-                return;
+                return e;
             }
-            Ok(client) => {
-                if let Err(e) = client.set_read_timeout(timeout) {
-                    log::error!("failed to set client read timeout: {e}");
-                }
-                if let Err(e) = sender.new_client(Client::Unix(client)) {
+            Ok((client, _)) => {
+                if let Err(e) = sender.new_channel_client(Client::Unix(client), None, channel) {
                     log::error!("failed to send Unix client to connect queue: {e}");
                 }
             }
@@ -228,19 +845,20 @@ fn unix_listener_loop(
 fn tcp_listener_loop(
     listener: net::TcpListener,
     sender: &send::Sender<Client>,
-    timeout: Option<time::Duration>,
-) {
-    for client in listener.incoming() {
-        match client {
+    channel: protocol::Channel,
+) -> io::Error {
+    loop {
+        // connections wait in the backlog of the socket while clients are paused
+        sender.wait_while_clients_paused();
+        match listener.accept() {
             Err(e) => {
                 log::error!("failed to accept TCP client: {e}");
-                return;
+                return e;
             }
-            Ok(client) => {
-                if let Err(e) = client.set_read_timeout(timeout) {
-                    log::error!("failed to set client read timeout: {e}");
-                }
-                if let Err(e) = sender.new_client(Client::Tcp(client)) {
+            Ok((client, peer)) => {
+                // a PROXY protocol header is read by the client worker, not to block accepting
+                if let Err(e) = sender.new_channel_client(Client::Tcp(client), Some(peer), channel)
+                {
                     log::error!("failed to send TCP client to connect queue: {e}");
                 }
             }
@@ -248,23 +866,366 @@ fn tcp_listener_loop(
     }
 }
 
+fn control_command(sender: &send::Sender<Client>, command: &str) -> String {
+    match command {
+        "pause" => {
+            sender.pause();
+            "ok".to_string()
+        }
+        "resume" => {
+            sender.resume();
+            "ok".to_string()
+        }
+        "pause_clients" => {
+            sender.pause_clients();
+            "ok".to_string()
+        }
+        "resume_clients" => {
+            sender.resume_clients();
+            "ok".to_string()
+        }
+        "announce_config" => match sender.announce_config() {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("error: {e}"),
+        },
+        "status" => format!(
+            "paused={} clients_paused={} backlog={} backpressure={} shedding={} bandwidth_limit={} heartbeat={} repair_block_size={} flush_timeout={} log_level={}",
+            sender.is_paused(),
+            sender.are_clients_paused(),
+            sender.backlog(),
+            sender.backpressure_level(),
+            sender.is_shedding(),
+            sender.bandwidth_limit() * 8.0 / 1_000_000.0,
+            sender.heartbeat_interval().map_or(0, |interval| interval.as_secs()),
+            sender.repair_block_size(),
+            sender.flush_timeout().map_or(0, |timeout| timeout.as_millis()),
+            log::max_level()
+        ),
+        "status_json" => sender.status_json(),
+        _ => match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["set", name, value] => match set_parameter(sender, name, value) {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error: {e}"),
+            },
+            _ => format!("error: unknown command \"{command}\""),
+        },
+    }
+}
+
+/// Changes a parameter of the running sender, `value` being given in the unit of the matching
+/// option
+fn set_parameter(sender: &send::Sender<Client>, name: &str, value: &str) -> Result<(), String> {
+    let invalid = |e: String| format!("invalid value \"{value}\" for {name}: {e}");
+    match name {
+        "bandwidth_limit" => {
+            let mbps = f64::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            sender.set_bandwidth_limit(mbps * 1_000_000.0 / 8.0)
+        }
+        "heartbeat" => {
+            let secs = u16::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            sender.set_heartbeat_interval(time::Duration::from_secs(u64::from(secs)))
+        }
+        "repair_block_size" => {
+            let size = u32::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            sender.set_repair_block_size(size)
+        }
+        "flush_timeout" => {
+            let ms = u64::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            sender.set_flush_timeout((ms != 0).then_some(time::Duration::from_millis(ms)))
+        }
+        "log_level" => {
+            let level = log::LevelFilter::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            diode::set_log_level(level);
+            Ok(())
+        }
+        _ => return Err(format!("unknown parameter \"{name}\"")),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn signal_loop(sender: &send::Sender<Client>, drain_timeout: time::Duration) {
+    if let Err(e) =
+        diode::signal::install(&[libc::SIGTSTP, libc::SIGCONT, libc::SIGTERM, libc::SIGINT])
+    {
+        log::error!("failed to install signal handlers: {e}");
+        return;
+    }
+
+    let mut drain_deadline = None;
+    loop {
+        if diode::signal::take(libc::SIGTSTP) {
+            sender.pause();
+        }
+        if diode::signal::take(libc::SIGCONT) {
+            sender.resume();
+        }
+        if diode::signal::take(libc::SIGTERM) | diode::signal::take(libc::SIGINT) {
+            if drain_deadline.is_some() {
+                log::warn!("shutdown requested again, exiting without draining");
+                process::exit(1);
+            }
+            notify_stopping();
+            sender.drain();
+            if sender.is_paused() {
+                log::warn!("UDP emission is paused, pending blocks are only sent once resumed");
+            }
+            drain_deadline = Some(time::Instant::now() + drain_timeout);
+        }
+        if let Some(deadline) = drain_deadline {
+            if sender.is_drained() {
+                log::info!("drained, exiting");
+                process::exit(0);
+            }
+            if deadline <= time::Instant::now() {
+                log::warn!(
+                    "transfers still in progress after {} s of draining, {} block(s) pending, exiting",
+                    drain_timeout.as_secs(),
+                    sender.backlog()
+                );
+                process::exit(1);
+            }
+        }
+        thread::sleep(time::Duration::from_millis(200));
+    }
+}
+
+/// Binds the listening socket of `channel`, unless passed by the service manager, and spawns the
+/// thread accepting its clients, returning `false` if it could not be bound
+fn spawn_listener<'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    sender: &'scope send::Sender<Client>,
+    activated: &mut sd_notify::Activated,
+    max_rebinds: u32,
+    channel: protocol::Channel,
+    listen: Listen,
+) -> bool {
+    let (suffix, thread_suffix) = if channel == 0 {
+        (String::new(), String::new())
+    } else {
+        (format!(" of channel {channel}"), format!("-{channel}"))
+    };
+
+    match listen {
+        Listen::Tcp(from_tcp) => {
+            log::info!(
+                "accepting TCP clients{suffix} at {}",
+                scrub::addr(&from_tcp)
+            );
+
+            let tcp_listener = match activated
+                .take_tcp(from_tcp)
+                .map_or_else(|| net::TcpListener::bind(from_tcp), Ok)
+            {
+                Err(e) => {
+                    log::error!("failed to bind TCP {}: {}", scrub::addr(&from_tcp), e);
+                    return false;
+                }
+                Ok(listener) => listener,
+            };
+
+            thread::Builder::new()
+                .name(format!("diode-send-tcp-server{thread_suffix}"))
+                .spawn_scoped(scope, move || {
+                    let e = send::listener::supervise(
+                        "TCP",
+                        tcp_listener,
+                        max_rebinds,
+                        || net::TcpListener::bind(from_tcp),
+                        |listener| tcp_listener_loop(listener, sender, channel),
+                    );
+                    log::error!("giving up binding TCP listener, exiting: {e}");
+                    process::exit(1);
+                })
+                .expect("thread spawn");
+        }
+        Listen::Unix(from_unix) => {
+            let activated_listener = activated.take_unix(&from_unix);
+            if activated_listener.is_none() && from_unix.exists() {
+                log::error!("Unix socket path '{}' already exists", from_unix.display());
+                return false;
+            }
+
+            log::info!("accepting Unix clients{suffix} at {}", from_unix.display());
+
+            let unix_listener = match activated_listener
+                .map_or_else(|| unix::net::UnixListener::bind(&from_unix), Ok)
+            {
+                Err(e) => {
+                    log::error!("failed to bind Unix {}: {}", from_unix.display(), e);
+                    return false;
+                }
+                Ok(listener) => listener,
+            };
+
+            thread::Builder::new()
+                .name(format!("diode-send-unix-server{thread_suffix}"))
+                .spawn_scoped(scope, move || {
+                    let e = send::listener::supervise(
+                        "Unix",
+                        unix_listener,
+                        max_rebinds,
+                        || {
+                            // the path is still bound to the failed listener socket
+                            if let Err(e) = std::fs::remove_file(&from_unix) {
+                                if e.kind() != io::ErrorKind::NotFound {
+                                    return Err(e);
+                                }
+                            }
+                            unix::net::UnixListener::bind(&from_unix)
+                        },
+                        |listener| unix_listener_loop(listener, sender, channel),
+                    );
+                    log::error!("giving up binding Unix listener, exiting: {e}");
+                    process::exit(1);
+                })
+                .expect("thread spawn");
+        }
+    }
+    true
+}
+
+/// Exits if some sockets passed by the service manager match no configured address
+fn check_activated(activated: &sd_notify::Activated) {
+    let remaining = activated.remaining();
+    if !remaining.is_empty() {
+        for addr in remaining {
+            log::error!("socket {addr} passed by the service manager is not configured");
+        }
+        process::exit(1);
+    }
+}
+
+/// Notifies the service manager that startup is complete, if it expects notifications
+fn notify_ready() {
+    match sd_notify::notify("READY=1") {
+        Ok(true) => log::info!("startup notified to the service manager"),
+        Ok(false) => (),
+        Err(e) => log::warn!("failed to notify startup to the service manager: {e}"),
+    }
+}
+
+/// Notifies the service manager that the process is shutting down, if it expects notifications
+fn notify_stopping() {
+    if let Err(e) = sd_notify::notify("STOPPING=1") {
+        log::warn!("failed to notify shutdown to the service manager: {e}");
+    }
+}
+
+/// Drops privileges once all sockets are bound, if asked to, exiting on failure
+fn init_logger(format: diode::LogFormat, file: Option<&path::Path>, stderr: bool) {
+    if let Err(e) = diode::init_logger_with(format, file, stderr) {
+        eprintln!("failed to open log file: {e}");
+        process::exit(1);
+    }
+}
+
+fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&path::Path>) {
+    if user.is_none() && group.is_none() && chroot.is_none() {
+        return;
+    }
+    if let Err(e) = diode::privileges::drop(user, group, chroot) {
+        log::error!("failed to drop privileges: {e}");
+        process::exit(1);
+    }
+    log::info!("dropped privileges");
+}
+
 fn main() {
     let config = command_args();
+    // taken before any thread is started, since the environment is modified
+    let activated = sd_notify::Activated::from_env();
+
+    init_logger(config.log_format, config.log_file.as_deref(), false);
 
-    diode::init_logger();
+    let mut activated = match activated {
+        Ok(activated) => activated,
+        Err(e) => {
+            log::error!("failed to take the sockets passed by the service manager: {e}");
+            process::exit(1);
+        }
+    };
+
+    let mut channels: Vec<protocol::Channel> = config.channels.iter().map(|(c, _)| *c).collect();
+    channels.sort_unstable();
+    if let Some(channel) = channels.windows(2).find(|w| w[0] == w[1]) {
+        log::error!("channel {} is given several listening sockets", channel[0]);
+        return;
+    }
+
+    if let Err(e) = scrub::init(config.scrub_addresses, config.scrub_key_file.as_deref()) {
+        log::error!("failed to read address scrubbing key: {e}");
+        return;
+    }
+
+    let psk = match config
+        .psk_file
+        .as_deref()
+        .map(crypto::Key::read)
+        .transpose()
+    {
+        Ok(psk) => psk,
+        Err(e) => {
+            log::error!("failed to read pre-shared key: {e}");
+            return;
+        }
+    };
 
     let sender = send::Sender::new(send::Config {
         nb_clients: config.nb_clients,
         encoding_block_size: config.encoding_block_size,
         repair_block_size: config.repair_block_size,
+        fec: config.fec,
+        repair_profile: config.repair_profile,
         udp_buffer_size: config.udp_buffer_size,
         nb_encoding_threads: config.nb_encoding_threads,
         heartbeat_interval: config.heartbeat,
+        heartbeat_status: config.heartbeat_status,
+        sd_watchdog: config.sd_watchdog,
+        config_broadcast: config.config_broadcast,
         to_bind: config.to_bind,
+        to_bind_device: config.to_bind_device,
         to_udp: config.to_udp,
+        to_udp_extra: config.to_udp_extra,
         to_mtu: config.to_udp_mtu,
+        to_multicast_ttl: config.to_udp_multicast_ttl,
+        to_multicast_interface: config.to_udp_multicast_interface,
+        stripe_to_udp: config.stripe_to_udp,
+        stripe_to_bind: config.stripe_to_bind,
+        stripe_to_bind_device: config.stripe_to_bind_device,
         bandwidth_limit: config.bandwidth_limit,
+        txtime: config.txtime,
+        udp_gso: config.udp_gso,
+        site_id: config.site_id.clone(),
+        flush_timeout: config.flush_timeout,
+        scan_policies: config.scan_policies,
+        // the process exits at the end of the transfer, which must have been emitted
+        confirm_flush: config.confirm_flush || config.from_stdin,
+        backpressure: config.backpressure,
+        overload_policy: config.overload_policy,
+        channel_weights: config.channel_weights,
+        block_trailer: config.block_trailer,
+        compress: config.compress,
+        integrity: config.integrity,
+        header_format: config.header_format,
+        psk,
+        proxy_protocol_from: config.accept_proxy_protocol,
+        client_queue_timeout: config.client_queue_timeout,
+        target_loss: config.target_loss,
+        allow_insufficient_repair: config.allow_insufficient_repair,
     });
+    let sender = match sender {
+        Ok(sender) => sender,
+        Err(send::Error::Config(errors)) => {
+            for e in errors {
+                log::error!("invalid configuration: {e}");
+            }
+            process::exit(1);
+        }
+        Err(e) => {
+            log::error!("failed to create diode sender: {e}");
+            process::exit(1);
+        }
+    };
 
     thread::scope(|scope| {
         if let Err(e) = sender.start(scope) {
@@ -272,45 +1233,139 @@ fn main() {
             return;
         }
 
-        log::info!("accepting TCP clients at {}", config.from_tcp);
-
-        let tcp_listener = match net::TcpListener::bind(config.from_tcp) {
-            Err(e) => {
-                log::error!("failed to bind TCP {}: {}", config.from_tcp, e);
-                return;
-            }
-            Ok(listener) => listener,
-        };
-
         thread::Builder::new()
-            .name("diode-send-tcp-server".into())
-            .spawn_scoped(scope, || {
-                tcp_listener_loop(tcp_listener, &sender, config.flush_timeout)
-            })
+            .name("diode-send-signals".into())
+            .spawn_scoped(scope, || signal_loop(&sender, config.drain_timeout))
             .expect("thread spawn");
 
-        if let Some(from_unix) = config.from_unix {
-            if from_unix.exists() {
-                log::error!("Unix socket path '{}' already exists", from_unix.display());
+        if let Some(control_socket) = &config.control_socket {
+            let activated_listener = activated.take_unix(control_socket);
+            if activated_listener.is_none() && control_socket.exists() {
+                log::error!(
+                    "control socket path '{}' already exists",
+                    control_socket.display()
+                );
                 return;
             }
 
-            log::info!("accepting Unix clients at {}", from_unix.display());
+            log::info!("accepting control commands at {}", control_socket.display());
 
-            let unix_listener = match unix::net::UnixListener::bind(&from_unix) {
+            let control_listener =
+                match activated_listener.map_or_else(|| diode::control::bind(control_socket), Ok) {
+                    Err(e) => {
+                        log::error!(
+                            "failed to bind control socket {}: {}",
+                            control_socket.display(),
+                            e
+                        );
+                        return;
+                    }
+                    Ok(listener) => listener,
+                };
+
+            thread::Builder::new()
+                .name("diode-send-control".into())
+                .spawn_scoped(scope, || {
+                    if let Err(e) = diode::control::serve(control_listener, |command| {
+                        control_command(&sender, command)
+                    }) {
+                        log::error!("control socket error: {e}");
+                    }
+                })
+                .expect("thread spawn");
+        }
+
+        if let Some(metrics_addr) = config.metrics {
+            let metrics_listener = match activated
+                .take_tcp(metrics_addr)
+                .map_or_else(|| net::TcpListener::bind(metrics_addr), Ok)
+            {
                 Err(e) => {
-                    log::error!("failed to bind Unix {}: {}", from_unix.display(), e);
+                    log::error!(
+                        "failed to bind metrics endpoint {}: {e}",
+                        scrub::addr(&metrics_addr)
+                    );
                     return;
                 }
                 Ok(listener) => listener,
             };
+            log::info!(
+                "serving metrics at http://{}/metrics",
+                scrub::addr(&metrics_addr)
+            );
 
             thread::Builder::new()
-                .name("diode-send-unix-server".into())
+                .name("diode-send-metrics".into())
                 .spawn_scoped(scope, || {
-                    unix_listener_loop(unix_listener, &sender, config.flush_timeout)
+                    if let Err(e) = metrics::serve(metrics_listener) {
+                        log::error!("metrics endpoint error: {e}");
+                    }
                 })
                 .expect("thread spawn");
         }
+
+        if let Some(interval) = config.metrics_log_interval {
+            thread::Builder::new()
+                .name("diode-send-metrics-log".into())
+                .spawn_scoped(scope, move || metrics::log_periodically(interval))
+                .expect("thread spawn");
+        }
+
+        if config.from_stdin {
+            // the standard input is not a socket, it is copied to one for the client worker to
+            // read it with timeouts
+            let (client, stdin_socket) = match unix::net::UnixStream::pair() {
+                Err(e) => {
+                    log::error!("failed to create standard input socket: {e}");
+                    process::exit(1);
+                }
+                Ok(pair) => pair,
+            };
+            thread::Builder::new()
+                .name("diode-send-stdin".into())
+                .spawn_scoped(scope, || stdin_loop(stdin_socket))
+                .expect("thread spawn");
+
+            check_activated(&activated);
+            drop_privileges(
+                config.user.as_deref(),
+                config.group.as_deref(),
+                config.chroot.as_deref(),
+            );
+            notify_ready();
+
+            log::info!("sending standard input");
+            let res = sender.run_client(Client::Unix(client));
+            process::exit(i32::from(res.is_err()));
+        }
+
+        let mut listens = vec![(0, Listen::Tcp(config.from_tcp))];
+        listens.extend(
+            config
+                .from_unix
+                .map(|from_unix| (0, Listen::Unix(from_unix))),
+        );
+        listens.extend(config.channels);
+
+        for (channel, listen) in listens {
+            if !spawn_listener(
+                scope,
+                &sender,
+                &mut activated,
+                config.max_rebinds,
+                channel,
+                listen,
+            ) {
+                return;
+            }
+        }
+
+        check_activated(&activated);
+        drop_privileges(
+            config.user.as_deref(),
+            config.group.as_deref(),
+            config.chroot.as_deref(),
+        );
+        notify_ready();
     });
 }
@@ -0,0 +1,93 @@
+use clap::{Arg, ArgAction, Command};
+use diode::control;
+use std::{env, path, process};
+
+fn main() {
+    let args = Command::new(env!("CARGO_BIN_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Sends administration commands to the control socket of diode-send or diode-receive")
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_name("path")
+                .required(true)
+                .help("Path of the Unix socket given to --control_socket"),
+        )
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("status")
+                .about("Reports the state of the diode end")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Report sessions, queues and counters as a JSON object"),
+                ),
+        )
+        .subcommand(
+            Command::new("log_level")
+                .about("Changes the level of the messages logged")
+                .arg(
+                    Arg::new("level")
+                        .value_name("off|error|warn|info|debug|trace")
+                        .required(true),
+                ),
+        )
+        .subcommand(Command::new("pause").about("Stops UDP emission of diode-send"))
+        .subcommand(Command::new("resume").about("Restarts UDP emission of diode-send"))
+        .subcommand(
+            Command::new("pause_clients").about("Stops accepting new clients on diode-send"),
+        )
+        .subcommand(Command::new("resume_clients").about("Accepts new clients again on diode-send"))
+        .subcommand(
+            Command::new("announce_config")
+                .about("Makes diode-send send a configuration packet before its next block"),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Changes a parameter of diode-send or diode-receive while it runs, diode-receive only accepting flush_timeout")
+                .arg(
+                    Arg::new("parameter")
+                        .value_name("bandwidth_limit|heartbeat|repair_block_size|flush_timeout")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("value")
+                        .value_name("value")
+                        .required(true)
+                        .help("New value, in the unit of the matching option"),
+                ),
+        )
+        .get_matches();
+
+    let socket = path::PathBuf::from(args.get_one::<String>("socket").expect("required"));
+
+    let command = match args.subcommand() {
+        Some(("status", args)) if args.get_flag("json") => "status_json".to_string(),
+        Some(("log_level", args)) => format!(
+            "set log_level {}",
+            args.get_one::<String>("level").expect("required")
+        ),
+        Some(("set", args)) => format!(
+            "set {} {}",
+            args.get_one::<String>("parameter").expect("required"),
+            args.get_one::<String>("value").expect("required")
+        ),
+        Some((command, _)) => command.to_string(),
+        None => unreachable!("subcommand required"),
+    };
+
+    match control::request(&socket, &command) {
+        Err(e) => {
+            eprintln!("failed to send command to {}: {e}", socket.display());
+            process::exit(1);
+        }
+        Ok(response) => {
+            if let Some(error) = response.strip_prefix("error: ") {
+                eprintln!("{error}");
+                process::exit(1);
+            }
+            println!("{response}");
+        }
+    }
+}
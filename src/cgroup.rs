@@ -0,0 +1,239 @@
+//! Self-placement of the process into a cgroup v2 with a memory limit
+//!
+//! The cgroup directory must already exist and be delegated to the user running the process
+//! (i.e. its `cgroup.procs`, `memory.max` and `memory.high` files must be writable). Limits are
+//! set before the process moves itself into the cgroup, so that no memory is ever charged to the
+//! cgroup without the limit in place.
+
+use crate::metrics;
+use std::{fmt, fs, io, path, process, thread, time};
+
+pub enum Error {
+    NotACgroup(path::PathBuf),
+    Write(path::PathBuf, io::Error),
+    Read(path::PathBuf, io::Error),
+    Parse(path::PathBuf, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::NotACgroup(p) => write!(
+                fmt,
+                "'{}' is not a cgroup v2 directory (no cgroup.procs file)",
+                p.display()
+            ),
+            Self::Write(p, e) => write!(fmt, "failed to write '{}': {e}", p.display()),
+            Self::Read(p, e) => write!(fmt, "failed to read '{}': {e}", p.display()),
+            Self::Parse(p, e) => write!(fmt, "invalid content of '{}': {e}", p.display()),
+        }
+    }
+}
+
+pub struct Cgroup {
+    path: path::PathBuf,
+}
+
+impl fmt::Display for Cgroup {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.path.display())
+    }
+}
+
+impl Cgroup {
+    pub fn new(path: path::PathBuf) -> Result<Self, Error> {
+        if !path.join("cgroup.procs").is_file() {
+            return Err(Error::NotACgroup(path));
+        }
+        Ok(Self { path })
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), Error> {
+        let path = self.path.join(file);
+        fs::write(&path, value).map_err(|e| Error::Write(path, e))
+    }
+
+    fn read(&self, file: &str) -> Result<String, Error> {
+        let path = self.path.join(file);
+        fs::read_to_string(&path).map_err(|e| Error::Read(path, e))
+    }
+
+    /// Sets `memory.max` to `max` bytes, and `memory.high` (above which the kernel throttles and
+    /// reclaims memory) to 90% of it
+    pub fn set_memory_limits(&self, max: u64) -> Result<(), Error> {
+        let high = max / 10 * 9;
+        // setting memory.high first, it must not be above memory.max to be useful
+        self.write("memory.high", &high.to_string())?;
+        self.write("memory.max", &max.to_string())
+    }
+
+    /// Moves the current process, with all its threads, into the cgroup
+    pub fn enter(&self) -> Result<(), Error> {
+        self.write("cgroup.procs", &process::id().to_string())
+    }
+
+    /// Returns the memory currently used by the processes of the cgroup, in bytes
+    pub fn memory_current(&self) -> Result<u64, Error> {
+        let content = self.read("memory.current")?;
+        content
+            .trim()
+            .parse()
+            .map_err(|e| Error::Parse(self.path.join("memory.current"), format!("{e}")))
+    }
+}
+
+/// Parses a size in bytes, with an optional `K`, `M`, `G` or `T` (power of 1024) suffix
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        Some(b'T' | b't') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let value = digits
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size \"{s}\": {e}"))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size \"{s}\" is too large"))
+}
+
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Periodically updates the `rx_cgroup_memory_current_bytes` gauge
+pub(crate) fn monitor(cgroup: &Cgroup) {
+    let gauge = metrics::gauge("rx_cgroup_memory_current_bytes");
+    let mut failing = false;
+
+    loop {
+        match cgroup.memory_current() {
+            Ok(current) => {
+                gauge.set(current);
+                failing = false;
+            }
+            Err(e) => {
+                if !failing {
+                    log::warn!("cgroup memory monitoring: {e}");
+                }
+                failing = true;
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, Cgroup, Error};
+    use crate::testing::TempDir;
+    use std::{fs, process};
+
+    /// Directory mimicking a delegated cgroup v2
+    fn fake_cgroup() -> (TempDir, Cgroup) {
+        let dir = TempDir::new("cgroup");
+        for file in ["cgroup.procs", "memory.max", "memory.high"] {
+            fs::write(dir.path().join(file), "").expect("create cgroup file");
+        }
+        let cgroup = Cgroup::new(dir.path().to_path_buf()).unwrap_or_else(|e| panic!("{e}"));
+        (dir, cgroup)
+    }
+
+    fn content(dir: &TempDir, file: &str) -> String {
+        fs::read_to_string(dir.path().join(file)).expect("read cgroup file")
+    }
+
+    #[test]
+    fn not_a_cgroup() {
+        let dir = TempDir::new("cgroup");
+        assert!(matches!(
+            Cgroup::new(dir.path().to_path_buf()),
+            Err(Error::NotACgroup(path)) if path == dir.path()
+        ));
+        assert!(matches!(
+            Cgroup::new(dir.path().join("missing")),
+            Err(Error::NotACgroup(_))
+        ));
+    }
+
+    #[test]
+    fn placement() {
+        let (dir, cgroup) = fake_cgroup();
+
+        cgroup
+            .set_memory_limits(2 << 30)
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(content(&dir, "memory.max"), "2147483648");
+        assert_eq!(content(&dir, "memory.high"), "1932735276");
+
+        cgroup.enter().unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(content(&dir, "cgroup.procs"), process::id().to_string());
+    }
+
+    #[test]
+    fn not_writable() {
+        let (dir, cgroup) = fake_cgroup();
+        let memory_max = dir.path().join("memory.max");
+        fs::remove_file(&memory_max).expect("remove memory.max");
+        fs::create_dir(&memory_max).expect("create directory");
+
+        assert!(matches!(
+            cgroup.set_memory_limits(1 << 20),
+            Err(Error::Write(path, _)) if path == memory_max
+        ));
+    }
+
+    #[test]
+    fn memory_current() {
+        let (dir, cgroup) = fake_cgroup();
+        let memory_current = dir.path().join("memory.current");
+        assert!(matches!(
+            cgroup.memory_current(),
+            Err(Error::Read(path, _)) if path == memory_current
+        ));
+
+        fs::write(&memory_current, "1234567\n").expect("write memory.current");
+        assert!(matches!(cgroup.memory_current(), Ok(1_234_567)));
+
+        fs::write(&memory_current, "max\n").expect("write memory.current");
+        assert!(matches!(
+            cgroup.memory_current(),
+            Err(Error::Parse(path, _)) if path == memory_current
+        ));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("3t"), Ok(3 << 40));
+        assert_eq!(parse_size("16777215T"), Ok(16_777_215 << 40));
+        for invalid in ["", "G", "-1", "1.5G", "2GB", "16777216T"] {
+            assert!(parse_size(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    /// Requires a cgroup v2 hierarchy mounted on `/sys/fs/cgroup`, in which a child cgroup can
+    /// be created, with the memory controller enabled. The test process is not moved into it.
+    #[test]
+    #[ignore]
+    fn delegated_cgroup() {
+        let path =
+            std::path::Path::new("/sys/fs/cgroup").join(format!("lidi-test-{}", process::id()));
+        fs::create_dir(&path).expect("create cgroup");
+        let res = (|| {
+            let cgroup = Cgroup::new(path.clone())?;
+            cgroup.set_memory_limits(64 << 20)?;
+            cgroup.memory_current()
+        })();
+        let memory_max = fs::read_to_string(path.join("memory.max"));
+        fs::remove_dir(&path).expect("remove cgroup");
+
+        res.unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(memory_max.expect("read memory.max").trim(), "67108864");
+    }
+}
@@ -0,0 +1,176 @@
+//! Runtime prerequisite checks, run by the binaries with `--check` instead of starting
+//!
+//! Each function checks one prerequisite and returns a [Check] instead of failing, so that all
+//! the problems of a configuration are reported at once by [report].
+
+use crate::{fs_utils, sock_utils};
+use std::{fmt, fs, io, net, os::unix, path, process};
+
+/// Outcome of a single check, `detail` giving what was found or why it failed
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl fmt::Display, result: Result<String, String>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Self {
+            name: name.to_string(),
+            passed,
+            detail,
+        }
+    }
+
+    /// A check which was not run, reported as passed
+    pub fn skipped(name: impl fmt::Display, reason: &str) -> Self {
+        Self::new(name, Ok(format!("skipped, {reason}")))
+    }
+}
+
+/// Checks that UDP packets can be received at `addr`, releasing the address immediately
+pub fn udp_bind(name: &str, addr: net::SocketAddr) -> Check {
+    Check::new(
+        name,
+        net::UdpSocket::bind(addr)
+            .map(|_| format!("{addr} can be bound"))
+            .map_err(|e| format!("cannot bind {addr}: {e}")),
+    )
+}
+
+/// Checks that TCP clients can be accepted at `addr`, releasing the address immediately
+pub fn tcp_bind(name: &str, addr: net::SocketAddr) -> Check {
+    Check::new(
+        name,
+        net::TcpListener::bind(addr)
+            .map(|_| format!("{addr} can be bound"))
+            .map_err(|e| format!("cannot bind {addr}: {e}")),
+    )
+}
+
+/// Checks that `connect` succeeds, the connection being closed immediately
+pub fn connect<S>(
+    name: &str,
+    target: impl fmt::Display,
+    connect: impl FnOnce() -> Result<S, io::Error>,
+) -> Check {
+    Check::new(
+        name,
+        connect()
+            .map(|_| format!("connected to {target}"))
+            .map_err(|e| format!("cannot connect to {target}: {e}")),
+    )
+}
+
+/// Checks that the kernel grants a UDP receive buffer of `size` bytes, failing if it grants less
+/// than `min_size` bytes
+pub fn udp_recv_buffer(name: &str, size: u32, min_size: u64) -> Check {
+    let result = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0))
+        .and_then(|socket| {
+            sock_utils::set_socket_recv_buffer_size(&socket, size as i32)?;
+            sock_utils::get_socket_recv_buffer_size(&socket)
+        })
+        .map_err(|e| format!("cannot set buffer size: {e}"))
+        .and_then(|granted| {
+            if min_size <= granted as u64 {
+                Ok(format!("{granted} bytes granted"))
+            } else {
+                Err(format!(
+                    "{granted} bytes granted, at least {min_size} needed: increase net.core.rmem_max"
+                ))
+            }
+        });
+    Check::new(name, result)
+}
+
+/// Checks that a file can be created in the directory at `path`, and that the filesystem has at
+/// least `min_free_bytes` bytes available
+pub fn writable_dir(name: &str, path: &path::Path, min_free_bytes: u64) -> Check {
+    Check::new(name, check_writable_dir(path, min_free_bytes))
+}
+
+fn check_writable_dir(path: &path::Path, min_free_bytes: u64) -> Result<String, String> {
+    if !path.is_dir() {
+        return Err(format!("'{}' is not a directory", path.display()));
+    }
+
+    let probe = path.join(format!(".diode-check-{}", process::id()));
+    fs::File::create(&probe)
+        .map_err(|e| format!("cannot create files in '{}': {e}", path.display()))?;
+    let _ = fs::remove_file(&probe);
+
+    let stats = fs_utils::statvfs(path)
+        .map_err(|e| format!("cannot get free space of '{}': {e}", path.display()))?;
+    if stats.free_bytes < min_free_bytes {
+        return Err(format!(
+            "'{}' has {} bytes free, {min_free_bytes} needed",
+            path.display(),
+            stats.free_bytes
+        ));
+    }
+    Ok(format!(
+        "'{}' writable, {} bytes free",
+        path.display(),
+        stats.free_bytes
+    ))
+}
+
+/// Checks that a Unix socket can be bound at `path`, which must not exist yet
+pub fn unix_bind(name: &str, path: &path::Path) -> Check {
+    let result = if path.exists() {
+        Err(format!("'{}' already exists", path.display()))
+    } else {
+        match unix::net::UnixListener::bind(path) {
+            Ok(_) => {
+                let _ = fs::remove_file(path);
+                Ok(format!("'{}' can be bound", path.display()))
+            }
+            Err(e) => Err(format!("cannot bind '{}': {e}", path.display())),
+        }
+    };
+    Check::new(name, result)
+}
+
+/// Checks that the files at `paths` can be opened for writing, without modifying them
+pub fn writable_files(name: &str, paths: &[path::PathBuf]) -> Check {
+    let result = paths
+        .iter()
+        .try_for_each(|path| {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map(|_| ())
+                .map_err(|e| format!("cannot write '{}': {e}", path.display()))
+        })
+        .map(|()| "writable".to_string());
+    Check::new(name, result)
+}
+
+/// Checks that the file at `path` can be read
+pub fn readable_file(name: &str, path: &path::Path) -> Check {
+    Check::new(
+        name,
+        fs::File::open(path)
+            .map(|_| format!("'{}' readable", path.display()))
+            .map_err(|e| format!("cannot read '{}': {e}", path.display())),
+    )
+}
+
+/// Prints a table of the outcomes of `checks` to standard output, returning whether all of them
+/// passed
+pub fn report(checks: &[Check]) -> bool {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    for check in checks {
+        let status = if check.passed { "PASS" } else { "FAIL" };
+        println!("{status}  {:width$}  {}", check.name, check.detail);
+    }
+    checks.iter().all(|check| check.passed)
+}
@@ -0,0 +1,417 @@
+//! Validation of the configurations of the sending and receiving ends of the diode
+//!
+//! [crate::send::Config::validate] and [crate::receive::Config::validate] report every problem
+//! of a configuration at once, each [Error] naming the configuration field at fault, so that
+//! binaries can print them all before exiting. [crate::send::Sender::new] and
+//! [crate::receive::Receiver::new] refuse the configurations failing validation.
+
+use crate::{fec, protocol};
+use std::{fmt, net, time};
+
+pub enum Error {
+    /// The field must not be zero
+    Zero(&'static str),
+    /// The address cannot be used by the field
+    InvalidAddress(&'static str, net::SocketAddr),
+    /// The MTU is too small for packets to carry data, the second value being the smallest MTU
+    MtuTooSmall(&'static str, u16, u16),
+    /// The block size is smaller than a packet, the second value being the packet size
+    BlockTooSmall(&'static str, u64, u64),
+    /// The block size makes blocks of too many packets, the second value being the maximum
+    TooManyPackets(&'static str, u64, u64),
+    /// The value is outside of the range described by the string
+    OutOfRange(&'static str, f64, &'static str),
+    /// The value of the field is invalid for the given reason
+    Invalid(&'static str, String),
+    /// The duration of the first field must be shorter than the duration of the second one
+    TimeoutOrder(&'static str, time::Duration, &'static str, time::Duration),
+    /// The first field is only meaningful when the second one is set
+    Requires(&'static str, &'static str),
+}
+
+impl Error {
+    /// Name of the configuration field at fault
+    pub const fn field(&self) -> &'static str {
+        match self {
+            Self::Zero(field)
+            | Self::InvalidAddress(field, _)
+            | Self::MtuTooSmall(field, _, _)
+            | Self::BlockTooSmall(field, _, _)
+            | Self::TooManyPackets(field, _, _)
+            | Self::OutOfRange(field, _, _)
+            | Self::Invalid(field, _)
+            | Self::TimeoutOrder(field, _, _, _)
+            | Self::Requires(field, _) => field,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}: ", self.field())?;
+        match self {
+            Self::Zero(_) => write!(fmt, "must not be zero"),
+            Self::InvalidAddress(_, addr) => {
+                write!(fmt, "{addr} is not a valid destination address")
+            }
+            Self::MtuTooSmall(_, mtu, min) => write!(
+                fmt,
+                "MTU of {mtu} bytes is too small, at least {min} bytes are needed"
+            ),
+            Self::BlockTooSmall(_, size, min) => write!(
+                fmt,
+                "{size} bytes is smaller than a packet, at least {min} bytes are needed"
+            ),
+            Self::TooManyPackets(_, nb_packets, max) => write!(
+                fmt,
+                "blocks of {nb_packets} packets exceed the maximum of {max} packets"
+            ),
+            Self::OutOfRange(_, value, range) => write!(fmt, "{value} is not {range}"),
+            Self::Invalid(_, reason) => write!(fmt, "{reason}"),
+            Self::TimeoutOrder(_, duration, longer, longer_duration) => write!(
+                fmt,
+                "{} ms must be shorter than {longer} ({} ms)",
+                duration.as_millis(),
+                longer_duration.as_millis()
+            ),
+            Self::Requires(_, other) => write!(fmt, "requires {other} to be set"),
+        }
+    }
+}
+
+/// Formats a list of errors on a single line
+pub struct Errors<'a>(pub &'a [Error]);
+
+impl fmt::Display for Errors<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        for (i, e) in self.0.iter().enumerate() {
+            if 0 < i {
+                write!(fmt, ", ")?;
+            }
+            write!(fmt, "{e}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the MTU, block sizes and forward error correction shared by both ends of the diode, for
+/// packets with headers in `format`
+pub(crate) fn check_geometry(
+    errors: &mut Vec<Error>,
+    mtu_field: &'static str,
+    format: protocol::HeaderFormat,
+    mtu: u16,
+    encoding_block_size: u64,
+    repair_block_size: u32,
+    fec: fec::Algorithm,
+) {
+    let min_mtu = protocol::min_mtu(format);
+    if mtu < min_mtu {
+        errors.push(Error::MtuTooSmall(mtu_field, mtu, min_mtu));
+        return;
+    }
+
+    let packet_size = u64::from(protocol::symbol_size(format, mtu));
+    if encoding_block_size == 0 {
+        errors.push(Error::Zero("encoding_block_size"));
+        return;
+    }
+    if encoding_block_size < packet_size {
+        errors.push(Error::BlockTooSmall(
+            "encoding_block_size",
+            encoding_block_size,
+            packet_size,
+        ));
+        return;
+    }
+
+    let nb_encoding_packets = encoding_block_size / packet_size;
+    if protocol::MAX_ENCODING_PACKETS < nb_encoding_packets {
+        errors.push(Error::TooManyPackets(
+            "encoding_block_size",
+            nb_encoding_packets,
+            protocol::MAX_ENCODING_PACKETS,
+        ));
+        return;
+    }
+
+    if fec.max_packets() < nb_encoding_packets {
+        errors.push(Error::TooManyPackets(
+            "encoding_block_size",
+            nb_encoding_packets,
+            fec.max_packets(),
+        ));
+        return;
+    }
+
+    let nb_packets = nb_encoding_packets + u64::from(repair_block_size) / packet_size;
+    if fec.max_packets() < nb_packets {
+        errors.push(Error::TooManyPackets(
+            "repair_block_size",
+            nb_packets,
+            fec.max_packets(),
+        ));
+    }
+}
+
+/// Checks that `addr` can be used as a destination address
+pub(crate) fn check_destination(
+    errors: &mut Vec<Error>,
+    field: &'static str,
+    addr: net::SocketAddr,
+) {
+    if addr.ip().is_unspecified() || addr.port() == 0 {
+        errors.push(Error::InvalidAddress(field, addr));
+    }
+}
+
+/// Checks that the network interface multicast datagrams of `addr` go through is only set for a
+/// multicast address, and is a valid interface name
+pub(crate) fn check_multicast_interface(
+    errors: &mut Vec<Error>,
+    field: &'static str,
+    interface: Option<&str>,
+    addr: net::SocketAddr,
+) {
+    let Some(interface) = interface else {
+        return;
+    };
+    if !addr.ip().is_multicast() {
+        errors.push(Error::Invalid(
+            field,
+            format!("{addr} is not a multicast address"),
+        ));
+    } else if interface.is_empty() || libc::IFNAMSIZ <= interface.len() {
+        errors.push(Error::Invalid(
+            field,
+            format!("\"{interface}\" is not a valid interface name"),
+        ));
+    }
+}
+
+/// Checks that `value` is a number in the range from `min` included to `max` excluded
+pub(crate) fn check_range(
+    errors: &mut Vec<Error>,
+    field: &'static str,
+    value: f64,
+    min: f64,
+    max: f64,
+    range: &'static str,
+) {
+    if !(min <= value && value < max) {
+        errors.push(Error::OutOfRange(field, value, range));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Errors};
+    use crate::{loopback, protocol, receive, send, testing};
+    use std::{io, net, time};
+
+    const TO_UDP: net::SocketAddr =
+        net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::LOCALHOST), 5000);
+
+    fn send_config() -> send::Config {
+        loopback::send_config(&testing::loopback_config(), TO_UDP)
+    }
+
+    fn receive_config() -> receive::Config {
+        loopback::receive_config(&testing::loopback_config(), TO_UDP, None)
+    }
+
+    /// Errors of a configuration expected to have a single problem
+    fn error(res: Result<(), Vec<Error>>) -> Error {
+        let mut errors = res.expect_err("invalid configuration");
+        assert_eq!(errors.len(), 1, "{}", Errors(&errors));
+        errors.remove(0)
+    }
+
+    #[test]
+    fn valid() {
+        assert!(send_config().validate().is_ok());
+        assert!(receive_config().validate().is_ok());
+    }
+
+    #[test]
+    fn zero() {
+        let config = send::Config {
+            nb_clients: 0,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(matches!(e, Error::Zero("nb_clients")), "{e}");
+        assert_eq!(e.to_string(), "nb_clients: must not be zero");
+    }
+
+    #[test]
+    fn invalid_address() {
+        let unspecified = net::SocketAddr::from((net::Ipv4Addr::UNSPECIFIED, 5000));
+        let config = send::Config {
+            to_udp: unspecified,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::InvalidAddress("to_udp", addr) if addr == unspecified),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn mtu_too_small() {
+        let min_mtu = protocol::min_mtu(protocol::HeaderFormat::Narrow);
+        let config = send::Config {
+            to_mtu: min_mtu - 1,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::MtuTooSmall("to_mtu", mtu, min) if mtu == min_mtu - 1 && min == min_mtu),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn block_too_small() {
+        let config = send::Config {
+            encoding_block_size: 100,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::BlockTooSmall("encoding_block_size", 100, _)),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn too_many_packets() {
+        let config = send::Config {
+            encoding_block_size: 1 << 40,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::TooManyPackets("encoding_block_size", _, _)),
+            "{e}"
+        );
+
+        let config = send::Config {
+            repair_block_size: u32::MAX,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::TooManyPackets("repair_block_size", _, _)),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        let config = send::Config {
+            bandwidth_limit: -1.0,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::OutOfRange("bandwidth_limit", value, _) if value == -1.0),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let config = send::Config {
+            site_id: Some("a/b".to_string()),
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(matches!(e, Error::Invalid("site_id", _)), "{e}");
+    }
+
+    #[test]
+    fn timeout_order() {
+        let config = receive::Config {
+            flush_timeout: time::Duration::from_secs(2),
+            heartbeat_interval: Some(time::Duration::from_secs(1)),
+            ..receive_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(
+                e,
+                Error::TimeoutOrder("flush_timeout", _, "heartbeat_interval", _)
+            ),
+            "{e}"
+        );
+        assert_eq!(
+            e.to_string(),
+            "flush_timeout: 2000 ms must be shorter than heartbeat_interval (1000 ms)"
+        );
+    }
+
+    #[test]
+    fn requires() {
+        let config = send::Config {
+            txtime: true,
+            ..send_config()
+        };
+        let e = error(config.validate());
+        assert!(
+            matches!(e, Error::Requires("txtime", "bandwidth_limit")),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn every_error_reported() {
+        let config = send::Config {
+            nb_clients: 0,
+            to_udp: net::SocketAddr::from((net::Ipv4Addr::UNSPECIFIED, 0)),
+            nb_encoding_threads: 0,
+            bandwidth_limit: -1.0,
+            site_id: Some(String::new()),
+            ..send_config()
+        };
+        let errors = config.validate().expect_err("invalid configuration");
+        let fields = errors.iter().map(Error::field).collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                "nb_clients",
+                "nb_encoding_threads",
+                "to_udp",
+                "bandwidth_limit",
+                "site_id"
+            ],
+            "{}",
+            Errors(&errors)
+        );
+        assert_eq!(Errors(&errors).to_string().matches(", ").count(), 4);
+
+        // constructors refuse it with every error
+        let config = send::Config {
+            nb_clients: 0,
+            nb_encoding_threads: 0,
+            ..send_config()
+        };
+        match send::Sender::<net::TcpStream>::new(config) {
+            Err(send::Error::Config(errors)) => assert_eq!(errors.len(), 2),
+            _ => panic!("invalid configuration accepted"),
+        }
+        let config = receive::Config {
+            nb_clients: 0,
+            queue_size: 0,
+            flush_timeout: time::Duration::ZERO,
+            ..receive_config()
+        };
+        match receive::Receiver::new(config, |_| -> Result<net::TcpStream, io::Error> {
+            Err(io::Error::other("no destination"))
+        }) {
+            Err(receive::Error::Config(errors)) => assert_eq!(errors.len(), 3),
+            _ => panic!("invalid configuration accepted"),
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Reading of the parameters of the binaries from TOML configuration files
+//!
+//! A configuration file sets parameters by their command line name, without the leading dashes:
+//!
+//! ```toml
+//! # diode-send.toml
+//! to_udp = "192.168.1.2:5000"
+//! encoding_block_size = 60000
+//! backpressure = true
+//! scan_deny = ["-----BEGIN .* PRIVATE KEY-----"]
+//! ```
+//!
+//! [args] turns the parameters of the file given with `--config` into command line arguments
+//! placed before the actual ones, so that every parameter of a binary can be set in its file.
+//! Parameters given on the command line take precedence: the values of the file are left out for
+//! them, parameters accepting several values included. Flags are set with `true`, and parameters
+//! accepting several values with arrays. With [command], flags also accept `--flag=false` on the
+//! command line, unsetting a flag set in the file.
+//!
+//! The file is parsed as TOML, but tables are not supported.
+
+use std::{ffi, fmt, fs, io, path};
+
+pub enum Error {
+    Io(path::PathBuf, io::Error),
+    MissingPath,
+    Syntax(toml::de::Error),
+    UnknownParameter(String),
+    InvalidValue(String, &'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(path, e) => write!(
+                fmt,
+                "failed to read configuration file {}: {e}",
+                path.display()
+            ),
+            Self::MissingPath => write!(fmt, "--config requires a path"),
+            Self::Syntax(e) => write!(fmt, "configuration file: {e}"),
+            Self::UnknownParameter(key) => {
+                write!(fmt, "configuration file: unknown parameter {key}")
+            }
+            Self::InvalidValue(key, reason) => {
+                write!(fmt, "configuration file: {key} {reason}")
+            }
+        }
+    }
+}
+
+/// Name of the command line parameter giving the path of the configuration file
+const CONFIG_PARAMETER: &str = "config";
+
+/// Makes the flags of `command` take an optional `true` or `false` value, `--flag` alone still
+/// setting them, so that a flag set in the configuration file can be unset on the command line
+pub fn command(command: clap::Command) -> clap::Command {
+    command.mut_args(|arg| {
+        if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+            arg.action(clap::ArgAction::Set)
+                .num_args(0..=1)
+                .require_equals(true)
+                .value_name("true|false")
+                .default_value("false")
+                .default_missing_value("true")
+                .value_parser(clap::value_parser!(bool))
+        } else {
+            arg
+        }
+    })
+}
+
+/// Returns `args`, the command line arguments of `command` starting with the program name, with
+/// the parameters of the configuration file given with `--config` inserted after the program
+/// name, except for the parameters given in `args`
+pub fn args<I>(command: &clap::Command, args: I) -> Result<Vec<ffi::OsString>, Error>
+where
+    I: IntoIterator<Item = ffi::OsString>,
+{
+    let mut args: Vec<ffi::OsString> = args.into_iter().collect();
+    let Some(path) = config_path(&args)? else {
+        return Ok(args);
+    };
+
+    let content = fs::read_to_string(&path).map_err(|e| Error::Io(path.clone(), e))?;
+    let table: toml::Table = content.parse().map_err(Error::Syntax)?;
+
+    // invalid arguments are reported when the whole command line is parsed
+    let given = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok();
+    let on_command_line = |arg: &clap::Arg| {
+        given.as_ref().is_some_and(|given| {
+            given.value_source(arg.get_id().as_str())
+                == Some(clap::parser::ValueSource::CommandLine)
+        })
+    };
+
+    let mut file_args = Vec::new();
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .filter(|_| key != CONFIG_PARAMETER)
+            .ok_or_else(|| Error::UnknownParameter(key.clone()))?;
+
+        let values = match value {
+            toml::Value::Array(values) => {
+                if !matches!(arg.get_action(), clap::ArgAction::Append) {
+                    return Err(Error::InvalidValue(key, "does not accept several values"));
+                }
+                values
+                    .into_iter()
+                    .map(|value| {
+                        scalar(value).ok_or_else(|| {
+                            Error::InvalidValue(
+                                key.clone(),
+                                "must be an array of strings, numbers or booleans",
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            value => vec![scalar(value).ok_or_else(|| {
+                Error::InvalidValue(
+                    key.clone(),
+                    "must be a string, a number, a boolean or an array of them",
+                )
+            })?],
+        };
+
+        if on_command_line(arg) {
+            continue;
+        }
+        // values starting with a dash must not be taken for parameters
+        file_args.extend(
+            values
+                .into_iter()
+                .map(|value| ffi::OsString::from(format!("--{key}={value}"))),
+        );
+    }
+
+    let position = args.len().min(1);
+    args.splice(position..position, file_args);
+    Ok(args)
+}
+
+/// Text of a string, number or boolean as given on the command line
+fn scalar(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(text) => Some(text),
+        toml::Value::Integer(integer) => Some(integer.to_string()),
+        toml::Value::Float(float) => Some(float.to_string()),
+        toml::Value::Boolean(boolean) => Some(boolean.to_string()),
+        _ => None,
+    }
+}
+
+/// Finds the path given with `--config` in the command line arguments
+fn config_path(args: &[ffi::OsString]) -> Result<Option<path::PathBuf>, Error> {
+    let flag = format!("--{CONFIG_PARAMETER}");
+    let prefix = format!("--{CONFIG_PARAMETER}=");
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.to_str() else {
+            continue;
+        };
+        if arg == "--" {
+            break;
+        }
+        if arg == flag {
+            return args
+                .next()
+                .map(|path| Some(path::PathBuf::from(path)))
+                .ok_or(Error::MissingPath);
+        }
+        if let Some(path) = arg.strip_prefix(&prefix) {
+            return Ok(Some(path::PathBuf::from(path)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{args, command, Error};
+    use crate::testing::TempDir;
+    use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+    use std::{ffi, fs};
+
+    fn test_command() -> Command {
+        command(
+            Command::new("test")
+                .args_override_self(true)
+                .arg(Arg::new("config").long("config"))
+                .arg(Arg::new("to_udp").long("to_udp"))
+                .arg(
+                    Arg::new("mtu")
+                        .long("mtu")
+                        .value_parser(value_parser!(u16))
+                        .default_value("1500"),
+                )
+                .arg(
+                    Arg::new("ratio")
+                        .long("ratio")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("backpressure")
+                        .long("backpressure")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("scan_deny")
+                        .long("scan_deny")
+                        .action(ArgAction::Append),
+                ),
+        )
+    }
+
+    /// Command line arguments of `test_command` given `command_line` and a configuration file
+    /// holding `content`
+    fn file_args(content: &str, command_line: &[&str]) -> Result<Vec<ffi::OsString>, Error> {
+        let dir = TempDir::new("config-file");
+        let path = dir.path().join("test.toml");
+        fs::write(&path, content).expect("write");
+        let config = format!("--config={}", path.display());
+        args(
+            &test_command(),
+            ["test", &config]
+                .iter()
+                .chain(command_line)
+                .map(ffi::OsString::from),
+        )
+    }
+
+    fn parse(content: &str, command_line: &[&str]) -> ArgMatches {
+        let args = file_args(content, command_line).unwrap_or_else(|e| panic!("{e}"));
+        test_command()
+            .try_get_matches_from(args)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn scan_deny(matches: &ArgMatches) -> Vec<&str> {
+        matches
+            .get_many::<String>("scan_deny")
+            .map(|values| values.map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn without_file() {
+        let command_line: Vec<ffi::OsString> = ["test", "--mtu", "9000"]
+            .iter()
+            .map(ffi::OsString::from)
+            .collect();
+        let given = args(&test_command(), command_line.clone()).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(given, command_line);
+    }
+
+    #[test]
+    fn quoting() {
+        let matches = parse(
+            r#"
+            to_udp = "a \"quoted\" \\ value"
+            scan_deny = ['C:\literal', "-----BEGIN .* PRIVATE KEY-----", "--mtu=1"]
+            "#,
+            &[],
+        );
+        assert_eq!(
+            matches.get_one::<String>("to_udp").map(String::as_str),
+            Some(r#"a "quoted" \ value"#)
+        );
+        // values starting with dashes are not taken for parameters
+        assert_eq!(
+            scan_deny(&matches),
+            [r"C:\literal", "-----BEGIN .* PRIVATE KEY-----", "--mtu=1"]
+        );
+        assert_eq!(matches.get_one::<u16>("mtu"), Some(&1500));
+
+        let matches = parse("to_udp = \"-1\"\nmtu = 9_000\nratio = 0.5\n", &[]);
+        assert_eq!(
+            matches.get_one::<String>("to_udp").map(String::as_str),
+            Some("-1")
+        );
+        assert_eq!(matches.get_one::<u16>("mtu"), Some(&9000));
+        assert_eq!(matches.get_one::<f64>("ratio"), Some(&0.5));
+    }
+
+    #[test]
+    fn comments() {
+        let matches = parse(
+            "# diode-send.toml\n\n  # indented comment\nmtu = 9000 # trailing comment\nto_udp = \"#not a comment\"\n",
+            &[],
+        );
+        assert_eq!(matches.get_one::<u16>("mtu"), Some(&9000));
+        assert_eq!(
+            matches.get_one::<String>("to_udp").map(String::as_str),
+            Some("#not a comment")
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let file = "scan_deny = [\"a\", 'b']\n";
+        assert_eq!(scan_deny(&parse(file, &[])), ["a", "b"]);
+        // values given on the command line replace the ones of the file
+        assert_eq!(
+            scan_deny(&parse(file, &["--scan_deny", "c", "--scan_deny=d"])),
+            ["c", "d"]
+        );
+        assert!(scan_deny(&parse("scan_deny = []\n", &[])).is_empty());
+
+        assert!(matches!(
+            file_args("mtu = [1500, 9000]\n", &[]),
+            Err(Error::InvalidValue(key, _)) if key == "mtu"
+        ));
+        assert!(matches!(
+            file_args("scan_deny = [[\"a\"]]\n", &[]),
+            Err(Error::InvalidValue(key, _)) if key == "scan_deny"
+        ));
+    }
+
+    #[test]
+    fn precedence() {
+        let matches = parse("to_udp = \"file\"\nmtu = 9000\n", &["--to_udp", "cli"]);
+        assert_eq!(
+            matches.get_one::<String>("to_udp").map(String::as_str),
+            Some("cli")
+        );
+        assert_eq!(matches.get_one::<u16>("mtu"), Some(&9000));
+
+        for (file, command_line, expected) in [
+            ("", &[][..], false),
+            ("", &["--backpressure"][..], true),
+            ("backpressure = true", &[][..], true),
+            ("backpressure = true", &["--backpressure=false"][..], false),
+            ("backpressure = false", &[][..], false),
+            ("backpressure = false", &["--backpressure"][..], true),
+            ("backpressure = false", &["--backpressure=true"][..], true),
+        ] {
+            assert_eq!(
+                parse(file, command_line).get_flag("backpressure"),
+                expected,
+                "{file:?} {command_line:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(matches!(
+            file_args("unknown = 1\n", &[]),
+            Err(Error::UnknownParameter(key)) if key == "unknown"
+        ));
+        assert!(matches!(
+            file_args("config = \"other.toml\"\n", &[]),
+            Err(Error::UnknownParameter(key)) if key == "config"
+        ));
+        assert!(matches!(
+            file_args("[section]\nmtu = 9000\n", &[]),
+            Err(Error::UnknownParameter(key)) if key == "section"
+        ));
+        assert!(matches!(
+            file_args("[to_udp]\nip = \"127.0.0.1\"\n", &[]),
+            Err(Error::InvalidValue(key, _)) if key == "to_udp"
+        ));
+        for content in [
+            "mtu\n",
+            "mtu = \n",
+            "to_udp = unquoted\n",
+            "mtu = 1\nmtu = 2\n",
+        ] {
+            assert!(
+                matches!(file_args(content, &[]), Err(Error::Syntax(_))),
+                "{content:?}"
+            );
+        }
+        // the value of a flag is checked along with the command line
+        let given = file_args("backpressure = \"yes\"\n", &[]).unwrap_or_else(|e| panic!("{e}"));
+        assert!(test_command().try_get_matches_from(given).is_err());
+
+        assert!(matches!(
+            args(
+                &test_command(),
+                ["test", "--config"].iter().map(ffi::OsString::from)
+            ),
+            Err(Error::MissingPath)
+        ));
+        assert!(matches!(
+            args(
+                &test_command(),
+                ["test", "--config", "/nonexistent/test.toml"]
+                    .iter()
+                    .map(ffi::OsString::from)
+            ),
+            Err(Error::Io(..))
+        ));
+    }
+}
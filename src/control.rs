@@ -0,0 +1,129 @@
+//! Line oriented administration socket
+//!
+//! An administration client connects to the Unix socket, writes one command per line and reads
+//! one response line per command. Commands are interpreted by the handler provided by the
+//! binary, which knows which actions are available. [request] sends a single command, as done by
+//! `lidi-ctl`.
+//!
+//! Commands change the behavior of the binary, so the socket is created by [bind] accessible to
+//! its owner only, and [serve] only answers clients running as the same user as the binary or as
+//! root, whatever the permissions of the socket, for example when it is passed by the service
+//! manager.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    mem, net,
+    os::{
+        fd::AsRawFd,
+        unix::{self, fs::PermissionsExt},
+    },
+    path, ptr, time,
+};
+
+/// Time the binary is given to answer a command sent by [request]
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Creates the control socket at `path`, readable and writable by its owner only
+pub fn bind(path: &path::Path) -> Result<unix::net::UnixListener, io::Error> {
+    let listener = unix::net::UnixListener::bind(path)?;
+    // connecting requires write permission, nobody else can connect once permissions are set
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        let _ = fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+pub fn serve<H>(listener: unix::net::UnixListener, handler: H) -> Result<(), io::Error>
+where
+    H: Fn(&str) -> String,
+{
+    for client in listener.incoming() {
+        let client = client?;
+        match peer_uid(&client) {
+            Ok(uid) if is_allowed(uid) => (),
+            Ok(uid) => {
+                log::warn!("control client of user {uid} refused");
+                continue;
+            }
+            Err(e) => {
+                log::warn!("control client refused, its credentials are unknown: {e}");
+                continue;
+            }
+        }
+        if let Err(e) = serve_client(client, &handler) {
+            log::warn!("control client error: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Whether a client running as `uid` may send commands
+fn is_allowed(uid: libc::uid_t) -> bool {
+    uid == 0 || uid == unsafe { libc::geteuid() }
+}
+
+/// Returns the user identifier of the process connected to `client`
+fn peer_uid(client: &unix::net::UnixStream) -> Result<libc::uid_t, io::Error> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            client.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            ptr::addr_of_mut!(cred).cast::<libc::c_void>(),
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn serve_client<H>(client: unix::net::UnixStream, handler: &H) -> Result<(), io::Error>
+where
+    H: Fn(&str) -> String,
+{
+    let mut writer = client.try_clone()?;
+    let reader = io::BufReader::new(client);
+
+    for line in reader.lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        log::debug!("control command \"{command}\"");
+        let response = handler(command);
+        writer.write_all(response.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Sends `command` to the control socket at `path`, returning the response line
+pub fn request(path: &path::Path, command: &str) -> Result<String, io::Error> {
+    let mut client = unix::net::UnixStream::connect(path)?;
+    client.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    client.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    writeln!(client, "{command}")?;
+    client.shutdown(net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    io::BufReader::new(client).read_line(&mut response)?;
+    if response.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed without response",
+        ));
+    }
+    Ok(response.trim_end().to_string())
+}
@@ -0,0 +1,418 @@
+//! Authenticated encryption of the datagrams of the diode link with a pre-shared key
+//!
+//! Datagrams are sealed with XChaCha20-Poly1305, ChaCha20-Poly1305 (RFC 8439) extended to 24
+//! bytes nonces with HChaCha20 (draft-irtf-cfrg-xchacha), as implemented by the
+//! `chacha20poly1305` crate, only the framing of datagrams being done here. Their header stays
+//! in clear, so that
+//! the receiver tells configuration packets apart and reads block identifiers without the key,
+//! but is authenticated as associated data. It is followed by the 24 bytes nonce of the
+//! datagram, then by the encrypted payload and the 16 bytes authentication tag:
+//!
+//! ```text
+//!
+//! <- header -> <-- 16 bytes --> <-- 8 bytes --> <--------------------> <-- 16 bytes -->
+//! -----------+-----------------+---------------+----------------------+----------------
+//! |          |                 |               |                      |              |
+//! |  header  |     session     |   sequence    |  encrypted payload   |     tag      |
+//! |          |                 |               |                      |              |
+//! -----------+-----------------+---------------+----------------------+----------------
+//!
+//! ```
+//!
+//! The nonce is made of a 128 bits random session identifier, drawn by the sender at startup,
+//! and of the little-endian sequence number of the datagram in this session, so that a nonce is
+//! never reused with the same key, even across restarts of the sender or by several senders
+//! sharing the key. Configuration packets (see [crate::protocol::write_config]) must be read
+//! before the receiver knows the encoding parameters: they are authenticated but not encrypted,
+//! the whole packet being associated data followed by the nonce and the tag, so that receivers
+//! without the key still read that the sender encrypts datagrams.
+//!
+//! The key is derived from the content of a file shared by both ends with HKDF-SHA256 (RFC
+//! 5869), so that any secret of at least [MIN_SECRET_LEN] bytes can be used.
+//!
+//! Since the nonce of a datagram is authenticated, the receiver drops replayed datagrams with a
+//! [ReplayWindow] of the nonces already received.
+
+use chacha20poly1305::{AeadInOut, KeyInit, XChaCha20Poly1305};
+use rand::RngCore;
+use std::{collections::VecDeque, fmt, fs, io, path};
+
+/// Size of the nonce carried by datagrams, made of the session identifier and of the sequence
+/// number
+const NONCE_SIZE: usize = 24;
+const SESSION_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+
+/// Number of bytes sealing adds to a datagram
+pub const OVERHEAD: u16 = (NONCE_SIZE + TAG_SIZE) as u16;
+
+/// MTU left to datagrams before sealing on a link of `mtu` bytes
+pub const fn unsealed_mtu(mtu: u16) -> u16 {
+    mtu.saturating_sub(OVERHEAD)
+}
+
+/// Shortest content of a pre-shared key file
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// Number of sequence numbers preceding the highest one received which are still accepted,
+/// datagrams being possibly reordered on the link
+const REPLAY_WINDOW: u64 = 4096;
+
+/// Number of sessions of the sender before the current one whose datagrams are dropped
+const RETIRED_SESSIONS: usize = 16;
+
+/// Label of the derivation of the key from the content of the pre-shared key file
+const KEY_LABEL: &[u8] = b"lidi datagram encryption";
+
+pub enum Error {
+    TooShort(usize),
+    Authentication,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::TooShort(len) => write!(fmt, "sealed datagram of {len} byte(s) is too short"),
+            Self::Authentication => write!(fmt, "datagram authentication failed"),
+        }
+    }
+}
+
+/// Pre-shared key of the diode link
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Derives the key from the content of `path`
+    pub fn read(path: &path::Path) -> Result<Self, io::Error> {
+        let secret = fs::read(path)?;
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "key file '{}' holds {} byte(s), less than {MIN_SECRET_LEN}",
+                    path.display(),
+                    secret.len()
+                ),
+            ));
+        }
+        let mut key = [0; 32];
+        hkdf::Hkdf::<sha2::Sha256>::new(None, &secret)
+            .expand(KEY_LABEL, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Ok(Self(key))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "Key(..)")
+    }
+}
+
+/// Sealing of the datagrams of a sender, holding the nonce of the next one
+pub(crate) struct Sealer {
+    session: [u8; SESSION_SIZE],
+    cipher: XChaCha20Poly1305,
+    sequence: u64,
+}
+
+impl Sealer {
+    pub(crate) fn new(key: &Key) -> Self {
+        let mut session = [0; SESSION_SIZE];
+        rand::thread_rng().fill_bytes(&mut session);
+        Self {
+            session,
+            cipher: key.cipher(),
+            sequence: 0,
+        }
+    }
+
+    /// Appends `datagram` to `out`, its first `clear_len` bytes in clear and the others
+    /// encrypted
+    pub(crate) fn seal(&mut self, clear_len: usize, datagram: &[u8], out: &mut Vec<u8>) {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[..SESSION_SIZE].copy_from_slice(&self.session);
+        nonce[SESSION_SIZE..].copy_from_slice(&self.sequence.to_le_bytes());
+        self.sequence += 1;
+
+        let (clear, payload) = datagram.split_at(clear_len);
+        out.extend_from_slice(clear);
+        out.extend_from_slice(&nonce);
+        let start = out.len();
+        out.extend_from_slice(payload);
+        let tag = self
+            .cipher
+            .encrypt_inout_detached(&nonce.into(), clear, (&mut out[start..]).into())
+            .expect("datagrams are shorter than the XChaCha20-Poly1305 limit");
+        out.extend_from_slice(&tag);
+    }
+}
+
+/// Nonce of a sealed datagram
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Nonce {
+    session: u128,
+    sequence: u64,
+}
+
+impl Nonce {
+    fn from_bytes(bytes: &[u8; NONCE_SIZE]) -> Self {
+        let (session, sequence) = bytes.split_at(SESSION_SIZE);
+        Self {
+            session: u128::from_le_bytes(session.try_into().expect("16 bytes")),
+            sequence: u64::from_le_bytes(sequence.try_into().expect("8 bytes")),
+        }
+    }
+}
+
+/// Nonces of the datagrams already received from the current session of the sender
+///
+/// A bit is kept for each of the [REPLAY_WINDOW] sequence numbers up to the highest one received,
+/// older ones being dropped. Since the sequence numbers restart with each session, the sessions
+/// preceding the current one are remembered, so that their replayed datagrams are not taken for
+/// the start of a new session.
+#[derive(Default)]
+pub(crate) struct ReplayWindow {
+    session: Option<u128>,
+    retired: VecDeque<u128>,
+    highest: u64,
+    /// Bit `sequence % REPLAY_WINDOW` set when `sequence` was received
+    seen: Vec<u64>,
+}
+
+impl ReplayWindow {
+    /// Records `nonce`, returning whether it was not received yet
+    pub(crate) fn check(&mut self, nonce: Nonce) -> bool {
+        if self.session != Some(nonce.session) {
+            if self.retired.contains(&nonce.session) {
+                return false;
+            }
+            if let Some(session) = self.session.replace(nonce.session) {
+                log::info!("sender started a new encryption session");
+                if RETIRED_SESSIONS <= self.retired.len() {
+                    self.retired.pop_front();
+                }
+                self.retired.push_back(session);
+            }
+            self.highest = nonce.sequence;
+            self.seen = vec![0; (REPLAY_WINDOW / 64) as usize];
+        } else if self.highest < nonce.sequence {
+            let forward = (nonce.sequence - self.highest).min(REPLAY_WINDOW);
+            for sequence in nonce.sequence - forward + 1..=nonce.sequence {
+                self.set(sequence, false);
+            }
+            self.highest = nonce.sequence;
+        } else if REPLAY_WINDOW <= self.highest - nonce.sequence || self.get(nonce.sequence) {
+            return false;
+        }
+        self.set(nonce.sequence, true);
+        true
+    }
+
+    fn get(&self, sequence: u64) -> bool {
+        let bit = sequence % REPLAY_WINDOW;
+        self.seen[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, sequence: u64, seen: bool) {
+        let bit = sequence % REPLAY_WINDOW;
+        let word = &mut self.seen[(bit / 64) as usize];
+        if seen {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+}
+
+/// Replaces the content of `out` with the `sealed` datagram once authenticated and decrypted,
+/// its first `clear_len` bytes being in clear, returning its nonce
+pub(crate) fn open(
+    key: &Key,
+    clear_len: usize,
+    sealed: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<Nonce, Error> {
+    if sealed.len() < clear_len + NONCE_SIZE + TAG_SIZE {
+        return Err(Error::TooShort(sealed.len()));
+    }
+    let (clear, rest) = sealed.split_at(clear_len);
+    let (nonce, rest) = rest.split_at(NONCE_SIZE);
+    let (payload, tag) = rest.split_at(rest.len() - TAG_SIZE);
+    let nonce: &[u8; NONCE_SIZE] = nonce.try_into().expect("nonce size");
+    let tag: &[u8; TAG_SIZE] = tag.try_into().expect("tag size");
+
+    out.clear();
+    out.extend_from_slice(clear);
+    out.extend_from_slice(payload);
+    key.cipher()
+        .decrypt_inout_detached(
+            &(*nonce).into(),
+            clear,
+            (&mut out[clear_len..]).into(),
+            &(*tag).into(),
+        )
+        .map_err(|_| Error::Authentication)?;
+    Ok(Nonce::from_bytes(nonce))
+}
+
+/// Length of the part of the sealed configuration packet `sealed` left in clear, the whole
+/// packet being authenticated but not encrypted
+pub(crate) const fn config_clear_len(sealed: &[u8]) -> usize {
+    sealed.len().saturating_sub(OVERHEAD as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("hex"))
+            .collect()
+    }
+
+    fn key(bytes: &[u8]) -> Key {
+        Key(bytes.try_into().expect("32 bytes"))
+    }
+
+    #[test]
+    fn xchacha20_poly1305_draft_a_3_1() {
+        // the test vector of draft-irtf-cfrg-xchacha framed as a datagram, its associated data
+        // being the part in clear
+        let key = key(&hex(
+            "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+        ));
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let mut sealed = aad.clone();
+        sealed.extend(hex("404142434445464748494a4b4c4d4e4f5051525354555657"));
+        sealed.extend(hex("bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b4522f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff921f9664c97637da9768812f615c68b13b52e"));
+        sealed.extend(hex("c0875924c1c7987947deafd8780acf49"));
+
+        let mut opened = Vec::new();
+        let Ok(nonce) = open(&key, aad.len(), &sealed, &mut opened) else {
+            panic!("datagram not authenticated");
+        };
+        assert_eq!(opened[..aad.len()], aad);
+        assert_eq!(
+            &opened[aad.len()..],
+            b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it."
+        );
+        assert_eq!(nonce.sequence, 0x5756_5554_5352_5150);
+    }
+
+    #[test]
+    fn key_derivation() {
+        let dir = TempDir::new("crypto-key");
+        let path = dir.path().join("psk");
+        fs::write(&path, b"too short").expect("write key");
+        assert!(Key::read(&path).is_err());
+
+        // input keying material of RFC 5869 test case 3, expanded with the label of the
+        // derivation, the key of a secret must never change
+        fs::write(&path, [0x0b; 22]).expect("write key");
+        let derived = Key::read(&path).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(
+            derived.0.to_vec(),
+            hex("9c33a574c2b5a2eaea2c886ded8c1235dceee2c1c34a016b7196385ec0c91598")
+        );
+    }
+
+    const HEADER_SIZE: usize = 8;
+
+    #[test]
+    fn sealed_datagram_round_trip() {
+        let key = key(&[7; 32]);
+        let mut sealer = Sealer::new(&key);
+        let datagram = (0..100).collect::<Vec<u8>>();
+
+        let mut sealed = Vec::new();
+        sealer.seal(HEADER_SIZE, &datagram, &mut sealed);
+        assert_eq!(sealed.len(), datagram.len() + usize::from(OVERHEAD));
+        assert_eq!(sealed[..HEADER_SIZE], datagram[..HEADER_SIZE]);
+        assert_ne!(
+            sealed[HEADER_SIZE + NONCE_SIZE..][..10],
+            datagram[HEADER_SIZE..][..10]
+        );
+
+        let mut opened = Vec::new();
+        let Ok(nonce) = open(&key, HEADER_SIZE, &sealed, &mut opened) else {
+            panic!("datagram not authenticated");
+        };
+        assert_eq!(opened, datagram);
+        assert_eq!(nonce.sequence, 0);
+
+        // header, nonce, payload and tag are all authenticated
+        for i in [0, HEADER_SIZE, HEADER_SIZE + NONCE_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(open(&key, HEADER_SIZE, &tampered, &mut opened).is_err());
+        }
+        assert!(open(&Key([8; 32]), HEADER_SIZE, &sealed, &mut opened).is_err());
+        assert!(matches!(
+            open(&key, HEADER_SIZE, &sealed[..30], &mut opened),
+            Err(Error::TooShort(30))
+        ));
+    }
+
+    #[test]
+    fn sealers_draw_distinct_sessions() {
+        let key = key(&[7; 32]);
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        Sealer::new(&key).seal(HEADER_SIZE, &[0; 20], &mut first);
+        Sealer::new(&key).seal(HEADER_SIZE, &[0; 20], &mut second);
+        assert_ne!(
+            first[HEADER_SIZE..][..NONCE_SIZE],
+            second[HEADER_SIZE..][..NONCE_SIZE]
+        );
+    }
+
+    #[test]
+    fn config_packet_authenticated_in_clear() {
+        let key = key(&[7; 32]);
+        let mut sealer = Sealer::new(&key);
+        let config = (0..40).collect::<Vec<u8>>();
+
+        let mut sealed = Vec::new();
+        sealer.seal(config.len(), &config, &mut sealed);
+        // still readable without the key
+        assert_eq!(sealed[..config.len()], config[..]);
+        assert_eq!(config_clear_len(&sealed), config.len());
+
+        let mut opened = Vec::new();
+        assert!(open(&key, config_clear_len(&sealed), &sealed, &mut opened).is_ok());
+        assert_eq!(opened, config);
+
+        // a spoofed configuration packet, in clear or with a forged tag, is rejected
+        assert!(open(&key, config_clear_len(&config), &config, &mut opened).is_err());
+        let mut forged = sealed.clone();
+        forged[1] ^= 1;
+        assert!(open(&key, config_clear_len(&forged), &forged, &mut opened).is_err());
+    }
+
+    #[test]
+    fn replay_window() {
+        let nonce = |session, sequence| Nonce { session, sequence };
+        let mut window = ReplayWindow::default();
+
+        assert!(window.check(nonce(1, 10)));
+        assert!(!window.check(nonce(1, 10)));
+        assert!(window.check(nonce(1, 5)));
+        assert!(window.check(nonce(1, 10 + REPLAY_WINDOW)));
+        // out of the window
+        assert!(!window.check(nonce(1, 9)));
+
+        // a new session of the sender, the previous one being retired
+        assert!(window.check(nonce(2, 0)));
+        assert!(!window.check(nonce(1, 11 + REPLAY_WINDOW)));
+        assert!(!window.check(nonce(2, 0)));
+        assert!(window.check(nonce(2, 1)));
+    }
+}
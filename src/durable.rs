@@ -0,0 +1,543 @@
+//! Append-only record files with a configurable durability policy
+//!
+//! Each record is framed by its length and a CRC-32 checksum of its content:
+//!
+//! ```text
+//!
+//! <-- 4 bytes --> <-- 4 bytes -->
+//! ---------------+---------------+-----------------------
+//! |              |               |                     |
+//! |    length    |     crc32     |       content       |
+//! |              |               |                     |
+//! ---------------+---------------+-----------------------
+//!
+//! ```
+//!
+//! 4-bytes values are encoded in little-endian byte order. A record torn by a crash while being
+//! written is detected by [replay], which stops at the last valid record, and is truncated away
+//! when the file is opened again by [DurableWriter::open].
+//!
+//! The [Policy] selects when records are synchronized to disk. Synchronizations are shared by
+//! concurrent writers (group commit): a writer waiting for its record to be on disk is released
+//! by a synchronization started after its record was written, whichever writer started it.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    path,
+    str::FromStr,
+    sync, time,
+};
+
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// When records are synchronized to disk
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Policy {
+    /// Never, leaving it to the kernel
+    None,
+    /// When a record is appended at least this long after the previous synchronization
+    Interval(time::Duration),
+    /// Every given number of records
+    Records(u32),
+    /// Before [DurableWriter::append] returns
+    Always,
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::None => write!(fmt, "none"),
+            Self::Interval(interval) => write!(fmt, "interval:{}", interval.as_millis()),
+            Self::Records(nb) => write!(fmt, "records:{nb}"),
+            Self::Always => write!(fmt, "always"),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    /// Parses `none`, `interval:<nb_milliseconds>`, `records:<nb>` or `always`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid durability policy \"{s}\"");
+        match s.split_once(':') {
+            None if s == "none" => Ok(Self::None),
+            None if s == "always" => Ok(Self::Always),
+            Some(("interval", ms)) => ms
+                .parse()
+                .map(|ms| Self::Interval(time::Duration::from_millis(ms)))
+                .map_err(|_| invalid()),
+            Some(("records", nb)) => match nb.parse() {
+                Ok(0) | Err(_) => Err(invalid()),
+                Ok(nb) => Ok(Self::Records(nb)),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Result of reading the records of a file
+pub struct Replay {
+    pub records: Vec<Vec<u8>>,
+    /// Length of the file up to the end of the last valid record
+    pub valid_len: u64,
+    /// Whether invalid data, usually a record torn by a crash, follows the last valid record
+    pub torn: bool,
+}
+
+/// Reads the valid records of the file at `path`, stopping at the first torn or corrupted one
+pub fn replay(path: &path::Path) -> Result<Replay, io::Error> {
+    let mut input = io::BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+
+    loop {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        match read_full(&mut input, &mut header)? {
+            0 => {
+                return Ok(Replay {
+                    records,
+                    valid_len,
+                    torn: false,
+                })
+            }
+            RECORD_HEADER_SIZE => (),
+            _ => break,
+        }
+
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        let mut content = vec![0; len];
+        if read_full(&mut input, &mut content)? != len || crc32(&content) != crc {
+            break;
+        }
+
+        valid_len += (RECORD_HEADER_SIZE + len) as u64;
+        records.push(content);
+    }
+
+    Ok(Replay {
+        records,
+        valid_len,
+        torn: true,
+    })
+}
+
+/// Reads as many bytes as possible into `buffer`, returning less than its length only at the end
+/// of the input
+fn read_full<R: Read>(input: &mut R, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match input.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+struct State {
+    file: File,
+    /// Number of records written since the file was opened
+    written: u64,
+    /// Number of records known to be on disk
+    synced: u64,
+    /// Whether a writer is synchronizing the file
+    syncing: bool,
+    synced_at: time::Instant,
+}
+
+/// Append-only writer of framed records, see [crate::durable]
+pub struct DurableWriter {
+    policy: Policy,
+    /// Handle used to synchronize the file without holding the state lock
+    sync_file: File,
+    state: sync::Mutex<State>,
+    sync_done: sync::Condvar,
+}
+
+impl DurableWriter {
+    /// Opens the file at `path` to append records to it, creating it if needed
+    ///
+    /// Data following the last valid record, left by a crash, is truncated.
+    pub fn open(path: &path::Path, policy: Policy) -> Result<Self, io::Error> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let replay = replay(path)?;
+        if replay.torn {
+            log::warn!(
+                "truncating torn record at offset {} of \"{}\"",
+                replay.valid_len,
+                path.display()
+            );
+            file.set_len(replay.valid_len)?;
+            file.sync_data()?;
+        }
+        file.seek(io::SeekFrom::Start(replay.valid_len))?;
+
+        Ok(Self {
+            policy,
+            sync_file: file.try_clone()?,
+            state: sync::Mutex::new(State {
+                file,
+                written: 0,
+                synced: 0,
+                syncing: false,
+                synced_at: time::Instant::now(),
+            }),
+            sync_done: sync::Condvar::new(),
+        })
+    }
+
+    pub const fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Appends `record`, returning once it is on disk if the policy requires it
+    pub fn append(&self, record: &[u8]) -> Result<(), io::Error> {
+        let len = u32::try_from(record.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+
+        let mut framed = Vec::with_capacity(RECORD_HEADER_SIZE + record.len());
+        framed.extend_from_slice(&len.to_le_bytes());
+        framed.extend_from_slice(&crc32(record).to_le_bytes());
+        framed.extend_from_slice(record);
+
+        let mut state = self.state.lock().expect("acquire lock");
+        state.file.write_all(&framed)?;
+        state.written += 1;
+        let seq = state.written;
+
+        match self.policy {
+            Policy::None => Ok(()),
+            Policy::Interval(interval) => {
+                if interval <= state.synced_at.elapsed() {
+                    self.sync_until(state, seq, false)?;
+                }
+                Ok(())
+            }
+            Policy::Records(nb) => {
+                if u64::from(nb) <= seq - state.synced {
+                    self.sync_until(state, seq, false)?;
+                }
+                Ok(())
+            }
+            Policy::Always => self.sync_until(state, seq, true),
+        }
+    }
+
+    /// Synchronizes all the records appended so far to disk
+    pub fn sync(&self) -> Result<(), io::Error> {
+        let state = self.state.lock().expect("acquire lock");
+        let seq = state.written;
+        self.sync_until(state, seq, true)
+    }
+
+    /// Makes sure the first `seq` records are on disk
+    ///
+    /// If another writer is already synchronizing, its synchronization may not cover record
+    /// `seq`: when `wait` is set, the writer waits for it to end and synchronizes again if needed,
+    /// otherwise the record is left to the next synchronization.
+    fn sync_until(
+        &self,
+        mut state: sync::MutexGuard<'_, State>,
+        seq: u64,
+        wait: bool,
+    ) -> Result<(), io::Error> {
+        loop {
+            if seq <= state.synced {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            if !wait {
+                return Ok(());
+            }
+            state = self.sync_done.wait(state).expect("acquire lock");
+        }
+
+        // this writer is the group commit leader, for all the records written so far
+        let target = state.written;
+        state.syncing = true;
+        drop(state);
+
+        let res = self.sync_file.sync_data();
+
+        let mut state = self.state.lock().expect("acquire lock");
+        state.syncing = false;
+        if res.is_ok() {
+            state.synced = state.synced.max(target);
+            state.synced_at = time::Instant::now();
+        }
+        drop(state);
+        self.sync_done.notify_all();
+
+        res
+    }
+}
+
+impl Drop for DurableWriter {
+    fn drop(&mut self) {
+        if self.policy != Policy::None {
+            if let Err(e) = self.sync() {
+                log::error!("failed to synchronize records: {e}");
+            }
+        }
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE 802.3) checksum
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::thread;
+
+    fn records(nb: usize) -> Vec<Vec<u8>> {
+        (0..nb)
+            .map(|i| format!("record {i}").repeat(i % 4).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn policy_from_str() {
+        for policy in [
+            Policy::None,
+            Policy::Interval(time::Duration::from_millis(250)),
+            Policy::Records(10),
+            Policy::Always,
+        ] {
+            assert_eq!(Policy::from_str(&policy.to_string()), Ok(policy));
+        }
+        for s in [
+            "",
+            "sometimes",
+            "records:0",
+            "records:x",
+            "interval:",
+            "always:1",
+        ] {
+            assert!(Policy::from_str(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = TempDir::new("durable-reopen");
+        let records = records(10);
+
+        for (i, policy) in [
+            Policy::None,
+            Policy::Interval(time::Duration::ZERO),
+            Policy::Records(3),
+            Policy::Always,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let path = dir.path().join(format!("records-{i}"));
+            let (first, second) = records.split_at(4);
+            for part in [first, second] {
+                let writer = DurableWriter::open(&path, policy).expect("open");
+                for record in part {
+                    writer.append(record).expect("append");
+                }
+            }
+
+            let replay = replay(&path).expect("replay");
+            assert_eq!(replay.records, records, "{policy}");
+            assert!(!replay.torn);
+            assert_eq!(
+                replay.valid_len,
+                fs::metadata(&path).expect("metadata").len()
+            );
+        }
+    }
+
+    /// Simulates a crash at every offset of the file, keeping only the data written before it
+    #[test]
+    fn crash_at_every_offset() {
+        let dir = TempDir::new("durable-crash");
+        let path = dir.path().join("records");
+        let records = records(6);
+        {
+            let writer = DurableWriter::open(&path, Policy::Always).expect("open");
+            for record in &records {
+                writer.append(record).expect("append");
+            }
+        }
+        let data = fs::read(&path).expect("read records");
+
+        let mut boundaries = vec![0];
+        for record in &records {
+            boundaries
+                .push(boundaries.last().expect("boundary") + RECORD_HEADER_SIZE + record.len());
+        }
+        assert_eq!(*boundaries.last().expect("boundary"), data.len());
+
+        let crashed = dir.path().join("crashed");
+        for len in 0..=data.len() {
+            fs::write(&crashed, &data[..len]).expect("write crashed file");
+            let nb_valid = boundaries.iter().filter(|&&b| 0 < b && b <= len).count();
+            let valid_len = boundaries[nb_valid] as u64;
+
+            let replay = replay(&crashed).expect("replay");
+            assert_eq!(replay.records, records[..nb_valid], "crash at {len}");
+            assert_eq!(replay.valid_len, valid_len, "crash at {len}");
+            assert_eq!(replay.torn, valid_len != len as u64, "crash at {len}");
+
+            // the torn record is truncated and appending goes on after the last valid one
+            {
+                let writer = DurableWriter::open(&crashed, Policy::None).expect("open");
+                writer.append(b"after crash").expect("append");
+            }
+            let replay = super::replay(&crashed).expect("replay");
+            assert!(!replay.torn, "crash at {len}");
+            assert_eq!(replay.records.len(), nb_valid + 1, "crash at {len}");
+            assert_eq!(replay.records[..nb_valid], records[..nb_valid]);
+            assert_eq!(replay.records[nb_valid], b"after crash");
+        }
+    }
+
+    #[test]
+    fn corrupted_record() {
+        let dir = TempDir::new("durable-corrupted");
+        let path = dir.path().join("records");
+        let records = records(4);
+        {
+            let writer = DurableWriter::open(&path, Policy::None).expect("open");
+            for record in &records {
+                writer.append(record).expect("append");
+            }
+        }
+
+        // flipping a bit of the content of the third record
+        let mut data = fs::read(&path).expect("read records");
+        let offset =
+            2 * RECORD_HEADER_SIZE + records[0].len() + records[1].len() + RECORD_HEADER_SIZE;
+        data[offset] ^= 0x01;
+        fs::write(&path, &data).expect("write records");
+
+        let replay = replay(&path).expect("replay");
+        assert_eq!(replay.records, records[..2]);
+        assert!(replay.torn);
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        let dir = TempDir::new("durable-concurrent");
+        let path = dir.path().join("records");
+        let writer = DurableWriter::open(&path, Policy::Always).expect("open");
+
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let writer = &writer;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        writer
+                            .append(format!("writer {t} record {i}").as_bytes())
+                            .expect("append");
+                    }
+                });
+            }
+        });
+        drop(writer);
+
+        let replay = replay(&path).expect("replay");
+        assert!(!replay.torn);
+        let mut records = replay
+            .records
+            .into_iter()
+            .map(|record| String::from_utf8(record).expect("record"))
+            .collect::<Vec<_>>();
+        records.sort();
+        let mut expected = (0..4)
+            .flat_map(|t| (0..50).map(move |i| format!("writer {t} record {i}")))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(records, expected);
+    }
+
+    /// Appending throughput of each policy, with concurrent writers sharing synchronizations
+    ///
+    /// Run with `cargo test --release durable::tests::throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn throughput() {
+        let dir = TempDir::new("durable-throughput");
+        let record = [0x5a; 256];
+
+        for policy in [
+            Policy::None,
+            Policy::Interval(time::Duration::from_millis(10)),
+            Policy::Records(100),
+            Policy::Always,
+        ] {
+            for nb_writers in [1, 8] {
+                let path = dir.path().join(format!("{policy}-{nb_writers}"));
+                let writer = DurableWriter::open(&path, policy).expect("open");
+                let nb_records = 2000 / nb_writers;
+                let start = time::Instant::now();
+                thread::scope(|scope| {
+                    for _ in 0..nb_writers {
+                        scope.spawn(|| {
+                            for _ in 0..nb_records {
+                                writer.append(&record).expect("append");
+                            }
+                        });
+                    }
+                });
+                writer.sync().expect("sync");
+                let elapsed = start.elapsed();
+                println!(
+                    "{:>12}, {nb_writers} writer(s): {:.0} records/s",
+                    policy.to_string(),
+                    (nb_writers * nb_records) as f64 / elapsed.as_secs_f64()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Stream of lifecycle events published on a Unix socket for real-time dashboards
+//!
+//! Any number of local subscribers can connect to the socket, they then receive one JSON object
+//! per line (NDJSON). Subscribers are written to in non-blocking mode: a subscriber that does not
+//! read its events fast enough is disconnected rather than stalling the pipeline.
+//!
+//! Each event object contains the `version` of the events format, the `event` type name, a
+//! `timestamp` (seconds since UNIX epoch) and the fields of the corresponding [Event] variant.
+
+use crate::{metrics, protocol};
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    os::unix,
+    sync, time,
+};
+
+/// Version of the events format, to be incremented on incompatible changes
+pub const VERSION: u32 = 1;
+
+pub enum Event {
+    /// A new transfer started, `client_id` being its identifier on the diode and `site_id` the
+    /// identifier of the sender site, if provided
+    SessionStart {
+        client_id: u32,
+        site_id: Option<String>,
+    },
+    /// A transfer completed, `bytes` being the number of bytes delivered to the destination and
+    /// `held` the time the data was held before delivery, in store and forward mode
+    SessionEnd {
+        client_id: u32,
+        bytes: u64,
+        held: Option<time::Duration>,
+    },
+    /// A transfer was aborted, either by the sender or after a synchronization loss
+    SessionAbort { client_id: u32, bytes: u64 },
+    /// A block could not be decoded, its data is lost
+    DecodeFailure { block_id: protocol::BlockId },
+    /// No heartbeat was received during the expected interval
+    LinkDown,
+    /// Heartbeats are received again after a `LinkDown` event
+    LinkUp,
+    /// A connection to the destination was opened for a transfer, `address` being the address
+    /// of the destination if it is reached over IP
+    DestinationConnected {
+        client_id: u32,
+        address: Option<String>,
+    },
+    /// The connection to the destination failed for a transfer
+    DestinationFailed { client_id: u32, error: String },
+}
+
+impl Event {
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::SessionStart { .. } => "session_start",
+            Self::SessionEnd { .. } => "session_end",
+            Self::SessionAbort { .. } => "session_abort",
+            Self::DecodeFailure { .. } => "decode_failure",
+            Self::LinkDown => "link_down",
+            Self::LinkUp => "link_up",
+            Self::DestinationConnected { .. } => "destination_connected",
+            Self::DestinationFailed { .. } => "destination_failed",
+        }
+    }
+
+    /// Serializes the event as a single line JSON object, without the trailing newline
+    pub fn to_json(&self) -> String {
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut json = format!(
+            "{{\"version\":{VERSION},\"event\":\"{}\",\"timestamp\":{timestamp:.6}",
+            self.name()
+        );
+
+        // writing into a String cannot fail
+        let _ = match self {
+            Self::SessionStart { client_id, site_id } => write!(json, ",\"client_id\":{client_id}")
+                .and_then(|()| match site_id {
+                    Some(site_id) => write!(json, ",\"site_id\":{}", json_string(site_id)),
+                    None => Ok(()),
+                }),
+            Self::DestinationConnected { client_id, address } => {
+                write!(json, ",\"client_id\":{client_id}").and_then(|()| match address {
+                    Some(address) => write!(json, ",\"address\":{}", json_string(address)),
+                    None => Ok(()),
+                })
+            }
+            Self::SessionEnd {
+                client_id,
+                bytes,
+                held,
+            } => write!(json, ",\"client_id\":{client_id},\"bytes\":{bytes}").and_then(|()| {
+                match held {
+                    Some(held) => write!(json, ",\"held_ms\":{}", held.as_millis()),
+                    None => Ok(()),
+                }
+            }),
+            Self::SessionAbort { client_id, bytes } => {
+                write!(json, ",\"client_id\":{client_id},\"bytes\":{bytes}")
+            }
+            Self::DecodeFailure { block_id } => write!(json, ",\"block_id\":{block_id}"),
+            Self::LinkDown | Self::LinkUp => Ok(()),
+            Self::DestinationFailed { client_id, error } => write!(
+                json,
+                ",\"client_id\":{client_id},\"error\":{}",
+                json_string(error)
+            ),
+        };
+
+        json.push('}');
+        json
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[derive(Default)]
+pub struct Events {
+    subscribers: sync::Mutex<Vec<unix::net::UnixStream>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts subscribers on `listener`, this function only returns on error
+    pub fn serve(&self, listener: unix::net::UnixListener) -> Result<(), io::Error> {
+        for subscriber in listener.incoming() {
+            let subscriber = subscriber?;
+            subscriber.set_nonblocking(true)?;
+            log::debug!("new events subscriber");
+            self.subscribers
+                .lock()
+                .expect("acquire lock")
+                .push(subscriber);
+        }
+        Ok(())
+    }
+
+    /// Sends `event` to every subscriber, disconnecting those that cannot accept it immediately
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().expect("acquire lock");
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let mut line = event.to_json();
+        line.push('\n');
+
+        subscribers.retain_mut(|subscriber| match subscriber.write(line.as_bytes()) {
+            Ok(n) if n == line.len() => true,
+            Ok(_) | Err(_) => {
+                log::warn!("disconnecting slow or closed events subscriber");
+                metrics::counter("events_subscribers_dropped").inc();
+                false
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, Event, Events};
+    use crate::{
+        metrics,
+        testing::{self, json_field as field, Diode, TempDir},
+    };
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix,
+        thread, time,
+    };
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("site"), "\"site\"");
+        assert_eq!(
+            json_string("a\"b\\c\nd\re\tf\u{1}"),
+            "\"a\\\"b\\\\c\\nd\\re\\tf\\u0001\""
+        );
+
+        let json = Event::DestinationFailed {
+            client_id: 7,
+            error: "refused \"x\"".to_string(),
+        }
+        .to_json();
+        assert!(json.starts_with("{\"version\":1,\"event\":\"destination_failed\",\"timestamp\":"));
+        assert!(json.ends_with(",\"client_id\":7,\"error\":\"refused \\\"x\\\"\"}"));
+        assert!(!json.contains('\n'));
+
+        let json = Event::SessionEnd {
+            client_id: 1,
+            bytes: 10,
+            held: Some(time::Duration::from_millis(1500)),
+        }
+        .to_json();
+        assert_eq!(field(&json, "bytes"), Some("10"));
+        assert_eq!(field(&json, "held_ms"), Some("1500"));
+        assert_eq!(
+            field(&Event::LinkDown.to_json(), "event"),
+            Some("link_down")
+        );
+    }
+
+    #[test]
+    fn slow_subscribers_dropped() {
+        let dir = TempDir::new("events-slow");
+        let path = dir.path().join("events.sock");
+        let listener = unix::net::UnixListener::bind(&path).expect("bind");
+        let events: &'static Events = Box::leak(Box::default());
+        thread::spawn(move || events.serve(listener));
+
+        let dropped = metrics::counter("events_subscribers_dropped");
+        let nb_dropped = dropped.get();
+        let _subscriber = unix::net::UnixStream::connect(&path).expect("connect");
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        // the subscriber never reads, until its socket buffer is full
+        while dropped.get() == nb_dropped {
+            assert!(time::Instant::now() < deadline, "subscriber not dropped");
+            events.emit(Event::SessionStart {
+                client_id: 1,
+                site_id: Some("x".repeat(1000)),
+            });
+        }
+        assert!(events.subscribers.lock().expect("acquire lock").is_empty());
+    }
+
+    #[test]
+    fn session_events() {
+        let dir = TempDir::new("events");
+        let path = dir.path().join("events.sock");
+        let socket_path = path.clone();
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |_| (),
+            move |config| config.events_socket = Some(socket_path),
+        );
+
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        let subscriber = loop {
+            match unix::net::UnixStream::connect(&path) {
+                Ok(subscriber) => break subscriber,
+                Err(e) => assert!(time::Instant::now() < deadline, "{e}"),
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        };
+        // accepted by the receiver before the session starts
+        thread::sleep(time::Duration::from_millis(200));
+        subscriber
+            .set_read_timeout(Some(time::Duration::from_secs(10)))
+            .expect("read timeout");
+
+        diode.send(&[1; 50_000]);
+        assert_eq!(
+            diode
+                .receive(time::Duration::from_secs(10))
+                .map(|data| data.len()),
+            Some(50_000)
+        );
+
+        let mut events = Vec::new();
+        for line in BufReader::new(subscriber).lines() {
+            let line = line.expect("event");
+            assert!(line.starts_with('{') && line.ends_with('}'), "{line}");
+            assert_eq!(field(&line, "version"), Some("1"), "{line}");
+            assert!(field(&line, "timestamp").is_some_and(|t| t.parse::<f64>().is_ok()));
+            let event = field(&line, "event").expect("event type").to_string();
+            let end = event == "session_end";
+            if end {
+                assert_eq!(field(&line, "bytes"), Some("50000"), "{line}");
+            }
+            events.push(event);
+            if end {
+                break;
+            }
+        }
+        assert_eq!(
+            events,
+            ["session_start", "destination_connected", "session_end"]
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Delivery to a secondary TCP destination while the primary one is failing
+//!
+//! Consecutive connection and write failures on the primary destination are counted. Once
+//! `failover_after` failures are reached, new sessions are delivered to the secondary
+//! destination. The primary destination is then probed (connected to, the connection being
+//! closed immediately) at most every `failback_after`, when a session starts: as soon as a probe
+//! succeeds, sessions are delivered to the primary destination again. Since the destination is
+//! only chosen when a session connects, a session is never moved from one destination to the
+//! other.
+
+use crate::{metrics, resolver};
+use std::{
+    fmt,
+    io::{self, Write},
+    net,
+    os::fd::{AsRawFd, RawFd},
+    sync::{self, Arc},
+    time,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Destination {
+    Primary,
+    Secondary,
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Primary => write!(fmt, "primary"),
+            Self::Secondary => write!(fmt, "secondary"),
+        }
+    }
+}
+
+struct State {
+    active: Destination,
+    failures: u32,
+    probed_at: time::Instant,
+}
+
+struct Shared {
+    failover_after: u32,
+    state: sync::Mutex<State>,
+}
+
+impl Shared {
+    /// Records a failure of the primary destination, returning true if it triggered the failover
+    fn primary_failed(&self) -> bool {
+        let mut state = self.state.lock().expect("acquire lock");
+        if state.active != Destination::Primary {
+            return false;
+        }
+        state.failures += 1;
+        if state.failures < self.failover_after {
+            return false;
+        }
+        log::error!(
+            "primary destination failed {} consecutive times, failing over to secondary destination",
+            state.failures
+        );
+        metrics::counter("rx_destination_failovers").inc();
+        state.active = Destination::Secondary;
+        state.failures = 0;
+        state.probed_at = time::Instant::now();
+        true
+    }
+}
+
+pub struct Failover {
+    primary: resolver::Resolver,
+    secondary: resolver::Resolver,
+    failback_after: time::Duration,
+    shared: Arc<Shared>,
+}
+
+impl fmt::Display for Failover {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{} (failing over to {})", self.primary, self.secondary)
+    }
+}
+
+impl Failover {
+    pub fn new(
+        primary: resolver::Resolver,
+        secondary: resolver::Resolver,
+        failover_after: u32,
+        failback_after: time::Duration,
+    ) -> Self {
+        Self {
+            primary,
+            secondary,
+            failback_after,
+            shared: Arc::new(Shared {
+                failover_after: failover_after.max(1),
+                state: sync::Mutex::new(State {
+                    active: Destination::Primary,
+                    failures: 0,
+                    probed_at: time::Instant::now(),
+                }),
+            }),
+        }
+    }
+
+    /// Probes the primary destination if it is time to, failing back to it if it is reachable
+    fn probe(&self) {
+        let mut state = self.shared.state.lock().expect("acquire lock");
+        if state.active != Destination::Secondary || state.probed_at.elapsed() < self.failback_after
+        {
+            return;
+        }
+        state.probed_at = time::Instant::now();
+        drop(state);
+
+        if self.primary.connect().is_ok() {
+            let mut state = self.shared.state.lock().expect("acquire lock");
+            if state.active == Destination::Secondary {
+                log::info!("primary destination is reachable, failing back to it");
+                metrics::counter("rx_destination_failbacks").inc();
+                state.active = Destination::Primary;
+                state.failures = 0;
+            }
+        }
+    }
+
+    /// Connects a new session to the active destination
+    pub fn connect(&self) -> Result<Stream, io::Error> {
+        self.probe();
+
+        let active = self.shared.state.lock().expect("acquire lock").active;
+
+        let destination = if active == Destination::Primary {
+            match self.primary.connect() {
+                Ok(stream) => {
+                    self.shared.state.lock().expect("acquire lock").failures = 0;
+                    return Ok(self.stream(stream, Destination::Primary));
+                }
+                Err(e) => {
+                    if !self.shared.primary_failed() {
+                        return Err(e);
+                    }
+                    // the session is not lost if the secondary destination is reachable
+                    Destination::Secondary
+                }
+            }
+        } else {
+            active
+        };
+
+        let stream = self.secondary.connect()?;
+        Ok(self.stream(stream, destination))
+    }
+
+    fn stream(&self, stream: net::TcpStream, destination: Destination) -> Stream {
+        log::debug!("session delivered to {destination} destination");
+        Stream {
+            stream,
+            destination,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Connection of a session, reporting write failures on the primary destination
+pub struct Stream {
+    stream: net::TcpStream,
+    destination: Destination,
+    shared: Arc<Shared>,
+}
+
+impl Stream {
+    fn failed<T>(&self, res: io::Result<T>) -> io::Result<T> {
+        if res.is_err() && self.destination == Destination::Primary {
+            self.shared.primary_failed();
+        }
+        res
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.stream.write(buf);
+        self.failed(res)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.stream.flush();
+        self.failed(res)
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Destination, Failover};
+    use crate::{metrics, resolver};
+    use std::{io::Write, net, thread, time};
+
+    const FAILBACK_AFTER: time::Duration = time::Duration::from_millis(300);
+
+    fn listener(port: u16) -> net::TcpListener {
+        net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, port)).expect("bind")
+    }
+
+    fn resolver(listener: &net::TcpListener) -> resolver::Resolver {
+        let addr = listener.local_addr().expect("address");
+        resolver::Resolver::new(addr.to_string(), time::Duration::from_secs(3600))
+    }
+
+    fn destination(failover: &Failover) -> Destination {
+        failover
+            .connect()
+            .unwrap_or_else(|e| panic!("{e}"))
+            .destination
+    }
+
+    #[test]
+    fn failover_and_failback() {
+        let primary = listener(0);
+        let port = primary.local_addr().expect("address").port();
+        let secondary = listener(0);
+        let failover = Failover::new(resolver(&primary), resolver(&secondary), 3, FAILBACK_AFTER);
+        let failovers = metrics::counter("rx_destination_failovers").get();
+        let failbacks = metrics::counter("rx_destination_failbacks").get();
+
+        assert!(destination(&failover) == Destination::Primary);
+
+        // sessions are lost until the threshold is reached, then delivered to the secondary
+        drop(primary);
+        assert!(failover.connect().is_err());
+        assert!(failover.connect().is_err());
+        assert!(destination(&failover) == Destination::Secondary);
+        assert!(destination(&failover) == Destination::Secondary);
+        // the other test fails over too
+        assert!(metrics::counter("rx_destination_failovers").get() > failovers);
+
+        // the primary is back, but is not probed before failback_after
+        let _primary = listener(port);
+        let mut session = failover.connect().unwrap_or_else(|e| panic!("{e}"));
+        assert!(session.destination == Destination::Secondary);
+
+        // the running session stays on the secondary, the next one fails back
+        thread::sleep(FAILBACK_AFTER);
+        session.write_all(b"session").expect("write");
+        assert!(destination(&failover) == Destination::Primary);
+        session.write_all(b"still delivered").expect("write");
+        assert!(session.destination == Destination::Secondary);
+        assert_eq!(
+            metrics::counter("rx_destination_failbacks").get(),
+            failbacks + 1
+        );
+    }
+
+    #[test]
+    fn unreachable_primary_stays_failed_over() {
+        let primary = listener(0);
+        let secondary = listener(0);
+        let failover = Failover::new(resolver(&primary), resolver(&secondary), 1, FAILBACK_AFTER);
+        drop(primary);
+
+        assert!(destination(&failover) == Destination::Secondary);
+        thread::sleep(FAILBACK_AFTER);
+        assert!(destination(&failover) == Destination::Secondary);
+        assert!(destination(&failover) == Destination::Secondary);
+    }
+}
@@ -0,0 +1,301 @@
+//! Forward error correction codes protecting the blocks against packet losses
+//!
+//! Each block is split into source packets, carrying the block data as is, followed by repair
+//! packets computed from them. Packets are identified by the symbol identifier of their header,
+//! source packets having the identifiers below the number of source symbols of the block and
+//! repair packets the following ones, whatever the code. Both ends of the diode must use the
+//! same [Algorithm]:
+//! - [Algorithm::RaptorQ] (RFC 6330) supports blocks of up to 56403 source packets and any
+//!   number of repair packets, but is the most expensive to compute, and may need a couple of
+//!   packets more than the number of source packets to decode a block,
+//! - [Algorithm::ReedSolomon] is cheaper to compute and decodes a block from any set of as many
+//!   packets as there are source packets, but is limited to 256 packets per block (see
+//!   [reed_solomon]),
+//! - [Algorithm::None] sends no repair packet, a block being lost as soon as one of its packets
+//!   is, for links which do not lose packets and hosts which cannot afford encoding.
+
+use crate::{protocol, receive::pool};
+use std::{fmt, str::FromStr};
+
+mod reed_solomon;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    RaptorQ,
+    ReedSolomon,
+    None,
+}
+
+impl Algorithm {
+    pub const fn id(self) -> u8 {
+        match self {
+            Self::RaptorQ => 0,
+            Self::ReedSolomon => 1,
+            Self::None => 2,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::RaptorQ),
+            1 => Some(Self::ReedSolomon),
+            2 => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    /// Largest number of packets, source and repair ones, of a block
+    pub const fn max_packets(self) -> u64 {
+        match self {
+            Self::RaptorQ => u16::MAX as u64,
+            Self::ReedSolomon => reed_solomon::MAX_PACKETS,
+            Self::None => protocol::MAX_ENCODING_PACKETS,
+        }
+    }
+
+    /// Number of packets beyond the source packets which are needed to decode a block with a
+    /// high probability
+    pub(crate) const fn repair_margin(self) -> u32 {
+        match self {
+            // RaptorQ decoding sometimes fails with exactly as many packets as symbols
+            Self::RaptorQ => 2,
+            Self::ReedSolomon | Self::None => 0,
+        }
+    }
+
+    /// Whether the code produces repair packets
+    pub const fn has_repair(self) -> bool {
+        !matches!(self, Self::None)
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::RaptorQ => write!(fmt, "raptorq"),
+            Self::ReedSolomon => write!(fmt, "rs"),
+            Self::None => write!(fmt, "none"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raptorq" => Ok(Self::RaptorQ),
+            "rs" => Ok(Self::ReedSolomon),
+            "none" => Ok(Self::None),
+            _ => Err(format!("unknown forward error correction \"{s}\"")),
+        }
+    }
+}
+
+/// Encoder and decoder of the blocks of a given geometry
+pub(crate) trait Fec {
+    /// Returns the source packets of the block `data`, followed by `nb_repair_packets` repair
+    /// packets
+    fn encode(
+        &self,
+        block_id: protocol::BlockId,
+        data: &[u8],
+        nb_repair_packets: u32,
+    ) -> Vec<raptorq::EncodingPacket>;
+
+    /// Returns the data of the block from the received `packets`, or `None` if they are not
+    /// enough to recover it
+    ///
+    /// With `buffers`, the block is written to a buffer of [pool::Buffers::blocks] and the
+    /// buffers of the packets are given back to [pool::Buffers::packets] when the code
+    /// allows it.
+    fn decode(
+        &self,
+        block_id: protocol::BlockId,
+        packets: Vec<raptorq::EncodingPacket>,
+        buffers: Option<&pool::Buffers>,
+    ) -> Option<Vec<u8>>;
+}
+
+/// Returns the implementation of `algorithm` for blocks of the geometry described by `oti`
+pub(crate) fn new(
+    algorithm: Algorithm,
+    oti: &raptorq::ObjectTransmissionInformation,
+) -> Box<dyn Fec + Send> {
+    let geometry = Geometry::new(oti);
+    match algorithm {
+        Algorithm::RaptorQ => Box::new(RaptorQ {
+            oti: *oti,
+            plan: raptorq::SourceBlockEncodingPlan::generate(geometry.nb_source_symbols as u16),
+        }),
+        Algorithm::ReedSolomon => Box::new(reed_solomon::ReedSolomon::new(geometry)),
+        Algorithm::None => Box::new(NoFec(geometry)),
+    }
+}
+
+/// Number and size of the source symbols of a block
+#[derive(Clone, Copy)]
+struct Geometry {
+    nb_source_symbols: usize,
+    symbol_size: usize,
+}
+
+impl Geometry {
+    fn new(oti: &raptorq::ObjectTransmissionInformation) -> Self {
+        Self {
+            nb_source_symbols: protocol::nb_encoding_packets(oti) as usize,
+            symbol_size: usize::from(oti.symbol_size()),
+        }
+    }
+
+    /// Splits `data` into source packets, padding the last one with zeros
+    fn source_packets(
+        &self,
+        block_id: protocol::BlockId,
+        data: &[u8],
+    ) -> Vec<raptorq::EncodingPacket> {
+        (0..self.nb_source_symbols)
+            .map(|i| {
+                let start = (i * self.symbol_size).min(data.len());
+                let end = ((i + 1) * self.symbol_size).min(data.len());
+                let mut symbol = data[start..end].to_vec();
+                symbol.resize(self.symbol_size, 0);
+                raptorq::EncodingPacket::new(
+                    raptorq::PayloadId::new(block_id.source_block_number(), i as u32),
+                    symbol,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the symbols of the block indexed by symbol identifier, the first one received
+    /// being kept when duplicated, or `None` if a symbol does not have the expected size
+    fn symbols(
+        &self,
+        packets: Vec<raptorq::EncodingPacket>,
+        nb_symbols: usize,
+        buffers: Option<&pool::Buffers>,
+    ) -> Option<Vec<Option<Vec<u8>>>> {
+        let mut symbols = vec![None; nb_symbols];
+        for packet in packets {
+            let (id, data) = packet.split();
+            if data.len() != self.symbol_size {
+                return None;
+            }
+            match symbols.get_mut(id.encoding_symbol_id() as usize) {
+                Some(symbol @ None) => *symbol = Some(data),
+                _ => release(buffers, data),
+            }
+        }
+        Some(symbols)
+    }
+
+    /// Returns the data of the block made of its `nb_source_symbols` first `symbols`, or `None`
+    /// if one of them is missing, all the symbols being released
+    fn assemble(
+        &self,
+        symbols: &mut [Option<Vec<u8>>],
+        buffers: Option<&pool::Buffers>,
+    ) -> Option<Vec<u8>> {
+        let mut data = match buffers {
+            Some(buffers) => buffers.blocks.lease(),
+            None => Vec::with_capacity(self.nb_source_symbols * self.symbol_size),
+        };
+        let mut complete = true;
+        for (i, symbol) in symbols.iter_mut().enumerate() {
+            match symbol.take() {
+                Some(symbol) => {
+                    if i < self.nb_source_symbols {
+                        data.extend_from_slice(&symbol);
+                    }
+                    release(buffers, symbol);
+                }
+                None => complete &= self.nb_source_symbols <= i,
+            }
+        }
+        if complete {
+            Some(data)
+        } else {
+            release_block(buffers, data);
+            None
+        }
+    }
+}
+
+/// Gives the buffer of a symbol back to the packet pool
+fn release(buffers: Option<&pool::Buffers>, symbol: Vec<u8>) {
+    if let Some(buffers) = buffers {
+        buffers.packets.release(symbol);
+    }
+}
+
+/// Gives the buffer of a block back to the block pool
+fn release_block(buffers: Option<&pool::Buffers>, block: Vec<u8>) {
+    if let Some(buffers) = buffers {
+        buffers.blocks.release(block);
+    }
+}
+
+struct RaptorQ {
+    oti: raptorq::ObjectTransmissionInformation,
+    plan: raptorq::SourceBlockEncodingPlan,
+}
+
+impl Fec for RaptorQ {
+    fn encode(
+        &self,
+        block_id: protocol::BlockId,
+        data: &[u8],
+        nb_repair_packets: u32,
+    ) -> Vec<raptorq::EncodingPacket> {
+        let encoder = raptorq::SourceBlockEncoder::with_encoding_plan(
+            block_id.source_block_number(),
+            &self.oti,
+            data,
+            &self.plan,
+        );
+        let mut packets = encoder.source_packets();
+        if 0 < nb_repair_packets {
+            packets.extend(encoder.repair_packets(0, nb_repair_packets));
+        }
+        packets
+    }
+
+    fn decode(
+        &self,
+        block_id: protocol::BlockId,
+        packets: Vec<raptorq::EncodingPacket>,
+        _buffers: Option<&pool::Buffers>,
+    ) -> Option<Vec<u8>> {
+        let mut decoder = raptorq::SourceBlockDecoder::new(
+            block_id.source_block_number(),
+            &self.oti,
+            self.oti.transfer_length(),
+        );
+        decoder.decode(packets)
+    }
+}
+
+/// Blocks sent as their source packets only
+struct NoFec(Geometry);
+
+impl Fec for NoFec {
+    fn encode(
+        &self,
+        block_id: protocol::BlockId,
+        data: &[u8],
+        _nb_repair_packets: u32,
+    ) -> Vec<raptorq::EncodingPacket> {
+        self.0.source_packets(block_id, data)
+    }
+
+    fn decode(
+        &self,
+        _block_id: protocol::BlockId,
+        packets: Vec<raptorq::EncodingPacket>,
+        buffers: Option<&pool::Buffers>,
+    ) -> Option<Vec<u8>> {
+        let mut symbols = self.0.symbols(packets, self.0.nb_source_symbols, buffers)?;
+        self.0.assemble(&mut symbols, buffers)
+    }
+}
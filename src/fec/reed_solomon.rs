@@ -0,0 +1,362 @@
+//! Systematic Reed-Solomon erasure code over GF(2^8)
+//!
+//! Repair symbol `i` of a block of `k` source symbols `S_j` is `sum_j C[i][j] * S_j`, computed
+//! byte by byte, `C` being the Cauchy matrix `C[i][j] = 1 / ((k + i) ^ j)`. Since every square
+//! submatrix of a Cauchy matrix is invertible, missing source symbols are recovered from as many
+//! repair symbols, whichever they are. The elements `k + i` and `j` being distinct bytes, a
+//! block has at most [MAX_PACKETS] source and repair symbols.
+
+use crate::{protocol, receive::pool};
+
+/// Largest number of source and repair packets of a block
+pub(crate) const MAX_PACKETS: u64 = 256;
+
+/// Reduction polynomial x^8 + x^4 + x^3 + x^2 + 1 of the field, 2 generating its
+/// multiplicative group
+const POLYNOMIAL: u16 = 0x11d;
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Tables {
+    const fn new() -> Self {
+        let mut exp = [0; 512];
+        let mut log = [0; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= POLYNOMIAL;
+            }
+            i += 1;
+        }
+        Self { exp, log }
+    }
+}
+
+static TABLES: Tables = Tables::new();
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[usize::from(TABLES.log[usize::from(a)]) + usize::from(TABLES.log[usize::from(b)])]
+}
+
+fn inv(a: u8) -> u8 {
+    debug_assert_ne!(a, 0);
+    TABLES.exp[255 - usize::from(TABLES.log[usize::from(a)])]
+}
+
+/// Adds `coefficient * src` to `dst`
+fn mul_add(dst: &mut [u8], coefficient: u8, src: &[u8]) {
+    match coefficient {
+        0 => (),
+        1 => dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s),
+        _ => {
+            let mut row = [0; 256];
+            for (b, product) in row.iter_mut().enumerate() {
+                *product = mul(coefficient, b as u8);
+            }
+            dst.iter_mut()
+                .zip(src)
+                .for_each(|(d, s)| *d ^= row[usize::from(*s)]);
+        }
+    }
+}
+
+/// Inverts the square `matrix` by Gauss-Jordan elimination
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|i| (0..n).map(|j| u8::from(i == j)).collect())
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|&row| matrix[row][col] != 0)?;
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let factor = inv(matrix[col][col]);
+        for j in 0..n {
+            matrix[col][j] = mul(matrix[col][j], factor);
+            inverse[col][j] = mul(inverse[col][j], factor);
+        }
+        for row in 0..n {
+            let factor = matrix[row][col];
+            if row == col || factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                matrix[row][j] ^= mul(factor, matrix[col][j]);
+                inverse[row][j] ^= mul(factor, inverse[col][j]);
+            }
+        }
+    }
+    Some(inverse)
+}
+
+pub(super) struct ReedSolomon(super::Geometry);
+
+impl ReedSolomon {
+    pub(super) const fn new(geometry: super::Geometry) -> Self {
+        Self(geometry)
+    }
+
+    /// Coefficient of source symbol `source` in repair symbol `repair`
+    fn coefficient(&self, repair: usize, source: usize) -> u8 {
+        inv(((self.0.nb_source_symbols + repair) ^ source) as u8)
+    }
+}
+
+impl super::Fec for ReedSolomon {
+    fn encode(
+        &self,
+        block_id: protocol::BlockId,
+        data: &[u8],
+        nb_repair_packets: u32,
+    ) -> Vec<raptorq::EncodingPacket> {
+        let k = self.0.nb_source_symbols;
+        let nb_repair_packets =
+            (nb_repair_packets as usize).min((MAX_PACKETS as usize).saturating_sub(k));
+        let mut packets = self.0.source_packets(block_id, data);
+        for i in 0..nb_repair_packets {
+            let mut symbol = vec![0; self.0.symbol_size];
+            for (j, source) in packets[..k].iter().enumerate() {
+                mul_add(&mut symbol, self.coefficient(i, j), source.data());
+            }
+            packets.push(raptorq::EncodingPacket::new(
+                raptorq::PayloadId::new(block_id.source_block_number(), (k + i) as u32),
+                symbol,
+            ));
+        }
+        packets
+    }
+
+    fn decode(
+        &self,
+        _block_id: protocol::BlockId,
+        packets: Vec<raptorq::EncodingPacket>,
+        buffers: Option<&pool::Buffers>,
+    ) -> Option<Vec<u8>> {
+        let k = self.0.nb_source_symbols;
+        let mut symbols = self
+            .0
+            .symbols(packets, k.max(MAX_PACKETS as usize), buffers)?;
+        let (sources, repairs) = symbols.split_at_mut(k);
+
+        let missing: Vec<usize> = (0..k).filter(|&j| sources[j].is_none()).collect();
+        if !missing.is_empty() {
+            let repairs: Vec<(usize, Vec<u8>)> = repairs
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, symbol)| Some((i, symbol.take()?)))
+                .take(missing.len())
+                .collect();
+            if repairs.len() < missing.len() {
+                return None;
+            }
+
+            let matrix = repairs
+                .iter()
+                .map(|(i, _)| missing.iter().map(|&j| self.coefficient(*i, j)).collect())
+                .collect();
+            let inverse = invert(matrix)?;
+
+            // removing the contribution of the received source symbols from the repair ones
+            let reduced: Vec<Vec<u8>> = repairs
+                .into_iter()
+                .map(|(i, mut symbol)| {
+                    for (j, source) in sources.iter().enumerate() {
+                        if let Some(source) = source {
+                            mul_add(&mut symbol, self.coefficient(i, j), source);
+                        }
+                    }
+                    symbol
+                })
+                .collect();
+
+            for (row, &j) in inverse.iter().zip(&missing) {
+                let mut symbol = buffers.map_or_else(Vec::new, |buffers| buffers.packets.lease());
+                symbol.resize(self.0.symbol_size, 0);
+                for (&coefficient, reduced) in row.iter().zip(&reduced) {
+                    mul_add(&mut symbol, coefficient, reduced);
+                }
+                sources[j] = Some(symbol);
+            }
+
+            if let Some(buffers) = buffers {
+                reduced
+                    .into_iter()
+                    .for_each(|symbol| buffers.packets.release(symbol));
+            }
+        }
+
+        self.0.assemble(&mut symbols, buffers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReedSolomon, MAX_PACKETS};
+    use crate::{fec::Fec, fec::Geometry, protocol};
+
+    const SYMBOL_SIZE: usize = 16;
+
+    fn code(nb_source_symbols: usize) -> ReedSolomon {
+        ReedSolomon::new(Geometry {
+            nb_source_symbols,
+            symbol_size: SYMBOL_SIZE,
+        })
+    }
+
+    fn block(nb_source_symbols: usize) -> Vec<u8> {
+        (0..nb_source_symbols * SYMBOL_SIZE)
+            .map(|i| (i * 7 + i / 251) as u8)
+            .collect()
+    }
+
+    /// Every subset of `size` elements of `0..n`, in lexicographic order
+    fn combinations(n: usize, size: usize) -> Vec<Vec<usize>> {
+        if size == 0 {
+            return vec![vec![]];
+        }
+        (size - 1..n)
+            .flat_map(|last| {
+                combinations(last, size - 1)
+                    .into_iter()
+                    .map(move |mut combination| {
+                        combination.push(last);
+                        combination
+                    })
+            })
+            .collect()
+    }
+
+    /// Decodes the packets of `data` encoded with `nb_repair` repair packets, those at the
+    /// indices of `erased` being lost
+    fn decode_without(
+        code: &ReedSolomon,
+        data: &[u8],
+        nb_repair: u32,
+        erased: &[usize],
+    ) -> Option<Vec<u8>> {
+        let block_id = protocol::BlockId::from(300);
+        let packets = code
+            .encode(block_id, data, nb_repair)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !erased.contains(i))
+            .map(|(_, packet)| packet)
+            .collect();
+        code.decode(block_id, packets, None)
+    }
+
+    #[test]
+    fn every_erasure_recovered() {
+        for (k, repair) in [(1, 1), (1, 3), (6, 4), (10, 2), (20, 3)] {
+            let code = code(k);
+            let data = block(k);
+            for nb_erased in 0..=repair {
+                for erased in combinations(k + repair, nb_erased) {
+                    assert!(
+                        decode_without(&code, &data, repair as u32, &erased)
+                            .is_some_and(|decoded| decoded == data),
+                        "{k} source and {repair} repair packets, {erased:?} lost"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_block_padded() {
+        let code = code(4);
+        let data = b"short block".to_vec();
+        let mut padded = data.clone();
+        padded.resize(4 * SYMBOL_SIZE, 0);
+        for erased in combinations(6, 2) {
+            assert!(
+                decode_without(&code, &data, 2, &erased).is_some_and(|decoded| decoded == padded),
+                "{erased:?} lost"
+            );
+        }
+    }
+
+    #[test]
+    fn too_many_losses() {
+        for (k, repair) in [(1, 1), (6, 4), (10, 2)] {
+            let code = code(k);
+            let data = block(k);
+            for erased in combinations(k + repair, repair + 1) {
+                assert!(
+                    decode_without(&code, &data, repair as u32, &erased).is_none(),
+                    "{k} source and {repair} repair packets, {erased:?} lost"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn repair_packets_bounded() {
+        let k = MAX_PACKETS as usize - 4;
+        let code = code(k);
+        let data = block(k);
+        let packets = code.encode(protocol::BlockId::default(), &data, 10);
+        assert_eq!(packets.len(), MAX_PACKETS as usize);
+        // the 4 repair packets recover as many losses, even at the end of the field
+        assert!(decode_without(&code, &data, 10, &[0, 100, 200, k + 3])
+            .is_some_and(|decoded| decoded == data));
+    }
+
+    #[test]
+    fn inconsistent_symbol_sizes() {
+        let code = code(6);
+        let data = block(6);
+        let block_id = protocol::BlockId::default();
+        // a short and a long source packet, a short repair packet
+        for (index, len) in [(0, SYMBOL_SIZE - 1), (3, SYMBOL_SIZE + 1), (7, 1)] {
+            let packets = code
+                .encode(block_id, &data, 4)
+                .into_iter()
+                .enumerate()
+                .map(|(i, packet)| {
+                    if i != index {
+                        return packet;
+                    }
+                    let (id, mut symbol) = packet.split();
+                    symbol.resize(len, 0);
+                    raptorq::EncodingPacket::new(id, symbol)
+                })
+                .collect();
+            assert!(
+                code.decode(block_id, packets, None).is_none(),
+                "packet {index} of {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn duplicates_and_unknown_symbols() {
+        let code = code(6);
+        let data = block(6);
+        let block_id = protocol::BlockId::default();
+        let mut packets = code.encode(block_id, &data, 4);
+        // a corrupted copy of a repair packet received after the genuine one is ignored
+        let (id, mut symbol) = packets[6].clone().split();
+        symbol[0] ^= 0xff;
+        packets.push(raptorq::EncodingPacket::new(id, symbol));
+        packets.push(raptorq::EncodingPacket::new(
+            raptorq::PayloadId::new(block_id.source_block_number(), MAX_PACKETS as u32),
+            vec![0; SYMBOL_SIZE],
+        ));
+        packets.drain(..2);
+        assert!(code
+            .decode(block_id, packets, None)
+            .is_some_and(|decoded| decoded == data));
+    }
+}
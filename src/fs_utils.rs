@@ -0,0 +1,324 @@
+//! Bindings and wrappers for filesystem libc functions
+
+use std::{
+    ffi, fs, io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path, ptr,
+};
+
+pub struct FsStats {
+    pub free_bytes: u64,
+    pub free_inodes: u64,
+}
+
+/// Returns the space and inodes available to unprivileged users on the filesystem containing
+/// `path`
+pub fn statvfs(path: &path::Path) -> Result<FsStats, io::Error> {
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stats = unsafe { mem::zeroed::<libc::statvfs>() };
+    let res = unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(FsStats {
+        free_bytes: stats.f_bavail as u64 * stats.f_frsize as u64,
+        free_inodes: stats.f_favail as u64,
+    })
+}
+
+/// Checks that `path` can be handled by the kernel, i.e. that it is shorter than `PATH_MAX` and
+/// that each of its components is shorter than `NAME_MAX`
+pub fn check_path_length(path: &path::Path) -> Result<(), String> {
+    let len = path.as_os_str().len();
+    if len >= libc::PATH_MAX as usize {
+        return Err(format!(
+            "path of {len} bytes exceeds PATH_MAX ({})",
+            libc::PATH_MAX
+        ));
+    }
+
+    for component in path.components() {
+        let len = component.as_os_str().len();
+        if len > NAME_MAX {
+            return Err(format!(
+                "path component of {len} bytes exceeds NAME_MAX ({NAME_MAX})"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+const NAME_MAX: usize = 255;
+
+/// Returns the identifier of the user named `name`, from the user database
+pub fn user_id(name: &str) -> Result<u32, io::Error> {
+    let c_name =
+        ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+    let mut result = ptr::null_mut();
+    with_entry_buffer(|buffer| unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    })?;
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user \"{name}\""),
+        ));
+    }
+    Ok(passwd.pw_uid)
+}
+
+/// Returns the identifier of the group named `name`, from the group database
+pub fn group_id(name: &str) -> Result<u32, io::Error> {
+    let c_name =
+        ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut group = unsafe { mem::zeroed::<libc::group>() };
+    let mut result = ptr::null_mut();
+    with_entry_buffer(|buffer| unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    })?;
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group \"{name}\""),
+        ));
+    }
+    Ok(group.gr_gid)
+}
+
+/// Calls a `get*nam_r` function with a buffer for the strings of the entry, growing the buffer
+/// as long as the function reports it is too small
+fn with_entry_buffer(
+    mut lookup: impl FnMut(&mut [libc::c_char]) -> libc::c_int,
+) -> Result<(), io::Error> {
+    let mut buffer = vec![0; 1024];
+    loop {
+        match lookup(&mut buffer) {
+            0 => return Ok(()),
+            libc::ERANGE if buffer.len() < MAX_ENTRY_BUFFER_SIZE => {
+                buffer.resize(2 * buffer.len(), 0);
+            }
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+const MAX_ENTRY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Event reported by an [Inotify] watch
+pub enum WatchEvent {
+    /// An entry was moved into the watched directory under this name
+    MovedIn(ffi::OsString),
+    /// The event queue overflowed, events were lost
+    Overflow,
+}
+
+/// Watch of the entries moved into a directory, with `inotify(7)`
+pub struct Inotify(OwnedFd);
+
+impl Inotify {
+    pub fn watch_moved_in(dir: &path::Path) -> Result<Self, io::Error> {
+        let c_path = ffi::CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let res = unsafe {
+            libc::inotify_add_watch(
+                fd.as_raw_fd(),
+                c_path.as_ptr(),
+                libc::IN_MOVED_TO | libc::IN_ONLYDIR,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self(fd))
+    }
+
+    /// Waits for events and returns them
+    pub fn read(&self) -> Result<Vec<WatchEvent>, io::Error> {
+        // large enough for at least one event with a name of NAME_MAX bytes
+        let mut buffer = [0u8; 16 * (INOTIFY_EVENT_SIZE + NAME_MAX + 1)];
+        let len = loop {
+            let res = unsafe {
+                libc::read(
+                    self.0.as_raw_fd(),
+                    buffer.as_mut_ptr().cast::<libc::c_void>(),
+                    buffer.len(),
+                )
+            };
+            if 0 <= res {
+                break res as usize;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        };
+
+        let mut events = Vec::new();
+        let mut offset = 0;
+        while offset + INOTIFY_EVENT_SIZE <= len {
+            let event = unsafe {
+                ptr::read_unaligned(buffer[offset..].as_ptr().cast::<libc::inotify_event>())
+            };
+            let name_start = offset + INOTIFY_EVENT_SIZE;
+            let name_end = (name_start + event.len as usize).min(len);
+            offset = name_end;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                events.push(WatchEvent::Overflow);
+            } else if event.mask & libc::IN_MOVED_TO != 0 {
+                // the name is padded with null bytes
+                let name = &buffer[name_start..name_end];
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                events.push(WatchEvent::MovedIn(ffi::OsStr::from_bytes(name).to_owned()));
+            }
+        }
+        Ok(events)
+    }
+}
+
+const INOTIFY_EVENT_SIZE: usize = mem::size_of::<libc::inotify_event>();
+
+/// Name and value of an extended attribute
+pub type Xattr = (Vec<u8>, Vec<u8>);
+
+/// Returns the extended attributes of `file` whose name starts with `prefix`
+pub fn xattrs(file: &fs::File, prefix: &[u8]) -> Result<Vec<Xattr>, io::Error> {
+    let fd = file.as_raw_fd();
+    let names = read_xattr_buffer(|buffer, len| unsafe {
+        libc::flistxattr(fd, buffer.cast::<libc::c_char>(), len)
+    })?;
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|b| *b == 0) {
+        if name.is_empty() || !name.starts_with(prefix) {
+            continue;
+        }
+        let c_name =
+            ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let value = read_xattr_buffer(|buffer, len| unsafe {
+            libc::fgetxattr(fd, c_name.as_ptr(), buffer.cast::<libc::c_void>(), len)
+        })?;
+        xattrs.push((name.to_vec(), value));
+    }
+    Ok(xattrs)
+}
+
+/// Sets the extended attribute `name` of `file` to `value`
+pub fn set_xattr(file: &fs::File, name: &[u8], value: &[u8]) -> Result<(), io::Error> {
+    let c_name =
+        ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let res = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            c_name.as_ptr(),
+            value.as_ptr().cast::<libc::c_void>(),
+            value.len(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Calls an extended attribute function, first to get the size of the result and then to read
+/// it, retrying if it grew meanwhile
+fn read_xattr_buffer(
+    mut read: impl FnMut(*mut u8, usize) -> libc::ssize_t,
+) -> Result<Vec<u8>, io::Error> {
+    loop {
+        let len = read(ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0; len as usize];
+        let res = read(buffer.as_mut_ptr(), buffer.len());
+        if 0 <= res {
+            buffer.truncate(res as usize);
+            return Ok(buffer);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_path_length, group_id, statvfs, user_id, NAME_MAX};
+    use crate::testing::TempDir;
+    use std::{io, path};
+
+    #[test]
+    fn statvfs_stats() {
+        let dir = TempDir::new("statvfs");
+        let stats = statvfs(dir.path()).unwrap_or_else(|e| panic!("{e}"));
+        assert!(0 < stats.free_bytes);
+
+        let missing = dir.path().join("missing");
+        assert!(statvfs(&missing).is_err_and(|e| e.kind() == io::ErrorKind::NotFound));
+        assert!(statvfs(path::Path::new("a\0b"))
+            .is_err_and(|e| e.kind() == io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn path_length() {
+        let path_max = libc::PATH_MAX as usize;
+        let component = "c".repeat(NAME_MAX);
+        assert!(check_path_length(path::Path::new("/tmp/file")).is_ok());
+        assert!(check_path_length(path::Path::new(&component)).is_ok());
+        assert!(check_path_length(path::Path::new(&format!("/dir/{component}c"))).is_err());
+
+        // a path of components of 99 bytes and a separator, shortened to the limit
+        let path = "/".to_string() + &format!("{}/", "d".repeat(99)).repeat(path_max / 100 + 1);
+        assert!(check_path_length(path::Path::new(&path[..path_max - 1])).is_ok());
+        assert!(check_path_length(path::Path::new(&path[..path_max])).is_err());
+        assert!(check_path_length(path::Path::new(&format!("{path}{}", "f".repeat(100)))).is_err());
+    }
+
+    #[test]
+    fn user_and_group_ids() {
+        assert_eq!(user_id("root").ok(), Some(0));
+        assert_eq!(group_id("root").ok(), Some(0));
+
+        let e = user_id("lidi-no-such-user").expect_err("unknown user");
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "unknown user \"lidi-no-such-user\"");
+        let e = group_id("lidi-no-such-group").expect_err("unknown group");
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "unknown group \"lidi-no-such-group\"");
+
+        assert!(user_id("a\0b").is_err_and(|e| e.kind() == io::ErrorKind::InvalidInput));
+        assert!(group_id("a\0b").is_err_and(|e| e.kind() == io::ErrorKind::InvalidInput));
+    }
+}
@@ -0,0 +1,376 @@
+//! Simulated impairments of the UDP link, applied by the relay of [crate::loopback]
+//!
+//! The fate of each packet, lost, delayed, reordered or duplicated, is drawn in turn from a
+//! random generator seeded with [Config::seed], so that a given seed always impairs the same
+//! packets of a transfer, making the recovery of the receiver reproducible. The generator is a
+//! xorshift64* implemented here rather than one of the `rand` crate, whose algorithms may change
+//! between versions, so that a seed impairs packets the same way with every version of lidi.
+//!
+//! Losses follow the Gilbert-Elliott model: the link is either in a good state, losing packets
+//! with the probability [Config::loss], or in a bad state, losing them with the probability
+//! [Burst::loss], and switches between both states with the probabilities of [Burst]. Without
+//! bursts, packets are lost independently of each other.
+
+use rand::Rng;
+use std::time;
+
+/// Burst losses, the bad state of the Gilbert-Elliott model
+#[derive(Clone, Copy, Debug)]
+pub struct Burst {
+    /// Probability that a burst starts at each packet, between 0 and 1
+    pub rate: f64,
+    /// Mean number of packets of a burst, at least 1
+    pub length: f64,
+    /// Probability that a packet of a burst is lost, between 0 and 1
+    pub loss: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Seed of the random generator, drawn at random if not given
+    pub seed: Option<u64>,
+    /// Probability that a packet is lost, outside of bursts, between 0 and 1
+    pub loss: f64,
+    pub burst: Option<Burst>,
+    /// Probability that a packet is delivered twice, between 0 and 1
+    pub duplicate: f64,
+    /// Probability that a packet is held back by `reorder_delay`, so that the following ones
+    /// overtake it, between 0 and 1
+    pub reorder: f64,
+    pub reorder_delay: time::Duration,
+    /// Delay added to every packet
+    pub delay: time::Duration,
+    /// Largest random delay added to each packet on top of `delay`, packets being reordered
+    /// when it exceeds the interval between them
+    pub jitter: time::Duration,
+}
+
+impl Config {
+    /// Whether packets are impaired at all
+    pub fn is_active(&self) -> bool {
+        0.0 < self.loss
+            || self.burst.is_some_and(|burst| 0.0 < burst.rate)
+            || 0.0 < self.duplicate
+            || 0.0 < self.reorder
+            || !self.delay.is_zero()
+            || !self.jitter.is_zero()
+    }
+}
+
+/// Xorshift64* generator, see Vigna, "An experimental exploration of Marsaglia's xorshift
+/// generators, scrambled", 2016
+struct Xorshift64Star(u64);
+
+impl Xorshift64Star {
+    fn new(seed: u64) -> Self {
+        // the state is first mixed by SplitMix64, so that close seeds give unrelated sequences,
+        // and must not be zero
+        let mut state = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        state ^= state >> 31;
+        Self(state.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a number drawn uniformly in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        // the 53 high bits fill the mantissa
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Drawing of the fate of successive packets
+pub struct Impairment {
+    config: Config,
+    seed: u64,
+    rng: Xorshift64Star,
+    /// Whether the link is in the bad state of the Gilbert-Elliott model
+    bursting: bool,
+}
+
+impl Impairment {
+    pub fn new(config: Config) -> Self {
+        let seed = config.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        Self {
+            config,
+            seed,
+            rng: Xorshift64Star::new(seed),
+            bursting: false,
+        }
+    }
+
+    /// Seed of the random generator, to impair another run the same way
+    pub const fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Draws the fate of the next packet, returning the delay of each of its copies to deliver,
+    /// none if it is lost
+    pub fn next_packet(&mut self) -> impl Iterator<Item = time::Duration> {
+        if let Some(burst) = self.config.burst {
+            self.bursting = if self.bursting {
+                !self.chance(1.0 / burst.length.max(1.0))
+            } else {
+                self.chance(burst.rate)
+            };
+        }
+        let loss = match self.config.burst {
+            Some(burst) if self.bursting => burst.loss,
+            _ => self.config.loss,
+        };
+
+        let mut copies = [None, None];
+        if !self.chance(loss) {
+            copies[0] = Some(self.delay());
+            if self.chance(self.config.duplicate) {
+                copies[1] = Some(self.delay());
+            }
+        }
+        copies.into_iter().flatten()
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.rng.next_f64() < probability
+    }
+
+    fn delay(&mut self) -> time::Duration {
+        let mut delay = self.config.delay;
+        if !self.config.jitter.is_zero() {
+            delay += self.config.jitter.mul_f64(self.rng.next_f64());
+        }
+        if self.chance(self.config.reorder) {
+            delay += self.config.reorder_delay;
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Burst, Config, Impairment, Xorshift64Star};
+    use crate::testing;
+    use std::time;
+
+    fn impaired(config: Config) -> Config {
+        Config {
+            seed: Some(2800),
+            ..config
+        }
+    }
+
+    /// Returns the number of copies of each of `nb_packets` packets
+    fn fates(config: Config, nb_packets: usize) -> Vec<usize> {
+        let mut impairment = Impairment::new(config);
+        (0..nb_packets)
+            .map(|_| impairment.next_packet().count())
+            .collect()
+    }
+
+    #[test]
+    fn generator_sequence() {
+        // the sequences of a seed must not change, to reproduce impairments of previous versions
+        let mut rng = Xorshift64Star::new(0);
+        assert_eq!(rng.next_u64(), 0x7bbc_b40d_5506_82d0);
+        assert_eq!(rng.next_u64(), 0xde7f_e413_d00c_c9fd);
+        assert_eq!(rng.next_u64(), 0xb3c6_3835_3c66_8c91);
+        let mut rng = Xorshift64Star::new(1);
+        assert_eq!(rng.next_u64(), 0x4b46_a55d_f361_1b9b);
+
+        let mut rng = Xorshift64Star::new(2800);
+        for _ in 0..10_000 {
+            let x = rng.next_f64();
+            assert!((0.0..1.0).contains(&x));
+        }
+    }
+
+    #[test]
+    fn reproducible() {
+        let config = impaired(Config {
+            loss: 0.1,
+            duplicate: 0.1,
+            ..Config::default()
+        });
+        assert_eq!(fates(config.clone(), 10_000), fates(config.clone(), 10_000));
+        let other = Config {
+            seed: Some(2801),
+            ..config.clone()
+        };
+        assert_ne!(fates(config, 10_000), fates(other, 10_000));
+    }
+
+    #[test]
+    fn rates() {
+        const NB_PACKETS: usize = 100_000;
+
+        let lost = |config| {
+            fates(config, NB_PACKETS)
+                .iter()
+                .filter(|n| **n == 0)
+                .count()
+        };
+        assert_eq!(lost(impaired(Config::default())), 0);
+        let nb_lost = lost(impaired(Config {
+            loss: 0.05,
+            ..Config::default()
+        }));
+        assert!((4_500..5_500).contains(&nb_lost), "{nb_lost} lost");
+        assert_eq!(
+            lost(impaired(Config {
+                loss: 1.0,
+                ..Config::default()
+            })),
+            NB_PACKETS
+        );
+
+        let copies = fates(
+            impaired(Config {
+                duplicate: 0.1,
+                ..Config::default()
+            }),
+            NB_PACKETS,
+        );
+        let nb_duplicated = copies.iter().filter(|n| **n == 2).count();
+        assert!(copies.iter().all(|n| *n != 0));
+        assert!(
+            (9_500..10_500).contains(&nb_duplicated),
+            "{nb_duplicated} duplicated"
+        );
+    }
+
+    #[test]
+    fn bursts() {
+        // bursts start every 100 packets and last 4 packets on average
+        let copies = fates(
+            impaired(Config {
+                burst: Some(Burst {
+                    rate: 0.01,
+                    length: 4.0,
+                    loss: 1.0,
+                }),
+                ..Config::default()
+            }),
+            100_000,
+        );
+        let bursts: Vec<usize> = copies
+            .split(|n| *n != 0)
+            .map(<[usize]>::len)
+            .filter(|len| *len != 0)
+            .collect();
+        let mean = bursts.iter().sum::<usize>() as f64 / bursts.len() as f64;
+        assert!(
+            (850..1_150).contains(&bursts.len()),
+            "{} bursts",
+            bursts.len()
+        );
+        assert!((3.5..4.5).contains(&mean), "bursts of {mean} packets");
+    }
+
+    #[test]
+    fn delays() {
+        let delay = time::Duration::from_millis(10);
+        let jitter = time::Duration::from_millis(5);
+        let reorder_delay = time::Duration::from_millis(100);
+        let mut impairment = Impairment::new(impaired(Config {
+            reorder: 0.1,
+            reorder_delay,
+            delay,
+            jitter,
+            ..Config::default()
+        }));
+        let mut nb_reordered = 0;
+        for _ in 0..10_000 {
+            let mut copies = impairment.next_packet();
+            let copy = copies.next().expect("packet delivered");
+            assert!(copies.next().is_none());
+            let copy = if reorder_delay <= copy {
+                nb_reordered += 1;
+                copy - reorder_delay
+            } else {
+                copy
+            };
+            assert!(delay <= copy && copy < delay + jitter, "{copy:?}");
+        }
+        assert!(
+            (900..1_100).contains(&nb_reordered),
+            "{nb_reordered} reordered"
+        );
+    }
+
+    /// Sends data through an impaired link at `bandwidth_limit` bytes per second, 0 for no limit,
+    /// checking that it is recovered intact
+    fn recovered(impairment: Config, bandwidth_limit: f64) {
+        let data: Vec<u8> = {
+            let mut rng = Xorshift64Star::new(0);
+            (0..256 * 1024).map(|_| rng.next_u64() as u8).collect()
+        };
+        let diode = testing::Diode::start(
+            crate::loopback::Config {
+                // 8 repair packets for 10 source packets
+                repair_block_size: 11_680,
+                impairment: impaired(impairment),
+                bandwidth_limit,
+                ..testing::loopback_config()
+            },
+            |_| (),
+            |_| (),
+        );
+        diode.send(&data);
+        let received = diode
+            .receive(time::Duration::from_secs(20))
+            .expect("session delivered");
+        assert!(received == data, "{} bytes received", received.len());
+    }
+
+    #[test]
+    fn recovery_from_losses() {
+        recovered(
+            Config {
+                loss: 0.05,
+                ..Config::default()
+            },
+            0.0,
+        );
+    }
+
+    #[test]
+    fn recovery_from_bursts() {
+        recovered(
+            Config {
+                loss: 0.01,
+                burst: Some(Burst {
+                    rate: 0.005,
+                    length: 2.0,
+                    loss: 0.8,
+                }),
+                ..Config::default()
+            },
+            0.0,
+        );
+    }
+
+    #[test]
+    fn recovery_from_reordering_and_duplicates() {
+        // the receiver only waits for the packets of the block preceding the current one: at
+        // 2.5 MB/s, the 16 packets of a block last about 10 ms, longer than the largest delay of a
+        // packet
+        recovered(
+            Config {
+                loss: 0.01,
+                duplicate: 0.05,
+                reorder: 0.05,
+                reorder_delay: time::Duration::from_millis(5),
+                delay: time::Duration::from_millis(1),
+                jitter: time::Duration::from_millis(2),
+                ..Config::default()
+            },
+            2_500_000.0,
+        );
+    }
+}
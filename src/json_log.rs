@@ -0,0 +1,109 @@
+//! Logger writing each record as a single line JSON object, for log collectors and SIEMs
+//!
+//! Each object contains the `timestamp` of the record (seconds since UNIX epoch), its `level`,
+//! the `thread` and the `target` module that emitted it, and its `message`. Following the
+//! conventions of the messages of the crate, the `session` identifier is extracted from messages
+//! starting with `client <id>:`, the `block` identifier from messages mentioning `block <id>`, and
+//! the values written by [crate::metrics::log_periodically] are given in a `counters` object.
+
+use crate::events;
+use std::{fmt::Write as _, io::Write, sync, thread, time};
+
+pub(crate) struct JsonLogger {
+    writer: sync::Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLogger {
+    pub(crate) fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: sync::Mutex::new(writer),
+        }
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut json = to_json(record);
+        json.push('\n');
+        // a failing log output cannot be reported anywhere
+        let _ = self
+            .writer
+            .lock()
+            .expect("acquire lock")
+            .write_all(json.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().expect("acquire lock").flush();
+    }
+}
+
+fn to_json(record: &log::Record) -> String {
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let message = record.args().to_string();
+
+    let mut json = format!(
+        "{{\"timestamp\":{timestamp:.6},\"level\":\"{}\"",
+        record.level()
+    );
+
+    // writing into a String cannot fail
+    if let Some(name) = thread::current().name() {
+        let _ = write!(json, ",\"thread\":{}", events::json_string(name));
+    }
+    let _ = write!(
+        json,
+        ",\"target\":{},\"message\":{}",
+        events::json_string(record.target()),
+        events::json_string(&message)
+    );
+    if let Some(session) = session(&message) {
+        let _ = write!(json, ",\"session\":\"{session}\"");
+    }
+    if let Some(block) = block(&message) {
+        let _ = write!(json, ",\"block\":{block}");
+    }
+    if let Some(counters) = counters(&message) {
+        let _ = write!(json, ",\"counters\":{counters}");
+    }
+
+    json.push('}');
+    json
+}
+
+/// Hexadecimal identifier of the session of messages starting with `client <id>:`
+fn session(message: &str) -> Option<&str> {
+    let (id, _) = message.strip_prefix("client ")?.split_once(':')?;
+    (!id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit())).then_some(id)
+}
+
+/// Identifier of the first block mentioned as `block <id>` in the message
+fn block(message: &str) -> Option<&str> {
+    let (_, rest) = message.split_once("block ")?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    (0 < end).then(|| &rest[..end])
+}
+
+/// Counters of the messages written by [crate::metrics::log_periodically], as a JSON object
+fn counters(message: &str) -> Option<String> {
+    let values = message.strip_prefix("metrics: ")?;
+    let counters = values
+        .split(' ')
+        .filter_map(|value| value.rsplit_once('='))
+        .filter(|(_, value)| value.parse::<u64>().is_ok())
+        .map(|(name, value)| format!("{}:{value}", events::json_string(name)))
+        .collect::<Vec<_>>();
+    Some(format!("{{{}}}", counters.join(",")))
+}
@@ -0,0 +1,136 @@
+//! Restriction of the filesystem writes of the calling thread with Landlock
+//!
+//! [restrict_writes] forbids the calling thread, and the threads it spawns afterwards, to create,
+//! modify, rename or remove anything outside of given directories. Reads are not restricted, and
+//! neither are the files and sockets already opened. The restriction cannot be lifted, and threads
+//! already running when it is applied are not restricted.
+//!
+//! The Landlock structures and constants are not provided by the libc crate, they are defined
+//! here after `linux/landlock.h`.
+
+use std::{
+    fs, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path, ptr,
+};
+
+/// Flag of `landlock_create_ruleset` returning the Landlock ABI version of the kernel
+const CREATE_RULESET_VERSION: u32 = 1;
+
+/// Type of the rules allowing accesses beneath a directory
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+/// Renaming and linking files across directories, from ABI version 2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// Truncating files, from ABI version 3
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// Accesses modifying the filesystem known to the first ABI version
+const ACCESS_FS_WRITE: u64 = ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM;
+
+/// `struct landlock_ruleset_attr`, the network accesses of later ABI versions being left out
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Restricts the filesystem writes of the calling thread and of its future threads to `dirs`
+///
+/// Fails if Landlock is not supported or not enabled by the running kernel, returning the ABI
+/// version in use otherwise.
+pub(crate) fn restrict_writes(dirs: &[&path::Path]) -> Result<i64, io::Error> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Landlock is not available: {e}"),
+        ));
+    }
+
+    let mut handled_access_fs = ACCESS_FS_WRITE;
+    if 2 <= abi {
+        handled_access_fs |= ACCESS_FS_REFER;
+    }
+    if 3 <= abi {
+        handled_access_fs |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr { handled_access_fs };
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+    for dir in dirs {
+        let dir = fs::File::open(dir)?;
+        let rule = PathBeneathAttr {
+            allowed_access: handled_access_fs,
+            parent_fd: dir.as_raw_fd(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // required to restrict an unprivileged thread
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(abi)
+}
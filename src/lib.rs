@@ -1,39 +1,223 @@
-use std::str::FromStr;
+//! Lidi library, used by the diode binaries and by programs embedding a diode end
+//!
+//! The public API is made of the modules and items exported below. Other modules are internal
+//! to the crate and may change without notice between versions:
+//! - [send] and [receive] provide the two ends of the diode, configured by a [SendConfig] or a
+//!   [ReceiveConfig] checked as described in [config], binaries reading their parameters
+//!   from the files described in [config_file],
+//! - [aux] provides the file and UDP transfer tools built on top of the diode,
+//! - [protocol] only exposes the site identifier checks, messages being opaque,
+//! - [fec] lists the forward error correction codes the blocks can be protected with,
+//! - [loopback] runs both ends in a single process, for tests and demonstrations, over a link
+//!   impaired as described in [impair],
+//! - [quarantine] reads the files of the blocks the receiver failed to decode,
+//! - [pcap] reads and writes captures of the diode link, replayed by `diode-replay`,
+//! - [metrics], [control], [signal], [proxy_protocol], [resolver], [failover], [scrub],
+//!   [server_sink], [cgroup], [check] and [sd_notify] are the helpers shared by the binaries,
+//! - [durable] writes append-only record files with a configurable durability policy,
+//! - [crypto] reads the pre-shared key encrypting the datagrams of the diode link,
+//! - [privileges] drops the privileges of the binaries once their sockets are bound.
 
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path,
+    str::FromStr,
+};
+
+pub(crate) mod audit;
 pub mod aux;
+pub mod cgroup;
+pub mod check;
+pub mod config;
+pub mod config_file;
+
+// Allow unsafe code to call libc functions getsockopt and geteuid on control clients sockets.
+#[allow(unsafe_code)]
+pub mod control;
+pub mod crypto;
+pub mod durable;
+pub(crate) mod events;
+pub mod failover;
+pub mod fec;
+
+// Allow unsafe code to call libc functions statvfs, inotify and xattr.
+#[allow(unsafe_code)]
+pub(crate) mod fs_utils;
+
+pub mod impair;
+pub(crate) mod json_log;
+
+// Allow unsafe code to call libc function localtime_r.
+#[allow(unsafe_code)]
+pub(crate) mod localtime;
+
+// Allow unsafe code to call the Landlock system calls and libc function prctl.
+#[allow(unsafe_code)]
+pub(crate) mod landlock;
+
+pub mod loopback;
+pub(crate) mod lz4;
+pub mod metrics;
+pub mod pcap;
+
+// Allow unsafe code to call libc functions chroot, setgroups, setgid, setuid and the user and
+// group database lookups.
+#[allow(unsafe_code)]
+pub mod privileges;
+
 pub mod protocol;
+pub mod proxy_protocol;
+pub mod quarantine;
 pub mod receive;
-pub mod semaphore;
+pub mod resolver;
+pub mod scrub;
+
+// Allow unsafe code to call libc functions fcntl and getsockopt on the file descriptors passed
+// by socket activation.
+#[allow(unsafe_code)]
+pub mod sd_notify;
+
+pub(crate) mod semaphore;
 pub mod send;
+pub mod server_sink;
+
+// Allow unsafe code to call libc function sigaction.
+#[allow(unsafe_code)]
+pub mod signal;
 
 // Allow unsafe code to call libc function setsockopt.
 #[allow(unsafe_code)]
-pub mod sock_utils;
+pub(crate) mod sock_utils;
+
+pub(crate) mod status;
+
+#[cfg(test)]
+pub(crate) mod testing;
 
 // Allow unsafe code to initialize C structs and call
 // libc functions recv_mmsg and send_mmsg.
 #[allow(unsafe_code)]
-pub mod udp;
+pub(crate) mod udp;
+
+/// Configuration of the receiving end of the diode
+pub use receive::Config as ReceiveConfig;
+/// Error of the receiving end of the diode
+pub use receive::Error as ReceiveError;
+/// Receiving end of the diode, writing decoded data to clients
+pub use receive::Receiver;
+/// Configuration of the sending end of the diode
+pub use send::Config as SendConfig;
+/// Error of the sending end of the diode
+pub use send::Error as SendError;
+/// Sending end of the diode, reading data from clients
+pub use send::Sender;
 
 pub fn init_logger() {
-    let level_filter = std::env::var("RUST_LOG")
+    init_term_logger(simplelog::TerminalMode::Mixed);
+}
+
+/// Logs every level to the standard error, for binaries whose standard output carries data
+pub fn init_stderr_logger() {
+    init_term_logger(simplelog::TerminalMode::Stderr);
+}
+
+/// Format of the log records, see [init_logger_with]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    /// Human readable lines, as written by [init_logger]
+    Text,
+    /// One JSON object per line, with the fields extracted from the messages
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(fmt, "text"),
+            Self::Json => write!(fmt, "json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format \"{s}\", expected text or json")),
+        }
+    }
+}
+
+/// Logs records in `format` to the end of `file` if given, to the terminal otherwise, every
+/// level going to the standard error with `stderr` as with [init_stderr_logger]
+pub fn init_logger_with(
+    format: LogFormat,
+    file: Option<&path::Path>,
+    stderr: bool,
+) -> Result<(), io::Error> {
+    let file = file
+        .map(|file| fs::OpenOptions::new().create(true).append(true).open(file))
+        .transpose()?;
+
+    match (format, file) {
+        (LogFormat::Text, None) if stderr => init_term_logger(simplelog::TerminalMode::Stderr),
+        (LogFormat::Text, None) => init_term_logger(simplelog::TerminalMode::Mixed),
+        (LogFormat::Text, Some(file)) => {
+            simplelog::WriteLogger::init(simplelog::LevelFilter::Trace, log_config(), file)
+                .expect("failed to initialize writelogger");
+            log::set_max_level(env_log_level());
+        }
+        (LogFormat::Json, file) => {
+            let writer: Box<dyn Write + Send> = match file {
+                Some(file) => Box::new(file),
+                None if stderr => Box::new(io::stderr()),
+                None => Box::new(io::stdout()),
+            };
+            log::set_boxed_logger(Box::new(json_log::JsonLogger::new(writer)))
+                .expect("failed to initialize json logger");
+            log::set_max_level(env_log_level());
+        }
+    }
+    Ok(())
+}
+
+/// Level given by the `RUST_LOG` environment variable, `info` by default
+fn env_log_level() -> simplelog::LevelFilter {
+    std::env::var("RUST_LOG")
         .map_err(|_| ())
         .and_then(|rust_log| simplelog::LevelFilter::from_str(&rust_log).map_err(|_| ()))
-        .unwrap_or(simplelog::LevelFilter::Info);
+        .unwrap_or(simplelog::LevelFilter::Info)
+}
 
-    let config = simplelog::ConfigBuilder::new()
+fn log_config() -> simplelog::Config {
+    simplelog::ConfigBuilder::new()
         .set_level_padding(simplelog::LevelPadding::Right)
         .set_target_level(simplelog::LevelFilter::Off)
         .set_thread_level(simplelog::LevelFilter::Info)
         .set_thread_mode(simplelog::ThreadLogMode::Names)
         .set_time_format_rfc2822()
-        .build();
+        .build()
+}
 
+fn init_term_logger(mode: simplelog::TerminalMode) {
+    // every level reaches the logger, the maximum level filtering messages so that it can be
+    // changed with set_log_level
     simplelog::TermLogger::init(
-        level_filter,
-        config,
-        simplelog::TerminalMode::Mixed,
+        simplelog::LevelFilter::Trace,
+        log_config(),
+        mode,
         simplelog::ColorChoice::Auto,
     )
     .expect("failed to initialize termlogger");
+    log::set_max_level(env_log_level());
+}
+
+/// Changes the level of the messages logged from now on, initially given by `RUST_LOG`
+pub fn set_log_level(level_filter: log::LevelFilter) {
+    log::set_max_level(level_filter);
+    log::info!("log level set to {level_filter}");
 }
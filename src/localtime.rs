@@ -0,0 +1,47 @@
+//! Local time of day and date, in the time zone of the host as given by `localtime_r(3)`
+
+use std::{mem, time};
+
+/// Number of minutes in a day
+pub(crate) const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Returns the number of minutes elapsed since local midnight at `t`, falling back to UTC if the
+/// local time cannot be computed
+pub(crate) fn minute_of_day(t: time::SystemTime) -> u16 {
+    let secs = seconds(t);
+    match local(secs) {
+        Some(tm) => (tm.tm_hour * 60 + tm.tm_min) as u16,
+        None => ((secs / 60) % u64::from(MINUTES_PER_DAY)) as u16,
+    }
+}
+
+/// Returns the local date at `t` as `YYYY-MM-DD`, falling back to UTC if the local time cannot
+/// be computed
+pub(crate) fn date(t: time::SystemTime) -> String {
+    let secs = seconds(t);
+    let tm = local(secs).unwrap_or_else(|| {
+        let timestamp = secs as libc::time_t;
+        let mut tm = unsafe { mem::zeroed::<libc::tm>() };
+        unsafe { libc::gmtime_r(&timestamp, &mut tm) };
+        tm
+    });
+    format!(
+        "{:04}-{:02}-{:02}",
+        i64::from(tm.tm_year) + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday
+    )
+}
+
+fn seconds(t: time::SystemTime) -> u64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn local(secs: u64) -> Option<libc::tm> {
+    let timestamp = secs as libc::time_t;
+    let mut tm = unsafe { mem::zeroed::<libc::tm>() };
+    let res = unsafe { libc::localtime_r(&timestamp, &mut tm) };
+    (!res.is_null()).then_some(tm)
+}
@@ -0,0 +1,507 @@
+//! Sending and receiving ends of the diode running in a single process
+//!
+//! Both ends are built from a single [Config], so that their parameters always match, and are
+//! linked by UDP over the loopback interface on ephemeral ports. TCP clients connecting to
+//! `from_tcp` have their data delivered to the TCP server listening on `to_tcp`.
+//!
+//! The UDP link can be impaired, to test the diode in realistic conditions without hardware:
+//! packets then go through a relay dropping, delaying, reordering or duplicating them as
+//! described in [impair].
+
+use crate::{durable, fec, impair, protocol, receive, scrub, send, sock_utils};
+use std::{cmp, collections, fmt, io, net, sync, thread, time};
+
+pub struct Config {
+    pub from_tcp: net::SocketAddr,
+    pub to_tcp: net::SocketAddr,
+    pub mtu: u16,
+    pub nb_clients: u16,
+    pub encoding_block_size: u64,
+    pub repair_block_size: u32,
+    pub udp_buffer_size: u32,
+    pub nb_encoding_threads: u8,
+    pub nb_decoding_threads: u8,
+    pub flush_timeout: time::Duration,
+    /// Number of repair packets of single packet blocks emitted and delivered without delay,
+    /// overriding the block sizes
+    pub low_latency: Option<u32>,
+    /// Interval between heartbeat messages, the receiver expecting them at twice this interval
+    pub heartbeat_interval: Option<time::Duration>,
+    pub bandwidth_limit: f64,
+    /// Impairments of the UDP packets
+    pub impairment: impair::Config,
+}
+
+pub enum Error {
+    Io(io::Error),
+    Send(send::Error),
+    Receive(receive::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::Send(e) => write!(fmt, "sender error: {e}"),
+            Self::Receive(e) => write!(fmt, "receiver error: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<send::Error> for Error {
+    fn from(e: send::Error) -> Self {
+        Self::Send(e)
+    }
+}
+
+impl From<receive::Error> for Error {
+    fn from(e: receive::Error) -> Self {
+        Self::Receive(e)
+    }
+}
+
+const LOCALHOST: net::IpAddr = net::IpAddr::V4(net::Ipv4Addr::LOCALHOST);
+
+/// Runs both ends of the diode and accepts TCP clients, only returning on error
+pub fn run(config: Config) -> Result<(), Error> {
+    let from_tcp = net::TcpListener::bind(config.from_tcp)?;
+    run_adjusted(config, from_tcp, |_| (), |_| (), |_| ())
+}
+
+/// Block sizes and flush timeout of both ends
+fn block_sizes(config: &Config) -> (u64, u32, time::Duration) {
+    match config.low_latency {
+        Some(nb_repair_packets) => {
+            let (encoding_block_size, repair_block_size) = protocol::single_packet_block_sizes(
+                protocol::HeaderFormat::Narrow,
+                config.mtu,
+                nb_repair_packets,
+            );
+            (encoding_block_size, repair_block_size, time::Duration::ZERO)
+        }
+        None => (
+            config.encoding_block_size,
+            config.repair_block_size,
+            config.flush_timeout,
+        ),
+    }
+}
+
+/// Configuration of the sending end, sending to `to_udp`
+pub(crate) fn send_config(config: &Config, to_udp: net::SocketAddr) -> send::Config {
+    let (encoding_block_size, repair_block_size, flush_timeout) = block_sizes(config);
+    send::Config {
+        nb_clients: config.nb_clients,
+        encoding_block_size,
+        repair_block_size,
+        fec: fec::Algorithm::RaptorQ,
+        repair_profile: None,
+        udp_buffer_size: config.udp_buffer_size,
+        nb_encoding_threads: config.nb_encoding_threads,
+        heartbeat_interval: config.heartbeat_interval,
+        to_bind: net::SocketAddr::new(LOCALHOST, 0),
+        to_bind_device: None,
+        to_udp,
+        to_udp_extra: Vec::new(),
+        to_mtu: config.mtu,
+        to_multicast_ttl: 1,
+        to_multicast_interface: None,
+        stripe_to_udp: None,
+        stripe_to_bind: net::SocketAddr::new(LOCALHOST, 0),
+        stripe_to_bind_device: None,
+        bandwidth_limit: config.bandwidth_limit,
+        txtime: false,
+        udp_gso: false,
+        site_id: None,
+        flush_timeout: Some(flush_timeout),
+        scan_policies: Vec::new(),
+        confirm_flush: false,
+        target_loss: None,
+        allow_insufficient_repair: false,
+        backpressure: false,
+        client_queue_timeout: None,
+        overload_policy: send::OverloadPolicy::Fair,
+        heartbeat_status: false,
+        sd_watchdog: false,
+        config_broadcast: None,
+        channel_weights: collections::BTreeMap::new(),
+        block_trailer: false,
+        compress: false,
+        integrity: false,
+        header_format: protocol::HeaderFormat::Narrow,
+        psk: None,
+        proxy_protocol_from: Vec::new(),
+    }
+}
+
+/// Configuration of the receiving end, receiving at `from_udp` on `from_udp_socket` if given
+pub(crate) fn receive_config(
+    config: &Config,
+    from_udp: net::SocketAddr,
+    from_udp_socket: Option<net::UdpSocket>,
+) -> receive::Config {
+    let (encoding_block_size, repair_block_size, _) = block_sizes(config);
+    receive::Config {
+        from_udp,
+        from_udp_mtu: config.mtu,
+        from_udp_socket,
+        from_multicast_interface: None,
+        stripe_from_udp: None,
+        nb_clients: config.nb_clients,
+        encoding_block_size,
+        repair_block_size,
+        fec: fec::Algorithm::RaptorQ,
+        udp_buffer_size: config.udp_buffer_size,
+        udp_poll_mode: receive::PollMode::Blocking,
+        udp_gro: false,
+        nb_udp_threads: 1,
+        queue_size: receive::DEFAULT_QUEUE_SIZE,
+        flush_timeout: config.flush_timeout,
+        reorder_max_bytes: 0,
+        low_latency: config.low_latency.is_some(),
+        nb_decoding_threads: config.nb_decoding_threads,
+        decode_capacity_warning: receive::DEFAULT_DECODE_CAPACITY_WARNING,
+        loss_report_interval: None,
+        heartbeat_interval: config.heartbeat_interval.map(|hb| 2 * hb),
+        events_socket: None,
+        audit_log: None,
+        audit_sync: durable::Policy::Always,
+        sd_watchdog: false,
+        index_stream: None,
+        cgroup: None,
+        quarantine_dir: None,
+        quarantine_max_bytes: 0,
+        session_header: false,
+        session_trailer: None,
+        store_and_forward: None,
+        store_and_forward_max_bytes: 0,
+        integrity: false,
+        header_format: protocol::HeaderFormat::Narrow,
+        check_sender_config: false,
+        force_best_effort: false,
+        psk: None,
+        allow_from: Vec::new(),
+    }
+}
+
+/// Runs both ends of the diode as [run] does, accepting TCP clients on `from_tcp`, the
+/// configurations of the ends being adjusted by `adjust_send` and `adjust_receive` before they
+/// are created, and the sender being given to `started` once both ends run
+pub(crate) fn run_adjusted<S, R, T>(
+    config: Config,
+    from_tcp: net::TcpListener,
+    adjust_send: S,
+    adjust_receive: R,
+    started: T,
+) -> Result<(), Error>
+where
+    S: FnOnce(&mut send::Config),
+    R: FnOnce(&mut receive::Config),
+    T: FnOnce(sync::Arc<send::Sender<net::TcpStream>>),
+{
+    let receiver_socket = net::UdpSocket::bind((LOCALHOST, 0))?;
+    let receiver_addr = receiver_socket.local_addr()?;
+
+    let relay_socket = if config.impairment.is_active() {
+        let socket = net::UdpSocket::bind((LOCALHOST, 0))?;
+        sock_utils::set_socket_recv_buffer_size(&socket, config.udp_buffer_size as i32)?;
+        Some(socket)
+    } else {
+        None
+    };
+    let to_udp = match &relay_socket {
+        Some(socket) => socket.local_addr()?,
+        None => receiver_addr,
+    };
+
+    let mut send_config = send_config(&config, to_udp);
+    adjust_send(&mut send_config);
+    let sender = sync::Arc::new(send::Sender::new(send_config)?);
+
+    let to_tcp = config.to_tcp;
+    let mut receive_config = receive_config(&config, receiver_addr, Some(receiver_socket));
+    adjust_receive(&mut receive_config);
+    let receiver = receive::Receiver::new(receive_config, |_| net::TcpStream::connect(to_tcp))?;
+
+    thread::scope(|scope| {
+        receiver.start(scope)?;
+        sender.start(scope)?;
+        started(sender.clone());
+
+        if let Some(relay_socket) = relay_socket {
+            let impairment = impair::Impairment::new(config.impairment.clone());
+            log::info!(
+                "impairing UDP link with {:.2}% loss and {} ms delay, seed {}",
+                100.0 * config.impairment.loss,
+                config.impairment.delay.as_millis(),
+                impairment.seed()
+            );
+            if let Some(burst) = config.impairment.burst {
+                log::info!(
+                    "bursts of {:.1} packets on average start with {:.2}% probability and lose {:.2}% of their packets",
+                    burst.length,
+                    100.0 * burst.rate,
+                    100.0 * burst.loss
+                );
+            }
+            let (to_delay, for_delay) = crossbeam_channel::unbounded();
+            thread::Builder::new()
+                .name("relay".into())
+                .spawn_scoped(scope, move || {
+                    if let Err(e) = relay(&relay_socket, impairment, &to_delay) {
+                        log::error!("relay error: {e}");
+                    }
+                })?;
+            thread::Builder::new()
+                .name("relay_delay".into())
+                .spawn_scoped(scope, move || {
+                    if let Err(e) = delay(for_delay, receiver_addr) {
+                        log::error!("relay delay error: {e}");
+                    }
+                })?;
+        }
+
+        log::info!(
+            "accepting TCP clients at {} and sending traffic to TCP {}",
+            scrub::addr(&config.from_tcp),
+            scrub::addr(&config.to_tcp)
+        );
+
+        for client in from_tcp.incoming() {
+            match client {
+                Err(e) => log::error!("failed to accept TCP client: {e}"),
+                Ok(client) => {
+                    if let Err(e) = sender.new_client(client) {
+                        log::error!("failed to send TCP client to connect queue: {e}");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Forwards every copy of the packets sent to `socket` kept by `impairment` to the delaying
+/// thread
+fn relay(
+    socket: &net::UdpSocket,
+    mut impairment: impair::Impairment,
+    to_delay: &crossbeam_channel::Sender<(time::Instant, Vec<u8>)>,
+) -> Result<(), io::Error> {
+    let mut buffer = vec![0; usize::from(u16::MAX)];
+
+    loop {
+        let len = socket.recv(&mut buffer)?;
+        let now = time::Instant::now();
+        for delay in impairment.next_packet() {
+            if to_delay
+                .send((now + delay, buffer[..len].to_vec()))
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Sends every packet to `to_udp` once its deadline is reached, packets of the same deadline
+/// keeping their order
+fn delay(
+    for_delay: crossbeam_channel::Receiver<(time::Instant, Vec<u8>)>,
+    to_udp: net::SocketAddr,
+) -> Result<(), io::Error> {
+    let socket = net::UdpSocket::bind((LOCALHOST, 0))?;
+    // packets waiting for their deadline, numbered in their order of arrival
+    let mut pending: collections::BinaryHeap<cmp::Reverse<(time::Instant, u64, Vec<u8>)>> =
+        collections::BinaryHeap::new();
+    let mut nb_received = 0u64;
+
+    loop {
+        let now = time::Instant::now();
+        while let Some(cmp::Reverse((deadline, _, _))) = pending.peek() {
+            if now < *deadline {
+                break;
+            }
+            let Some(cmp::Reverse((_, _, packet))) = pending.pop() else {
+                break;
+            };
+            socket.send_to(&packet, to_udp)?;
+        }
+
+        let received = match pending.peek() {
+            Some(cmp::Reverse((deadline, _, _))) => {
+                match for_delay.recv_timeout(deadline.saturating_duration_since(now)) {
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
+                    Err(crossbeam_channel::RecvTimeoutError::Disconnected) => None,
+                    Ok(received) => Some(received),
+                }
+            }
+            None => for_delay.recv().ok(),
+        };
+        let Some((deadline, packet)) = received else {
+            return Ok(());
+        };
+        pending.push(cmp::Reverse((deadline, nb_received, packet)));
+        nb_received += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{receive_config, run, send_config, Config, LOCALHOST};
+    use crate::{impair, testing};
+    use std::{
+        io::{Read, Write},
+        net, thread, time,
+    };
+
+    fn assert_consistent(config: &Config) {
+        let addr = net::SocketAddr::new(LOCALHOST, 5000);
+        let send = send_config(config, addr);
+        let receive = receive_config(config, addr, None);
+        assert_eq!(send.to_udp, receive.from_udp);
+        assert_eq!(send.to_mtu, receive.from_udp_mtu);
+        assert_eq!(send.nb_clients, receive.nb_clients);
+        assert_eq!(send.encoding_block_size, receive.encoding_block_size);
+        assert_eq!(send.repair_block_size, receive.repair_block_size);
+        assert!(send.fec == receive.fec);
+        assert!(send.header_format == receive.header_format);
+        assert_eq!(
+            send.heartbeat_interval.map(|hb| 2 * hb),
+            receive.heartbeat_interval
+        );
+    }
+
+    #[test]
+    fn consistent_ends() {
+        let config = testing::loopback_config();
+        assert_consistent(&config);
+
+        let config = Config {
+            low_latency: Some(2),
+            ..config
+        };
+        assert_consistent(&config);
+        assert_eq!(
+            send_config(&config, net::SocketAddr::new(LOCALHOST, 5000)).flush_timeout,
+            Some(time::Duration::ZERO)
+        );
+        assert!(receive_config(&config, net::SocketAddr::new(LOCALHOST, 5000), None).low_latency);
+    }
+
+    #[test]
+    fn integrity_under_loss() {
+        let from_tcp = net::TcpListener::bind((LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("free port");
+        let to_tcp = net::TcpListener::bind((LOCALHOST, 0)).expect("bind");
+        let config = Config {
+            from_tcp,
+            to_tcp: to_tcp.local_addr().expect("address"),
+            // 8 repair packets for 40 source packets, losing more than 8 packets of a block being
+            // unlikely at 2% loss
+            encoding_block_size: 58_400,
+            repair_block_size: 11_680,
+            impairment: impair::Config {
+                loss: 0.02,
+                delay: time::Duration::from_millis(20),
+                ..testing::loopback_config().impairment
+            },
+            ..testing::loopback_config()
+        };
+        thread::spawn(move || {
+            if let Err(e) = run(config) {
+                panic!("loopback failed: {e}");
+            }
+        });
+
+        for seed in 1..=3u8 {
+            let data: Vec<u8> = (0..512 * 1024u32)
+                .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8 ^ seed)
+                .collect();
+            // the sender may not listen yet
+            let mut client = loop {
+                match net::TcpStream::connect(from_tcp) {
+                    Ok(client) => break client,
+                    Err(_) => thread::sleep(time::Duration::from_millis(10)),
+                }
+            };
+            client.write_all(&data).expect("send data");
+            drop(client);
+
+            let (mut session, _) = to_tcp.accept().expect("session delivered");
+            session
+                .set_read_timeout(Some(time::Duration::from_secs(20)))
+                .expect("read timeout");
+            let mut received = Vec::new();
+            session.read_to_end(&mut received).expect("receive data");
+            assert!(
+                received == data,
+                "session {seed}: {} bytes received",
+                received.len()
+            );
+        }
+    }
+
+    #[test]
+    fn low_latency_messages() {
+        let diode = testing::Diode::start(
+            Config {
+                low_latency: Some(1),
+                ..testing::loopback_config()
+            },
+            |_| (),
+            |_| (),
+        );
+        let mut client = diode.connect();
+        client.set_nodelay(true).expect("no delay");
+
+        let mut latencies = Vec::new();
+        let mut session = None;
+        let mut message = [0; 300];
+        for i in 0..100u8 {
+            message.fill(i);
+            let sent = time::Instant::now();
+            client.write_all(&message).expect("send message");
+            let session = session.get_or_insert_with(|| {
+                diode
+                    .accept(time::Instant::now() + time::Duration::from_secs(10))
+                    .expect("session delivered")
+            });
+            let mut received = [0; 300];
+            session.read_exact(&mut received).expect("receive message");
+            latencies.push(sent.elapsed());
+            assert!(received == message, "message {i}");
+            // sparse messages, some pausing longer than the flush timeout and heartbeat interval
+            thread::sleep(time::Duration::from_millis(if i % 25 == 24 {
+                1200
+            } else {
+                5
+            }));
+        }
+
+        // the first message also opens the session
+        latencies.remove(0);
+        latencies.sort();
+        let median = latencies[latencies.len() / 2];
+        eprintln!(
+            "one-way latency: median {median:?}, max {:?}",
+            latencies[latencies.len() - 1]
+        );
+        // the unoptimized codec of debug builds takes milliseconds, still well below the 50 ms
+        // flush timeout that blocks would otherwise wait for
+        let bound = if cfg!(debug_assertions) {
+            time::Duration::from_millis(20)
+        } else {
+            time::Duration::from_micros(500)
+        };
+        assert!(median < bound, "median latency {median:?}");
+    }
+}
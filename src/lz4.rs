@@ -0,0 +1,333 @@
+//! Compression of blocks in the LZ4 block format
+//!
+//! Only the block format is implemented, without the frame format: the length of the compressed
+//! data is known from the message carrying it, and the length of the decompressed data is bounded
+//! by the caller of [decompress].
+//!
+//! A compressed block is a sequence of sequences, each one made of a token, literals copied as
+//! is, and a match copied from the already decompressed data at a 2-bytes little-endian offset.
+//! The high and low 4 bits of the token are the lengths of the literals and of the match minus 4,
+//! the value 15 meaning that the length continues in the following bytes, each byte being added
+//! until one is not 255. The last sequence only has literals, and is at least 5 bytes long unless
+//! the block is shorter than 13 bytes.
+
+use std::fmt;
+
+pub enum Error {
+    Truncated,
+    InvalidOffset(usize, usize),
+    TooLarge(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Truncated => write!(fmt, "truncated compressed data"),
+            Self::InvalidOffset(offset, len) => write!(
+                fmt,
+                "match offset {offset} is outside of the {len} byte(s) already decompressed"
+            ),
+            Self::TooLarge(max) => write!(fmt, "decompressed data exceeds {max} bytes"),
+        }
+    }
+}
+
+const MIN_MATCH: usize = 4;
+const LAST_LITERALS: usize = 5;
+/// Distance from the end of the input within which no match may start
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_LOG: u32 = 12;
+/// Number of bytes without a match after which positions are skipped faster
+const SKIP_TRIGGER: u32 = 6;
+
+/// Compresses `input`, the result being at most `input.len() / 255 + 16` bytes larger than it
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 255 + 16);
+    let mut table = vec![0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if MF_LIMIT < input.len() {
+        let match_start_limit = input.len() - MF_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+
+        while pos < match_start_limit {
+            let sequence = read_u32(input, pos);
+            let hash = hash(sequence);
+            let candidate = table[hash] as usize;
+            table[hash] = pos as u32;
+
+            if candidate < pos
+                && pos - candidate <= MAX_OFFSET
+                && read_u32(input, candidate) == sequence
+            {
+                let mut len = MIN_MATCH;
+                while pos + len < match_end_limit && input[candidate + len] == input[pos + len] {
+                    len += 1;
+                }
+                write_sequence(
+                    &mut out,
+                    &input[anchor..pos],
+                    Some(((pos - candidate) as u16, len)),
+                );
+                pos += len;
+                anchor = pos;
+            } else {
+                // incompressible data is skipped faster and faster
+                pos += 1 + ((pos - anchor) >> SKIP_TRIGGER);
+            }
+        }
+    }
+
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Decompresses `input`, failing if it is invalid or if the result exceeds `max_len` bytes
+pub(crate) fn decompress(input: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let token = *input.get(pos).ok_or(Error::Truncated)?;
+        pos += 1;
+
+        let literals_len = read_length(input, &mut pos, token >> 4)?;
+        let literals = pos
+            .checked_add(literals_len)
+            .and_then(|end| input.get(pos..end))
+            .ok_or(Error::Truncated)?;
+        if max_len < out.len() + literals_len {
+            return Err(Error::TooLarge(max_len));
+        }
+        out.extend_from_slice(literals);
+        pos += literals_len;
+
+        if pos == input.len() {
+            return Ok(out);
+        }
+
+        let offset = input
+            .get(pos..pos + 2)
+            .map(|bytes| usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+            .ok_or(Error::Truncated)?;
+        pos += 2;
+        if offset == 0 || out.len() < offset {
+            return Err(Error::InvalidOffset(offset, out.len()));
+        }
+
+        let match_len = read_length(input, &mut pos, token & 0x0f)?.saturating_add(MIN_MATCH);
+        if max_len - out.len() < match_len {
+            return Err(Error::TooLarge(max_len));
+        }
+        let start = out.len() - offset;
+        if match_len <= offset {
+            out.extend_from_within(start..start + match_len);
+        } else {
+            // the match overlaps the bytes it produces
+            for i in start..start + match_len {
+                out.push(out[i]);
+            }
+        }
+    }
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+const fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Appends a sequence of `literals` followed by a match of the given offset and length
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(u16, usize)>) {
+    let match_code = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_code.min(15) as u8);
+    if 15 <= literals.len() {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&offset.to_le_bytes());
+        if 15 <= match_code {
+            write_length(out, match_code - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while 255 <= len {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Reads the length whose first 4 bits are `nibble`, continued at `pos` if they are all set
+fn read_length(input: &[u8], pos: &mut usize, nibble: u8) -> Result<usize, Error> {
+    let mut len = usize::from(nibble);
+    if nibble == 15 {
+        loop {
+            let byte = *input.get(*pos).ok_or(Error::Truncated)?;
+            *pos += 1;
+            len = len.saturating_add(usize::from(byte));
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, Error};
+    use proptest::{collection::vec, prelude::*};
+
+    fn round_trip(data: &[u8]) {
+        let compressed = compress(data);
+        assert!(compressed.len() <= data.len() + data.len() / 255 + 16);
+        let decompressed = decompress(&compressed, data.len())
+            .unwrap_or_else(|e| panic!("{} bytes: {e}", data.len()));
+        assert!(decompressed == data, "{} bytes", data.len());
+        assert!(
+            matches!(
+                decompress(&compressed, data.len().wrapping_sub(1)),
+                Err(Error::TooLarge(_))
+            ) || data.is_empty()
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"");
+        round_trip(b"a");
+        // shorter than a match may start in
+        round_trip(b"abcdabcdabcd");
+        round_trip(&[0; 13]);
+        // overlapping matches, long literals and matches whose lengths take several bytes
+        round_trip(&[7; 100_000]);
+        let text = b"lidi sends data through a unidirectional link. ".repeat(1000);
+        round_trip(&text);
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..70_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        round_trip(&noise);
+        // matches at the largest offset
+        let mut far = noise[..65_535].to_vec();
+        far.extend_from_slice(&noise[..1000]);
+        round_trip(&far);
+        assert!(compress(&text).len() < text.len() / 10);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(decompress(&[], 100), Err(Error::Truncated)));
+        // 5 literals announced, 2 given
+        assert!(matches!(
+            decompress(&[0x50, b'a', b'b'], 100),
+            Err(Error::Truncated)
+        ));
+        // literals length continued in a missing byte
+        assert!(matches!(decompress(&[0xf0], 100), Err(Error::Truncated)));
+        assert!(matches!(
+            decompress(&[0xf0, 255, 255], 1000),
+            Err(Error::Truncated)
+        ));
+        // offset cut in the middle
+        assert!(matches!(
+            decompress(&[0x10, b'a', 1], 100),
+            Err(Error::Truncated)
+        ));
+        // match length continued in a missing byte
+        assert!(matches!(
+            decompress(&[0x1f, b'a', 1, 0], 100),
+            Err(Error::Truncated)
+        ));
+        assert!(matches!(
+            decompress(&[0x10, b'a', 0, 0, 0x00], 100),
+            Err(Error::InvalidOffset(0, 1))
+        ));
+        assert!(matches!(
+            decompress(&[0x10, b'a', 2, 0, 0x00], 100),
+            Err(Error::InvalidOffset(2, 1))
+        ));
+        assert!(matches!(
+            decompress(&[0x00, 1, 0, 0x00], 100),
+            Err(Error::InvalidOffset(1, 0))
+        ));
+
+        // a match of 4 + 15 + 255 * 1000 + 0 bytes, far beyond the limit
+        let mut overlong = vec![0x1f, b'a', 1, 0];
+        overlong.extend_from_slice(&[255; 1000]);
+        overlong.extend_from_slice(&[0, 0x00]);
+        assert!(matches!(
+            decompress(&overlong, 100_000),
+            Err(Error::TooLarge(100_000))
+        ));
+        // a length overflowing the counters
+        let mut overflowing = vec![0xff];
+        overflowing.extend(std::iter::repeat_n(255, 100_000));
+        assert!(matches!(
+            decompress(&overflowing, 1 << 20),
+            Err(Error::Truncated)
+        ));
+
+        // literals beyond the limit
+        assert!(matches!(
+            decompress(&[0x30, b'a', b'b', b'c'], 2),
+            Err(Error::TooLarge(2))
+        ));
+        assert!(decompress(&[0x30, b'a', b'b', b'c'], 3).is_ok_and(|data| data == b"abc"));
+        // a match reaching the limit exactly, then exceeding it
+        let repeated = [0x10, b'a', 1, 0, 0x00];
+        assert!(decompress(&repeated, 5).is_ok_and(|data| data == b"aaaaa"));
+        assert!(matches!(decompress(&repeated, 4), Err(Error::TooLarge(4))));
+    }
+
+    proptest! {
+        #[test]
+        fn decompress_never_panics(input in vec(any::<u8>(), 0..4096), max_len in 0..65_536usize) {
+            if let Ok(data) = decompress(&input, max_len) {
+                prop_assert!(data.len() <= max_len);
+            }
+        }
+
+        #[test]
+        fn decompress_never_panics_on_sequences(
+            sequences in vec((any::<u8>(), vec(any::<u8>(), 0..20), any::<u16>()), 0..64),
+            max_len in 0..65_536usize,
+        ) {
+            // well-formed sequences with arbitrary tokens, lengths and offsets
+            let mut input = Vec::new();
+            for (token, literals, offset) in sequences {
+                input.push(token);
+                input.extend_from_slice(&literals);
+                input.extend_from_slice(&offset.to_le_bytes());
+            }
+            if let Ok(data) = decompress(&input, max_len) {
+                prop_assert!(data.len() <= max_len);
+            }
+        }
+
+        #[test]
+        fn arbitrary_round_trip(data in vec(any::<u8>(), 0..8192)) {
+            let decompressed = decompress(&compress(&data), data.len());
+            prop_assert!(decompressed.is_ok_and(|decompressed| decompressed == data));
+        }
+
+        #[test]
+        fn compressible_round_trip(data in vec(0..4u8, 0..32_768)) {
+            let decompressed = decompress(&compress(&data), data.len());
+            prop_assert!(decompressed.is_ok_and(|decompressed| decompressed == data));
+        }
+    }
+}
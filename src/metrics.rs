@@ -0,0 +1,336 @@
+//! Minimal in-process metrics registry, no external dependency
+//!
+//! Workers register named counters and gauges once and keep the returned handle to update it
+//! with a single atomic operation. The registry can then be enumerated, for example to answer a
+//! status request on the control socket, exposed to Prometheus with [serve] or written to the
+//! logs with [log_periodically]. The metrics of [Scoped] registrations can also be enumerated by
+//! scope with [scopes], to report each session in progress.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread, time,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+pub struct Metric {
+    kind: Kind,
+    value: AtomicU64,
+}
+
+impl Metric {
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: u64) {
+        self.value.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub const fn kind(&self) -> Kind {
+        self.kind
+    }
+}
+
+static REGISTRY: Mutex<BTreeMap<String, Arc<Metric>>> = Mutex::new(BTreeMap::new());
+
+fn register(name: &str, kind: Kind) -> Arc<Metric> {
+    let mut registry = REGISTRY.lock().expect("acquire lock");
+    registry
+        .entry(name.to_string())
+        .or_insert_with(|| {
+            Arc::new(Metric {
+                kind,
+                value: AtomicU64::new(0),
+            })
+        })
+        .clone()
+}
+
+/// Returns the counter registered under `name`, creating it if needed
+pub fn counter(name: &str) -> Arc<Metric> {
+    register(name, Kind::Counter)
+}
+
+/// Returns the gauge registered under `name`, creating it if needed
+pub fn gauge(name: &str) -> Arc<Metric> {
+    register(name, Kind::Gauge)
+}
+
+/// Builds the name of a metric with labels, following the Prometheus notation
+/// `name{label="value",...}`
+pub fn labeled(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels = labels
+        .iter()
+        .map(|(label, value)| {
+            format!(
+                "{label}=\"{}\"",
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{name}{{{labels}}}")
+}
+
+/// Labels of a scope, as pairs of label and value
+pub type Labels = Vec<(String, String)>;
+
+/// Labels and unlabeled names of the metrics of a scope, see [scopes]
+type ScopeMetrics = (Labels, Vec<(String, Arc<Metric>)>);
+
+/// Labels of a scope with the current value of its metrics by unlabeled name, see [scopes]
+pub type ScopeValues = (Labels, Vec<(String, u64)>);
+
+/// Scopes alive, by creation order
+static SCOPES: Mutex<BTreeMap<usize, ScopeMetrics>> = Mutex::new(BTreeMap::new());
+static NEXT_SCOPE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Labeled metrics registered for a limited time, such as the duration of a session, and removed
+/// from the registry when dropped so that the number of metrics does not grow without bound
+pub struct Scoped {
+    id: usize,
+    labels: Labels,
+    names: Vec<String>,
+}
+
+impl Scoped {
+    pub fn new(labels: &[(&str, &str)]) -> Self {
+        let id = NEXT_SCOPE_ID.fetch_add(1, Ordering::Relaxed);
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| ((*label).to_string(), (*value).to_string()))
+            .collect();
+        SCOPES
+            .lock()
+            .expect("acquire lock")
+            .insert(id, (labels.clone(), Vec::new()));
+        Self {
+            id,
+            labels,
+            names: Vec::new(),
+        }
+    }
+
+    fn register(&mut self, name: &str, kind: Kind) -> Arc<Metric> {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(label, value)| (label.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        let labeled_name = labeled(name, &labels);
+        let metric = register(&labeled_name, kind);
+        self.names.push(labeled_name);
+        if let Some((_, metrics)) = SCOPES.lock().expect("acquire lock").get_mut(&self.id) {
+            metrics.push((name.to_string(), metric.clone()));
+        }
+        metric
+    }
+
+    /// Returns the counter registered under `name` with the labels of the scope
+    pub fn counter(&mut self, name: &str) -> Arc<Metric> {
+        self.register(name, Kind::Counter)
+    }
+
+    /// Returns the gauge registered under `name` with the labels of the scope
+    pub fn gauge(&mut self, name: &str) -> Arc<Metric> {
+        self.register(name, Kind::Gauge)
+    }
+}
+
+impl Drop for Scoped {
+    fn drop(&mut self) {
+        SCOPES.lock().expect("acquire lock").remove(&self.id);
+        let mut registry = REGISTRY.lock().expect("acquire lock");
+        for name in &self.names {
+            registry.remove(name);
+        }
+    }
+}
+
+/// Returns the labels of every scope alive, in creation order, with the current value of its
+/// metrics by unlabeled name
+pub fn scopes() -> Vec<ScopeValues> {
+    let scopes = SCOPES.lock().expect("acquire lock");
+    scopes
+        .values()
+        .map(|(labels, metrics)| {
+            (
+                labels.clone(),
+                metrics
+                    .iter()
+                    .map(|(name, metric)| (name.clone(), metric.get()))
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Distribution of observed values, following the Prometheus notation: a `name_bucket` counter
+/// labeled with `le` for each upper bound, and `name_sum` and `name_count` counters
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<Arc<Metric>>,
+    sum: Arc<Metric>,
+    count: Arc<Metric>,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: u64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if value <= *bound {
+                bucket.inc();
+            }
+        }
+        // the last bucket is +Inf
+        if let Some(bucket) = self.buckets.last() {
+            bucket.inc();
+        }
+        self.sum.add(value);
+        self.count.inc();
+    }
+}
+
+/// Returns the histogram registered under `name` with the given increasing bucket upper `bounds`,
+/// creating it if needed
+pub fn histogram(name: &str, bounds: &'static [u64]) -> Histogram {
+    let bucket_name = format!("{name}_bucket");
+    let buckets = bounds
+        .iter()
+        .map(ToString::to_string)
+        .chain([String::from("+Inf")])
+        .map(|le| counter(&labeled(&bucket_name, &[("le", &le)])))
+        .collect();
+    Histogram {
+        bounds,
+        buckets,
+        sum: counter(&format!("{name}_sum")),
+        count: counter(&format!("{name}_count")),
+    }
+}
+
+/// Returns the current value of every registered metric, sorted by name
+pub fn snapshot() -> Vec<(String, Kind, u64)> {
+    let registry = REGISTRY.lock().expect("acquire lock");
+    registry
+        .iter()
+        .map(|(name, metric)| (name.clone(), metric.kind(), metric.get()))
+        .collect()
+}
+
+/// Formats every registered metric in the Prometheus text exposition format, metrics sharing a
+/// name but not their labels being grouped under a single `TYPE` line
+pub fn render() -> String {
+    let mut families: BTreeMap<String, (Kind, Vec<(String, u64)>)> = BTreeMap::new();
+    for (name, kind, value) in snapshot() {
+        let family = name
+            .split_once('{')
+            .map_or(name.as_str(), |(family, _)| family);
+        families
+            .entry(family.to_string())
+            .or_insert_with(|| (kind, Vec::new()))
+            .1
+            .push((name, value));
+    }
+
+    let mut out = String::new();
+    for (family, (kind, metrics)) in families {
+        let kind = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(out, "# TYPE {family} {kind}");
+        for (name, value) in metrics {
+            let _ = writeln!(out, "{name} {value}");
+        }
+    }
+    out
+}
+
+/// Maximum size of the request of a scrape, larger requests being rejected
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Time a scraper is given to send its request and read the response
+const SCRAPE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Answers Prometheus scrapes accepted on `listener`, one at a time, with [render]
+pub fn serve(listener: net::TcpListener) -> Result<(), io::Error> {
+    for client in listener.incoming() {
+        let client = client?;
+        if let Err(e) = serve_scrape(client) {
+            log::warn!("metrics scrape error: {e}");
+        }
+    }
+    Ok(())
+}
+
+fn serve_scrape(mut client: net::TcpStream) -> Result<(), io::Error> {
+    client.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    client.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if MAX_REQUEST_SIZE < request.len() {
+            return respond(&mut client, "431 Request Header Fields Too Large", "");
+        }
+        let nread = client.read(&mut buffer)?;
+        if nread == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete request",
+            ));
+        }
+        request.extend_from_slice(&buffer[..nread]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => respond(&mut client, "200 OK", &render()),
+        (Some("GET"), _) => respond(&mut client, "404 Not Found", ""),
+        _ => respond(&mut client, "405 Method Not Allowed", ""),
+    }
+}
+
+fn respond(client: &mut net::TcpStream, status: &str, body: &str) -> Result<(), io::Error> {
+    write!(
+        client,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    client.flush()
+}
+
+/// Writes the value of every registered metric to the logs every `interval`, never returning
+pub fn log_periodically(interval: time::Duration) {
+    loop {
+        thread::sleep(interval);
+        let metrics = snapshot()
+            .into_iter()
+            .map(|(name, _, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>();
+        log::info!("metrics: {}", metrics.join(" "));
+    }
+}
@@ -0,0 +1,544 @@
+//! Captures of the datagrams of the diode link in the pcap format, so that traffic recorded in
+//! the field can be replayed against a receiver
+//!
+//! Captures are written as classic pcap files with nanosecond timestamps and raw IP frames
+//! (`LINKTYPE_RAW`), each datagram being given the IPv4 or IPv6 and UDP headers of its source
+//! and destination, so that they can be opened by usual network analyzers. Captures written by
+//! other tools, such as tcpdump, can be read too, from Ethernet, Linux cooked
+//! (`LINKTYPE_LINUX_SLL`) and raw IP links, with microsecond or nanosecond timestamps in either
+//! byte order. Frames which are not complete UDP datagrams, such as other protocols, IP fragments
+//! or datagrams truncated by the capture length, are skipped.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net, time,
+};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER_SIZE: usize = 8;
+
+/// Largest frame written or read, larger records being taken for a corrupted capture
+const SNAPLEN: u32 = 262_144;
+
+pub enum Error {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedLinkType(u32),
+    InvalidLength(u32),
+    Truncated,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::InvalidMagic => write!(fmt, "not a pcap capture"),
+            Self::UnsupportedLinkType(link_type) => {
+                write!(fmt, "unsupported link type {link_type}")
+            }
+            Self::InvalidLength(len) => write!(fmt, "invalid record of {len} bytes"),
+            Self::Truncated => write!(fmt, "truncated capture"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+/// UDP datagram of a capture
+pub struct Datagram {
+    /// Time the datagram was captured at, since UNIX epoch
+    pub timestamp: time::Duration,
+    pub source: net::SocketAddr,
+    pub destination: net::SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+pub struct Writer<W> {
+    output: W,
+    frame: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a capture by writing its header to `output`
+    pub fn new(mut output: W) -> Result<Self, io::Error> {
+        output.write_all(&MAGIC_NANOS.to_le_bytes())?;
+        // version 2.4
+        output.write_all(&2u16.to_le_bytes())?;
+        output.write_all(&4u16.to_le_bytes())?;
+        // time zone and timestamps accuracy, always 0
+        output.write_all(&[0; 8])?;
+        output.write_all(&SNAPLEN.to_le_bytes())?;
+        output.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Self {
+            output,
+            frame: Vec::new(),
+        })
+    }
+
+    /// Appends `datagram` to the capture, its source and destination being of the same family
+    pub fn write(&mut self, datagram: &Datagram) -> Result<(), io::Error> {
+        write_frame(&mut self.frame, datagram)?;
+        let len = self.frame.len() as u32;
+        self.output
+            .write_all(&(datagram.timestamp.as_secs() as u32).to_le_bytes())?;
+        self.output
+            .write_all(&datagram.timestamp.subsec_nanos().to_le_bytes())?;
+        self.output.write_all(&len.to_le_bytes())?;
+        self.output.write_all(&len.to_le_bytes())?;
+        self.output.write_all(&self.frame)
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.output.flush()
+    }
+}
+
+/// Writes the IP packet carrying `datagram` in `frame`
+fn write_frame(frame: &mut Vec<u8>, datagram: &Datagram) -> Result<(), io::Error> {
+    let udp_len = UDP_HEADER_SIZE + datagram.payload.len();
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason);
+
+    frame.clear();
+    let pseudo_header_sum = match (datagram.source.ip(), datagram.destination.ip()) {
+        (net::IpAddr::V4(source), net::IpAddr::V4(destination)) => {
+            let total_len =
+                u16::try_from(20 + udp_len).map_err(|_| invalid("datagram too large"))?;
+            let mut header = [0; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&total_len.to_be_bytes());
+            // don't fragment
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = IPPROTO_UDP;
+            header[12..16].copy_from_slice(&source.octets());
+            header[16..20].copy_from_slice(&destination.octets());
+            let checksum = fold(sum(&header));
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            frame.extend_from_slice(&header);
+            sum(&source.octets()) + sum(&destination.octets()) + udp_len as u64
+        }
+        (net::IpAddr::V6(source), net::IpAddr::V6(destination)) => {
+            let payload_len = u16::try_from(udp_len).map_err(|_| invalid("datagram too large"))?;
+            frame.extend_from_slice(&[0x60, 0, 0, 0]);
+            frame.extend_from_slice(&payload_len.to_be_bytes());
+            frame.extend_from_slice(&[IPPROTO_UDP, 64]);
+            frame.extend_from_slice(&source.octets());
+            frame.extend_from_slice(&destination.octets());
+            sum(&source.octets()) + sum(&destination.octets()) + udp_len as u64
+        }
+        _ => return Err(invalid("source and destination of different families")),
+    };
+
+    let udp_start = frame.len();
+    frame.extend_from_slice(&datagram.source.port().to_be_bytes());
+    frame.extend_from_slice(&datagram.destination.port().to_be_bytes());
+    frame.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&datagram.payload);
+    let udp_sum = pseudo_header_sum + u64::from(IPPROTO_UDP) + sum(&frame[udp_start..]);
+    let checksum = match fold(udp_sum) {
+        // a null checksum means no checksum
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    frame[udp_start + 6..udp_start + 8].copy_from_slice(&checksum.to_be_bytes());
+    Ok(())
+}
+
+/// Sum of the 16-bit big-endian words of `data`, as computed by the Internet checksum
+fn sum(data: &[u8]) -> u64 {
+    data.chunks(2)
+        .map(|word| (u64::from(word[0]) << 8) | word.get(1).copied().map_or(0, u64::from))
+        .sum()
+}
+
+/// Internet checksum of words which sum to `sum`
+fn fold(mut sum: u64) -> u16 {
+    while 0xffff < sum {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub struct Reader<R> {
+    input: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32,
+    frame: Vec<u8>,
+    /// Number of frames which were not complete UDP datagrams
+    pub skipped: u64,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the header of the capture from `input`
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let magic = read_bytes(&mut input)?;
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => return Err(Error::InvalidMagic),
+        };
+        let header: [u8; 20] = read_bytes(&mut input)?;
+        let mut reader = Self {
+            input,
+            big_endian,
+            nanos,
+            link_type: 0,
+            frame: Vec::new(),
+            skipped: 0,
+        };
+        reader.link_type = reader.u32(&header[16..20]);
+        match reader.link_type {
+            LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL | LINKTYPE_IPV4
+            | LINKTYPE_IPV6 => Ok(reader),
+            link_type => Err(Error::UnsupportedLinkType(link_type)),
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().expect("4 bytes");
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// Reads the next UDP datagram of the capture, returning `None` at its end
+    pub fn read(&mut self) -> Result<Option<Datagram>, Error> {
+        loop {
+            let mut header = [0; 16];
+            match read_full(&mut self.input, &mut header)? {
+                0 => return Ok(None),
+                16 => (),
+                _ => return Err(Error::Truncated),
+            }
+            let seconds = self.u32(&header[0..4]);
+            let fraction = self.u32(&header[4..8]);
+            let len = self.u32(&header[8..12]);
+            if SNAPLEN < len {
+                return Err(Error::InvalidLength(len));
+            }
+            self.frame.resize(len as usize, 0);
+            self.input.read_exact(&mut self.frame)?;
+
+            let Some((source, destination, payload)) = udp_datagram(self.link_type, &self.frame)
+            else {
+                self.skipped += 1;
+                continue;
+            };
+            let fraction = if self.nanos {
+                time::Duration::from_nanos(u64::from(fraction))
+            } else {
+                time::Duration::from_micros(u64::from(fraction))
+            };
+            return Ok(Some(Datagram {
+                timestamp: time::Duration::from_secs(u64::from(seconds)) + fraction,
+                source,
+                destination,
+                payload: payload.to_vec(),
+            }));
+        }
+    }
+}
+
+fn read_bytes<const N: usize>(input: &mut impl Read) -> Result<[u8; N], io::Error> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads until `buffer` is full or the end of `input` is reached, returning the number of bytes
+/// read
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match input.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..at + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Source, destination and payload of the UDP datagram carried by `frame`
+fn udp_datagram(link_type: u32, frame: &[u8]) -> Option<(net::SocketAddr, net::SocketAddr, &[u8])> {
+    let packet = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = be16(frame, offset)?;
+            while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+                offset += 4;
+                ethertype = be16(frame, offset)?;
+            }
+            if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                return None;
+            }
+            frame.get(offset + 2..)?
+        }
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        _ => frame,
+    };
+
+    let (source, destination, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(be16(packet, 2)?);
+            // fragments are skipped, only the first one holding the UDP header
+            let fragmented = be16(packet, 6)? & 0x3fff != 0;
+            if header_len < 20 || *packet.get(9)? != IPPROTO_UDP || fragmented {
+                return None;
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                net::IpAddr::from(source),
+                net::IpAddr::from(destination),
+                packet.get(header_len..total_len)?,
+            )
+        }
+        6 => {
+            // extension headers are not supported
+            if *packet.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let payload_len = usize::from(be16(packet, 4)?);
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                net::IpAddr::from(source),
+                net::IpAddr::from(destination),
+                packet.get(40..40 + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let udp_len = usize::from(be16(udp, 4)?);
+    if udp_len < UDP_HEADER_SIZE {
+        return None;
+    }
+    Some((
+        net::SocketAddr::new(source, be16(udp, 0)?),
+        net::SocketAddr::new(destination, be16(udp, 2)?),
+        udp.get(UDP_HEADER_SIZE..udp_len)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(source: &str, destination: &str, payload: &[u8]) -> Datagram {
+        Datagram {
+            timestamp: time::Duration::new(1_700_000_000, 123_456_000),
+            source: source.parse().expect("address"),
+            destination: destination.parse().expect("address"),
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn frame(datagram: &Datagram) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, datagram).expect("frame");
+        frame
+    }
+
+    /// Capture written in the byte order and timestamp precision given, of `records` made of
+    /// their timestamp seconds and fraction, and of their frame
+    fn capture(
+        big_endian: bool,
+        nanos: bool,
+        link_type: u32,
+        records: &[(u32, u32, &[u8])],
+    ) -> Vec<u8> {
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut capture = Vec::new();
+        capture.extend_from_slice(&u32_bytes(if nanos { MAGIC_NANOS } else { MAGIC_MICROS }));
+        capture.extend_from_slice(&[0; 12]);
+        capture.extend_from_slice(&u32_bytes(SNAPLEN));
+        capture.extend_from_slice(&u32_bytes(link_type));
+        for (seconds, fraction, frame) in records {
+            capture.extend_from_slice(&u32_bytes(*seconds));
+            capture.extend_from_slice(&u32_bytes(*fraction));
+            capture.extend_from_slice(&u32_bytes(frame.len() as u32));
+            capture.extend_from_slice(&u32_bytes(frame.len() as u32));
+            capture.extend_from_slice(frame);
+        }
+        capture
+    }
+
+    fn read_all(capture: &[u8]) -> Result<(Vec<Datagram>, u64), Error> {
+        let mut reader = Reader::new(capture)?;
+        let mut datagrams = Vec::new();
+        while let Some(datagram) = reader.read()? {
+            datagrams.push(datagram);
+        }
+        Ok((datagrams, reader.skipped))
+    }
+
+    fn assert_same(read: &Datagram, written: &Datagram) {
+        assert_eq!(read.source, written.source);
+        assert_eq!(read.destination, written.destination);
+        assert_eq!(read.payload, written.payload);
+    }
+
+    #[test]
+    fn round_trip() {
+        let datagrams = [
+            datagram("10.0.0.1:5000", "10.0.0.2:6000", b"ipv4"),
+            datagram("[fd00::1]:5000", "[fd00::2]:6000", b"ipv6"),
+            datagram("10.0.0.1:5000", "10.0.0.2:6000", b""),
+        ];
+        let mut writer = Writer::new(Vec::new()).expect("header");
+        for datagram in &datagrams {
+            writer.write(datagram).expect("datagram");
+        }
+        let (read, skipped) = read_all(&writer.output).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(skipped, 0);
+        assert_eq!(read.len(), datagrams.len());
+        for (read, written) in read.iter().zip(&datagrams) {
+            assert_same(read, written);
+            assert_eq!(read.timestamp, written.timestamp);
+        }
+
+        let mixed = datagram("10.0.0.1:5000", "[fd00::2]:6000", b"");
+        assert!(writer.write(&mixed).is_err());
+    }
+
+    #[test]
+    fn byte_orders() {
+        let written = datagram("10.0.0.1:5000", "10.0.0.2:6000", b"payload");
+        let frame = frame(&written);
+        for big_endian in [false, true] {
+            for nanos in [false, true] {
+                let fraction = if nanos { 123_456_789 } else { 123_456 };
+                let capture = capture(big_endian, nanos, LINKTYPE_RAW, &[(10, fraction, &frame)]);
+                let (read, _) = read_all(&capture).unwrap_or_else(|e| panic!("{e}"));
+                assert_same(&read[0], &written);
+                let expected = if nanos {
+                    time::Duration::new(10, 123_456_789)
+                } else {
+                    time::Duration::new(10, 123_456_000)
+                };
+                assert_eq!(read[0].timestamp, expected, "{big_endian} {nanos}");
+            }
+        }
+    }
+
+    #[test]
+    fn link_types() {
+        let written = datagram("10.0.0.1:5000", "10.0.0.2:6000", b"payload");
+        let ip = frame(&written);
+        let mut ethernet = vec![0; 12];
+        ethernet.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        ethernet.extend_from_slice(&[0, 1]);
+        ethernet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        ethernet.extend_from_slice(&ip);
+        let mut arp = vec![0; 12];
+        arp.extend_from_slice(&0x0806u16.to_be_bytes());
+        arp.extend_from_slice(&[0; 28]);
+        let mut sll = vec![0; 16];
+        sll.extend_from_slice(&ip);
+        // a TCP segment
+        let mut tcp = ip.clone();
+        tcp[9] = 6;
+
+        for (link_type, frames, nb_skipped) in [
+            (LINKTYPE_ETHERNET, vec![&ethernet[..], &arp], 1),
+            (LINKTYPE_LINUX_SLL, vec![&sll[..]], 0),
+            (LINKTYPE_IPV4, vec![&ip[..], &tcp], 1),
+        ] {
+            let records = frames
+                .iter()
+                .map(|frame| (0, 0, *frame))
+                .collect::<Vec<_>>();
+            let (read, skipped) = read_all(&capture(false, false, link_type, &records))
+                .unwrap_or_else(|e| panic!("{e}"));
+            assert_eq!(read.len(), 1, "{link_type}");
+            assert_same(&read[0], &written);
+            assert_eq!(skipped, nb_skipped, "{link_type}");
+        }
+    }
+
+    #[test]
+    fn invalid_captures() {
+        let frame = frame(&datagram("10.0.0.1:5000", "10.0.0.2:6000", b"payload"));
+        let valid = capture(false, true, LINKTYPE_RAW, &[(0, 0, &frame)]);
+
+        // truncated global header
+        for len in [0, 3, 4, 23] {
+            assert!(
+                matches!(Reader::new(&valid[..len]), Err(Error::Truncated)),
+                "{len}"
+            );
+        }
+        assert!(matches!(
+            Reader::new(&[0xd4, 0xc3, 0xb2, 0xa2][..]),
+            Err(Error::InvalidMagic)
+        ));
+        assert!(matches!(
+            Reader::new(&[0u8; 24][..]),
+            Err(Error::InvalidMagic)
+        ));
+        assert!(matches!(
+            Reader::new(&capture(true, false, 147, &[])[..]),
+            Err(Error::UnsupportedLinkType(147))
+        ));
+
+        // truncated record header and record
+        for len in [24 + 1, 24 + 15, 24 + 16, valid.len() - 1] {
+            assert!(
+                matches!(read_all(&valid[..len]), Err(Error::Truncated)),
+                "{len}"
+            );
+        }
+        assert!(read_all(&valid[..24]).is_ok_and(|(read, _)| read.is_empty()));
+
+        let mut oversized = valid.clone();
+        oversized[32..36].copy_from_slice(&(SNAPLEN + 1).to_le_bytes());
+        assert!(matches!(
+            read_all(&oversized),
+            Err(Error::InvalidLength(len)) if len == SNAPLEN + 1
+        ));
+    }
+}
@@ -0,0 +1,143 @@
+//! Dropping of the privileges of the diode binaries once their sockets are bound
+//!
+//! The long-running processes facing the network only need privileges at startup, for instance
+//! to bind ports below 1024 or sockets in protected directories. Once their sockets are bound,
+//! [drop] optionally confines them in a directory with `chroot` and switches them to an
+//! unprivileged user and group, for the whole process: the C library applies identity changes to
+//! every thread, and threads share the root directory.
+//!
+//! Users and groups are given by name or numeric identifier. They are resolved before entering
+//! the `chroot` directory, where the user and group databases are usually not available.
+
+use std::{ffi, io, mem, os::unix::ffi::OsStrExt, path, ptr};
+
+/// Size of the buffer of the strings of user and group database entries
+const ENTRY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Enters `chroot` and switches to `user` and `group`, each change being skipped when not given
+///
+/// Without `group`, the primary group of `user` is taken. Supplementary groups are dropped
+/// whenever the group changes.
+pub fn drop(
+    user: Option<&str>,
+    group: Option<&str>,
+    chroot: Option<&path::Path>,
+) -> Result<(), io::Error> {
+    let user = user.map(passwd_entry).transpose()?;
+    let gid = match (group, user) {
+        (Some(group), _) => Some(group_id(group)?),
+        (None, Some((_, Some(gid)))) => Some(gid),
+        (None, Some((uid, None))) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("user {uid} has no primary group, a group must be given"),
+            ))
+        }
+        (None, None) => None,
+    };
+
+    if let Some(chroot) = chroot {
+        let dir = ffi::CString::new(chroot.as_os_str().as_bytes())?;
+        if unsafe { libc::chroot(dir.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        std::env::set_current_dir("/")?;
+    }
+
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // privileges must not be recoverable once dropped
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "privileges could be regained after switching user",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the identifier of `user` and its primary group, if the user is known to the user
+/// database, a numeric identifier being accepted otherwise
+fn passwd_entry(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>), io::Error> {
+    let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let numeric = user.parse::<libc::uid_t>().ok();
+
+    let res = match numeric {
+        Some(uid) => unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        },
+        None => {
+            let name = ffi::CString::new(user)?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+
+    match (result.is_null(), numeric) {
+        (false, _) => Ok((passwd.pw_uid, Some(passwd.pw_gid))),
+        (true, Some(uid)) => Ok((uid, None)),
+        (true, None) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user \"{user}\""),
+        )),
+    }
+}
+
+/// Returns the identifier of `group`, a numeric identifier being accepted as is
+fn group_id(group: &str) -> Result<libc::gid_t, io::Error> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let name = ffi::CString::new(group)?;
+    let mut entry = unsafe { mem::zeroed::<libc::group>() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let res = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group \"{group}\""),
+        ));
+    }
+    Ok(entry.gr_gid)
+}
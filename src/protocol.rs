@@ -4,7 +4,7 @@
 //! by the messages structure. There are 5 message types:
 //! - `MessageType::Heartbeat` lets know the receiver that transfer can happen,
 //! - `MessageType::Start` informs the receiver that the sent data chunk represents the beginning of
-//!   a new transfer,
+//!   a new transfer, `MessageType::StartSite` doing so for a sender with a site identifier,
 //! - `MessageType::Data` is used to send a data chunk that is not the beginning nor the ending of
 //!   a transfer,
 //! - `MessageType::Abort` informs the receiver that the current transfer has been aborted on the
@@ -28,16 +28,98 @@
 //!
 //! 4-bytes values are encoded in little-endian byte order.
 //!
+//! When integrity checking is enabled on both ends, the sender ends each message with the CRC-32
+//! checksum of the rest of the message (see [CHECKSUM_SIZE]), which the receiver verifies once the
+//! block is decoded, so that corrupted blocks are not delivered.
+//!
+//! Messages are encoded with RaptorQ, each resulting packet being sent in a UDP datagram prefixed
+//! by a [Header] identifying the block and the encoding symbol it carries:
+//!
+//! ```text
+//!
+//! <- 1 byte -> <-- 3 bytes -->
+//! ------------+---------------+-----------------------
+//! |           |               |                     |
+//! |  block_id |   symbol_id   |    symbol data      |
+//! |           |               |                     |
+//! ------------+---------------+-----------------------
+//!  <------ HEADER_SIZE ------>
+//!
+//! ```
+//!
+//! The header is the RaptorQ payload identifier, `symbol_id` being encoded in big-endian byte
+//! order. [write_packet] and [read_packet] are the only functions building and parsing packets.
+//!
+//! Block identifiers are 16-bit values, of which the header only carries the lowest byte, the
+//! RaptorQ source block number. The receiver recovers the identifier of each block as the one
+//! closest to the block it is receiving (see [BlockId::closest]), so that a block late by more
+//! than 128 blocks is mistaken for a later one. With the [HeaderFormat::Wide] format, which both
+//! ends must use, the header is followed by the highest byte of the block identifier, pushing
+//! this limit to the whole 16-bit space:
+//!
+//! ```text
+//!
+//! <- 1 byte -> <-- 3 bytes --> <---- 1 byte ---->
+//! ------------+---------------+------------------+-----------------------
+//! |           |               |                  |                     |
+//! |  block_id |   symbol_id   |  block_id_high   |    symbol data      |
+//! |           |               |                  |                     |
+//! ------------+---------------+------------------+-----------------------
+//!  <----------------- HeaderFormat::size ------->
+//!
+//! ```
+//!
+//! Optionally, the sender follows the packets of each block with a block trailer, a datagram made
+//! of a header whose `symbol_id` is [TRAILER_SYMBOL_ID] and of the number of packets of the block
+//! actually emitted, as a 4-bytes little-endian value (see [write_trailer]). This symbol
+//! identifier is never used by encoding packets.
+//!
+//! Also optionally, the sender periodically emits configuration packets, datagrams made of a
+//! header whose `symbol_id` is [CONFIG_SYMBOL_ID] and of its [EncodingConfig] (see
+//! [write_config]), so that the receiver can check its own configuration or adopt the one of the
+//! sender. Since they must be read before the receiver knows how blocks are encoded, they are
+//! not encoded messages but datagrams on their own, like block trailers, and their header is
+//! always in the [HeaderFormat::Narrow] format. They start with the [PROTOCOL_VERSION] of the
+//! sender and the optional [Features] it uses, and later versions may only append fields to
+//! them, so that a receiver tells an incompatible sender apart from corrupted packets.
+//!
+//! The highest bit of `symbol_id` flags the packets of blocks carrying a compressed message, whose
+//! data was compressed in the LZ4 block format by the sender (see [Message::compressed]) and is
+//! decompressed by the receiver once the block is decoded. Encoding symbol identifiers thus fit in
+//! 23 bits, the trailer and configuration identifiers excepted.
+//!
+//! The highest byte of `client_id` is the [Channel] of the transfer, a logical channel chosen by
+//! the sender for each of its listening sockets and used by the receiver to select the
+//! destination of the transfer. The 3 remaining bytes identify the transfer among those of the
+//! channel.
+//!
 //! In `Heartbeat` messages, `client_id` is unused and should be set to 0 by the constructor
 //! caller. Also no data payload should be provided by the constructor caller in case the message
-//! is of type `Heartbeat`, `Abort` or `End`. Then the `data_length` will be set to 0 by the
-//! message constructor and the data chunk will be fully padded with zeros.
+//! is of type `Abort` or `End`. Then the `data_length` will be set to 0 by the message
+//! constructor and the data chunk will be fully padded with zeros. The payload of `Heartbeat`
+//! messages is either empty or contains the sender site identifier (see [check_site_id]), or
+//! a [SenderStatus] (see [heartbeat_payload]). The data of `StartSite` messages starts with the
+//! length of the site identifier on 1 byte and the site identifier, followed by the data of the
+//! transfer (see [Message::site_id]).
 
-use std::{fmt, io, sync};
+use crate::{durable, fec, lz4};
+use std::{fmt, io, net, sync, time};
 
 pub enum Error {
     Io(io::Error),
     InvalidMessageType(Option<u8>),
+    PacketTooShort(usize),
+    BufferTooSmall(usize, usize),
+    InvalidSymbolId(u32),
+    InvalidTrailer(usize),
+    InvalidConfig(usize),
+    UnknownFec(u8),
+    InvalidDataLength(u32, usize),
+    InvalidPadding(usize),
+    Decompression(String),
+    ChecksumMismatch(u32, u32),
+    InvalidHeartbeat(usize),
+    InvalidSiteId(String),
 }
 
 impl fmt::Display for Error {
@@ -45,6 +127,39 @@ impl fmt::Display for Error {
         match self {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::InvalidMessageType(b) => write!(fmt, "invalid message type: {b:?}"),
+            Self::PacketTooShort(len) => write!(fmt, "packet of {len} byte(s) is too short"),
+            Self::BufferTooSmall(needed, len) => write!(
+                fmt,
+                "buffer of {len} byte(s) is too small for a packet of {needed} byte(s)"
+            ),
+            Self::InvalidSymbolId(id) => {
+                write!(fmt, "symbol id {id} does not fit in 23 bits")
+            }
+            Self::InvalidTrailer(len) => {
+                write!(fmt, "block trailer of {len} byte(s) has an invalid length")
+            }
+            Self::InvalidConfig(len) => {
+                write!(fmt, "configuration packet of {len} byte(s) is invalid")
+            }
+            Self::UnknownFec(id) => {
+                write!(fmt, "unknown forward error correction identifier {id}")
+            }
+            Self::InvalidDataLength(len, max) => write!(
+                fmt,
+                "declared data length of {len} byte(s) inconsistent with a payload of {max} byte(s)"
+            ),
+            Self::InvalidPadding(offset) => {
+                write!(fmt, "non-zero padding byte at payload offset {offset}")
+            }
+            Self::Decompression(e) => write!(fmt, "invalid compressed data: {e}"),
+            Self::ChecksumMismatch(expected, computed) => write!(
+                fmt,
+                "block checksum {computed:08x} does not match the checksum {expected:08x} of the sender"
+            ),
+            Self::InvalidHeartbeat(len) => {
+                write!(fmt, "heartbeat payload of {len} byte(s) has an invalid status")
+            }
+            Self::InvalidSiteId(e) => write!(fmt, "invalid site identifier: {e}"),
         }
     }
 }
@@ -58,6 +173,8 @@ impl From<io::Error> for Error {
 pub(crate) enum MessageType {
     Heartbeat,
     Start,
+    /// `Start` whose data is preceded by the site identifier of the sender
+    StartSite,
     Data,
     Abort,
     End,
@@ -68,6 +185,7 @@ impl MessageType {
         match self {
             Self::Heartbeat => ID_HEARTBEAT,
             Self::Start => ID_START,
+            Self::StartSite => ID_START_SITE,
             Self::Data => ID_DATA,
             Self::Abort => ID_ABORT,
             Self::End => ID_END,
@@ -80,6 +198,7 @@ impl fmt::Display for MessageType {
         match self {
             Self::Heartbeat => write!(fmt, "Heartbeat"),
             Self::Start => write!(fmt, "Start"),
+            Self::StartSite => write!(fmt, "StartSite"),
             Self::Data => write!(fmt, "Data"),
             Self::Abort => write!(fmt, "Abort"),
             Self::End => write!(fmt, "End"),
@@ -92,26 +211,216 @@ const ID_START: u8 = 0x01;
 const ID_DATA: u8 = 0x02;
 const ID_ABORT: u8 = 0x03;
 const ID_END: u8 = 0x04;
+const ID_START_SITE: u8 = 0x05;
 
 pub(crate) type ClientId = u32;
 
+/// Logical channel of a transfer, carried by the highest byte of its [ClientId]
+///
+/// Transfers of the default channel 0 have the same client identifiers as those of senders not
+/// supporting channels.
+pub type Channel = u8;
+
+/// Bits of the [ClientId] identifying the transfer within its [Channel]
+const CLIENT_ID_MASK: u32 = (1 << 24) - 1;
+
+/// Maximum length in bytes of the site identifier carried by heartbeat and `StartSite` messages
+pub const MAX_SITE_ID_LEN: usize = 32;
+
+/// Checks that `site_id` can be carried by heartbeat and `StartSite` messages, and safely used
+/// in logs, metrics labels and paths
+///
+/// A site identifier is made of 1 to [MAX_SITE_ID_LEN] ASCII letters, digits, `-`, `_` and `.`,
+/// and does not start with `.`.
+pub fn check_site_id(site_id: &str) -> Result<(), String> {
+    if site_id.is_empty() || MAX_SITE_ID_LEN < site_id.len() {
+        return Err(format!(
+            "site identifier must be 1 to {MAX_SITE_ID_LEN} bytes long"
+        ));
+    }
+    if !site_id
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+    {
+        return Err(
+            "site identifier must only contain ASCII letters, digits, '-', '_' and '.'".into(),
+        );
+    }
+    if site_id.starts_with('.') {
+        return Err("site identifier must not start with '.'".into());
+    }
+    Ok(())
+}
+
+/// Parses a site identifier received from the sender, an empty one meaning that the sender has
+/// none
+fn parse_site_id(site_id: &[u8]) -> Result<Option<String>, Error> {
+    if site_id.is_empty() {
+        return Ok(None);
+    }
+    let site_id = std::str::from_utf8(site_id).map_err(|e| Error::InvalidSiteId(e.to_string()))?;
+    check_site_id(site_id).map_err(Error::InvalidSiteId)?;
+    Ok(Some(site_id.to_string()))
+}
+
+/// Data of the `StartSite` message of a transfer from the site `site_id`, starting with `data`
+pub(crate) fn start_site_data(site_id: &str, data: &[u8]) -> Vec<u8> {
+    let mut start = Vec::with_capacity(1 + site_id.len() + data.len());
+    start.push(site_id.len() as u8);
+    start.extend_from_slice(site_id.as_bytes());
+    start.extend_from_slice(data);
+    start
+}
+
+/// Parses a `channel:value` parameter, mapping a channel other than the default channel 0 to
+/// `value`
+pub fn parse_channel(s: &str) -> Result<(Channel, String), String> {
+    let (channel, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("\"{s}\" is not of the form channel:value"))?;
+    let channel = channel
+        .parse::<Channel>()
+        .map_err(|e| format!("invalid channel \"{channel}\": {e}"))?;
+    if channel == 0 {
+        return Err("channel 0 is the default channel, it cannot be mapped".to_string());
+    }
+    if value.is_empty() {
+        return Err(format!("no value given for channel {channel}"));
+    }
+    Ok((channel, value.to_string()))
+}
+
+/// State and configuration of the sender, carried by its heartbeat messages so that the receiver
+/// can detect configurations inconsistent with its own
+pub(crate) struct SenderStatus {
+    pub(crate) uptime: time::Duration,
+    /// Number of sessions started
+    pub(crate) sessions: u64,
+    /// Number of blocks encoded, including heartbeats
+    pub(crate) blocks: u64,
+    pub(crate) mtu: u16,
+    pub(crate) encoding_block_size: u64,
+    /// Repair block size of the blocks currently encoded
+    pub(crate) repair_block_size: u32,
+    /// Whether the sender paused its emission after this heartbeat, see
+    /// [crate::send::Sender::pause]
+    pub(crate) paused: bool,
+}
+
+/// First byte of heartbeat payloads carrying a [SenderStatus], which cannot start a site
+/// identifier
+const STATUS_MARKER: u8 = 0x00;
+
+/// Size of the serialized [SenderStatus] following the site identifier, without its flags
+/// byte, as sent by previous versions
+const STATUS_SIZE: usize = 8 + 8 + 8 + 2 + 8 + 4;
+
+/// Bit of the flags byte of a serialized [SenderStatus] set when the sender paused its emission
+const STATUS_PAUSED: u8 = 0x01;
+
+/// Builds the payload of heartbeat messages
+///
+/// Without `status`, the payload is the site identifier, if any. With `status`, the payload is
+/// the `0x00` marker, the length of the site identifier on 1 byte, the site identifier, then the
+/// uptime in seconds, the numbers of sessions and of blocks, the MTU, the encoding block size and
+/// the repair block size, as little-endian values of 8, 8, 8, 2, 8 and 4 bytes, and a flags byte
+/// whose bit `0x01` is set when the sender paused its emission. Receivers of previous versions
+/// refuse statuses with a flags byte, senders of previous versions do not send it.
+pub(crate) fn heartbeat_payload(site_id: Option<&str>, status: Option<&SenderStatus>) -> Vec<u8> {
+    let site_id = site_id.unwrap_or("").as_bytes();
+    let Some(status) = status else {
+        return site_id.to_vec();
+    };
+    let mut payload = Vec::with_capacity(2 + site_id.len() + STATUS_SIZE + 1);
+    payload.push(STATUS_MARKER);
+    payload.push(site_id.len() as u8);
+    payload.extend_from_slice(site_id);
+    payload.extend_from_slice(&status.uptime.as_secs().to_le_bytes());
+    payload.extend_from_slice(&status.sessions.to_le_bytes());
+    payload.extend_from_slice(&status.blocks.to_le_bytes());
+    payload.extend_from_slice(&status.mtu.to_le_bytes());
+    payload.extend_from_slice(&status.encoding_block_size.to_le_bytes());
+    payload.extend_from_slice(&status.repair_block_size.to_le_bytes());
+    payload.push(if status.paused { STATUS_PAUSED } else { 0 });
+    payload
+}
+
+/// Parses the payload of a heartbeat message built by [heartbeat_payload], returning the site
+/// identifier and the status of the sender, if any
+pub(crate) fn parse_heartbeat(
+    payload: &[u8],
+) -> Result<(Option<String>, Option<SenderStatus>), Error> {
+    if payload.first() != Some(&STATUS_MARKER) {
+        return Ok((parse_site_id(payload)?, None));
+    }
+    let invalid = || Error::InvalidHeartbeat(payload.len());
+    let site_id_len = usize::from(*payload.get(1).ok_or_else(invalid)?);
+    // the flags byte is missing from the statuses of previous versions
+    let status_len = payload
+        .len()
+        .checked_sub(2 + site_id_len)
+        .ok_or_else(invalid)?;
+    if status_len != STATUS_SIZE && status_len != STATUS_SIZE + 1 {
+        return Err(invalid());
+    }
+    let (site_id, mut status) = payload[2..].split_at(site_id_len);
+    let mut take = |n: usize| {
+        let (bytes, rest) = status.split_at(n);
+        status = rest;
+        bytes
+    };
+    let u64_le = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().expect("8 bytes"));
+    let uptime = time::Duration::from_secs(u64_le(take(8)));
+    let sessions = u64_le(take(8));
+    let blocks = u64_le(take(8));
+    let mtu = u16::from_le_bytes(take(2).try_into().expect("2 bytes"));
+    let encoding_block_size = u64_le(take(8));
+    let repair_block_size = u32::from_le_bytes(take(4).try_into().expect("4 bytes"));
+    let flags = status.first().copied().unwrap_or(0);
+    Ok((
+        parse_site_id(site_id)?,
+        Some(SenderStatus {
+            uptime,
+            sessions,
+            blocks,
+            mtu,
+            encoding_block_size,
+            repair_block_size,
+            paused: flags & STATUS_PAUSED != 0,
+        }),
+    ))
+}
+
 static CLIENT_ID_COUNTER: sync::atomic::AtomicU32 = sync::atomic::AtomicU32::new(0);
 
-pub(crate) fn new_client_id() -> ClientId {
-    CLIENT_ID_COUNTER.fetch_add(1, sync::atomic::Ordering::Relaxed)
+pub(crate) fn new_client_id(channel: Channel) -> ClientId {
+    let id = CLIENT_ID_COUNTER.fetch_add(1, sync::atomic::Ordering::Relaxed);
+    (u32::from(channel) << 24) | (id & CLIENT_ID_MASK)
 }
 
-pub struct Message(Vec<u8>);
+/// Channel of the transfer identified by `client_id`
+pub(crate) const fn channel(client_id: ClientId) -> Channel {
+    (client_id >> 24) as Channel
+}
+
+pub(crate) struct Message {
+    content: Vec<u8>,
+    /// Whether the data of the message is compressed, see [Message::compressed]
+    compressed: bool,
+}
 
 const SERIALIZE_OVERHEAD: usize = 4 + 1 + 4;
 
+/// Size of the checksum ending messages when integrity checking is enabled
+pub const CHECKSUM_SIZE: usize = 4;
+
 impl Message {
     /// Message constructor, craft a message according to the representation introduced in
     /// [crate::protocol].
     ///
     /// Some (unchecked) constraints on arguments must be respected:
-    /// - if `message` is `MessageType::Heartbeat`, `MessageType::Abort` or `MessageType::End`
-    ///   then no data should be provided,
+    /// - if `message` is `MessageType::Abort` or `MessageType::End` then no data should be
+    ///   provided,
     /// - if `message` is `MessageType::Heartbeat` then `client_id` should be equal to 0,
     /// - if there is some `data`, its length must be greater than `message_length`.
     pub(crate) fn new(
@@ -129,7 +438,10 @@ impl Message {
                 content[2] = bytes[2];
                 content[3] = bytes[3];
                 content[4] = message.serialized();
-                Self(content)
+                Self {
+                    content,
+                    compressed: false,
+                }
             }
             Some(data) => {
                 let mut content = Vec::with_capacity(message_length as usize + SERIALIZE_OVERHEAD);
@@ -140,47 +452,196 @@ impl Message {
                 if content.len() < content.capacity() {
                     content.resize(content.capacity(), 0);
                 }
-                Self(content)
+                Self {
+                    content,
+                    compressed: false,
+                }
             }
         }
     }
 
     pub(crate) fn client_id(&self) -> ClientId {
-        let bytes = [self.0[0], self.0[1], self.0[2], self.0[3]];
+        let bytes = [
+            self.content[0],
+            self.content[1],
+            self.content[2],
+            self.content[3],
+        ];
         u32::from_le_bytes(bytes)
     }
 
     pub(crate) fn message_type(&self) -> Result<MessageType, Error> {
-        match self.0.get(4) {
+        match self.content.get(4) {
             Some(&ID_HEARTBEAT) => Ok(MessageType::Heartbeat),
             Some(&ID_START) => Ok(MessageType::Start),
             Some(&ID_DATA) => Ok(MessageType::Data),
             Some(&ID_ABORT) => Ok(MessageType::Abort),
             Some(&ID_END) => Ok(MessageType::End),
+            Some(&ID_START_SITE) => Ok(MessageType::StartSite),
             b => Err(Error::InvalidMessageType(b.copied())),
         }
     }
 
     fn payload_len(&self) -> u32 {
-        let data_len_bytes = [self.0[5], self.0[6], self.0[7], self.0[8]];
+        let data_len_bytes = [
+            self.content[5],
+            self.content[6],
+            self.content[7],
+            self.content[8],
+        ];
         u32::from_le_bytes(data_len_bytes)
     }
 
+    /// Checks that the declared data length is consistent with the message: the data must fit in
+    /// the payload, `Abort` and `End` messages carry none, the padding following the data must be
+    /// zeros as written by [Message::new], and the data of `StartSite` messages must start with a
+    /// valid site identifier
+    ///
+    /// A message failing this check was not built by a sender, for instance decoded from a
+    /// corrupted block, and [Message::payload] must not be called on it.
+    pub(crate) fn check_length(&self) -> Result<(), Error> {
+        self.check_data_length()?;
+        if self.content[4] == ID_START_SITE {
+            let data = self.data();
+            let site_id = data
+                .first()
+                .and_then(|len| data.get(1..=usize::from(*len)))
+                .ok_or_else(|| Error::InvalidSiteId("truncated".to_string()))?;
+            if parse_site_id(site_id)?.is_none() {
+                return Err(Error::InvalidSiteId("empty".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the declared data length as [Message::check_length] does, regardless of the content
+    /// of the data, which may still be compressed
+    fn check_data_length(&self) -> Result<(), Error> {
+        let max = self.content.len().saturating_sub(SERIALIZE_OVERHEAD);
+        if self.content.len() < SERIALIZE_OVERHEAD {
+            return Err(Error::InvalidDataLength(0, max));
+        }
+        let len = self.payload_len();
+        let no_data = matches!(
+            self.message_type(),
+            Ok(MessageType::Abort | MessageType::End)
+        );
+        if max < len as usize || (no_data && len != 0) {
+            return Err(Error::InvalidDataLength(len, max));
+        }
+        let padding = &self.content[SERIALIZE_OVERHEAD + len as usize..];
+        match padding.iter().position(|byte| *byte != 0) {
+            Some(offset) => Err(Error::InvalidPadding(len as usize + offset)),
+            None => Ok(()),
+        }
+    }
+
+    /// Compressed message constructor, returning a message whose data is `data` compressed in
+    /// the LZ4 block format, or `None` if the compressed data does not fit in `message_length`
+    /// bytes
+    ///
+    /// The message must be encoded in a block flagged as [compressed](Header::compressed), and is
+    /// restored by [Message::decompress] once decoded.
+    pub(crate) fn compressed(
+        message: MessageType,
+        message_length: u32,
+        client_id: ClientId,
+        data: &[u8],
+    ) -> Option<Self> {
+        let data = lz4::compress(data);
+        if (message_length as usize) < data.len() {
+            return None;
+        }
+        let mut message = Self::new(message, message_length, client_id, Some(&data));
+        message.compressed = true;
+        Some(message)
+    }
+
+    pub(crate) const fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Returns the message whose data is the decompressed data of this one, decoded from a block
+    /// flagged as compressed, failing if the decompressed data exceeds `max_len` bytes
+    pub(crate) fn decompress(&self, max_len: usize) -> Result<Self, Error> {
+        self.check_data_length()?;
+        let message_type = self.message_type()?;
+        let data = lz4::decompress(self.data(), max_len)
+            .map_err(|e| Error::Decompression(e.to_string()))?;
+        Ok(Self::new(
+            message_type,
+            data.len() as u32,
+            self.client_id(),
+            Some(&data),
+        ))
+    }
+
+    /// Appends the CRC-32 checksum of the serialized message, verified by
+    /// [Message::deserialize_checked]
+    pub(crate) fn append_checksum(&mut self) {
+        let checksum = durable::crc32(&self.content);
+        self.content.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Deserializes a message ending with the checksum appended by [Message::append_checksum],
+    /// failing if the checksum does not match
+    pub(crate) fn deserialize_checked(mut data: Vec<u8>) -> Result<Self, Error> {
+        let Some(len) = data.len().checked_sub(CHECKSUM_SIZE) else {
+            return Err(Error::InvalidDataLength(0, 0));
+        };
+        let expected = u32::from_le_bytes(data[len..].try_into().expect("4 bytes"));
+        data.truncate(len);
+        let computed = durable::crc32(&data);
+        if computed != expected {
+            return Err(Error::ChecksumMismatch(expected, computed));
+        }
+        Ok(Self::deserialize(data))
+    }
+
     pub(crate) const fn deserialize(data: Vec<u8>) -> Self {
-        Self(data)
+        Self {
+            content: data,
+            compressed: false,
+        }
+    }
+
+    /// Returns the buffer holding the serialized message, for it to be reused
+    pub(crate) fn into_buffer(self) -> Vec<u8> {
+        self.content
     }
 
     pub const fn serialize_overhead() -> usize {
         SERIALIZE_OVERHEAD
     }
 
+    /// Data of the transfer carried by the message, without the site identifier of `StartSite`
+    /// messages
     pub(crate) fn payload(&self) -> &[u8] {
+        let data = self.data();
+        if self.content[4] == ID_START_SITE {
+            &data[1 + usize::from(data[0])..]
+        } else {
+            data
+        }
+    }
+
+    /// Site identifier of the sender carried by a `StartSite` message, which must have passed
+    /// [Message::check_length]
+    pub(crate) fn site_id(&self) -> Option<&str> {
+        if self.content[4] != ID_START_SITE {
+            return None;
+        }
+        let data = self.data();
+        std::str::from_utf8(&data[1..=usize::from(data[0])]).ok()
+    }
+
+    fn data(&self) -> &[u8] {
         let len = self.payload_len();
-        &self.0[SERIALIZE_OVERHEAD..(SERIALIZE_OVERHEAD + len as usize)]
+        &self.content[SERIALIZE_OVERHEAD..(SERIALIZE_OVERHEAD + len as usize)]
     }
 
     pub(crate) fn serialized(&self) -> &[u8] {
-        &self.0
+        &self.content
     }
 }
 
@@ -200,16 +661,677 @@ impl fmt::Display for Message {
     }
 }
 
-const PACKET_HEADER_SIZE: u16 = 20 + 8;
+/// Size of the [Header] of each packet sent over UDP, in the [HeaderFormat::Narrow] format
+pub const HEADER_SIZE: usize = 4;
+
+/// Format of the [Header] of packets, which must be the same on both ends
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum HeaderFormat {
+    /// Only the lowest byte of block identifiers is carried
+    #[default]
+    Narrow,
+    /// The header is followed by the highest byte of block identifiers
+    Wide,
+}
+
+impl HeaderFormat {
+    /// Size of the headers of packets
+    pub const fn size(self) -> usize {
+        match self {
+            Self::Narrow => HEADER_SIZE,
+            Self::Wide => HEADER_SIZE + 1,
+        }
+    }
+
+    /// Size of a block trailer datagram
+    pub const fn trailer_size(self) -> usize {
+        self.size() + 4
+    }
+}
+
+/// Symbol identifier of block trailers, the largest identifier fitting in the header
+pub const TRAILER_SYMBOL_ID: u32 = (1 << 24) - 1;
+
+/// Symbol identifier of configuration packets, the identifier preceding [TRAILER_SYMBOL_ID]
+pub const CONFIG_SYMBOL_ID: u32 = TRAILER_SYMBOL_ID - 1;
+
+/// Size of a configuration packet datagram
+///
+/// Configuration packets of later protocol versions may be larger, their additional fields
+/// following those known here.
+pub const CONFIG_SIZE: usize = HEADER_SIZE + 1 + 4 + 1 + 2 + 4 + 12 + 1;
+
+/// Version of the protocol announced in configuration packets, to be incremented by every change
+/// of the format of packets, described in [PROTOCOL_VERSIONS]
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Version of the protocol and the versions of the other end it works with
+pub struct ProtocolVersion {
+    pub version: u8,
+    /// Oldest version of the receivers reading the packets of senders of this version, announced
+    /// in configuration packets
+    pub read_from: u8,
+    /// Oldest version of the senders whose packets receivers of this version read
+    pub reads_from: u8,
+    /// Changes of the format of packets made by this version
+    pub changes: &'static str,
+}
+
+/// Every version of the protocol, oldest first, the last one being [PROTOCOL_VERSION]
+///
+/// Version 0 stands for the senders which do not send configuration packets, and thus never
+/// announce their version.
+pub const PROTOCOL_VERSIONS: &[ProtocolVersion] = &[ProtocolVersion {
+    version: 1,
+    read_from: 1,
+    reads_from: 0,
+    changes: "configuration packets announcing the protocol version and features",
+}];
+
+/// Whether a receiver reads the packets of a sender, from the version the sender announces
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compatibility {
+    Same,
+    /// The sender runs an older version, which the receiver reads
+    OlderSender,
+    /// The sender runs a newer version, announcing that the receiver reads its packets
+    NewerSender,
+    /// The sender runs an older version which the receiver no longer reads
+    TooOld,
+    /// The sender runs a newer version which the receiver cannot read
+    TooNew,
+}
+
+impl Compatibility {
+    pub const fn is_compatible(self) -> bool {
+        !matches!(self, Self::TooOld | Self::TooNew)
+    }
+}
+
+impl ProtocolVersion {
+    /// Description of [PROTOCOL_VERSION]
+    pub fn current() -> &'static Self {
+        PROTOCOL_VERSIONS.last().expect("protocol versions")
+    }
+
+    /// Compatibility of a receiver of this version with a sender announcing `sender`
+    pub const fn compatibility(&self, sender: &EncodingConfig) -> Compatibility {
+        if sender.version == self.version {
+            Compatibility::Same
+        } else if sender.version < self.version {
+            if self.reads_from <= sender.version {
+                Compatibility::OlderSender
+            } else {
+                Compatibility::TooOld
+            }
+        } else if sender.read_from <= self.version {
+            Compatibility::NewerSender
+        } else {
+            Compatibility::TooNew
+        }
+    }
+}
+
+/// Returns the protocol versions, features and header formats known to this build, and which
+/// senders its receiver reads
+pub fn compatibility_matrix() -> String {
+    let current = ProtocolVersion::current();
+    let mut matrix = format!(
+        "protocol version {}, reading senders from version {}, read by receivers from version {}\n",
+        current.version, current.reads_from, current.read_from
+    );
+    matrix.push_str("\nversion  read from  reads from  changes\n");
+    for version in PROTOCOL_VERSIONS {
+        matrix.push_str(&format!(
+            "{:>7}  {:>9}  {:>10}  {}\n",
+            version.version, version.read_from, version.reads_from, version.changes
+        ));
+    }
+    matrix.push_str("\nsenders:\n");
+    for version in 0..current.version {
+        let read = if current.reads_from <= version {
+            "read"
+        } else {
+            "refused"
+        };
+        matrix.push_str(&format!("  version {version}: {read}\n"));
+    }
+    matrix.push_str(&format!(
+        "  version {}: read\n  version {} and later: read when announcing to be read from version {} or older, refused otherwise\n",
+        current.version,
+        current.version + 1,
+        current.version
+    ));
+    matrix.push_str(&format!(
+        "\nfeatures: {}\n",
+        Features(
+            Features::NAMES
+                .iter()
+                .fold(0, |bits, (feature, _)| bits | feature.0)
+        )
+    ));
+    matrix.push_str(&format!(
+        "header formats: narrow ({} bytes), wide ({} bytes)\n",
+        HeaderFormat::Narrow.size(),
+        HeaderFormat::Wide.size()
+    ));
+    matrix
+}
+
+/// Bit of the symbol identifier of the header flagging the packets of compressed blocks
+const COMPRESSED_FLAG: u32 = 1 << 23;
+
+/// Largest ratio between the size of the data of a compressed message and the size of the data
+/// of a message fitting in a block
+pub const MAX_COMPRESSION_RATIO: u32 = 4;
+
+/// Identifier of a block, whose lowest byte is the RaptorQ source block number
+///
+/// Block identifiers are consecutive modulo 65536: all wraparound arithmetic on them is done
+/// here.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct BlockId(u16);
+
+impl BlockId {
+    /// Number of distinct block identifiers
+    pub const COUNT: usize = u16::MAX as usize + 1;
+
+    /// Identifier of the block following this one
+    #[must_use]
+    pub const fn next(self) -> Self {
+        Self(self.0.wrapping_add(1))
+    }
+
+    /// Identifier of the block preceding this one
+    #[must_use]
+    pub const fn prev(self) -> Self {
+        Self(self.0.wrapping_sub(1))
+    }
+
+    /// Number of blocks from this one to `other`, following them forward
+    pub const fn distance(self, other: Self) -> u16 {
+        other.0.wrapping_sub(self.0)
+    }
+
+    /// Position of the block in a table of [BlockId::COUNT] entries
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// RaptorQ source block number of the block, the lowest byte of its identifier
+    pub const fn source_block_number(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Identifier of the block closest to this one whose source block number is
+    /// `source_block_number`, recovering the identifier of a block from a narrow header
+    #[must_use]
+    pub const fn closest(self, source_block_number: u8) -> Self {
+        let delta = source_block_number.wrapping_sub(self.source_block_number()) as i8;
+        Self(self.0.wrapping_add_signed(delta as i16))
+    }
+}
+
+impl From<u16> for BlockId {
+    fn from(id: u16) -> Self {
+        Self(id)
+    }
+}
+
+impl From<BlockId> for u16 {
+    fn from(id: BlockId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+/// Header of a packet sent over UDP, see [crate::protocol]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub block_id: BlockId,
+    pub symbol_id: u32,
+    /// Whether the block of the packet carries a compressed message
+    pub compressed: bool,
+}
+
+impl Header {
+    /// Serializes the header in the [HeaderFormat::Narrow] format
+    pub fn serialized(&self) -> [u8; HEADER_SIZE] {
+        let id = self.wire_symbol_id().to_be_bytes();
+        [self.block_id.source_block_number(), id[1], id[2], id[3]]
+    }
+
+    /// Writes the header in `format` at the beginning of `out`, of `format.size()` bytes
+    fn serialize(&self, format: HeaderFormat, out: &mut [u8]) {
+        out[..HEADER_SIZE].copy_from_slice(&self.serialized());
+        if format == HeaderFormat::Wide {
+            out[HEADER_SIZE] = u16::from(self.block_id).to_be_bytes()[0];
+        }
+    }
+
+    fn deserialize(bytes: [u8; HEADER_SIZE]) -> Self {
+        Self::with_wire_symbol_id(
+            u16::from(bytes[0]).into(),
+            u32::from_be_bytes([0, bytes[1], bytes[2], bytes[3]]),
+        )
+    }
+
+    /// Symbol identifier written in the packet, with the compression flag
+    const fn wire_symbol_id(&self) -> u32 {
+        if self.compressed {
+            self.symbol_id | COMPRESSED_FLAG
+        } else {
+            self.symbol_id
+        }
+    }
+
+    fn with_wire_symbol_id(block_id: BlockId, symbol_id: u32) -> Self {
+        if symbol_id == TRAILER_SYMBOL_ID || symbol_id == CONFIG_SYMBOL_ID {
+            return Self {
+                block_id,
+                symbol_id,
+                compressed: false,
+            };
+        }
+        Self {
+            block_id,
+            symbol_id: symbol_id & !COMPRESSED_FLAG,
+            compressed: symbol_id & COMPRESSED_FLAG != 0,
+        }
+    }
+}
+
+/// The block identifier of the header is the source block number of the payload identifier, its
+/// highest byte being lost
+impl From<&raptorq::PayloadId> for Header {
+    fn from(id: &raptorq::PayloadId) -> Self {
+        Self::with_wire_symbol_id(
+            u16::from(id.source_block_number()).into(),
+            id.encoding_symbol_id(),
+        )
+    }
+}
+
+/// The symbol identifier of the payload identifier keeps the compression flag, so that received
+/// packets carry it up to the decoding of their block, see [strip_compression_flag]
+impl From<Header> for raptorq::PayloadId {
+    fn from(header: Header) -> Self {
+        Self::new(
+            header.block_id.source_block_number(),
+            header.wire_symbol_id(),
+        )
+    }
+}
+
+/// Writes the packet made of `header` in `format` and `payload` at the beginning of `out`,
+/// returning the length of the packet
+pub fn write_packet(
+    format: HeaderFormat,
+    header: &Header,
+    payload: &[u8],
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    if COMPRESSED_FLAG <= header.symbol_id {
+        return Err(Error::InvalidSymbolId(header.symbol_id));
+    }
+    let len = format.size() + payload.len();
+    let Some(out) = out.get_mut(..len) else {
+        return Err(Error::BufferTooSmall(len, out.len()));
+    };
+    let (out_header, out_payload) = out.split_at_mut(format.size());
+    header.serialize(format, out_header);
+    out_payload.copy_from_slice(payload);
+    Ok(len)
+}
+
+/// Parses a packet whose header is in `format`, returning its header and payload
+///
+/// Block trailers and configuration packets are parsed as packets too, their length being
+/// checked: [read_trailer] tells block trailers apart from encoding packets, and configuration
+/// packets are parsed with [read_config].
+pub fn read_packet(format: HeaderFormat, buf: &[u8]) -> Result<(Header, &[u8]), Error> {
+    let Some((header, payload)) = buf.split_first_chunk::<HEADER_SIZE>() else {
+        return Err(Error::PacketTooShort(buf.len()));
+    };
+    let mut header = Header::deserialize(*header);
+    if header.symbol_id == CONFIG_SYMBOL_ID {
+        if buf.len() < CONFIG_SIZE {
+            return Err(Error::InvalidConfig(buf.len()));
+        }
+        return Ok((header, payload));
+    }
+    let payload = match format {
+        HeaderFormat::Narrow => payload,
+        HeaderFormat::Wide => {
+            let Some((high, payload)) = payload.split_first() else {
+                return Err(Error::PacketTooShort(buf.len()));
+            };
+            header.block_id =
+                u16::from_be_bytes([*high, header.block_id.source_block_number()]).into();
+            payload
+        }
+    };
+    if header.symbol_id == TRAILER_SYMBOL_ID && buf.len() != format.trailer_size() {
+        return Err(Error::InvalidTrailer(buf.len()));
+    }
+    Ok((header, payload))
+}
+
+/// Returns whether `buf` is a configuration packet, whatever the header format
+pub(crate) fn is_config_packet(buf: &[u8]) -> bool {
+    buf.first_chunk::<HEADER_SIZE>()
+        .is_some_and(|header| Header::deserialize(*header).symbol_id == CONFIG_SYMBOL_ID)
+}
+
+/// Returns the block trailer in `format` announcing that `nb_packets` packets of block
+/// `block_id` were emitted
+pub fn write_trailer(format: HeaderFormat, block_id: BlockId, nb_packets: u32) -> Vec<u8> {
+    let mut trailer = vec![0; format.trailer_size()];
+    let header = Header {
+        block_id,
+        symbol_id: TRAILER_SYMBOL_ID,
+        compressed: false,
+    };
+    header.serialize(format, &mut trailer);
+    trailer[format.size()..].copy_from_slice(&nb_packets.to_le_bytes());
+    trailer
+}
+
+/// Returns the number of emitted packets announced by `packet` if it is a block trailer read by
+/// [read_packet]
+pub(crate) fn read_trailer(packet: &raptorq::EncodingPacket) -> Option<u32> {
+    if packet.payload_id().encoding_symbol_id() != TRAILER_SYMBOL_ID {
+        return None;
+    }
+    let nb_packets = packet.data().first_chunk::<4>()?;
+    Some(u32::from_le_bytes(*nb_packets))
+}
+
+/// Optional protocol features used by a sender, announced in configuration packets
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Features(u32);
+
+impl Features {
+    /// Block identifiers in the [HeaderFormat::Wide] format
+    pub const WIDE_BLOCK_IDS: Self = Self(1);
+    /// Messages ended by a checksum
+    pub const INTEGRITY: Self = Self(1 << 1);
+    /// Blocks possibly carrying compressed messages
+    pub const COMPRESSION: Self = Self(1 << 2);
+    /// Blocks followed by block trailers
+    pub const BLOCK_TRAILER: Self = Self(1 << 3);
+    /// Datagrams encrypted with a pre-shared key, see [crate::crypto]
+    pub const ENCRYPTION: Self = Self(1 << 4);
+
+    const NAMES: [(Self, &'static str); 5] = [
+        (Self::WIDE_BLOCK_IDS, "wide block ids"),
+        (Self::INTEGRITY, "integrity"),
+        (Self::COMPRESSION, "compression"),
+        (Self::BLOCK_TRAILER, "block trailer"),
+        (Self::ENCRYPTION, "encryption"),
+    ];
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns these features with `other` added if `enabled`
+    #[must_use]
+    pub const fn with(self, other: Self, enabled: bool) -> Self {
+        if enabled {
+            Self(self.0 | other.0)
+        } else {
+            self
+        }
+    }
+
+    /// Features unknown to this version of the protocol, which a receiver cannot support
+    pub fn unknown(self) -> Self {
+        let known = Self::NAMES
+            .iter()
+            .fold(0, |bits, (feature, _)| bits | feature.0);
+        Self(self.0 & !known)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let mut names: Vec<String> = Self::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| (*name).to_string())
+            .collect();
+        if !self.unknown().is_empty() {
+            names.push(format!("unknown {:#x}", self.unknown().0));
+        }
+        if names.is_empty() {
+            write!(fmt, "none")
+        } else {
+            write!(fmt, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Encoding parameters of the sender, broadcast in configuration packets
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EncodingConfig {
+    /// Protocol version of the sender, see [PROTOCOL_VERSION]
+    pub version: u8,
+    /// Oldest protocol version of the receivers reading the packets of the sender, see
+    /// [ProtocolVersion::read_from]
+    pub read_from: u8,
+    pub features: Features,
+    pub fec: fec::Algorithm,
+    /// MTU of the link of the sender
+    pub mtu: u16,
+    pub oti: raptorq::ObjectTransmissionInformation,
+    /// Number of repair packets of the blocks, the largest one when following a repair profile
+    pub nb_repair_packets: u32,
+}
+
+impl EncodingConfig {
+    pub fn encoding_block_size(&self) -> u64 {
+        self.oti.transfer_length()
+    }
+
+    pub fn repair_block_size(&self) -> u32 {
+        self.nb_repair_packets * u32::from(packet_size(&self.oti))
+    }
+
+    /// Format of the headers of the packets of the sender
+    pub fn header_format(&self) -> HeaderFormat {
+        if self.features.contains(Features::WIDE_BLOCK_IDS) {
+            HeaderFormat::Wide
+        } else {
+            HeaderFormat::Narrow
+        }
+    }
+}
+
+impl fmt::Display for EncodingConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            fmt,
+            "protocol version {} read from version {} with features {}, forward error correction {}, MTU {}, encoding block size {}, repair block size {}",
+            self.version,
+            self.read_from,
+            self.features,
+            self.fec,
+            self.mtu,
+            self.encoding_block_size(),
+            self.repair_block_size()
+        )
+    }
+}
+
+/// Returns the configuration packet announcing `config`
+///
+/// The header is followed by the protocol version on 1 byte, the [Features] as a little-endian
+/// value of 4 bytes, the identifier of the forward error correction code on 1 byte, the MTU and
+/// the number of repair packets as little-endian values of 2 and 4 bytes, the 12 bytes of the
+/// serialized RaptorQ object transmission information, then the oldest protocol version of the
+/// receivers reading the packets of the sender on 1 byte.
+pub fn write_config(config: &EncodingConfig) -> [u8; CONFIG_SIZE] {
+    let mut packet = [0; CONFIG_SIZE];
+    let header = Header {
+        block_id: BlockId::default(),
+        symbol_id: CONFIG_SYMBOL_ID,
+        compressed: false,
+    };
+    packet[..HEADER_SIZE].copy_from_slice(&header.serialized());
+    let payload = &mut packet[HEADER_SIZE..];
+    payload[0] = config.version;
+    payload[1..5].copy_from_slice(&config.features.bits().to_le_bytes());
+    payload[5] = config.fec.id();
+    payload[6..8].copy_from_slice(&config.mtu.to_le_bytes());
+    payload[8..12].copy_from_slice(&config.nb_repair_packets.to_le_bytes());
+    payload[12..24].copy_from_slice(&config.oti.serialize());
+    payload[24] = config.read_from;
+    packet
+}
+
+/// Parses the `payload` of a configuration packet read by [read_packet]
+///
+/// Fields appended by later protocol versions are ignored.
+pub fn read_config(payload: &[u8]) -> Result<EncodingConfig, Error> {
+    let invalid = || Error::InvalidConfig(HEADER_SIZE + payload.len());
+    let payload: &[u8; CONFIG_SIZE - HEADER_SIZE] = payload.first_chunk().ok_or_else(invalid)?;
+    let version = payload[0];
+    let features = Features::from_bits(u32::from_le_bytes([
+        payload[1], payload[2], payload[3], payload[4],
+    ]));
+    let fec = fec::Algorithm::from_id(payload[5]).ok_or(Error::UnknownFec(payload[5]))?;
+    let mtu = u16::from_le_bytes([payload[6], payload[7]]);
+    let nb_repair_packets = u32::from_le_bytes([payload[8], payload[9], payload[10], payload[11]]);
+    let oti = raptorq::ObjectTransmissionInformation::deserialize(
+        payload[12..24].try_into().expect("12 bytes"),
+    );
+    let read_from = payload[24];
+    // parameters of blocks which cannot have been encoded, which would make sizes computations
+    // divide by zero
+    let symbol_size = u64::from(oti.symbol_size());
+    if symbol_size == 0
+        || oti.transfer_length() < symbol_size
+        || MAX_ENCODING_PACKETS < oti.transfer_length() / symbol_size
+    {
+        return Err(invalid());
+    }
+    Ok(EncodingConfig {
+        version,
+        read_from,
+        features,
+        fec,
+        mtu,
+        oti,
+        nb_repair_packets,
+    })
+}
+
+/// Removes the compression flag from the payload identifiers of the received `packets` of a
+/// block, returning whether the block is compressed
+pub(crate) fn strip_compression_flag(
+    packets: Vec<raptorq::EncodingPacket>,
+) -> (Vec<raptorq::EncodingPacket>, bool) {
+    let compressed = packets
+        .iter()
+        .any(|packet| Header::from(packet.payload_id()).compressed);
+    if !compressed {
+        return (packets, false);
+    }
+    let packets = packets
+        .into_iter()
+        .map(|packet| {
+            let (id, data) = packet.split();
+            let header = Header::from(&id);
+            raptorq::EncodingPacket::new(
+                raptorq::PayloadId::new(id.source_block_number(), header.symbol_id),
+                data,
+            )
+        })
+        .collect();
+    (packets, true)
+}
+
+/// Returns the payload of the heartbeat message carried by the received `packets` of a block,
+/// read from its first packet as encoding codes leave the beginning of the message as is
+///
+/// The payload is read even if the block cannot be decoded, for instance when the sender uses
+/// another encoding block size, as long as it fits in the first packet.
+pub(crate) fn heartbeat_in_packets(packets: &[raptorq::EncodingPacket]) -> Option<&[u8]> {
+    let first = packets
+        .iter()
+        .find(|packet| packet.payload_id().encoding_symbol_id() == 0)?;
+    let data = first.data();
+    if data.len() < SERIALIZE_OVERHEAD || data[..5] != [0, 0, 0, 0, ID_HEARTBEAT] {
+        return None;
+    }
+    let len = u32::from_le_bytes([data[5], data[6], data[7], data[8]]) as usize;
+    data.get(SERIALIZE_OVERHEAD..SERIALIZE_OVERHEAD.checked_add(len)?)
+}
+
+/// Size of the IPv4 and UDP headers of a datagram
+pub(crate) const PACKET_HEADER_SIZE: u16 = 20 + 8;
+/// Number of bytes the IPv6 header adds to the IPv4 one
+const IPV6_EXTRA_HEADER_SIZE: u16 = 20;
+
+/// MTU from which the size of the packets exchanged with `addr` on a link of `mtu` bytes is
+/// computed
+///
+/// Packet sizes are computed for IPv4 datagrams, the larger header of IPv6 datagrams being
+/// accounted for by reducing the MTU. Both ends of the diode must thus use addresses of the same
+/// family to agree on the size of packets.
+pub fn packet_mtu(mtu: u16, addr: &net::SocketAddr) -> u16 {
+    if is_ipv6(addr) {
+        mtu.saturating_sub(IPV6_EXTRA_HEADER_SIZE)
+    } else {
+        mtu
+    }
+}
+
+/// MTU of the link on which packets computed from `packet_mtu` are exchanged with `addr`, the
+/// inverse of [packet_mtu]
+pub(crate) fn link_mtu(packet_mtu: u16, addr: &net::SocketAddr) -> u16 {
+    if is_ipv6(addr) {
+        packet_mtu.saturating_add(IPV6_EXTRA_HEADER_SIZE)
+    } else {
+        packet_mtu
+    }
+}
+
+/// Whether datagrams exchanged with `addr` are IPv6 datagrams, IPv4-mapped addresses being used
+/// for IPv4 traffic
+fn is_ipv6(addr: &net::SocketAddr) -> bool {
+    match addr {
+        net::SocketAddr::V4(_) => false,
+        net::SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_none(),
+    }
+}
+
 const RAPTORQ_ALIGNMENT: u16 = 8;
-const RAPTORQ_HEADER_SIZE: u16 = 4;
 
-pub fn object_transmission_information(
+pub(crate) fn object_transmission_information(
+    format: HeaderFormat,
     mtu: u16,
     logical_block_size: u64,
 ) -> raptorq::ObjectTransmissionInformation {
-    let data_mtu: u16 =
-        RAPTORQ_ALIGNMENT * ((mtu - PACKET_HEADER_SIZE - RAPTORQ_HEADER_SIZE) / RAPTORQ_ALIGNMENT);
+    let data_mtu = symbol_size(format, mtu);
 
     let nb_encoding_packets = logical_block_size / u64::from(data_mtu);
 
@@ -220,21 +1342,531 @@ pub fn object_transmission_information(
     raptorq::ObjectTransmissionInformation::with_defaults(encoding_block_size, data_mtu)
 }
 
+/// Smallest MTU whose packets, with headers in `format`, carry a symbol larger than the header
+/// of a message
+pub const fn min_mtu(format: HeaderFormat) -> u16 {
+    PACKET_HEADER_SIZE + format.size() as u16 + 2 * RAPTORQ_ALIGNMENT
+}
+
+/// Largest number of encoding packets of a block, RaptorQ splitting larger objects in several
+/// source blocks
+pub const MAX_ENCODING_PACKETS: u64 = 56403;
+
+/// Size of the largest RaptorQ symbol fitting in a datagram of `mtu` bytes with a header in
+/// `format`
+pub(crate) fn symbol_size(format: HeaderFormat, mtu: u16) -> u16 {
+    RAPTORQ_ALIGNMENT * ((mtu - PACKET_HEADER_SIZE - format.size() as u16) / RAPTORQ_ALIGNMENT)
+}
+
+/// Returns the encoding and repair block sizes making each block a single packet, followed by
+/// `nb_repair_packets` repair packets, on a link of `mtu` bytes with headers in `format`
+pub fn single_packet_block_sizes(
+    format: HeaderFormat,
+    mtu: u16,
+    nb_repair_packets: u32,
+) -> (u64, u32) {
+    let symbol_size = symbol_size(format, mtu);
+    (
+        u64::from(symbol_size),
+        nb_repair_packets * u32::from(symbol_size),
+    )
+}
+
+/// Returns the MTU for which the sender produces packets of `packet_size` bytes with headers in
+/// `format`, if this size is valid
+pub(crate) fn mtu_of_packet_size(format: HeaderFormat, packet_size: usize) -> Option<u16> {
+    let data_size = packet_size.checked_sub(format.size())?;
+    if data_size == 0 || data_size % usize::from(RAPTORQ_ALIGNMENT) != 0 {
+        return None;
+    }
+    u16::try_from(packet_size + usize::from(PACKET_HEADER_SIZE)).ok()
+}
+
 pub(crate) fn data_mtu(oti: &raptorq::ObjectTransmissionInformation) -> u16 {
     oti.symbol_size()
 }
 
-pub fn packet_size(oti: &raptorq::ObjectTransmissionInformation) -> u16 {
+pub(crate) fn packet_size(oti: &raptorq::ObjectTransmissionInformation) -> u16 {
     (oti.transfer_length() / nb_encoding_packets(oti)) as u16
 }
 
-pub fn nb_encoding_packets(oti: &raptorq::ObjectTransmissionInformation) -> u64 {
+pub(crate) fn nb_encoding_packets(oti: &raptorq::ObjectTransmissionInformation) -> u64 {
     oti.transfer_length() / u64::from(data_mtu(oti))
 }
 
-pub fn nb_repair_packets(
+pub(crate) fn nb_repair_packets(
     oti: &raptorq::ObjectTransmissionInformation,
     repair_block_size: u32,
 ) -> u32 {
     repair_block_size / u32::from(data_mtu(oti))
 }
+
+/// Ratio of the packets of a block which can be lost while the block can still be decoded
+pub(crate) fn loss_tolerance(
+    oti: &raptorq::ObjectTransmissionInformation,
+    repair_block_size: u32,
+    fec: fec::Algorithm,
+) -> f64 {
+    let nb_encoding_packets = nb_encoding_packets(oti) as f64;
+    let nb_repair_packets = nb_repair_packets(oti, repair_block_size);
+    let spare_packets = nb_repair_packets.saturating_sub(fec.repair_margin());
+    f64::from(spare_packets) / (nb_encoding_packets + f64::from(nb_repair_packets))
+}
+
+/// Smallest repair block size whose [loss_tolerance] reaches `loss`, lower than 1
+pub(crate) fn repair_block_size_for_loss(
+    oti: &raptorq::ObjectTransmissionInformation,
+    loss: f64,
+    fec: fec::Algorithm,
+) -> u32 {
+    // (repair - margin) / (encoding + repair) >= loss
+    // <=> repair >= (loss * encoding + margin) / (1 - loss)
+    let nb_encoding_packets = nb_encoding_packets(oti) as f64;
+    let nb_repair_packets = ((loss * nb_encoding_packets + f64::from(fec.repair_margin()))
+        / (1.0 - loss))
+        .ceil() as u32;
+    nb_repair_packets * u32::from(data_mtu(oti))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    #[test]
+    fn site_id_check() {
+        for site_id in ["a", "site-01", "paris.dc_2", &"x".repeat(MAX_SITE_ID_LEN)] {
+            assert!(check_site_id(site_id).is_ok(), "{site_id}");
+        }
+        for site_id in [
+            "",
+            &"x".repeat(MAX_SITE_ID_LEN + 1),
+            "a/b",
+            "a\\b",
+            "..",
+            ".hidden",
+            "a b",
+            "a\"b",
+            "s\u{1b}[31m",
+            "sité",
+        ] {
+            assert!(check_site_id(site_id).is_err(), "{site_id}");
+        }
+    }
+
+    fn status() -> SenderStatus {
+        SenderStatus {
+            uptime: time::Duration::from_secs(10),
+            sessions: 2,
+            blocks: 3,
+            mtu: 1500,
+            encoding_block_size: 60000,
+            repair_block_size: 6000,
+            paused: false,
+        }
+    }
+
+    #[test]
+    fn heartbeat_status() {
+        for paused in [false, true] {
+            let status = SenderStatus { paused, ..status() };
+            let payload = heartbeat_payload(Some("site-01"), Some(&status));
+            let Ok((_, Some(parsed))) = parse_heartbeat(&payload) else {
+                panic!("valid heartbeat");
+            };
+            assert_eq!(parsed.uptime, status.uptime);
+            assert_eq!(parsed.repair_block_size, status.repair_block_size);
+            assert_eq!(parsed.paused, paused);
+
+            // statuses of previous versions have no flags byte
+            let Ok((_, Some(parsed))) = parse_heartbeat(&payload[..payload.len() - 1]) else {
+                panic!("valid heartbeat without flags");
+            };
+            assert_eq!(parsed.repair_block_size, status.repair_block_size);
+            assert!(!parsed.paused);
+
+            for len in [2, payload.len() - 2] {
+                assert!(matches!(
+                    parse_heartbeat(&payload[..len]),
+                    Err(Error::InvalidHeartbeat(_))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn heartbeat_site_id() {
+        for status in [None, Some(status())] {
+            let payload = heartbeat_payload(Some("site-01"), status.as_ref());
+            let Ok((site_id, parsed)) = parse_heartbeat(&payload) else {
+                panic!("valid heartbeat");
+            };
+            assert_eq!(site_id.as_deref(), Some("site-01"));
+            assert_eq!(parsed.is_some(), status.is_some());
+
+            let payload = heartbeat_payload(None, status.as_ref());
+            assert!(matches!(parse_heartbeat(&payload), Ok((None, _))));
+
+            // identifiers unfit for logs and paths are rejected with the whole heartbeat
+            for site_id in ["../etc", "s\u{1b}[31m", "a\"b"] {
+                let payload = heartbeat_payload(Some(site_id), status.as_ref());
+                assert!(matches!(
+                    parse_heartbeat(&payload),
+                    Err(Error::InvalidSiteId(_))
+                ));
+            }
+        }
+        assert!(matches!(
+            parse_heartbeat(b"\xffsite"),
+            Err(Error::InvalidSiteId(_))
+        ));
+    }
+
+    #[test]
+    fn start_site_message() {
+        let data = start_site_data("site-01", b"session data");
+        let message = Message::new(MessageType::StartSite, 100, 0x0100_0001, Some(&data));
+        assert!(message.check_length().is_ok());
+        assert!(matches!(message.message_type(), Ok(MessageType::StartSite)));
+        assert_eq!(message.site_id(), Some("site-01"));
+        assert_eq!(message.payload(), b"session data");
+
+        let message = Message::new(MessageType::Start, 100, 0x0100_0001, Some(b"data"));
+        assert_eq!(message.site_id(), None);
+        assert_eq!(message.payload(), b"data");
+
+        // the site identifier is compressed with the data
+        let data = start_site_data("site-01", &[b'a'; 1000]);
+        let compressed =
+            Message::compressed(MessageType::StartSite, 100, 1, &data).expect("data compresses");
+        let Ok(message) = compressed.decompress(2000) else {
+            panic!("valid compressed message");
+        };
+        assert!(message.check_length().is_ok());
+        assert_eq!(message.site_id(), Some("site-01"));
+        assert_eq!(message.payload(), &[b'a'; 1000]);
+    }
+
+    #[test]
+    fn start_site_message_invalid() {
+        for data in [
+            &b""[..],
+            b"\x00data",
+            b"\x20short",
+            b"\x05a/b/cdata",
+            b"\x04\x1b[31data",
+        ] {
+            let message = Message::new(MessageType::StartSite, 100, 1, Some(data));
+            assert!(
+                matches!(message.check_length(), Err(Error::InvalidSiteId(_))),
+                "{data:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn end_message_lengths() {
+        let with_len = |message: Message, len: u32| {
+            let mut content = message.into_buffer();
+            content[5..9].copy_from_slice(&len.to_le_bytes());
+            Message::deserialize(content)
+        };
+        let end = || Message::new(MessageType::End, 100, 1, None);
+        assert!(end().check_length().is_ok());
+        assert!(matches!(
+            with_len(end(), 1).check_length(),
+            Err(Error::InvalidDataLength(1, 100))
+        ));
+        assert!(matches!(
+            with_len(end(), u32::MAX).check_length(),
+            Err(Error::InvalidDataLength(u32::MAX, 100))
+        ));
+        assert!(matches!(
+            Message::deserialize(vec![1, 0, 0, 0, ID_END]).check_length(),
+            Err(Error::InvalidDataLength(0, 0))
+        ));
+
+        let data = Message::new(MessageType::Data, 100, 1, Some(&[0x5a; 60]));
+        assert!(data.check_length().is_ok());
+        assert!(matches!(
+            with_len(data, 50).check_length(),
+            Err(Error::InvalidPadding(50))
+        ));
+    }
+
+    #[test]
+    fn write_packet_checks() {
+        let header = Header {
+            block_id: BlockId::from(0x1234),
+            symbol_id: COMPRESSED_FLAG,
+            compressed: false,
+        };
+        let mut out = [0; 16];
+        assert!(matches!(
+            write_packet(HeaderFormat::Narrow, &header, b"data", &mut out),
+            Err(Error::InvalidSymbolId(COMPRESSED_FLAG))
+        ));
+        let header = Header {
+            symbol_id: COMPRESSED_FLAG - 1,
+            ..header
+        };
+        assert!(matches!(
+            write_packet(HeaderFormat::Wide, &header, &[0; 12], &mut out),
+            Err(Error::BufferTooSmall(17, 16))
+        ));
+        assert!(matches!(
+            write_packet(HeaderFormat::Wide, &header, &[0; 11], &mut out),
+            Ok(16)
+        ));
+        assert_eq!(&out[..5], &[0x34, 0x7f, 0xff, 0xff, 0x12]);
+    }
+
+    fn header_format() -> impl Strategy<Value = HeaderFormat> {
+        prop_oneof![Just(HeaderFormat::Narrow), Just(HeaderFormat::Wide)]
+    }
+
+    proptest! {
+        #[test]
+        fn packet_round_trip(
+            format in header_format(),
+            block_id in any::<u16>(),
+            symbol_id in 0..COMPRESSED_FLAG,
+            compressed in any::<bool>(),
+            payload in vec(any::<u8>(), 0..2048),
+        ) {
+            let header = Header {
+                block_id: BlockId::from(block_id),
+                symbol_id,
+                compressed,
+            };
+            let mut out = vec![0; format.size() + payload.len() + 16];
+            let len = write_packet(format, &header, &payload, &mut out)
+                .unwrap_or_else(|e| panic!("{e}"));
+            prop_assert_eq!(len, format.size() + payload.len());
+            let (read, read_payload) =
+                read_packet(format, &out[..len]).unwrap_or_else(|e| panic!("{e}"));
+            prop_assert_eq!(read_payload, &payload[..]);
+            match format {
+                HeaderFormat::Wide => prop_assert_eq!(read, header),
+                // the highest byte of the block identifier is not sent
+                HeaderFormat::Narrow => prop_assert_eq!(
+                    read,
+                    Header {
+                        block_id: BlockId::from(block_id & 0xff),
+                        ..header
+                    }
+                ),
+            }
+        }
+
+        #[test]
+        fn read_packet_never_panics(format in header_format(), buf in vec(any::<u8>(), 0..64)) {
+            if let Ok((_, payload)) = read_packet(format, &buf) {
+                prop_assert!(buf.ends_with(payload));
+                prop_assert!(payload.len() + HEADER_SIZE <= buf.len());
+            }
+        }
+    }
+
+    #[test]
+    fn block_id_next_and_distance() {
+        for id in 0..=u16::MAX {
+            let block_id = BlockId::from(id);
+            assert_eq!(u16::from(block_id.next()), id.wrapping_add(1));
+            assert_eq!(block_id.next().prev(), block_id);
+            assert_eq!(block_id.distance(block_id), 0);
+            assert_eq!(block_id.distance(block_id.next()), 1);
+            assert_eq!(block_id.next().distance(block_id), u16::MAX);
+            assert_eq!(block_id.index(), usize::from(id));
+            assert_eq!(block_id.source_block_number(), id.to_le_bytes()[0]);
+        }
+
+        let last = BlockId::from(u16::MAX);
+        assert_eq!(last.next(), BlockId::from(0));
+        assert_eq!(BlockId::from(0).prev(), last);
+        assert_eq!(last.distance(BlockId::from(0)), 1);
+        assert_eq!(BlockId::from(0).distance(last), u16::MAX);
+        assert_eq!(BlockId::from(65_500).distance(BlockId::from(20)), 56);
+    }
+
+    #[test]
+    fn block_id_closest() {
+        for id in 0..=u16::MAX {
+            let block_id = BlockId::from(id);
+            for source_block_number in 0..=u8::MAX {
+                let closest = block_id.closest(source_block_number);
+                assert_eq!(closest.source_block_number(), source_block_number);
+                // within 128 blocks before and 127 blocks after
+                let distance = block_id.distance(closest);
+                assert!(
+                    distance <= 127 || u16::MAX - 127 <= distance,
+                    "{id} {source_block_number}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn block_id_closest_across_wrap() {
+        // blocks of a window straddling the wrap, recovered from their narrow header
+        for reference in [65_500, 65_535, 0, 40] {
+            let reference = BlockId::from(reference);
+            let mut block_id = BlockId::from(65_480);
+            while block_id != BlockId::from(60) {
+                if reference.distance(block_id) <= 127 || block_id.distance(reference) <= 128 {
+                    assert_eq!(
+                        reference.closest(block_id.source_block_number()),
+                        block_id,
+                        "{reference} {block_id}"
+                    );
+                }
+                block_id = block_id.next();
+            }
+        }
+
+        let reference = BlockId::from(u16::MAX);
+        assert_eq!(reference.closest(0), BlockId::from(0));
+        assert_eq!(reference.closest(126), BlockId::from(126));
+        // 128 blocks after or before, taken before
+        assert_eq!(reference.closest(127), BlockId::from(65_407));
+        assert_eq!(reference.closest(128), BlockId::from(65_408));
+        assert_eq!(BlockId::from(0).closest(255), reference);
+        assert_eq!(BlockId::from(0).closest(128), BlockId::from(65_408));
+        assert_eq!(BlockId::from(0).closest(127), BlockId::from(127));
+    }
+
+    fn encoding_config(version: u8, read_from: u8) -> EncodingConfig {
+        EncodingConfig {
+            version,
+            read_from,
+            features: Features::default().with(Features::INTEGRITY, true),
+            fec: fec::Algorithm::RaptorQ,
+            mtu: 1500,
+            oti: object_transmission_information(HeaderFormat::Narrow, 1500, 14_600),
+            nb_repair_packets: 2,
+        }
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = encoding_config(PROTOCOL_VERSION + 1, PROTOCOL_VERSION);
+        let packet = write_config(&config);
+        let Ok(read) = read_config(&packet[HEADER_SIZE..]) else {
+            panic!("valid configuration");
+        };
+        assert_eq!(read.to_string(), config.to_string());
+        assert_eq!(read.read_from, PROTOCOL_VERSION);
+
+        // fields appended by later versions are ignored, truncated packets refused
+        let mut longer = packet.to_vec();
+        longer.extend_from_slice(&[0xff; 8]);
+        assert!(read_config(&longer[HEADER_SIZE..]).is_ok());
+        assert!(matches!(
+            read_config(&packet[HEADER_SIZE..CONFIG_SIZE - 1]),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn compatibility() {
+        assert_eq!(ProtocolVersion::current().version, PROTOCOL_VERSION);
+        assert!(PROTOCOL_VERSIONS
+            .windows(2)
+            .all(|versions| versions[0].version < versions[1].version));
+
+        // a receiver of version 3 reading senders from version 2
+        let receiver = ProtocolVersion {
+            version: 3,
+            read_from: 3,
+            reads_from: 2,
+            changes: "",
+        };
+        for (version, read_from, compatibility) in [
+            (3, 3, Compatibility::Same),
+            (3, 1, Compatibility::Same),
+            (2, 2, Compatibility::OlderSender),
+            (1, 1, Compatibility::TooOld),
+            (0, 0, Compatibility::TooOld),
+            (4, 3, Compatibility::NewerSender),
+            (4, 2, Compatibility::NewerSender),
+            (4, 4, Compatibility::TooNew),
+            (9, 5, Compatibility::TooNew),
+        ] {
+            let sender = encoding_config(version, read_from);
+            assert_eq!(
+                receiver.compatibility(&sender),
+                compatibility,
+                "{version} {read_from}"
+            );
+            assert_eq!(
+                compatibility.is_compatible(),
+                !matches!(compatibility, Compatibility::TooOld | Compatibility::TooNew)
+            );
+        }
+    }
+
+    #[test]
+    fn compatibility_matrix_versions() {
+        let matrix = compatibility_matrix();
+        for version in PROTOCOL_VERSIONS {
+            assert!(matrix.contains(version.changes), "{matrix}");
+        }
+        for (_, name) in Features::NAMES {
+            assert!(matrix.contains(name), "{matrix}");
+        }
+        assert!(matrix.contains(&format!("version {}: read", PROTOCOL_VERSION)));
+    }
+
+    #[test]
+    fn default_loss_tolerance() {
+        // default geometry of diode-send: 40 packets of 1464 bytes per block
+        let oti = object_transmission_information(HeaderFormat::Narrow, 1500, 60_000);
+        assert_eq!(data_mtu(&oti), 1464);
+        assert_eq!(nb_encoding_packets(&oti), 40);
+
+        // 2 spare packets out of 44
+        let tolerance = loss_tolerance(&oti, 6000, fec::Algorithm::RaptorQ);
+        assert!((tolerance - 2.0 / 44.0).abs() < 1e-9);
+        assert_eq!(
+            repair_block_size_for_loss(&oti, 0.10, fec::Algorithm::RaptorQ),
+            10_248
+        );
+        let tolerance = loss_tolerance(&oti, 10_248, fec::Algorithm::RaptorQ);
+        assert!((tolerance - 5.0 / 47.0).abs() < 1e-9);
+
+        // repair packets within the margin of RaptorQ tolerate no loss
+        assert_eq!(loss_tolerance(&oti, 0, fec::Algorithm::RaptorQ), 0.0);
+        assert_eq!(loss_tolerance(&oti, 2928, fec::Algorithm::RaptorQ), 0.0);
+        let tolerance = loss_tolerance(&oti, 2928, fec::Algorithm::ReedSolomon);
+        assert!((tolerance - 2.0 / 42.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn repair_block_size_for_loss_is_smallest() {
+        for (format, mtu, block_size) in [
+            (HeaderFormat::Narrow, 1500, 14_600),
+            (HeaderFormat::Narrow, 1500, 60_000),
+            (HeaderFormat::Wide, 1500, 60_000),
+            (HeaderFormat::Narrow, 9000, 1_000_000),
+            (HeaderFormat::Narrow, 576, 5_000),
+        ] {
+            let oti = object_transmission_information(format, mtu, block_size);
+            let symbol_size = u32::from(data_mtu(&oti));
+            for fec in [fec::Algorithm::RaptorQ, fec::Algorithm::ReedSolomon] {
+                for percent in [1, 2, 5, 10, 25, 50] {
+                    let loss = f64::from(percent) / 100.0;
+                    let size = repair_block_size_for_loss(&oti, loss, fec);
+                    assert!(size.is_multiple_of(symbol_size));
+                    assert!(
+                        loss <= loss_tolerance(&oti, size, fec),
+                        "{mtu} {block_size} {fec} {percent}%"
+                    );
+                    assert!(
+                        loss_tolerance(&oti, size - symbol_size, fec) < loss,
+                        "{mtu} {block_size} {fec} {percent}%"
+                    );
+                }
+            }
+        }
+    }
+}
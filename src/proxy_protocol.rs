@@ -0,0 +1,349 @@
+//! Parser for the PROXY protocol headers prepended by TCP load balancers
+//!
+//! Both the binary version 2 and the text version 1 of the protocol are accepted, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>. As the specification requires,
+//! the header is not guessed: it is only read from the connections of trusted load balancers,
+//! which must start with one, so that other clients cannot forge their origin.
+
+use crate::sock_utils;
+use std::{
+    fmt,
+    io::{self, Read},
+    net,
+    os::fd::AsRawFd,
+    time,
+};
+
+pub enum Error {
+    Io(io::Error),
+    Timeout,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::Timeout => write!(fmt, "PROXY protocol header not received in time"),
+            Self::Malformed(e) => write!(fmt, "malformed PROXY protocol header: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+const V2_HEADER_SIZE: usize = 16;
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+const V1_MAX_SIZE: usize = 107;
+
+/// Longest time a load balancer takes to send the whole header
+pub const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Reads and strips the PROXY protocol header which must start `stream`, failing if it is not
+/// entirely received within `timeout`
+///
+/// Returns the original source address announced by the proxy, or `None` if the proxy did not
+/// provide a source address (`LOCAL` command or `UNKNOWN` protocol). The receive timeout of
+/// `stream` is left set.
+pub fn read_header<S: Read + AsRawFd>(
+    stream: &mut S,
+    timeout: time::Duration,
+) -> Result<Option<net::SocketAddr>, Error> {
+    let deadline = time::Instant::now() + timeout;
+
+    // the length of the v1 signature, also the shortest v1 header prefix
+    let mut start = [0u8; V1_SIGNATURE.len()];
+    read_exact_before(stream, &mut start, deadline)?;
+
+    if start[..] == V2_SIGNATURE[..start.len()] {
+        let mut header = [0u8; V2_HEADER_SIZE];
+        header[..start.len()].copy_from_slice(&start);
+        read_exact_before(stream, &mut header[start.len()..], deadline)?;
+        let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+        let mut body = vec![0u8; len];
+        read_exact_before(stream, &mut body, deadline)?;
+        return parse_v2(&header, &body);
+    }
+
+    if start == V1_SIGNATURE {
+        let mut line = Vec::with_capacity(V1_MAX_SIZE);
+        line.extend_from_slice(&start);
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_SIZE {
+                return Err(Error::Malformed("v1 header too long"));
+            }
+            read_exact_before(stream, &mut byte, deadline)?;
+            line.push(byte[0]);
+        }
+        return parse_v1(&line);
+    }
+
+    Err(Error::Malformed("missing signature"))
+}
+
+/// Fills `buf` from `stream`, each read being given the time left before `deadline`
+fn read_exact_before<S: Read + AsRawFd>(
+    stream: &mut S,
+    mut buf: &mut [u8],
+    deadline: time::Instant,
+) -> Result<(), Error> {
+    while !buf.is_empty() {
+        let left = deadline.saturating_duration_since(time::Instant::now());
+        if left.is_zero() {
+            return Err(Error::Timeout);
+        }
+        // a null timeout would mean no timeout
+        sock_utils::set_socket_recv_timeout(
+            stream,
+            Some(left.max(time::Duration::from_micros(1))),
+        )?;
+        match stream.read(buf) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Parses a version 2 header, `header` being the fixed 16 bytes part and `body` the following
+/// variable length part
+pub fn parse_v2(
+    header: &[u8; V2_HEADER_SIZE],
+    body: &[u8],
+) -> Result<Option<net::SocketAddr>, Error> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(Error::Malformed("invalid v2 signature"));
+    }
+
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        return Err(Error::Malformed("invalid v2 version"));
+    }
+
+    match command {
+        0x00 => return Ok(None),
+        0x01 => (),
+        _ => return Err(Error::Malformed("invalid v2 command")),
+    }
+
+    let family = header[13] >> 4;
+    match family {
+        // AF_INET
+        0x1 => {
+            let Some(addresses) = body.get(..12) else {
+                return Err(Error::Malformed("truncated v2 IPv4 addresses"));
+            };
+            let ip = net::Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(net::SocketAddr::from((ip, port))))
+        }
+        // AF_INET6
+        0x2 => {
+            let Some(addresses) = body.get(..36) else {
+                return Err(Error::Malformed("truncated v2 IPv6 addresses"));
+            };
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let ip = net::Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(net::SocketAddr::from((ip, port))))
+        }
+        // AF_UNSPEC or AF_UNIX
+        0x0 | 0x3 => Ok(None),
+        _ => Err(Error::Malformed("invalid v2 address family")),
+    }
+}
+
+/// Parses a version 1 header line, including its terminating CRLF
+pub fn parse_v1(line: &[u8]) -> Result<Option<net::SocketAddr>, Error> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or(Error::Malformed("unterminated v1 header"))?;
+    let line = std::str::from_utf8(line).map_err(|_| Error::Malformed("non UTF-8 v1 header"))?;
+
+    let mut fields = line.split(' ');
+
+    if fields.next() != Some("PROXY") {
+        return Err(Error::Malformed("invalid v1 signature"));
+    }
+
+    match fields.next() {
+        Some("TCP4" | "TCP6") => (),
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(Error::Malformed("invalid v1 protocol")),
+    }
+
+    let (Some(src_ip), Some(_dst_ip), Some(src_port), Some(_dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(Error::Malformed("invalid v1 fields count"));
+    };
+
+    let ip = src_ip
+        .parse::<net::IpAddr>()
+        .map_err(|_| Error::Malformed("invalid v1 source address"))?;
+    let port = src_port
+        .parse::<u16>()
+        .map_err(|_| Error::Malformed("invalid v1 source port"))?;
+
+    Ok(Some(net::SocketAddr::from((ip, port))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    fn addr(s: &str) -> Option<net::SocketAddr> {
+        Some(s.parse().expect("address"))
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family << 4 | 0x1]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    /// Reads the header of a connection on which `sent` was written, returning the header read
+    /// and the data following it
+    fn read(sent: &[u8]) -> (Result<Option<net::SocketAddr>, Error>, Vec<u8>) {
+        let (mut client, mut server) = UnixStream::pair().expect("socket pair");
+        client.write_all(sent).expect("write");
+        drop(client);
+        let header = read_header(&mut server, TIMEOUT);
+        let mut rest = Vec::new();
+        let _ = server.read_to_end(&mut rest);
+        (header, rest)
+    }
+
+    #[test]
+    fn v1_headers() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\ndata");
+        assert_eq!(header.ok().flatten(), addr("192.0.2.1:56324"));
+        assert_eq!(rest, b"data");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n");
+        assert_eq!(header.ok().flatten(), addr("[2001:db8::1]:56324"));
+
+        let (header, rest) = read(b"PROXY UNKNOWN\r\ndata");
+        assert!(matches!(header, Ok(None)));
+        assert_eq!(rest, b"data");
+    }
+
+    #[test]
+    fn v2_headers() {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        body.extend_from_slice(&56324u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        let mut sent = v2(1, 1, &body);
+        sent.extend_from_slice(b"data");
+        let (header, rest) = read(&sent);
+        assert_eq!(header.ok().flatten(), addr("192.0.2.1:56324"));
+        assert_eq!(rest, b"data");
+
+        let mut body = net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)
+            .octets()
+            .to_vec();
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&56324u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        // trailing TLVs are skipped
+        body.extend_from_slice(&[0x04, 0, 1, 0]);
+        let (header, _) = read(&v2(1, 2, &body));
+        assert_eq!(header.ok().flatten(), addr("[2001:db8::1]:56324"));
+
+        // health checks of the proxy itself
+        let mut sent = v2(0, 0, &[]);
+        sent.extend_from_slice(b"data");
+        let (header, rest) = read(&sent);
+        assert!(matches!(header, Ok(None)));
+        assert_eq!(rest, b"data");
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let malformed = |sent: &[u8]| matches!(read(sent).0, Err(Error::Malformed(_)));
+
+        assert!(malformed(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(malformed(
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+        ));
+        assert!(malformed(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"));
+        assert!(malformed(
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443 1\r\n"
+        ));
+        assert!(malformed(
+            b"PROXY TCP4 192.0.2.300 198.51.100.1 56324 443\r\n"
+        ));
+        assert!(malformed(
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n"
+        ));
+        assert!(malformed(
+            &[b"PROXY ".as_slice(), &[b'x'; V1_MAX_SIZE]].concat()
+        ));
+
+        let mut bad_version = v2(1, 1, &[0; 12]);
+        bad_version[12] = 0x11;
+        assert!(malformed(&bad_version));
+        assert!(malformed(&v2(2, 1, &[0; 12])));
+        assert!(malformed(&v2(1, 1, &[0; 11])));
+        assert!(malformed(&v2(1, 2, &[0; 35])));
+        assert!(malformed(&v2(1, 4, &[])));
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert!(matches!(read(b"PRO").0, Err(Error::Io(_))));
+        assert!(matches!(read(b"PROXY TCP4 192.0.2.1").0, Err(Error::Io(_))));
+        assert!(matches!(
+            read(&v2(1, 1, &[0; 12])[..20]).0,
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn stalled_header_times_out() {
+        let (mut client, mut server) = UnixStream::pair().expect("socket pair");
+        let timeout = time::Duration::from_millis(200);
+        let started = time::Instant::now();
+
+        // a proxy sending a partial header byte by byte, slower than the deadline
+        let stalling = std::thread::spawn(move || {
+            for byte in b"PROXY TCP4 ".iter() {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(time::Duration::from_millis(50));
+            }
+            client
+        });
+
+        assert!(matches!(
+            read_header(&mut server, timeout),
+            Err(Error::Timeout)
+        ));
+        let elapsed = started.elapsed();
+        assert!(timeout <= elapsed && elapsed < 2 * timeout, "{elapsed:?}");
+        drop(stalling.join());
+    }
+}
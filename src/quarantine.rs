@@ -0,0 +1,723 @@
+//! Storage of the blocks the receiver failed to decode, for offline analysis
+//!
+//! When a block cannot be decoded, its packets are written with the block geometry to a single
+//! file of the quarantine directory, named after the time of the failure and the block
+//! identifier. The session the block belonged to cannot be known, since the client identifier
+//! is part of the undecodable data. Files are written by a dedicated worker, the decoding
+//! workers only queuing the packets: when the queue is full, blocks are dropped and counted.
+//! Once the total size of the quarantine files exceeds the configured budget, the oldest files
+//! are removed.
+//!
+//! In store and forward mode (see [crate::receive::StoreAndForward]), the data held for a
+//! session aborted by the sender is written the same way, to a file named after the time of the
+//! abort and the client identifier of the session, with the [SESSION_EXTENSION] extension. It
+//! holds the payloads of the messages of the session as they would have been delivered.
+//!
+//! A quarantine block file has the following representation, integers being encoded in
+//! little-endian byte order:
+//!
+//! ```text
+//!
+//! <-- 6 bytes --> <- 8 bytes -> <- 2 bytes -> <---- 8 bytes ----> <---- 4 bytes ---->
+//! ---------------+-------------+-------------+-------------------+-------------------+--
+//! |              |             |             |                   |                   |
+//! | "LIDIQ" + 3  |  timestamp  |     mtu     |  encoding_block_  |  repair_block_    |
+//! |              |             |             |  size             |  size             |
+//! ---------------+-------------+-------------+-------------------+-------------------+--
+//!
+//!  <- 1 byte -> <-- 12 bytes --> <- 2 bytes -> <-- 4 bytes -->
+//! --+----------+----------------+-------------+---------------+----------------------------------
+//!   |          |                |             |               |                                |
+//!   |   fec    |  RaptorQ       |  block_id   |  nb_packets   |  packets: 2 bytes length +     |
+//!   |          |  OTI           |             |               |  packet (see [crate::protocol]) |
+//! --+----------+----------------+-------------+---------------+----------------------------------
+//!
+//! ```
+//!
+//! The timestamp is in milliseconds since UNIX epoch, `fec` is the identifier of the forward
+//! error correction code of the block (see [crate::fec::Algorithm::id]), and the RaptorQ object
+//! transmission information is serialized as specified by RFC 6330, whatever the code. Packets
+//! are written with narrow headers (see [crate::protocol::HeaderFormat]), whatever the format
+//! used on the link. Version 1 files, written before the code was configurable, have no `fec`
+//! byte and hold RaptorQ blocks. Version 1 and 2 files, written before block identifiers were
+//! widened, have a `block_id` of 1 byte.
+
+use crate::{fec, metrics, protocol, receive::store};
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    io::{self, Read, Write},
+    path, time,
+};
+
+const MAGIC: &[u8; 5] = b"LIDIQ";
+const VERSION: u8 = 3;
+
+/// Extension of the quarantine block files, other files of the directory are left untouched
+pub const EXTENSION: &str = "lidiq";
+
+/// Extension of the quarantine files of aborted sessions
+pub const SESSION_EXTENSION: &str = "lidiqs";
+
+/// Maximum number of blocks and sessions waiting to be written
+const QUEUE_SIZE: usize = 16;
+
+pub enum Error {
+    Io(io::Error),
+    Protocol(protocol::Error),
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    UnknownFec(u8),
+    Truncated,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::Protocol(e) => write!(fmt, "invalid packet: {e}"),
+            Self::InvalidMagic => write!(fmt, "not a quarantine file"),
+            Self::UnsupportedVersion(v) => write!(fmt, "unsupported quarantine file version {v}"),
+            Self::UnknownFec(id) => write!(fmt, "unknown forward error correction {id}"),
+            Self::Truncated => write!(fmt, "truncated quarantine file"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+impl From<protocol::Error> for Error {
+    fn from(e: protocol::Error) -> Self {
+        Self::Protocol(e)
+    }
+}
+
+/// Geometry of the blocks, needed to decode them
+#[derive(Clone, Copy)]
+pub struct Geometry {
+    pub mtu: u16,
+    pub encoding_block_size: u64,
+    pub repair_block_size: u32,
+    pub fec: fec::Algorithm,
+    pub oti: raptorq::ObjectTransmissionInformation,
+}
+
+impl Geometry {
+    /// Number of source symbols of a block, symbols with greater identifiers being repair ones
+    pub fn nb_source_symbols(&self) -> u32 {
+        protocol::nb_encoding_packets(&self.oti) as u32
+    }
+}
+
+/// Content of a quarantine file
+pub struct Block {
+    pub timestamp: time::SystemTime,
+    pub geometry: Geometry,
+    pub block_id: protocol::BlockId,
+    pub packets: Vec<raptorq::EncodingPacket>,
+}
+
+impl Block {
+    fn write<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        let timestamp = self
+            .timestamp
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.write_all(&timestamp.to_le_bytes())?;
+        out.write_all(&self.geometry.mtu.to_le_bytes())?;
+        out.write_all(&self.geometry.encoding_block_size.to_le_bytes())?;
+        out.write_all(&self.geometry.repair_block_size.to_le_bytes())?;
+        out.write_all(&[self.geometry.fec.id()])?;
+        out.write_all(&self.geometry.oti.serialize())?;
+        out.write_all(&u16::from(self.block_id).to_le_bytes())?;
+        out.write_all(&(self.packets.len() as u32).to_le_bytes())?;
+
+        let mut buffer =
+            vec![0; protocol::HEADER_SIZE + usize::from(self.geometry.oti.symbol_size())];
+        for packet in &self.packets {
+            if buffer.len() < protocol::HEADER_SIZE + packet.data().len() {
+                buffer.resize(protocol::HEADER_SIZE + packet.data().len(), 0);
+            }
+            let header = protocol::Header::from(packet.payload_id());
+            let len = protocol::write_packet(
+                protocol::HeaderFormat::Narrow,
+                &header,
+                packet.data(),
+                &mut buffer,
+            )?;
+            out.write_all(&(len as u16).to_le_bytes())?;
+            out.write_all(&buffer[..len])?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads a quarantine file
+    pub fn read(path: &path::Path) -> Result<Self, Error> {
+        let mut input = io::BufReader::new(fs::File::open(path)?);
+
+        let mut magic = [0; 5];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let [version] = read_bytes(&mut input)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let timestamp = u64::from_le_bytes(read_bytes(&mut input)?);
+        let mtu = u16::from_le_bytes(read_bytes(&mut input)?);
+        let encoding_block_size = u64::from_le_bytes(read_bytes(&mut input)?);
+        let repair_block_size = u32::from_le_bytes(read_bytes(&mut input)?);
+        let fec = if version == 1 {
+            fec::Algorithm::RaptorQ
+        } else {
+            let [id] = read_bytes(&mut input)?;
+            fec::Algorithm::from_id(id).ok_or(Error::UnknownFec(id))?
+        };
+        let oti = raptorq::ObjectTransmissionInformation::deserialize(&read_bytes(&mut input)?);
+        let block_id = if version < 3 {
+            let [block_id] = read_bytes(&mut input)?;
+            u16::from(block_id)
+        } else {
+            u16::from_le_bytes(read_bytes(&mut input)?)
+        };
+        let nb_packets = u32::from_le_bytes(read_bytes(&mut input)?);
+
+        let mut packets = Vec::new();
+        let mut buffer = Vec::new();
+        for _ in 0..nb_packets {
+            let len = u16::from_le_bytes(read_bytes(&mut input)?);
+            buffer.resize(usize::from(len), 0);
+            input.read_exact(&mut buffer)?;
+            let (header, payload) = protocol::read_packet(protocol::HeaderFormat::Narrow, &buffer)?;
+            packets.push(raptorq::EncodingPacket::new(
+                header.into(),
+                payload.to_vec(),
+            ));
+        }
+
+        Ok(Self {
+            timestamp: time::UNIX_EPOCH + time::Duration::from_millis(timestamp),
+            geometry: Geometry {
+                mtu,
+                encoding_block_size,
+                repair_block_size,
+                fec,
+                oti,
+            },
+            block_id: block_id.into(),
+            packets,
+        })
+    }
+
+    /// Counts the packets of the block and tries to decode it again
+    pub fn analyze(&self) -> Report {
+        let nb_source_symbols = self.geometry.nb_source_symbols();
+
+        let mut source_ids = BTreeSet::new();
+        let mut repair_ids = BTreeSet::new();
+        let mut nb_duplicates = 0;
+        let mut nb_foreign = 0;
+        let mut packets = Vec::with_capacity(self.packets.len());
+
+        for packet in &self.packets {
+            let header = protocol::Header::from(packet.payload_id());
+            if header.block_id.source_block_number() != self.block_id.source_block_number() {
+                nb_foreign += 1;
+                continue;
+            }
+            // the decoder only accepts packets of the block
+            packets.push(packet.clone());
+            let ids = if header.symbol_id < nb_source_symbols {
+                &mut source_ids
+            } else {
+                &mut repair_ids
+            };
+            if !ids.insert(header.symbol_id) {
+                nb_duplicates += 1;
+            }
+        }
+
+        let missing_source_ids = (0..nb_source_symbols)
+            .filter(|id| !source_ids.contains(id))
+            .collect();
+
+        let decoded = fec::new(self.geometry.fec, &self.geometry.oti)
+            .decode(self.block_id, packets, None)
+            .is_some();
+
+        Report {
+            nb_source_symbols,
+            nb_source_packets: source_ids.len(),
+            nb_repair_packets: repair_ids.len(),
+            nb_duplicates,
+            nb_foreign,
+            missing_source_ids,
+            decoded,
+        }
+    }
+}
+
+fn read_bytes<const N: usize, R: Read>(input: &mut R) -> Result<[u8; N], io::Error> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Result of the analysis of a quarantined block
+pub struct Report {
+    /// Number of source symbols of a complete block
+    pub nb_source_symbols: u32,
+    /// Number of distinct source symbols received
+    pub nb_source_packets: usize,
+    /// Number of distinct repair symbols received
+    pub nb_repair_packets: usize,
+    /// Number of packets carrying an already received symbol
+    pub nb_duplicates: usize,
+    /// Number of packets belonging to another block
+    pub nb_foreign: usize,
+    /// Identifiers of the source symbols that were not received
+    pub missing_source_ids: Vec<u32>,
+    /// Whether the block can be decoded with the received packets
+    pub decoded: bool,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(
+            fmt,
+            "source symbols: {}/{} received",
+            self.nb_source_packets, self.nb_source_symbols
+        )?;
+        writeln!(fmt, "repair symbols: {} received", self.nb_repair_packets)?;
+        if 0 < self.nb_duplicates {
+            writeln!(fmt, "duplicate packets: {}", self.nb_duplicates)?;
+        }
+        if 0 < self.nb_foreign {
+            writeln!(fmt, "packets of other blocks: {}", self.nb_foreign)?;
+        }
+
+        write!(fmt, "missing source symbols:")?;
+        if self.missing_source_ids.is_empty() {
+            write!(fmt, " none")?;
+        }
+        // displaying consecutive identifiers as ranges
+        let mut ids = self.missing_source_ids.iter().peekable();
+        while let Some(&first) = ids.next() {
+            let mut last = first;
+            while ids.next_if(|&&id| id == last + 1).is_some() {
+                last += 1;
+            }
+            if first == last {
+                write!(fmt, " {first}")?;
+            } else {
+                write!(fmt, " {first}-{last}")?;
+            }
+        }
+        writeln!(fmt)?;
+
+        write!(
+            fmt,
+            "decoding: {}",
+            if self.decoded { "success" } else { "failure" }
+        )
+    }
+}
+
+/// Data queued to be quarantined
+enum Entry {
+    /// Packets of a block which could not be decoded
+    Block(protocol::BlockId, Vec<raptorq::EncodingPacket>),
+    /// Data held for an aborted session
+    Session(protocol::ClientId, store::Store),
+}
+
+pub(crate) struct Quarantine {
+    dir: path::PathBuf,
+    max_bytes: u64,
+    geometry: Geometry,
+    to_quarantine: crossbeam_channel::Sender<(time::SystemTime, Entry)>,
+    for_quarantine: crossbeam_channel::Receiver<(time::SystemTime, Entry)>,
+}
+
+impl Quarantine {
+    pub(crate) fn new(dir: path::PathBuf, max_bytes: u64, geometry: Geometry) -> Self {
+        let (to_quarantine, for_quarantine) = crossbeam_channel::bounded(QUEUE_SIZE);
+        Self {
+            dir,
+            max_bytes,
+            geometry,
+            to_quarantine,
+            for_quarantine,
+        }
+    }
+
+    pub(crate) fn dir(&self) -> &path::Path {
+        &self.dir
+    }
+
+    /// Queues the packets of block `block_id` which could not be decoded
+    pub(crate) fn record(
+        &self,
+        block_id: protocol::BlockId,
+        packets: Vec<raptorq::EncodingPacket>,
+    ) {
+        let now = time::SystemTime::now();
+        if self
+            .to_quarantine
+            .try_send((now, Entry::Block(block_id, packets)))
+            .is_err()
+        {
+            metrics::counter("rx_quarantine_dropped_blocks").inc();
+        }
+    }
+
+    /// Queues the data held for the session `client_id`, which was aborted
+    pub(crate) fn record_session(&self, client_id: protocol::ClientId, store: store::Store) {
+        let now = time::SystemTime::now();
+        if self
+            .to_quarantine
+            .try_send((now, Entry::Session(client_id, store)))
+            .is_err()
+        {
+            metrics::counter("rx_quarantine_dropped_sessions").inc();
+        }
+    }
+
+    fn write(&self, block: &Block, seq: u64) -> Result<path::PathBuf, Error> {
+        let timestamp = block
+            .timestamp
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.dir.join(format!(
+            "{timestamp}-{seq}-block{}.{EXTENSION}",
+            block.block_id
+        ));
+        let file = fs::File::create(&path)?;
+        block.write(io::BufWriter::new(file))?;
+        Ok(path)
+    }
+
+    fn write_session(
+        &self,
+        timestamp: time::SystemTime,
+        client_id: protocol::ClientId,
+        store: store::Store,
+        seq: u64,
+    ) -> Result<path::PathBuf, Error> {
+        let timestamp = timestamp
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.dir.join(format!(
+            "{timestamp}-{seq}-session{client_id:x}.{SESSION_EXTENSION}"
+        ));
+        let mut out = io::BufWriter::new(fs::File::create(&path)?);
+        store.replay(|_, payload| out.write_all(payload))?;
+        out.flush()?;
+        Ok(path)
+    }
+
+    /// Removes the oldest quarantine files until their total size fits in the budget
+    fn evict(&self) -> Result<(), io::Error> {
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path
+                .extension()
+                .is_none_or(|ext| ext != EXTENSION && ext != SESSION_EXTENSION)
+            {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total += metadata.len();
+            files.push((metadata.modified()?, path, metadata.len()));
+        }
+
+        if total <= self.max_bytes {
+            return Ok(());
+        }
+
+        files.sort();
+        for (_, path, len) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            log::debug!("quarantine budget exceeded, removing {}", path.display());
+            fs::remove_file(&path)?;
+            metrics::counter("rx_quarantine_evicted_files").inc();
+            total -= len;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn start(quarantine: &Quarantine) {
+    log::info!(
+        "quarantining undecodable blocks in {} (up to {} bytes)",
+        quarantine.dir.display(),
+        quarantine.max_bytes
+    );
+
+    for (seq, (timestamp, entry)) in quarantine.for_quarantine.iter().enumerate() {
+        match entry {
+            Entry::Block(block_id, packets) => {
+                let block = Block {
+                    timestamp,
+                    geometry: quarantine.geometry,
+                    block_id,
+                    packets,
+                };
+                match quarantine.write(&block, seq as u64) {
+                    Ok(path) => {
+                        log::info!("block {block_id} quarantined in {}", path.display());
+                        metrics::counter("rx_quarantined_blocks").inc();
+                    }
+                    Err(e) => {
+                        log::error!("failed to quarantine block {block_id}: {e}");
+                        metrics::counter("rx_quarantine_dropped_blocks").inc();
+                    }
+                }
+            }
+            Entry::Session(client_id, store) => {
+                match quarantine.write_session(timestamp, client_id, store, seq as u64) {
+                    Ok(path) => {
+                        log::info!("session {client_id:x} quarantined in {}", path.display());
+                        metrics::counter("rx_quarantined_sessions").inc();
+                    }
+                    Err(e) => {
+                        log::error!("failed to quarantine session {client_id:x}: {e}");
+                        metrics::counter("rx_quarantine_dropped_sessions").inc();
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = quarantine.evict() {
+            log::error!("failed to evict quarantine files: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, Error, Geometry, Quarantine, Report};
+    use crate::{fec, protocol, testing::TempDir};
+    use std::{fs, path, thread, time};
+
+    fn geometry() -> Geometry {
+        Geometry {
+            mtu: 1500,
+            encoding_block_size: 14_600,
+            repair_block_size: 2920,
+            fec: fec::Algorithm::RaptorQ,
+            oti: protocol::object_transmission_information(
+                protocol::HeaderFormat::Narrow,
+                1500,
+                14_600,
+            ),
+        }
+    }
+
+    /// Block `block_id` with the packets whose symbol identifiers are kept by `keep`
+    fn block<K>(block_id: protocol::BlockId, nb_repair_packets: u32, keep: K) -> Block
+    where
+        K: Fn(u32) -> bool,
+    {
+        let geometry = geometry();
+        let data = vec![0x5a; geometry.oti.transfer_length() as usize];
+        let packets = fec::new(geometry.fec, &geometry.oti)
+            .encode(block_id, &data, nb_repair_packets)
+            .into_iter()
+            .filter(|packet| keep(protocol::Header::from(packet.payload_id()).symbol_id))
+            .collect();
+        Block {
+            timestamp: time::UNIX_EPOCH + time::Duration::from_millis(1_700_000_000_123),
+            geometry,
+            block_id,
+            packets,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("quarantine");
+        let block_id = protocol::BlockId::from(0x1234);
+        let block = block(block_id, 2, |id| id != 1);
+        let quarantine = Quarantine::new(dir.path().to_path_buf(), 1 << 20, block.geometry);
+        let path = quarantine
+            .write(&block, 7)
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(
+            path.file_name().and_then(|name| name.to_str()),
+            Some("1700000000123-7-block4660.lidiq")
+        );
+
+        let read = Block::read(&path).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(read.timestamp, block.timestamp);
+        assert_eq!(read.block_id, block_id);
+        assert_eq!(read.geometry.mtu, 1500);
+        assert_eq!(read.geometry.encoding_block_size, 14_600);
+        assert_eq!(read.geometry.repair_block_size, 2920);
+        assert_eq!(read.geometry.fec, fec::Algorithm::RaptorQ);
+        assert_eq!(read.geometry.oti, block.geometry.oti);
+        assert_eq!(read.packets, block.packets);
+
+        // a lost source symbol recovered with a repair one
+        let report = read.analyze();
+        assert_eq!(report.missing_source_ids, [1]);
+        assert_eq!(report.nb_repair_packets, 2);
+        assert!(report.decoded);
+    }
+
+    #[test]
+    fn analysis() {
+        let block_id = protocol::BlockId::from(3);
+        let mut block = block(block_id, 0, |id| !id.is_multiple_of(4) && id != 5);
+        let nb_source_symbols = block.geometry.nb_source_symbols();
+        let duplicate = block.packets[0].clone();
+        block.packets.push(duplicate);
+        let foreign = self::block(block_id.next(), 0, |id| id == 0).packets;
+        block.packets.extend(foreign);
+
+        let report = block.analyze();
+        let missing = (0..nb_source_symbols)
+            .filter(|id| id.is_multiple_of(4) || *id == 5)
+            .collect::<Vec<_>>();
+        assert_eq!(report.nb_source_symbols, nb_source_symbols);
+        assert_eq!(
+            report.nb_source_packets,
+            (nb_source_symbols as usize) - missing.len()
+        );
+        assert_eq!(report.nb_repair_packets, 0);
+        assert_eq!(report.nb_duplicates, 1);
+        assert_eq!(report.nb_foreign, 1);
+        assert_eq!(report.missing_source_ids, missing);
+        assert!(!report.decoded);
+    }
+
+    #[test]
+    fn report() {
+        let report = Report {
+            nb_source_symbols: 10,
+            nb_source_packets: 4,
+            nb_repair_packets: 1,
+            nb_duplicates: 2,
+            nb_foreign: 0,
+            missing_source_ids: vec![0, 2, 3, 4, 7, 9],
+            decoded: false,
+        };
+        assert_eq!(
+            report.to_string(),
+            "source symbols: 4/10 received\n\
+             repair symbols: 1 received\n\
+             duplicate packets: 2\n\
+             missing source symbols: 0 2-4 7 9\n\
+             decoding: failure"
+        );
+
+        let report = Report {
+            nb_source_packets: 10,
+            nb_duplicates: 0,
+            missing_source_ids: Vec::new(),
+            decoded: true,
+            ..report
+        };
+        assert!(report
+            .to_string()
+            .ends_with("missing source symbols: none\ndecoding: success"));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let dir = TempDir::new("quarantine");
+        let block = block(protocol::BlockId::default(), 0, |_| true);
+        let quarantine = Quarantine::new(dir.path().to_path_buf(), 1 << 20, block.geometry);
+        let path = quarantine
+            .write(&block, 0)
+            .unwrap_or_else(|e| panic!("{e}"));
+        let content = fs::read(&path).expect("read quarantine file");
+
+        let read = |content: &[u8]| {
+            let path = dir.path().join("altered.lidiq");
+            fs::write(&path, content).expect("write altered file");
+            Block::read(&path)
+        };
+
+        let mut altered = content.clone();
+        altered[0] = b'X';
+        assert!(matches!(read(&altered), Err(Error::InvalidMagic)));
+        altered = content.clone();
+        altered[5] = 4;
+        assert!(matches!(read(&altered), Err(Error::UnsupportedVersion(4))));
+        altered = content.clone();
+        altered[5 + 1 + 8 + 2 + 8 + 4] = 99;
+        assert!(matches!(read(&altered), Err(Error::UnknownFec(99))));
+        assert!(matches!(
+            read(&content[..content.len() - 1]),
+            Err(Error::Truncated)
+        ));
+        assert!(matches!(
+            Block::read(&dir.path().join("missing.lidiq")),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn eviction() {
+        let dir = TempDir::new("quarantine");
+        let block = block(protocol::BlockId::default(), 0, |_| true);
+        let other = dir.path().join("notes.txt");
+        fs::write(&other, vec![0; 1 << 20]).expect("write other file");
+
+        let file_size = {
+            let quarantine = Quarantine::new(dir.path().to_path_buf(), u64::MAX, block.geometry);
+            let path = quarantine
+                .write(&block, 0)
+                .unwrap_or_else(|e| panic!("{e}"));
+            fs::metadata(path).expect("metadata").len()
+        };
+
+        let quarantine = Quarantine::new(dir.path().to_path_buf(), 2 * file_size, block.geometry);
+        let mut paths = Vec::new();
+        for seq in 1..4 {
+            thread::sleep(time::Duration::from_millis(20));
+            paths.push(
+                quarantine
+                    .write(&block, seq)
+                    .unwrap_or_else(|e| panic!("{e}")),
+            );
+            quarantine.evict().unwrap_or_else(|e| panic!("{e}"));
+        }
+
+        let remaining = |path: &path::PathBuf| path.exists();
+        assert_eq!(
+            paths.iter().map(remaining).collect::<Vec<_>>(),
+            [false, true, true]
+        );
+        assert_eq!(fs::read_dir(dir.path()).expect("read dir").count(), 3);
+        assert!(other.exists());
+    }
+}
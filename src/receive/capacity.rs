@@ -0,0 +1,237 @@
+//! Estimation of the margin left between the cost of decoding blocks and their arrival rate
+//!
+//! Decoding workers report the time spent decoding each block and the reordering worker the
+//! arrival of each decoded block. The averages of both are compared periodically: when decoding
+//! a block takes close to the time available to the decoding workers for each block (the interval
+//! between two blocks times the number of workers), the receiver is about to accumulate an
+//! unrecoverable backlog, and a warning is logged before it happens.
+
+use crate::metrics;
+use std::{sync, time};
+
+/// Interval between two comparisons of the decoding time and the block interval
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// Weight of a new value in the exponentially weighted moving averages
+const EWMA_WEIGHT: f64 = 1.0 / 16.0;
+
+/// Number of the most recent decoding times the percentile is computed from
+const NB_SAMPLES: usize = 1024;
+
+/// Averages of the decoding time and of the interval between blocks, in microseconds
+struct Estimator {
+    decode_ewma: Option<f64>,
+    interval_ewma: Option<f64>,
+    samples: Vec<u64>,
+    next_sample: usize,
+}
+
+impl Estimator {
+    const fn new() -> Self {
+        Self {
+            decode_ewma: None,
+            interval_ewma: None,
+            samples: Vec::new(),
+            next_sample: 0,
+        }
+    }
+
+    fn record_decode(&mut self, micros: u64) {
+        self.decode_ewma = Some(ewma(self.decode_ewma, micros));
+        if self.samples.len() < NB_SAMPLES {
+            self.samples.push(micros);
+        } else {
+            self.samples[self.next_sample] = micros;
+        }
+        self.next_sample = (self.next_sample + 1) % NB_SAMPLES;
+    }
+
+    fn record_interval(&mut self, micros: u64) {
+        self.interval_ewma = Some(ewma(self.interval_ewma, micros));
+    }
+
+    fn decode_ewma(&self) -> Option<f64> {
+        self.decode_ewma
+    }
+
+    fn interval_ewma(&self) -> Option<f64> {
+        self.interval_ewma
+    }
+
+    /// 99th percentile of the most recent decoding times
+    fn decode_p99(&self) -> Option<u64> {
+        let mut samples = self.samples.clone();
+        samples.sort_unstable();
+        let index = (samples.len() * 99).div_ceil(100).checked_sub(1)?;
+        samples.get(index).copied()
+    }
+
+    /// Ratio of the average decoding time to the time `nb_workers` decoding workers have for
+    /// each block, `None` until both averages are known
+    fn load(&self, nb_workers: u32) -> Option<f64> {
+        let interval = self.interval_ewma? * f64::from(nb_workers.max(1));
+        if interval <= 0.0 {
+            return None;
+        }
+        Some(self.decode_ewma? / interval)
+    }
+}
+
+fn ewma(average: Option<f64>, value: u64) -> f64 {
+    let value = value as f64;
+    average.map_or(value, |average| {
+        EWMA_WEIGHT.mul_add(value - average, average)
+    })
+}
+
+struct State {
+    estimator: Estimator,
+    last_arrival: Option<time::Instant>,
+    checked_at: time::Instant,
+}
+
+pub(crate) struct DecodeCapacity {
+    /// Load above which a warning is logged, 0 disabling it
+    threshold: f64,
+    nb_workers: u32,
+    state: sync::Mutex<State>,
+    decode_ewma: sync::Arc<metrics::Metric>,
+    decode_p99: sync::Arc<metrics::Metric>,
+    interval_ewma: sync::Arc<metrics::Metric>,
+    load: sync::Arc<metrics::Metric>,
+}
+
+impl DecodeCapacity {
+    pub(crate) fn new(threshold: f64, nb_workers: u8) -> Self {
+        Self {
+            threshold,
+            nb_workers: u32::from(nb_workers),
+            state: sync::Mutex::new(State {
+                estimator: Estimator::new(),
+                last_arrival: None,
+                checked_at: time::Instant::now(),
+            }),
+            decode_ewma: metrics::gauge("rx_decode_ewma_microseconds"),
+            decode_p99: metrics::gauge("rx_decode_p99_microseconds"),
+            interval_ewma: metrics::gauge("rx_block_interval_ewma_microseconds"),
+            load: metrics::gauge("rx_decode_load_percent"),
+        }
+    }
+
+    /// Records the time a decoding worker spent decoding a block
+    pub(crate) fn record_decode(&self, duration: time::Duration) {
+        let mut state = self.state.lock().expect("acquire lock");
+        state.estimator.record_decode(duration.as_micros() as u64);
+    }
+
+    /// Records the arrival of a decoded block in the reordering worker, comparing the averages
+    /// at most every [CHECK_INTERVAL]
+    pub(crate) fn record_arrival(&self) {
+        let now = time::Instant::now();
+        let mut state = self.state.lock().expect("acquire lock");
+        if let Some(last_arrival) = state.last_arrival.replace(now) {
+            let interval = now.duration_since(last_arrival);
+            state.estimator.record_interval(interval.as_micros() as u64);
+        }
+        if CHECK_INTERVAL <= now.duration_since(state.checked_at) {
+            state.checked_at = now;
+            self.check(&state.estimator);
+        }
+    }
+
+    /// Exports the averages and warns if the load reached the threshold, returning whether it did
+    fn check(&self, estimator: &Estimator) -> bool {
+        let decode_ewma = estimator.decode_ewma().unwrap_or_default();
+        let decode_p99 = estimator.decode_p99().unwrap_or_default();
+        let interval_ewma = estimator.interval_ewma().unwrap_or_default();
+        self.decode_ewma.set(decode_ewma as u64);
+        self.decode_p99.set(decode_p99);
+        self.interval_ewma.set(interval_ewma as u64);
+
+        let Some(load) = estimator.load(self.nb_workers) else {
+            return false;
+        };
+        self.load.set((100.0 * load) as u64);
+
+        let overloaded = 0.0 < self.threshold && self.threshold <= load;
+        if overloaded {
+            log::warn!(
+                "decoding takes {decode_ewma:.0} us per block on average (p99 {decode_p99} us) while blocks arrive every {interval_ewma:.0} us with {} decoding thread(s), {:.0}% of the decoding capacity: increase nb_decoding_threads or reduce encoding_block_size",
+                self.nb_workers,
+                100.0 * load
+            );
+        }
+        overloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ewma, DecodeCapacity, Estimator, NB_SAMPLES};
+
+    /// Estimator fed with `nb` constant decoding times and block intervals
+    fn fed(decode: u64, interval: u64, nb: usize) -> Estimator {
+        let mut estimator = Estimator::new();
+        for _ in 0..nb {
+            estimator.record_decode(decode);
+            estimator.record_interval(interval);
+        }
+        estimator
+    }
+
+    #[test]
+    fn averages() {
+        assert_eq!(ewma(None, 100), 100.0);
+        assert_eq!(ewma(Some(100.0), 116), 101.0);
+        assert_eq!(ewma(Some(101.0), 85), 100.0);
+
+        let mut estimator = Estimator::new();
+        assert_eq!(estimator.decode_p99(), None);
+        for micros in (1..=100).rev() {
+            estimator.record_decode(micros);
+        }
+        assert_eq!(estimator.decode_p99(), Some(99));
+
+        // only the most recent decoding times are kept
+        let mut estimator = fed(1000, 1000, NB_SAMPLES);
+        assert_eq!(estimator.decode_p99(), Some(1000));
+        for _ in 0..NB_SAMPLES {
+            estimator.record_decode(10);
+        }
+        assert_eq!(estimator.decode_p99(), Some(10));
+    }
+
+    #[test]
+    fn load() {
+        let mut estimator = Estimator::new();
+        estimator.record_decode(700);
+        assert_eq!(estimator.load(2), None);
+        estimator.record_interval(500);
+        assert_eq!(estimator.load(2), Some(0.7));
+        assert_eq!(estimator.load(1), Some(1.4));
+        // no worker is counted as one
+        assert_eq!(estimator.load(0), Some(1.4));
+        assert_eq!(fed(700, 0, 1).load(2), None);
+
+        // a burst of slow decodings moves the average gradually
+        let mut estimator = fed(400, 500, 100);
+        estimator.record_decode(2000);
+        assert_eq!(estimator.load(2), Some(0.5));
+    }
+
+    #[test]
+    fn alert_threshold() {
+        let capacity = DecodeCapacity::new(0.7, 2);
+        // 2 workers for blocks arriving every 500 us: 1000 us to decode each
+        assert!(!capacity.check(&fed(699, 500, 10)));
+        assert!(capacity.check(&fed(700, 500, 10)));
+        assert!(capacity.check(&fed(1500, 500, 10)));
+        assert!(!capacity.check(&fed(700, 1000, 10)));
+        assert!(!capacity.check(&Estimator::new()));
+
+        // a single worker halves the capacity
+        assert!(DecodeCapacity::new(0.7, 1).check(&fed(350, 500, 10)));
+        // a threshold of 0 disables the warning
+        assert!(!DecodeCapacity::new(0.0, 2).check(&fed(1500, 500, 10)));
+    }
+}
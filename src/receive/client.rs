@@ -1,68 +1,128 @@
 //! Worker that writes decoded and reordered messages to client
+//!
+//! In store and forward mode, the messages of the session are held until its end, the
+//! connection to the destination being only opened once the session completed.
 
-use crate::{protocol, receive, sock_utils};
+use crate::{
+    audit,
+    aux::file::hash,
+    events, metrics, protocol, receive,
+    receive::{header, store, trailer},
+    scrub, sock_utils,
+};
 use std::{
     io::{self, Write},
     os::fd::AsRawFd,
+    time,
 };
 
 pub(crate) fn start<C, F, E>(
     receiver: &receive::Receiver<F>,
     client_id: protocol::ClientId,
-    recvq: &crossbeam_channel::Receiver<protocol::Message>,
+    site_id: Option<String>,
+    recvq: &crossbeam_channel::Receiver<(u64, protocol::Message)>,
 ) -> Result<(), receive::Error>
 where
     C: Write + AsRawFd,
-    F: Send + Sync + Fn() -> Result<C, E>,
+    F: Send + Sync + Fn(protocol::Channel) -> Result<C, E>,
     E: Into<receive::Error>,
 {
-    log::info!("client {client_id:x}: starting transfer");
+    match &site_id {
+        Some(site_id) => log::info!("client {client_id:x}: starting transfer from site {site_id}"),
+        None => log::info!("client {client_id:x}: starting transfer"),
+    }
 
-    let client = (receiver.new_client)().map_err(Into::into)?;
+    receiver.events.emit(events::Event::SessionStart {
+        client_id,
+        site_id: site_id.clone(),
+    });
 
-    let sock_buffer_size = sock_utils::get_socket_send_buffer_size(&client)?;
-    if (sock_buffer_size as usize) < 2 * receiver.to_buffer_size {
-        sock_utils::set_socket_send_buffer_size(&client, receiver.to_buffer_size as i32)?;
-        let new_sock_buffer_size = sock_utils::get_socket_send_buffer_size(&client)?;
-        log::debug!(
-            "client socket send buffer size set to {}",
-            new_sock_buffer_size
-        );
-        if (new_sock_buffer_size as usize) < 2 * receiver.to_buffer_size {
-            log::warn!(
-                "client socket send buffer may be too small to achieve optimal performances"
-            );
-            log::warn!("Please review the kernel parameters using sysctl");
+    let labels = [("site", site_id.as_deref().unwrap_or(""))];
+    metrics::counter(&metrics::labeled("rx_sessions_started", &labels)).inc();
+
+    let mut record = audit::Record::new();
+    record.session = Some(format!("{client_id:x}"));
+    record.site.clone_from(&site_id);
+
+    let res = deliver(receiver, client_id, recvq, &labels, &mut record);
+
+    if let Some(audit) = &receiver.audit {
+        if let Err(e) = &res {
+            record.error = Some(e.to_string());
         }
+        audit.write(&record);
     }
 
-    let mut client = io::BufWriter::with_capacity(receiver.to_buffer_size, client);
+    res
+}
 
-    let mut transmitted = 0;
+/// Delivers the messages of the session to its destination, accounting for them in `record`
+fn deliver<C, F, E>(
+    receiver: &receive::Receiver<F>,
+    client_id: protocol::ClientId,
+    recvq: &crossbeam_channel::Receiver<(u64, protocol::Message)>,
+    labels: &[(&str, &str)],
+    record: &mut audit::Record,
+) -> Result<(), receive::Error>
+where
+    C: Write + AsRawFd,
+    F: Send + Sync + Fn(protocol::Channel) -> Result<C, E>,
+    E: Into<receive::Error>,
+{
+    let store = match &receiver.config.store_and_forward {
+        None => None,
+        Some(mode) => match hold(receiver, client_id, recvq, mode)? {
+            Some(store) => Some(store),
+            None => {
+                record.outcome = audit::Outcome::Aborted;
+                ended(receiver, client_id, labels, false, 0, None);
+                return Ok(());
+            }
+        },
+    };
+
+    let mut output = Output {
+        receiver,
+        client_id,
+        client: connect(receiver, client_id)?,
+        record,
+        hasher: receiver.config.session_trailer.map(hash::Hasher::new),
+    };
+    output.start()?;
+
+    if let Some(store) = store {
+        let held = store.held();
+        store.replay(|block_seq, payload| output.write(block_seq, payload))?;
+        let transmitted = output.finish(trailer::Status::Completed)?;
+        log::info!(
+            "client {client_id:x}: finished transfer, {transmitted} bytes transmitted after holding them for {} ms",
+            held.as_millis()
+        );
+        ended(receiver, client_id, labels, true, transmitted, Some(held));
+        return Ok(());
+    }
 
     loop {
-        match recvq.recv_timeout(receiver.config.flush_timeout) {
-            Err(crossbeam_channel::RecvTimeoutError::Timeout) => client.flush()?,
+        match recvq.recv_timeout(receiver.tunables.flush_timeout()) {
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => output.client.flush()?,
             Err(e) => return Err(receive::Error::from(e)),
-            Ok(message) => {
+            Ok((block_seq, message)) => {
                 let message_type = message.message_type()?;
 
-                let payload = message.payload();
-
-                if !payload.is_empty() {
-                    log::trace!("client {client_id:x}: payload {} bytes", payload.len());
-                    transmitted += payload.len();
-                    client.write_all(payload)?;
-                }
+                output.write(block_seq, message.payload())?;
+                receiver.buffers.blocks.release(message.into_buffer());
 
                 match message_type {
                     protocol::MessageType::Abort => {
                         log::warn!("client {client_id:x}: aborting transfer");
+                        let transmitted = output.finish(trailer::Status::Aborted)?;
+                        ended(receiver, client_id, labels, false, transmitted, None);
                         return Ok(());
                     }
                     protocol::MessageType::End => {
+                        let transmitted = output.finish(trailer::Status::Completed)?;
                         log::info!("client {client_id:x}: finished transfer, {transmitted} bytes transmitted");
-                        client.flush()?;
+                        ended(receiver, client_id, labels, true, transmitted, None);
                         return Ok(());
                     }
                     _ => (),
@@ -71,3 +131,345 @@ where
         }
     }
 }
+
+/// Holds the data of the session in a [store::Store] until its end, returning `None` if the
+/// session was aborted or its data could not be held
+fn hold<F>(
+    receiver: &receive::Receiver<F>,
+    client_id: protocol::ClientId,
+    recvq: &crossbeam_channel::Receiver<(u64, protocol::Message)>,
+    mode: &receive::StoreAndForward,
+) -> Result<Option<store::Store>, receive::Error> {
+    let max_bytes = receiver.config.store_and_forward_max_bytes;
+    let mut store = match store::Store::new(mode, max_bytes, client_id) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            log::error!("client {client_id:x}: cannot hold session data, discarding it: {e}");
+            None
+        }
+    };
+
+    // the remaining messages of a discarded session are still received until its end
+    loop {
+        let (block_seq, message) = recvq.recv()?;
+        let message_type = message.message_type()?;
+
+        let payload = message.payload();
+        if let Some(held) = &mut store {
+            let res = if held.fits(payload.len()) {
+                held.push(block_seq, payload)
+            } else {
+                Err(io::Error::other(format!(
+                    "session exceeds {max_bytes} bytes"
+                )))
+            };
+            if let Err(e) = res {
+                log::error!("client {client_id:x}: cannot hold session data, discarding it: {e}");
+                store = None;
+            }
+        }
+        receiver.buffers.blocks.release(message.into_buffer());
+
+        match message_type {
+            protocol::MessageType::Abort => {
+                let len = store.as_ref().map_or(0, store::Store::len);
+                match (&receiver.quarantine, store) {
+                    (Some(quarantine), Some(store)) => {
+                        log::warn!(
+                            "client {client_id:x}: aborting transfer, quarantining {len} bytes held"
+                        );
+                        quarantine.record_session(client_id, store);
+                    }
+                    _ => log::warn!(
+                        "client {client_id:x}: aborting transfer, discarding {len} bytes held"
+                    ),
+                }
+                return Ok(None);
+            }
+            protocol::MessageType::End => return Ok(store),
+            _ => (),
+        }
+    }
+}
+
+/// Opens a connection to the destination for the session
+fn connect<C, F, E>(
+    receiver: &receive::Receiver<F>,
+    client_id: protocol::ClientId,
+) -> Result<io::BufWriter<C>, receive::Error>
+where
+    C: Write + AsRawFd,
+    F: Send + Sync + Fn(protocol::Channel) -> Result<C, E>,
+    E: Into<receive::Error>,
+{
+    let client = match (receiver.new_client)(protocol::channel(client_id)).map_err(Into::into) {
+        Ok(client) => {
+            let address = sock_utils::get_peer_addr(&client);
+            if let Some(address) = address {
+                log::info!(
+                    "client {client_id:x}: delivering to {}",
+                    scrub::addr(&address)
+                );
+            }
+            receiver.events.emit(events::Event::DestinationConnected {
+                client_id,
+                address: address.map(|address| scrub::addr(&address).to_string()),
+            });
+            client
+        }
+        Err(e) => {
+            receiver.events.emit(events::Event::DestinationFailed {
+                client_id,
+                error: e.to_string(),
+            });
+            return Err(e);
+        }
+    };
+
+    // destinations which are not sockets, such as pipes, keep their buffer size
+    let sock_buffer_size = sock_utils::get_socket_send_buffer_size(&client).ok();
+    if sock_buffer_size.is_some_and(|size| (size as usize) < 2 * receiver.to_buffer_size) {
+        sock_utils::set_socket_send_buffer_size(&client, receiver.to_buffer_size as i32)?;
+        let new_sock_buffer_size = sock_utils::get_socket_send_buffer_size(&client)?;
+        log::debug!(
+            "client socket send buffer size set to {}",
+            new_sock_buffer_size
+        );
+        if (new_sock_buffer_size as usize) < 2 * receiver.to_buffer_size {
+            log::warn!(
+                "client socket send buffer may be too small to achieve optimal performances"
+            );
+            log::warn!("Please review the kernel parameters using sysctl");
+        }
+    }
+
+    Ok(io::BufWriter::with_capacity(
+        receiver.to_buffer_size,
+        client,
+    ))
+}
+
+/// Destination of the session, with the accounting of the data written to it in the audit
+/// record of the session
+struct Output<'a, C: Write, F> {
+    receiver: &'a receive::Receiver<F>,
+    client_id: protocol::ClientId,
+    client: io::BufWriter<C>,
+    record: &'a mut audit::Record,
+    hasher: Option<hash::Hasher>,
+}
+
+impl<C: Write, F> Output<'_, C, F> {
+    /// Starts the output with the session header, if enabled
+    fn start(&mut self) -> Result<(), receive::Error> {
+        if self.receiver.config.session_header {
+            let header = header::Header {
+                client_id: self.client_id,
+                site_id: self.record.site.clone(),
+            }
+            .serialize();
+            self.client.write_all(&header)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the payload of the block `block_seq`
+    fn write(&mut self, block_seq: u64, payload: &[u8]) -> Result<(), receive::Error> {
+        if payload.is_empty() {
+            return Ok(());
+        }
+        log::trace!(
+            "client {:x}: payload {} bytes",
+            self.client_id,
+            payload.len()
+        );
+        self.client.write_all(payload)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(payload);
+        }
+        if let Some(index) = &self.receiver.index {
+            index.record(self.client_id, block_seq, self.record.bytes, payload);
+        }
+        self.record.bytes += payload.len() as u64;
+        if self.receiver.config.low_latency {
+            self.client.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the output with the session trailer, if enabled, returning the number of bytes of
+    /// session data written
+    fn finish(mut self, status: trailer::Status) -> Result<u64, receive::Error> {
+        if let Some(hasher) = self.hasher.take() {
+            let algorithm = self
+                .receiver
+                .config
+                .session_trailer
+                .unwrap_or(hash::Algorithm::None);
+            let digest = hasher.finalize();
+            let trailer = trailer::Trailer {
+                client_id: self.client_id,
+                bytes: self.record.bytes,
+                status,
+                algorithm,
+                digest: digest.clone(),
+            }
+            .serialize();
+            self.client.write_all(&trailer)?;
+            self.record.hash = Some((algorithm, digest));
+        }
+        self.client.flush()?;
+        self.record.outcome = match status {
+            trailer::Status::Completed => audit::Outcome::Success,
+            trailer::Status::Aborted => audit::Outcome::Aborted,
+        };
+        Ok(self.record.bytes)
+    }
+}
+
+/// Accounts for the end of the session, `bytes` having been delivered to the destination
+fn ended<F>(
+    receiver: &receive::Receiver<F>,
+    client_id: protocol::ClientId,
+    labels: &[(&str, &str)],
+    completed: bool,
+    bytes: u64,
+    held: Option<time::Duration>,
+) {
+    let name = if completed {
+        "rx_sessions_completed"
+    } else {
+        "rx_sessions_aborted"
+    };
+    metrics::counter(&metrics::labeled(name, labels)).inc();
+    metrics::counter(&metrics::labeled("rx_bytes", labels)).add(bytes);
+    if let Some(index) = &receiver.index {
+        index.record_end(client_id, completed, bytes);
+    }
+    receiver.events.emit(if completed {
+        events::Event::SessionEnd {
+            client_id,
+            bytes,
+            held,
+        }
+    } else {
+        events::Event::SessionAbort { client_id, bytes }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aux::file::hash,
+        durable, metrics,
+        receive::{
+            header,
+            trailer::{self, Trailer},
+        },
+        send::scan::MaxBytesPerSession,
+        testing::{self, Diode, TempDir},
+    };
+    use std::{io::Write, thread, time};
+
+    #[test]
+    fn site_id_propagation() {
+        let audit = TempDir::new("site-audit");
+        let audit_log = audit.path().join("audit.log");
+        let audit_path = audit_log.clone();
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |config| config.site_id = Some("site-2460".to_string()),
+            move |config| {
+                config.session_header = true;
+                config.audit_log = Some(audit_path);
+            },
+        );
+
+        diode.send(b"data from site 2460");
+        let data = diode
+            .receive(time::Duration::from_secs(10))
+            .expect("session delivered");
+
+        let (session_header, data) = data.split_at(header::SIZE);
+        let session_header =
+            header::Header::deserialize(session_header.try_into().expect("header size"))
+                .expect("valid session header");
+        assert_eq!(session_header.site_id.as_deref(), Some("site-2460"));
+        assert_eq!(data, b"data from site 2460");
+
+        let labels = [("site", "site-2460")];
+        for name in ["rx_sessions_started", "rx_sessions_completed"] {
+            assert_eq!(
+                metrics::counter(&metrics::labeled(name, &labels)).get(),
+                1,
+                "{name}"
+            );
+        }
+
+        // the audit record is written once the session ended
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        let record = loop {
+            let records = durable::replay(&audit_log).map(|replay| replay.records);
+            if let Some(record) = records.unwrap_or_default().pop() {
+                break String::from_utf8(record).expect("JSON record");
+            }
+            assert!(time::Instant::now() < deadline, "no audit record");
+            thread::sleep(time::Duration::from_millis(10));
+        };
+        assert!(record.contains("\"site\":\"site-2460\""), "{record}");
+    }
+
+    /// Splits the output of a session into its data and its trailer
+    fn trailed(output: &[u8]) -> (&[u8], Trailer) {
+        let (data, trailer) = output.split_at(output.len() - trailer::SIZE);
+        let trailer =
+            Trailer::deserialize(trailer.try_into().expect("trailer size")).expect("valid trailer");
+        (data, trailer)
+    }
+
+    #[test]
+    fn session_trailer() {
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |config| config.scan_policies = vec![Box::new(MaxBytesPerSession::new(100_000))],
+            |config| config.session_trailer = Some(hash::Algorithm::Blake3),
+        );
+        let digest = |data: &[u8]| {
+            let mut hasher = hash::Hasher::new(hash::Algorithm::Blake3);
+            hasher.update(data);
+            hasher.finalize()
+        };
+
+        // completed sessions, empty ones not being sent
+        for len in [50_000, 1] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 249) as u8).collect();
+            diode.send(&data);
+            let output = diode
+                .receive(time::Duration::from_secs(10))
+                .unwrap_or_else(|| panic!("session of {len} bytes delivered"));
+            let (received, trailer) = trailed(&output);
+            assert!(received == data, "{} bytes received", received.len());
+            assert_eq!(trailer.status, trailer::Status::Completed);
+            assert_eq!(trailer.bytes, len);
+            assert!(trailer.algorithm == hash::Algorithm::Blake3);
+            assert_eq!(trailer.digest, digest(&data));
+        }
+
+        // aborted by the sender once the data of the session exceeds the scan policy limit, the
+        // data of the first block being delivered
+        let mut client = diode.connect();
+        client.write_all(&[1; 60_000]).expect("send data");
+        thread::sleep(time::Duration::from_millis(300));
+        client.write_all(&[2; 60_000]).expect("send data");
+        drop(client);
+        let output = diode
+            .receive(time::Duration::from_secs(10))
+            .expect("session delivered");
+        let (received, trailer) = trailed(&output);
+        assert_eq!(trailer.status, trailer::Status::Aborted);
+        assert_eq!(trailer.bytes, received.len() as u64);
+        assert!(received.len() < 120_000);
+        assert!(received.iter().take(60_000).all(|&byte| byte == 1));
+        assert_eq!(trailer.digest, digest(received));
+    }
+}
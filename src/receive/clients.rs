@@ -1,22 +1,22 @@
 //! Worker that acquires multiplex access and then becomes a `crate::receive::client` worker
 
-use crate::{receive, receive::client};
+use crate::{protocol, receive, receive::client};
 use std::{io::Write, os::fd::AsRawFd};
 
 pub(crate) fn start<C, F, E>(receiver: &receive::Receiver<F>) -> Result<(), receive::Error>
 where
     C: Write + AsRawFd,
-    F: Send + Sync + Fn() -> Result<C, E>,
+    F: Send + Sync + Fn(protocol::Channel) -> Result<C, E>,
     E: Into<receive::Error>,
 {
     loop {
-        let (client_id, recvq) = receiver.for_clients.recv()?;
+        let (client_id, site_id, recvq) = receiver.for_clients.recv()?;
 
         log::debug!("try to acquire multiplex access..");
         receiver.multiplex_control.acquire();
         log::debug!("multiplex access acquired");
 
-        let client_res = client::start(receiver, client_id, &recvq);
+        let client_res = client::start(receiver, client_id, site_id, &recvq);
 
         receiver.multiplex_control.release();
 
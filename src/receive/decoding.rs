@@ -1,13 +1,23 @@
-//! Worker that decodes RaptorQ packets into protocol messages
+//! Worker that decodes packets into protocol messages with the configured [crate::fec] code
+//!
+//! With `integrity`, the checksum ending each decoded block is verified before the message is
+//! read from it, blocks failing the check being handled as if they could not be decoded.
 
-use crate::{protocol, receive};
+use crate::{events, fec, metrics, protocol, receive, receive::watchdog};
+use std::time;
 
 pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::Error> {
-    let encoding_block_size = receiver.object_transmission_info.transfer_length();
+    let fec = fec::new(receiver.config.fec, &receiver.object_transmission_info);
+    let max_decompressed_len = receiver.to_buffer_size * protocol::MAX_COMPRESSION_RATIO as usize;
+    let compressed_blocks = metrics::counter("rx_blocks_compressed");
+    let corrupted_blocks = metrics::counter("rx_blocks_corrupted");
+    let symbol_size = usize::from(receiver.object_transmission_info.symbol_size());
 
     loop {
         let (block_id, packets) = receiver.for_decoding.recv()?;
 
+        watchdog::Progress::inc(&receiver.progress.decoding);
+
         let packets = match packets {
             None => {
                 log::warn!("synchronization lost received, propagating");
@@ -18,29 +28,205 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
             Some(packets) => packets,
         };
 
+        let (packets, compressed) = protocol::strip_compression_flag(packets);
+
         log::trace!(
             "trying to decode block {block_id} with {} packets",
             packets.len()
         );
 
-        let mut decoder = raptorq::SourceBlockDecoder::new(
-            block_id,
-            &receiver.object_transmission_info,
-            encoding_block_size,
-        );
+        // keeping the packets only when they may have to be quarantined
+        let quarantined = receiver.quarantine.as_ref().map(|_| packets.clone());
 
-        match decoder.decode(packets) {
+        // a heartbeat which cannot be decoded may still tell why, see [receive::heartbeat]
+        let heartbeat = protocol::heartbeat_in_packets(&packets).map(<[u8]>::to_vec);
+
+        // packets of another size come from a sender with another MTU, and cannot be decoded
+        let decoded = match packets
+            .iter()
+            .find(|packet| packet.data().len() != symbol_size)
+        {
+            Some(packet) => {
+                log::error!(
+                    "block {block_id} has packets of {} bytes instead of {symbol_size}, the MTU or the header format of the sender differs",
+                    packet.data().len()
+                );
+                receiver.buffers.packets.release_packets(packets);
+                None
+            }
             None => {
-                log::error!("lost block {block_id}, synchronization lost");
-                // Sending lost synchronization signal to reorder thread
-                receiver.to_reordering.send((block_id, None))?;
+                let start = time::Instant::now();
+                let decoded = fec.decode(block_id, packets, Some(&receiver.buffers));
+                receiver.decode_capacity.record_decode(start.elapsed());
+                decoded
+            }
+        };
+
+        let Some(block) = decoded else {
+            log::error!("lost block {block_id}, synchronization lost");
+            if let Some(payload) = heartbeat {
+                undecoded_heartbeat(receiver, &payload);
             }
-            Some(block) => {
-                log::trace!("block {block_id} decoded with {} bytes!", block.len());
+            if let (Some(quarantine), Some(packets)) = (&receiver.quarantine, quarantined) {
+                quarantine.record(block_id, packets);
+            }
+            receiver
+                .events
+                .emit(events::Event::DecodeFailure { block_id });
+            // Sending lost synchronization signal to reorder thread
+            receiver.to_reordering.send((block_id, None))?;
+            continue;
+        };
+
+        log::trace!("block {block_id} decoded with {} bytes!", block.len());
+
+        let message = if receiver.config.integrity {
+            protocol::Message::deserialize_checked(block)
+        } else {
+            Ok(protocol::Message::deserialize(block))
+        };
+
+        let message = match message {
+            Ok(message) if compressed => {
+                compressed_blocks.inc();
+                let decompressed = message.decompress(max_decompressed_len);
+                receiver.buffers.blocks.release(message.into_buffer());
+                decompressed
+            }
+            Ok(message) => Ok(message),
+            Err(e) => {
+                corrupted_blocks.inc();
+                if let Some(payload) = heartbeat {
+                    undecoded_heartbeat(receiver, &payload);
+                }
+                // the packets were decoded into a block which is not the one the sender encoded
+                if let (Some(quarantine), Some(packets)) = (&receiver.quarantine, quarantined) {
+                    quarantine.record(block_id, packets);
+                }
+                Err(e)
+            }
+        };
+
+        match message {
+            Ok(message) => receiver.to_reordering.send((block_id, Some(message)))?,
+            Err(e) => {
+                log::error!("lost block {block_id}, synchronization lost: {e}");
                 receiver
-                    .to_reordering
-                    .send((block_id, Some(protocol::Message::deserialize(block))))?;
+                    .events
+                    .emit(events::Event::DecodeFailure { block_id });
+                receiver.to_reordering.send((block_id, None))?;
             }
         }
     }
 }
+
+/// Reads the sender status from the payload of a heartbeat whose block could not be decoded
+fn undecoded_heartbeat<F>(receiver: &receive::Receiver<F>, payload: &[u8]) {
+    if let Ok((_, Some(status))) = protocol::parse_heartbeat(payload) {
+        receiver
+            .sender_state
+            .lock()
+            .expect("acquire lock")
+            .update(&receiver.config, &status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fec, loopback, protocol, quarantine, receive, testing};
+    use std::{fs, io, net, thread, time};
+
+    const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+    #[test]
+    fn undecodable_block_quarantined() {
+        let dir = testing::TempDir::new("quarantine");
+        let addr = net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 5000));
+        let mut config = loopback::receive_config(&testing::loopback_config(), addr, None);
+        config.quarantine_dir = Some(dir.path().to_path_buf());
+        config.quarantine_max_bytes = 1 << 20;
+        let receiver = receive::Receiver::new(config, |_| -> Result<net::TcpStream, io::Error> {
+            Err(io::Error::other("no destination"))
+        })
+        .unwrap_or_else(|e| panic!("{e}"));
+        // left running until the end of the tests, like the workers of a diode
+        let receiver: &'static _ = Box::leak(Box::new(receiver));
+        thread::spawn(move || super::start(receiver));
+        let quarantine = receiver.quarantine.as_ref().expect("quarantine");
+        thread::spawn(move || quarantine::start(quarantine));
+
+        let oti = receiver.object_transmission_info;
+        let block_id = protocol::BlockId::from(0x0203);
+        let data = vec![0x5a; oti.transfer_length() as usize];
+        let nb_source_symbols = protocol::nb_encoding_packets(&oti) as u32;
+        let lost = |id: u32| id < nb_source_symbols && id.is_multiple_of(3);
+        let packets = fec::new(receiver.config.fec, &oti)
+            .encode(block_id, &data, 1)
+            .into_iter()
+            .filter(|packet| !lost(protocol::Header::from(packet.payload_id()).symbol_id))
+            .collect::<Vec<_>>();
+        let nb_packets = packets.len();
+        receiver
+            .to_decoding
+            .send((block_id, Some(packets)))
+            .expect("decoding");
+
+        let (lost_id, message) = receiver
+            .for_reordering
+            .recv_timeout(TIMEOUT)
+            .expect("decoding result");
+        assert_eq!(lost_id, block_id);
+        assert!(message.is_none());
+
+        let deadline = time::Instant::now() + TIMEOUT;
+        let path = loop {
+            let file = fs::read_dir(dir.path())
+                .expect("read quarantine dir")
+                .map(|entry| entry.expect("entry").path())
+                .find(|path| {
+                    path.extension()
+                        .is_some_and(|ext| ext == quarantine::EXTENSION)
+                });
+            match file {
+                Some(path) => break path,
+                None if time::Instant::now() < deadline => {
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                None => panic!("no quarantine file"),
+            }
+        };
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .expect("name");
+        assert!(name.ends_with(&format!("-block{block_id}.lidiq")), "{name}");
+
+        // the file may be seen while still being written
+        let block = loop {
+            match quarantine::Block::read(&path) {
+                Ok(block) => break block,
+                Err(quarantine::Error::Truncated) if time::Instant::now() < deadline => {
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                Err(e) => panic!("{e}"),
+            }
+        };
+        assert_eq!(block.block_id, block_id);
+        assert_eq!(block.packets.len(), nb_packets);
+
+        let report = block.analyze();
+        let missing = (0..nb_source_symbols)
+            .filter(|&id| lost(id))
+            .collect::<Vec<_>>();
+        assert_eq!(report.missing_source_ids, missing);
+        assert_eq!(report.nb_repair_packets, 1);
+        assert!(!report.decoded);
+        let expected = missing
+            .iter()
+            .map(|id| format!(" {id}"))
+            .collect::<String>();
+        assert!(report
+            .to_string()
+            .contains(&format!("missing source symbols:{expected}\n")));
+    }
+}
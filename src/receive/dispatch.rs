@@ -1,34 +1,113 @@
 //! Worker that manages active transfers queue and dispatch incoming [crate::protocol]
 //! messages to clients
+//!
+//! The messages of each session are queued for its client worker in a queue of
+//! [receive::Config::queue_size] blocks. When the destination of a session is slow and its queue is
+//! full, the worker waits for it, slowing down the whole pipeline up to the udp workers which then
+//! drop datagrams. A session whose queue stays full for [receive::RESYNC_FLUSH_TIMEOUTS] flush
+//! timeouts, for instance because no client worker is available for it, is failed.
 
-use crate::{protocol, receive};
+use crate::{events, metrics, protocol, receive, receive::watchdog};
 use std::{
     collections::{BTreeMap, BTreeSet},
-    time,
+    sync, time,
 };
 
+/// Transfer in progress, with the metrics of its session
+struct Transfer {
+    sendq: crossbeam_channel::Sender<(u64, protocol::Message)>,
+    metrics: SessionMetrics,
+}
+
+/// Metrics of a session, labeled with its identifier and the site of the sender, and removed
+/// once the session ended
+struct SessionMetrics {
+    client_id: protocol::ClientId,
+    _scope: metrics::Scoped,
+    blocks: sync::Arc<metrics::Metric>,
+    bytes: sync::Arc<metrics::Metric>,
+    missing_packets: sync::Arc<metrics::Metric>,
+    first_block: sync::Arc<metrics::Metric>,
+    last_block: sync::Arc<metrics::Metric>,
+}
+
+impl SessionMetrics {
+    fn new(client_id: protocol::ClientId, site_id: Option<&str>, block_seq: u64) -> Self {
+        let session = format!("{client_id:x}");
+        let mut scope =
+            metrics::Scoped::new(&[("session", &session), ("site", site_id.unwrap_or(""))]);
+        let metrics = Self {
+            client_id,
+            blocks: scope.counter("rx_session_blocks"),
+            bytes: scope.counter("rx_session_bytes"),
+            missing_packets: scope.counter("rx_session_packets_missing"),
+            first_block: scope.gauge("rx_session_first_block"),
+            last_block: scope.gauge("rx_session_last_block"),
+            _scope: scope,
+        };
+        metrics.first_block.set(block_seq);
+        metrics
+    }
+
+    /// Accounts for a block of the session, of sequence number `block_seq`, from which
+    /// `nb_missing` packets were missing
+    fn record(&self, block_seq: u64, message: &protocol::Message, nb_missing: u32) {
+        self.blocks.inc();
+        self.bytes.add(message.payload().len() as u64);
+        self.missing_packets.add(u64::from(nb_missing));
+        self.last_block.set(block_seq);
+    }
+}
+
+impl Drop for SessionMetrics {
+    fn drop(&mut self) {
+        let nb_missing = self.missing_packets.get();
+        if 0 < nb_missing {
+            log::info!(
+                "client {:x}: {nb_missing} packet(s) missing from blocks {} to {} of the session",
+                self.client_id,
+                self.first_block.get(),
+                self.last_block.get()
+            );
+        }
+    }
+}
+
 pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::Error> {
-    let mut active_transfers: BTreeMap<
-        protocol::ClientId,
-        crossbeam_channel::Sender<protocol::Message>,
-    > = BTreeMap::new();
+    let mut active_transfers: BTreeMap<protocol::ClientId, Transfer> = BTreeMap::new();
     let mut ended_transfers: BTreeMap<
         protocol::ClientId,
-        crossbeam_channel::Sender<protocol::Message>,
+        crossbeam_channel::Sender<(u64, protocol::Message)>,
     > = BTreeMap::new();
     let mut failed_transfers: BTreeSet<protocol::ClientId> = BTreeSet::new();
 
     let mut last_heartbeat = time::Instant::now();
+    let mut link_down = false;
+
+    // sequence number of delivered blocks, not wrapping contrary to protocol block ids
+    let mut block_seq: u64 = 0;
+
+    let slow_destinations = metrics::counter("rx_sessions_slow_destination");
 
     loop {
+        let queue_timeout = receiver.tunables.flush_timeout() * receive::RESYNC_FLUSH_TIMEOUTS;
         let message = if let Some(hb_interval) = receiver.config.heartbeat_interval {
             match receiver.for_dispatch.recv_timeout(hb_interval) {
                 Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
-                    if last_heartbeat.elapsed() > hb_interval {
+                    let paused = receiver
+                        .sender_state
+                        .lock()
+                        .expect("acquire lock")
+                        .is_paused();
+                    if last_heartbeat.elapsed() > hb_interval && !paused {
                         log::warn!(
                             "no heartbeat message received during the last {} second(s)",
                             hb_interval.as_secs()
                         );
+                        if !link_down {
+                            link_down = true;
+                            receiver.events.emit(events::Event::LinkDown);
+                        }
                     }
                     continue;
                 }
@@ -38,12 +117,24 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
             receiver.for_dispatch.recv()?
         };
 
-        let message = match message {
-            Some(m) => m,
+        watchdog::Progress::inc(&receiver.progress.dispatch);
+
+        let (message, nb_missing) = match message {
+            Some((block_id, m)) => {
+                block_seq += 1;
+                (m, receiver.missing_packets.take(block_id))
+            }
             None => {
                 // Synchonization has been lost
                 // Marking all active transfers as failed
-                for (client_id, client_sendq) in active_transfers {
+                for (
+                    client_id,
+                    Transfer {
+                        sendq: client_sendq,
+                        ..
+                    },
+                ) in active_transfers
+                {
                     let message = protocol::Message::new(
                         protocol::MessageType::Abort,
                         receiver.to_buffer_size as u32,
@@ -51,7 +142,7 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
                         None,
                     );
 
-                    if let Err(e) = client_sendq.send(message) {
+                    if let Err(e) = client_sendq.send_timeout((block_seq, message), queue_timeout) {
                         log::error!("failed to send payload to client {client_id:x}: {e}");
                     }
 
@@ -78,21 +169,79 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
             Ok(mt) => mt,
         };
 
+        if let Err(e) = message.check_length() {
+            log::error!("client {client_id:x}: dropping inconsistent {message_type} message: {e}");
+            metrics::counter("rx_messages_inconsistent").inc();
+            if matches!(message_type, protocol::MessageType::Heartbeat) {
+                continue;
+            }
+            // failing the session rather than delivering data which was not sent
+            if let Some(Transfer {
+                sendq: client_sendq,
+                ..
+            }) = active_transfers.remove(&client_id)
+            {
+                let message = protocol::Message::new(
+                    protocol::MessageType::Abort,
+                    receiver.to_buffer_size as u32,
+                    client_id,
+                    None,
+                );
+                if let Err(e) = client_sendq.send_timeout((block_seq, message), queue_timeout) {
+                    log::error!("failed to send payload to client {client_id:x}: {e}");
+                }
+            }
+            failed_transfers.insert(client_id);
+            continue;
+        }
+
         let mut will_end = false;
 
         match message_type {
             protocol::MessageType::Heartbeat => {
                 last_heartbeat = time::Instant::now();
+                match protocol::parse_heartbeat(message.payload()) {
+                    Err(e) => log::warn!("ignoring heartbeat: {e}"),
+                    Ok((site_id, status)) => {
+                        update_site_id(receiver, site_id);
+                        if let Some(status) = status {
+                            receiver
+                                .sender_state
+                                .lock()
+                                .expect("acquire lock")
+                                .update(&receiver.config, &status);
+                        }
+                    }
+                }
+                if link_down {
+                    link_down = false;
+                    receiver.events.emit(events::Event::LinkUp);
+                }
                 continue;
             }
 
-            protocol::MessageType::Start => {
-                let (client_sendq, client_recvq) =
-                    crossbeam_channel::unbounded::<protocol::Message>();
+            protocol::MessageType::Start | protocol::MessageType::StartSite => {
+                let (client_sendq, client_recvq) = crossbeam_channel::bounded::<(
+                    u64,
+                    protocol::Message,
+                )>(receiver.config.queue_size);
 
-                active_transfers.insert(client_id, client_sendq);
+                // senders not carrying it in the session may still carry it in heartbeats
+                let site_id = message
+                    .site_id()
+                    .map(str::to_string)
+                    .or_else(|| receiver.site_id.read().expect("acquire lock").clone());
+                active_transfers.insert(
+                    client_id,
+                    Transfer {
+                        sendq: client_sendq,
+                        metrics: SessionMetrics::new(client_id, site_id.as_deref(), block_seq),
+                    },
+                );
 
-                receiver.to_clients.send((client_id, client_recvq))?;
+                receiver
+                    .to_clients
+                    .send((client_id, site_id, client_recvq))?;
             }
 
             protocol::MessageType::Abort | protocol::MessageType::End => will_end = true,
@@ -105,16 +254,34 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
                 log::error!("receive data for inactive transfer {client_id:x}");
                 failed_transfers.insert(client_id);
             }
-            Some(client_sendq) => {
-                if let Err(e) = client_sendq.send(message) {
-                    log::error!("failed to send payload to client {client_id:x}: {e}");
+            Some(transfer) => {
+                transfer.metrics.record(block_seq, &message, nb_missing);
+                if let Err(e) = transfer
+                    .sendq
+                    .send_timeout((block_seq, message), queue_timeout)
+                {
+                    match e {
+                        crossbeam_channel::SendTimeoutError::Timeout(_) => {
+                            slow_destinations.inc();
+                            log::error!(
+                                "client {client_id:x}: destination did not read data for {} ms, failing transfer",
+                                queue_timeout.as_millis()
+                            );
+                        }
+                        crossbeam_channel::SendTimeoutError::Disconnected(_) => {
+                            log::error!("failed to send payload to client {client_id:x}: {e}");
+                        }
+                    }
                     active_transfers.remove(&client_id);
                     failed_transfers.insert(client_id);
                     continue;
                 }
 
                 if will_end {
-                    let client_sendq = active_transfers
+                    let Transfer {
+                        sendq: client_sendq,
+                        ..
+                    } = active_transfers
                         .remove(&client_id)
                         .expect("active transfer");
 
@@ -132,3 +299,116 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
         }
     }
 }
+
+fn update_site_id<F>(receiver: &receive::Receiver<F>, site_id: Option<String>) {
+    let mut current = receiver.site_id.write().expect("acquire lock");
+    if *current != site_id {
+        match &site_id {
+            Some(site_id) => log::info!("sender site identifier is \"{site_id}\""),
+            None => log::info!("sender does not provide a site identifier anymore"),
+        }
+        *current = site_id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{loopback, metrics, protocol, receive, testing};
+    use std::{io, net, thread, time};
+
+    const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+    /// Message of `message_type` whose data length is overwritten with `declared_len`
+    fn crafted(
+        message_type: protocol::MessageType,
+        client_id: protocol::ClientId,
+        data: Option<&[u8]>,
+        declared_len: u32,
+    ) -> protocol::Message {
+        let mut content = protocol::Message::new(message_type, 100, client_id, data)
+            .serialized()
+            .to_vec();
+        content[5..9].copy_from_slice(&declared_len.to_le_bytes());
+        protocol::Message::deserialize(content)
+    }
+
+    #[test]
+    fn inconsistent_end_blocks() {
+        let addr = net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 5000));
+        let config = loopback::receive_config(&testing::loopback_config(), addr, None);
+        let receiver = receive::Receiver::new(config, |_| -> Result<net::TcpStream, io::Error> {
+            Err(io::Error::other("no destination"))
+        })
+        .unwrap_or_else(|e| panic!("{e}"));
+        // left running until the end of the tests, like the workers of a diode
+        let receiver: &'static _ = Box::leak(Box::new(receiver));
+        thread::spawn(move || super::start(receiver));
+
+        let inconsistent = metrics::counter("rx_messages_inconsistent");
+        let mut block_id = protocol::BlockId::default();
+        let mut dispatch = |message: protocol::Message| {
+            receiver
+                .to_dispatch
+                .send(Some((block_id, message)))
+                .expect("dispatch");
+            block_id = block_id.next();
+        };
+
+        // End blocks declaring data, or more data than the payload, or followed by non zero
+        // padding, fail their session
+        let data = [0x5a; 50];
+        for (client_id, end) in [
+            (1, crafted(protocol::MessageType::End, 1, None, 10)),
+            (2, crafted(protocol::MessageType::End, 2, None, 101)),
+            (3, crafted(protocol::MessageType::End, 3, Some(&data), 0)),
+            (4, crafted(protocol::MessageType::Data, 4, Some(&data), 101)),
+            (5, crafted(protocol::MessageType::Data, 5, Some(&data), 40)),
+        ] {
+            let nb_inconsistent = inconsistent.get();
+            dispatch(protocol::Message::new(
+                protocol::MessageType::Start,
+                100,
+                client_id,
+                Some(b"start"),
+            ));
+            let (started, _, messages) = receiver.for_clients.recv_timeout(TIMEOUT).expect("start");
+            assert_eq!(started, client_id);
+            let (_, start) = messages.recv_timeout(TIMEOUT).expect("start");
+            assert_eq!(start.payload(), b"start");
+
+            dispatch(end);
+            let (_, aborted) = messages.recv_timeout(TIMEOUT).expect("abort");
+            assert!(
+                matches!(aborted.message_type(), Ok(protocol::MessageType::Abort)),
+                "{client_id}"
+            );
+            assert_eq!(inconsistent.get(), nb_inconsistent + 1, "{client_id}");
+
+            // later blocks of the failed session are dropped
+            dispatch(protocol::Message::new(
+                protocol::MessageType::Data,
+                100,
+                client_id,
+                Some(b"late"),
+            ));
+            assert!(messages
+                .recv_timeout(time::Duration::from_millis(100))
+                .is_err());
+        }
+
+        // a consistent End block ends its session
+        let nb_inconsistent = inconsistent.get();
+        dispatch(protocol::Message::new(
+            protocol::MessageType::Start,
+            100,
+            6,
+            Some(b"start"),
+        ));
+        let (_, _, messages) = receiver.for_clients.recv_timeout(TIMEOUT).expect("start");
+        dispatch(crafted(protocol::MessageType::End, 6, None, 0));
+        let _ = messages.recv_timeout(TIMEOUT).expect("start");
+        let (_, end) = messages.recv_timeout(TIMEOUT).expect("end");
+        assert!(matches!(end.message_type(), Ok(protocol::MessageType::End)));
+        assert_eq!(inconsistent.get(), nb_inconsistent);
+    }
+}
@@ -0,0 +1,106 @@
+//! Header written by the receiver at the beginning of the output of each session
+//!
+//! A destination only seeing a connection cannot tell which sender site the session comes from.
+//! When enabled, the receiver writes a fixed size header before the first data of every session,
+//! with the following representation:
+//!
+//! ```text
+//!
+//! <- 4 bytes -> <- 4 bytes -> <- 1 byte -> <------ 32 bytes ------>
+//! ------------+-------------+------------+-------------------------
+//! |           |             |            |                       |
+//! |  "LDHD"   |  client_id  |  site_len  |        site_id        |
+//! |           |             |            |                       |
+//! ------------+-------------+------------+-------------------------
+//!
+//! ```
+//!
+//! Integers are encoded in little-endian byte order. `site_id` holds the `site_len` bytes of the
+//! site identifier of the sender, padded with zeros, `site_len` being 0 when the sender has none.
+
+use crate::protocol;
+
+pub const MAGIC: [u8; 4] = *b"LDHD";
+
+/// Size in bytes of a serialized header
+pub const SIZE: usize = 4 + 4 + 1 + protocol::MAX_SITE_ID_LEN;
+
+pub struct Header {
+    pub client_id: protocol::ClientId,
+    pub site_id: Option<String>,
+}
+
+impl Header {
+    pub fn serialize(&self) -> [u8; SIZE] {
+        let mut header = [0; SIZE];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&self.client_id.to_le_bytes());
+        let site_id = self.site_id.as_deref().unwrap_or("").as_bytes();
+        let len = site_id.len().min(protocol::MAX_SITE_ID_LEN);
+        header[8] = len as u8;
+        header[9..9 + len].copy_from_slice(&site_id[..len]);
+        header
+    }
+
+    /// Parses the header starting the output of a session, returning `None` if `header` is not a
+    /// valid header
+    pub fn deserialize(header: &[u8; SIZE]) -> Option<Self> {
+        if header[0..4] != MAGIC {
+            return None;
+        }
+        let site_id = header.get(9..9 + usize::from(header[8]))?;
+        let site_id = match site_id {
+            [] => None,
+            site_id => {
+                let site_id = std::str::from_utf8(site_id).ok()?;
+                protocol::check_site_id(site_id).ok()?;
+                Some(site_id.to_string())
+            }
+        };
+        Some(Self {
+            client_id: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+            site_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for site_id in [None, Some("a"), Some("site-01.paris_2")] {
+            let header = Header {
+                client_id: 0x0100_0002,
+                site_id: site_id.map(str::to_string),
+            }
+            .serialize();
+            let header = Header::deserialize(&header).expect("valid header");
+            assert_eq!(header.client_id, 0x0100_0002);
+            assert_eq!(header.site_id.as_deref(), site_id);
+        }
+    }
+
+    #[test]
+    fn invalid_site_id() {
+        let mut header = Header {
+            client_id: 1,
+            site_id: Some("site".to_string()),
+        }
+        .serialize();
+        assert!(Header::deserialize(&header).is_some());
+
+        header[8] = 33;
+        assert!(Header::deserialize(&header).is_none());
+
+        for site_id in [&b"../x"[..], b"a/b", b"s\x1b[31m", b".hidden", b"\xff"] {
+            header[8] = site_id.len() as u8;
+            header[9..9 + site_id.len()].copy_from_slice(site_id);
+            assert!(Header::deserialize(&header).is_none());
+        }
+
+        header[0] = b'X';
+        assert!(Header::deserialize(&header).is_none());
+    }
+}
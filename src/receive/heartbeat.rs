@@ -0,0 +1,85 @@
+//! Status of the sender carried by its heartbeat messages, see [protocol::SenderStatus]
+//!
+//! The status is read from decoded heartbeat messages by the dispatch worker. Since a sender
+//! whose encoding block size or MTU differs from the receiver one produces blocks which cannot
+//! be decoded, the decoding worker also reads it from the first packet of heartbeat blocks
+//! which could not be decoded, the packet carrying the beginning of the message as is.
+//!
+//! A sender pausing its emission announces it in a last heartbeat, so that the dispatch worker
+//! does not report the link as down while heartbeats are missing.
+
+use crate::{metrics, protocol, receive};
+use std::time;
+
+/// Last status received from the sender
+#[derive(Default)]
+pub(crate) struct SenderState {
+    uptime: Option<time::Duration>,
+    /// Configuration mismatches last reported, `None` before the first status
+    mismatches: Option<Vec<String>>,
+    paused: bool,
+}
+
+impl SenderState {
+    /// Exposes `status` and reports the differences between the configuration of the sender and
+    /// `config` when they change
+    pub(crate) fn update(&mut self, config: &receive::Config, status: &protocol::SenderStatus) {
+        if self.uptime.is_some_and(|uptime| status.uptime < uptime) {
+            log::info!("sender restarted {} second(s) ago", status.uptime.as_secs());
+        }
+        self.uptime = Some(status.uptime);
+
+        metrics::gauge("rx_sender_uptime_seconds").set(status.uptime.as_secs());
+        metrics::gauge("rx_sender_sessions_started").set(status.sessions);
+        metrics::gauge("rx_sender_blocks").set(status.blocks);
+
+        if status.paused != self.paused {
+            if status.paused {
+                log::info!(
+                    "sender paused its emission, heartbeats are expected again once it resumes"
+                );
+            } else {
+                log::info!("sender resumed its emission");
+            }
+            metrics::gauge("rx_sender_paused").set(u64::from(status.paused));
+            self.paused = status.paused;
+        }
+
+        let mut mismatches = Vec::new();
+        if status.mtu != config.from_udp_mtu {
+            mismatches.push(format!(
+                "MTU is {} on the sender and {} on the receiver",
+                status.mtu, config.from_udp_mtu
+            ));
+        }
+        if status.encoding_block_size != config.encoding_block_size {
+            mismatches.push(format!(
+                "encoding block size is {} on the sender and {} on the receiver",
+                status.encoding_block_size, config.encoding_block_size
+            ));
+        }
+        // a sender following a repair profile uses repair block sizes up to the receiver one
+        if config.repair_block_size < status.repair_block_size {
+            mismatches.push(format!(
+                "repair block size is {} on the sender, larger than {} on the receiver",
+                status.repair_block_size, config.repair_block_size
+            ));
+        }
+
+        if self.mismatches.as_ref() != Some(&mismatches) {
+            if mismatches.is_empty() && self.mismatches.is_some() {
+                log::info!("sender configuration is now consistent with the receiver one");
+            }
+            for mismatch in &mismatches {
+                log::warn!("sender configuration mismatch: {mismatch}");
+            }
+            metrics::gauge("rx_sender_config_mismatch").set(u64::from(!mismatches.is_empty()));
+            self.mismatches = Some(mismatches);
+        }
+    }
+
+    /// Whether the last status received announced that the sender paused its emission
+    pub(crate) const fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
@@ -0,0 +1,200 @@
+//! Worker that publishes an out-of-band index of the blocks delivered to clients
+//!
+//! For each block written to a client, a JSON record is sent on a dedicated TCP connection
+//! (one record per line) with the `session` (client identifier), the `block` sequence number on
+//! the receiver, the `start` and `end` byte offsets of the block data within the session, the
+//! `murmur3` 128 bits hash of this data and a `timestamp` (seconds since UNIX epoch). When a
+//! session ends, a last record gives its `status` (`completed` or `aborted`) and the total number
+//! of `bytes` delivered.
+//!
+//! Records are queued in a bounded channel: when the index consumer is disconnected or too slow,
+//! records are dropped and counted rather than slowing down the delivery to clients.
+
+use crate::{metrics, protocol, receive, scrub};
+use fasthash::HasherExt;
+use std::{
+    hash::Hasher,
+    io::{self, Write},
+    net, thread, time,
+};
+
+/// Maximum number of records waiting to be sent on the index stream
+const QUEUE_SIZE: usize = 4096;
+
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(1);
+
+pub(crate) struct Index {
+    to_index: crossbeam_channel::Sender<String>,
+    for_index: crossbeam_channel::Receiver<String>,
+}
+
+impl Index {
+    pub(crate) fn new() -> Self {
+        let (to_index, for_index) = crossbeam_channel::bounded(QUEUE_SIZE);
+        Self {
+            to_index,
+            for_index,
+        }
+    }
+
+    /// Queues the record of a block whose `data` was delivered at `offset` in the session of
+    /// `client_id`
+    pub(crate) fn record(
+        &self,
+        client_id: protocol::ClientId,
+        block_seq: u64,
+        offset: u64,
+        data: &[u8],
+    ) {
+        let mut hasher = fasthash::Murmur3HasherExt::default();
+        hasher.write(data);
+        let hash = hasher.finish_ext();
+
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let record = format!(
+            "{{\"session\":{client_id},\"block\":{block_seq},\"start\":{offset},\"end\":{},\"murmur3\":\"{hash:032x}\",\"timestamp\":{timestamp:.6}}}\n",
+            offset + data.len() as u64,
+        );
+
+        if self.to_index.try_send(record).is_err() {
+            metrics::counter("rx_index_records_dropped").inc();
+        }
+    }
+
+    /// Queues the record of the end of the session of `client_id`, after `bytes` were delivered
+    pub(crate) fn record_end(&self, client_id: protocol::ClientId, completed: bool, bytes: u64) {
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let status = if completed { "completed" } else { "aborted" };
+        let record = format!(
+            "{{\"session\":{client_id},\"status\":\"{status}\",\"bytes\":{bytes},\"timestamp\":{timestamp:.6}}}\n"
+        );
+
+        if self.to_index.try_send(record).is_err() {
+            metrics::counter("rx_index_records_dropped").inc();
+        }
+    }
+}
+
+fn send_records(index: &Index, to: net::SocketAddr) -> Result<(), io::Error> {
+    let mut stream = net::TcpStream::connect(to)?;
+    log::info!("index stream connected to {}", scrub::addr(&to));
+
+    for record in &index.for_index {
+        if let Err(e) = stream.write_all(record.as_bytes()) {
+            metrics::counter("rx_index_records_dropped").inc();
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn start(index: &Index, to: net::SocketAddr) -> Result<(), receive::Error> {
+    log::info!("publishing blocks index to TCP {}", scrub::addr(&to));
+
+    loop {
+        if let Err(e) = send_records(index, to) {
+            log::warn!("index stream to {} failed: {e}", scrub::addr(&to));
+        }
+
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Index;
+    use crate::{
+        metrics, protocol,
+        testing::{self, json_field as field, Diode},
+    };
+    use fasthash::HasherExt;
+    use std::{
+        hash::Hasher,
+        io::{BufRead, BufReader},
+        net, time,
+    };
+
+    const TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+    fn murmur3(data: &[u8]) -> String {
+        let mut hasher = fasthash::Murmur3HasherExt::default();
+        hasher.write(data);
+        format!("{:032x}", hasher.finish_ext())
+    }
+
+    #[test]
+    fn records_tile_sessions() {
+        let index = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let index_addr = index.local_addr().expect("address");
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |_| {},
+            move |config| config.index_stream = Some(index_addr),
+        );
+        let (index, _) = index.accept().expect("index connection");
+        index.set_read_timeout(Some(TIMEOUT)).expect("read timeout");
+        let mut records = BufReader::new(index).lines();
+
+        for len in [50_000, 1, 100_000] {
+            let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            diode.send(&data);
+            assert_eq!(diode.receive(TIMEOUT).as_deref(), Some(data.as_slice()));
+
+            let mut session = None;
+            let mut offset = 0;
+            let mut blocks = Vec::new();
+            loop {
+                let record = records.next().expect("record").expect("read record");
+                let client_id = field(&record, "session").expect("session").to_string();
+                assert_eq!(session.get_or_insert_with(|| client_id.clone()), &client_id);
+
+                if let Some(status) = field(&record, "status") {
+                    assert_eq!(status, "completed");
+                    assert_eq!(field(&record, "bytes"), Some(len.to_string().as_str()));
+                    break;
+                }
+
+                let block = field(&record, "block").expect("block");
+                let start = field(&record, "start").expect("start").parse::<usize>();
+                let end = field(&record, "end").expect("end").parse::<usize>();
+                let (start, end) = (start.expect("start"), end.expect("end"));
+                assert_eq!(start, offset, "gap or overlap at block {block}");
+                assert!(start < end && end <= len);
+                assert_eq!(
+                    field(&record, "murmur3"),
+                    Some(murmur3(&data[start..end]).as_str())
+                );
+                assert!(field(&record, "timestamp").is_some());
+                blocks.push(block.parse::<u64>().expect("block"));
+                offset = end;
+            }
+            assert_eq!(offset, len);
+            assert!(blocks.windows(2).all(|w| w[0] < w[1]), "{blocks:?}");
+        }
+    }
+
+    #[test]
+    fn full_queue_drops() {
+        let index = Index::new();
+        let client_id = protocol::ClientId::default();
+        for block_seq in 0..super::QUEUE_SIZE as u64 {
+            index.record(client_id, block_seq, block_seq, b"x");
+        }
+
+        let dropped = metrics::counter("rx_index_records_dropped");
+        let nb_dropped = dropped.get();
+        index.record(client_id, super::QUEUE_SIZE as u64, 0, b"x");
+        index.record_end(client_id, true, 1);
+        assert!(nb_dropped + 2 <= dropped.get());
+        assert_eq!(index.for_index.len(), super::QUEUE_SIZE);
+    }
+}
@@ -0,0 +1,115 @@
+//! Statistics of the packets missing from each block, to size the repair packets of the sender
+//!
+//! The reblock worker records the number of packets missing from each block it closes. At the
+//! end of each interval, the ratio of missing packets and the distribution of the number missing
+//! per block are logged, along with the repair block size which would have recovered every block
+//! of the interval, the margin of the forward error correction code included. Since the sender
+//! cannot learn them over the one-way link, reports collected at different times of day help
+//! writing its repair profile (see [crate::send::repair_profile]).
+
+use crate::{fec, metrics, protocol};
+use std::{sync, time};
+
+pub(crate) struct LossReport {
+    interval: time::Duration,
+    fec: fec::Algorithm,
+    packet_size: u32,
+    started_at: time::Instant,
+    nb_packets: u64,
+    /// Number of blocks closed during the interval, indexed by the number of packets missing
+    histogram: Vec<u64>,
+    missing_ppm: sync::Arc<metrics::Metric>,
+    block_missing_p99: sync::Arc<metrics::Metric>,
+    block_missing_max: sync::Arc<metrics::Metric>,
+}
+
+impl LossReport {
+    pub(crate) fn new(
+        interval: time::Duration,
+        fec: fec::Algorithm,
+        oti: &raptorq::ObjectTransmissionInformation,
+    ) -> Self {
+        Self {
+            interval,
+            fec,
+            packet_size: u32::from(protocol::data_mtu(oti)),
+            started_at: time::Instant::now(),
+            nb_packets: 0,
+            histogram: Vec::new(),
+            missing_ppm: metrics::gauge("rx_packets_missing_ppm"),
+            block_missing_p99: metrics::gauge("rx_block_packets_missing_p99"),
+            block_missing_max: metrics::gauge("rx_block_packets_missing_max"),
+        }
+    }
+
+    /// Records a block of `nb_packets` packets, `nb_missing` of them missing when it was closed
+    pub(crate) fn record(&mut self, nb_packets: usize, nb_missing: usize) {
+        self.nb_packets += nb_packets as u64;
+        if self.histogram.len() <= nb_missing {
+            self.histogram.resize(nb_missing + 1, 0);
+        }
+        self.histogram[nb_missing] += 1;
+        self.tick();
+    }
+
+    /// Reports and resets the statistics once the interval elapsed
+    pub(crate) fn tick(&mut self) {
+        let elapsed = self.started_at.elapsed();
+        if elapsed < self.interval {
+            return;
+        }
+        self.report(elapsed);
+        self.started_at = time::Instant::now();
+        self.nb_packets = 0;
+        self.histogram.clear();
+    }
+
+    /// Smallest number of missing packets of at least `ratio` of the blocks
+    fn percentile(&self, nb_blocks: u64, ratio: f64) -> usize {
+        let target = (ratio * nb_blocks as f64).ceil() as u64;
+        let mut count = 0;
+        for (nb_missing, nb) in self.histogram.iter().enumerate() {
+            count += nb;
+            if target <= count {
+                return nb_missing;
+            }
+        }
+        self.histogram.len().saturating_sub(1)
+    }
+
+    fn report(&self, elapsed: time::Duration) {
+        let nb_blocks: u64 = self.histogram.iter().sum();
+        if nb_blocks == 0 {
+            return;
+        }
+        let nb_missing: u64 = self
+            .histogram
+            .iter()
+            .enumerate()
+            .map(|(nb_missing, nb)| nb_missing as u64 * nb)
+            .sum();
+        let ratio = nb_missing as f64 / self.nb_packets.max(1) as f64;
+        let p99 = self.percentile(nb_blocks, 0.99);
+        let max = self.histogram.len() - 1;
+
+        self.missing_ppm.set((1_000_000.0 * ratio) as u64);
+        self.block_missing_p99.set(p99 as u64);
+        self.block_missing_max.set(max as u64);
+
+        let message = format!(
+            "loss over the last {} s: {nb_missing} of {} packets missing ({:.3}%) from {nb_blocks} blocks, per block p99 {p99}, max {max}",
+            elapsed.as_secs(),
+            self.nb_packets,
+            100.0 * ratio
+        );
+        if max == 0 || !self.fec.has_repair() {
+            log::info!("{message}");
+        } else {
+            let nb_repair_packets = max as u32 + self.fec.repair_margin();
+            log::info!(
+                "{message}: recovering every block takes a repair_block_size of at least {} bytes",
+                nb_repair_packets * self.packet_size
+            );
+        }
+    }
+}
@@ -14,80 +14,700 @@
 //! - heartbeat does not need a dedicated worker on the receiver side, heartbeat messages are
 //!   handled by the dispatch worker,
 //! - there are `nb_clients` clients workers running in parallel,
-//! - there are `nb_decoding_threads` decoding workers running in parallel.
+//! - there are `nb_decoding_threads` decoding workers running in parallel,
+//! - there are `nb_udp_threads` udp workers running in parallel, each receiving datagrams on its
+//!   own socket, the reblock worker merging the packets they receive,
+//! - when run under a systemd watchdog, a watchdog worker checks that every stage makes
+//!   progress,
+//! - when placed in a cgroup, a cgroup worker polls its memory usage,
+//! - when a quarantine directory is configured, a quarantine worker writes the blocks which
+//!   cannot be decoded (see [crate::quarantine]),
+//! - with `session_header`, clients workers start the output of every session with a [header]
+//!   giving the site identifier of the sender,
+//! - with `session_trailer`, clients workers end the output of every session with a [trailer],
+//! - with `store_and_forward`, clients workers only connect to the destination once the session
+//!   ended, replaying its data from a [store],
+//! - with `audit_log`, clients workers append a record of every session once it ended, see
+//!   [crate::audit],
+//! - udp workers drop the configuration packets of the sender (see [protocol::write_config]),
+//!   counting those announcing an incompatible protocol (see [Config::protocol_mismatches]),
+//!   refusing the following packets unless `force_best_effort` is set, and stopping with
+//!   `check_sender_config` when one is inconsistent with the configuration,
+//! - with `psk`, udp workers authenticate every datagram and decrypt those but configuration
+//!   packets, dropping those failing authentication or replayed, see [crate::crypto],
+//! - with `allow_from`, udp workers drop the datagrams of sources outside of the given networks.
+//!
+//! The flush timeout can be changed while the receiver runs with [Receiver::set_flush_timeout],
+//! workers reading it from [tunables].
 
-use crate::{protocol, semaphore};
+use crate::{
+    audit, aux::file::hash, cgroup, config, crypto, durable, events, fec, metrics, protocol,
+    quarantine, sd_notify, semaphore, sock_utils, status,
+};
 use std::{
     fmt,
     io::{self, Write},
     net,
-    os::fd::AsRawFd,
-    thread, time,
+    os::{fd::AsRawFd, unix},
+    path,
+    str::FromStr,
+    sync, thread, time,
 };
 
+mod capacity;
 mod client;
 mod clients;
 mod decoding;
 mod dispatch;
+pub mod header;
+mod heartbeat;
+mod index;
+mod loss_report;
+pub(crate) mod pool;
 mod reblock;
 mod reordering;
+pub(crate) mod store;
+pub mod trailer;
+mod tunables;
 mod udp;
+mod watchdog;
 
 pub struct Config {
     pub from_udp: net::SocketAddr,
     pub from_udp_mtu: u16,
+    /// Socket already bound to `from_udp`, see [detect_mtu], or passed by socket activation
+    pub from_udp_socket: Option<net::UdpSocket>,
+    /// Network interface on which to join `from_udp` when it is a multicast group, instead of the
+    /// one chosen by the kernel
+    pub from_multicast_interface: Option<String>,
+    /// Address of a second diode link the sender stripes packets over, received by its own udp
+    /// worker and merged with the packets of `from_udp`
+    pub stripe_from_udp: Option<net::SocketAddr>,
     pub nb_clients: u16,
     pub encoding_block_size: u64,
     pub repair_block_size: u32,
+    /// Forward error correction code of the blocks, which must be the same on both ends
+    pub fec: fec::Algorithm,
     pub udp_buffer_size: u32,
+    /// How the UDP thread waits for datagrams
+    pub udp_poll_mode: PollMode,
+    /// Let the kernel coalesce received datagrams (`UDP_GRO`), when it supports it
+    pub udp_gro: bool,
+    /// Number of UDP threads, each receiving datagrams on its own socket, the sockets sharing
+    /// `from_udp` with `SO_REUSEPORT`
+    pub nb_udp_threads: u8,
+    /// Capacity of the queues between workers, in batches of datagrams between the udp workers
+    /// and the reblock worker, and in blocks between the following workers, see [udp]
+    pub queue_size: usize,
     pub flush_timeout: time::Duration,
+    /// Size of the blocks decoded ahead of a missing one above which the reordering worker gives
+    /// it up, 0 meaning no limit
+    pub reorder_max_bytes: u64,
+    /// Deliver blocks as soon as all their source packets are received, and write data to
+    /// clients without buffering
+    pub low_latency: bool,
     pub nb_decoding_threads: u8,
+    /// Ratio of the time available to decode each block above which a warning is logged, 0
+    /// disabling the warning
+    pub decode_capacity_warning: f64,
+    /// Interval between two reports of the packets missing from blocks, `None` disabling them
+    pub loss_report_interval: Option<time::Duration>,
     pub heartbeat_interval: Option<time::Duration>,
+    pub events_socket: Option<path::PathBuf>,
+    /// File where to append a record of every session, see [crate::audit]
+    pub audit_log: Option<path::PathBuf>,
+    /// When records of the audit log are synchronized to disk
+    pub audit_sync: durable::Policy,
+    /// Send `WATCHDOG=1` notifications to systemd when it requests them
+    pub sd_watchdog: bool,
+    /// Address where to publish the index of delivered blocks
+    pub index_stream: Option<net::SocketAddr>,
+    /// Cgroup the process was placed in, whose memory usage is exported as a gauge
+    pub cgroup: Option<cgroup::Cgroup>,
+    /// Directory where to write the packets of the blocks which cannot be decoded
+    pub quarantine_dir: Option<path::PathBuf>,
+    /// Maximum total size of the files of the quarantine directory, in bytes
+    pub quarantine_max_bytes: u64,
+    /// Write a [header] at the beginning of the output of every session, with the site identifier
+    /// of the sender
+    pub session_header: bool,
+    /// Write a [trailer] at the end of the output of every session, with a digest of the session
+    /// data computed with this algorithm
+    pub session_trailer: Option<hash::Algorithm>,
+    /// Hold the data of every session until its end, delivering nothing of aborted sessions
+    pub store_and_forward: Option<StoreAndForward>,
+    /// Maximum size of the data of a session held by `store_and_forward`, larger sessions being
+    /// discarded
+    pub store_and_forward_max_bytes: u64,
+    /// Verify the checksum ending each block, written by senders with `integrity`
+    pub integrity: bool,
+    /// Format of the headers of packets, which must be the same on both ends
+    pub header_format: protocol::HeaderFormat,
+    /// Stop receiving when the sender announces encoding parameters inconsistent with this
+    /// configuration in its configuration packets, see [Config::sender_config_mismatches]
+    pub check_sender_config: bool,
+    /// Read the packets of a sender announcing an incompatible protocol in its configuration
+    /// packets, instead of refusing them, see [Config::protocol_mismatches]
+    pub force_best_effort: bool,
+    /// Authenticate and decrypt datagrams with this pre-shared key, which must be the same on
+    /// both ends
+    pub psk: Option<crypto::Key>,
+    /// Networks datagrams are accepted from, datagrams from any source being accepted if empty
+    pub allow_from: Vec<Network>,
+}
+
+/// Network of addresses, written in CIDR notation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Network {
+    addr: net::IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Returns whether `ip` belongs to this network, IPv4-mapped IPv6 addresses being taken as
+    /// IPv4 addresses
+    pub fn contains(&self, ip: &net::IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (net::IpAddr::V4(network), net::IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                (u32::from(network) ^ u32::from(ip)) & mask.unwrap_or(0) == 0
+            }
+            (net::IpAddr::V6(network), net::IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                (u128::from(network) ^ u128::from(ip)) & mask.unwrap_or(0) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// Parses `address/prefix_len`, or a single address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = net::IpAddr::from_str(addr)
+            .map_err(|e| format!("invalid address \"{addr}\": {e}"))?
+            .to_canonical();
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length \"{prefix_len}\""))?,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Where [Config::store_and_forward] holds the data of sessions
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StoreAndForward {
+    Memory,
+    /// Spool files in the given directory
+    Disk(path::PathBuf),
+}
+
+impl fmt::Display for StoreAndForward {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Memory => write!(fmt, "memory"),
+            Self::Disk(dir) => write!(fmt, "disk in '{}'", dir.display()),
+        }
+    }
+}
+
+/// Number of flush timeouts after which a block still expected is given up, the receiver
+/// skipping forward to the blocks received since, see [reblock] and [reordering], and after which
+/// a session whose queue stays full is failed, see [dispatch]
+const RESYNC_FLUSH_TIMEOUTS: u32 = 4;
+
+/// Default value of [Config::queue_size]
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// Default value of [Config::decode_capacity_warning]
+pub const DEFAULT_DECODE_CAPACITY_WARNING: f64 = 0.7;
+
+/// Default busy polling duration of [PollMode::Busy], in microseconds
+pub const DEFAULT_BUSY_POLL_US: u32 = 50;
+
+/// How the receiving thread waits for datagrams
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PollMode {
+    /// Blocking receive calls, the thread sleeping until datagrams arrive
+    Blocking,
+    /// Non-blocking receive calls in a loop, spinning during the given number of microseconds
+    /// after the last datagram before backing off to short sleeps
+    Busy(u32),
+}
+
+impl fmt::Display for PollMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Blocking => write!(fmt, "blocking"),
+            Self::Busy(spin_us) => write!(fmt, "busy:{spin_us}"),
+        }
+    }
+}
+
+impl FromStr for PollMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "blocking" => Ok(Self::Blocking),
+            None if s == "busy" => Ok(Self::Busy(DEFAULT_BUSY_POLL_US)),
+            Some(("busy", spin_us)) => spin_us
+                .parse()
+                .map(Self::Busy)
+                .map_err(|e| format!("invalid spin duration \"{spin_us}\": {e}")),
+            _ => Err(format!("unknown poll mode \"{s}\"")),
+        }
+    }
 }
 
 impl Config {
+    /// Checks the configuration, returning every problem found
+    pub fn validate(&self) -> Result<(), Vec<config::Error>> {
+        let mut errors = Vec::new();
+
+        if self.nb_clients == 0 {
+            errors.push(config::Error::Zero("nb_clients"));
+        }
+        if self.nb_decoding_threads == 0 {
+            errors.push(config::Error::Zero("nb_decoding_threads"));
+        }
+        if self.queue_size == 0 {
+            errors.push(config::Error::Zero("queue_size"));
+        }
+        if self.nb_udp_threads == 0 {
+            errors.push(config::Error::Zero("nb_udp_threads"));
+        } else if 1 < self.nb_udp_threads && self.from_udp.ip().is_multicast() {
+            errors.push(config::Error::Invalid(
+                "nb_udp_threads",
+                "every socket bound to a multicast group receives all its datagrams".to_string(),
+            ));
+        }
+        config::check_geometry(
+            &mut errors,
+            "from_udp_mtu",
+            self.header_format,
+            self.packet_mtu(),
+            self.encoding_block_size,
+            self.repair_block_size,
+            self.fec,
+        );
+        config::check_multicast_interface(
+            &mut errors,
+            "from_multicast_interface",
+            self.from_multicast_interface.as_deref(),
+            self.from_udp,
+        );
+        if let Some(stripe_from_udp) = self.stripe_from_udp {
+            if stripe_from_udp.ip().is_multicast() {
+                errors.push(config::Error::Invalid(
+                    "stripe_from_udp",
+                    "striping over a multicast group is not supported".to_string(),
+                ));
+            } else if stripe_from_udp.is_ipv4() != self.from_udp.is_ipv4() {
+                errors.push(config::Error::Invalid(
+                    "stripe_from_udp",
+                    format!(
+                        "{stripe_from_udp} and {} must be of the same family",
+                        self.from_udp
+                    ),
+                ));
+            } else if stripe_from_udp == self.from_udp {
+                errors.push(config::Error::Invalid(
+                    "stripe_from_udp",
+                    "the second link must be received at another address than from_udp".to_string(),
+                ));
+            }
+        }
+        if self.flush_timeout.is_zero() {
+            errors.push(config::Error::Zero("flush_timeout"));
+        }
+        match self.heartbeat_interval {
+            Some(interval) if interval.is_zero() => {
+                errors.push(config::Error::Zero("heartbeat_interval"));
+            }
+            // incomplete blocks are only flushed after flush_timeout, the link would be
+            // reported down while blocks are pending
+            Some(interval) if interval <= self.flush_timeout => {
+                errors.push(config::Error::TimeoutOrder(
+                    "flush_timeout",
+                    self.flush_timeout,
+                    "heartbeat_interval",
+                    interval,
+                ));
+            }
+            _ => (),
+        }
+        config::check_range(
+            &mut errors,
+            "decode_capacity_warning",
+            self.decode_capacity_warning,
+            0.0,
+            f64::INFINITY,
+            "a finite positive ratio",
+        );
+        if self.loss_report_interval == Some(time::Duration::ZERO) {
+            errors.push(config::Error::Zero("loss_report_interval"));
+        }
+        if self.loss_report_interval.is_some() && self.low_latency {
+            errors.push(config::Error::Invalid(
+                "loss_report_interval",
+                "missing packets are not counted with low_latency".to_string(),
+            ));
+        }
+        if let Some(index_stream) = self.index_stream {
+            config::check_destination(&mut errors, "index_stream", index_stream);
+        }
+        if self.quarantine_dir.is_some() && self.quarantine_max_bytes == 0 {
+            errors.push(config::Error::Zero("quarantine_max_bytes"));
+        }
+        if self.store_and_forward.is_some() && self.store_and_forward_max_bytes == 0 {
+            errors.push(config::Error::Zero("store_and_forward_max_bytes"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns the differences between the protocol version and features announced by the
+    /// sender and those of this receiver which prevent packets from being read or blocks from
+    /// being checked, each naming the parameter to set
+    pub fn protocol_mismatches(&self, sender: &protocol::EncodingConfig) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        let receiver = protocol::ProtocolVersion::current();
+        match receiver.compatibility(sender) {
+            protocol::Compatibility::TooOld => mismatches.push(format!(
+                "protocol version is {} on the sender, older than {} read by the receiver of version {}, upgrade the sender",
+                sender.version, receiver.reads_from, receiver.version
+            )),
+            protocol::Compatibility::TooNew => mismatches.push(format!(
+                "protocol version is {} on the sender, read from version {}, newer than {} on the receiver, upgrade the receiver",
+                sender.version, sender.read_from, receiver.version
+            )),
+            _ => (),
+        }
+        let unknown = sender.features.unknown();
+        if !unknown.is_empty() {
+            mismatches.push(format!(
+                "sender uses features {unknown} unsupported by the receiver, upgrade the receiver"
+            ));
+        }
+        if sender.header_format() != self.header_format {
+            let wide = sender.header_format() == protocol::HeaderFormat::Wide;
+            mismatches.push(format!(
+                "wide block ids are {} on the sender and {} on the receiver, {} wide_block_ids",
+                enabled(wide),
+                enabled(!wide),
+                if wide { "set" } else { "unset" }
+            ));
+        }
+        let encryption = sender.features.contains(protocol::Features::ENCRYPTION);
+        if encryption != self.psk.is_some() {
+            mismatches.push(format!(
+                "encryption is {} on the sender and {} on the receiver, {} psk_file",
+                enabled(encryption),
+                enabled(self.psk.is_some()),
+                if encryption { "set" } else { "unset" }
+            ));
+        }
+        let integrity = sender.features.contains(protocol::Features::INTEGRITY);
+        if integrity != self.integrity {
+            mismatches.push(format!(
+                "integrity is {} on the sender and {} on the receiver, {} integrity",
+                enabled(integrity),
+                enabled(self.integrity),
+                if integrity { "set" } else { "unset" }
+            ));
+        }
+        mismatches
+    }
+
+    /// Returns the differences between the encoding parameters announced by the sender and this
+    /// configuration which prevent blocks from being decoded, each naming the parameter to set,
+    /// including the [Config::protocol_mismatches]
+    pub fn sender_config_mismatches(&self, sender: &protocol::EncodingConfig) -> Vec<String> {
+        let oti = protocol::object_transmission_information(
+            self.header_format,
+            self.packet_mtu(),
+            self.encoding_block_size,
+        );
+
+        let mut mismatches = self.protocol_mismatches(sender);
+        if sender.fec != self.fec {
+            mismatches.push(format!(
+                "forward error correction is {} on the sender and {} on the receiver, set fec to {}",
+                sender.fec, self.fec, sender.fec
+            ));
+        }
+        if sender.oti.symbol_size() != oti.symbol_size() {
+            mismatches.push(format!(
+                "MTU is {} on the sender and {} on the receiver, set from_udp_mtu to {}",
+                sender.mtu, self.from_udp_mtu, sender.mtu
+            ));
+        } else if sender.encoding_block_size() != oti.transfer_length() {
+            mismatches.push(format!(
+                "encoding block size is {} on the sender and {} on the receiver, set encoding_block_size to {}",
+                sender.encoding_block_size(),
+                oti.transfer_length(),
+                sender.encoding_block_size()
+            ));
+        }
+        // a sender following a repair profile uses repair block sizes up to the announced one
+        let repair_block_size = if self.fec.has_repair() {
+            protocol::nb_repair_packets(&oti, self.repair_block_size)
+                * u32::from(protocol::packet_size(&oti))
+        } else {
+            0
+        };
+        if repair_block_size < sender.repair_block_size() {
+            mismatches.push(format!(
+                "repair block size is {} on the sender, larger than {repair_block_size} on the receiver, set repair_block_size to {}",
+                sender.repair_block_size(),
+                sender.repair_block_size()
+            ));
+        }
+        mismatches
+    }
+
+    /// MTU from which the size of packets is computed, see [protocol::packet_mtu], leaving room
+    /// for the encryption overhead
+    pub(crate) fn packet_mtu(&self) -> u16 {
+        let mtu = protocol::packet_mtu(self.from_udp_mtu, &self.from_udp);
+        if self.psk.is_some() {
+            crypto::unsealed_mtu(mtu)
+        } else {
+            mtu
+        }
+    }
+
     pub(crate) fn adjust(&mut self) {
-        let oti =
-            protocol::object_transmission_information(self.from_udp_mtu, self.encoding_block_size);
+        let oti = protocol::object_transmission_information(
+            self.header_format,
+            self.packet_mtu(),
+            self.encoding_block_size,
+        );
 
         let packet_size = protocol::packet_size(&oti);
         let nb_encoding_packets = protocol::nb_encoding_packets(&oti);
-        let nb_repair_packets = protocol::nb_repair_packets(&oti, self.repair_block_size);
+        let nb_repair_packets = if self.fec.has_repair() {
+            protocol::nb_repair_packets(&oti, self.repair_block_size)
+        } else {
+            0
+        };
 
         self.encoding_block_size = nb_encoding_packets * u64::from(packet_size);
         self.repair_block_size = nb_repair_packets * u32::from(packet_size);
     }
 }
 
+/// Returns whether datagrams from `source` are accepted when only those of `allow_from` are,
+/// datagrams from any source being accepted if it is empty
+pub(crate) fn allowed(allow_from: &[Network], source: Option<net::SocketAddr>) -> bool {
+    allow_from.is_empty()
+        || source.is_some_and(|source| {
+            allow_from
+                .iter()
+                .any(|network| network.contains(&source.ip()))
+        })
+}
+
+fn enabled(flag: bool) -> &'static str {
+    if flag {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+/// Binds the UDP socket of the receiver to `from_udp`, joining it on the network interface named
+/// `multicast_interface`, or the one chosen by the kernel, when it is a multicast group
+///
+/// Multicast groups are bound with `SO_REUSEADDR`, so that several receivers of the same host
+/// can be fed by one sender. With `reuse_port`, the socket is bound with `SO_REUSEPORT`, so that
+/// the [Config::nb_udp_threads] sockets of the receiver share the datagrams.
+pub fn bind(
+    from_udp: net::SocketAddr,
+    multicast_interface: Option<&str>,
+    reuse_port: bool,
+) -> Result<net::UdpSocket, io::Error> {
+    let group = from_udp.ip();
+    if !group.is_multicast() {
+        if reuse_port {
+            return sock_utils::bind_udp_reuse(from_udp, false, true);
+        }
+        return net::UdpSocket::bind(from_udp);
+    }
+
+    let socket = sock_utils::bind_udp_reuse(from_udp, true, reuse_port)?;
+    multicast_interface
+        .map(sock_utils::interface_index)
+        .transpose()
+        .and_then(|index| sock_utils::join_socket_multicast(&socket, &group, index.unwrap_or(0)))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot join multicast group {group}: {e}"),
+            )
+        })?;
+    log::info!(
+        "joined multicast group on interface {}",
+        multicast_interface.unwrap_or("chosen by the kernel")
+    );
+    Ok(socket)
+}
+
+/// Waits on `socket`, bound with [bind] or passed by socket activation, for a valid diode packet,
+/// with a header in `format` and `encrypted` or not, to derive the MTU used by the sender,
+/// packets of sources outside of `allow_from` being dropped
+///
+/// The packet is left in the socket queue, the returned socket being then given to the receiver
+/// in [Config::from_udp_socket] so that no packet is lost. It must be bound with `reuse_port`
+/// when [Config::nb_udp_threads] is greater than 1.
+pub fn detect_mtu(
+    socket: net::UdpSocket,
+    format: protocol::HeaderFormat,
+    encrypted: bool,
+    allow_from: &[Network],
+) -> Result<(net::UdpSocket, u16), io::Error> {
+    let from_udp = socket.local_addr()?;
+    let mut buffer = vec![0; usize::from(u16::MAX)];
+    let overhead = if encrypted { crypto::OVERHEAD } else { 0 };
+
+    loop {
+        let (len, source) = socket.peek_from(&mut buffer)?;
+        if !allowed(allow_from, Some(source)) {
+            socket.recv(&mut buffer)?;
+            continue;
+        }
+        if let Some(mtu) = len
+            .checked_sub(usize::from(overhead))
+            .and_then(|len| protocol::mtu_of_packet_size(format, len))
+        {
+            let mtu = protocol::link_mtu(mtu.saturating_add(overhead), &from_udp);
+            log::info!("detected MTU {mtu} from a packet of {len} bytes");
+            return Ok((socket, mtu));
+        }
+        log::warn!("ignoring invalid packet of {len} bytes while detecting MTU");
+        socket.recv(&mut buffer)?;
+    }
+}
+
+/// Waits on `socket`, bound with [bind] or passed by socket activation, for a configuration
+/// packet of the sender, see [protocol::write_config]
+///
+/// Packets received before it are dropped, since their encoding parameters are not known yet,
+/// as well as packets of sources outside of `allow_from`. With `psk`, the configuration packet
+/// must be authenticated with this key, see [crate::crypto], forged ones being dropped too. The
+/// returned socket is then given to the receiver in [Config::from_udp_socket]. It must be bound
+/// with `reuse_port` when [Config::nb_udp_threads] is greater than 1.
+pub fn wait_sender_config(
+    socket: net::UdpSocket,
+    allow_from: &[Network],
+    psk: Option<&crypto::Key>,
+) -> Result<(net::UdpSocket, protocol::EncodingConfig), io::Error> {
+    let mut buffer = vec![0; usize::from(u16::MAX)];
+    let mut opened = Vec::new();
+    let mut nb_dropped = 0u64;
+    let unauthenticated_packets = metrics::counter("rx_packets_unauthenticated");
+    loop {
+        let (len, source) = socket.recv_from(&mut buffer)?;
+        if !allowed(allow_from, Some(source)) || !protocol::is_config_packet(&buffer[..len]) {
+            nb_dropped += 1;
+            continue;
+        }
+        let packet = match psk {
+            Some(key) => {
+                let packet = &buffer[..len];
+                match crypto::open(key, crypto::config_clear_len(packet), packet, &mut opened) {
+                    Ok(_) => &opened[..],
+                    Err(e) => {
+                        // logging only the first one, the sender likely using another key
+                        if unauthenticated_packets.get() == 0 {
+                            log::error!(
+                                "ignoring configuration packet: {e}, check that both ends use the same pre-shared key"
+                            );
+                        }
+                        unauthenticated_packets.inc();
+                        continue;
+                    }
+                }
+            }
+            None => &buffer[..len],
+        };
+        // configuration packets have a narrow header whatever the format of the sender
+        match protocol::read_packet(protocol::HeaderFormat::Narrow, packet) {
+            Ok((header, payload)) if header.symbol_id == protocol::CONFIG_SYMBOL_ID => {
+                match protocol::read_config(payload) {
+                    Ok(config) => {
+                        if 0 < nb_dropped {
+                            log::info!(
+                                "dropped {nb_dropped} packet(s) received before the configuration of the sender"
+                            );
+                        }
+                        log::info!("sender announces {config}");
+                        return Ok((socket, config));
+                    }
+                    Err(e) => log::warn!("ignoring configuration packet: {e}"),
+                }
+            }
+            _ => nb_dropped += 1,
+        }
+    }
+}
+
 pub enum Error {
     Io(io::Error),
-    SendPackets(crossbeam_channel::SendError<Vec<raptorq::EncodingPacket>>),
-    SendBlockPackets(crossbeam_channel::SendError<(u8, Option<Vec<raptorq::EncodingPacket>>)>),
-    SendBlockMessage(crossbeam_channel::SendError<(u8, Option<protocol::Message>)>),
-    SendMessage(crossbeam_channel::SendError<Option<protocol::Message>>),
-    SendClients(
-        crossbeam_channel::SendError<(
-            protocol::ClientId,
-            crossbeam_channel::Receiver<protocol::Message>,
-        )>,
-    ),
+    /// A worker channel was disconnected, the string naming the channel
+    Send(&'static str),
     Receive(crossbeam_channel::RecvError),
     ReceiveTimeout(crossbeam_channel::RecvTimeoutError),
     Protocol(protocol::Error),
+    /// The configuration failed [Config::validate]
+    Config(Vec<config::Error>),
+    /// The sender announced encoding parameters inconsistent with the configuration, see
+    /// [Config::sender_config_mismatches]
+    SenderConfig(Vec<String>),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
-            Self::SendPackets(e) => write!(fmt, "crossbeam send packets error: {e}"),
-            Self::SendBlockPackets(e) => write!(fmt, "crossbeam send block packets error: {e}"),
-            Self::SendBlockMessage(e) => write!(fmt, "crossbeam send block/message error: {e}"),
-            Self::SendMessage(e) => write!(fmt, "crossbeam send message error: {e}"),
-            Self::SendClients(e) => write!(fmt, "crossbeam send client error: {e}"),
+            Self::Send(channel) => write!(
+                fmt,
+                "crossbeam send {channel} error: sending on a disconnected channel"
+            ),
             Self::Receive(e) => write!(fmt, "crossbeam receive error: {e}"),
             Self::ReceiveTimeout(e) => write!(fmt, "crossbeam receive timeout error: {e}"),
             Self::Protocol(e) => write!(fmt, "diode protocol error: {e}"),
+            Self::Config(errors) => {
+                write!(fmt, "invalid configuration: {}", config::Errors(errors))
+            }
+            Self::SenderConfig(mismatches) => write!(
+                fmt,
+                "sender configuration mismatch: {}",
+                mismatches.join("; ")
+            ),
         }
     }
 }
@@ -98,45 +718,45 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<crossbeam_channel::SendError<Vec<raptorq::EncodingPacket>>> for Error {
-    fn from(e: crossbeam_channel::SendError<Vec<raptorq::EncodingPacket>>) -> Self {
-        Self::SendPackets(e)
+impl From<crossbeam_channel::SendError<Vec<(protocol::BlockId, raptorq::EncodingPacket)>>>
+    for Error
+{
+    fn from(
+        _: crossbeam_channel::SendError<Vec<(protocol::BlockId, raptorq::EncodingPacket)>>,
+    ) -> Self {
+        Self::Send("packets")
     }
 }
 
-impl From<crossbeam_channel::SendError<(u8, Option<Vec<raptorq::EncodingPacket>>)>> for Error {
-    fn from(e: crossbeam_channel::SendError<(u8, Option<Vec<raptorq::EncodingPacket>>)>) -> Self {
-        Self::SendBlockPackets(e)
+impl From<crossbeam_channel::SendError<(protocol::BlockId, Option<Vec<raptorq::EncodingPacket>>)>>
+    for Error
+{
+    fn from(
+        _: crossbeam_channel::SendError<(protocol::BlockId, Option<Vec<raptorq::EncodingPacket>>)>,
+    ) -> Self {
+        Self::Send("block packets")
     }
 }
 
-impl From<crossbeam_channel::SendError<(u8, Option<protocol::Message>)>> for Error {
-    fn from(oe: crossbeam_channel::SendError<(u8, Option<protocol::Message>)>) -> Self {
-        Self::SendBlockMessage(oe)
+impl From<crossbeam_channel::SendError<(protocol::BlockId, Option<protocol::Message>)>> for Error {
+    fn from(
+        _: crossbeam_channel::SendError<(protocol::BlockId, Option<protocol::Message>)>,
+    ) -> Self {
+        Self::Send("block/message")
     }
 }
 
-impl From<crossbeam_channel::SendError<Option<protocol::Message>>> for Error {
-    fn from(oe: crossbeam_channel::SendError<Option<protocol::Message>>) -> Self {
-        Self::SendMessage(oe)
+impl From<crossbeam_channel::SendError<Option<(protocol::BlockId, protocol::Message)>>> for Error {
+    fn from(
+        _: crossbeam_channel::SendError<Option<(protocol::BlockId, protocol::Message)>>,
+    ) -> Self {
+        Self::Send("message")
     }
 }
 
-impl
-    From<
-        crossbeam_channel::SendError<(
-            protocol::ClientId,
-            crossbeam_channel::Receiver<protocol::Message>,
-        )>,
-    > for Error
-{
-    fn from(
-        e: crossbeam_channel::SendError<(
-            protocol::ClientId,
-            crossbeam_channel::Receiver<protocol::Message>,
-        )>,
-    ) -> Self {
-        Self::SendClients(e)
+impl From<crossbeam_channel::SendError<StartedSession>> for Error {
+    fn from(_: crossbeam_channel::SendError<StartedSession>) -> Self {
+        Self::Send("client")
     }
 }
 
@@ -158,6 +778,14 @@ impl From<protocol::Error> for Error {
     }
 }
 
+/// Session started, with the site identifier of its sender if known, and the queue of its
+/// messages
+pub(crate) type StartedSession = (
+    protocol::ClientId,
+    Option<String>,
+    crossbeam_channel::Receiver<(u64, protocol::Message)>,
+);
+
 /// An instance of this data structure is shared by workers to synchronize them and to access
 /// communication channels
 pub struct Receiver<F> {
@@ -166,43 +794,67 @@ pub struct Receiver<F> {
     pub(crate) to_buffer_size: usize,
     pub(crate) from_max_messages: u16,
     pub(crate) multiplex_control: semaphore::Semaphore,
-    pub(crate) resync_needed_block_id: crossbeam_utils::atomic::AtomicCell<(bool, u8)>,
-    pub(crate) to_reblock: crossbeam_channel::Sender<Vec<raptorq::EncodingPacket>>,
-    pub(crate) for_reblock: crossbeam_channel::Receiver<Vec<raptorq::EncodingPacket>>,
-    pub(crate) to_decoding: crossbeam_channel::Sender<(u8, Option<Vec<raptorq::EncodingPacket>>)>,
+    pub(crate) resync_needed_block_id:
+        crossbeam_utils::atomic::AtomicCell<(bool, protocol::BlockId)>,
+    pub(crate) to_reblock:
+        crossbeam_channel::Sender<Vec<(protocol::BlockId, raptorq::EncodingPacket)>>,
+    pub(crate) for_reblock:
+        crossbeam_channel::Receiver<Vec<(protocol::BlockId, raptorq::EncodingPacket)>>,
+    pub(crate) to_decoding:
+        crossbeam_channel::Sender<(protocol::BlockId, Option<Vec<raptorq::EncodingPacket>>)>,
     pub(crate) for_decoding:
-        crossbeam_channel::Receiver<(u8, Option<Vec<raptorq::EncodingPacket>>)>,
-    pub(crate) to_reordering: crossbeam_channel::Sender<(u8, Option<protocol::Message>)>,
-    pub(crate) for_reordering: crossbeam_channel::Receiver<(u8, Option<protocol::Message>)>,
-    pub(crate) to_dispatch: crossbeam_channel::Sender<Option<protocol::Message>>,
-    pub(crate) for_dispatch: crossbeam_channel::Receiver<Option<protocol::Message>>,
-    pub(crate) to_clients: crossbeam_channel::Sender<(
-        protocol::ClientId,
-        crossbeam_channel::Receiver<protocol::Message>,
-    )>,
-    pub(crate) for_clients: crossbeam_channel::Receiver<(
-        protocol::ClientId,
-        crossbeam_channel::Receiver<protocol::Message>,
-    )>,
+        crossbeam_channel::Receiver<(protocol::BlockId, Option<Vec<raptorq::EncodingPacket>>)>,
+    pub(crate) to_reordering:
+        crossbeam_channel::Sender<(protocol::BlockId, Option<protocol::Message>)>,
+    pub(crate) for_reordering:
+        crossbeam_channel::Receiver<(protocol::BlockId, Option<protocol::Message>)>,
+    pub(crate) to_dispatch:
+        crossbeam_channel::Sender<Option<(protocol::BlockId, protocol::Message)>>,
+    pub(crate) for_dispatch:
+        crossbeam_channel::Receiver<Option<(protocol::BlockId, protocol::Message)>>,
+    pub(crate) to_clients: crossbeam_channel::Sender<StartedSession>,
+    pub(crate) for_clients: crossbeam_channel::Receiver<StartedSession>,
     pub(crate) new_client: F,
+    pub(crate) events: events::Events,
+    pub(crate) site_id: sync::RwLock<Option<String>>,
+    pub(crate) tunables: tunables::Tunables,
+    pub(crate) progress: watchdog::Progress,
+    pub(crate) index: Option<index::Index>,
+    pub(crate) audit: Option<audit::AuditLog>,
+    pub(crate) quarantine: Option<quarantine::Quarantine>,
+    pub(crate) decode_capacity: capacity::DecodeCapacity,
+    pub(crate) missing_packets: reblock::MissingPackets,
+    pub(crate) buffers: pool::Buffers,
+    pub(crate) sender_state: sync::Mutex<heartbeat::SenderState>,
+    /// Nonces of the datagrams received by udp workers with [Config::psk]
+    pub(crate) replay_window: sync::Mutex<crypto::ReplayWindow>,
 }
 
 impl<C, F, E> Receiver<F>
 where
     C: Write + AsRawFd,
-    F: Send + Sync + Fn() -> Result<C, E>,
+    F: Send + Sync + Fn(protocol::Channel) -> Result<C, E>,
     E: Into<Error>,
 {
-    pub fn new(mut config: Config, new_client: F) -> Self {
+    /// Creates the receiver, failing if `config` does not pass [Config::validate]
+    pub fn new(mut config: Config, new_client: F) -> Result<Self, Error> {
+        config.validate().map_err(Error::Config)?;
         config.adjust();
 
         let object_transmission_info = protocol::object_transmission_information(
-            config.from_udp_mtu,
+            config.header_format,
+            config.packet_mtu(),
             config.encoding_block_size,
         );
 
-        let to_buffer_size =
-            config.encoding_block_size as usize - protocol::Message::serialize_overhead();
+        let checksum_size = if config.integrity {
+            protocol::CHECKSUM_SIZE
+        } else {
+            0
+        };
+        let to_buffer_size = config.encoding_block_size as usize
+            - protocol::Message::serialize_overhead()
+            - checksum_size;
 
         let from_max_messages = protocol::nb_encoding_packets(&object_transmission_info) as u16
             + protocol::nb_repair_packets(&object_transmission_info, config.repair_block_size)
@@ -210,23 +862,68 @@ where
 
         let multiplex_control = semaphore::Semaphore::new(config.nb_clients as usize);
 
+        let index = config.index_stream.map(|_| index::Index::new());
+
+        let audit = config
+            .audit_log
+            .as_deref()
+            .map(|path| audit::AuditLog::open(path, config.audit_sync))
+            .transpose()?;
+
+        let decode_capacity = capacity::DecodeCapacity::new(
+            config.decode_capacity_warning,
+            config.nb_decoding_threads,
+        );
+
+        let quarantine = config.quarantine_dir.clone().map(|dir| {
+            let geometry = quarantine::Geometry {
+                mtu: config.from_udp_mtu,
+                encoding_block_size: config.encoding_block_size,
+                repair_block_size: config.repair_block_size,
+                fec: config.fec,
+                oti: object_transmission_info,
+            };
+            quarantine::Quarantine::new(dir, config.quarantine_max_bytes, geometry)
+        });
+
+        // enough buffers for the blocks being reblocked and decoded, and for the messages being
+        // written to clients
+        let symbol_size = usize::from(object_transmission_info.symbol_size());
+        let buffers = pool::Buffers {
+            packets: pool::Pool::new(
+                (config.nb_decoding_threads as usize + 2) * usize::from(from_max_messages),
+                symbol_size,
+                "rx_packet_buffers_allocated",
+            ),
+            blocks: pool::Pool::new(
+                config.nb_decoding_threads as usize + config.nb_clients as usize,
+                protocol::nb_encoding_packets(&object_transmission_info) as usize * symbol_size,
+                "rx_block_buffers_allocated",
+            ),
+        };
+
         let resync_needed_block_id = crossbeam_utils::atomic::AtomicCell::default();
 
-        let (to_reblock, for_reblock) =
-            crossbeam_channel::unbounded::<Vec<raptorq::EncodingPacket>>();
-        let (to_decoding, for_decoding) =
-            crossbeam_channel::unbounded::<(u8, Option<Vec<raptorq::EncodingPacket>>)>();
-        let (to_reordering, for_reordering) =
-            crossbeam_channel::unbounded::<(u8, Option<protocol::Message>)>();
-        let (to_dispatch, for_dispatch) =
-            crossbeam_channel::unbounded::<Option<protocol::Message>>();
-
-        let (to_clients, for_clients) = crossbeam_channel::bounded::<(
-            protocol::ClientId,
-            crossbeam_channel::Receiver<protocol::Message>,
-        )>(1);
-
-        Self {
+        let (to_reblock, for_reblock) = crossbeam_channel::bounded::<
+            Vec<(protocol::BlockId, raptorq::EncodingPacket)>,
+        >(config.queue_size);
+        let (to_decoding, for_decoding) = crossbeam_channel::bounded::<(
+            protocol::BlockId,
+            Option<Vec<raptorq::EncodingPacket>>,
+        )>(config.queue_size);
+        let (to_reordering, for_reordering) = crossbeam_channel::bounded::<(
+            protocol::BlockId,
+            Option<protocol::Message>,
+        )>(config.queue_size);
+        let (to_dispatch, for_dispatch) = crossbeam_channel::bounded::<
+            Option<(protocol::BlockId, protocol::Message)>,
+        >(config.queue_size);
+
+        let (to_clients, for_clients) = crossbeam_channel::bounded::<StartedSession>(1);
+
+        let tunables = tunables::Tunables::new(&config);
+
+        Ok(Self {
             config,
             object_transmission_info,
             to_buffer_size,
@@ -244,7 +941,27 @@ where
             to_clients,
             for_clients,
             new_client,
-        }
+            events: events::Events::new(),
+            site_id: sync::RwLock::new(None),
+            tunables,
+            progress: watchdog::Progress::default(),
+            index,
+            audit,
+            quarantine,
+            decode_capacity,
+            missing_packets: reblock::MissingPackets::new(),
+            buffers,
+            sender_state: sync::Mutex::default(),
+            replay_window: sync::Mutex::default(),
+        })
+    }
+
+    /// Marks the pipeline as broken when a worker returns, so that the watchdog stops
+    fn exited(&self, res: Result<(), Error>) -> Result<(), Error> {
+        self.progress
+            .exited
+            .store(true, sync::atomic::Ordering::Relaxed);
+        res
     }
 
     pub fn start<'a>(&'a self, scope: &'a thread::Scope<'a, '_>) -> Result<(), Error> {
@@ -255,6 +972,8 @@ where
 
         log::info!("client socket buffer size is {} bytes", self.to_buffer_size);
 
+        log::info!("forward error correction is {}", self.config.fec);
+
         log::info!(
             "decoding will expect {} packets ({} bytes per block) + {} repair packets",
             protocol::nb_encoding_packets(&self.object_transmission_info),
@@ -267,9 +986,16 @@ where
 
         log::info!(
             "flush timeout is {} ms",
-            self.config.flush_timeout.as_millis()
+            self.tunables.flush_timeout().as_millis()
         );
 
+        if 0 < self.config.reorder_max_bytes {
+            log::info!(
+                "blocks waiting for a missing one limited to {} bytes",
+                self.config.reorder_max_bytes
+            );
+        }
+
         if let Some(hb_interval) = self.config.heartbeat_interval {
             log::info!(
                 "heartbeat interval is set to {} seconds",
@@ -279,34 +1005,393 @@ where
             log::info!("heartbeat is disabled");
         }
 
+        if let Some(events_socket) = &self.config.events_socket {
+            if events_socket.exists() {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "events socket path '{}' already exists",
+                        events_socket.display()
+                    ),
+                )));
+            }
+
+            log::info!("publishing events at {}", events_socket.display());
+
+            let listener = unix::net::UnixListener::bind(events_socket)?;
+
+            thread::Builder::new()
+                .name("events".to_string())
+                .spawn_scoped(scope, || {
+                    if let Err(e) = self.events.serve(listener) {
+                        log::error!("events socket error: {e}");
+                    }
+                })?;
+        }
+
+        if let (Some(index), Some(to)) = (&self.index, self.config.index_stream) {
+            thread::Builder::new()
+                .name("index".to_string())
+                .spawn_scoped(scope, move || index::start(index, to))?;
+        }
+
+        if let Some(quarantine) = &self.quarantine {
+            if !quarantine.dir().is_dir() {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "quarantine directory '{}' does not exist",
+                        quarantine.dir().display()
+                    ),
+                )));
+            }
+
+            thread::Builder::new()
+                .name("quarantine".to_string())
+                .spawn_scoped(scope, move || quarantine::start(quarantine))?;
+        }
+
+        if let Some(store_and_forward) = &self.config.store_and_forward {
+            if let StoreAndForward::Disk(dir) = store_and_forward {
+                if !dir.is_dir() {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "store and forward directory '{}' does not exist",
+                            dir.display()
+                        ),
+                    )));
+                }
+            }
+            log::info!(
+                "sessions are held in {store_and_forward} until their end, up to {} bytes each",
+                self.config.store_and_forward_max_bytes
+            );
+        }
+
+        if let Some(cgroup) = &self.config.cgroup {
+            thread::Builder::new()
+                .name("cgroup".to_string())
+                .spawn_scoped(scope, move || cgroup::monitor(cgroup))?;
+        }
+
         for i in 0..self.config.nb_clients {
             thread::Builder::new()
                 .name(format!("receive_thread_{i}"))
-                .spawn_scoped(scope, || clients::start(self))?;
+                .spawn_scoped(scope, || self.exited(clients::start(self)))?;
         }
 
         thread::Builder::new()
             .name("dispatch".to_string())
-            .spawn_scoped(scope, || dispatch::start(self))?;
+            .spawn_scoped(scope, || self.exited(dispatch::start(self)))?;
 
         thread::Builder::new()
             .name("reordering".to_string())
-            .spawn_scoped(scope, || reordering::start(self))?;
+            .spawn_scoped(scope, || self.exited(reordering::start(self)))?;
 
         for i in 0..self.config.nb_decoding_threads {
             thread::Builder::new()
                 .name(format!("decoding_{i}"))
-                .spawn_scoped(scope, || decoding::start(self))?;
+                .spawn_scoped(scope, || self.exited(decoding::start(self)))?;
         }
 
         thread::Builder::new()
             .name("reblock".to_string())
-            .spawn_scoped(scope, || reblock::start(self))?;
+            .spawn_scoped(scope, || self.exited(reblock::start(self)))?;
 
-        thread::Builder::new()
-            .name("udp".to_string())
-            .spawn_scoped(scope, || udp::start(self))?;
+        // bound before any thread is started, so that startup fails early and cleanly
+        let sockets = udp::bind(self)?;
+        let nb_sockets = sockets.len();
+        for (i, socket) in sockets.into_iter().enumerate() {
+            let name = if nb_sockets == 1 {
+                "udp".to_string()
+            } else {
+                format!("udp_{i}")
+            };
+            thread::Builder::new()
+                .name(name)
+                .spawn_scoped(scope, move || self.exited(udp::start(self, socket)))?;
+        }
+
+        if self.config.sd_watchdog {
+            if let Some(interval) = sd_notify::watchdog_interval() {
+                thread::Builder::new()
+                    .name("watchdog".to_string())
+                    .spawn_scoped(scope, move || {
+                        let res = watchdog::start(self, interval);
+                        if let Err(e) = &res {
+                            log::error!("watchdog error: {e}");
+                        }
+                        res
+                    })?;
+            }
+        }
 
         Ok(())
     }
 }
+
+impl<F> Receiver<F> {
+    /// Duration after which incomplete blocks are flushed and pending data is written to
+    /// destinations
+    pub fn flush_timeout(&self) -> time::Duration {
+        self.tunables.flush_timeout()
+    }
+
+    /// Changes the flush timeout, from the next wait of each worker
+    ///
+    /// As at startup, the flush timeout must stay shorter than the heartbeat interval. Stalled
+    /// blocks and slow destinations are detected after [RESYNC_FLUSH_TIMEOUTS] flush timeouts,
+    /// which also follow the new value.
+    pub fn set_flush_timeout(&self, flush_timeout: time::Duration) -> Result<(), Error> {
+        if flush_timeout.is_zero() {
+            return Err(Error::Config(vec![config::Error::Zero("flush_timeout")]));
+        }
+        if let Some(interval) = self.config.heartbeat_interval {
+            if interval <= flush_timeout {
+                return Err(Error::Config(vec![config::Error::TimeoutOrder(
+                    "flush_timeout",
+                    flush_timeout,
+                    "heartbeat_interval",
+                    interval,
+                )]));
+            }
+        }
+
+        log::info!("flush timeout set to {} ms", flush_timeout.as_millis());
+        self.tunables.set_flush_timeout(flush_timeout);
+        Ok(())
+    }
+
+    /// Returns `true` when no transfer to a destination is in progress and nothing is waiting in
+    /// the pipeline, so that the process can exit without interrupting a transfer
+    pub fn is_drained(&self) -> bool {
+        self.multiplex_control.in_use() == 0
+            && self.for_reblock.is_empty()
+            && self.for_decoding.is_empty()
+            && self.for_reordering.is_empty()
+            && self.for_dispatch.is_empty()
+    }
+
+    /// Status of the receiver as a single line JSON object, see [crate::status]
+    pub fn status_json(&self) -> String {
+        let mut status = status::Object::new("receive");
+        status
+            .field("transfers_active", self.multiplex_control.in_use())
+            .field("transfers_waiting", self.multiplex_control.waiters())
+            .field("queue_reblock", self.for_reblock.len())
+            .field("queue_decoding", self.for_decoding.len())
+            .field("queue_reordering", self.for_reordering.len())
+            .field("queue_dispatch", self.for_dispatch.len())
+            .field("flush_timeout_ms", self.flush_timeout().as_millis());
+        if let Some(site_id) = self.site_id.read().expect("acquire lock").as_deref() {
+            status.string("site_id", site_id);
+        }
+        status.finish("rx_", "rx_session_")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_mtu, wait_sender_config};
+    use crate::{
+        crypto, fec, loopback, metrics, protocol,
+        testing::{self, Diode, TempDir},
+    };
+    use std::{fs, net, sync, thread, time};
+
+    fn read_key(dir: &TempDir, secret: &[u8]) -> crypto::Key {
+        let path = dir.path().join("psk");
+        fs::write(&path, secret).expect("write key");
+        crypto::Key::read(&path).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn config_packet(mtu: u16) -> [u8; protocol::CONFIG_SIZE] {
+        protocol::write_config(&protocol::EncodingConfig {
+            version: protocol::PROTOCOL_VERSION,
+            read_from: protocol::PROTOCOL_VERSION,
+            features: protocol::Features::default().with(protocol::Features::ENCRYPTION, true),
+            fec: fec::Algorithm::RaptorQ,
+            mtu,
+            oti: protocol::object_transmission_information(
+                protocol::HeaderFormat::Narrow,
+                crypto::unsealed_mtu(mtu),
+                14_600,
+            ),
+            nb_repair_packets: 2,
+        })
+    }
+
+    #[test]
+    fn sender_config_authenticated() {
+        let dir = TempDir::new("sender-config");
+        let key = read_key(&dir, b"pre-shared key of the diode");
+        let other_key = read_key(&dir, b"another pre-shared key");
+
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let addr = socket.local_addr().expect("address");
+        let waiting = {
+            let key = key.clone();
+            thread::spawn(move || wait_sender_config(socket, &[], Some(&key)))
+        };
+
+        let unauthenticated = metrics::counter("rx_packets_unauthenticated");
+        let nb_unauthenticated = unauthenticated.get();
+        let sender = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let sealed = |key: &crypto::Key, packet: &[u8]| {
+            let mut sealed = Vec::new();
+            crypto::Sealer::new(key).seal(packet.len(), packet, &mut sealed);
+            sealed
+        };
+
+        // unsealed, sealed with another key, and tampered with
+        sender.send_to(&config_packet(1000), addr).expect("send");
+        sender
+            .send_to(&sealed(&other_key, &config_packet(1100)), addr)
+            .expect("send");
+        let mut forged = sealed(&key, &config_packet(1500));
+        forged[protocol::HEADER_SIZE + 6] = 0xe8;
+        forged[protocol::HEADER_SIZE + 7] = 0x04;
+        sender.send_to(&forged, addr).expect("send");
+        sender
+            .send_to(&sealed(&key, &config_packet(1500)), addr)
+            .expect("send");
+
+        let (_, config) = waiting
+            .join()
+            .expect("waiting thread")
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(config.mtu, 1500);
+        assert!(nb_unauthenticated + 3 <= unauthenticated.get());
+    }
+
+    /// Starts a diode whose sender address is returned, to announce protocol versions of other
+    /// senders to its receiver
+    fn diode(force_best_effort: bool) -> (Diode, net::SocketAddr) {
+        let (to_udp_sender, to_udp) = sync::mpsc::channel();
+        let diode = Diode::start(
+            testing::loopback_config(),
+            move |config| to_udp_sender.send(config.to_udp).expect("send address"),
+            move |config| config.force_best_effort = force_best_effort,
+        );
+        (diode, to_udp.recv().expect("receiver address"))
+    }
+
+    /// Announces the encoding parameters of the sender of `diode` as those of a sender of
+    /// protocol `version`, read by receivers from version `read_from`
+    fn announce(diode: &Diode, to_udp: net::SocketAddr, version: u8, read_from: u8) {
+        let config = protocol::EncodingConfig {
+            version,
+            read_from,
+            ..diode.sender.encoding_config()
+        };
+        net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0))
+            .expect("bind")
+            .send_to(&protocol::write_config(&config), to_udp)
+            .expect("send");
+    }
+
+    #[test]
+    fn sender_versions() {
+        let (diode, to_udp) = diode(false);
+        let refused = metrics::counter("rx_packets_refused");
+        let version = protocol::PROTOCOL_VERSION;
+
+        // an older sender, then a newer one announcing to be read by this version
+        for (version, read_from) in [(0, 0), (version + 1, version)] {
+            announce(&diode, to_udp, version, read_from);
+            let data = vec![version; 50_000];
+            diode.send(&data);
+            assert_eq!(
+                diode.receive(time::Duration::from_secs(10)),
+                Some(data),
+                "version {version}"
+            );
+        }
+
+        // a newer sender which cannot be read, refused until it announces a compatible version
+        let nb_refused = refused.get();
+        announce(&diode, to_udp, version + 1, version + 1);
+        diode.send(&[1; 50_000]);
+        assert_eq!(diode.receive(time::Duration::from_secs(1)), None);
+        assert!(nb_refused < refused.get());
+
+        announce(&diode, to_udp, version, version);
+        let data = vec![2; 50_000];
+        diode.send(&data);
+        assert_eq!(diode.receive(time::Duration::from_secs(10)), Some(data));
+    }
+
+    #[test]
+    fn sender_version_forced() {
+        let (diode, to_udp) = diode(true);
+        let version = protocol::PROTOCOL_VERSION;
+        announce(&diode, to_udp, version + 1, version + 1);
+        let data = vec![3; 50_000];
+        diode.send(&data);
+        assert_eq!(diode.receive(time::Duration::from_secs(10)), Some(data));
+    }
+
+    #[test]
+    fn larger_sender_mtu() {
+        let truncated = metrics::counter("rx_truncated_pkts");
+        let nb_truncated = truncated.get();
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |config| config.to_mtu = 9000,
+            |_| (),
+        );
+
+        diode.send(&[4; 50_000]);
+        assert_eq!(diode.receive(time::Duration::from_secs(1)), None);
+        assert!(nb_truncated + 5 < truncated.get());
+    }
+
+    #[test]
+    fn detected_mtu() {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let addr = socket.local_addr().expect("address");
+
+        // invalid datagrams are skipped, the packets of a sender announcing its configuration first
+        net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0))
+            .expect("bind")
+            .send_to(&[0; 7], addr)
+            .expect("send");
+        let diode = Diode::start(
+            loopback::Config {
+                mtu: 9000,
+                ..testing::loopback_config()
+            },
+            move |config| config.to_udp = addr,
+            |_| (),
+        );
+        diode.send(&[5; 50_000]);
+
+        let (socket, mtu) = detect_mtu(socket, protocol::HeaderFormat::Narrow, false, &[])
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(mtu, 9000);
+        // the packet the MTU was detected from is left to the receiver
+        let mut buffer = vec![0; usize::from(u16::MAX)];
+        let len = socket.recv(&mut buffer).expect("receive");
+        assert_eq!(len, 9000 - usize::from(protocol::PACKET_HEADER_SIZE));
+    }
+
+    #[test]
+    fn detected_mtu_ipv6() {
+        let Ok(socket) = net::UdpSocket::bind((net::Ipv6Addr::LOCALHOST, 0)) else {
+            eprintln!("no IPv6 loopback, skipped");
+            return;
+        };
+        let addr = socket.local_addr().expect("address");
+        net::UdpSocket::bind((net::Ipv6Addr::LOCALHOST, 0))
+            .expect("bind")
+            .send_to(&[0; 1468], addr)
+            .expect("send");
+
+        // packets of a sender with a 1500 bytes MTU over IPv4, carrying 20 more bytes of headers
+        // over IPv6, 1496 being the smallest MTU giving this packet size
+        let (_, mtu) = detect_mtu(socket, protocol::HeaderFormat::Narrow, false, &[])
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(mtu, 1496 + 20);
+    }
+}
@@ -0,0 +1,79 @@
+//! Pools of reusable buffers holding the payloads of the received packets and the decoded blocks
+//!
+//! The udp worker copies the payload of each datagram into a buffer leased from a [Pool]. Once
+//! not needed anymore, the buffer is released back to the pool, avoiding one allocation per
+//! packet at high packet rates: when the reblock worker discards a packet, when a decoder is done
+//! with the symbols of a block, and when a client worker has written a message. Pools are
+//! bounded, and allocate a buffer whenever they are empty.
+//!
+//! The RaptorQ decoder takes ownership of the packets it is given, so that their buffers are
+//! freed by it rather than released.
+
+use crate::metrics;
+use std::sync::Arc;
+
+/// A bounded pool of buffers of a given capacity
+pub(crate) struct Pool {
+    to_pool: crossbeam_channel::Sender<Vec<u8>>,
+    for_pool: crossbeam_channel::Receiver<Vec<u8>>,
+    buffer_size: usize,
+    allocated: Arc<metrics::Metric>,
+}
+
+impl Pool {
+    /// Creates an empty pool keeping up to `nb_buffers` buffers of `buffer_size` bytes, counting
+    /// the buffers it allocates in the `allocated` metric
+    pub(crate) fn new(nb_buffers: usize, buffer_size: usize, allocated: &str) -> Self {
+        let (to_pool, for_pool) = crossbeam_channel::bounded(nb_buffers);
+        Self {
+            to_pool,
+            for_pool,
+            buffer_size,
+            allocated: metrics::counter(allocated),
+        }
+    }
+
+    /// Returns an empty buffer of at least `buffer_size` bytes of capacity
+    pub(crate) fn lease(&self) -> Vec<u8> {
+        match self.for_pool.try_recv() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                buffer
+            }
+            Err(_) => {
+                self.allocated.inc();
+                Vec::with_capacity(self.buffer_size)
+            }
+        }
+    }
+
+    /// Returns a buffer holding a copy of `data`
+    pub(crate) fn copy(&self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = self.lease();
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    /// Gives `buffer` back to the pool, it is dropped if the pool is full or if the buffer is too
+    /// small to be leased again
+    pub(crate) fn release(&self, buffer: Vec<u8>) {
+        if self.buffer_size <= buffer.capacity() {
+            let _ = self.to_pool.try_send(buffer);
+        }
+    }
+
+    /// Gives the buffers of `packets` back to the pool
+    pub(crate) fn release_packets(&self, packets: Vec<raptorq::EncodingPacket>) {
+        for packet in packets {
+            self.release(packet.split().1);
+        }
+    }
+}
+
+/// Pools of the receiving pipeline
+pub(crate) struct Buffers {
+    /// Payloads of the received packets
+    pub(crate) packets: Pool,
+    /// Data of the decoded blocks, forwarded to clients as messages
+    pub(crate) blocks: Pool,
+}
@@ -1,7 +1,55 @@
 //! Worker for grouping packets according to their block numbers to handle potential UDP packets
 //! reordering
+//!
+//! Block trailers sent by the sender are not queued but tell how many packets of their block
+//! were emitted, so that packets lost on the link are told apart from packets the sender failed
+//! to emit.
+//!
+//! Narrow packet headers only carry the lowest byte of block identifiers, the block of their
+//! packets being taken as the closest one to the current block, see [protocol::BlockId::closest].
+//!
+//! When only packets of other blocks than the current one and its neighbours are received during
+//! [receive::RESYNC_FLUSH_TIMEOUTS] flush timeouts, for instance after the sender restarted while
+//! traffic kept flowing, the worker gives up the current block and resynchronizes on the block of
+//! the received packets.
+//!
+//! Packets whose symbol was already queued in their block, received again when several links
+//! or destinations carry the same traffic, are dropped and counted in `rx_packets_duplicate`, so
+//! that they are not taken for the packets the block misses.
 
-use crate::{protocol, receive};
+use crate::{
+    metrics, protocol, receive,
+    receive::{loss_report, watchdog},
+};
+use std::{
+    collections::HashSet,
+    mem,
+    sync::atomic::{AtomicU32, Ordering},
+    time,
+};
+
+/// Number of packets missing from each block when it was closed, indexed by block id, for the
+/// dispatch worker to account them to the session the block belongs to
+pub(crate) struct MissingPackets(Box<[AtomicU32]>);
+
+impl MissingPackets {
+    pub(crate) fn new() -> Self {
+        Self(
+            (0..protocol::BlockId::COUNT)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+        )
+    }
+
+    fn record(&self, block_id: protocol::BlockId, nb_packets: usize) {
+        self.0[block_id.index()].store(nb_packets as u32, Ordering::Relaxed);
+    }
+
+    /// Returns the number of packets missing from the block, resetting it
+    pub(crate) fn take(&self, block_id: protocol::BlockId) -> u32 {
+        self.0[block_id.index()].swap(0, Ordering::Relaxed)
+    }
+}
 
 pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::Error> {
     let nb_normal_packets = protocol::nb_encoding_packets(&receiver.object_transmission_info);
@@ -14,34 +62,71 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
     let capacity = nb_normal_packets as usize + nb_repair_packets as usize;
     let mut prev_queue: Option<Vec<raptorq::EncodingPacket>> = None;
     let mut queue = Vec::with_capacity(capacity);
-    let mut block_id = 0;
+    let mut block_id = protocol::BlockId::default();
+    // symbols of the packets in `queue` and `prev_queue`
+    let mut symbols = HashSet::with_capacity(capacity);
+    let mut prev_symbols = HashSet::with_capacity(capacity);
+
+    // packets which were sent but not received, from the wire or the local host, estimated from
+    // the blocks closed before all their packets were received
+    let missing_packets = metrics::counter("rx_packets_missing");
+    // packets which were not emitted by the sender, announced by block trailers
+    let unsent_packets = metrics::counter("rx_packets_unsent");
+    let duplicate_packets = metrics::counter("rx_packets_duplicate");
+    // packets of the current block emitted by the sender, once its trailer was received
+    let mut nb_emitted: Option<usize> = None;
+    // when a packet of the current block or of its neighbours was last received
+    let mut progress_at = time::Instant::now();
+    let mut loss_report = receiver.config.loss_report_interval.map(|interval| {
+        loss_report::LossReport::new(
+            interval,
+            receiver.config.fec,
+            &receiver.object_transmission_info,
+        )
+    });
 
     loop {
-        let packets = match receiver
-            .for_reblock
-            .recv_timeout(receiver.config.flush_timeout)
-        {
+        let flush_timeout = receiver.tunables.flush_timeout();
+        let resync_timeout = flush_timeout * receive::RESYNC_FLUSH_TIMEOUTS;
+        let packets = match receiver.for_reblock.recv_timeout(flush_timeout) {
             Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
                 let qlen = queue.len();
                 if 0 < qlen {
+                    if !receiver.config.low_latency {
+                        let nb_packets = nb_emitted.unwrap_or(capacity);
+                        let nb_missing = nb_packets.saturating_sub(qlen);
+                        missing_packets.add(nb_missing as u64);
+                        receiver.missing_packets.record(block_id, nb_missing);
+                        if let Some(loss_report) = &mut loss_report {
+                            loss_report.record(nb_packets, nb_missing);
+                        }
+                    }
                     // no more traffic but ongoing block, trying to decode
                     if nb_normal_packets as usize <= qlen {
                         log::debug!("flushing block {block_id} with {qlen} packets");
                         receiver.to_decoding.send((block_id, Some(queue)))?;
-                        block_id = block_id.wrapping_add(1);
+                        block_id = block_id.next();
                     } else {
                         log::debug!(
                             "not enough packets ({qlen} packets) to decode block {block_id}"
                         );
                         log::warn!("lost block {block_id}");
                         receiver.to_decoding.send((block_id, None))?;
+                        receiver.buffers.packets.release_packets(queue);
                         desynchro = true;
                     }
                     queue = Vec::with_capacity(capacity);
-                    prev_queue = None;
+                    symbols.clear();
+                    if let Some(pqueue) = prev_queue.take() {
+                        receiver.buffers.packets.release_packets(pqueue);
+                    }
+                    nb_emitted = None;
                 } else {
                     // without data for some time we reset the current block_id
                     desynchro = true;
+                    if let Some(loss_report) = &mut loss_report {
+                        loss_report.tick();
+                    }
                 }
                 continue;
             }
@@ -49,25 +134,76 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
             Ok(packet) => packet,
         };
 
-        for packet in packets {
-            let payload_id = packet.payload_id();
-            let message_block_id = payload_id.source_block_number();
+        watchdog::Progress::inc(&receiver.progress.reblock);
+
+        let now = time::Instant::now();
+        for (message_block_id, packet) in packets {
+            let message_block_id = match receiver.config.header_format {
+                protocol::HeaderFormat::Narrow => {
+                    block_id.closest(message_block_id.source_block_number())
+                }
+                protocol::HeaderFormat::Wide => message_block_id,
+            };
+
+            if let Some(nb_packets) = protocol::read_trailer(&packet) {
+                let nb_packets = nb_packets as usize;
+                if nb_packets < capacity {
+                    unsent_packets.add((capacity - nb_packets) as u64);
+                    log::error!(
+                        "sender only emitted {nb_packets} of the {capacity} packets of block {message_block_id}: packets were lost before reaching the link"
+                    );
+                }
+                if !desynchro && message_block_id == block_id {
+                    nb_emitted = Some(nb_packets);
+                }
+                receiver.buffers.packets.release(packet.split().1);
+                continue;
+            }
 
             if desynchro {
-                block_id = message_block_id;
-                receiver.resync_needed_block_id.store((true, block_id));
+                // after an idle period, traffic resuming with the expected block is not a
+                // synchronization loss
+                if message_block_id != block_id {
+                    block_id = message_block_id;
+                    receiver.resync_needed_block_id.store((true, block_id));
+                }
                 desynchro = false;
             }
 
+            let symbol_id = protocol::Header::from(packet.payload_id()).symbol_id;
+
             if message_block_id == block_id {
+                progress_at = now;
+                if !symbols.insert(symbol_id) {
+                    duplicate_packets.inc();
+                    receiver.buffers.packets.release(packet.split().1);
+                    continue;
+                }
                 log::trace!("queueing in block {block_id}");
                 queue.push(packet);
+                if receiver.config.low_latency && has_source_packets(&queue, nb_normal_packets) {
+                    // the block can be decoded, later repair packets being discarded
+                    log::trace!("delivering complete block {block_id}");
+                    receiver.to_decoding.send((block_id, Some(queue)))?;
+                    queue = Vec::with_capacity(capacity);
+                    symbols.clear();
+                    prev_queue = None;
+                    nb_emitted = None;
+                    block_id = block_id.next();
+                }
                 continue;
             }
 
-            if message_block_id.wrapping_add(1) == block_id {
+            if message_block_id.next() == block_id {
                 //packet is from previous block; is this block parked ?
+                progress_at = now;
                 if let Some(mut pqueue) = prev_queue {
+                    if !prev_symbols.insert(symbol_id) {
+                        duplicate_packets.inc();
+                        receiver.buffers.packets.release(packet.split().1);
+                        prev_queue = Some(pqueue);
+                        continue;
+                    }
                     pqueue.push(packet);
                     if nb_normal_packets as usize <= pqueue.len() {
                         //now there is enough packets to decode it
@@ -78,27 +214,73 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
                     } else {
                         prev_queue = Some(pqueue);
                     }
+                } else {
+                    receiver.buffers.packets.release(packet.split().1);
                 }
                 continue;
             }
 
-            if message_block_id != block_id.wrapping_add(1) {
-                log::warn!("discarding packet with block_id {message_block_id} (current block_id is {block_id})");
+            if message_block_id != block_id.next() {
+                if now.duration_since(progress_at) < resync_timeout {
+                    log::warn!("discarding packet with block_id {message_block_id} (current block_id is {block_id})");
+                    receiver.buffers.packets.release(packet.split().1);
+                    continue;
+                }
+                log::error!(
+                    "no packet of block {block_id} received for {} ms, lost block {block_id}, resynchronizing on block {message_block_id}",
+                    now.duration_since(progress_at).as_millis()
+                );
+                receiver.to_decoding.send((block_id, None))?;
+                receiver
+                    .buffers
+                    .packets
+                    .release_packets(mem::replace(&mut queue, Vec::with_capacity(capacity)));
+                symbols.clear();
+                if let Some(pqueue) = prev_queue.take() {
+                    receiver.buffers.packets.release_packets(pqueue);
+                }
+                nb_emitted = None;
+                block_id = message_block_id;
+                receiver.resync_needed_block_id.store((true, block_id));
+                progress_at = now;
+                log::trace!("queueing in block {block_id}");
+                symbols.insert(symbol_id);
+                queue.push(packet);
                 continue;
             }
 
             //this is the first packet of the next block
+            progress_at = now;
+
+            if !receiver.config.low_latency {
+                let nb_packets = nb_emitted.unwrap_or(capacity);
+                let nb_missing = nb_packets.saturating_sub(queue.len());
+                missing_packets.add(nb_missing as u64);
+                receiver.missing_packets.record(block_id, nb_missing);
+                if let Some(loss_report) = &mut loss_report {
+                    loss_report.record(nb_packets, nb_missing);
+                }
+            }
+            nb_emitted = None;
 
+            // a parked block which did not get enough packets is lost, which must be told for
+            // the following blocks not to wait for it
             if nb_normal_packets as usize <= queue.len() {
                 //enough packets in the current block to decode it
-                receiver.to_decoding.send((block_id, Some(queue)))?;
-                if prev_queue.is_some() {
-                    log::warn!("lost block {}", block_id.wrapping_sub(1));
+                if let Some(pqueue) = prev_queue.take() {
+                    log::warn!("lost block {}", block_id.prev());
+                    receiver.to_decoding.send((block_id.prev(), None))?;
+                    receiver.buffers.packets.release_packets(pqueue);
                 }
-                prev_queue = None;
+                receiver.to_decoding.send((block_id, Some(queue)))?;
             } else {
                 //not enough packet, parking the current block
-                prev_queue = Some(queue);
+                mem::swap(&mut symbols, &mut prev_symbols);
+                if let Some(pqueue) = prev_queue.replace(queue) {
+                    log::warn!("lost block {}", block_id.prev());
+                    receiver.to_decoding.send((block_id.prev(), None))?;
+                    receiver.buffers.packets.release_packets(pqueue);
+                }
             }
 
             //starting the next block
@@ -107,7 +289,139 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
 
             log::trace!("queueing in block {block_id}");
             queue = Vec::with_capacity(capacity);
+            symbols.clear();
+            symbols.insert(symbol_id);
             queue.push(packet);
         }
     }
 }
+
+/// Whether `queue` holds the `nb_normal_packets` source packets of its block
+fn has_source_packets(queue: &[raptorq::EncodingPacket], nb_normal_packets: u64) -> bool {
+    nb_normal_packets as usize <= queue.len()
+        && queue
+            .iter()
+            .filter(|packet| {
+                u64::from(protocol::Header::from(packet.payload_id()).symbol_id) < nb_normal_packets
+            })
+            .count()
+            == nb_normal_packets as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{loopback, metrics, protocol, receive, testing};
+    use std::{io, net, thread, time};
+
+    const FORMAT: protocol::HeaderFormat = protocol::HeaderFormat::Narrow;
+
+    /// Packet read by an udp worker from `datagram`
+    fn read(datagram: &[u8]) -> (protocol::BlockId, raptorq::EncodingPacket) {
+        let (header, payload) =
+            protocol::read_packet(FORMAT, datagram).unwrap_or_else(|e| panic!("{e}"));
+        (
+            header.block_id,
+            raptorq::EncodingPacket::new(header.into(), payload.to_vec()),
+        )
+    }
+
+    fn packet(block_id: u16, symbol_id: u32) -> (protocol::BlockId, raptorq::EncodingPacket) {
+        let header = protocol::Header {
+            block_id: block_id.into(),
+            symbol_id,
+            compressed: false,
+        };
+        let mut datagram = [0; 1500];
+        let len = protocol::write_packet(FORMAT, &header, &[0; 1400], &mut datagram)
+            .unwrap_or_else(|e| panic!("{e}"));
+        read(&datagram[..len])
+    }
+
+    fn trailer(block_id: u16, nb_packets: u32) -> (protocol::BlockId, raptorq::EncodingPacket) {
+        read(&protocol::write_trailer(
+            FORMAT,
+            block_id.into(),
+            nb_packets,
+        ))
+    }
+
+    #[test]
+    fn trailer_loss_attribution() {
+        let addr = net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 5000));
+        let mut config = loopback::receive_config(&testing::loopback_config(), addr, None);
+        // blocks are closed by the first packet of the next one only
+        config.flush_timeout = time::Duration::from_secs(60);
+        config.heartbeat_interval = None;
+        config.repair_block_size = 6000;
+        let receiver = receive::Receiver::new(config, |_| -> Result<net::TcpStream, io::Error> {
+            Err(io::Error::other("no destination"))
+        })
+        .unwrap_or_else(|e| panic!("{e}"));
+        // left running until the end of the tests, like the workers of a diode
+        let receiver: &'static _ = Box::leak(Box::new(receiver));
+        thread::spawn(move || super::start(receiver));
+
+        let nb_normal_packets =
+            protocol::nb_encoding_packets(&receiver.object_transmission_info) as u32;
+        let capacity = nb_normal_packets
+            + protocol::nb_repair_packets(
+                &receiver.object_transmission_info,
+                receiver.config.repair_block_size,
+            );
+        assert!(nb_normal_packets + 2 <= capacity);
+        let source_packets = |block_id: u16| {
+            (0..nb_normal_packets)
+                .map(|symbol_id| packet(block_id, symbol_id))
+                .collect::<Vec<_>>()
+        };
+        let decoded = |block_id: u16| {
+            let (decoded, packets) = receiver
+                .for_decoding
+                .recv_timeout(time::Duration::from_secs(5))
+                .expect("block closed");
+            assert_eq!(decoded, protocol::BlockId::from(block_id));
+            assert_eq!(
+                packets.map(|packets| packets.len()),
+                Some(nb_normal_packets as usize)
+            );
+        };
+        let unsent = metrics::counter("rx_packets_unsent");
+
+        // every packet emitted, the repair packets being lost on the wire
+        let nb_unsent = unsent.get();
+        let mut packets = source_packets(0);
+        packets.push(trailer(0, capacity));
+        packets.push(packet(1, 0));
+        receiver.to_reblock.send(packets).expect("reblock");
+        decoded(0);
+        assert_eq!(
+            receiver.missing_packets.take(0.into()),
+            capacity - nb_normal_packets
+        );
+        assert_eq!(unsent.get(), nb_unsent);
+
+        // the repair packets not emitted by the sender, none lost on the wire
+        let mut packets = source_packets(1).split_off(1);
+        packets.push(trailer(1, nb_normal_packets));
+        packets.push(packet(2, 0));
+        receiver.to_reblock.send(packets).expect("reblock");
+        decoded(1);
+        assert_eq!(receiver.missing_packets.take(1.into()), 0);
+        let nb_repair_packets = u64::from(capacity - nb_normal_packets);
+        assert_eq!(unsent.get(), nb_unsent + nb_repair_packets);
+
+        // both, a packet emitted by the sender being lost on the wire
+        let mut packets = source_packets(2).split_off(1);
+        packets.push(packet(2, nb_normal_packets));
+        packets.push(trailer(2, nb_normal_packets + 2));
+        packets.push(packet(3, 0));
+        receiver.to_reblock.send(packets).expect("reblock");
+        let (decoded, _) = receiver
+            .for_decoding
+            .recv_timeout(time::Duration::from_secs(5))
+            .expect("block closed");
+        assert_eq!(decoded, protocol::BlockId::from(2));
+        assert_eq!(receiver.missing_packets.take(2.into()), 1);
+        assert_eq!(unsent.get(), nb_unsent + 2 * nb_repair_packets - 2);
+    }
+}
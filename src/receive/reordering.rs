@@ -1,30 +1,210 @@
 //! Worker that reorders received messages according to block numbers
+//!
+//! A block which is never received, because it was lost or could not be decoded, would hold back
+//! all the following ones: once messages wait for more than [receive::RESYNC_FLUSH_TIMEOUTS]
+//! flush timeouts, the worker gives up on the expected block and skips forward to the first
+//! block received after it, propagating a synchronization loss so that active transfers fail.
+//!
+//! With [receive::Config::reorder_max_bytes], the worker also skips forward as soon as the
+//! messages received ahead of the expected block exceed this size, dropping the oldest block
+//! instead of buffering messages without bound under pathological reordering.
 
-use crate::receive;
+use crate::{metrics, protocol, receive, receive::watchdog};
+use std::time;
+
+/// Messages received ahead of the expected block, indexed by block id
+///
+/// The entries holding a message are marked in a bitmap, so that clearing only visits its
+/// [protocol::BlockId::COUNT] / 64 words and the pending messages instead of the whole table,
+/// and synchronization losses are handled without stalling the reception of packets. Messages
+/// are dropped as soon as they are cleared, [Pending::bytes] always accounting for every message
+/// held.
+struct Pending {
+    len: usize,
+    /// Size of the pending messages
+    bytes: usize,
+    entries: Box<[Option<protocol::Message>]>,
+    /// Bit `index % 64` of word `index / 64` set when entry `index` holds a message
+    occupied: Box<[u64]>,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            len: 0,
+            bytes: 0,
+            entries: (0..protocol::BlockId::COUNT).map(|_| None).collect(),
+            occupied: vec![0; protocol::BlockId::COUNT.div_ceil(64)].into_boxed_slice(),
+        }
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    const fn len(&self) -> usize {
+        self.len
+    }
+
+    const fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn clear(&mut self) {
+        for (word_index, word) in self.occupied.iter_mut().enumerate() {
+            while *word != 0 {
+                let bit = word.trailing_zeros() as usize;
+                *word &= *word - 1;
+                self.entries[word_index * 64 + bit] = None;
+            }
+        }
+        self.len = 0;
+        self.bytes = 0;
+    }
+
+    fn contains(&self, block_id: protocol::BlockId) -> bool {
+        self.entries[block_id.index()].is_some()
+    }
+
+    fn take(&mut self, block_id: protocol::BlockId) -> Option<protocol::Message> {
+        let index = block_id.index();
+        let message = self.entries[index].take()?;
+        self.occupied[index / 64] &= !(1 << (index % 64));
+        self.len -= 1;
+        self.bytes -= message.serialized().len();
+        Some(message)
+    }
+
+    /// First block following `block_id` with a pending message
+    fn first_after(&self, block_id: protocol::BlockId) -> Option<protocol::BlockId> {
+        let mut next = block_id.next();
+        while next != block_id {
+            if self.contains(next) {
+                return Some(next);
+            }
+            next = next.next();
+        }
+        None
+    }
+
+    fn replace(
+        &mut self,
+        block_id: protocol::BlockId,
+        message: protocol::Message,
+    ) -> Option<protocol::Message> {
+        let index = block_id.index();
+        self.bytes += message.serialized().len();
+        let previous = self.entries[index].replace(message);
+        match &previous {
+            None => {
+                self.occupied[index / 64] |= 1 << (index % 64);
+                self.len += 1;
+            }
+            Some(previous) => self.bytes -= previous.serialized().len(),
+        }
+        previous
+    }
+}
+
+/// Gives up the expected block `block_to_receive`, propagating a synchronization loss, and
+/// sends the messages pending from the first block received after it, returning the new
+/// expected block
+fn skip_forward<F>(
+    receiver: &receive::Receiver<F>,
+    pending_messages: &mut Pending,
+    block_to_receive: protocol::BlockId,
+) -> Result<protocol::BlockId, receive::Error> {
+    let mut block_to_receive = pending_messages
+        .first_after(block_to_receive)
+        .expect("pending message");
+    receiver.to_dispatch.send(None)?;
+    while let Some(message) = pending_messages.take(block_to_receive) {
+        receiver
+            .to_dispatch
+            .send(Some((block_to_receive, message)))?;
+        block_to_receive = block_to_receive.next();
+    }
+    Ok(block_to_receive)
+}
+
+/// Upper bounds, in microseconds, of the buckets of the control operations duration histogram
+const CONTROL_TIME_BUCKETS: &[u64] = &[1, 10, 100, 1_000, 10_000];
 
 pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::Error> {
-    let mut block_to_receive = 0;
-    let mut pending_messages = [const { None }; u8::MAX as usize + 1];
+    let mut block_to_receive = protocol::BlockId::default();
+    let mut pending_messages = Pending::new();
+
+    let control_time = metrics::histogram("rx_reorder_control_microseconds", CONTROL_TIME_BUCKETS);
+    // messages received ahead of the expected block
+    let queue_depth = metrics::gauge("rx_reorder_queue_depth");
+    let buffered_bytes = metrics::gauge("rx_reorder_buffered_bytes");
+    let evictions = metrics::counter("rx_reorder_evictions");
+    let max_bytes = receiver.config.reorder_max_bytes;
+    // blocks closed by the reblock worker but not yet given to the dispatch worker
+    let in_flight = metrics::gauge("rx_blocks_in_flight");
+    let clear = |pending_messages: &mut Pending| {
+        let start = time::Instant::now();
+        pending_messages.clear();
+        control_time.observe(start.elapsed().as_micros() as u64);
+    };
+
+    let resyncs = metrics::counter("rx_resync");
+    // block expected while messages are pending, and since when
+    let mut stalled = (block_to_receive, time::Instant::now());
 
     loop {
-        let (block_id, message) = receiver.for_reordering.recv()?;
+        let resync_timeout = receiver.tunables.flush_timeout() * receive::RESYNC_FLUSH_TIMEOUTS;
+        if pending_messages.is_empty() || stalled.0 != block_to_receive {
+            stalled = (block_to_receive, time::Instant::now());
+        } else if resync_timeout <= stalled.1.elapsed() {
+            let next = pending_messages
+                .first_after(block_to_receive)
+                .expect("pending message");
+            log::error!(
+                "block {block_to_receive} still missing after {} ms, skipping forward to block {next}, synchronization lost",
+                resync_timeout.as_millis()
+            );
+            resyncs.inc();
+            block_to_receive = skip_forward(receiver, &mut pending_messages, block_to_receive)?;
+            continue;
+        }
+
+        let (block_id, message) = match receiver.for_reordering.recv_timeout(resync_timeout) {
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
+            other => other?,
+        };
+        queue_depth.set(pending_messages.len() as u64);
+        buffered_bytes.set(pending_messages.bytes() as u64);
+        in_flight.set(
+            (receiver.for_decoding.len() + receiver.for_reordering.len() + pending_messages.len())
+                as u64,
+        );
+
+        watchdog::Progress::inc(&receiver.progress.reordering);
 
-        if message.is_none() {
+        let Some(message) = message else {
             // Synchronization lost, dropping everything
             log::warn!("synchronization lost received, dropping everything, propagating it");
-            pending_messages.fill_with(|| None);
+            clear(&mut pending_messages);
             receiver.to_dispatch.send(None)?;
+            // the lost block will not be received, expecting the following one
+            if block_id == block_to_receive {
+                block_to_receive = block_to_receive.next();
+            }
             continue;
-        }
+        };
+
+        receiver.decode_capacity.record_arrival();
 
         let (resync_needed, resync_block_id) = receiver.resync_needed_block_id.take();
 
         if resync_needed {
             log::debug!("forced resynchronization, propagating it");
+            resyncs.inc();
             receiver.to_dispatch.send(None)?;
-            if pending_messages.iter().any(Option::is_some) {
+            if !pending_messages.is_empty() {
                 log::warn!("forced resynchronization with pending messages, dropping everything");
-                pending_messages.fill_with(|| None);
+                clear(&mut pending_messages);
             }
             block_to_receive = resync_block_id;
         }
@@ -32,32 +212,217 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
         log::debug!("received block {block_id}, expecting block {block_to_receive}");
 
         if block_to_receive == block_id {
-            let message = if pending_messages[block_to_receive as usize].is_some() {
+            let message = if pending_messages.contains(block_to_receive) {
                 // a message was already pending
                 // using the old one, storing the newly received one
-                pending_messages[block_to_receive as usize]
-                    .replace(message)
+                pending_messages
+                    .replace(block_to_receive, message)
                     .expect("infallible")
             } else {
                 // no message was pending, using the newly received one
                 message
             };
 
-            receiver.to_dispatch.send(message)?;
-            block_to_receive = block_to_receive.wrapping_add(1);
+            receiver
+                .to_dispatch
+                .send(Some((block_to_receive, message)))?;
+            block_to_receive = block_to_receive.next();
 
             // flushing as much as possible further pending blocks
-            while let Some(message) = pending_messages[block_to_receive as usize].take() {
-                receiver.to_dispatch.send(message)?;
-                block_to_receive = block_to_receive.wrapping_add(1);
-            }
-        } else if pending_messages[block_id as usize]
-            .replace(message)
-            .is_some()
-        {
-            log::error!("received a new block {block_id} but existing one was not sent to dispatch, synchronization lost, dropping everything");
-            pending_messages.fill_with(|| None);
+            while let Some(message) = pending_messages.take(block_to_receive) {
+                receiver
+                    .to_dispatch
+                    .send(Some((block_to_receive, message)))?;
+                block_to_receive = block_to_receive.next();
+            }
+        } else if pending_messages.replace(block_id, message).is_some() {
+            // the expected block is a whole round of block ids late, it will not be received
+            log::error!("received a new block {block_id} but existing one was not sent to dispatch, synchronization lost, dropping everything and skipping forward to it");
+            let message = pending_messages.take(block_id).expect("replaced message");
+            clear(&mut pending_messages);
+            resyncs.inc();
             receiver.to_dispatch.send(None)?;
+            receiver.to_dispatch.send(Some((block_id, message)))?;
+            block_to_receive = block_id.next();
+        }
+
+        while 0 < max_bytes && max_bytes < pending_messages.bytes() as u64 {
+            let next = pending_messages
+                .first_after(block_to_receive)
+                .expect("pending message");
+            log::error!(
+                "{} bytes received ahead of missing block {block_to_receive} exceed the {max_bytes} bytes limit, dropping it and skipping forward to block {next}, synchronization lost",
+                pending_messages.bytes()
+            );
+            evictions.inc();
+            resyncs.inc();
+            block_to_receive = skip_forward(receiver, &mut pending_messages, block_to_receive)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pending;
+    use crate::protocol;
+    use std::{mem, time};
+
+    fn message(len: usize) -> protocol::Message {
+        protocol::Message::new(
+            protocol::MessageType::Data,
+            len as u32,
+            1,
+            Some(&vec![0x5a; len]),
+        )
+    }
+
+    /// Size of the messages actually held by `pending`
+    fn held(pending: &Pending) -> usize {
+        pending
+            .entries
+            .iter()
+            .flatten()
+            .map(|message| message.serialized().len())
+            .sum()
+    }
+
+    #[test]
+    fn replace_and_take() {
+        let mut pending = Pending::new();
+        let block_id = protocol::BlockId::from(7);
+        assert!(pending.replace(block_id, message(100)).is_none());
+        assert!(pending.contains(block_id));
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.bytes(), held(&pending));
+
+        // a duplicate replaces the message without being counted twice
+        let previous = pending.replace(block_id, message(300)).expect("previous");
+        assert_eq!(previous.serialized(), message(100).serialized());
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.bytes(), held(&pending));
+
+        let taken = pending.take(block_id).expect("message");
+        assert_eq!(taken.serialized(), message(300).serialized());
+        assert!(!pending.contains(block_id));
+        assert!(pending.take(block_id).is_none());
+        assert!(pending.is_empty());
+        assert_eq!(pending.bytes(), 0);
+        assert!(pending.occupied.iter().all(|word| *word == 0));
+    }
+
+    #[test]
+    fn first_after_wraps() {
+        let mut pending = Pending::new();
+        assert_eq!(pending.first_after(protocol::BlockId::default()), None);
+        pending.replace(protocol::BlockId::from(u16::MAX), message(10));
+        pending.replace(protocol::BlockId::from(2), message(10));
+        assert_eq!(
+            pending.first_after(protocol::BlockId::from(u16::MAX - 1)),
+            Some(protocol::BlockId::from(u16::MAX))
+        );
+        assert_eq!(
+            pending.first_after(protocol::BlockId::from(u16::MAX)),
+            Some(protocol::BlockId::from(2))
+        );
+        assert_eq!(
+            pending.first_after(protocol::BlockId::from(2)),
+            Some(protocol::BlockId::from(u16::MAX))
+        );
+    }
+
+    #[test]
+    fn clear_drops_every_message() {
+        let mut pending = Pending::new();
+        // entries in the first and last words of the bitmap, and several in the same word
+        for id in [0, 1, 63, 64, 1000, 1001, u16::MAX - 1, u16::MAX] {
+            pending.replace(protocol::BlockId::from(id), message(50));
+        }
+        assert_eq!(pending.len(), 8);
+        pending.clear();
+        assert!(pending.is_empty());
+        assert_eq!(pending.bytes(), 0);
+        assert!(pending.entries.iter().all(Option::is_none));
+        assert!(pending.occupied.iter().all(|word| *word == 0));
+
+        // the table is usable again after being cleared
+        pending.replace(protocol::BlockId::from(63), message(50));
+        assert_eq!(
+            pending.first_after(protocol::BlockId::default()),
+            Some(protocol::BlockId::from(63))
+        );
+        assert_eq!(pending.bytes(), held(&pending));
+    }
+
+    #[test]
+    fn cap_across_resync() {
+        // as done by the worker: messages are received ahead of missing block 0, the oldest
+        // pending block being dropped when their size exceeds the cap, and synchronization is
+        // lost from time to time
+        let max_bytes = 64 * 1024;
+        let mut pending = Pending::new();
+        let mut block_id = protocol::BlockId::default();
+        for round in 0..20 {
+            for _ in 0..100 {
+                block_id = block_id.next();
+                pending.replace(block_id, message(1000));
+                while max_bytes < pending.bytes() {
+                    let oldest = pending
+                        .first_after(protocol::BlockId::default())
+                        .expect("pending message");
+                    pending.take(oldest);
+                }
+                assert_eq!(held(&pending), pending.bytes());
+                assert!(held(&pending) <= max_bytes, "round {round}");
+            }
+            pending.clear();
+            assert!(pending.is_empty());
+            assert_eq!(held(&pending), 0);
+        }
+    }
+
+    /// Duration of the handling of a synchronization loss, as on the reception of an Init message
+    /// after a sender restart, with blocks of 10 packets at 9000 bytes MTU, compared with a
+    /// sweep of the whole table as done before the occupied entries were tracked
+    ///
+    /// Run with `cargo test --release reordering::tests::init_latency -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn init_latency() {
+        const MESSAGE_LEN: usize = 10 * (9000 - 20 - 8 - protocol::HEADER_SIZE);
+        const ROUNDS: u32 = 20;
+
+        let mut pending = Pending::new();
+        println!(
+            "table: {} bytes, whatever the number of pending messages",
+            mem::size_of_val(&*pending.entries) + mem::size_of_val(&*pending.occupied)
+        );
+        for nb_pending in [0, 1, 16, 256] {
+            let mut tracked = time::Duration::ZERO;
+            let mut sweep = time::Duration::ZERO;
+            for _ in 0..ROUNDS {
+                for id in 0..nb_pending {
+                    pending.replace(protocol::BlockId::from(id), message(MESSAGE_LEN));
+                }
+                let start = time::Instant::now();
+                pending.clear();
+                tracked += start.elapsed();
+                assert_eq!(held(&pending), 0);
+
+                for id in 0..nb_pending {
+                    pending.replace(protocol::BlockId::from(id), message(MESSAGE_LEN));
+                }
+                let start = time::Instant::now();
+                pending.entries.iter_mut().for_each(|entry| *entry = None);
+                sweep += start.elapsed();
+                pending.occupied.fill(0);
+                pending.len = 0;
+                pending.bytes = 0;
+            }
+            println!(
+                "{nb_pending:>3} pending message(s) of {MESSAGE_LEN} bytes: {:>6} µs tracked, {:>6} µs sweep",
+                (tracked / ROUNDS).as_micros(),
+                (sweep / ROUNDS).as_micros()
+            );
         }
     }
 }
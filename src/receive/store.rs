@@ -0,0 +1,227 @@
+//! Buffering of the data of a session until it ends, for store and forward delivery
+//!
+//! With [receive::StoreAndForward], clients workers keep the payloads of a session in a
+//! [Store] instead of writing them to the destination, and only replay them once the end of the
+//! session was received: the destination never sees a byte of sessions which are aborted.
+//!
+//! On disk, payloads are appended to a spool file removed from its directory as soon as it is
+//! created, so that no spool file outlives the process. Each payload is recorded with the
+//! sequence number of its block (8 bytes) and its length (4 bytes), in little-endian byte order.
+
+use crate::{protocol, receive};
+use std::{
+    fs,
+    io::{self, Read, Seek, Write},
+    path, process, time,
+};
+
+enum Buffer {
+    Memory(Vec<(u64, Vec<u8>)>),
+    Disk(io::BufWriter<fs::File>),
+}
+
+pub(crate) struct Store {
+    buffer: Buffer,
+    max_bytes: u64,
+    len: u64,
+    since: time::Instant,
+}
+
+impl Store {
+    pub(crate) fn new(
+        mode: &receive::StoreAndForward,
+        max_bytes: u64,
+        client_id: protocol::ClientId,
+    ) -> Result<Self, io::Error> {
+        let buffer = match mode {
+            receive::StoreAndForward::Memory => Buffer::Memory(Vec::new()),
+            receive::StoreAndForward::Disk(dir) => {
+                Buffer::Disk(io::BufWriter::new(spool_file(dir, client_id)?))
+            }
+        };
+        Ok(Self {
+            buffer,
+            max_bytes,
+            len: 0,
+            since: time::Instant::now(),
+        })
+    }
+
+    /// Whether a payload of `len` bytes can be pushed without exceeding the size of the store
+    pub(crate) const fn fits(&self, len: usize) -> bool {
+        self.len + len as u64 <= self.max_bytes
+    }
+
+    /// Appends the payload of the block `block_seq`, which must [fit](Store::fits) in the store
+    pub(crate) fn push(&mut self, block_seq: u64, payload: &[u8]) -> Result<(), io::Error> {
+        self.len += payload.len() as u64;
+        match &mut self.buffer {
+            Buffer::Memory(payloads) => payloads.push((block_seq, payload.to_vec())),
+            Buffer::Disk(file) => {
+                file.write_all(&block_seq.to_le_bytes())?;
+                file.write_all(&(payload.len() as u32).to_le_bytes())?;
+                file.write_all(payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of bytes of payload held
+    pub(crate) const fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Time elapsed since the store was created
+    pub(crate) fn held(&self) -> time::Duration {
+        self.since.elapsed()
+    }
+
+    /// Calls `deliver` with every payload, in the order they were pushed
+    pub(crate) fn replay<D, E>(self, mut deliver: D) -> Result<(), E>
+    where
+        D: FnMut(u64, &[u8]) -> Result<(), E>,
+        E: From<io::Error>,
+    {
+        match self.buffer {
+            Buffer::Memory(payloads) => {
+                for (block_seq, payload) in payloads {
+                    deliver(block_seq, &payload)?;
+                }
+            }
+            Buffer::Disk(file) => {
+                let mut file = file.into_inner().map_err(io::IntoInnerError::into_error)?;
+                file.rewind()?;
+                let mut file = io::BufReader::new(file);
+                let mut payload = Vec::new();
+                loop {
+                    let mut record = [0; 12];
+                    match file.read_exact(&mut record) {
+                        Ok(()) => (),
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                    let (block_seq, len) = record.split_at(8);
+                    let block_seq = u64::from_le_bytes(block_seq.try_into().expect("8 bytes"));
+                    let len = u32::from_le_bytes(len.try_into().expect("4 bytes"));
+                    payload.resize(len as usize, 0);
+                    file.read_exact(&mut payload)?;
+                    deliver(block_seq, &payload)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a spool file in `dir` and removes it from the directory, the file living as long as
+/// it is open
+fn spool_file(dir: &path::Path, client_id: protocol::ClientId) -> Result<fs::File, io::Error> {
+    let path = dir.join(format!(
+        ".diode-receive-{}-{client_id:x}.spool",
+        process::id()
+    ));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        metrics, quarantine, receive,
+        send::scan::RegexDeny,
+        testing::{self, Diode, TempDir},
+    };
+    use std::{fs, io::Write, thread, time};
+
+    fn store_and_forward(
+        mode: receive::StoreAndForward,
+        quarantine_dir: Option<&TempDir>,
+    ) -> impl FnOnce(&mut receive::Config) + Send + 'static {
+        let quarantine_dir = quarantine_dir.map(|dir| dir.path().to_path_buf());
+        move |config| {
+            config.store_and_forward = Some(mode);
+            config.store_and_forward_max_bytes = 1024 * 1024;
+            config.quarantine_max_bytes = 1024 * 1024;
+            config.quarantine_dir = quarantine_dir;
+        }
+    }
+
+    #[test]
+    fn completed_session() {
+        let spool = TempDir::new("store-spool");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        for mode in [
+            receive::StoreAndForward::Memory,
+            receive::StoreAndForward::Disk(spool.path().to_path_buf()),
+        ] {
+            let diode = Diode::start(
+                testing::loopback_config(),
+                |_| (),
+                store_and_forward(mode, None),
+            );
+            // nothing is delivered until the session ends
+            let mut client = diode.connect();
+            client.write_all(&data).expect("send data");
+            assert!(diode.receive(time::Duration::from_millis(500)).is_none());
+            drop(client);
+
+            assert_eq!(
+                diode.receive(time::Duration::from_secs(10)).as_deref(),
+                Some(&data[..])
+            );
+            assert!(diode.receive(time::Duration::from_millis(500)).is_none());
+            assert_eq!(fs::read_dir(spool.path()).expect("spool").count(), 0);
+        }
+    }
+
+    #[test]
+    fn aborted_session() {
+        let quarantine_dir = TempDir::new("store-quarantine");
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |config| {
+                config.scan_policies = vec![Box::new(
+                    RegexDeny::new(&["secret-\\d+".to_string()], 64)
+                        .unwrap_or_else(|e| panic!("{e}")),
+                )];
+            },
+            store_and_forward(receive::StoreAndForward::Memory, Some(&quarantine_dir)),
+        );
+
+        // each write is sent in its own block after the flush timeout
+        let mut client = diode.connect();
+        for data in [&b"public data, "[..], b"secret-", b"2506"] {
+            client.write_all(data).expect("send data");
+            thread::sleep(time::Duration::from_millis(300));
+        }
+        drop(client);
+
+        // counted once the file is written
+        let quarantined = metrics::counter("rx_quarantined_sessions");
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        while quarantined.get() == 0 {
+            assert!(time::Instant::now() < deadline, "session not quarantined");
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let entries: Vec<_> = fs::read_dir(quarantine_dir.path())
+            .expect("quarantine directory")
+            .map(|entry| entry.expect("entry").path())
+            .collect();
+        let [entry] = &entries[..] else {
+            panic!("{entries:?} quarantined");
+        };
+        assert!(entry
+            .extension()
+            .is_some_and(|ext| ext == quarantine::SESSION_EXTENSION));
+        let held = fs::read(entry).expect("quarantined session");
+        assert!(held.starts_with(b"public data, "), "{held:?}");
+        assert!(!held.ends_with(b"2506"), "{held:?}");
+
+        assert!(diode.receive(time::Duration::from_secs(1)).is_none());
+    }
+}
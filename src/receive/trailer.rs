@@ -0,0 +1,145 @@
+//! Trailer written by the receiver at the end of the output of each session
+//!
+//! A destination only seeing its connection being closed cannot tell a completed session from an
+//! aborted one. When enabled, the receiver writes a fixed size trailer after the last data of
+//! every session, with the following representation:
+//!
+//! ```text
+//!
+//! <- 4 bytes -> <- 4 bytes -> <- 8 bytes -> <- 1 byte -> <- 1 byte -> <---- 32 bytes ---->
+//! ------------+-------------+-------------+------------+-------------+--------------------
+//! |           |             |             |            |             |                  |
+//! |  "LDTR"   |  client_id  |    bytes    |   status   |  algorithm  |      digest      |
+//! |           |             |             |            |             |                  |
+//! ------------+-------------+-------------+------------+-------------+--------------------
+//!
+//! ```
+//!
+//! Integers are encoded in little-endian byte order. `bytes` is the number of bytes of the session
+//! written before the trailer, `status` is 0 for a completed session and 1 for an aborted one.
+//! `algorithm` identifies the hash algorithm of the session data (see
+//! [crate::aux::file::hash::Algorithm::id]) and `digest` holds its digest, padded with zeros.
+
+use crate::{aux::file::hash, protocol};
+
+pub const MAGIC: [u8; 4] = *b"LDTR";
+
+/// Size in bytes of a serialized trailer
+pub const SIZE: usize = 4 + 4 + 8 + 1 + 1 + DIGEST_SIZE;
+
+const DIGEST_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Completed,
+    Aborted,
+}
+
+impl Status {
+    const fn id(self) -> u8 {
+        match self {
+            Self::Completed => 0,
+            Self::Aborted => 1,
+        }
+    }
+
+    const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Completed),
+            1 => Some(Self::Aborted),
+            _ => None,
+        }
+    }
+}
+
+pub struct Trailer {
+    pub client_id: protocol::ClientId,
+    pub bytes: u64,
+    pub status: Status,
+    pub algorithm: hash::Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Trailer {
+    pub fn serialize(&self) -> [u8; SIZE] {
+        let mut trailer = [0; SIZE];
+        trailer[0..4].copy_from_slice(&MAGIC);
+        trailer[4..8].copy_from_slice(&self.client_id.to_le_bytes());
+        trailer[8..16].copy_from_slice(&self.bytes.to_le_bytes());
+        trailer[16] = self.status.id();
+        trailer[17] = self.algorithm.id();
+        let len = self.digest.len().min(DIGEST_SIZE);
+        trailer[18..18 + len].copy_from_slice(&self.digest[..len]);
+        trailer
+    }
+
+    /// Parses the trailer ending the output of a session, returning `None` if `trailer` is not a
+    /// valid trailer
+    pub fn deserialize(trailer: &[u8; SIZE]) -> Option<Self> {
+        if trailer[0..4] != MAGIC {
+            return None;
+        }
+        let algorithm = hash::Algorithm::from_id(trailer[17])?;
+        Some(Self {
+            client_id: u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]),
+            bytes: u64::from_le_bytes(trailer[8..16].try_into().expect("8 bytes")),
+            status: Status::from_id(trailer[16])?,
+            algorithm,
+            digest: trailer[18..18 + algorithm.digest_len()].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Status, Trailer, SIZE};
+    use crate::aux::file::hash;
+
+    #[test]
+    fn round_trip() {
+        for (algorithm, status) in [
+            (hash::Algorithm::None, Status::Completed),
+            (hash::Algorithm::Sha256, Status::Aborted),
+            (hash::Algorithm::Blake3, Status::Completed),
+        ] {
+            let mut hasher = hash::Hasher::new(algorithm);
+            hasher.update(b"session data");
+            let trailer = Trailer {
+                client_id: 0x2495_0001,
+                bytes: 12,
+                status,
+                algorithm,
+                digest: hasher.finalize(),
+            };
+            let serialized = trailer.serialize();
+            assert_eq!(serialized.len(), SIZE);
+            assert!(serialized.starts_with(b"LDTR"));
+
+            let parsed = Trailer::deserialize(&serialized).expect("valid trailer");
+            assert_eq!(parsed.client_id, trailer.client_id);
+            assert_eq!(parsed.bytes, 12);
+            assert_eq!(parsed.status, status);
+            assert!(parsed.algorithm == algorithm);
+            assert_eq!(parsed.digest, trailer.digest);
+            assert_eq!(parsed.digest.len(), algorithm.digest_len());
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let valid = Trailer {
+            client_id: 1,
+            bytes: 0,
+            status: Status::Completed,
+            algorithm: hash::Algorithm::None,
+            digest: Vec::new(),
+        }
+        .serialize();
+        assert!(Trailer::deserialize(&valid).is_some());
+        for (offset, value) in [(0, b'X'), (16, 2), (17, 0xff)] {
+            let mut invalid = valid;
+            invalid[offset] = value;
+            assert!(Trailer::deserialize(&invalid).is_none(), "{offset}");
+        }
+    }
+}
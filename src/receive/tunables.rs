@@ -0,0 +1,38 @@
+//! Parameters of the receiver that can be changed while it runs
+//!
+//! Workers read these values instead of the matching [receive::Config] fields each time they need
+//! them: the reblock worker before waiting for packets, the reordering and dispatch workers
+//! before checking for a stalled block or a slow destination, and clients workers before waiting
+//! for messages.
+
+use crate::receive;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time,
+};
+
+pub(crate) struct Tunables {
+    /// Nanoseconds
+    flush_timeout: AtomicU64,
+}
+
+fn to_nanos(duration: time::Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+impl Tunables {
+    pub(crate) fn new(config: &receive::Config) -> Self {
+        Self {
+            flush_timeout: AtomicU64::new(to_nanos(config.flush_timeout)),
+        }
+    }
+
+    pub(crate) fn flush_timeout(&self) -> time::Duration {
+        time::Duration::from_nanos(self.flush_timeout.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_flush_timeout(&self, flush_timeout: time::Duration) {
+        self.flush_timeout
+            .store(to_nanos(flush_timeout), Ordering::Relaxed);
+    }
+}
@@ -1,15 +1,85 @@
 //! Worker that actually receives packets from the UDP diode link
+//!
+//! The diode cannot push back on the sender: when the queue to the reblock worker is full, because
+//! decoding or the destinations are too slow, the datagrams just received are dropped and counted
+//! in `rx_udp_queue_dropped_packets`, the blocks they belong to being recovered from their repair
+//! packets or lost. The highest occupancy of the queue since the previous check is given by the
+//! `rx_udp_queue_watermark` gauge.
 
-use crate::{receive, sock_utils, udp};
-use std::net;
+use crate::{crypto, metrics, protocol, receive, scrub, sock_utils, udp};
+use std::{hint, io, net, sync::Arc, thread, time};
 
-pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::Error> {
+/// Interval between two checks of the datagrams dropped by the kernel
+const KERNEL_DROPS_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// Interval between the logs of the packets refused from a sender of an incompatible protocol
+const REFUSAL_LOG_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// Shortest and longest sleeps between two polls in [receive::PollMode::Busy], once the spinning
+/// duration is elapsed
+const BUSY_POLL_MIN_SLEEP: time::Duration = time::Duration::from_micros(10);
+const BUSY_POLL_MAX_SLEEP: time::Duration = time::Duration::from_millis(1);
+
+/// Number of empty polls between two yields of the CPU while spinning
+const BUSY_POLL_YIELD_INTERVAL: u32 = 64;
+
+/// Returns the [receive::Config::nb_udp_threads] sockets of the receiver, bound to `from_udp`,
+/// the first one being [receive::Config::from_udp_socket] when given, followed by the socket
+/// bound to [receive::Config::stripe_from_udp] when given
+///
+/// With several sockets, datagrams are spread randomly over them, a sender emitting a single
+/// flow. Packets received on the second link are merged with the others by the reblock worker.
+pub(crate) fn bind<F>(
+    receiver: &receive::Receiver<F>,
+) -> Result<Vec<net::UdpSocket>, receive::Error> {
     log::info!(
         "listening for UDP packets at {} with MTU {}",
-        receiver.config.from_udp,
+        scrub::addr(&receiver.config.from_udp),
         receiver.config.from_udp_mtu
     );
-    let socket = net::UdpSocket::bind(receiver.config.from_udp)?;
+    if !receiver.config.allow_from.is_empty() {
+        log::info!(
+            "accepting datagrams from {} network(s) only",
+            receiver.config.allow_from.len()
+        );
+    }
+    let nb_sockets = usize::from(receiver.config.nb_udp_threads);
+    let mut sockets = Vec::with_capacity(nb_sockets);
+    if let Some(socket) = &receiver.config.from_udp_socket {
+        sockets.push(socket.try_clone()?);
+    }
+    while sockets.len() < nb_sockets {
+        sockets.push(receive::bind(
+            receiver.config.from_udp,
+            receiver.config.from_multicast_interface.as_deref(),
+            1 < nb_sockets,
+        )?);
+    }
+
+    if 1 < nb_sockets {
+        match sock_utils::set_socket_reuseport_random(&sockets[0], nb_sockets as u32) {
+            Ok(()) => log::info!("datagrams spread over {nb_sockets} UDP sockets"),
+            Err(e) => log::warn!(
+                "failed to spread datagrams over UDP sockets, each sender flow being received by a single one: {e}"
+            ),
+        }
+    }
+
+    if let Some(stripe_from_udp) = receiver.config.stripe_from_udp {
+        log::info!(
+            "listening for UDP packets striped over a second link at {}",
+            scrub::addr(&stripe_from_udp)
+        );
+        sockets.push(receive::bind(stripe_from_udp, None, false)?);
+    }
+    Ok(sockets)
+}
+
+/// Receives the packets of the diode on `socket`, bound by [bind]
+pub(crate) fn start<F>(
+    receiver: &receive::Receiver<F>,
+    socket: net::UdpSocket,
+) -> Result<(), receive::Error> {
     sock_utils::set_socket_recv_buffer_size(&socket, receiver.config.udp_buffer_size as i32)?;
     let sock_buffer_size = sock_utils::get_socket_recv_buffer_size(&socket)?;
     log::info!("UDP socket receive buffer size set to {sock_buffer_size}");
@@ -20,16 +90,555 @@ pub(crate) fn start<F>(receiver: &receive::Receiver<F>) -> Result<(), receive::E
         log::warn!("Please review the kernel parameters using sysctl");
     }
 
+    if let Err(e) = sock_utils::set_socket_rxq_ovfl(&socket) {
+        log::warn!("failed to enable kernel drops reporting on UDP socket: {e}");
+    }
+    let mut drops_check = KernelDropsCheck::new(socket.try_clone()?);
+
+    log::info!("UDP poll mode is {}", receiver.config.udp_poll_mode);
+    let busy_poll = match receiver.config.udp_poll_mode {
+        receive::PollMode::Blocking => None,
+        receive::PollMode::Busy(spin_us) => {
+            if let Err(e) = sock_utils::set_socket_busy_poll(&socket, spin_us) {
+                log::warn!("failed to set busy polling duration of UDP socket: {e}");
+            }
+            Some(BusyPoll::new(spin_us))
+        }
+    };
+
     let mut udp_messages = udp::UdpMessages::new_receiver(
         socket,
         usize::from(receiver.from_max_messages),
         usize::from(receiver.config.from_udp_mtu),
     );
+    if receiver.config.udp_gro {
+        match udp_messages.enable_gro() {
+            Ok(()) => log::info!("UDP receive coalescing (UDP_GRO) enabled"),
+            Err(e) => log::warn!("failed to enable UDP receive coalescing: {e}"),
+        }
+    }
+
+    let invalid_packets = metrics::counter("rx_packets_invalid");
+    let truncated_packets = metrics::counter("rx_truncated_pkts");
+    let protocol_mismatches = metrics::counter("rx_protocol_mismatches");
+    let refused_packets = metrics::counter("rx_packets_refused");
+    let unauthenticated_packets = metrics::counter("rx_packets_unauthenticated");
+    let replayed_packets = metrics::counter("rx_packets_replayed");
+    let rejected_packets = metrics::counter("rx_packets_rejected");
+    let mut max_truncated_len = 0;
+
+    let queue_dropped = metrics::counter("rx_udp_queue_dropped_packets");
+    let queue_watermark = metrics::gauge("rx_udp_queue_watermark");
+    // highest occupancy of the queue since the previous check, shared by the udp workers
+    let mut watermark = 0;
+    // value of the dropped packets counter when the queue became full
+    let mut full_since: Option<u64> = None;
+
+    // datagrams once decrypted, reused from one to the next
+    let mut opened = Vec::with_capacity(usize::from(receiver.config.from_udp_mtu));
+    let header_size = receiver.config.header_format.size();
+
+    // last encoding parameters announced by the sender, and their differences with the
+    // configuration when they are checked
+    let mut sender_config: Option<protocol::EncodingConfig> = None;
+    let mut mismatches = Vec::new();
+    // whether the last announced protocol version or features are incompatible, counted in
+    // `rx_protocol_mismatches` for every configuration packet
+    let mut incompatible_sender = false;
+    // packets refused since the last log, and when it was written
+    let mut refusal_logged = (0, time::Instant::now());
 
     loop {
-        let packets = udp_messages
-            .recv_mmsg()?
-            .map(raptorq::EncodingPacket::deserialize);
-        receiver.to_reblock.send(packets.collect())?;
+        match &busy_poll {
+            None => udp_messages.recv_mmsg()?,
+            Some(busy_poll) => busy_poll.recv(&mut udp_messages)?,
+        };
+        // locked once for all the received datagrams
+        let mut replay_window = receiver
+            .config
+            .psk
+            .as_ref()
+            .map(|_| receiver.replay_window.lock().expect("acquire lock"));
+        let packets = udp_messages.received().filter_map(|(source, packet)| {
+            if !receive::allowed(&receiver.config.allow_from, source) {
+                // logging only the first one, not for every datagram
+                if rejected_packets.get() == 0 {
+                    match &source {
+                        Some(source) => log::warn!(
+                            "dropping datagram from {}, outside of allow_from",
+                            scrub::addr(source)
+                        ),
+                        None => log::warn!("dropping datagram of unknown source"),
+                    }
+                }
+                rejected_packets.inc();
+                return None;
+            }
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(len) => {
+                    truncated_packets.inc();
+                    // logging only when a larger datagram is seen, not for every datagram
+                    if max_truncated_len < len {
+                        max_truncated_len = len;
+                        log::error!(
+                            "dropping datagram of {len} bytes larger than the MTU of {} bytes: the sender MTU is larger, set from_udp_mtu to {} or auto",
+                            receiver.config.from_udp_mtu,
+                            protocol::link_mtu(
+                                (len + usize::from(protocol::PACKET_HEADER_SIZE)) as u16,
+                                &receiver.config.from_udp,
+                            ),
+                        );
+                    }
+                    return None;
+                }
+            };
+            let packet = match &receiver.config.psk {
+                Some(key) => {
+                    // configuration packets are authenticated but left in clear
+                    let clear_len = if protocol::is_config_packet(packet) {
+                        crypto::config_clear_len(packet)
+                    } else {
+                        header_size
+                    };
+                    match crypto::open(key, clear_len, packet, &mut opened) {
+                        Ok(nonce) => {
+                            if let Some(replay_window) = &mut replay_window {
+                                if !replay_window.check(nonce) {
+                                    replayed_packets.inc();
+                                    return None;
+                                }
+                            }
+                            &opened[..]
+                        }
+                        Err(e) => {
+                            // logging only the first one, the sender likely using another key
+                            if unauthenticated_packets.get() == 0 {
+                                log::error!(
+                                    "dropping datagram: {e}, check that both ends use the same pre-shared key"
+                                );
+                            }
+                            unauthenticated_packets.inc();
+                            return None;
+                        }
+                    }
+                }
+                None => packet,
+            };
+            match protocol::read_packet(receiver.config.header_format, packet) {
+                Ok((header, payload)) if header.symbol_id == protocol::CONFIG_SYMBOL_ID => {
+                    match protocol::read_config(payload) {
+                        Ok(config) if sender_config != Some(config) => {
+                            match sender_config {
+                                None => log::info!("sender announces {config}"),
+                                Some(_) => log::warn!("sender now announces {config}"),
+                            }
+                            let incompatibilities = receiver.config.protocol_mismatches(&config);
+                            let was_incompatible = incompatible_sender;
+                            incompatible_sender = !incompatibilities.is_empty();
+                            if incompatible_sender {
+                                protocol_mismatches.inc();
+                                // stopping below when checking the configuration of the sender
+                                if !receiver.config.check_sender_config {
+                                    for incompatibility in &incompatibilities {
+                                        log::error!("sender protocol mismatch: {incompatibility}");
+                                    }
+                                    if receiver.config.force_best_effort {
+                                        log::error!("packets of the sender cannot be read or checked, reading them anyway as force_best_effort is set");
+                                    } else {
+                                        log::error!("refusing the packets of the sender until it announces a compatible protocol, set force_best_effort to read them anyway");
+                                        refusal_logged = (refused_packets.get(), time::Instant::now());
+                                    }
+                                }
+                            } else if was_incompatible {
+                                log::info!("sender protocol now compatible, reading its packets");
+                            }
+                            if receiver.config.check_sender_config {
+                                mismatches = receiver.config.sender_config_mismatches(&config);
+                            }
+                            sender_config = Some(config);
+                        }
+                        Ok(_) if incompatible_sender => protocol_mismatches.inc(),
+                        Ok(_) => (),
+                        Err(e) => {
+                            log::warn!("dropping invalid configuration packet: {e}");
+                            invalid_packets.inc();
+                        }
+                    }
+                    None
+                }
+                Ok(_) if incompatible_sender && !receiver.config.force_best_effort => {
+                    refused_packets.inc();
+                    None
+                }
+                Ok((header, payload)) => Some((
+                    header.block_id,
+                    raptorq::EncodingPacket::new(
+                        header.into(),
+                        receiver.buffers.packets.copy(payload),
+                    ),
+                )),
+                Err(e) => {
+                    log::warn!("dropping invalid packet: {e}");
+                    invalid_packets.inc();
+                    None
+                }
+            }
+        });
+        let packets = packets.collect();
+        drop(replay_window);
+        match receiver.to_reblock.try_send(packets) {
+            Ok(()) => {
+                watermark = watermark.max(receiver.for_reblock.len());
+                if let Some(dropped) = full_since.take() {
+                    log::info!(
+                        "reblock queue no longer full, {} packets dropped",
+                        queue_dropped.get() - dropped
+                    );
+                }
+            }
+            Err(crossbeam_channel::TrySendError::Full(packets)) => {
+                watermark = receiver.config.queue_size;
+                if full_since.is_none() {
+                    log::warn!("reblock queue full, dropping packets: decoding or destinations are too slow");
+                    full_since = Some(queue_dropped.get());
+                }
+                queue_dropped.add(packets.len() as u64);
+                for (_, packet) in packets {
+                    receiver.buffers.packets.release(packet.split().1);
+                }
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(packets)) => {
+                return Err(crossbeam_channel::SendError(packets).into());
+            }
+        }
+
+        if incompatible_sender
+            && !receiver.config.force_best_effort
+            && REFUSAL_LOG_INTERVAL <= refusal_logged.1.elapsed()
+        {
+            let version = sender_config.map_or(0, |config| config.version);
+            log::error!(
+                "refused {} packets of the sender of protocol version {version} in the last {} seconds, the receiver running version {}",
+                refused_packets.get() - refusal_logged.0,
+                REFUSAL_LOG_INTERVAL.as_secs(),
+                protocol::PROTOCOL_VERSION
+            );
+            refusal_logged = (refused_packets.get(), time::Instant::now());
+        }
+
+        if !mismatches.is_empty() {
+            for mismatch in &mismatches {
+                log::error!("sender configuration mismatch: {mismatch}");
+            }
+            log::error!("stopping reception, blocks of the sender cannot be decoded");
+            return Err(receive::Error::SenderConfig(mismatches));
+        }
+
+        if KERNEL_DROPS_CHECK_INTERVAL <= drops_check.checked_at.elapsed() {
+            drops_check.check(&udp_messages);
+            queue_watermark.set(watermark as u64);
+            watermark = receiver.for_reblock.len();
+        }
+    }
+}
+
+/// Tracking of the datagrams dropped by the kernel on the socket of a udp worker, to tell them
+/// apart from the packets lost on the wire
+struct KernelDropsCheck {
+    socket: net::UdpSocket,
+    kernel_drops: Arc<metrics::Metric>,
+    missing_packets: Arc<metrics::Metric>,
+    /// Last value of the `SO_RXQ_OVFL` counter
+    last_rxq_ovfl: Option<u32>,
+    /// Drops of the socket reported by `SO_RXQ_OVFL` since startup
+    rxq_ovfl_drops: u64,
+    /// Drops reported in `/proc` at startup
+    proc_drops_at_start: u64,
+    /// Local drops and missing packets at the previous check
+    last_local_drops: u64,
+    last_missing_packets: u64,
+    checked_at: time::Instant,
+}
+
+impl KernelDropsCheck {
+    fn new(socket: net::UdpSocket) -> Self {
+        // drops accounted before startup, on a socket bound and handed over by another process
+        let proc_drops_at_start = match sock_utils::get_socket_drops(&socket) {
+            Ok(drops) => {
+                if 0 < drops {
+                    log::warn!("UDP socket already dropped {drops} datagrams at startup");
+                }
+                drops
+            }
+            Err(e) => {
+                log::debug!("cannot read kernel drops of UDP socket: {e}");
+                0
+            }
+        };
+
+        let missing_packets = metrics::counter("rx_packets_missing");
+        Self {
+            socket,
+            kernel_drops: metrics::counter("rx_kernel_drops"),
+            last_missing_packets: missing_packets.get(),
+            missing_packets,
+            last_rxq_ovfl: None,
+            rxq_ovfl_drops: 0,
+            proc_drops_at_start,
+            last_local_drops: 0,
+            checked_at: time::Instant::now(),
+        }
+    }
+
+    /// Updates the `rx_kernel_drops` counter and warns if the datagrams dropped by the kernel
+    /// account for most of the packets missing since the previous check
+    fn check(&mut self, udp_messages: &udp::UdpMessages<udp::UdpRecv>) {
+        self.checked_at = time::Instant::now();
+
+        if let Some(rxq_ovfl) = udp_messages.kernel_drops() {
+            let new_drops = rxq_ovfl.wrapping_sub(self.last_rxq_ovfl.unwrap_or(0));
+            self.kernel_drops.add(u64::from(new_drops));
+            self.rxq_ovfl_drops += u64::from(new_drops);
+            self.last_rxq_ovfl = Some(rxq_ovfl);
+        }
+
+        // SO_RXQ_OVFL only reports drops with the next datagram received, while the /proc table
+        // is up to date
+        let mut local_drops = self.rxq_ovfl_drops;
+        if let Ok(proc_drops) = sock_utils::get_socket_drops(&self.socket) {
+            let proc_drops = proc_drops.saturating_sub(self.proc_drops_at_start);
+            if local_drops < proc_drops {
+                log::debug!("{proc_drops} kernel drops in /proc, {local_drops} in SO_RXQ_OVFL");
+                local_drops = proc_drops;
+            }
+        }
+
+        let missing_packets = self.missing_packets.get();
+        let new_local_drops = local_drops.saturating_sub(self.last_local_drops);
+        let new_missing_packets = missing_packets - self.last_missing_packets;
+        self.last_local_drops = self.last_local_drops.max(local_drops);
+        self.last_missing_packets = missing_packets;
+
+        if new_local_drops == 0 {
+            return;
+        }
+        let wire_loss = new_missing_packets.saturating_sub(new_local_drops);
+        if wire_loss <= new_local_drops {
+            log::warn!(
+                "{new_local_drops} datagrams dropped by the kernel and about {wire_loss} lost on the wire since last check: drops are local — increase udp_buffer_size / rmem_max"
+            );
+        } else {
+            log::info!(
+                "{new_local_drops} datagrams dropped by the kernel and about {wire_loss} lost on the wire since last check"
+            );
+        }
+    }
+}
+
+/// Receive calls returning immediately, polled by [BusyPoll]
+trait TryRecv {
+    /// Returns the number of datagrams received, 0 if none is available
+    fn try_recv(&mut self) -> Result<usize, io::Error>;
+}
+
+impl TryRecv for udp::UdpMessages<udp::UdpRecv> {
+    fn try_recv(&mut self) -> Result<usize, io::Error> {
+        self.try_recv_mmsg()
+    }
+}
+
+/// Polling of the socket without blocking in [receive::PollMode::Busy]
+struct BusyPoll {
+    spin: time::Duration,
+    /// Time spent spinning and sleeping while no datagram was available, in microseconds
+    spin_us: Arc<metrics::Metric>,
+    sleep_us: Arc<metrics::Metric>,
+}
+
+impl BusyPoll {
+    fn new(spin_us: u32) -> Self {
+        Self {
+            spin: time::Duration::from_micros(u64::from(spin_us)),
+            spin_us: metrics::counter("rx_udp_spin_us"),
+            sleep_us: metrics::counter("rx_udp_sleep_us"),
+        }
+    }
+
+    /// Receives datagrams, spinning while none is available, then sleeping with an exponential
+    /// backoff once the spinning duration is elapsed
+    fn recv<R: TryRecv>(&self, udp_messages: &mut R) -> Result<usize, io::Error> {
+        let started = time::Instant::now();
+        let mut spinning = true;
+        let mut nb_empty_polls = 0u32;
+        let mut sleep = BUSY_POLL_MIN_SLEEP;
+
+        loop {
+            let nb_msg = udp_messages.try_recv()?;
+            if 0 < nb_msg {
+                if spinning {
+                    self.spin_us.add(started.elapsed().as_micros() as u64);
+                }
+                return Ok(nb_msg);
+            }
+
+            if spinning {
+                if started.elapsed() < self.spin {
+                    nb_empty_polls = nb_empty_polls.wrapping_add(1);
+                    if nb_empty_polls.is_multiple_of(BUSY_POLL_YIELD_INTERVAL) {
+                        thread::yield_now();
+                    } else {
+                        hint::spin_loop();
+                    }
+                    continue;
+                }
+                spinning = false;
+                self.spin_us.add(started.elapsed().as_micros() as u64);
+            }
+
+            thread::sleep(sleep);
+            self.sleep_us.add(sleep.as_micros() as u64);
+            sleep = (2 * sleep).min(BUSY_POLL_MAX_SLEEP);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BusyPoll, TryRecv, BUSY_POLL_MAX_SLEEP, BUSY_POLL_MIN_SLEEP};
+    use crate::{metrics, receive, udp};
+    use std::{io, net, thread, time};
+
+    /// Receive calls finding no datagram `empty` times, then `ready` datagrams, recording the
+    /// time of every poll
+    struct Polls {
+        empty: usize,
+        ready: Result<usize, io::ErrorKind>,
+        at: Vec<time::Instant>,
+    }
+
+    impl Polls {
+        fn new(empty: usize, ready: Result<usize, io::ErrorKind>) -> Self {
+            Self {
+                empty,
+                ready,
+                at: Vec::new(),
+            }
+        }
+    }
+
+    impl TryRecv for Polls {
+        fn try_recv(&mut self) -> Result<usize, io::Error> {
+            self.at.push(time::Instant::now());
+            if self.at.len() <= self.empty {
+                return Ok(0);
+            }
+            self.ready.map_err(io::Error::from)
+        }
+    }
+
+    #[test]
+    fn poll_modes() {
+        for (mode, parsed) in [
+            ("blocking", receive::PollMode::Blocking),
+            (
+                "busy",
+                receive::PollMode::Busy(receive::DEFAULT_BUSY_POLL_US),
+            ),
+            ("busy:0", receive::PollMode::Busy(0)),
+            ("busy:200", receive::PollMode::Busy(200)),
+        ] {
+            assert_eq!(mode.parse(), Ok(parsed));
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+        for mode in ["", "spin", "busy:", "busy:-1", "blocking:10"] {
+            assert!(mode.parse::<receive::PollMode>().is_err(), "{mode}");
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let sleep_us = metrics::counter("rx_udp_sleep_us");
+        let slept = sleep_us.get();
+
+        // without spinning, sleeps double from the shortest to the longest
+        let mut polls = Polls::new(9, Ok(5));
+        assert_eq!(BusyPoll::new(0).recv(&mut polls).expect("received"), 5);
+        assert_eq!(polls.at.len(), 10);
+        let mut sleep = BUSY_POLL_MIN_SLEEP;
+        let mut total = time::Duration::ZERO;
+        for pair in polls.at.windows(2) {
+            assert!(sleep <= pair[1] - pair[0], "{sleep:?}");
+            total += sleep;
+            sleep = (2 * sleep).min(BUSY_POLL_MAX_SLEEP);
+        }
+        assert_eq!(total, time::Duration::from_micros(3270));
+        assert_eq!(sleep_us.get() - slept, 3270);
+    }
+
+    #[test]
+    fn spinning() {
+        // datagrams arriving while spinning are received without sleeping
+        let mut polls = Polls::new(1000, Ok(1));
+        let started = time::Instant::now();
+        assert_eq!(
+            BusyPoll::new(10_000_000)
+                .recv(&mut polls)
+                .expect("received"),
+            1
+        );
+        assert_eq!(polls.at.len(), 1001);
+        assert!(started.elapsed() < time::Duration::from_secs(10));
+
+        // errors end the polling
+        let mut polls = Polls::new(3, Err(io::ErrorKind::ConnectionRefused));
+        let e = BusyPoll::new(0).recv(&mut polls).expect_err("error");
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(polls.at.len(), 4);
+    }
+
+    /// Rate of datagrams received during a second from a sender flooding the socket
+    fn packet_rate(mode: receive::PollMode) -> f64 {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let addr = socket.local_addr().expect("address");
+        let mut messages = udp::UdpMessages::new_receiver(socket, 64, 1472);
+        let busy_poll = match mode {
+            receive::PollMode::Blocking => None,
+            receive::PollMode::Busy(spin_us) => Some(BusyPoll::new(spin_us)),
+        };
+
+        let duration = time::Duration::from_secs(1);
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                let sender = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+                let started = time::Instant::now();
+                // the receiver stops receiving once the last datagrams are sent
+                while started.elapsed() < duration + time::Duration::from_millis(100) {
+                    let _ = sender.send_to(&[0; 1400], addr);
+                }
+            });
+
+            let started = time::Instant::now();
+            let mut nb_received = 0;
+            while started.elapsed() < duration {
+                nb_received += match &busy_poll {
+                    None => messages.recv_mmsg(),
+                    Some(busy_poll) => busy_poll.recv(&mut messages),
+                }
+                .expect("receive");
+            }
+            nb_received as f64 / started.elapsed().as_secs_f64()
+        })
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn packet_rate_ceilings() {
+        for mode in [
+            receive::PollMode::Blocking,
+            receive::PollMode::Busy(receive::DEFAULT_BUSY_POLL_US),
+        ] {
+            eprintln!("{mode}: {:.0} datagrams per second", packet_rate(mode));
+        }
     }
 }
@@ -0,0 +1,160 @@
+//! Worker that checks the receipt pipeline makes progress and notifies the systemd watchdog
+//!
+//! A stage is considered stalled when messages are waiting in its input queue but it did not
+//! consume any of them since the previous check. As soon as a stage is stalled or a worker
+//! exited, `WATCHDOG=1` notifications stop and the service manager eventually restarts the
+//! process. An idle pipeline, with empty queues, is healthy.
+
+use crate::{receive, sd_notify};
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread, time,
+};
+
+/// Progress counters of the pipeline stages, incremented by workers each time they consume an
+/// item from their input queue
+#[derive(Default)]
+pub(crate) struct Progress {
+    pub(crate) reblock: AtomicU64,
+    pub(crate) decoding: AtomicU64,
+    pub(crate) reordering: AtomicU64,
+    pub(crate) dispatch: AtomicU64,
+    pub(crate) exited: AtomicBool,
+}
+
+impl Progress {
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> [u64; 4] {
+        [
+            self.reblock.load(Ordering::Relaxed),
+            self.decoding.load(Ordering::Relaxed),
+            self.reordering.load(Ordering::Relaxed),
+            self.dispatch.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+const STAGES: [&str; 4] = ["reblock", "decoding", "reordering", "dispatch"];
+
+/// Returns the name of a stalled stage, if any
+fn stalled_stage<F>(
+    receiver: &receive::Receiver<F>,
+    previous: &[u64; 4],
+    current: &[u64; 4],
+) -> Option<&'static str> {
+    if receiver.progress.exited.load(Ordering::Relaxed) {
+        return Some("worker exited");
+    }
+
+    let pending = [
+        receiver.for_reblock.len(),
+        receiver.for_decoding.len(),
+        receiver.for_reordering.len(),
+        receiver.for_dispatch.len(),
+    ];
+
+    (0..STAGES.len())
+        .find(|&i| 0 < pending[i] && current[i] == previous[i])
+        .map(|i| STAGES[i])
+}
+
+pub(crate) fn start<F>(
+    receiver: &receive::Receiver<F>,
+    interval: time::Duration,
+) -> Result<(), receive::Error> {
+    log::info!(
+        "notifying systemd watchdog every {} ms",
+        interval.as_millis()
+    );
+
+    let mut previous = receiver.progress.snapshot();
+    let mut stalled = false;
+
+    loop {
+        thread::sleep(interval);
+
+        let current = receiver.progress.snapshot();
+
+        match stalled_stage(receiver, &previous, &current) {
+            Some(stage) => {
+                if !stalled {
+                    log::error!("pipeline stalled ({stage}), stopping watchdog notifications");
+                    stalled = true;
+                }
+            }
+            None => {
+                if stalled {
+                    log::warn!("pipeline progressing again, resuming watchdog notifications");
+                    stalled = false;
+                }
+                sd_notify::notify("WATCHDOG=1")?;
+            }
+        }
+
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Progress;
+    use crate::{loopback, receive, testing};
+    use std::{
+        io, net,
+        sync::atomic::{AtomicBool, Ordering},
+        thread, time,
+    };
+
+    const INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+    #[test]
+    fn pings() {
+        let notify = testing::NotifySocket::bind();
+
+        let addr = net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 5000));
+        let config = loopback::receive_config(&testing::loopback_config(), addr, None);
+        let receiver = receive::Receiver::new(config, |_| -> Result<net::TcpStream, io::Error> {
+            Err(io::Error::other("no destination"))
+        })
+        .unwrap_or_else(|e| panic!("{e}"));
+        // left running until the end of the tests, silenced by the exit flag set below
+        let receiver: &'static _ = Box::leak(Box::new(receiver));
+        thread::spawn(move || super::start(receiver, INTERVAL));
+
+        let window = 8 * INTERVAL;
+        let settle = || notify.pings(3 * INTERVAL);
+
+        // idle pipeline
+        assert!(4 <= notify.pings(window));
+
+        // a block waits for reblock, which keeps consuming
+        receiver.to_reblock.send(Vec::new()).expect("reblock");
+        let working = AtomicBool::new(true);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while working.load(Ordering::Relaxed) {
+                    Progress::inc(&receiver.progress.reblock);
+                    thread::sleep(INTERVAL / 5);
+                }
+            });
+            assert!(4 <= notify.pings(window));
+            working.store(false, Ordering::Relaxed);
+        });
+
+        // reblock stalled
+        settle();
+        assert_eq!(notify.pings(window), 0);
+
+        // queue drained, resumed
+        receiver.for_reblock.try_recv().expect("pending block");
+        assert!(4 <= notify.pings(window));
+
+        // a worker exited
+        receiver.progress.exited.store(true, Ordering::Relaxed);
+        settle();
+        assert_eq!(notify.pings(window), 0);
+    }
+}
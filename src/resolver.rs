@@ -0,0 +1,321 @@
+//! Connection to a TCP destination given as a host name, possibly resolving to several addresses
+//!
+//! The name is resolved again when the previous answer is older than a configurable TTL, so that
+//! DNS changes (e.g. a failover virtual IP) are picked up without restarting. Addresses are
+//! tried in the order of the answer, except that an address which recently failed is put aside
+//! with an exponential backoff: a dead primary address then does not delay every connection.
+
+use crate::scrub;
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::{self, ToSocketAddrs},
+    sync, time,
+};
+
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+struct Backoff {
+    failures: u32,
+    retry_at: time::Instant,
+}
+
+/// Resolution of a `host:port` target to its addresses
+pub(crate) trait Resolve {
+    fn resolve(&self, target: &str) -> Result<Vec<net::SocketAddr>, io::Error>;
+}
+
+/// Resolution by the system resolver
+struct System;
+
+impl Resolve for System {
+    fn resolve(&self, target: &str) -> Result<Vec<net::SocketAddr>, io::Error> {
+        Ok(target.to_socket_addrs()?.collect())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    addresses: Vec<net::SocketAddr>,
+    resolved_at: Option<time::Instant>,
+    backoffs: BTreeMap<net::SocketAddr, Backoff>,
+}
+
+pub struct Resolver {
+    target: String,
+    ttl: time::Duration,
+    resolve: Box<dyn Resolve + Send + Sync>,
+    state: sync::Mutex<State>,
+}
+
+impl fmt::Display for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", scrub::target(&self.target))
+    }
+}
+
+impl Resolver {
+    /// `target` is of the form `host:port`, `host` being a name or an IP address
+    pub fn new(target: String, ttl: time::Duration) -> Self {
+        Self::with_resolve(target, ttl, System)
+    }
+
+    pub(crate) fn with_resolve<R>(target: String, ttl: time::Duration, resolve: R) -> Self
+    where
+        R: Resolve + Send + Sync + 'static,
+    {
+        Self {
+            target,
+            ttl,
+            resolve: Box::new(resolve),
+            state: sync::Mutex::new(State::default()),
+        }
+    }
+
+    /// Returns the addresses to try, in order
+    fn candidates(&self) -> Result<Vec<net::SocketAddr>, io::Error> {
+        let mut state = self.state.lock().expect("acquire lock");
+
+        if state.resolved_at.is_none_or(|at| self.ttl <= at.elapsed()) {
+            match self.resolve.resolve(&self.target) {
+                Ok(addresses) => {
+                    if addresses != state.addresses {
+                        log::info!(
+                            "{} resolved to {}",
+                            scrub::target(&self.target),
+                            addresses
+                                .iter()
+                                .map(|addr| scrub::addr(addr).to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        );
+                    }
+                    state.backoffs.retain(|addr, _| addresses.contains(addr));
+                    state.addresses = addresses;
+                    state.resolved_at = Some(time::Instant::now());
+                }
+                Err(e) if state.addresses.is_empty() => return Err(e),
+                Err(e) => {
+                    log::warn!(
+                        "failed to resolve {}, keeping previous addresses: {e}",
+                        scrub::target(&self.target)
+                    );
+                }
+            }
+        }
+
+        let now = time::Instant::now();
+        let (mut ready, mut backing_off): (Vec<_>, Vec<_>) =
+            state.addresses.iter().partition(|addr| {
+                state
+                    .backoffs
+                    .get(addr)
+                    .is_none_or(|backoff| backoff.retry_at <= now)
+            });
+
+        // addresses in backoff are still tried as a last resort, soonest retry first
+        backing_off.sort_by_key(|addr| state.backoffs[addr].retry_at);
+        ready.append(&mut backing_off);
+
+        Ok(ready)
+    }
+
+    fn failed(&self, addr: net::SocketAddr) {
+        let mut state = self.state.lock().expect("acquire lock");
+        let backoff = state.backoffs.entry(addr).or_insert(Backoff {
+            failures: 0,
+            retry_at: time::Instant::now(),
+        });
+        backoff.failures = backoff.failures.saturating_add(1);
+        let delay = MIN_BACKOFF
+            .saturating_mul(1 << (backoff.failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        backoff.retry_at = time::Instant::now() + delay;
+    }
+
+    fn succeeded(&self, addr: net::SocketAddr) {
+        self.state
+            .lock()
+            .expect("acquire lock")
+            .backoffs
+            .remove(&addr);
+    }
+
+    /// Connects to the first reachable address of the target
+    pub fn connect(&self) -> Result<net::TcpStream, io::Error> {
+        let mut last_error = None;
+
+        for addr in self.candidates()? {
+            match net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    self.succeeded(addr);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "failed to connect to {} ({}): {e}",
+                        scrub::target(&self.target),
+                        scrub::addr(&addr)
+                    );
+                    self.failed(addr);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} resolved to no address", scrub::target(&self.target)),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Resolve, Resolver};
+    use std::{io, net, sync, time};
+
+    const TTL: time::Duration = time::Duration::from_secs(3600);
+
+    /// Resolver giving the answer last set, an error for `None`, and counting resolutions
+    #[derive(Clone, Default)]
+    struct Mock(sync::Arc<sync::Mutex<(usize, Option<Vec<net::SocketAddr>>)>>);
+
+    impl Mock {
+        fn set(&self, answer: Option<&[net::SocketAddr]>) {
+            self.0.lock().expect("acquire lock").1 = answer.map(<[_]>::to_vec);
+        }
+
+        fn resolutions(&self) -> usize {
+            self.0.lock().expect("acquire lock").0
+        }
+    }
+
+    impl Resolve for Mock {
+        fn resolve(&self, _: &str) -> Result<Vec<net::SocketAddr>, io::Error> {
+            let mut mock = self.0.lock().expect("acquire lock");
+            mock.0 += 1;
+            mock.1
+                .clone()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no answer"))
+        }
+    }
+
+    fn mocked(ttl: time::Duration, answer: &[net::SocketAddr]) -> (Resolver, Mock) {
+        let mock = Mock::default();
+        mock.set(Some(answer));
+        let resolver = Resolver::with_resolve("destination:7000".to_string(), ttl, mock.clone());
+        (resolver, mock)
+    }
+
+    fn listener() -> (net::TcpListener, net::SocketAddr) {
+        let listener = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let addr = listener.local_addr().expect("address");
+        (listener, addr)
+    }
+
+    /// Address on which connections are refused
+    fn dead() -> net::SocketAddr {
+        listener().1
+    }
+
+    fn peer(resolver: &Resolver) -> net::SocketAddr {
+        let stream = resolver.connect().unwrap_or_else(|e| panic!("{e}"));
+        stream.peer_addr().expect("peer address")
+    }
+
+    #[test]
+    fn failed_addresses_backoff() {
+        let dead = dead();
+        let (_first, first) = listener();
+        let (_second, second) = listener();
+        let (resolver, _) = mocked(TTL, &[dead, first, second]);
+
+        assert_eq!(peer(&resolver), first);
+        assert_eq!(
+            resolver.candidates().unwrap_or_else(|e| panic!("{e}")),
+            [first, second, dead]
+        );
+
+        let state = resolver.state.lock().expect("acquire lock");
+        let backoff = &state.backoffs[&dead];
+        assert_eq!(backoff.failures, 1);
+        let delay = backoff.retry_at - time::Instant::now();
+        assert!(delay <= super::MIN_BACKOFF && super::MIN_BACKOFF / 2 < delay);
+        drop(state);
+
+        // still tried as a last resort, the backoff doubling
+        resolver.failed(first);
+        resolver.failed(second);
+        assert_eq!(peer(&resolver), first);
+        assert_eq!(
+            resolver.state.lock().expect("acquire lock").backoffs[&dead].failures,
+            2
+        );
+        assert!(!resolver
+            .state
+            .lock()
+            .expect("acquire lock")
+            .backoffs
+            .contains_key(&first));
+    }
+
+    #[test]
+    fn answers_change() {
+        let (a, b) = (dead(), dead());
+        let (resolver, mock) = mocked(time::Duration::ZERO, &[a]);
+        let candidates = || resolver.candidates().unwrap_or_else(|e| panic!("{e}"));
+
+        assert_eq!(candidates(), [a]);
+        resolver.failed(a);
+        mock.set(Some(&[b]));
+        assert_eq!(candidates(), [b]);
+
+        // the backoff of a removed address is forgotten
+        mock.set(Some(&[a, b]));
+        assert_eq!(candidates(), [a, b]);
+
+        // previous addresses kept on failure
+        mock.set(None);
+        assert_eq!(candidates(), [a, b]);
+        assert_eq!(mock.resolutions(), 4);
+
+        // cached during the TTL
+        let (resolver, mock) = mocked(TTL, &[a]);
+        resolver.candidates().unwrap_or_else(|e| panic!("{e}"));
+        mock.set(Some(&[b]));
+        assert_eq!(resolver.candidates().unwrap_or_else(|e| panic!("{e}")), [a]);
+        assert_eq!(mock.resolutions(), 1);
+    }
+
+    #[test]
+    fn no_address() {
+        let (resolver, mock) = mocked(time::Duration::ZERO, &[]);
+        let err = resolver.connect().expect_err("no address");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        mock.set(None);
+        assert!(resolver.connect().is_err());
+        assert_eq!(mock.resolutions(), 2);
+    }
+
+    #[test]
+    fn failover() {
+        let (primary, primary_addr) = listener();
+        let (_secondary, secondary_addr) = listener();
+        let (resolver, _) = mocked(TTL, &[primary_addr, secondary_addr]);
+
+        assert_eq!(peer(&resolver), primary_addr);
+        drop(primary);
+        assert_eq!(peer(&resolver), secondary_addr);
+        assert_eq!(
+            resolver.candidates().unwrap_or_else(|e| panic!("{e}")),
+            [secondary_addr, primary_addr]
+        );
+        assert_eq!(peer(&resolver), secondary_addr);
+    }
+}
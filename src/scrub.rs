@@ -0,0 +1,318 @@
+//! Formatting of network addresses in logs and events, optionally scrubbed for privacy
+//!
+//! Every address written to logs or to the events stream goes through [addr], [ip] or
+//! [target], so that a single [init] call selects how addresses appear:
+//! - [Mode::Off] writes addresses as is,
+//! - [Mode::Hash] replaces IP addresses with a short keyed hash (HMAC-SHA256 truncated to 48
+//!   bits), so that occurrences of an address can still be correlated. The key is either read
+//!   from a file, keeping hashes stable across runs, or randomly generated at startup,
+//! - [Mode::Redact] only keeps the /24 IPv4 or /48 IPv6 network of addresses.
+//!
+//! Ports are kept in all modes. Host names given in the configuration are not addresses and are
+//! written as is.
+
+use crate::aux::file::hash::Sha256;
+use rand::RngCore;
+use std::{fmt, fs, io, net, path, str::FromStr, sync::OnceLock};
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    Off,
+    Hash,
+    Redact,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Off => write!(fmt, "off"),
+            Self::Hash => write!(fmt, "hash"),
+            Self::Redact => write!(fmt, "redact"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "hash" => Ok(Self::Hash),
+            "redact" => Ok(Self::Redact),
+            _ => Err(format!("unknown scrub mode \"{s}\"")),
+        }
+    }
+}
+
+struct Scrubber {
+    mode: Mode,
+    key: Vec<u8>,
+}
+
+static SCRUBBER: OnceLock<Scrubber> = OnceLock::new();
+
+/// Selects how addresses are formatted for the rest of the process lifetime
+///
+/// In [Mode::Hash], the hash key is the content of `key_file` if given, a random key otherwise.
+/// Only the first call has an effect.
+pub fn init(mode: Mode, key_file: Option<&path::Path>) -> Result<(), io::Error> {
+    let key = match (mode, key_file) {
+        (Mode::Hash, Some(key_file)) => {
+            let key = fs::read(key_file)?;
+            if key.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("key file '{}' is empty", key_file.display()),
+                ));
+            }
+            key
+        }
+        (Mode::Hash, None) => {
+            let mut key = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        }
+        _ => Vec::new(),
+    };
+
+    let _ = SCRUBBER.set(Scrubber { mode, key });
+    Ok(())
+}
+
+fn scrubber() -> Option<&'static Scrubber> {
+    SCRUBBER.get().filter(|scrubber| scrubber.mode != Mode::Off)
+}
+
+/// Address formatted according to the selected [Mode]
+pub struct Scrubbed<T>(T);
+
+/// Formats a socket address
+pub const fn addr(address: &net::SocketAddr) -> Scrubbed<&net::SocketAddr> {
+    Scrubbed(address)
+}
+
+/// Formats an IP address
+pub const fn ip(address: &net::IpAddr) -> Scrubbed<&net::IpAddr> {
+    Scrubbed(address)
+}
+
+/// Formats a `host:port` target, only scrubbing it when `host` is an IP address
+pub fn target(target: &str) -> Scrubbed<&str> {
+    Scrubbed(target)
+}
+
+impl fmt::Display for Scrubbed<&net::IpAddr> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match scrubber() {
+            None => write!(fmt, "{}", self.0),
+            Some(scrubber) => scrubber.write_ip(fmt, self.0),
+        }
+    }
+}
+
+impl fmt::Display for Scrubbed<&net::SocketAddr> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match scrubber() {
+            None => write!(fmt, "{}", self.0),
+            Some(scrubber) => scrubber.write_addr(fmt, self.0),
+        }
+    }
+}
+
+impl fmt::Display for Scrubbed<&str> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match net::SocketAddr::from_str(self.0) {
+            Ok(address) => write!(fmt, "{}", addr(&address)),
+            Err(_) => write!(fmt, "{}", self.0),
+        }
+    }
+}
+
+impl Scrubber {
+    fn write_addr(&self, fmt: &mut fmt::Formatter<'_>, address: &net::SocketAddr) -> fmt::Result {
+        match address {
+            net::SocketAddr::V4(_) => {
+                self.write_ip(fmt, &address.ip())?;
+                write!(fmt, ":{}", address.port())
+            }
+            net::SocketAddr::V6(_) => {
+                write!(fmt, "[")?;
+                self.write_ip(fmt, &address.ip())?;
+                write!(fmt, "]:{}", address.port())
+            }
+        }
+    }
+
+    fn write_ip(&self, fmt: &mut fmt::Formatter<'_>, address: &net::IpAddr) -> fmt::Result {
+        match self.mode {
+            Mode::Off => write!(fmt, "{address}"),
+            Mode::Hash => {
+                let bytes = match address {
+                    net::IpAddr::V4(v4) => v4.octets().to_vec(),
+                    net::IpAddr::V6(v6) => v6.octets().to_vec(),
+                };
+                let digest = hmac_sha256(&self.key, &bytes);
+                write!(fmt, "ip-")?;
+                for byte in &digest[..6] {
+                    write!(fmt, "{byte:02x}")?;
+                }
+                Ok(())
+            }
+            Mode::Redact => match address {
+                net::IpAddr::V4(v4) => {
+                    let [a, b, c, _] = v4.octets();
+                    write!(fmt, "{}/24", net::Ipv4Addr::new(a, b, c, 0))
+                }
+                net::IpAddr::V6(v6) => {
+                    let mut segments = v6.segments();
+                    segments[3..].fill(0);
+                    write!(fmt, "{}/48", net::Ipv6Addr::from(segments))
+                }
+            },
+        }
+    }
+}
+
+/// HMAC (RFC 2104) with SHA-256
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block_key = [0u8; 64];
+    if key.len() <= block_key.len() {
+        block_key[..key.len()].copy_from_slice(key);
+    } else {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        block_key[..32].copy_from_slice(&hasher.finalize());
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&block_key.map(|b| b ^ 0x36));
+    inner.update(data);
+
+    let mut outer = Sha256::new();
+    outer.update(&block_key.map(|b| b ^ 0x5c));
+    outer.update(&inner.finalize());
+    outer.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_sha256, init, Mode, Scrubber};
+    use crate::testing::{self, Diode, TempDir};
+    use std::{env, fmt, fs, net, process, time};
+
+    /// Address formatted by a given scrubber rather than the one of the process
+    struct Formatted<'a>(&'a Scrubber, net::SocketAddr);
+
+    impl fmt::Display for Formatted<'_> {
+        fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+            self.0.write_addr(fmt, &self.1)
+        }
+    }
+
+    fn scrubbed(mode: Mode, key: &[u8], address: &str) -> String {
+        let scrubber = Scrubber {
+            mode,
+            key: key.to_vec(),
+        };
+        let address = address.parse().expect("address");
+        Formatted(&scrubber, address).to_string()
+    }
+
+    #[test]
+    fn modes() {
+        for mode in [Mode::Off, Mode::Hash, Mode::Redact] {
+            assert!(mode.to_string().parse::<Mode>() == Ok(mode));
+        }
+        assert!("hashed".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn hmac() {
+        // RFC 4231, test case 2
+        let digest = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        let hex = digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn hashed() {
+        let hashed = scrubbed(Mode::Hash, b"key", "10.1.2.3:7000");
+        assert_eq!(hashed.len(), "ip-".len() + 12 + ":7000".len());
+        assert!(hashed.starts_with("ip-") && hashed.ends_with(":7000"));
+        assert_eq!(
+            scrubbed(Mode::Hash, b"key", "10.1.2.3:80"),
+            hashed.replace(":7000", ":80")
+        );
+        assert_ne!(scrubbed(Mode::Hash, b"key", "10.1.2.4:7000"), hashed);
+        assert_ne!(scrubbed(Mode::Hash, b"other key", "10.1.2.3:7000"), hashed);
+
+        let hashed = scrubbed(Mode::Hash, b"key", "[fd00::1]:7000");
+        assert!(hashed.starts_with("[ip-") && hashed.ends_with("]:7000"));
+        assert!(!hashed.contains("fd00"));
+    }
+
+    #[test]
+    fn redacted() {
+        assert_eq!(
+            scrubbed(Mode::Redact, b"", "10.1.2.3:7000"),
+            "10.1.2.0/24:7000"
+        );
+        assert_eq!(
+            scrubbed(Mode::Redact, b"", "[2001:db8:1:2:3:4:5:6]:7000"),
+            "[2001:db8:1::/48]:7000"
+        );
+        assert_eq!(scrubbed(Mode::Off, b"", "10.1.2.3:7000"), "10.1.2.3:7000");
+    }
+
+    #[test]
+    fn empty_key_file() {
+        let dir = TempDir::new("scrub");
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, b"").expect("write key file");
+        assert!(init(Mode::Hash, Some(&key_file)).is_err());
+        assert!(init(Mode::Hash, Some(&dir.path().join("missing"))).is_err());
+    }
+
+    const CHILD_LOG: &str = "LIDI_TEST_SCRUB_LOG";
+
+    /// Runs a transfer in a child process, addresses being hashed and every record being logged
+    /// to a file, in which no loopback address must appear
+    #[test]
+    fn logs_scrubbed() {
+        if let Some(log) = env::var_os(CHILD_LOG) {
+            init(Mode::Hash, None).expect("scrub addresses");
+            crate::init_logger_with(crate::LogFormat::Text, Some(log.as_ref()), false)
+                .expect("logger");
+            let diode = Diode::start(testing::loopback_config(), |_| (), |_| ());
+            diode.send(b"scrubbed");
+            let received = diode.receive(time::Duration::from_secs(10));
+            assert_eq!(received.as_deref(), Some(&b"scrubbed"[..]));
+            return;
+        }
+
+        let dir = TempDir::new("scrub");
+        let log = dir.path().join("log");
+        let status = process::Command::new(env::current_exe().expect("test executable"))
+            .args(["--exact", "scrub::tests::logs_scrubbed", "--test-threads=1"])
+            .env(CHILD_LOG, &log)
+            .env("RUST_LOG", "trace")
+            .stdout(process::Stdio::null())
+            .status()
+            .expect("run child");
+        assert!(status.success());
+
+        let log = fs::read_to_string(log).expect("read log");
+        assert!(log.contains("ip-"), "no scrubbed address logged");
+        for (nb, line) in log.lines().enumerate() {
+            assert!(!line.contains("127.0.0."), "line {nb}: {line}");
+        }
+    }
+}
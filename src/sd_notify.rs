@@ -0,0 +1,272 @@
+//! Minimal client of the systemd notification and socket activation protocols, see
+//! `sd_notify(3)` and `sd_listen_fds(3)`
+//!
+//! Notifications are datagrams sent to the Unix socket given by the `NOTIFY_SOCKET` environment
+//! variable, which may be a path or an abstract socket name starting with `@`. When the
+//! variable is not set, i.e. when not run by systemd, notifications are silently ignored.
+//!
+//! With socket activation, the service manager binds the sockets of the unit and passes them to
+//! the process as file descriptors, starting at 3, their number being given by the `LISTEN_FDS`
+//! environment variable. [Activated] takes them, to be used in place of the configured
+//! addresses.
+
+use std::{
+    env, ffi, io, mem, net,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{self, ffi::OsStrExt},
+    },
+    path, process, ptr, time,
+};
+
+/// Sends `state` (e.g. `READY=1` or `WATCHDOG=1`) to the service manager
+///
+/// Returns `false` if no service manager expects notifications.
+pub fn notify(state: &str) -> Result<bool, io::Error> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+
+    let socket = unix::net::UnixDatagram::unbound()?;
+
+    let path = path.as_encoded_bytes();
+    if let Some(name) = path.strip_prefix(b"@") {
+        let addr = unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+    } else {
+        socket.send_to(state.as_bytes(), ffi::OsStr::from_bytes(path))?;
+    }
+
+    Ok(true)
+}
+
+/// Returns the interval at which `WATCHDOG=1` notifications must be sent, if the service manager
+/// enabled the watchdog for this process
+///
+/// As recommended by systemd, the interval is half of the `WATCHDOG_USEC` timeout.
+pub fn watchdog_interval() -> Option<time::Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(time::Duration::from_micros(usec) / 2)
+}
+
+/// First file descriptor passed by the service manager with socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by the service manager with socket activation, which the binaries take in
+/// place of binding the configured addresses
+#[derive(Default)]
+pub struct Activated {
+    tcp: Vec<net::TcpListener>,
+    udp: Vec<net::UdpSocket>,
+    unix: Vec<unix::net::UnixListener>,
+}
+
+impl Activated {
+    /// Takes the sockets passed by the service manager, none when the process was not socket
+    /// activated
+    ///
+    /// The `LISTEN_*` variables are removed from the environment, so that child processes do not
+    /// take the sockets for theirs: this must be called before any thread is started. Only TCP
+    /// and Unix stream listening sockets and UDP sockets are supported.
+    pub fn from_env() -> Result<Self, io::Error> {
+        let nb_fds = env::var_os("LISTEN_FDS");
+        let pid = env::var_os("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let mut activated = Self::default();
+        let Some(nb_fds) = nb_fds else {
+            return Ok(activated);
+        };
+        if pid.is_some_and(|pid| pid.to_str() != Some(&process::id().to_string())) {
+            return Ok(activated);
+        }
+        let nb_fds = nb_fds
+            .to_str()
+            .and_then(|nb_fds| nb_fds.parse::<RawFd>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid LISTEN_FDS"))?;
+
+        for raw_fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(nb_fds) {
+            if unsafe { libc::fcntl(raw_fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // the descriptor is valid and belongs to the process from now on
+            let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+            match (
+                socket_option(&fd, libc::SO_DOMAIN)?,
+                socket_option(&fd, libc::SO_TYPE)?,
+            ) {
+                (libc::AF_INET | libc::AF_INET6, libc::SOCK_STREAM) => {
+                    activated.tcp.push(net::TcpListener::from(fd));
+                }
+                (libc::AF_INET | libc::AF_INET6, libc::SOCK_DGRAM) => {
+                    activated.udp.push(net::UdpSocket::from(fd));
+                }
+                (libc::AF_UNIX, libc::SOCK_STREAM) => {
+                    activated.unix.push(unix::net::UnixListener::from(fd));
+                }
+                (domain, kind) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "unsupported socket {raw_fd} of domain {domain} and type {kind} passed by the service manager"
+                        ),
+                    ))
+                }
+            }
+        }
+
+        Ok(activated)
+    }
+
+    /// Takes the TCP listening socket bound to `addr`, if passed by the service manager
+    pub fn take_tcp(&mut self, addr: net::SocketAddr) -> Option<net::TcpListener> {
+        let i = self
+            .tcp
+            .iter()
+            .position(|listener| listener.local_addr().is_ok_and(|a| a == addr))?;
+        Some(self.tcp.remove(i))
+    }
+
+    /// Takes the UDP socket bound to `addr`, if passed by the service manager
+    pub fn take_udp(&mut self, addr: net::SocketAddr) -> Option<net::UdpSocket> {
+        let i = self
+            .udp
+            .iter()
+            .position(|socket| socket.local_addr().is_ok_and(|a| a == addr))?;
+        Some(self.udp.remove(i))
+    }
+
+    /// Takes the Unix listening socket bound to `path`, if passed by the service manager
+    pub fn take_unix(&mut self, path: &path::Path) -> Option<unix::net::UnixListener> {
+        let i = self.unix.iter().position(|listener| {
+            listener
+                .local_addr()
+                .is_ok_and(|a| a.as_pathname() == Some(path))
+        })?;
+        Some(self.unix.remove(i))
+    }
+
+    /// Returns the addresses of the sockets which were not taken
+    pub fn remaining(&self) -> Vec<String> {
+        let tcp = self
+            .tcp
+            .iter()
+            .map(|l| l.local_addr().map(|a| a.to_string()));
+        let udp = self
+            .udp
+            .iter()
+            .map(|s| s.local_addr().map(|a| a.to_string()));
+        let unix = self.unix.iter().map(|l| {
+            l.local_addr().map(|a| match a.as_pathname() {
+                Some(path) => format!("'{}'", path.display()),
+                None => "unnamed Unix socket".to_string(),
+            })
+        });
+        tcp.chain(udp)
+            .chain(unix)
+            .map(|addr| addr.unwrap_or_else(|e| format!("unknown address ({e})")))
+            .collect()
+    }
+}
+
+/// Returns the integer value of the socket level `option` of `fd`
+fn socket_option(fd: &OwnedFd, option: libc::c_int) -> Result<libc::c_int, io::Error> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            ptr::addr_of_mut!(value).cast::<libc::c_void>(),
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use std::{
+        env,
+        os::{linux::net::SocketAddrExt, unix},
+        process, time,
+    };
+
+    const TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+    #[test]
+    fn notify() {
+        let socket = testing::NotifySocket::bind();
+        assert!(super::notify("READY=1").unwrap_or_else(|e| panic!("{e}")));
+        assert_eq!(socket.receive(TIMEOUT).as_deref(), Some("READY=1"));
+        drop(socket);
+
+        let _env = testing::NotifySocket::lock_env();
+        assert!(!super::notify("READY=1").unwrap_or_else(|e| panic!("{e}")));
+
+        let name = format!("lidi-test-notify-{}", process::id());
+        let addr = unix::net::SocketAddr::from_abstract_name(&name).expect("abstract address");
+        let socket = unix::net::UnixDatagram::bind_addr(&addr).expect("bind abstract socket");
+        socket
+            .set_read_timeout(Some(TIMEOUT))
+            .expect("read timeout");
+        env::set_var("NOTIFY_SOCKET", format!("@{name}"));
+        let res = super::notify("WATCHDOG=1");
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(res.unwrap_or_else(|e| panic!("{e}")));
+        let mut buffer = [0; 16];
+        let len = socket.recv(&mut buffer).expect("notification");
+        assert_eq!(&buffer[..len], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn watchdog_interval() {
+        let _env = testing::NotifySocket::lock_env();
+        let interval = |usec: Option<&str>, pid: Option<String>| {
+            match usec {
+                Some(usec) => env::set_var("WATCHDOG_USEC", usec),
+                None => env::remove_var("WATCHDOG_USEC"),
+            }
+            match pid {
+                Some(pid) => env::set_var("WATCHDOG_PID", pid),
+                None => env::remove_var("WATCHDOG_PID"),
+            }
+            let interval = super::watchdog_interval();
+            env::remove_var("WATCHDOG_USEC");
+            env::remove_var("WATCHDOG_PID");
+            interval
+        };
+
+        let half = Some(time::Duration::from_millis(1500));
+        assert_eq!(interval(None, None), None);
+        assert_eq!(interval(Some("3000000"), None), half);
+        assert_eq!(
+            interval(Some("3000000"), Some(process::id().to_string())),
+            half
+        );
+        assert_eq!(
+            interval(Some("3000000"), Some((process::id() + 1).to_string())),
+            None
+        );
+        assert_eq!(interval(Some("0"), None), None);
+        assert_eq!(interval(Some("3s"), None), None);
+    }
+}
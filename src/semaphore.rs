@@ -1,30 +1,274 @@
 //! Simple semaphores built on top of `std::sync` primitives, no external dependency
 
-use std::sync::{Arc, Condvar, Mutex};
+use crate::metrics;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Condvar, Mutex},
+    time,
+};
 
+struct State {
+    available: usize,
+    waiters: usize,
+    /// Number of waiters of each rank
+    ranks: BTreeMap<u32, usize>,
+}
+
+impl State {
+    /// Whether a waiter of `rank` may take a permit, no waiter of a higher rank waiting
+    fn is_eligible(&self, rank: u32) -> bool {
+        0 < self.available && self.ranks.range(rank.saturating_add(1)..).next().is_none()
+    }
+}
+
+struct Inner {
+    count: usize,
+    state: Mutex<State>,
+    cv: Condvar,
+    /// Gauges of the permits in use and of the waiters, updated under the lock
+    gauges: Option<(Arc<metrics::Metric>, Arc<metrics::Metric>)>,
+}
+
+/// Counting semaphore, waiters are not guaranteed to be served in arrival order
+///
+/// Waiters of a higher rank are served before those of a lower rank, [Semaphore::acquire]
+/// waiting with the lowest rank 0.
 #[derive(Clone)]
-pub struct Semaphore(Arc<(Mutex<usize>, Condvar)>);
+pub struct Semaphore(Arc<Inner>);
 
 impl Semaphore {
     pub fn new(count: usize) -> Self {
-        Self(Arc::new((Mutex::new(count), Condvar::new())))
+        Self::build(count, None)
+    }
+
+    /// Same as [Semaphore::new], the number of permits in use and of waiters being exported in
+    /// the `in_use` and `waiters` gauges
+    pub(crate) fn with_gauges(count: usize, in_use: &str, waiters: &str) -> Self {
+        Self::build(
+            count,
+            Some((metrics::gauge(in_use), metrics::gauge(waiters))),
+        )
+    }
+
+    fn build(count: usize, gauges: Option<(Arc<metrics::Metric>, Arc<metrics::Metric>)>) -> Self {
+        let inner = Inner {
+            count,
+            state: Mutex::new(State {
+                available: count,
+                waiters: 0,
+                ranks: BTreeMap::new(),
+            }),
+            cv: Condvar::new(),
+            gauges,
+        };
+        inner.update_gauges(&inner.state.lock().expect("acquire lock"));
+        Self(Arc::new(inner))
     }
 
     pub(crate) fn acquire(&self) {
-        let (lock, cv) = &*self.0;
-        let mut counter = lock.lock().expect("acquire lock");
-        while *counter == 0 {
-            counter = cv
-                .wait_while(counter, |counter| *counter == 0)
-                .expect("condvar wait");
+        self.acquire_ranked(0, None);
+    }
+
+    /// Acquires a permit if one is available to a waiter of `rank`, without waiting
+    pub(crate) fn try_acquire(&self, rank: u32) -> bool {
+        let mut state = self.0.state.lock().expect("acquire lock");
+        if !state.is_eligible(rank) {
+            return false;
         }
-        *counter = counter.checked_sub(1).expect("semaphore counter decrement");
+        self.0.take(&mut state);
+        true
+    }
+
+    /// Waits with `rank` for a permit, at most `timeout` if any, returning whether it was
+    /// acquired
+    pub(crate) fn acquire_ranked(&self, rank: u32, timeout: Option<time::Duration>) -> bool {
+        let mut state = self.0.state.lock().expect("acquire lock");
+        if !state.is_eligible(rank) {
+            state.waiters += 1;
+            *state.ranks.entry(rank).or_default() += 1;
+            self.0.update_gauges(&state);
+            // the wait_while variants account for spurious wakeups and permits taken by others
+            state = match timeout {
+                None => self
+                    .0
+                    .cv
+                    .wait_while(state, |state| !state.is_eligible(rank))
+                    .expect("condvar wait"),
+                Some(timeout) => {
+                    self.0
+                        .cv
+                        .wait_timeout_while(state, timeout, |state| !state.is_eligible(rank))
+                        .expect("condvar wait")
+                        .0
+                }
+            };
+            state.waiters -= 1;
+            match state.ranks.get_mut(&rank) {
+                Some(1) => {
+                    state.ranks.remove(&rank);
+                    // waiters of lower ranks may have become eligible
+                    self.0.cv.notify_all();
+                }
+                Some(count) => *count -= 1,
+                None => unreachable!("waiter rank is counted"),
+            }
+            if !state.is_eligible(rank) {
+                self.0.update_gauges(&state);
+                return false;
+            }
+        }
+        self.0.take(&mut state);
+        true
     }
 
     pub(crate) fn release(&self) {
-        let (lock, cv) = &*self.0;
-        let mut counter = lock.lock().expect("acquire lock");
-        *counter = counter.checked_add(1).expect("semaphore counter increment");
-        cv.notify_one();
+        let mut state = self.0.state.lock().expect("acquire lock");
+        state.available = state
+            .available
+            .checked_add(1)
+            .expect("semaphore counter increment");
+        self.0.update_gauges(&state);
+        // the first waiter woken up may not be eligible because of its rank
+        self.0.cv.notify_all();
+    }
+
+    /// Number of threads waiting for a permit
+    pub(crate) fn waiters(&self) -> usize {
+        self.0.state.lock().expect("acquire lock").waiters
+    }
+
+    /// Number of permits currently acquired
+    pub(crate) fn in_use(&self) -> usize {
+        self.0.count - self.0.state.lock().expect("acquire lock").available
+    }
+}
+
+impl Inner {
+    fn take(&self, state: &mut State) {
+        state.available = state
+            .available
+            .checked_sub(1)
+            .expect("semaphore counter decrement");
+        self.update_gauges(state);
+    }
+
+    fn update_gauges(&self, state: &State) {
+        if let Some((in_use, waiters)) = &self.gauges {
+            in_use.set((self.count - state.available) as u64);
+            waiters.set(state.waiters as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{atomic, mpsc},
+        thread,
+    };
+
+    /// Waits until `n` threads wait for a permit of `semaphore`
+    fn wait_waiters(semaphore: &Semaphore, n: usize) {
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while semaphore.waiters() != n {
+            assert!(time::Instant::now() < deadline, "{n} waiters expected");
+            thread::sleep(time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn contention() {
+        let semaphore = Semaphore::new(3);
+        let holders = atomic::AtomicUsize::new(0);
+        let max_holders = atomic::AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for rank in 0..16 {
+                let (semaphore, holders, max_holders) = (&semaphore, &holders, &max_holders);
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        assert!(semaphore.acquire_ranked(rank % 3, None));
+                        let n = holders.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+                        max_holders.fetch_max(n, atomic::Ordering::SeqCst);
+                        thread::yield_now();
+                        holders.fetch_sub(1, atomic::Ordering::SeqCst);
+                        semaphore.release();
+                    }
+                });
+            }
+        });
+
+        assert!(max_holders.into_inner() <= 3);
+        assert_eq!(semaphore.in_use(), 0);
+        assert_eq!(semaphore.waiters(), 0);
+    }
+
+    #[test]
+    fn try_acquire() {
+        let semaphore = Semaphore::new(2);
+        assert!(semaphore.try_acquire(0));
+        assert!(semaphore.try_acquire(5));
+        assert!(!semaphore.try_acquire(u32::MAX));
+        assert_eq!(semaphore.in_use(), 2);
+        semaphore.release();
+        assert!(semaphore.try_acquire(0));
+    }
+
+    #[test]
+    fn timeout() {
+        let semaphore = Semaphore::new(1);
+        semaphore.acquire();
+
+        let timeout = time::Duration::from_millis(50);
+        let start = time::Instant::now();
+        assert!(!semaphore.acquire_ranked(0, Some(timeout)));
+        assert!(timeout <= start.elapsed());
+        assert_eq!(semaphore.waiters(), 0);
+        assert_eq!(semaphore.in_use(), 1);
+
+        // a permit released while waiting is acquired before the timeout
+        thread::scope(|scope| {
+            let waiter =
+                scope.spawn(|| semaphore.acquire_ranked(0, Some(time::Duration::from_secs(10))));
+            wait_waiters(&semaphore, 1);
+            semaphore.release();
+            assert!(waiter.join().expect("waiter"));
+        });
+        assert_eq!(semaphore.in_use(), 1);
+    }
+
+    #[test]
+    fn rank_priority() {
+        let semaphore = Semaphore::new(1);
+        semaphore.acquire();
+        let (to_served, served) = mpsc::channel();
+
+        thread::scope(|scope| {
+            for (i, rank) in [0, 2, 1, 2].into_iter().enumerate() {
+                let (semaphore, to_served) = (&semaphore, to_served.clone());
+                scope.spawn(move || {
+                    assert!(semaphore.acquire_ranked(rank, None));
+                    to_served.send(rank).expect("send rank");
+                    semaphore.release();
+                });
+                wait_waiters(semaphore, i + 1);
+            }
+
+            semaphore.release();
+        });
+
+        assert_eq!(served.try_iter().collect::<Vec<_>>(), [2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn lower_ranks_served_after_timeout() {
+        let semaphore = Semaphore::new(1);
+        semaphore.acquire();
+
+        assert!(!semaphore.acquire_ranked(1, Some(time::Duration::from_millis(10))));
+        semaphore.release();
+        // the waiter that gave up no longer holds back waiters of lower ranks
+        assert!(semaphore.try_acquire(0));
     }
 }
@@ -0,0 +1,190 @@
+//! Backpressure signaled to clients by shrinking the receive buffer of their sockets
+//!
+//! Without it, clients only notice that the UDP link is the bottleneck when all the channels are
+//! full and their writes block at once. The udp worker measures the number of messages and
+//! encoded blocks waiting to be sent: above the high-water mark the backpressure level is raised,
+//! below the low-water mark it is lowered, one step at a time and at most once per
+//! [STEP_INTERVAL]. Clients workers halve the receive buffer of their socket for each level, so
+//! that TCP flow control slows producers down gradually.
+//!
+//! The high-water and low-water marks are defined by [Watermarks], also used to decide when new
+//! clients are deferred (see [crate::send::shedding]).
+
+use crate::metrics;
+use std::{
+    sync::{
+        self,
+        atomic::{AtomicU8, Ordering},
+    },
+    time,
+};
+
+/// Highest backpressure level, dividing receive buffers by 2 to the power of this value
+pub(crate) const MAX_LEVEL: u8 = 4;
+
+/// Minimum time between two level changes, leaving TCP flow control time to react
+pub(crate) const STEP_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Hysteresis thresholds on the number of messages and blocks waiting to be sent
+#[derive(Clone, Copy)]
+pub(crate) struct Watermarks {
+    high_water: usize,
+    low_water: usize,
+}
+
+impl Watermarks {
+    /// Thresholds are set at three quarters and one quarter of `capacity`, the number of messages
+    /// and blocks the channels can hold
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            high_water: (3 * capacity).div_ceil(4),
+            low_water: capacity / 4,
+        }
+    }
+
+    pub(crate) const fn is_high(&self, backlog: usize) -> bool {
+        self.high_water <= backlog
+    }
+
+    pub(crate) const fn is_low(&self, backlog: usize) -> bool {
+        backlog <= self.low_water
+    }
+}
+
+pub(crate) struct Backpressure {
+    watermarks: Watermarks,
+    level: AtomicU8,
+    changed_at: sync::Mutex<time::Instant>,
+    gauge: sync::Arc<metrics::Metric>,
+}
+
+impl Backpressure {
+    pub(crate) fn new(watermarks: Watermarks) -> Self {
+        let gauge = metrics::gauge("tx_backpressure_level");
+        gauge.set(0);
+        Self {
+            watermarks,
+            level: AtomicU8::new(0),
+            changed_at: sync::Mutex::new(time::Instant::now()),
+            gauge,
+        }
+    }
+
+    pub(crate) fn level(&self) -> u8 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    /// Adjusts the level according to the current `backlog`, returning the new level
+    pub(crate) fn update(&self, backlog: usize) -> u8 {
+        let level = self.level();
+        let new_level = if self.watermarks.is_high(backlog) && level < MAX_LEVEL {
+            level + 1
+        } else if self.watermarks.is_low(backlog) && 0 < level {
+            level - 1
+        } else {
+            return level;
+        };
+
+        let mut changed_at = self.changed_at.lock().expect("acquire lock");
+        if changed_at.elapsed() < STEP_INTERVAL {
+            return level;
+        }
+        *changed_at = time::Instant::now();
+        drop(changed_at);
+
+        log::debug!("backpressure level {level} -> {new_level} with {backlog} block(s) pending");
+        self.level.store(new_level, Ordering::Relaxed);
+        self.gauge.set(u64::from(new_level));
+        new_level
+    }
+}
+
+/// Receive buffer size to set on client sockets at `level`, `normal_size` being the size set
+/// without backpressure
+///
+/// Buffers are not shrunk below `block_size`: a client could then not fill a block per round-trip
+/// and throughput would collapse instead of slowing down.
+pub(crate) fn recv_buffer_size(normal_size: u32, block_size: u32, level: u8) -> u32 {
+    if level == 0 {
+        normal_size
+    } else {
+        (normal_size >> level).max(block_size).min(normal_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recv_buffer_size, Backpressure, Watermarks, MAX_LEVEL, STEP_INTERVAL};
+    use std::time;
+
+    /// Lets the next update change the level without waiting for [STEP_INTERVAL]
+    fn step(backpressure: &Backpressure, backlog: usize) -> u8 {
+        *backpressure.changed_at.lock().expect("acquire lock") -= STEP_INTERVAL;
+        backpressure.update(backlog)
+    }
+
+    #[test]
+    fn watermarks() {
+        let watermarks = Watermarks::new(10);
+        assert!(!watermarks.is_high(7));
+        assert!(watermarks.is_high(8));
+        assert!(watermarks.is_low(2));
+        assert!(!watermarks.is_low(3));
+
+        // a single message queue is high when full, low when empty
+        let watermarks = Watermarks::new(1);
+        assert!(watermarks.is_high(1));
+        assert!(watermarks.is_low(0));
+        assert!(!watermarks.is_low(1));
+    }
+
+    #[test]
+    fn hysteresis() {
+        let backpressure = Backpressure::new(Watermarks::new(8));
+        assert_eq!(backpressure.level(), 0);
+
+        // raised one level at a time, at most once per step interval
+        assert_eq!(step(&backpressure, 6), 1);
+        assert_eq!(backpressure.update(8), 1);
+        assert_eq!(step(&backpressure, 8), 2);
+
+        // kept between the watermarks
+        for backlog in 3..6 {
+            assert_eq!(step(&backpressure, backlog), 2);
+        }
+
+        // up to the highest level
+        for _ in 0..2 * MAX_LEVEL {
+            step(&backpressure, 8);
+        }
+        assert_eq!(backpressure.level(), MAX_LEVEL);
+
+        // lowered one level at a time, down to 0
+        assert_eq!(step(&backpressure, 2), MAX_LEVEL - 1);
+        assert_eq!(backpressure.update(0), MAX_LEVEL - 1);
+        for _ in 0..2 * MAX_LEVEL {
+            step(&backpressure, 0);
+        }
+        assert_eq!(backpressure.level(), 0);
+
+        // a change waits for the step interval
+        let backpressure = Backpressure::new(Watermarks::new(8));
+        let started = time::Instant::now();
+        while backpressure.update(8) == 0 {
+            assert!(started.elapsed() < 10 * STEP_INTERVAL, "level not raised");
+        }
+        assert!(STEP_INTERVAL <= started.elapsed());
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        let normal = 4 * 1024 * 1024;
+        assert_eq!(recv_buffer_size(normal, 60_000, 0), normal);
+        assert_eq!(recv_buffer_size(normal, 60_000, 1), normal / 2);
+        assert_eq!(recv_buffer_size(normal, 60_000, MAX_LEVEL), normal / 16);
+        // never below a block, nor above the normal size
+        assert_eq!(recv_buffer_size(200_000, 60_000, MAX_LEVEL), 60_000);
+        assert_eq!(recv_buffer_size(50_000, 60_000, 1), 50_000);
+        assert_eq!(recv_buffer_size(50_000, 60_000, 0), 50_000);
+    }
+}
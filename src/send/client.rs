@@ -1,19 +1,37 @@
 //! Worker that reads data from a client socket and split it into [crate::protocol] messages
+//!
+//! Two levels of flush are distinguished: a block is emitted as soon as the buffer is full, and a
+//! partially filled block is only emitted once its oldest byte has been waiting for
+//! `flush_timeout`. Small writes arriving within this latency bound are thus gathered in the same
+//! block instead of each producing a mostly padded block.
 
-use crate::{protocol, send, sock_utils};
-use std::{io, os::fd::AsRawFd};
+use crate::{metrics, protocol, scrub, send, send::backpressure, send::scan, sock_utils};
+use std::{io, net, os::fd::AsRawFd, sync, time};
 
 pub(crate) fn start<C>(
     sender: &send::Sender<C>,
     client_id: protocol::ClientId,
+    peer: Option<net::SocketAddr>,
     mut client: C,
 ) -> Result<(), send::Error>
 where
     C: io::Read + AsRawFd + Send,
 {
-    log::info!("client {client_id:x}: connected");
+    match peer {
+        Some(peer) => log::info!(
+            "client {client_id:x}: connected from {}",
+            scrub::addr(&peer)
+        ),
+        None => log::info!("client {client_id:x}: connected"),
+    }
 
-    let mut buffer = vec![0; sender.from_buffer_size as usize];
+    // with compression, data is read by several blocks at once in the hope it fits in one block
+    let buffer_size = if sender.config.compress {
+        sender.from_buffer_size * protocol::MAX_COMPRESSION_RATIO
+    } else {
+        sender.from_buffer_size
+    };
+    let mut buffer = vec![0; buffer_size as usize];
     let mut cursor = 0;
     let mut transmitted = 0;
 
@@ -32,113 +50,279 @@ where
         }
     }
 
+    // the kernel doubles the size it is given, and reports the doubled size
+    let normal_sock_buffer_size = sock_utils::get_socket_recv_buffer_size(&client)? as u32 / 2;
+    let mut backpressure_level = 0;
+
+    let flush_timeout = sender.tunables.flush_timeout();
+    sock_utils::set_socket_recv_timeout(&client, flush_timeout)?;
+    let mut read_timeout = flush_timeout;
+
+    let mut scans = scan::Scans::new(&sender.config.scan_policies);
+    let session = SessionMetrics::new(client_id, peer);
+
     let mut is_first = true;
+    let mut pending_since: Option<time::Instant> = None;
 
     loop {
-        log::trace!("client {client_id:x}: read...");
+        let mut flush = false;
 
-        match client.read(&mut buffer[cursor..]) {
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => {
-                    if 0 < cursor {
-                        log::debug!("client {client_id:x}: flushing pending data");
+        if let (Some(flush_timeout), Some(pending_since)) = (flush_timeout, pending_since) {
+            let remaining = flush_timeout.saturating_sub(pending_since.elapsed());
+            if remaining < MIN_READ_TIMEOUT {
+                flush = true;
+            } else if read_timeout.is_some_and(|timeout| {
+                // updating the socket timeout only when it drifted enough from the deadline, to
+                // avoid a system call per read
+                flush_timeout / 10 < timeout.saturating_sub(remaining)
+            }) {
+                sock_utils::set_socket_recv_timeout(&client, Some(remaining))?;
+                read_timeout = Some(remaining);
+            }
+        }
 
-                        transmitted += cursor;
+        if !flush {
+            let level = sender.backpressure_level();
+            if level != backpressure_level {
+                let size = backpressure::recv_buffer_size(
+                    normal_sock_buffer_size,
+                    sender.from_buffer_size,
+                    level,
+                );
+                log::debug!("client {client_id:x}: backpressure level {level}, recv buffer size set to {size}");
+                sock_utils::set_socket_recv_buffer_size(&client, size as i32)?;
+                backpressure_level = level;
+            }
 
-                        let message_type = if is_first {
-                            protocol::MessageType::Start
-                        } else {
-                            protocol::MessageType::Data
-                        };
+            log::trace!("client {client_id:x}: read...");
 
-                        is_first = false;
+            match client.read(&mut buffer[cursor..]) {
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => flush = true,
+                    _ => return Err(e.into()),
+                },
+                Ok(0) => {
+                    log::trace!("client {client_id:x}: end of stream");
 
-                        sender.to_encoding.send(protocol::Message::new(
-                            message_type,
+                    if 0 < cursor {
+                        // handling incomplete last packet
+                        log::trace!("client {client_id:x}: send last buffer");
+                        transmitted += cursor;
+                        send_data(
+                            sender,
+                            client_id,
+                            &session,
+                            &mut scans,
+                            &mut is_first,
+                            &buffer[..cursor],
+                        )?;
+                    }
+
+                    if !is_first {
+                        sender.encoding_queue.push(protocol::Message::new(
+                            protocol::MessageType::End,
                             sender.from_buffer_size,
                             client_id,
-                            Some(&buffer[..cursor]),
-                        ))?;
+                            None,
+                        ));
+                        session.blocks.inc();
 
-                        cursor = 0;
+                        if sender.config.confirm_flush {
+                            log::debug!("client {client_id:x}: waiting for end of emission");
+                            sender.wait_flushed(client_id);
+                        }
                     }
+
+                    log::info!("client {client_id:x}: disconnect, {transmitted} bytes transmitted");
+
+                    return Ok(());
                 }
-                _ => return Err(e.into()),
-            },
-            Ok(0) => {
-                log::trace!("client {client_id:x}: end of stream");
 
-                if 0 < cursor {
-                    // handling incomplete last packet
-                    log::trace!("client {client_id:x}: send last buffer");
+                Ok(nread) => {
+                    log::trace!("client {client_id:x}: {nread} bytes read");
 
-                    transmitted += cursor;
+                    if cursor == 0 {
+                        pending_since = Some(time::Instant::now());
+                    }
 
-                    let message_type = if is_first {
-                        protocol::MessageType::Start
+                    if (cursor + nread) < buffer.len() {
+                        // buffer is not full
+                        log::trace!("client {client_id:x}: buffer is not full, looping");
+                        cursor += nread;
                     } else {
-                        protocol::MessageType::Data
-                    };
-
-                    is_first = false;
+                        // buffer is full
+                        log::trace!(
+                            "client {client_id:x}: send full buffer ({} bytes)",
+                            buffer.len()
+                        );
 
-                    sender.to_encoding.send(protocol::Message::new(
-                        message_type,
-                        sender.from_buffer_size,
-                        client_id,
-                        Some(&buffer[..cursor]),
-                    ))?;
+                        transmitted += buffer.len();
+                        send_data(
+                            sender,
+                            client_id,
+                            &session,
+                            &mut scans,
+                            &mut is_first,
+                            &buffer,
+                        )?;
+                        cursor = 0;
+                    }
                 }
+            }
+        }
 
-                if !is_first {
-                    sender.to_encoding.send(protocol::Message::new(
-                        protocol::MessageType::End,
-                        sender.from_buffer_size,
-                        client_id,
-                        None,
-                    ))?;
-                }
+        if flush && 0 < cursor {
+            log::debug!("client {client_id:x}: flushing pending data");
+            transmitted += cursor;
+            send_data(
+                sender,
+                client_id,
+                &session,
+                &mut scans,
+                &mut is_first,
+                &buffer[..cursor],
+            )?;
+            cursor = 0;
+        }
 
-                log::info!("client {client_id:x}: disconnect, {transmitted} bytes transmitted");
+        if cursor == 0 && pending_since.take().is_some() && read_timeout != flush_timeout {
+            sock_utils::set_socket_recv_timeout(&client, flush_timeout)?;
+            read_timeout = flush_timeout;
+        }
+    }
+}
 
-                return Ok(());
-            }
+/// Read timeouts below this value are not worth a system call, pending data is flushed instead
+const MIN_READ_TIMEOUT: time::Duration = time::Duration::from_millis(1);
 
-            Ok(nread) => {
-                log::trace!("client {client_id:x}: {nread} bytes read");
+/// Metrics of a session, labeled with its identifier and the address of its client, and removed
+/// once the session ended
+struct SessionMetrics {
+    _scope: metrics::Scoped,
+    bytes: sync::Arc<metrics::Metric>,
+    blocks: sync::Arc<metrics::Metric>,
+}
 
-                if (cursor + nread) < sender.from_buffer_size as usize {
-                    // buffer is not full
-                    log::trace!("client {client_id:x}: buffer is not full, looping");
-                    cursor += nread;
-                    continue;
-                }
+impl SessionMetrics {
+    fn new(client_id: protocol::ClientId, peer: Option<net::SocketAddr>) -> Self {
+        let session = format!("{client_id:x}");
+        let client = peer.map(|peer| scrub::addr(&peer).to_string());
+        let mut scope = metrics::Scoped::new(&[
+            ("session", &session),
+            ("client", client.as_deref().unwrap_or("")),
+        ]);
+        Self {
+            bytes: scope.counter("tx_session_bytes"),
+            blocks: scope.counter("tx_session_blocks"),
+            _scope: scope,
+        }
+    }
+}
 
-                // buffer is full
-                log::trace!(
-                    "client {client_id:x}: send full buffer ({} bytes)",
-                    buffer.len()
-                );
+fn send_data<C>(
+    sender: &send::Sender<C>,
+    client_id: protocol::ClientId,
+    session: &SessionMetrics,
+    scans: &mut scan::Scans,
+    is_first: &mut bool,
+    data: &[u8],
+) -> Result<(), send::Error> {
+    scans
+        .scan(data)
+        .map_err(|(policy, reason)| send::Error::ScanPolicy(policy, reason))?;
+    session.bytes.add(data.len() as u64);
 
-                transmitted += buffer.len();
+    // the data of the first message of the session is preceded by the site identifier
+    let site_id = sender.config.site_id.as_deref().filter(|_| *is_first);
+    let start_site_data = site_id.map(|site_id| protocol::start_site_data(site_id, data));
+    let data = start_site_data.as_deref().unwrap_or(data);
 
-                let message_type = if is_first {
-                    protocol::MessageType::Start
-                } else {
-                    protocol::MessageType::Data
-                };
+    if sender.config.compress {
+        if let Some(message) = protocol::Message::compressed(
+            message_type(*is_first, site_id.is_some()),
+            sender.from_buffer_size,
+            client_id,
+            data,
+        ) {
+            *is_first = false;
+            sender.encoding_queue.push(message);
+            session.blocks.inc();
+            return Ok(());
+        }
+        log::trace!("client {client_id:x}: data does not compress enough, sending it as is");
+    }
 
-                is_first = false;
+    for data in data.chunks(sender.from_buffer_size as usize) {
+        let message_type = message_type(*is_first, site_id.is_some());
+        *is_first = false;
 
-                sender.to_encoding.send(protocol::Message::new(
-                    message_type,
-                    sender.from_buffer_size,
-                    client_id,
-                    Some(&buffer),
-                ))?;
+        sender.encoding_queue.push(protocol::Message::new(
+            message_type,
+            sender.from_buffer_size,
+            client_id,
+            Some(data),
+        ));
+        session.blocks.inc();
+    }
 
-                cursor = 0;
+    Ok(())
+}
+
+const fn message_type(is_first: bool, site_id: bool) -> protocol::MessageType {
+    match (is_first, site_id) {
+        (true, true) => protocol::MessageType::StartSite,
+        (true, false) => protocol::MessageType::Start,
+        (false, _) => protocol::MessageType::Data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use std::{io::Write, net, sync::atomic::Ordering, thread, time};
+
+    #[test]
+    fn small_writes_flushed() {
+        let diode = testing::Diode::start(
+            testing::loopback_config(),
+            |config| config.heartbeat_interval = None,
+            |config| config.heartbeat_interval = None,
+        );
+        let blocks_sent = || diode.sender.blocks_sent.load(Ordering::Relaxed);
+        let nb_blocks = blocks_sent();
+
+        // writes more frequent than the flush timeout of 50 ms, the client staying connected
+        let mut client = diode.connect();
+        let mut sent = Vec::new();
+        let start = time::Instant::now();
+        let mut first_block = None;
+        for i in 0..40u8 {
+            let data = [i; 100];
+            client.write_all(&data).expect("send data");
+            sent.extend_from_slice(&data);
+            if first_block.is_none() && nb_blocks < blocks_sent() {
+                first_block = Some(start.elapsed());
             }
+            thread::sleep(time::Duration::from_millis(10));
         }
+        // emitted once the oldest pending byte waited for the flush timeout
+        let first_block = first_block.expect("pending data flushed while the client writes");
+        assert!(
+            first_block < time::Duration::from_millis(250),
+            "{first_block:?}"
+        );
+        // gathering several writes in each block
+        let nb_small_blocks = blocks_sent() - nb_blocks;
+        assert!(nb_small_blocks <= 20, "{nb_small_blocks} blocks");
+
+        // a large write, filling several blocks
+        let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        client.write_all(&data).expect("send data");
+        sent.extend_from_slice(&data);
+        client.write_all(b"end").expect("send data");
+        sent.extend_from_slice(b"end");
+        client.shutdown(net::Shutdown::Write).expect("close");
+        drop(client);
+        assert!(diode.receive(time::Duration::from_secs(10)) == Some(sent));
     }
 }
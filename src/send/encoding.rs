@@ -1,34 +1,32 @@
-//! Worker that encodes protocol messages into RaptorQ packets
+//! Worker that encodes protocol messages into packets with the configured [crate::fec] code
 
-use crate::{protocol, send};
+use crate::{fec, metrics, protocol, send, send::watchdog};
+use std::sync::atomic::Ordering;
 
 pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
-    let nb_repair_packets = protocol::nb_repair_packets(
-        &sender.object_transmission_info,
-        sender.config.repair_block_size,
-    );
-
-    if nb_repair_packets == 0 {
+    let nb_repair_packets = sender.nb_repair_packets.load(Ordering::Relaxed);
+    if nb_repair_packets == 0 && sender.config.fec.has_repair() {
         log::warn!("configuration produces 0 repair packet");
     }
 
-    let sbep = raptorq::SourceBlockEncodingPlan::generate(
-        (sender.object_transmission_info.transfer_length()
-            / u64::from(sender.object_transmission_info.symbol_size())) as u16,
-    );
+    let fec = fec::new(sender.config.fec, &sender.object_transmission_info);
+
+    let compressed_blocks = metrics::counter("tx_blocks_compressed");
+    let blocks = metrics::counter("tx_blocks");
 
     loop {
         let mut block_id_to_encode = sender.block_to_encode.lock().expect("acquire lock");
-        let message = sender.for_encoding.recv()?;
+        let mut message = sender.encoding_queue.pop();
+        watchdog::Progress::inc(&sender.progress.encoding);
         let block_id = *block_id_to_encode;
-        *block_id_to_encode = block_id_to_encode.wrapping_add(1);
+        *block_id_to_encode = block_id.next();
         drop(block_id_to_encode);
 
         let message_type = message.message_type()?;
         let client_id = message.client_id();
 
         match message_type {
-            protocol::MessageType::Start => log::debug!(
+            protocol::MessageType::Start | protocol::MessageType::StartSite => log::debug!(
                 "start of encoding block {block_id} for client {:x}",
                 client_id
             ),
@@ -39,28 +37,43 @@ pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
             _ => (),
         }
 
+        // read before the checksum is appended to the payload
+        let pauses = matches!(message_type, protocol::MessageType::Heartbeat)
+            && protocol::parse_heartbeat(message.payload())
+                .is_ok_and(|(_, status)| status.is_some_and(|status| status.paused));
+
+        if sender.config.integrity {
+            message.append_checksum();
+        }
+
         let data = message.serialized();
 
         log::trace!("encoding a serialized block of {} bytes", data.len());
 
-        let encoder = raptorq::SourceBlockEncoder::with_encoding_plan(
-            block_id,
-            &sender.object_transmission_info,
-            data,
-            &sbep,
-        );
+        blocks.inc();
+
+        let mut packets = sender.packets_pool.lease();
+        packets.block_id = block_id;
+        packets.compressed = message.is_compressed();
+        if packets.compressed {
+            compressed_blocks.inc();
+        }
 
-        let mut packets = encoder.source_packets();
+        if sender.config.confirm_flush && matches!(message_type, protocol::MessageType::End) {
+            packets.end_of = Some(client_id);
+        }
+        packets.pauses = pauses;
 
-        if 0 < nb_repair_packets {
-            packets.extend(encoder.repair_packets(0, nb_repair_packets));
+        let nb_repair_packets = sender.nb_repair_packets.load(Ordering::Relaxed);
+        for packet in fec.encode(block_id, data, nb_repair_packets) {
+            packets.push(sender.config.header_format, &packet)?;
         }
 
         loop {
             let mut to_send = sender.block_to_send.lock().expect("acquire lock");
             if *to_send == block_id {
                 sender.to_send.send(packets)?;
-                *to_send = to_send.wrapping_add(1);
+                *to_send = block_id.next();
                 break;
             }
         }
@@ -1,18 +1,47 @@
 //! Optional worker that periodically inserts [crate::protocol] heartbeat message in the encoding queue
+//!
+//! With `heartbeat_status`, heartbeat messages also carry a [protocol::SenderStatus]. The
+//! interval is read again after each message, so that it can be changed while the sender runs.
+//! No heartbeat is queued while emission is paused, [send::Sender::pause] queuing the one
+//! announcing the pause.
 
-use crate::{protocol, send};
+use crate::{metrics, protocol, send};
+use std::{sync::atomic::Ordering, thread};
 
 pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
-    let alarm =
-        crossbeam_channel::tick(sender.config.heartbeat_interval.expect("heartbeat enabled"));
-
     loop {
-        sender.to_encoding.send(protocol::Message::new(
-            protocol::MessageType::Heartbeat,
-            sender.from_buffer_size,
-            0,
-            None,
-        ))?;
-        let _ = alarm.recv()?;
+        if !sender.is_paused() {
+            sender.encoding_queue.push(message(sender, false));
+        }
+        thread::sleep(
+            sender
+                .tunables
+                .heartbeat_interval()
+                .expect("heartbeat enabled"),
+        );
     }
 }
+
+/// Returns a heartbeat message, carrying the status of the sender with `heartbeat_status`
+pub(crate) fn message<C>(sender: &send::Sender<C>, paused: bool) -> protocol::Message {
+    let packet_size = u32::from(protocol::packet_size(&sender.object_transmission_info));
+    let status = sender
+        .config
+        .heartbeat_status
+        .then(|| protocol::SenderStatus {
+            uptime: sender.started.elapsed(),
+            sessions: metrics::counter("tx_sessions_started").get(),
+            blocks: metrics::counter("tx_blocks").get(),
+            mtu: sender.config.to_mtu,
+            encoding_block_size: sender.config.encoding_block_size,
+            repair_block_size: sender.nb_repair_packets.load(Ordering::Relaxed) * packet_size,
+            paused,
+        });
+    let payload = protocol::heartbeat_payload(sender.config.site_id.as_deref(), status.as_ref());
+    protocol::Message::new(
+        protocol::MessageType::Heartbeat,
+        sender.from_buffer_size,
+        0,
+        (!payload.is_empty()).then_some(payload.as_slice()),
+    )
+}
@@ -0,0 +1,121 @@
+//! Supervision of the listeners accepting clients
+//!
+//! Listeners threads are spawned from binary, each running a loop accepting clients until
+//! accepting fails, for instance with `EMFILE` when file descriptors are exhausted. [supervise]
+//! then binds the listener again and runs the loop anew, rather than letting the thread end with
+//! the sender still running but accepting no more clients.
+
+use crate::metrics;
+use std::{io, thread, time};
+
+const REBIND_MIN_BACKOFF: time::Duration = time::Duration::from_millis(100);
+const REBIND_MAX_BACKOFF: time::Duration = time::Duration::from_secs(10);
+
+/// Runs a listener loop, binding the listener again each time the loop fails
+///
+/// Failures are retried with an exponential backoff, reset once the loop ran for longer than the
+/// maximum backoff. After `max_rebinds` consecutive bind failures, the last bind error is
+/// returned, the caller being expected to exit with a non-zero status so that its supervisor
+/// (e.g. systemd) restarts it.
+pub fn supervise<L>(
+    name: &str,
+    mut listener: L,
+    max_rebinds: u32,
+    mut bind: impl FnMut() -> io::Result<L>,
+    mut serve: impl FnMut(L) -> io::Error,
+) -> io::Error {
+    let mut backoff = REBIND_MIN_BACKOFF;
+    loop {
+        let started = time::Instant::now();
+        let e = serve(listener);
+        log::error!("{name} listener stopped: {e}");
+        metrics::counter("tx_listener_restarts").inc();
+        if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+            metrics::counter("tx_listener_fd_exhausted").inc();
+        }
+        if REBIND_MAX_BACKOFF < started.elapsed() {
+            backoff = REBIND_MIN_BACKOFF;
+        }
+
+        let mut failures = 0;
+        listener = loop {
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(REBIND_MAX_BACKOFF);
+            match bind() {
+                Ok(listener) => {
+                    log::info!("{name} listener bound again");
+                    break listener;
+                }
+                Err(e) => {
+                    failures += 1;
+                    log::error!(
+                        "failed to bind {name} listener again ({failures}/{max_rebinds}): {e}"
+                    );
+                    metrics::counter("tx_listener_rebind_failures").inc();
+                    if max_rebinds <= failures {
+                        return e;
+                    }
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{supervise, REBIND_MIN_BACKOFF};
+    use crate::metrics;
+    use std::{io, time};
+
+    #[test]
+    fn rebinds_until_consecutive_failures() {
+        let restarts = metrics::counter("tx_listener_restarts").get();
+        let fd_exhausted = metrics::counter("tx_listener_fd_exhausted").get();
+        let rebind_failures = metrics::counter("tx_listener_rebind_failures").get();
+
+        // listener doubles are numbered by bind attempt, the initial one being 0
+        let mut binds = [false, true, true, false, false].into_iter().enumerate();
+        let mut served = vec![];
+        let e = supervise(
+            "test",
+            0,
+            2,
+            || match binds.next().expect("bind attempt") {
+                (attempt, true) => Ok(attempt + 1),
+                (attempt, false) => Err(io::Error::other(format!("bind {}", attempt + 1))),
+            },
+            |listener| {
+                served.push((listener, time::Instant::now()));
+                if served.len() == 1 {
+                    io::Error::from_raw_os_error(libc::EMFILE)
+                } else {
+                    io::Error::from(io::ErrorKind::ConnectionAborted)
+                }
+            },
+        );
+
+        // a single bind failure is retried, two consecutive ones give up
+        assert_eq!(e.to_string(), "bind 5");
+        assert_eq!(binds.next(), None);
+        assert_eq!(
+            served
+                .iter()
+                .map(|(listener, _)| *listener)
+                .collect::<Vec<_>>(),
+            [0, 2, 3]
+        );
+        // backoff doubles between bind attempts: 100 + 200 ms, then 400 ms
+        assert!(REBIND_MIN_BACKOFF * 3 <= served[1].1 - served[0].1);
+        assert!(REBIND_MIN_BACKOFF * 4 <= served[2].1 - served[1].1);
+
+        assert_eq!(metrics::counter("tx_listener_restarts").get(), restarts + 3);
+        assert_eq!(
+            metrics::counter("tx_listener_fd_exhausted").get(),
+            fd_exhausted + 1
+        );
+        assert_eq!(
+            metrics::counter("tx_listener_rebind_failures").get(),
+            rebind_failures + 3
+        );
+    }
+}
@@ -14,46 +14,367 @@
 //! ```
 //!
 //! Notes:
-//! - listeners threads are spawned from binary and not the library crate,
+//! - listeners threads are spawned from binary and not the library crate, under [listener]
+//!   supervision,
 //! - heartbeat worker has been omitted from the representation for readability,
 //! - there are `nb_clients` clients workers running in parallel,
-//! - there are `nb_encoding_threads` encoding workers running in parallel.
+//! - there are `nb_encoding_threads` encoding workers running in parallel,
+//! - packets buffers are leased by encoding workers from a pool and released by the udp worker,
+//! - with `proxy_protocol_from`, clients workers first read the PROXY protocol header starting
+//!   the connections of these load balancers, see [crate::proxy_protocol],
+//! - clients workers check data against the configured [scan] policies before sending it,
+//! - with `confirm_flush`, clients workers wait for the udp worker to confirm that the last block
+//!   of their session was handed to the kernel before releasing their multiplex slot,
+//! - with `backpressure`, the udp worker raises a [backpressure] level as the backlog grows and
+//!   clients workers shrink the receive buffer of their sockets accordingly,
+//! - with [OverloadPolicy::FinishFirst], clients workers defer new clients while the backlog is
+//!   high, see [shedding],
+//! - with `block_trailer`, the udp worker sends a block trailer (see [crate::protocol]) after the
+//!   packets of each block,
+//! - with `config_broadcast`, the udp worker periodically sends a configuration packet (see
+//!   [protocol::write_config]) between blocks,
+//! - with `compress`, clients workers read up to [protocol::MAX_COMPRESSION_RATIO] blocks of data
+//!   at once and send them in a single compressed message when it fits in a block, in as many
+//!   uncompressed messages as needed otherwise, and encoding workers flag the packets of blocks
+//!   carrying compressed messages,
+//! - with `integrity`, encoding workers end each message with a checksum before encoding it,
+//! - encoding workers take the messages of the channels according to their `channel_weights`,
+//!   see [schedule], and waiting clients of channels of higher weight are given transfer slots
+//!   first,
+//! - with `psk`, the udp worker authenticates every datagram and encrypts those but
+//!   configuration packets, see [crate::crypto],
+//! - with `sd_watchdog`, a watchdog worker notifies systemd as long as the encoding and udp
+//!   workers make progress, see [watchdog],
+//! - the bandwidth limit, heartbeat interval, repair block size and flush timeout can be changed
+//!   while the sender runs, workers reading them from [tunables].
 
-use crate::{protocol, semaphore};
+use crate::{
+    config, crypto, fec, localtime, metrics, protocol, receive, scrub, sd_notify, semaphore, status,
+};
 use std::{
+    collections::{self, HashSet},
     fmt,
     io::{self, Read},
     net,
     os::fd::AsRawFd,
+    path,
+    str::FromStr,
     sync, thread, time,
 };
 
+mod backpressure;
 mod client;
 mod encoding;
 mod heartbeat;
+pub mod listener;
+mod pool;
+pub mod repair_profile;
+pub mod scan;
+mod schedule;
 mod server;
+mod shedding;
+mod tunables;
 mod udp;
+mod watchdog;
 
 pub struct Config {
     pub nb_clients: u16,
     pub encoding_block_size: u64,
     pub repair_block_size: u32,
+    /// Forward error correction code of the blocks, which must be the same on both ends
+    pub fec: fec::Algorithm,
+    /// Time-of-day schedule of the repair block size, replacing `repair_block_size`
+    pub repair_profile: Option<path::PathBuf>,
     pub udp_buffer_size: u32,
     pub nb_encoding_threads: u8,
     pub heartbeat_interval: Option<time::Duration>,
+    /// Carry the state and the configuration of the sender in heartbeat messages
+    pub heartbeat_status: bool,
+    /// Send `WATCHDOG=1` notifications to systemd when it requests them
+    pub sd_watchdog: bool,
+    /// Interval between two configuration packets announcing the encoding parameters, `None`
+    /// disabling them
+    pub config_broadcast: Option<time::Duration>,
     pub to_bind: net::SocketAddr,
+    /// Network interface the UDP socket is restricted to, whatever the routes
+    pub to_bind_device: Option<String>,
     pub to_udp: net::SocketAddr,
+    /// Other destinations every datagram is also sent to, feeding separate receivers
+    pub to_udp_extra: Vec<net::SocketAddr>,
     pub to_mtu: u16,
+    /// TTL, or hop limit, of the datagrams when a destination is a multicast group
+    pub to_multicast_ttl: u8,
+    /// Network interface multicast datagrams are sent from, instead of the one chosen by routes
+    pub to_multicast_interface: Option<String>,
+    /// Destination on a second diode link the packets of each block are striped over, every
+    /// other packet being sent to it from its own socket
+    pub stripe_to_udp: Option<net::SocketAddr>,
+    /// Address and network interface the socket of the second link is bound to
+    pub stripe_to_bind: net::SocketAddr,
+    pub stripe_to_bind_device: Option<String>,
     pub bandwidth_limit: f64,
+    /// Offload the pacing of `bandwidth_limit` to the qdisc or the network card by giving each
+    /// datagram its transmit time, falling back to software pacing when unsupported
+    pub txtime: bool,
+    /// Let the kernel segment batches of datagrams (`UDP_SEGMENT`), when it supports it
+    pub udp_gso: bool,
+    /// Identifier of the site of the sender, carried by the first message of every transfer and
+    /// by heartbeat messages
+    pub site_id: Option<String>,
+    /// Maximum time data read from a client may wait before being sent in a partial block,
+    /// `None` meaning partial blocks are only sent at the end of the transfer
+    pub flush_timeout: Option<time::Duration>,
+    /// Content checks applied to every client session, sessions violating one being aborted
+    pub scan_policies: Vec<Box<dyn scan::ScanPolicy>>,
+    /// Keep client sessions open until all their packets were sent on the UDP socket
+    pub confirm_flush: bool,
+    /// Ratio of lost packets the repair packets must compensate, checked at startup
+    pub target_loss: Option<f64>,
+    /// Only warn, instead of refusing to start, when repair packets do not meet `target_loss`
+    pub allow_insufficient_repair: bool,
+    /// Shrink the receive buffer of clients sockets when blocks accumulate before the UDP link
+    pub backpressure: bool,
+    /// Maximum time a client waits for one of the `nb_clients` transfer slots before being
+    /// rejected, `None` meaning clients wait indefinitely
+    pub client_queue_timeout: Option<time::Duration>,
+    pub overload_policy: OverloadPolicy,
+    /// Follow the packets of each block with a trailer telling how many were emitted
+    pub block_trailer: bool,
+    /// Compress client data when it makes it fit in fewer blocks
+    pub compress: bool,
+    /// End each block with a checksum of its content, verified by receivers with `integrity`
+    pub integrity: bool,
+    /// Format of the headers of packets, which must be the same on both ends
+    pub header_format: protocol::HeaderFormat,
+    /// Share of the UDP link and precedence for transfer slots of each channel, channels not
+    /// given a weight having a weight of 1
+    pub channel_weights: collections::BTreeMap<protocol::Channel, u32>,
+    /// Encrypt and authenticate datagrams with this pre-shared key, which must be the same on
+    /// both ends
+    pub psk: Option<crypto::Key>,
+    /// Load balancers whose connections start with a PROXY protocol header announcing the
+    /// origin of the client, see [crate::proxy_protocol]
+    pub proxy_protocol_from: Vec<receive::Network>,
+}
+
+/// How the sender shares the UDP link when clients offer more data than it can carry
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverloadPolicy {
+    /// All sessions are slowed down equally
+    Fair,
+    /// Sessions in progress keep their rate while new clients are deferred until the backlog
+    /// drains
+    FinishFirst,
+}
+
+impl fmt::Display for OverloadPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Fair => write!(fmt, "fair"),
+            Self::FinishFirst => write!(fmt, "finish_first"),
+        }
+    }
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fair" => Ok(Self::Fair),
+            "finish_first" => Ok(Self::FinishFirst),
+            _ => Err(format!("unknown overload policy \"{s}\"")),
+        }
+    }
 }
 
 impl Config {
+    /// Checks the configuration, returning every problem found
+    pub fn validate(&self) -> Result<(), Vec<config::Error>> {
+        let mut errors = Vec::new();
+
+        if self.nb_clients == 0 {
+            errors.push(config::Error::Zero("nb_clients"));
+        }
+        if self.nb_encoding_threads == 0 {
+            errors.push(config::Error::Zero("nb_encoding_threads"));
+        }
+        for (channel, weight) in &self.channel_weights {
+            if *weight == 0 {
+                errors.push(config::Error::Invalid(
+                    "channel_weights",
+                    format!("weight of channel {channel} must be at least 1"),
+                ));
+            }
+        }
+        config::check_geometry(
+            &mut errors,
+            "to_mtu",
+            self.header_format,
+            self.packet_mtu(),
+            self.encoding_block_size,
+            self.repair_block_size,
+            self.fec,
+        );
+        for to_udp in self.destinations() {
+            config::check_destination(&mut errors, "to_udp", to_udp);
+            if self.to_bind.is_ipv4() != to_udp.is_ipv4() {
+                errors.push(config::Error::Invalid(
+                    "to_bind",
+                    format!(
+                        "{} cannot send to {to_udp}, addresses must be of the same family",
+                        self.to_bind
+                    ),
+                ));
+            }
+        }
+        if let Some(stripe_to_udp) = self.stripe_to_udp {
+            config::check_destination(&mut errors, "stripe_to_udp", stripe_to_udp);
+            if stripe_to_udp.ip().is_multicast() {
+                errors.push(config::Error::Invalid(
+                    "stripe_to_udp",
+                    "striping over a multicast group is not supported".to_string(),
+                ));
+            } else if stripe_to_udp.is_ipv4() != self.to_udp.is_ipv4() {
+                errors.push(config::Error::Invalid(
+                    "stripe_to_udp",
+                    format!(
+                        "{stripe_to_udp} and {} must be of the same family",
+                        self.to_udp
+                    ),
+                ));
+            } else if self.stripe_to_bind.is_ipv4() != stripe_to_udp.is_ipv4() {
+                errors.push(config::Error::Invalid(
+                    "stripe_to_bind",
+                    format!(
+                        "{} cannot send to {stripe_to_udp}, addresses must be of the same family",
+                        self.stripe_to_bind
+                    ),
+                ));
+            }
+        }
+        config::check_multicast_interface(
+            &mut errors,
+            "to_multicast_interface",
+            self.to_multicast_interface.as_deref(),
+            self.multicast_group().unwrap_or(self.to_udp),
+        );
+        if self.heartbeat_interval == Some(time::Duration::ZERO) {
+            errors.push(config::Error::Zero("heartbeat_interval"));
+        }
+        if self.config_broadcast == Some(time::Duration::ZERO) {
+            errors.push(config::Error::Zero("config_broadcast"));
+        }
+        if self.client_queue_timeout == Some(time::Duration::ZERO) {
+            errors.push(config::Error::Zero("client_queue_timeout"));
+        }
+        config::check_range(
+            &mut errors,
+            "bandwidth_limit",
+            self.bandwidth_limit,
+            0.0,
+            f64::INFINITY,
+            "a finite positive bandwidth or 0",
+        );
+        if self.txtime && self.bandwidth_limit == 0.0 {
+            errors.push(config::Error::Requires("txtime", "bandwidth_limit"));
+        }
+        if self.txtime && self.udp_gso {
+            errors.push(config::Error::Invalid(
+                "udp_gso",
+                "transmit times are given per message, segmented messages would lose them"
+                    .to_string(),
+            ));
+        }
+        if let Some(site_id) = &self.site_id {
+            if let Err(e) = protocol::check_site_id(site_id) {
+                errors.push(config::Error::Invalid("site_id", e));
+            }
+        }
+        match self.target_loss {
+            Some(target_loss) => config::check_range(
+                &mut errors,
+                "target_loss",
+                target_loss,
+                0.0,
+                1.0,
+                "a ratio between 0 and 1",
+            ),
+            None if self.allow_insufficient_repair => errors.push(config::Error::Requires(
+                "allow_insufficient_repair",
+                "target_loss",
+            )),
+            None => (),
+        }
+        if self.repair_profile.is_some() && !self.fec.has_repair() {
+            errors.push(config::Error::Invalid(
+                "repair_profile",
+                format!(
+                    "no repair packet is sent with \"{}\" forward error correction",
+                    self.fec
+                ),
+            ));
+        }
+        if self.repair_profile.is_some() && self.block_trailer {
+            errors.push(config::Error::Invalid(
+                "repair_profile",
+                "block trailers would report the repair packets left out by the profile as unsent"
+                    .to_string(),
+            ));
+        }
+        if self.target_loss.is_some() && !self.fec.has_repair() {
+            errors.push(config::Error::Invalid(
+                "target_loss",
+                format!(
+                    "no repair packet is sent with \"{}\" forward error correction",
+                    self.fec
+                ),
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Every destination of the datagrams, `to_udp` first
+    pub(crate) fn destinations(&self) -> impl Iterator<Item = net::SocketAddr> + '_ {
+        std::iter::once(self.to_udp).chain(self.to_udp_extra.iter().copied())
+    }
+
+    /// First destination which is a multicast group, whose parameters apply to every group
+    pub(crate) fn multicast_group(&self) -> Option<net::SocketAddr> {
+        self.destinations()
+            .find(|to_udp| to_udp.ip().is_multicast())
+    }
+
+    /// MTU from which the size of packets is computed, see [protocol::packet_mtu], leaving room
+    /// for the encryption overhead
+    pub(crate) fn packet_mtu(&self) -> u16 {
+        let mtu = protocol::packet_mtu(self.to_mtu, &self.to_udp);
+        if self.psk.is_some() {
+            crypto::unsealed_mtu(mtu)
+        } else {
+            mtu
+        }
+    }
+
     pub(crate) fn adjust(&mut self) {
-        let oti = protocol::object_transmission_information(self.to_mtu, self.encoding_block_size);
+        let oti = protocol::object_transmission_information(
+            self.header_format,
+            self.packet_mtu(),
+            self.encoding_block_size,
+        );
 
         let packet_size = protocol::packet_size(&oti);
         let nb_encoding_packets = protocol::nb_encoding_packets(&oti);
-        let nb_repair_packets = protocol::nb_repair_packets(&oti, self.repair_block_size);
+        let nb_repair_packets = if self.fec.has_repair() {
+            protocol::nb_repair_packets(&oti, self.repair_block_size)
+        } else {
+            0
+        };
 
         self.encoding_block_size = nb_encoding_packets * u64::from(packet_size);
         self.repair_block_size = nb_repair_packets * u32::from(packet_size);
@@ -62,22 +383,64 @@ impl Config {
 
 pub enum Error {
     Io(io::Error),
-    SendMessage(crossbeam_channel::SendError<protocol::Message>),
-    SendUdp(crossbeam_channel::SendError<Vec<raptorq::EncodingPacket>>),
+    /// A worker channel was disconnected, the string naming the channel
+    Send(&'static str),
     Receive(crossbeam_channel::RecvError),
     Protocol(protocol::Error),
     Diode(String),
+    ScanPolicy(&'static str, String),
+    /// The UDP socket could not be bound to the address
+    Bind(net::SocketAddr, io::Error),
+    /// The UDP socket could not be restricted to the network interface
+    BindDevice(String, io::Error),
+    /// The UDP socket could not be set up to send to the multicast group
+    Multicast(net::IpAddr, io::Error),
+    /// The configuration failed [Config::validate]
+    Config(Vec<config::Error>),
+    RepairProfile(repair_profile::Error),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
-            Self::SendMessage(e) => write!(fmt, "crossbeam send message error: {e}"),
-            Self::SendUdp(e) => write!(fmt, "crossbeam send UDP error: {e}"),
+            Self::Send(channel) => write!(
+                fmt,
+                "crossbeam send {channel} error: sending on a disconnected channel"
+            ),
             Self::Receive(e) => write!(fmt, "crossbeam receive error: {e}"),
             Self::Protocol(e) => write!(fmt, "diode protocol error: {e}"),
             Self::Diode(e) => write!(fmt, "diode error: {e}"),
+            Self::ScanPolicy(policy, reason) => {
+                write!(fmt, "rejected by {policy} scan policy: {reason}")
+            }
+            Self::Bind(addr, e) => {
+                write!(fmt, "cannot bind UDP socket to {}: ", scrub::addr(addr))?;
+                match e.kind() {
+                    io::ErrorKind::AddrInUse => {
+                        write!(fmt, "port {} is already in use", addr.port())
+                    }
+                    io::ErrorKind::AddrNotAvailable => {
+                        write!(fmt, "address is not assigned to a local interface")
+                    }
+                    _ => write!(fmt, "{e}"),
+                }
+            }
+            Self::BindDevice(device, e) => {
+                write!(fmt, "cannot bind UDP socket to device {device}: ")?;
+                match e.raw_os_error() {
+                    Some(libc::EPERM) => write!(fmt, "the CAP_NET_RAW capability is required"),
+                    Some(libc::ENODEV) => write!(fmt, "no such device"),
+                    _ => write!(fmt, "{e}"),
+                }
+            }
+            Self::Multicast(group, e) => {
+                write!(fmt, "cannot send to multicast group {group}: {e}")
+            }
+            Self::Config(errors) => {
+                write!(fmt, "invalid configuration: {}", config::Errors(errors))
+            }
+            Self::RepairProfile(e) => write!(fmt, "{e}"),
         }
     }
 }
@@ -88,15 +451,9 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<crossbeam_channel::SendError<protocol::Message>> for Error {
-    fn from(e: crossbeam_channel::SendError<protocol::Message>) -> Self {
-        Self::SendMessage(e)
-    }
-}
-
-impl From<crossbeam_channel::SendError<Vec<raptorq::EncodingPacket>>> for Error {
-    fn from(e: crossbeam_channel::SendError<Vec<raptorq::EncodingPacket>>) -> Self {
-        Self::SendUdp(e)
+impl From<crossbeam_channel::SendError<pool::Packets>> for Error {
+    fn from(_: crossbeam_channel::SendError<pool::Packets>) -> Self {
+        Self::Send("UDP")
     }
 }
 
@@ -112,6 +469,37 @@ impl From<protocol::Error> for Error {
     }
 }
 
+/// Checks that the blocks of `profile` do not have more packets than the forward error correction
+/// code supports
+fn check_repair_profile_geometry(
+    config: &Config,
+    oti: &raptorq::ObjectTransmissionInformation,
+    profile: &repair_profile::Profile,
+) -> Result<(), Error> {
+    let nb_encoding_packets = protocol::nb_encoding_packets(oti);
+    let max_packets = config.fec.max_packets();
+    let errors: Vec<_> = profile
+        .repair_block_sizes()
+        .map(|size| nb_encoding_packets + u64::from(protocol::nb_repair_packets(oti, size)))
+        .filter(|nb_packets| max_packets < *nb_packets)
+        .map(|nb_packets| config::Error::TooManyPackets("repair_profile", nb_packets, max_packets))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Config(errors))
+    }
+}
+
+/// Emission state of the udp worker, see [Sender::pause]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Emission {
+    Running,
+    /// A heartbeat announcing the pause was queued, blocks being emitted until its own is
+    Pausing,
+    Paused,
+}
+
 /// An instance of this data structure is shared by workers to synchronize them and to access
 /// communication channels
 ///
@@ -123,49 +511,139 @@ pub struct Sender<C> {
     pub(crate) from_buffer_size: u32,
     pub(crate) to_max_messages: u16,
     pub(crate) multiplex_control: semaphore::Semaphore,
-    pub(crate) block_to_encode: sync::Mutex<u8>,
-    pub(crate) block_to_send: sync::Mutex<u8>,
-    pub(crate) to_server: crossbeam_channel::Sender<C>,
-    pub(crate) for_server: crossbeam_channel::Receiver<C>,
-    pub(crate) to_encoding: crossbeam_channel::Sender<protocol::Message>,
-    pub(crate) for_encoding: crossbeam_channel::Receiver<protocol::Message>,
-    pub(crate) to_send: crossbeam_channel::Sender<Vec<raptorq::EncodingPacket>>,
-    pub(crate) for_send: crossbeam_channel::Receiver<Vec<raptorq::EncodingPacket>>,
+    pub(crate) block_to_encode: sync::Mutex<protocol::BlockId>,
+    pub(crate) block_to_send: sync::Mutex<protocol::BlockId>,
+    pub(crate) emission: sync::Mutex<Emission>,
+    pub(crate) resumed: sync::Condvar,
+    /// Set by [Sender::pause_clients], listeners of the binaries no longer accepting clients
+    pub(crate) clients_paused: sync::Mutex<bool>,
+    pub(crate) clients_resumed: sync::Condvar,
+    /// Requests of [Sender::announce_config], handled by the udp worker
+    pub(crate) to_config_request: crossbeam_channel::Sender<()>,
+    pub(crate) for_config_request: crossbeam_channel::Receiver<()>,
+    pub(crate) to_server:
+        crossbeam_channel::Sender<(C, Option<net::SocketAddr>, protocol::Channel)>,
+    pub(crate) for_server:
+        crossbeam_channel::Receiver<(C, Option<net::SocketAddr>, protocol::Channel)>,
+    pub(crate) encoding_queue: schedule::Queue,
+    pub(crate) to_send: crossbeam_channel::Sender<pool::Packets>,
+    pub(crate) for_send: crossbeam_channel::Receiver<pool::Packets>,
+    pub(crate) packets_pool: pool::Pool,
+    pub(crate) flushed: sync::Mutex<HashSet<protocol::ClientId>>,
+    pub(crate) flush_confirmed: sync::Condvar,
+    pub(crate) backpressure: Option<backpressure::Backpressure>,
+    pub(crate) shedding: Option<shedding::Shedding>,
+    /// Number of repair packets of the blocks being encoded, changed by the repair profile
+    pub(crate) nb_repair_packets: sync::atomic::AtomicU32,
+    pub(crate) repair_profile: Option<repair_profile::Profile>,
+    pub(crate) progress: watchdog::Progress,
+    /// Number of blocks handed to the kernel, compared to the number of encoded ones to tell
+    /// whether the pipeline is drained
+    pub(crate) blocks_sent: sync::atomic::AtomicU64,
+    /// Set by [Sender::drain], new clients being rejected
+    pub(crate) draining: sync::atomic::AtomicBool,
+    pub(crate) tunables: tunables::Tunables,
+    /// Reported as the uptime of the sender in heartbeat messages
+    pub(crate) started: time::Instant,
 }
 
 impl<C> Sender<C>
 where
     C: Read + AsRawFd + Send,
 {
-    pub fn new(mut config: Config) -> Self {
+    /// Creates the sender, failing if `config` does not pass [Config::validate]
+    pub fn new(mut config: Config) -> Result<Self, Error> {
+        config.validate().map_err(Error::Config)?;
         config.adjust();
 
-        let object_transmission_info =
-            protocol::object_transmission_information(config.to_mtu, config.encoding_block_size);
+        let object_transmission_info = protocol::object_transmission_information(
+            config.header_format,
+            config.packet_mtu(),
+            config.encoding_block_size,
+        );
 
+        let checksum_size = if config.integrity {
+            protocol::CHECKSUM_SIZE
+        } else {
+            0
+        };
         let from_buffer_size = (object_transmission_info.transfer_length()
-            - protocol::Message::serialize_overhead() as u64) as u32;
+            - (protocol::Message::serialize_overhead() + checksum_size) as u64)
+            as u32;
+
+        let repair_profile = match &config.repair_profile {
+            Some(path) => {
+                let profile = repair_profile::Profile::read(path).map_err(Error::RepairProfile)?;
+                check_repair_profile_geometry(&config, &object_transmission_info, &profile)?;
+                Some(profile)
+            }
+            None => None,
+        };
 
+        let repair_block_size = match &repair_profile {
+            Some(profile) => {
+                profile.repair_block_size_at(localtime::minute_of_day(time::SystemTime::now()))
+            }
+            None => config.repair_block_size,
+        };
+        let nb_repair_packets =
+            protocol::nb_repair_packets(&object_transmission_info, repair_block_size);
+
+        // enough messages for the largest blocks of the profile
+        let max_repair_block_size = repair_profile
+            .iter()
+            .flat_map(repair_profile::Profile::repair_block_sizes)
+            .chain([repair_block_size])
+            .max()
+            .unwrap_or_default();
         let to_max_messages = protocol::nb_encoding_packets(&object_transmission_info) as u16
-            + protocol::nb_repair_packets(&object_transmission_info, config.repair_block_size)
-                as u16;
+            + protocol::nb_repair_packets(&object_transmission_info, max_repair_block_size) as u16;
+
+        let multiplex_control = semaphore::Semaphore::with_gauges(
+            config.nb_clients as usize,
+            "tx_clients_active",
+            "tx_clients_waiting",
+        );
+
+        let block_to_encode = sync::Mutex::new(protocol::BlockId::default());
 
-        let multiplex_control = semaphore::Semaphore::new(config.nb_clients as usize);
+        let block_to_send = sync::Mutex::new(protocol::BlockId::default());
 
-        let block_to_encode = sync::Mutex::new(0);
+        let emission = sync::Mutex::new(Emission::Running);
 
-        let block_to_send = sync::Mutex::new(0);
+        let resumed = sync::Condvar::new();
 
-        let (to_server, for_server) = crossbeam_channel::bounded::<C>(1);
+        let (to_server, for_server) =
+            crossbeam_channel::bounded::<(C, Option<net::SocketAddr>, protocol::Channel)>(1);
 
-        let (to_encoding, for_encoding) =
-            crossbeam_channel::bounded::<protocol::Message>(config.nb_clients as usize);
+        let (to_config_request, for_config_request) = crossbeam_channel::bounded::<()>(1);
 
-        let (to_send, for_send) = crossbeam_channel::bounded::<Vec<raptorq::EncodingPacket>>(
-            2 * config.nb_encoding_threads as usize,
+        let encoding_queue =
+            schedule::Queue::new(config.channel_weights.clone(), config.nb_clients as usize);
+
+        let (to_send, for_send) =
+            crossbeam_channel::bounded::<pool::Packets>(2 * config.nb_encoding_threads as usize);
+
+        // enough buffers for the packets queue, plus one per encoding worker and one being sent
+        let packets_pool = pool::Pool::new(
+            3 * config.nb_encoding_threads as usize + 1,
+            usize::from(protocol::packet_size(&object_transmission_info))
+                + config.header_format.size(),
+            to_max_messages as usize,
+        );
+
+        let watermarks = backpressure::Watermarks::new(
+            config.nb_clients as usize + 2 * config.nb_encoding_threads as usize,
         );
+        let backpressure = config
+            .backpressure
+            .then(|| backpressure::Backpressure::new(watermarks));
+        let shedding = (config.overload_policy == OverloadPolicy::FinishFirst)
+            .then(|| shedding::Shedding::new(watermarks));
 
-        Self {
+        let tunables = tunables::Tunables::new(&config);
+
+        Ok(Self {
             config,
             object_transmission_info,
             from_buffer_size,
@@ -173,13 +651,30 @@ where
             multiplex_control,
             block_to_encode,
             block_to_send,
+            emission,
+            resumed,
+            clients_paused: sync::Mutex::new(false),
+            clients_resumed: sync::Condvar::new(),
+            to_config_request,
+            for_config_request,
             to_server,
             for_server,
-            to_encoding,
-            for_encoding,
+            encoding_queue,
             to_send,
             for_send,
-        }
+            packets_pool,
+            flushed: sync::Mutex::new(HashSet::new()),
+            flush_confirmed: sync::Condvar::new(),
+            backpressure,
+            shedding,
+            nb_repair_packets: sync::atomic::AtomicU32::new(nb_repair_packets),
+            repair_profile,
+            progress: watchdog::Progress::default(),
+            blocks_sent: sync::atomic::AtomicU64::new(0),
+            draining: sync::atomic::AtomicBool::new(false),
+            tunables,
+            started: time::Instant::now(),
+        })
     }
 
     pub fn start<'a>(&'a self, scope: &'a thread::Scope<'a, '_>) -> Result<(), Error> {
@@ -193,19 +688,34 @@ where
             self.from_buffer_size
         );
 
+        log::info!("forward error correction is {}", self.config.fec);
+
         log::info!(
             "encoding will produce {} packets ({} bytes per block) + {} repair packets",
             protocol::nb_encoding_packets(&self.object_transmission_info),
             self.config.encoding_block_size,
-            protocol::nb_repair_packets(
-                &self.object_transmission_info,
-                self.config.repair_block_size
-            ),
+            self.nb_repair_packets.load(sync::atomic::Ordering::Relaxed),
         );
 
+        match &self.repair_profile {
+            Some(profile) => self.check_repair_profile(profile)?,
+            None => self.check_loss_tolerance(self.config.repair_block_size)?,
+        }
+
+        // bound before any thread is started, so that startup fails early and cleanly
+        let socket = udp::bind(&self.config)?;
+        let stripe_socket = udp::bind_stripe(&self.config)?;
         thread::Builder::new()
             .name("udp".into())
-            .spawn_scoped(scope, || udp::start(self))?;
+            .spawn_scoped(scope, move || udp::start(self, socket, stripe_socket))?;
+
+        if let (Some(path), Some(profile)) = (&self.config.repair_profile, &self.repair_profile) {
+            log::info!("repair block size follows the profile {}", path.display());
+            let profile = profile.clone();
+            thread::Builder::new()
+                .name("repair_profile".into())
+                .spawn_scoped(scope, move || repair_profile::start(self, path, profile))?;
+        }
 
         for i in 0..self.config.nb_encoding_threads {
             thread::Builder::new()
@@ -213,6 +723,10 @@ where
                 .spawn_scoped(scope, || encoding::start(self))?;
         }
 
+        if let Some(site_id) = &self.config.site_id {
+            log::info!("site identifier is \"{site_id}\"");
+        }
+
         if let Some(hb_interval) = self.config.heartbeat_interval {
             log::info!(
                 "heartbeat message will be sent every {} seconds",
@@ -225,19 +739,820 @@ where
             log::info!("heartbeat is disabled");
         }
 
-        for i in 0..self.config.nb_clients {
+        // with a queue timeout, as many clients as transfer slots can wait for a slot and be
+        // rejected on time, the following ones waiting to be accepted
+        let nb_server_threads = if self.config.client_queue_timeout.is_some() {
+            2 * self.config.nb_clients
+        } else {
+            self.config.nb_clients
+        };
+
+        for i in 0..nb_server_threads {
             thread::Builder::new()
                 .name(format!("send_thread_{i}"))
                 .spawn_scoped(scope, || server::start(self))?;
         }
 
+        if self.config.sd_watchdog {
+            if let Some(interval) = sd_notify::watchdog_interval() {
+                thread::Builder::new()
+                    .name("watchdog".into())
+                    .spawn_scoped(scope, move || {
+                        let res = watchdog::start(self, interval);
+                        if let Err(e) = &res {
+                            log::error!("watchdog error: {e}");
+                        }
+                        res
+                    })?;
+            }
+        }
+
         Ok(())
     }
 
     pub fn new_client(&self, client: C) -> Result<(), Error> {
-        if let Err(e) = self.to_server.send(client) {
+        self.new_channel_client(client, None, 0)
+    }
+
+    /// Same as [Sender::new_client] but `peer` will be reported in the client logs
+    pub fn new_client_from(&self, client: C, peer: net::SocketAddr) -> Result<(), Error> {
+        self.new_channel_client(client, Some(peer), 0)
+    }
+
+    /// Same as [Sender::new_client_from] but the data of `client` is transferred on `channel`
+    /// rather than on the default channel 0
+    pub fn new_channel_client(
+        &self,
+        client: C,
+        peer: Option<net::SocketAddr>,
+        channel: protocol::Channel,
+    ) -> Result<(), Error> {
+        if self.is_draining() {
+            metrics::counter("tx_clients_rejected").inc();
+            return Err(Error::Diode("sender is shutting down".to_string()));
+        }
+        if let Err(e) = self.to_server.send((client, peer, channel)) {
             return Err(Error::Diode(format!("failed to enqueue client: {e}")));
         }
         Ok(())
     }
+
+    /// Runs the transfer of `client` in the calling thread, returning once all its data was read
+    /// and, with [Config::confirm_flush], emitted on the UDP link
+    pub fn run_client(&self, client: C) -> Result<(), Error> {
+        server::serve(self, client, None, 0)
+    }
+}
+
+impl<C> Sender<C> {
+    /// Checks that the blocks of `profile` do not have more packets than the forward error
+    /// correction code supports, and that their repair packets compensate the configured target
+    /// loss
+    pub(crate) fn check_repair_profile(
+        &self,
+        profile: &repair_profile::Profile,
+    ) -> Result<(), Error> {
+        check_repair_profile_geometry(&self.config, &self.object_transmission_info, profile)?;
+        let repair_block_sizes: collections::BTreeSet<u32> = profile.repair_block_sizes().collect();
+        for repair_block_size in repair_block_sizes {
+            self.check_loss_tolerance(repair_block_size)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the repair packets of a block compensate the configured target loss
+    fn check_loss_tolerance(&self, repair_block_size: u32) -> Result<(), Error> {
+        let tolerance = protocol::loss_tolerance(
+            &self.object_transmission_info,
+            repair_block_size,
+            self.config.fec,
+        );
+        log::info!(
+            "blocks with {} repair packets can be decoded with up to {:.2}% of their packets lost",
+            protocol::nb_repair_packets(&self.object_transmission_info, repair_block_size),
+            100.0 * tolerance
+        );
+
+        let Some(target_loss) = self.config.target_loss else {
+            return Ok(());
+        };
+        if target_loss <= tolerance {
+            return Ok(());
+        }
+
+        let message = format!(
+            "repair packets compensate {:.2}% loss, below the {:.2}% target, a repair_block_size of at least {} bytes is needed",
+            100.0 * tolerance,
+            100.0 * target_loss,
+            protocol::repair_block_size_for_loss(
+                &self.object_transmission_info,
+                target_loss,
+                self.config.fec
+            )
+        );
+        if self.config.allow_insufficient_repair {
+            log::warn!("{message}");
+            Ok(())
+        } else {
+            Err(Error::Diode(message))
+        }
+    }
+
+    /// Stops emitting UDP packets without disconnecting clients
+    ///
+    /// Encoded blocks accumulate in the bounded channels until they are full, then clients
+    /// workers stop reading from their sockets and TCP backpressure propagates to the sources.
+    ///
+    /// With [Config::heartbeat_status], a heartbeat flagged as paused is queued so that the
+    /// receiver knows heartbeats stop on purpose: emission stops once this heartbeat was sent,
+    /// after the blocks queued before it. No heartbeat is queued while emission is paused.
+    pub fn pause(&self) {
+        let announce = self.config.heartbeat_status && self.heartbeat_interval().is_some();
+        {
+            let mut emission = self.emission.lock().expect("acquire lock");
+            if *emission != Emission::Running {
+                return;
+            }
+            log::info!("pausing UDP emission");
+            *emission = if announce {
+                Emission::Pausing
+            } else {
+                Emission::Paused
+            };
+            metrics::gauge("tx_paused").set(1);
+        }
+        if announce {
+            // queued without holding the lock, the udp worker emitting the blocks queued before
+            self.encoding_queue.push(heartbeat::message(self, true));
+        }
+    }
+
+    /// Restarts UDP emission after a call to [Sender::pause]
+    pub fn resume(&self) {
+        let mut emission = self.emission.lock().expect("acquire lock");
+        if *emission != Emission::Running {
+            log::info!("resuming UDP emission, {} block(s) pending", self.backlog());
+            *emission = Emission::Running;
+            metrics::gauge("tx_paused").set(0);
+            self.resumed.notify_all();
+        }
+    }
+
+    /// Whether emission was paused by [Sender::pause], even if the heartbeat announcing it was
+    /// not sent yet
+    pub fn is_paused(&self) -> bool {
+        *self.emission.lock().expect("acquire lock") != Emission::Running
+    }
+
+    /// Stops emission once the heartbeat queued by [Sender::pause] was sent, unless emission
+    /// was resumed meanwhile
+    pub(crate) fn pause_announced(&self) {
+        let mut emission = self.emission.lock().expect("acquire lock");
+        if *emission == Emission::Pausing {
+            log::debug!("pause announced to the receiver");
+            *emission = Emission::Paused;
+        }
+    }
+
+    /// Stops accepting new clients, transfers in progress going on
+    ///
+    /// Listeners calling [Sender::wait_while_clients_paused] before accepting a client leave new
+    /// connections waiting in the backlog of their socket until [Sender::resume_clients].
+    pub fn pause_clients(&self) {
+        let mut clients_paused = self.clients_paused.lock().expect("acquire lock");
+        if !*clients_paused {
+            log::info!("pausing acceptance of new clients");
+            *clients_paused = true;
+            metrics::gauge("tx_clients_paused").set(1);
+        }
+    }
+
+    /// Accepts new clients again after a call to [Sender::pause_clients]
+    pub fn resume_clients(&self) {
+        let mut clients_paused = self.clients_paused.lock().expect("acquire lock");
+        if *clients_paused {
+            log::info!("resuming acceptance of new clients");
+            *clients_paused = false;
+            metrics::gauge("tx_clients_paused").set(0);
+            self.clients_resumed.notify_all();
+        }
+    }
+
+    pub fn are_clients_paused(&self) -> bool {
+        *self.clients_paused.lock().expect("acquire lock")
+    }
+
+    /// Blocks while new clients are paused, see [Sender::pause_clients]
+    pub fn wait_while_clients_paused(&self) {
+        let mut clients_paused = self.clients_paused.lock().expect("acquire lock");
+        while *clients_paused {
+            clients_paused = self
+                .clients_resumed
+                .wait(clients_paused)
+                .expect("condvar wait");
+        }
+    }
+
+    /// Makes the udp worker send a configuration packet (see [protocol::write_config]) before
+    /// the next block, for instance for a receiver restarted with a `--sender_config` mode not to
+    /// wait for the next periodic one
+    ///
+    /// Configuration packets are not sent while emission is paused.
+    pub fn announce_config(&self) -> Result<(), Error> {
+        if self.is_paused() {
+            return Err(Error::Diode("emission is paused".to_string()));
+        }
+        // a request already pending is enough
+        let _ = self.to_config_request.try_send(());
+        Ok(())
+    }
+
+    /// Bandwidth limit of the UDP link in bytes per second, 0 meaning unlimited
+    pub fn bandwidth_limit(&self) -> f64 {
+        self.tunables.bandwidth_limit()
+    }
+
+    /// Changes the bandwidth limit of the UDP link, in bytes per second, from the next block sent
+    ///
+    /// With [Config::txtime], the limit cannot be removed.
+    pub fn set_bandwidth_limit(&self, bandwidth_limit: f64) -> Result<(), Error> {
+        let mut errors = Vec::new();
+        config::check_range(
+            &mut errors,
+            "bandwidth_limit",
+            bandwidth_limit,
+            0.0,
+            f64::INFINITY,
+            "a finite positive bandwidth or 0",
+        );
+        if self.config.txtime && bandwidth_limit == 0.0 {
+            errors.push(config::Error::Requires("txtime", "bandwidth_limit"));
+        }
+        if !errors.is_empty() {
+            return Err(Error::Config(errors));
+        }
+
+        log::info!(
+            "bandwidth limit set to {:.3} Mbit/s",
+            bandwidth_limit * 8.0 / 1_000_000.0
+        );
+        self.tunables.set_bandwidth_limit(bandwidth_limit);
+        Ok(())
+    }
+
+    /// Duration between two heartbeat messages, `None` if heartbeat is disabled
+    pub fn heartbeat_interval(&self) -> Option<time::Duration> {
+        self.tunables.heartbeat_interval()
+    }
+
+    /// Changes the duration between two heartbeat messages, from the next one
+    ///
+    /// Heartbeat cannot be enabled if it was disabled at startup. The receiver must expect
+    /// heartbeat messages at least as often, or it reports the sender as gone.
+    pub fn set_heartbeat_interval(&self, interval: time::Duration) -> Result<(), Error> {
+        if self.config.heartbeat_interval.is_none() {
+            return Err(Error::Diode("heartbeat is disabled".to_string()));
+        }
+        if interval.is_zero() {
+            return Err(Error::Config(vec![config::Error::Zero(
+                "heartbeat_interval",
+            )]));
+        }
+
+        log::info!(
+            "heartbeat message will be sent every {} ms",
+            interval.as_millis()
+        );
+        self.tunables.set_heartbeat_interval(interval);
+        Ok(())
+    }
+
+    /// Size of the repair data of the blocks being encoded, in bytes
+    pub fn repair_block_size(&self) -> u32 {
+        self.nb_repair_packets.load(sync::atomic::Ordering::Relaxed)
+            * u32::from(protocol::packet_size(&self.object_transmission_info))
+    }
+
+    /// Changes the size of the repair data of the blocks, rounded down to a whole number of
+    /// packets, from the next block encoded
+    ///
+    /// The size cannot go beyond the one the sender started with, for which packets buffers are
+    /// allocated and which configuration packets announce, nor be changed when it follows a
+    /// repair profile.
+    pub fn set_repair_block_size(&self, repair_block_size: u32) -> Result<(), Error> {
+        if self.repair_profile.is_some() {
+            return Err(Error::Diode(
+                "repair block size follows the repair profile".to_string(),
+            ));
+        }
+        if !self.config.fec.has_repair() {
+            return Err(Error::Config(vec![config::Error::Invalid(
+                "repair_block_size",
+                format!(
+                    "no repair packet is sent with \"{}\" forward error correction",
+                    self.config.fec
+                ),
+            )]));
+        }
+        if self.config.repair_block_size < repair_block_size {
+            return Err(Error::Config(vec![config::Error::Invalid(
+                "repair_block_size",
+                format!(
+                    "cannot be raised above the {} bytes the sender started with",
+                    self.config.repair_block_size
+                ),
+            )]));
+        }
+        self.check_loss_tolerance(repair_block_size)?;
+
+        let nb_repair_packets =
+            protocol::nb_repair_packets(&self.object_transmission_info, repair_block_size);
+        log::info!("{nb_repair_packets} repair packets per block from now on");
+        self.nb_repair_packets
+            .store(nb_repair_packets, sync::atomic::Ordering::Relaxed);
+        metrics::gauge("tx_repair_packets").set(u64::from(nb_repair_packets));
+        Ok(())
+    }
+
+    /// Duration pending data of new sessions waits before being flushed, `None` if data is only
+    /// flushed when a block is full
+    pub fn flush_timeout(&self) -> Option<time::Duration> {
+        self.tunables.flush_timeout()
+    }
+
+    /// Changes the flush timeout of the sessions started from now on, `None` disabling it
+    ///
+    /// The flush timeout of a sender flushing every read, as in low latency mode, cannot be
+    /// changed.
+    pub fn set_flush_timeout(&self, flush_timeout: Option<time::Duration>) -> Result<(), Error> {
+        if self.config.flush_timeout == Some(time::Duration::ZERO) {
+            return Err(Error::Diode(
+                "data is flushed as soon as it is read".to_string(),
+            ));
+        }
+        if flush_timeout == Some(time::Duration::ZERO) {
+            return Err(Error::Config(vec![config::Error::Zero("flush_timeout")]));
+        }
+
+        match flush_timeout {
+            Some(flush_timeout) => log::info!(
+                "pending data of new sessions flushed after {} ms",
+                flush_timeout.as_millis()
+            ),
+            None => log::info!("pending data of new sessions flushed only when blocks are full"),
+        }
+        self.tunables.set_flush_timeout(flush_timeout);
+        Ok(())
+    }
+
+    /// Weight of `channel`, see [Config::channel_weights]
+    pub(crate) fn channel_weight(&self, channel: protocol::Channel) -> u32 {
+        self.config
+            .channel_weights
+            .get(&channel)
+            .copied()
+            .unwrap_or(schedule::DEFAULT_WEIGHT)
+    }
+
+    /// Encoding parameters announced by configuration packets, the repair block size being the
+    /// largest one of the repair profile, if any
+    pub(crate) fn encoding_config(&self) -> protocol::EncodingConfig {
+        let repair_block_size = match &self.repair_profile {
+            Some(profile) => profile.repair_block_sizes().max().unwrap_or_default(),
+            None => self.config.repair_block_size,
+        };
+        let features = protocol::Features::default()
+            .with(
+                protocol::Features::WIDE_BLOCK_IDS,
+                self.config.header_format == protocol::HeaderFormat::Wide,
+            )
+            .with(protocol::Features::INTEGRITY, self.config.integrity)
+            .with(protocol::Features::COMPRESSION, self.config.compress)
+            .with(protocol::Features::BLOCK_TRAILER, self.config.block_trailer)
+            .with(protocol::Features::ENCRYPTION, self.config.psk.is_some());
+        protocol::EncodingConfig {
+            version: protocol::PROTOCOL_VERSION,
+            read_from: protocol::ProtocolVersion::current().read_from,
+            features,
+            fec: self.config.fec,
+            mtu: self.config.to_mtu,
+            oti: self.object_transmission_info,
+            nb_repair_packets: if self.config.fec.has_repair() {
+                protocol::nb_repair_packets(&self.object_transmission_info, repair_block_size)
+            } else {
+                0
+            },
+        }
+    }
+
+    /// Stops accepting clients before shutting down, the transfers in progress going on until
+    /// [Sender::is_drained]
+    pub fn drain(&self) {
+        if !self.draining.swap(true, sync::atomic::Ordering::Relaxed) {
+            log::info!(
+                "draining: rejecting new clients, {} transfer(s) in progress and {} block(s) pending",
+                self.multiplex_control.in_use(),
+                self.backlog()
+            );
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Returns `true` when no transfer is in progress and every encoded block was handed to the
+    /// kernel, so that the process can exit without losing data
+    pub fn is_drained(&self) -> bool {
+        self.multiplex_control.in_use() == 0
+            && self.encoding_queue.len() == 0
+            && self.progress.encoded() == self.blocks_sent.load(sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Number of messages and encoded blocks waiting to be sent on the UDP link
+    pub fn backlog(&self) -> usize {
+        self.encoding_queue.len() + self.for_send.len()
+    }
+
+    /// Current backpressure level, 0 meaning that clients sockets have their normal receive
+    /// buffer size
+    pub fn backpressure_level(&self) -> u8 {
+        self.backpressure
+            .as_ref()
+            .map_or(0, backpressure::Backpressure::level)
+    }
+
+    /// Whether new clients are currently deferred, see [OverloadPolicy::FinishFirst]
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
+            .as_ref()
+            .is_some_and(shedding::Shedding::is_shedding)
+    }
+
+    /// Status of the sender as a single line JSON object, see [crate::status]
+    pub fn status_json(&self) -> String {
+        let mut status = status::Object::new("send");
+        status
+            .field("paused", self.is_paused())
+            .field("clients_paused", self.are_clients_paused())
+            .field("draining", self.is_draining())
+            .field("clients_active", self.multiplex_control.in_use())
+            .field("clients_waiting", self.multiplex_control.waiters())
+            .field("backlog", self.backlog())
+            .field("backpressure", self.backpressure_level())
+            .field("shedding", self.is_shedding())
+            .field(
+                "block_to_encode",
+                *self.block_to_encode.lock().expect("acquire lock"),
+            )
+            .field(
+                "block_to_send",
+                *self.block_to_send.lock().expect("acquire lock"),
+            )
+            .field("blocks_encoded", self.progress.encoded())
+            .field(
+                "blocks_sent",
+                self.blocks_sent.load(sync::atomic::Ordering::Relaxed),
+            )
+            .field(
+                "bandwidth_limit_mbit",
+                self.bandwidth_limit() * 8.0 / 1_000_000.0,
+            )
+            .field("repair_block_size", self.repair_block_size());
+        status.finish("tx_", "tx_session_")
+    }
+
+    /// Updates the backpressure level and the shedding state according to the current backlog
+    pub(crate) fn update_load(&self) {
+        let backlog = self.backlog();
+        if let Some(backpressure) = &self.backpressure {
+            backpressure.update(backlog);
+        }
+        if let Some(shedding) = &self.shedding {
+            shedding.update(backlog);
+        }
+    }
+
+    /// Whether the load must be updated even while no packet is sent, for the backpressure level
+    /// or the shedding state to be reset once the backlog drained
+    pub(crate) fn load_update_pending(&self) -> bool {
+        0 < self.backpressure_level() || self.is_shedding()
+    }
+
+    /// Records that the last block of the session of `client_id` was sent, see
+    /// [Sender::wait_flushed]
+    pub(crate) fn confirm_flushed(&self, client_id: protocol::ClientId) {
+        self.flushed.lock().expect("acquire lock").insert(client_id);
+        self.flush_confirmed.notify_all();
+    }
+
+    /// Waits until the udp worker confirmed that the last block of the session of `client_id`
+    /// was handed to the kernel
+    pub(crate) fn wait_flushed(&self, client_id: protocol::ClientId) {
+        let mut flushed = self.flushed.lock().expect("acquire lock");
+        while !flushed.remove(&client_id) {
+            flushed = self.flush_confirmed.wait(flushed).expect("condvar wait");
+        }
+    }
+
+    /// Blocks while emission is paused, blocks being emitted while the pause is announced
+    pub(crate) fn wait_while_paused(&self) {
+        let backlog = metrics::gauge("tx_backlog_blocks");
+        let mut emission = self.emission.lock().expect("acquire lock");
+        while *emission == Emission::Paused {
+            backlog.set(self.backlog() as u64);
+            emission = self
+                .resumed
+                .wait_timeout(emission, time::Duration::from_secs(1))
+                .expect("condvar wait")
+                .0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backpressure;
+    use crate::{loopback, metrics, sock_utils, testing};
+    use std::{
+        io::{Read, Write},
+        net,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread, time,
+    };
+
+    #[test]
+    fn pause_and_resume() {
+        let diode = testing::Diode::start(
+            testing::loopback_config(),
+            |config| config.heartbeat_status = true,
+            |_| (),
+        );
+        let paused = metrics::gauge("rx_sender_paused");
+        let wait_paused = |expected| {
+            let deadline = time::Instant::now() + time::Duration::from_secs(5);
+            while paused.get() != expected {
+                assert!(time::Instant::now() < deadline, "pause not announced");
+                thread::sleep(time::Duration::from_millis(10));
+            }
+        };
+
+        let mut client = diode.connect();
+        client.write_all(b"before pause ").expect("send data");
+        diode.sender.pause();
+        wait_paused(1);
+
+        let blocks_sent = diode.sender.blocks_sent.load(Ordering::Relaxed);
+        client.write_all(b"while paused").expect("send data");
+        // longer than the heartbeat interval and the flush timeout
+        thread::sleep(time::Duration::from_millis(1500));
+        assert_eq!(
+            diode.sender.blocks_sent.load(Ordering::Relaxed),
+            blocks_sent
+        );
+
+        diode.sender.resume();
+        client.shutdown(net::Shutdown::Write).expect("close");
+        drop(client);
+        assert_eq!(
+            diode.receive(time::Duration::from_secs(10)).as_deref(),
+            Some(&b"before pause while paused"[..])
+        );
+        wait_paused(0);
+    }
+
+    /// Sends a session at a limited rate and stops emission as soon as the sender closed the
+    /// connection of the client, returning the data received by then
+    fn stopped_after_close(confirm_flush: bool, data: &[u8]) -> Option<Vec<u8>> {
+        let config = loopback::Config {
+            // bytes per second, the session taking about 400 ms to be sent
+            bandwidth_limit: 2_500_000.0,
+            ..testing::loopback_config()
+        };
+        let diode = testing::Diode::start(
+            config,
+            move |config| config.confirm_flush = confirm_flush,
+            |_| (),
+        );
+
+        let mut client = diode.connect();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(10)))
+            .expect("read timeout");
+        client.write_all(data).expect("send data");
+        client.shutdown(net::Shutdown::Write).expect("close");
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).expect("wait for close");
+        // as if diode-send was stopped
+        diode.sender.pause();
+
+        let received = diode.receive(time::Duration::from_secs(3));
+        diode.sender.resume();
+        received
+    }
+
+    #[test]
+    fn flush_confirmed_before_close() {
+        let data = (0..1_000_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(stopped_after_close(true, &data), Some(data.clone()));
+        // without confirmation, the tail of the session was still queued
+        assert_eq!(stopped_after_close(false, &data), None);
+    }
+
+    #[test]
+    fn target_loss_checked_at_startup() {
+        let sender = |repair_block_size, target_loss, allow_insufficient_repair| {
+            let config = super::Config {
+                repair_block_size,
+                target_loss: Some(target_loss),
+                allow_insufficient_repair,
+                ..loopback::send_config(
+                    &testing::loopback_config(),
+                    net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 9)),
+                )
+            };
+            super::Sender::<net::TcpStream>::new(config).unwrap_or_else(|e| panic!("{e}"))
+        };
+
+        // the single repair packet of blocks of 9 packets is within the margin of RaptorQ: the
+        // sender refuses to start, naming the repair block size meeting the target
+        let insufficient = sender(2920, 0.05, false);
+        let e = thread::scope(|scope| insufficient.start(scope))
+            .expect_err("insufficient repair refused");
+        assert!(
+            matches!(&e, super::Error::Diode(message) if message.contains("at least 4392 bytes")),
+            "{e}"
+        );
+
+        assert!(sender(2920, 0.05, true).check_loss_tolerance(2920).is_ok());
+        // 1 spare packet out of 12
+        assert!(sender(4392, 0.05, false).check_loss_tolerance(4392).is_ok());
+        assert!(sender(4392, 0.08, false).check_loss_tolerance(4392).is_ok());
+        assert!(sender(4392, 0.09, false)
+            .check_loss_tolerance(4392)
+            .is_err());
+    }
+
+    /// Sends 4 MB at a limited rate by writes of 16 KB, returning the latencies of the writes and
+    /// the highest backpressure level reached
+    fn limited_writes(backpressure: bool) -> (Vec<time::Duration>, u8) {
+        let diode = testing::Diode::start(
+            loopback::Config {
+                // bytes per second, the session taking about 1.6 s to be sent
+                bandwidth_limit: 2_500_000.0,
+                // on the loopback interface, whose MSS is 64 KB, receive buffers shrunk to blocks
+                // of the default configuration would stall on zero window probes
+                encoding_block_size: 58_560,
+                repair_block_size: 5856,
+                ..testing::loopback_config()
+            },
+            move |config| config.backpressure = backpressure,
+            |_| (),
+        );
+        let data: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+        let mut latencies = Vec::new();
+        let mut max_level = 0;
+        thread::scope(|scope| {
+            let received = scope.spawn(|| diode.receive(time::Duration::from_secs(20)));
+            let mut client = diode.connect();
+            sock_utils::set_socket_send_buffer_size(&client, 64 * 1024).expect("send buffer");
+            for chunk in data.chunks(16 * 1024) {
+                let start = time::Instant::now();
+                client.write_all(chunk).expect("send data");
+                latencies.push(start.elapsed());
+
+                let level = diode.sender.backpressure_level();
+                if max_level < level {
+                    max_level = level;
+                    assert!(diode
+                        .sender
+                        .status_json()
+                        .contains(&format!("\"backpressure\":{level}")));
+                }
+            }
+            drop(client);
+            let received = received.join().expect("receiving thread");
+            assert!(received.as_deref() == Some(&data[..]), "session delivered");
+        });
+
+        // lowered once the backlog is sent
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while 0 < diode.sender.backpressure_level() {
+            assert!(time::Instant::now() < deadline, "backpressure not lowered");
+            thread::sleep(backpressure::STEP_INTERVAL);
+        }
+
+        latencies.sort();
+        (latencies, max_level)
+    }
+
+    #[test]
+    fn backpressure_raised() {
+        assert_eq!(limited_writes(false).1, 0);
+        assert!(0 < limited_writes(true).1);
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn backpressure_write_latencies() {
+        let percentile =
+            |latencies: &[time::Duration], p: usize| latencies[latencies.len() * p / 100];
+        let (default, _) = limited_writes(false);
+        let (smoothed, _) = limited_writes(true);
+        for (name, latencies) in [("default", &default), ("backpressure", &smoothed)] {
+            eprintln!(
+                "{name}: write latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                percentile(latencies, 50),
+                percentile(latencies, 90),
+                percentile(latencies, 99),
+                latencies[latencies.len() - 1]
+            );
+        }
+        assert!(percentile(&smoothed, 99) < percentile(&default, 99));
+    }
+
+    /// Sends a long session at a limited rate and, once the link is saturated, a short session,
+    /// returning how much of the long session was delivered before the short one
+    fn delivered_before_short(overload_policy: super::OverloadPolicy) -> f64 {
+        let finish_first = overload_policy == super::OverloadPolicy::FinishFirst;
+        let diode = testing::Diode::start(
+            loopback::Config {
+                // bytes per second, the long session taking about 1.6 s to be sent
+                bandwidth_limit: 2_500_000.0,
+                encoding_block_size: 58_560,
+                repair_block_size: 5856,
+                ..testing::loopback_config()
+            },
+            move |config| config.overload_policy = overload_policy,
+            |_| (),
+        );
+        let long: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+        let deferred = metrics::counter("tx_clients_deferred");
+        let deadline = time::Instant::now() + time::Duration::from_secs(20);
+        let long_received = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            // the long session is read concurrently, not to stall the short one
+            let received = scope.spawn(|| {
+                let mut session = diode.accept(deadline).expect("long session delivered");
+                let long_received = &long_received;
+                let reader = scope.spawn(move || {
+                    let mut data = Vec::new();
+                    let mut buffer = vec![0; 64 * 1024];
+                    loop {
+                        match session.read(&mut buffer).expect("read session") {
+                            0 => return data,
+                            len => {
+                                data.extend_from_slice(&buffer[..len]);
+                                long_received.fetch_add(len, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                });
+                let mut session = diode.accept(deadline).expect("short session delivered");
+                let delivered_before = long_received.load(Ordering::Relaxed);
+                let mut short = Vec::new();
+                session.read_to_end(&mut short).expect("read session");
+                let long = reader.join().expect("reading thread");
+                (long, short, delivered_before)
+            });
+            let writer = scope.spawn(|| {
+                let mut client = diode.connect();
+                sock_utils::set_socket_send_buffer_size(&client, 64 * 1024).expect("send buffer");
+                client.write_all(&long).expect("send data");
+            });
+
+            thread::sleep(time::Duration::from_millis(300));
+            if finish_first {
+                while !diode.sender.is_shedding() {
+                    assert!(time::Instant::now() < deadline, "link not saturated");
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                assert!(diode.sender.status_json().contains("\"shedding\":true"));
+            }
+            let nb_deferred = deferred.get();
+            diode.send(b"short");
+            if finish_first {
+                let deadline = time::Instant::now() + time::Duration::from_secs(1);
+                while deferred.get() == nb_deferred {
+                    assert!(time::Instant::now() < deadline, "client not deferred");
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+            }
+
+            writer.join().expect("writing thread");
+            let (delivered, short, delivered_before) = received.join().expect("receiving thread");
+            assert!(delivered == long, "long session delivered");
+            assert_eq!(short, b"short");
+            assert!(!diode.sender.is_shedding());
+            delivered_before as f64 / long.len() as f64
+        })
+    }
+
+    #[test]
+    fn overload_policies() {
+        // deferred until the backlog of the long session drained
+        assert!(0.75 < delivered_before_short(super::OverloadPolicy::FinishFirst));
+        assert!(delivered_before_short(super::OverloadPolicy::Fair) < 0.5);
+    }
 }
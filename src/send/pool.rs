@@ -0,0 +1,188 @@
+//! Pool of reusable buffers holding the serialized packets of a block
+//!
+//! Encoding workers lease a buffer, serialize the packets of a block into it contiguously, and
+//! pass it to the udp worker which sends the packets directly from the buffer. Once sent, the
+//! buffer is released back to the pool, avoiding one allocation per packet at high block rates.
+
+use crate::protocol;
+
+/// Serialized packets of a block, stored one after the other in a single allocation
+pub(crate) struct Packets {
+    data: Vec<u8>,
+    ends: Vec<usize>,
+    /// Block the packets were encoded from
+    pub(crate) block_id: protocol::BlockId,
+    /// Whether the block carries a compressed message, flagged in the header of its packets
+    pub(crate) compressed: bool,
+    /// Client whose session ends with this block, when its emission must be confirmed
+    pub(crate) end_of: Option<protocol::ClientId>,
+    /// Whether the block carries the heartbeat announcing a pause, emission stopping after it
+    pub(crate) pauses: bool,
+}
+
+impl Packets {
+    fn with_capacity(packet_size: usize, nb_packets: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(packet_size * nb_packets),
+            ends: Vec::with_capacity(nb_packets),
+            block_id: protocol::BlockId::default(),
+            compressed: false,
+            end_of: None,
+            pauses: false,
+        }
+    }
+
+    /// Appends `packet` serialized with [protocol::write_packet], with a header in `format`
+    pub(crate) fn push(
+        &mut self,
+        format: protocol::HeaderFormat,
+        packet: &raptorq::EncodingPacket,
+    ) -> Result<(), protocol::Error> {
+        let start = self.data.len();
+        self.data
+            .resize(start + format.size() + packet.data().len(), 0);
+        let header = protocol::Header {
+            block_id: self.block_id,
+            compressed: self.compressed,
+            ..protocol::Header::from(packet.payload_id())
+        };
+        let len = protocol::write_packet(format, &header, packet.data(), &mut self.data[start..])?;
+        self.ends.push(start + len);
+        Ok(())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.ends.iter().scan(0, |start, &end| {
+            let packet = &self.data[*start..end];
+            *start = end;
+            Some(packet)
+        })
+    }
+}
+
+pub(crate) struct Pool {
+    packet_size: usize,
+    nb_packets: usize,
+    to_pool: crossbeam_channel::Sender<Packets>,
+    for_pool: crossbeam_channel::Receiver<Packets>,
+}
+
+impl Pool {
+    /// Creates a pool keeping at most `size` unused buffers, each able to hold `nb_packets`
+    /// serialized packets of `packet_size` bytes without reallocation
+    pub(crate) fn new(size: usize, packet_size: usize, nb_packets: usize) -> Self {
+        let (to_pool, for_pool) = crossbeam_channel::bounded(size);
+        Self {
+            packet_size,
+            nb_packets,
+            to_pool,
+            for_pool,
+        }
+    }
+
+    /// Returns an empty buffer, allocating it if the pool is empty
+    pub(crate) fn lease(&self) -> Packets {
+        self.for_pool
+            .try_recv()
+            .unwrap_or_else(|_| Packets::with_capacity(self.packet_size, self.nb_packets))
+    }
+
+    /// Gives `packets` back to the pool, it is dropped if the pool is already full
+    pub(crate) fn release(&self, mut packets: Packets) {
+        packets.data.clear();
+        packets.ends.clear();
+        packets.compressed = false;
+        packets.end_of = None;
+        packets.pauses = false;
+        let _ = self.to_pool.try_send(packets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pool;
+    use crate::protocol;
+
+    const PAYLOAD_SIZE: usize = 10;
+
+    fn packet(block_id: protocol::BlockId, symbol_id: u32) -> raptorq::EncodingPacket {
+        let header = protocol::Header {
+            block_id,
+            symbol_id,
+            compressed: false,
+        };
+        raptorq::EncodingPacket::new(header.into(), vec![symbol_id as u8; PAYLOAD_SIZE])
+    }
+
+    #[test]
+    fn packets_serialized() {
+        for format in [protocol::HeaderFormat::Narrow, protocol::HeaderFormat::Wide] {
+            let pool = Pool::new(1, format.size() + PAYLOAD_SIZE, 3);
+            let block_id = protocol::BlockId::from(0x1234);
+
+            let mut packets = pool.lease();
+            packets.block_id = block_id;
+            packets.compressed = true;
+            let data = packets.data.as_ptr();
+            for symbol_id in [0, 1, 300] {
+                packets
+                    .push(format, &packet(block_id, symbol_id))
+                    .unwrap_or_else(|e| panic!("{e}"));
+            }
+            assert_eq!(packets.data.as_ptr(), data, "reallocated");
+
+            let serialized = packets.iter().collect::<Vec<_>>();
+            assert_eq!(serialized.len(), 3);
+            for (packet, symbol_id) in serialized.into_iter().zip([0, 1, 300]) {
+                let header = protocol::Header {
+                    block_id,
+                    symbol_id,
+                    compressed: true,
+                };
+                let mut expected = vec![0; format.size() + PAYLOAD_SIZE];
+                protocol::write_packet(
+                    format,
+                    &header,
+                    &[symbol_id as u8; PAYLOAD_SIZE],
+                    &mut expected,
+                )
+                .unwrap_or_else(|e| panic!("{e}"));
+                assert_eq!(packet, expected.as_slice());
+
+                let (read, payload) =
+                    protocol::read_packet(format, packet).unwrap_or_else(|e| panic!("{e}"));
+                assert_eq!(read.symbol_id, symbol_id);
+                assert!(read.compressed);
+                assert_eq!(payload, &[symbol_id as u8; PAYLOAD_SIZE]);
+            }
+        }
+    }
+
+    #[test]
+    fn buffers_reused() {
+        let format = protocol::HeaderFormat::Narrow;
+        let pool = Pool::new(1, format.size() + PAYLOAD_SIZE, 2);
+        let block_id = protocol::BlockId::default();
+
+        let mut packets = pool.lease();
+        packets
+            .push(format, &packet(block_id, 0))
+            .unwrap_or_else(|e| panic!("{e}"));
+        packets.compressed = true;
+        packets.end_of = Some(1);
+        packets.pauses = true;
+        let data = packets.data.as_ptr();
+        pool.release(packets);
+
+        let packets = pool.lease();
+        assert_eq!(packets.data.as_ptr(), data);
+        assert_eq!(packets.iter().count(), 0);
+        assert!(!packets.compressed && packets.end_of.is_none() && !packets.pauses);
+
+        // at most one unused buffer is kept
+        pool.release(packets);
+        let other = super::Packets::with_capacity(format.size() + PAYLOAD_SIZE, 2);
+        pool.release(other);
+        assert_eq!(pool.for_pool.len(), 1);
+    }
+}
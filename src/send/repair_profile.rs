@@ -0,0 +1,176 @@
+//! Time-of-day schedule of the repair block size
+//!
+//! Since the link is one-way, the sender cannot learn the loss rate observed by the receiver,
+//! but a repair profile can follow its known daily pattern. A profile is a TOML file mapping
+//! times of day, in the local time of the host, to the repair block size in bytes applied from
+//! then on, the last entry of the day lasting until the first one of the next day:
+//!
+//! ```toml
+//! # quiet nights, protected peak hours
+//! "00:00" = 2928
+//! "07:30" = 11712
+//! "20:00" = 5856
+//! ```
+//!
+//! The profile worker applies the entry of the current time to the blocks encoded from then on,
+//! and reads the file again when it is modified, so that the schedule can be changed without
+//! restarting the sender. A profile failing to be read or checked is ignored, the previous one
+//! remaining in use.
+
+use crate::{localtime, metrics, protocol, send};
+use std::{fmt, fs, io, path, sync::atomic::Ordering, thread, time};
+
+/// Interval at which the current entry and the modification time of the file are checked
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+pub enum Error {
+    Io(path::PathBuf, io::Error),
+    Syntax(usize, &'static str),
+    /// The time of day of the line is already given by an earlier line
+    Duplicate(usize, String),
+    Empty,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(path, e) => {
+                write!(fmt, "failed to read repair profile {}: {e}", path.display())
+            }
+            Self::Syntax(line, reason) => write!(fmt, "repair profile, line {line}: {reason}"),
+            Self::Duplicate(line, time) => {
+                write!(fmt, "repair profile, line {line}: {time} is given twice")
+            }
+            Self::Empty => write!(fmt, "repair profile has no entry"),
+        }
+    }
+}
+
+/// Repair block sizes of a day, sorted by the minute of the day they start at
+#[derive(Clone, PartialEq, Eq)]
+pub struct Profile(Vec<(u16, u32)>);
+
+impl Profile {
+    pub fn read(path: &path::Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let mut entries: Vec<(u16, u32)> = Vec::new();
+
+        for (i, line) in content.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(Error::Syntax(line_number, "expected \"HH:MM\" = nb_bytes"));
+            };
+            let key = key.trim();
+            let Some(time) = key.strip_prefix('"').and_then(|key| key.strip_suffix('"')) else {
+                return Err(Error::Syntax(line_number, "time of day must be quoted"));
+            };
+            let Some(minute) = parse_time(time) else {
+                return Err(Error::Syntax(line_number, "time of day must be HH:MM"));
+            };
+            let value = value.split('#').next().unwrap_or_default().trim();
+            let Ok(repair_block_size) = value.parse::<u32>() else {
+                return Err(Error::Syntax(
+                    line_number,
+                    "repair block size must be a number of bytes",
+                ));
+            };
+
+            if entries.iter().any(|(m, _)| *m == minute) {
+                return Err(Error::Duplicate(line_number, time.to_string()));
+            }
+            entries.push((minute, repair_block_size));
+        }
+
+        if entries.is_empty() {
+            return Err(Error::Empty);
+        }
+        entries.sort_unstable();
+        Ok(Self(entries))
+    }
+
+    /// Repair block size applied at `minute` of the day
+    pub fn repair_block_size_at(&self, minute: u16) -> u32 {
+        self.0
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= minute)
+            .or_else(|| self.0.last())
+            .map_or(0, |(_, size)| *size)
+    }
+
+    /// Repair block sizes of the profile, in the order of the day
+    pub fn repair_block_sizes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().map(|(_, size)| *size)
+    }
+}
+
+/// Parses a `HH:MM` time of day into a number of minutes since midnight
+fn parse_time(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours = hours.parse::<u16>().ok().filter(|h| *h < 24)?;
+    let minutes = minutes.parse::<u16>().ok().filter(|m| *m < 60)?;
+    Some(hours * 60 + minutes)
+}
+
+fn modified(path: &path::Path) -> Option<time::SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub(crate) fn start<C>(
+    sender: &send::Sender<C>,
+    path: &path::Path,
+    mut profile: Profile,
+) -> Result<(), send::Error> {
+    let gauge = metrics::gauge("tx_repair_packets");
+    let mut last_modified = modified(path);
+    let mut nb_repair_packets = sender.nb_repair_packets.load(Ordering::Relaxed);
+    gauge.set(u64::from(nb_repair_packets));
+
+    loop {
+        let now_modified = modified(path);
+        if now_modified != last_modified {
+            last_modified = now_modified;
+            match Profile::read(path).map_err(send::Error::RepairProfile) {
+                Ok(new_profile) if new_profile == profile => (),
+                Ok(new_profile) => match sender.check_repair_profile(&new_profile) {
+                    Ok(()) => {
+                        log::info!("repair profile {} reloaded", path.display());
+                        profile = new_profile;
+                    }
+                    Err(e) => log::error!("ignoring modified repair profile: {e}"),
+                },
+                Err(e) => log::error!("ignoring modified repair profile: {e}"),
+            }
+        }
+
+        let minute = localtime::minute_of_day(time::SystemTime::now());
+        let repair_block_size = profile.repair_block_size_at(minute);
+        let new_nb_repair_packets =
+            protocol::nb_repair_packets(&sender.object_transmission_info, repair_block_size);
+        if new_nb_repair_packets != nb_repair_packets {
+            log::info!(
+                "repair profile: {} repair packets per block from now on, instead of {nb_repair_packets}",
+                new_nb_repair_packets
+            );
+            nb_repair_packets = new_nb_repair_packets;
+            sender
+                .nb_repair_packets
+                .store(nb_repair_packets, Ordering::Relaxed);
+            gauge.set(u64::from(nb_repair_packets));
+        }
+
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
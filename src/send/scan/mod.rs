@@ -0,0 +1,251 @@
+//! Outbound content checks applied to client data before it is sent through the diode
+//!
+//! A [ScanPolicy] is asked for a new [SessionScan] for every client session. Each buffer read
+//! from the client is then given to the session scans before being enqueued for encoding: when a
+//! scan reports a violation, the buffer is not sent and the session is aborted. Policies are
+//! public so that embedders of the library can provide their own checks.
+
+use crate::metrics;
+
+pub mod regex;
+
+/// Content check of a single client session
+pub trait SessionScan: Send {
+    /// Checks the next buffer of data read from the client, returning the reason of the
+    /// violation if the session must be aborted
+    fn scan(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+pub trait ScanPolicy: Send + Sync {
+    /// Name of the policy, as reported in logs and metrics
+    fn name(&self) -> &'static str;
+
+    /// Returns the content check of a new client session
+    fn new_session(&self) -> Box<dyn SessionScan + '_>;
+}
+
+/// Policy accepting any content
+pub struct Passthrough;
+
+impl SessionScan for Passthrough {
+    fn scan(&mut self, _data: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl ScanPolicy for Passthrough {
+    fn name(&self) -> &'static str {
+        "passthrough"
+    }
+
+    fn new_session(&self) -> Box<dyn SessionScan + '_> {
+        Box::new(Self)
+    }
+}
+
+/// Policy aborting sessions sending more than a given number of bytes
+pub struct MaxBytesPerSession {
+    max_bytes: u64,
+}
+
+impl MaxBytesPerSession {
+    pub const fn new(max_bytes: u64) -> Self {
+        Self { max_bytes }
+    }
+}
+
+struct SessionBytes {
+    max_bytes: u64,
+    bytes: u64,
+}
+
+impl SessionScan for SessionBytes {
+    fn scan(&mut self, data: &[u8]) -> Result<(), String> {
+        self.bytes += data.len() as u64;
+        if self.max_bytes < self.bytes {
+            return Err(format!(
+                "session exceeds the maximum of {} bytes",
+                self.max_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ScanPolicy for MaxBytesPerSession {
+    fn name(&self) -> &'static str {
+        "max_bytes_per_session"
+    }
+
+    fn new_session(&self) -> Box<dyn SessionScan + '_> {
+        Box::new(SessionBytes {
+            max_bytes: self.max_bytes,
+            bytes: 0,
+        })
+    }
+}
+
+/// Policy aborting sessions whose content matches one of a set of regular expressions
+///
+/// The search state is kept across buffers, so that matches spanning buffer boundaries are
+/// found. Only matches of at most `window` bytes are reported, bounding the span of patterns
+/// with unbounded repetitions such as `.*`.
+pub struct RegexDeny {
+    regexes: regex::RegexSet,
+    window: u64,
+}
+
+impl RegexDeny {
+    pub fn new(patterns: &[String], window: u64) -> Result<Self, regex::Error> {
+        Ok(Self {
+            regexes: regex::RegexSet::new(patterns)?,
+            window,
+        })
+    }
+}
+
+struct SessionRegex<'a> {
+    policy: &'a RegexDeny,
+    stream: regex::Stream,
+}
+
+impl SessionScan for SessionRegex<'_> {
+    fn scan(&mut self, data: &[u8]) -> Result<(), String> {
+        match self
+            .stream
+            .feed(&self.policy.regexes, data, self.policy.window)
+        {
+            Some(i) => Err(format!(
+                "content matches forbidden pattern \"{}\"",
+                self.policy.regexes.pattern(i)
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ScanPolicy for RegexDeny {
+    fn name(&self) -> &'static str {
+        "regex_deny"
+    }
+
+    fn new_session(&self) -> Box<dyn SessionScan + '_> {
+        Box::new(SessionRegex {
+            policy: self,
+            stream: self.regexes.stream(),
+        })
+    }
+}
+
+/// Content checks of a session for all the configured policies
+pub(crate) struct Scans<'a> {
+    scans: Vec<(&'static str, Box<dyn SessionScan + 'a>)>,
+}
+
+impl<'a> Scans<'a> {
+    pub(crate) fn new(policies: &'a [Box<dyn ScanPolicy>]) -> Self {
+        Self {
+            scans: policies
+                .iter()
+                .map(|policy| (policy.name(), policy.new_session()))
+                .collect(),
+        }
+    }
+
+    /// Returns the name of the violated policy and the reason of the violation, if any
+    pub(crate) fn scan(&mut self, data: &[u8]) -> Result<(), (&'static str, String)> {
+        for (name, scan) in &mut self.scans {
+            if let Err(reason) = scan.scan(data) {
+                metrics::counter(&metrics::labeled(
+                    "tx_sessions_rejected_scan",
+                    &[("policy", name)],
+                ))
+                .inc();
+                return Err((name, reason));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Diode};
+    use std::{io::Write, thread, time};
+
+    #[test]
+    fn first_violated_policy() {
+        let policies: Vec<Box<dyn ScanPolicy>> = vec![
+            Box::new(Passthrough),
+            Box::new(MaxBytesPerSession::new(10)),
+            Box::new(
+                RegexDeny::new(&["forbidden".to_string()], 64).unwrap_or_else(|e| panic!("{e}")),
+            ),
+        ];
+
+        let mut scans = Scans::new(&policies);
+        assert!(scans.scan(b"forbid").is_ok());
+        let (policy, reason) = scans.scan(b"den").expect_err("violation");
+        assert_eq!(policy, "regex_deny");
+        assert_eq!(reason, "content matches forbidden pattern \"forbidden\"");
+
+        // every session has its own state
+        let mut scans = Scans::new(&policies);
+        assert!(scans.scan(b"den").is_ok());
+        let (policy, _) = scans.scan(b"12345678").expect_err("violation");
+        assert_eq!(policy, "max_bytes_per_session");
+    }
+
+    #[test]
+    fn session_aborted() {
+        let diode = Diode::start(
+            testing::loopback_config(),
+            |config| {
+                config.site_id = Some("scan-2469".to_string());
+                config.scan_policies = vec![Box::new(
+                    RegexDeny::new(&["secret-\\d+".to_string()], 64)
+                        .unwrap_or_else(|e| panic!("{e}")),
+                )];
+            },
+            |_| (),
+        );
+
+        // each write is sent in its own block after the flush timeout
+        let mut client = diode.connect();
+        for data in [&b"public data, "[..], b"secret-", b"2469"] {
+            client.write_all(data).expect("send data");
+            thread::sleep(time::Duration::from_millis(300));
+        }
+        drop(client);
+
+        let data = diode
+            .receive(time::Duration::from_secs(10))
+            .expect("session started");
+        assert!(data.starts_with(b"public data, "));
+        assert!(!data.ends_with(b"2469"));
+
+        let labels = [("site", "scan-2469")];
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while metrics::counter(&metrics::labeled("rx_sessions_aborted", &labels)).get() == 0
+            && time::Instant::now() < deadline
+        {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(
+            metrics::counter(&metrics::labeled("rx_sessions_aborted", &labels)).get(),
+            1
+        );
+        assert_eq!(
+            metrics::counter(&metrics::labeled("rx_sessions_completed", &labels)).get(),
+            0
+        );
+        assert!(
+            1 <= metrics::counter(&metrics::labeled(
+                "tx_sessions_rejected_scan",
+                &[("policy", "regex_deny")]
+            ))
+            .get()
+        );
+    }
+}
@@ -0,0 +1,711 @@
+//! Minimal streaming regular expressions engine over bytes
+//!
+//! Patterns are compiled into a single non-deterministic automaton, simulated one byte at a
+//! time: the simulation state is carried from one buffer to the next, so that matches spanning
+//! several buffers are found without copying data. The state size is bounded by the size of the
+//! automaton, and a match is only reported if it is at most `window` bytes long.
+//!
+//! Supported syntax: literal bytes, `.` (any byte but `\n`), classes (`[a-z]`, `[^0-9]`),
+//! escapes (`\d`, `\w`, `\s` and their negations, `\n`, `\r`, `\t`, `\0`, `\xHH`, escaped
+//! meta-characters), groups (`(...)`, `(?:...)`), alternation (`|`), quantifiers (`*`, `+`, `?`,
+//! `{n}`, `{n,}`, `{n,m}`, lazy variants being accepted) and a leading `(?i)` flag for ASCII case
+//! insensitivity. Anchors and back-references are not supported.
+
+use std::{fmt, mem};
+
+/// Maximum number of instructions of a compiled set of patterns
+const MAX_INSTS: usize = 65536;
+
+/// Maximum value of a counted repetition
+const MAX_REPEAT: u32 = 1000;
+
+pub struct Error {
+    pattern: String,
+    reason: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "invalid pattern \"{}\": {}", self.pattern, self.reason)
+    }
+}
+
+#[derive(Clone)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    const fn empty() -> Self {
+        Self([0; 4])
+    }
+
+    fn single(b: u8) -> Self {
+        let mut set = Self::empty();
+        set.insert(b);
+        set
+    }
+
+    fn insert(&mut self, b: u8) {
+        self.0[usize::from(b >> 6)] |= 1 << (b & 63);
+    }
+
+    fn insert_range(&mut self, from: u8, to: u8) {
+        for b in from..=to {
+            self.insert(b);
+        }
+    }
+
+    fn union(&mut self, other: &Self) {
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word |= other;
+        }
+    }
+
+    fn negate(&mut self) {
+        for word in &mut self.0 {
+            *word = !*word;
+        }
+    }
+
+    const fn contains(&self, b: u8) -> bool {
+        self.0[(b >> 6) as usize] & (1 << (b & 63)) != 0
+    }
+
+    fn ignore_case(&mut self) {
+        for b in b'a'..=b'z' {
+            if self.contains(b) || self.contains(b.to_ascii_uppercase()) {
+                self.insert(b);
+                self.insert(b.to_ascii_uppercase());
+            }
+        }
+    }
+}
+
+enum Node {
+    Empty,
+    Set(ByteSet),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    ignore_case: bool,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, String> {
+        let b = self.peek().ok_or("unexpected end of pattern")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn eat(&mut self, prefix: &[u8]) -> bool {
+        if self.input[self.pos..].starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternate(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.eat(b"|") {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().expect("one branch")
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(b) = self.peek() {
+            if b == b'|' || b == b')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().expect("one node"),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn quantified(&mut self, mut atom: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some(b'*') => (0, None),
+                Some(b'+') => (1, None),
+                Some(b'?') => (0, Some(1)),
+                Some(b'{') => {
+                    self.pos += 1;
+                    self.bounds()?
+                }
+                _ => return Ok(atom),
+            };
+            self.pos += 1;
+            // lazy quantifiers match the same inputs
+            self.eat(b"?");
+            atom = Node::Repeat(Box::new(atom), min, max);
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<u32>, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let n = std::str::from_utf8(&self.input[start..self.pos])
+            .expect("ASCII digits")
+            .parse::<u32>()
+            .map_err(|e| format!("invalid repetition count: {e}"))?;
+        if MAX_REPEAT < n {
+            return Err(format!("repetition count {n} exceeds {MAX_REPEAT}"));
+        }
+        Ok(Some(n))
+    }
+
+    /// Parses `n}`, `n,}` or `n,m}`, leaving the cursor on the closing brace
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), String> {
+        let min = self.number()?.ok_or("missing repetition count")?;
+        let max = if self.eat(b",") {
+            self.number()?
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some(b'}') {
+            return Err("unclosed counted repetition".to_string());
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(format!(
+                "invalid repetition range {{{min},{}}}",
+                max.unwrap_or(0)
+            ));
+        }
+        Ok((min, max))
+    }
+
+    fn set(&self, mut set: ByteSet) -> Node {
+        if self.ignore_case {
+            set.ignore_case();
+        }
+        Node::Set(set)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next()? {
+            b'(' => {
+                self.eat(b"?:");
+                let node = self.alternate()?;
+                if !self.eat(b")") {
+                    return Err("unclosed group".to_string());
+                }
+                Ok(node)
+            }
+            b'[' => {
+                let set = self.class()?;
+                Ok(self.set(set))
+            }
+            b'.' => {
+                let mut set = ByteSet::single(b'\n');
+                set.negate();
+                Ok(Node::Set(set))
+            }
+            b'\\' => {
+                let set = self.escape()?;
+                Ok(self.set(set))
+            }
+            b @ (b'*' | b'+' | b'?' | b'{') => {
+                Err(format!("nothing to repeat before '{}'", char::from(b)))
+            }
+            b @ (b'^' | b'$') => Err(format!("anchor '{}' is not supported", char::from(b))),
+            b => Ok(self.set(ByteSet::single(b))),
+        }
+    }
+
+    fn hex_digit(&mut self) -> Result<u8, String> {
+        let b = self.next()?;
+        char::from(b)
+            .to_digit(16)
+            .map(|d| d as u8)
+            .ok_or_else(|| format!("invalid hexadecimal digit '{}'", char::from(b)))
+    }
+
+    fn escape(&mut self) -> Result<ByteSet, String> {
+        let mut set = ByteSet::empty();
+        let b = self.next()?;
+        match b.to_ascii_lowercase() {
+            b'd' => set.insert_range(b'0', b'9'),
+            b'w' => {
+                set.insert_range(b'0', b'9');
+                set.insert_range(b'a', b'z');
+                set.insert_range(b'A', b'Z');
+                set.insert(b'_');
+            }
+            b's' => {
+                for b in [b' ', b'\t', b'\n', b'\r', 0x0b, 0x0c] {
+                    set.insert(b);
+                }
+            }
+            _ => {
+                let b = match b {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'0' => 0,
+                    b'x' => self.hex_digit()? << 4 | self.hex_digit()?,
+                    b if b.is_ascii_alphanumeric() => {
+                        return Err(format!("unknown escape '\\{}'", char::from(b)))
+                    }
+                    b => b,
+                };
+                return Ok(ByteSet::single(b));
+            }
+        }
+        if b.is_ascii_uppercase() {
+            set.negate();
+        }
+        Ok(set)
+    }
+
+    fn class(&mut self) -> Result<ByteSet, String> {
+        let negated = self.eat(b"^");
+        let mut set = ByteSet::empty();
+        let mut first = true;
+        loop {
+            let b = self.next().map_err(|_| "unclosed class".to_string())?;
+            let from = match b {
+                b']' if !first => break,
+                b'\\' => {
+                    let escaped = self.escape()?;
+                    if escaped.0.iter().map(|w| w.count_ones()).sum::<u32>() != 1 {
+                        set.union(&escaped);
+                        first = false;
+                        continue;
+                    }
+                    (0..=255).find(|&b| escaped.contains(b)).expect("one byte")
+                }
+                b => b,
+            };
+            first = false;
+            if self.peek() == Some(b'-') && self.input.get(self.pos + 1) != Some(&b']') {
+                self.pos += 1;
+                let to = match self.next()? {
+                    b'\\' => {
+                        let escaped = self.escape()?;
+                        (0..=255)
+                            .find(|&b| escaped.contains(b))
+                            .ok_or("invalid class range")?
+                    }
+                    b => b,
+                };
+                if to < from {
+                    return Err("invalid class range".to_string());
+                }
+                set.insert_range(from, to);
+            } else {
+                set.insert(from);
+            }
+        }
+        if negated {
+            // folding case before negating, so that `(?i)[^a]` excludes both `a` and `A`
+            if self.ignore_case {
+                set.ignore_case();
+            }
+            set.negate();
+        }
+        Ok(set)
+    }
+}
+
+enum Inst {
+    Set(ByteSet),
+    Split(usize, usize),
+    Jump(usize),
+    Match(usize),
+}
+
+struct Compiler {
+    insts: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if MAX_INSTS <= self.insts.len() {
+            return Err("pattern is too large".to_string());
+        }
+        self.insts.push(inst);
+        Ok(self.insts.len() - 1)
+    }
+
+    fn patch(&mut self, pc: usize, target: usize) {
+        match &mut self.insts[pc] {
+            Inst::Split(_, b) => *b = target,
+            Inst::Jump(t) => *t = target,
+            _ => unreachable!("only splits and jumps are patched"),
+        }
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Empty => (),
+            Node::Set(set) => {
+                self.push(Inst::Set(set.clone()))?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.node(node)?;
+                }
+            }
+            Node::Alternate(branches) => {
+                let mut jumps = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.push(Inst::Split(self.insts.len() + 1, 0))?;
+                        self.node(branch)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        let next = self.insts.len();
+                        self.patch(split, next);
+                    } else {
+                        self.node(branch)?;
+                    }
+                }
+                let end = self.insts.len();
+                for jump in jumps {
+                    self.patch(jump, end);
+                }
+            }
+            Node::Repeat(node, min, max) => {
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(self.insts.len() + 1, 0))?;
+                        self.node(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let end = self.insts.len();
+                        self.patch(split, end);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(self.insts.len() + 1, 0))?);
+                            self.node(node)?;
+                        }
+                        let end = self.insts.len();
+                        for split in splits {
+                            self.patch(split, end);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of patterns compiled together, searched in a single pass
+pub struct RegexSet {
+    insts: Vec<Inst>,
+    patterns: Vec<String>,
+}
+
+impl RegexSet {
+    pub fn new(patterns: &[String]) -> Result<Self, Error> {
+        let mut compiler = Compiler { insts: Vec::new() };
+
+        let mut splits = Vec::new();
+        for (i, pattern) in patterns.iter().enumerate() {
+            let error = |reason| Error {
+                pattern: pattern.clone(),
+                reason,
+            };
+
+            let mut parser = Parser {
+                input: pattern.as_bytes(),
+                pos: 0,
+                ignore_case: false,
+            };
+            parser.ignore_case = parser.eat(b"(?i)");
+            let node = parser.alternate().map_err(error)?;
+            if parser.pos != parser.input.len() {
+                return Err(error("unmatched ')'".to_string()));
+            }
+
+            if i + 1 < patterns.len() {
+                let split = compiler.insts.len();
+                splits.push(split);
+                compiler.push(Inst::Split(split + 1, 0)).map_err(error)?;
+            }
+            let start = compiler.insts.len();
+            compiler.node(&node).map_err(error)?;
+            compiler.push(Inst::Match(i)).map_err(error)?;
+            if i + 1 < patterns.len() {
+                let next = compiler.insts.len();
+                compiler.patch(splits[i], next);
+            }
+
+            // a pattern matching empty data would match everywhere
+            let mut stream = Stream::new(compiler.insts.len());
+            stream.generation = 1;
+            if let Some(i) = stream.closure(&compiler.insts, start, 0) {
+                return Err(Error {
+                    pattern: patterns[i].clone(),
+                    reason: "pattern matches empty data".to_string(),
+                });
+            }
+        }
+
+        Ok(Self {
+            insts: compiler.insts,
+            patterns: patterns.to_vec(),
+        })
+    }
+
+    pub fn pattern(&self, i: usize) -> &str {
+        &self.patterns[i]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Returns the state of a new search
+    pub fn stream(&self) -> Stream {
+        Stream::new(self.insts.len())
+    }
+}
+
+/// State of a search through data given in several parts
+pub struct Stream {
+    /// Threads waiting for the next byte, as instruction index and start offset of the match
+    threads: Vec<(usize, u64)>,
+    next: Vec<(usize, u64)>,
+    stack: Vec<usize>,
+    /// Generation at which each instruction was last added to a threads list
+    seen: Vec<u64>,
+    generation: u64,
+    offset: u64,
+}
+
+impl Stream {
+    fn new(nb_insts: usize) -> Self {
+        Self {
+            threads: Vec::new(),
+            next: Vec::new(),
+            stack: Vec::new(),
+            seen: vec![0; nb_insts],
+            generation: 0,
+            offset: 0,
+        }
+    }
+
+    /// Adds to `next` the threads reachable from `pc` without consuming input, returning the
+    /// index of a pattern if one matches
+    fn closure(&mut self, insts: &[Inst], pc: usize, start: u64) -> Option<usize> {
+        let mut matched = None;
+        self.stack.push(pc);
+        while let Some(pc) = self.stack.pop() {
+            if self.seen[pc] == self.generation {
+                continue;
+            }
+            self.seen[pc] = self.generation;
+            match insts[pc] {
+                Inst::Set(_) => self.next.push((pc, start)),
+                Inst::Jump(target) => self.stack.push(target),
+                Inst::Split(a, b) => {
+                    self.stack.push(b);
+                    self.stack.push(a);
+                }
+                Inst::Match(i) => matched = matched.or(Some(i)),
+            }
+        }
+        matched
+    }
+
+    /// Feeds `data` to the search, returning the index of the first pattern found to match at
+    /// most `window` bytes
+    pub fn feed(&mut self, regexes: &RegexSet, data: &[u8], window: u64) -> Option<usize> {
+        if regexes.is_empty() {
+            return None;
+        }
+
+        for &b in data {
+            // a match may start at the current offset, such threads being added first so that
+            // the most recent start is kept when several threads reach the same instruction
+            self.generation += 1;
+            self.next.clear();
+            self.closure(&regexes.insts, 0, self.offset);
+            for i in 0..self.threads.len() {
+                let (pc, start) = self.threads[i];
+                if self.seen[pc] != self.generation {
+                    self.seen[pc] = self.generation;
+                    self.next.push((pc, start));
+                }
+            }
+            mem::swap(&mut self.threads, &mut self.next);
+
+            self.generation += 1;
+            self.next.clear();
+            self.offset += 1;
+            for i in 0..self.threads.len() {
+                let (pc, start) = self.threads[i];
+                if window < self.offset - start {
+                    continue;
+                }
+                let Inst::Set(bytes) = &regexes.insts[pc] else {
+                    unreachable!("threads wait on sets");
+                };
+                if bytes.contains(b) {
+                    if let Some(i) = self.closure(&regexes.insts, pc + 1, start) {
+                        return Some(i);
+                    }
+                }
+            }
+            mem::swap(&mut self.threads, &mut self.next);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the index of the pattern found in `data` given as `chunks`
+    fn find(patterns: &[&str], chunks: &[&[u8]], window: u64) -> Option<usize> {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let regexes = RegexSet::new(&patterns).unwrap_or_else(|e| panic!("{e}"));
+        let mut stream = regexes.stream();
+        chunks
+            .iter()
+            .find_map(|chunk| stream.feed(&regexes, chunk, window))
+    }
+
+    #[test]
+    fn feed_across_chunks() {
+        let data = b"some text, then secret-2469 and more text";
+        let pattern = ["secret-[0-9]+ and"];
+
+        assert_eq!(find(&pattern, &[data], 64), Some(0));
+        for split in 0..=data.len() {
+            let (a, b) = data.split_at(split);
+            assert_eq!(find(&pattern, &[a, b], 64), Some(0), "split at {split}");
+        }
+        let bytes = data.iter().map(std::slice::from_ref).collect::<Vec<_>>();
+        assert_eq!(find(&pattern, &bytes, 64), Some(0));
+
+        // a match restarting after a failed partial match in a previous chunk
+        assert_eq!(find(&pattern, &[b"secret-", b"secret-1 and"], 64), Some(0));
+        assert_eq!(find(&pattern, &[b"secret-", b"x and"], 64), None);
+        assert_eq!(
+            find(&pattern, &[b"secret-2469", b"", b" an", b"d"], 64),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn first_matching_pattern() {
+        let patterns = ["alpha", "beta", "gamma"];
+        assert_eq!(find(&patterns, &[b"..gam", b"ma.."], 64), Some(2));
+        assert_eq!(find(&patterns, &[b"..beta.."], 64), Some(1));
+        assert_eq!(find(&patterns, &[b"..delta.."], 64), None);
+        assert_eq!(find(&[], &[b"anything"], 64), None);
+    }
+
+    #[test]
+    fn window() {
+        let pattern = ["begin.*end"];
+        // "begin" and "end" with 8 bytes in between is a 16 bytes long match
+        let data = b"xxbegin12345678endxx";
+
+        assert_eq!(find(&pattern, &[data], 16), Some(0));
+        assert_eq!(find(&pattern, &[data], 15), None);
+        let (a, b) = data.split_at(10);
+        assert_eq!(find(&pattern, &[a, b], 16), Some(0));
+        assert_eq!(find(&pattern, &[a, b], 15), None);
+
+        // a later start within the window matches even if an earlier one is too far
+        assert_eq!(
+            find(&pattern, &[b"begin1234567890", b"begin1end"], 16),
+            Some(0)
+        );
+        // the window also bounds counted repetitions
+        assert_eq!(find(&["a{20}"], &[&[b'a'; 20]], 19), None);
+        assert_eq!(find(&["a{20}"], &[&[b'a'; 20]], 20), Some(0));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(find(&["[a-c]x"], &[b"..bx.."], 64), Some(0));
+        assert_eq!(find(&["[a-c]x"], &[b"..dx.."], 64), None);
+        assert_eq!(find(&["[^0-9]9"], &[b"0909"], 64), None);
+        assert_eq!(find(&["[^0-9]9"], &[b"0a9"], 64), Some(0));
+        assert_eq!(find(&["[]x]y"], &[b"]y"], 64), Some(0));
+        assert_eq!(find(&["[a-]y"], &[b"-y"], 64), Some(0));
+        assert_eq!(find(&["[\\d_]{3}"], &[b"a1_2b"], 64), Some(0));
+        assert_eq!(find(&["[\\x00-\\x1f]"], &[b"text\x1b"], 64), Some(0));
+        assert_eq!(find(&["[\\x00-\\x1f]"], &[b"text"], 64), None);
+
+        assert_eq!(find(&["\\d\\d"], &[b"a1b2"], 64), None);
+        assert_eq!(find(&["\\d\\d"], &[b"a12"], 64), Some(0));
+        assert_eq!(find(&["\\w+\\s\\W"], &[b"word !"], 64), Some(0));
+        assert_eq!(find(&["\\S\\S"], &[b" 1 2"], 64), None);
+        assert_eq!(find(&["\\xff\\x00"], &[b"\x01\xff", b"\x00"], 64), Some(0));
+        assert_eq!(find(&["a.b"], &[b"a\nb"], 64), None);
+        assert_eq!(find(&["a.b"], &[b"a\rb"], 64), Some(0));
+        assert_eq!(find(&["a\\.b"], &[b"axb"], 64), None);
+    }
+
+    #[test]
+    fn ignore_case() {
+        assert_eq!(find(&["(?i)password"], &[b"PassWord"], 64), Some(0));
+        assert_eq!(find(&["password"], &[b"PassWord"], 64), None);
+        assert_eq!(find(&["(?i)[a-c]-\\x41"], &[b"B-a"], 64), Some(0));
+        assert_eq!(find(&["(?i)[^a]"], &[b"aA"], 64), None);
+        // only the pattern starting with the flag ignores case
+        let patterns = ["secret", "(?i)token"];
+        assert_eq!(find(&patterns, &[b"SECRET TOKEN"], 64), Some(1));
+    }
+
+    #[test]
+    fn alternation_and_repetitions() {
+        assert_eq!(find(&["(?:ab|cd)+e"], &[b"abcdabe"], 64), Some(0));
+        assert_eq!(find(&["x(ab|cd)?y"], &[b"xy"], 64), Some(0));
+        assert_eq!(find(&["x(ab|cd)?y"], &[b"xaby"], 64), Some(0));
+        assert_eq!(find(&["x(ab|cd)?y"], &[b"xacy"], 64), None);
+        assert_eq!(find(&["a{2,3}b"], &[b"ab"], 64), None);
+        assert_eq!(find(&["xa{2,3}b"], &[b"xaaab"], 64), Some(0));
+        assert_eq!(find(&["xa{2,3}b"], &[b"xaaaab"], 64), None);
+        assert_eq!(find(&["xa{2,}?b"], &[b"xaaaab"], 64), Some(0));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for pattern in [
+            "", "a*", "(a|)", "^a", "a$", "*a", "(a", "a)", "[a", "a{2", "a{3,2}", "a{1001}",
+            "\\q", "\\xg0", "[b-a]",
+        ] {
+            assert!(
+                RegexSet::new(&[pattern.to_string()]).is_err(),
+                "{pattern} accepted"
+            );
+        }
+    }
+}
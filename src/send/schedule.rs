@@ -0,0 +1,122 @@
+//! Queue of the messages to encode, shared by the channels according to their weights
+//!
+//! Each channel has its own bounded queue of messages, so that a channel filling its queue does
+//! not prevent the other channels from queueing theirs. Encoding workers take the messages of the
+//! channels by smooth weighted round-robin: while several channels have messages waiting, a
+//! channel of weight `w` gets `w` blocks out of each round of as many blocks as the sum of their
+//! weights, the blocks of the channels being interleaved. Messages of a channel are encoded in the
+//! order they were queued.
+
+use crate::protocol;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync,
+};
+
+/// Weight of the channels not given one
+pub(crate) const DEFAULT_WEIGHT: u32 = 1;
+
+struct Channel {
+    messages: VecDeque<protocol::Message>,
+    weight: u32,
+    /// Current credit of the smooth weighted round-robin
+    credit: i64,
+}
+
+pub(crate) struct Queue {
+    weights: BTreeMap<protocol::Channel, u32>,
+    capacity: usize,
+    channels: sync::Mutex<BTreeMap<protocol::Channel, Channel>>,
+    not_empty: sync::Condvar,
+    not_full: sync::Condvar,
+}
+
+impl Queue {
+    /// Creates a queue holding up to `capacity` messages per channel
+    pub(crate) fn new(weights: BTreeMap<protocol::Channel, u32>, capacity: usize) -> Self {
+        Self {
+            weights,
+            capacity,
+            channels: sync::Mutex::new(BTreeMap::new()),
+            not_empty: sync::Condvar::new(),
+            not_full: sync::Condvar::new(),
+        }
+    }
+
+    /// Queues `message`, waiting while the queue of its channel is full
+    pub(crate) fn push(&self, message: protocol::Message) {
+        let channel = protocol::channel(message.client_id());
+        let mut channels = self.channels.lock().expect("acquire lock");
+        channels = self
+            .not_full
+            .wait_while(channels, |channels| {
+                channels
+                    .get(&channel)
+                    .is_some_and(|queue| self.capacity <= queue.messages.len())
+            })
+            .expect("condvar wait");
+        channels
+            .entry(channel)
+            .or_insert_with(|| Channel {
+                messages: VecDeque::with_capacity(self.capacity),
+                weight: self
+                    .weights
+                    .get(&channel)
+                    .copied()
+                    .unwrap_or(DEFAULT_WEIGHT),
+                credit: 0,
+            })
+            .messages
+            .push_back(message);
+        self.not_empty.notify_one();
+    }
+
+    /// Takes the next message to encode, waiting for one to be queued
+    pub(crate) fn pop(&self) -> protocol::Message {
+        let mut channels = self.channels.lock().expect("acquire lock");
+        channels = self
+            .not_empty
+            .wait_while(channels, |channels| {
+                channels.values().all(|queue| queue.messages.is_empty())
+            })
+            .expect("condvar wait");
+
+        let mut total = 0;
+        let mut elected: Option<(protocol::Channel, i64)> = None;
+        for (channel, queue) in channels.iter_mut() {
+            if queue.messages.is_empty() {
+                continue;
+            }
+            queue.credit += i64::from(queue.weight);
+            total += i64::from(queue.weight);
+            if elected.is_none_or(|(_, credit)| credit < queue.credit) {
+                elected = Some((*channel, queue.credit));
+            }
+        }
+        let (channel, _) = elected.expect("a channel has messages");
+
+        let queue = channels.get_mut(&channel).expect("elected channel");
+        queue.credit -= total;
+        let message = queue
+            .messages
+            .pop_front()
+            .expect("elected channel has messages");
+        if queue.messages.is_empty() {
+            // a channel starting to send again does not benefit from its past credit
+            queue.credit = 0;
+        }
+        // waiters of several channels may wait on the same condition variable
+        self.not_full.notify_all();
+        message
+    }
+
+    /// Number of messages waiting to be encoded
+    pub(crate) fn len(&self) -> usize {
+        self.channels
+            .lock()
+            .expect("acquire lock")
+            .values()
+            .map(|queue| queue.messages.len())
+            .sum()
+    }
+}
@@ -1,36 +1,211 @@
 //! Worker that gets a client socket and becomes a `crate::send::client` worker
 
-use crate::{protocol, send, send::client};
-use std::{io::Read, os::fd::AsRawFd};
+use crate::{metrics, protocol, proxy_protocol, scrub, send, send::client};
+use std::{io::Read, net, os::fd::AsRawFd};
 
 pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error>
 where
     C: Read + AsRawFd + Send,
 {
     loop {
-        let client = sender.for_server.recv()?;
+        let (client, peer, channel) = sender.for_server.recv()?;
+        // errors are logged, the worker going on with the next client
+        let _ = serve(sender, client, peer, channel);
+    }
+}
+
+/// Runs the transfer of `client` on `channel` once a transfer slot is available, aborting it on
+/// error
+pub(crate) fn serve<C>(
+    sender: &send::Sender<C>,
+    mut client: C,
+    peer: Option<net::SocketAddr>,
+    channel: protocol::Channel,
+) -> Result<(), send::Error>
+where
+    C: Read + AsRawFd + Send,
+{
+    // connections of trusted load balancers start with the address of the client they proxy
+    let peer = match peer {
+        Some(proxy)
+            if sender
+                .config
+                .proxy_protocol_from
+                .iter()
+                .any(|network| network.contains(&proxy.ip())) =>
+        {
+            match proxy_protocol::read_header(&mut client, proxy_protocol::TIMEOUT) {
+                Ok(origin) => {
+                    if let Some(origin) = origin {
+                        log::debug!(
+                            "client from {} proxied for {}",
+                            scrub::addr(&proxy),
+                            scrub::addr(&origin)
+                        );
+                    }
+                    origin.or(peer)
+                }
+                Err(e) => {
+                    metrics::counter("tx_proxy_protocol_rejected").inc();
+                    let e = format!("rejecting client from {}: {e}", scrub::addr(&proxy));
+                    log::error!("{e}");
+                    return Err(send::Error::Diode(e));
+                }
+            }
+        }
+        _ => peer,
+    };
 
-        log::debug!("try to acquire multiplex access..");
-        sender.multiplex_control.acquire();
-        log::debug!("multiplex access acquired");
+    let client_name = peer.map_or_else(
+        || "client".to_string(),
+        |peer| format!("client from {}", scrub::addr(&peer)),
+    );
 
-        let client_id = protocol::new_client_id();
+    if let Some(shedding) = &sender.shedding {
+        if shedding.is_shedding() {
+            log::info!("{client_name}: UDP link saturated, deferred until the backlog drains");
+            metrics::counter("tx_clients_deferred").inc();
+            shedding.wait();
+        }
+    }
 
-        let client_res = client::start(sender, client_id, client);
+    // clients of channels of higher weight are given transfer slots first
+    let rank = sender.channel_weight(channel);
+
+    log::debug!("try to acquire multiplex access..");
+    if !sender.multiplex_control.try_acquire(rank) {
+        log::info!(
+            "{client_name}: all {} transfer slots busy, waiting ({} other client(s) waiting)",
+            sender.multiplex_control.in_use(),
+            sender.multiplex_control.waiters(),
+        );
+        let timeout = sender.config.client_queue_timeout;
+        if !sender.multiplex_control.acquire_ranked(rank, timeout) {
+            log::warn!(
+                "{client_name}: rejected after waiting {} ms for a transfer slot",
+                timeout.unwrap_or_default().as_millis()
+            );
+            metrics::counter("tx_clients_rejected").inc();
+            return Err(send::Error::Diode(format!(
+                "{client_name}: no transfer slot available"
+            )));
+        }
+    }
+    log::debug!("multiplex access acquired");
 
+    // clients waiting for a slot when the sender started draining are not served
+    if sender.is_draining() {
         sender.multiplex_control.release();
+        metrics::counter("tx_clients_rejected").inc();
+        return Err(send::Error::Diode(format!(
+            "{client_name}: rejected, sender is shutting down"
+        )));
+    }
 
-        if let Err(e) = client_res {
-            log::error!("client {client_id:x}: error: {e}");
+    let client_id = protocol::new_client_id(channel);
+    metrics::counter("tx_sessions_started").inc();
 
-            if let Err(e) = sender.to_encoding.send(protocol::Message::new(
-                protocol::MessageType::Abort,
-                sender.from_buffer_size,
-                client_id,
-                None,
-            )) {
-                log::error!("client {client_id:x}: failed to abort : {e}");
-            }
+    let client_res = client::start(sender, client_id, peer, client);
+
+    sender.multiplex_control.release();
+
+    if let Err(e) = &client_res {
+        log::error!("client {client_id:x}: error: {e}");
+
+        sender.encoding_queue.push(protocol::Message::new(
+            protocol::MessageType::Abort,
+            sender.from_buffer_size,
+            client_id,
+            None,
+        ));
+    }
+    client_res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, Diode};
+    use std::{
+        io::{Read, Write},
+        thread, time,
+    };
+
+    /// Number of clients connected at once, above the 2 transfer slots of the test diode
+    const NB_CLIENTS: usize = 2 + 3;
+
+    #[test]
+    fn clients_wait_for_slots() {
+        let diode = Diode::start(testing::loopback_config(), |_| (), |_| ());
+
+        let clients = (0..NB_CLIENTS)
+            .map(|i| {
+                let mut client = diode.connect();
+                client
+                    .write_all(format!("data of client {i}").as_bytes())
+                    .expect("send data");
+                client
+            })
+            .collect::<Vec<_>>();
+        // sessions are kept open for a while, the last clients waiting for a transfer slot
+        thread::sleep(time::Duration::from_millis(300));
+        drop(clients);
+
+        let mut sessions = (0..NB_CLIENTS)
+            .map(|_| {
+                let data = diode
+                    .receive(time::Duration::from_secs(10))
+                    .expect("session delivered");
+                String::from_utf8(data).expect("client data")
+            })
+            .collect::<Vec<_>>();
+        sessions.sort();
+        let expected = (0..NB_CLIENTS)
+            .map(|i| format!("data of client {i}"))
+            .collect::<Vec<_>>();
+        assert_eq!(sessions, expected);
+    }
+
+    #[test]
+    fn clients_rejected_after_queue_timeout() {
+        let timeout = time::Duration::from_millis(200);
+        let diode = Diode::start(
+            testing::loopback_config(),
+            move |config| config.client_queue_timeout = Some(timeout),
+            |_| (),
+        );
+
+        let mut clients = Vec::new();
+        for i in 0..NB_CLIENTS {
+            let mut client = diode.connect();
+            client
+                .write_all(format!("data of client {i}").as_bytes())
+                .expect("send data");
+            clients.push(client);
+            // the first clients get the transfer slots
+            thread::sleep(time::Duration::from_millis(50));
         }
+
+        // the sender closes the connections of the clients that waited too long, the first ones
+        // keeping their slots
+        for client in &mut clients[2..] {
+            client
+                .set_read_timeout(Some(time::Duration::from_secs(10)))
+                .expect("read timeout");
+            let mut buffer = [0; 1];
+            assert!(matches!(client.read(&mut buffer), Ok(0) | Err(_)));
+        }
+        drop(clients);
+
+        let mut sessions = (0..2)
+            .map(|_| {
+                let data = diode
+                    .receive(time::Duration::from_secs(10))
+                    .expect("session delivered");
+                String::from_utf8(data).expect("client data")
+            })
+            .collect::<Vec<_>>();
+        sessions.sort();
+        assert_eq!(sessions, ["data of client 0", "data of client 1"]);
+        assert!(diode.receive(time::Duration::from_secs(1)).is_none());
     }
 }
@@ -0,0 +1,109 @@
+//! Deferral of new clients while the UDP link is saturated, see [crate::send::OverloadPolicy]
+//!
+//! When the backlog of messages and blocks waiting to be sent reaches the high-water mark, the
+//! sender starts shedding load: clients accepted from then on wait before being given a transfer
+//! slot, while sessions in progress continue at full rate. Shedding stops once the backlog drops
+//! to the low-water mark, waiting clients being served again.
+
+use crate::{metrics, send::backpressure::Watermarks};
+use std::sync;
+
+pub(crate) struct Shedding {
+    watermarks: Watermarks,
+    shedding: sync::Mutex<bool>,
+    drained: sync::Condvar,
+    gauge: sync::Arc<metrics::Metric>,
+}
+
+impl Shedding {
+    pub(crate) fn new(watermarks: Watermarks) -> Self {
+        let gauge = metrics::gauge("tx_shedding");
+        gauge.set(0);
+        Self {
+            watermarks,
+            shedding: sync::Mutex::new(false),
+            drained: sync::Condvar::new(),
+            gauge,
+        }
+    }
+
+    pub(crate) fn is_shedding(&self) -> bool {
+        *self.shedding.lock().expect("acquire lock")
+    }
+
+    /// Starts or stops shedding according to the current `backlog`
+    pub(crate) fn update(&self, backlog: usize) {
+        let mut shedding = self.shedding.lock().expect("acquire lock");
+        if !*shedding && self.watermarks.is_high(backlog) {
+            log::info!("UDP link saturated with {backlog} block(s) pending, deferring new clients");
+            *shedding = true;
+            self.gauge.set(1);
+        } else if *shedding && self.watermarks.is_low(backlog) {
+            log::info!("backlog drained to {backlog} block(s), serving new clients");
+            *shedding = false;
+            self.gauge.set(0);
+            self.drained.notify_all();
+        }
+    }
+
+    /// Waits until shedding stops, returning whether the caller had to wait
+    pub(crate) fn wait(&self) -> bool {
+        let mut shedding = self.shedding.lock().expect("acquire lock");
+        if !*shedding {
+            return false;
+        }
+        while *shedding {
+            shedding = self.drained.wait(shedding).expect("condvar wait");
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shedding;
+    use crate::send::backpressure::Watermarks;
+    use std::{thread, time};
+
+    #[test]
+    fn hysteresis() {
+        // high-water mark at 6, low-water mark at 2
+        let shedding = Shedding::new(Watermarks::new(8));
+        assert!(!shedding.wait());
+
+        for (backlog, expected) in [
+            (5, false),
+            (6, true),
+            (8, true),
+            (3, true),
+            (2, false),
+            (5, false),
+            (7, true),
+            (0, false),
+        ] {
+            shedding.update(backlog);
+            assert_eq!(shedding.is_shedding(), expected, "backlog {backlog}");
+        }
+    }
+
+    #[test]
+    fn deferred_until_drained() {
+        let shedding = Shedding::new(Watermarks::new(8));
+        shedding.update(8);
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                let started = time::Instant::now();
+                (shedding.wait(), started.elapsed())
+            });
+            thread::sleep(time::Duration::from_millis(100));
+            // above the low-water mark, clients keep waiting
+            shedding.update(3);
+            thread::sleep(time::Duration::from_millis(100));
+            assert!(!waiting.is_finished());
+            shedding.update(1);
+            let (waited, elapsed) = waiting.join().expect("waiting thread");
+            assert!(waited);
+            assert!(time::Duration::from_millis(200) <= elapsed);
+        });
+    }
+}
@@ -0,0 +1,71 @@
+//! Parameters of the sender that can be changed while it runs
+//!
+//! Workers read these values instead of the matching [send::Config] fields each time they need
+//! them: the udp worker before each batch of datagrams, the heartbeat worker before each sleep and
+//! clients workers when a session starts. The number of repair packets of the blocks is
+//! [send::Sender::nb_repair_packets], shared with the repair profile worker.
+
+use crate::send;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time,
+};
+
+/// Stored instead of a duration when the matching feature is disabled
+const DISABLED: u64 = u64::MAX;
+
+pub(crate) struct Tunables {
+    /// Bytes per second, as the bits of a `f64`, 0 meaning unlimited
+    bandwidth_limit: AtomicU64,
+    /// Nanoseconds
+    heartbeat_interval: AtomicU64,
+    /// Nanoseconds
+    flush_timeout: AtomicU64,
+}
+
+fn to_nanos(duration: Option<time::Duration>) -> u64 {
+    duration.map_or(DISABLED, |duration| {
+        u64::try_from(duration.as_nanos()).unwrap_or(DISABLED - 1)
+    })
+}
+
+fn from_nanos(nanos: u64) -> Option<time::Duration> {
+    (nanos != DISABLED).then_some(time::Duration::from_nanos(nanos))
+}
+
+impl Tunables {
+    pub(crate) fn new(config: &send::Config) -> Self {
+        Self {
+            bandwidth_limit: AtomicU64::new(config.bandwidth_limit.to_bits()),
+            heartbeat_interval: AtomicU64::new(to_nanos(config.heartbeat_interval)),
+            flush_timeout: AtomicU64::new(to_nanos(config.flush_timeout)),
+        }
+    }
+
+    pub(crate) fn bandwidth_limit(&self) -> f64 {
+        f64::from_bits(self.bandwidth_limit.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_bandwidth_limit(&self, bandwidth_limit: f64) {
+        self.bandwidth_limit
+            .store(bandwidth_limit.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn heartbeat_interval(&self) -> Option<time::Duration> {
+        from_nanos(self.heartbeat_interval.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_heartbeat_interval(&self, interval: time::Duration) {
+        self.heartbeat_interval
+            .store(to_nanos(Some(interval)), Ordering::Relaxed);
+    }
+
+    pub(crate) fn flush_timeout(&self) -> Option<time::Duration> {
+        from_nanos(self.flush_timeout.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_flush_timeout(&self, flush_timeout: Option<time::Duration>) {
+        self.flush_timeout
+            .store(to_nanos(flush_timeout), Ordering::Relaxed);
+    }
+}
@@ -1,16 +1,253 @@
 //! Worker that actually sends packets on the UDP diode link
 
-use crate::{send, sock_utils, udp};
-use std::net;
+use crate::{
+    crypto, metrics, protocol, scrub, send, send::backpressure, send::watchdog, sock_utils, udp,
+};
+use std::{io, net, sync, time};
 
-pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
+/// Binds the UDP socket of the sender to `to_bind` and, if given, restricts it to the network
+/// interface named `to_bind_device`, setting its multicast parameters when a destination is a
+/// multicast group
+///
+/// Every UDP socket of the sender on the diode link must be created by this function, so that
+/// they all leave the host through the same interface and with the same source address, as
+/// expected by firewalls, the socket of the second link being created by [bind_stripe].
+pub(crate) fn bind(config: &send::Config) -> Result<net::UdpSocket, send::Error> {
+    let socket = bind_to(config.to_bind, config.to_bind_device.as_deref())?;
+
+    if let Some(group) = config.multicast_group() {
+        let group = group.ip();
+        config
+            .to_multicast_interface
+            .as_deref()
+            .map(sock_utils::interface_index)
+            .transpose()
+            .and_then(|index| {
+                sock_utils::set_socket_multicast_send(
+                    &socket,
+                    &group,
+                    config.to_multicast_ttl,
+                    index.unwrap_or(0),
+                )
+            })
+            .map_err(|e| send::Error::Multicast(group, e))?;
+        log::info!(
+            "sending to multicast group with TTL {} on interface {}",
+            config.to_multicast_ttl,
+            config
+                .to_multicast_interface
+                .as_deref()
+                .unwrap_or("chosen by routes")
+        );
+    }
+    Ok(socket)
+}
+
+/// Binds the UDP socket of the second link to `stripe_to_bind` and, if given, restricts it to
+/// the network interface named `stripe_to_bind_device`, when packets are striped over a second
+/// link
+pub(crate) fn bind_stripe(config: &send::Config) -> Result<Option<net::UdpSocket>, send::Error> {
+    if config.stripe_to_udp.is_none() {
+        return Ok(None);
+    }
+    bind_to(
+        config.stripe_to_bind,
+        config.stripe_to_bind_device.as_deref(),
+    )
+    .map(Some)
+}
+
+fn bind_to(to_bind: net::SocketAddr, device: Option<&str>) -> Result<net::UdpSocket, send::Error> {
+    let socket = net::UdpSocket::bind(to_bind).map_err(|e| send::Error::Bind(to_bind, e))?;
+    if let Some(device) = device {
+        sock_utils::set_socket_bind_device(&socket, device)
+            .map_err(|e| send::Error::BindDevice(device.to_string(), e))?;
+    }
+
+    let local_addr = socket.local_addr()?;
+    match device {
+        Some(device) => log::info!(
+            "UDP socket bound to {} on device {device}",
+            scrub::addr(&local_addr)
+        ),
+        None => log::info!("UDP socket bound to {}", scrub::addr(&local_addr)),
+    }
+    Ok(socket)
+}
+
+/// Sends the packets of the sender on `socket`, bound by [bind], striping them with
+/// `stripe_socket`, bound by [bind_stripe], when packets are striped over a second link
+///
+/// Even packets of each block are sent on the first link and odd ones on the second, any subset
+/// of enough packets allowing to decode a block. Configuration packets are sent on both links,
+/// block trailers, counting the packets sent on both, on the first one only.
+pub(crate) fn start<C>(
+    sender: &send::Sender<C>,
+    socket: net::UdpSocket,
+    stripe_socket: Option<net::UdpSocket>,
+) -> Result<(), send::Error> {
+    let destinations: Vec<_> = sender.config.destinations().collect();
     log::info!(
-        "sending UDP traffic to {} with MTU {} binding to {}",
-        sender.config.to_udp,
-        sender.config.to_mtu,
-        sender.config.to_bind
+        "sending UDP traffic to {} with MTU {}",
+        destinations
+            .iter()
+            .map(|to_udp| scrub::addr(to_udp).to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        sender.config.to_mtu
     );
-    let socket = net::UdpSocket::bind(sender.config.to_bind)?;
+    let ipv4_mtu = protocol::packet_mtu(sender.config.to_mtu, &sender.config.to_udp);
+    if ipv4_mtu != sender.config.to_mtu {
+        log::info!("packets sized for an IPv4 MTU of {ipv4_mtu} to leave room for the IPv6 header");
+    }
+    let mut bandwidth_limit = sender.tunables.bandwidth_limit();
+    let mut udp_messages = messages(sender, socket, &destinations, bandwidth_limit)?;
+    let mut stripe_messages = match (stripe_socket, sender.config.stripe_to_udp) {
+        (Some(socket), Some(stripe_to_udp)) => {
+            log::info!(
+                "striping UDP traffic over a second link to {}",
+                scrub::addr(&stripe_to_udp)
+            );
+            Some(messages(sender, socket, &[stripe_to_udp], bandwidth_limit)?)
+        }
+        _ => None,
+    };
+
+    if sender.config.block_trailer {
+        log::info!("each block will be followed by a trailer");
+    }
+
+    let mut sealed = sender.config.psk.clone().map(|key| {
+        log::info!(
+            "datagrams encrypted with the pre-shared key, packets sized for an MTU of {}",
+            sender.config.packet_mtu()
+        );
+        Sealed::new(key, sender.config.header_format.size())
+    });
+
+    let encoding_config = sender.encoding_config();
+    let config_packet = protocol::write_config(&encoding_config);
+    let config_broadcast = sender.config.config_broadcast;
+    if let Some(interval) = config_broadcast {
+        log::info!(
+            "configuration packet announcing {encoding_config} will be sent every {} seconds",
+            interval.as_secs()
+        );
+    }
+    let mut config_sent_at: Option<time::Instant> = None;
+
+    let backlog = metrics::gauge("tx_backlog_blocks");
+
+    loop {
+        // configuration packets are sent between blocks, but not while emission is paused
+        let paused = sender.is_paused();
+        if let Some(interval) = config_broadcast {
+            if !paused && config_sent_at.is_none_or(|at| interval <= at.elapsed()) {
+                send_config(
+                    &mut udp_messages,
+                    stripe_messages.as_mut(),
+                    sealed.as_mut(),
+                    &config_packet,
+                )?;
+                config_sent_at = Some(time::Instant::now());
+            }
+        }
+
+        // waking up while idle to lower the backpressure level and stop shedding once the
+        // backlog drained, and to send the next configuration packet
+        let config_timeout = config_broadcast
+            .zip(config_sent_at)
+            .filter(|_| !paused)
+            .map(|(interval, at)| interval.saturating_sub(at.elapsed()));
+        let timeout = sender
+            .load_update_pending()
+            .then_some(backpressure::STEP_INTERVAL)
+            .into_iter()
+            .chain(config_timeout)
+            .min();
+        let timer = timeout.map_or_else(crossbeam_channel::never, crossbeam_channel::after);
+        let packets = crossbeam_channel::select! {
+            recv(sender.for_send) -> packets => packets?,
+            recv(sender.for_config_request) -> _ => {
+                log::info!("sending configuration packet announcing {encoding_config} on request");
+                send_config(
+                    &mut udp_messages,
+                    stripe_messages.as_mut(),
+                    sealed.as_mut(),
+                    &config_packet,
+                )?;
+                config_sent_at = Some(time::Instant::now());
+                continue;
+            }
+            recv(timer) -> _ => {
+                sender.update_load();
+                continue;
+            }
+        };
+        watchdog::Progress::inc(&sender.progress.udp);
+        sender.wait_while_paused();
+        backlog.set(sender.backlog() as u64);
+        sender.update_load();
+        let new_bandwidth_limit = sender.tunables.bandwidth_limit();
+        if new_bandwidth_limit != bandwidth_limit {
+            let set = udp_messages
+                .set_bandwidth_limit(new_bandwidth_limit)
+                .and_then(|()| {
+                    stripe_messages.as_mut().map_or(Ok(()), |stripe| {
+                        stripe.set_bandwidth_limit(new_bandwidth_limit)
+                    })
+                });
+            match set {
+                Ok(()) => bandwidth_limit = new_bandwidth_limit,
+                Err(e) => log::error!("failed to change bandwidth limit: {e}"),
+            }
+        }
+        let nb_sent = match &mut stripe_messages {
+            None => send_datagrams(&mut udp_messages, sealed.as_mut(), packets.iter())?,
+            Some(stripe_messages) => {
+                send_datagrams(
+                    &mut udp_messages,
+                    sealed.as_mut(),
+                    packets.iter().step_by(2),
+                )? + send_datagrams(
+                    stripe_messages,
+                    sealed.as_mut(),
+                    packets.iter().skip(1).step_by(2),
+                )?
+            }
+        };
+        if sender.config.block_trailer {
+            let trailer = protocol::write_trailer(
+                sender.config.header_format,
+                packets.block_id,
+                nb_sent as u32,
+            );
+            send_datagrams(
+                &mut udp_messages,
+                sealed.as_mut(),
+                std::iter::once(&trailer[..]),
+            )?;
+        }
+        if let Some(client_id) = packets.end_of {
+            sender.confirm_flushed(client_id);
+        }
+        if packets.pauses {
+            sender.pause_announced();
+        }
+        sender.packets_pool.release(packets);
+        sender
+            .blocks_sent
+            .fetch_add(1, sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Prepares the sending of datagrams on `socket` to `destinations`
+fn messages<C>(
+    sender: &send::Sender<C>,
+    socket: net::UdpSocket,
+    destinations: &[net::SocketAddr],
+    bandwidth_limit: f64,
+) -> Result<udp::UdpMessages<udp::UdpSend>, send::Error> {
     sock_utils::set_socket_send_buffer_size(&socket, sender.config.udp_buffer_size as i32)?;
     let sock_buffer_size = sock_utils::get_socket_send_buffer_size(&socket)?;
     log::info!("UDP socket send buffer size set to {sock_buffer_size}");
@@ -24,17 +261,217 @@ pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
     let mut udp_messages = udp::UdpMessages::new_sender(
         socket,
         usize::from(sender.to_max_messages),
-        sender.config.to_udp,
-        sender.config.bandwidth_limit,
+        destinations,
+        bandwidth_limit,
     );
 
-    loop {
-        let packets = sender.for_send.recv()?;
-        udp_messages.send_mmsg(
-            packets
-                .iter()
-                .map(raptorq::EncodingPacket::serialize)
-                .collect(),
-        )?;
+    if sender.config.txtime {
+        match udp_messages.enable_txtime() {
+            Ok(()) => log::info!("pacing offloaded with transmit times (SO_TXTIME)"),
+            Err(e) => log::warn!("failed to enable transmit times, pacing in software: {e}"),
+        }
+    }
+
+    if sender.config.udp_gso {
+        match udp_messages.enable_gso() {
+            Ok(()) => log::info!("UDP segmentation offload (UDP_SEGMENT) enabled"),
+            Err(e) => log::warn!("failed to enable UDP segmentation offload: {e}"),
+        }
+    }
+
+    Ok(udp_messages)
+}
+
+/// Sends `datagrams`, sealed first when a pre-shared key is set, returning the number of
+/// datagrams sent
+fn send_datagrams<'a>(
+    udp_messages: &mut udp::UdpMessages<udp::UdpSend>,
+    sealed: Option<&mut Sealed>,
+    datagrams: impl Iterator<Item = &'a [u8]>,
+) -> Result<usize, io::Error> {
+    match sealed {
+        None => udp_messages.send_mmsg(datagrams),
+        Some(sealed) => udp_messages.send_mmsg(sealed.seal(datagrams)),
+    }
+}
+
+/// Sends the configuration packet on every link, authenticated when a pre-shared key is set
+fn send_config(
+    udp_messages: &mut udp::UdpMessages<udp::UdpSend>,
+    stripe_messages: Option<&mut udp::UdpMessages<udp::UdpSend>>,
+    mut sealed: Option<&mut Sealed>,
+    config_packet: &[u8],
+) -> Result<(), io::Error> {
+    for messages in std::iter::once(udp_messages).chain(stripe_messages) {
+        match sealed.as_deref_mut() {
+            None => messages.send_mmsg(std::iter::once(config_packet))?,
+            Some(sealed) => {
+                messages.send_mmsg(std::iter::once(sealed.authenticate(config_packet)))?
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Datagrams sealed with the pre-shared key, their buffer being reused from one block to the
+/// next
+struct Sealed {
+    sealer: crypto::Sealer,
+    /// Size of the header of packets, left in clear
+    header_size: usize,
+    data: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl Sealed {
+    fn new(key: crypto::Key, header_size: usize) -> Self {
+        Self {
+            sealer: crypto::Sealer::new(&key),
+            header_size,
+            data: Vec::new(),
+            ends: Vec::new(),
+        }
+    }
+
+    /// Authenticates `datagram` without encrypting it, returning the sealed datagram
+    fn authenticate(&mut self, datagram: &[u8]) -> &[u8] {
+        self.data.clear();
+        self.ends.clear();
+        self.sealer.seal(datagram.len(), datagram, &mut self.data);
+        &self.data
+    }
+
+    /// Seals `datagrams`, returning the sealed datagrams
+    fn seal<'a>(
+        &mut self,
+        datagrams: impl Iterator<Item = &'a [u8]>,
+    ) -> impl Iterator<Item = &[u8]> {
+        self.data.clear();
+        self.ends.clear();
+        for datagram in datagrams {
+            self.sealer.seal(self.header_size, datagram, &mut self.data);
+            self.ends.push(self.data.len());
+        }
+        let data = &self.data;
+        self.ends.iter().scan(0, move |start, &end| {
+            let datagram = &data[*start..end];
+            *start = end;
+            Some(datagram)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bind_to;
+    use crate::{send, testing::TempDir};
+    use std::{env, fs, io, net, process};
+
+    fn bind_error(to_bind: &str, device: Option<&str>) -> send::Error {
+        match bind_to(to_bind.parse().expect("address"), device) {
+            Ok(socket) => panic!("{to_bind} bound to {:?}", socket.local_addr()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn bind_errors() {
+        let busy = net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let busy = busy.local_addr().expect("local address");
+        let e = bind_error(&busy.to_string(), None);
+        assert!(matches!(e, send::Error::Bind(addr, _) if addr == busy));
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "cannot bind UDP socket to {busy}: port {} is already in use",
+                busy.port()
+            )
+        );
+
+        // TEST-NET-1 address, assigned to no interface
+        let e = bind_error("192.0.2.1:0", None);
+        assert!(matches!(e, send::Error::Bind(..)));
+        assert_eq!(
+            e.to_string(),
+            "cannot bind UDP socket to 192.0.2.1:0: address is not assigned to a local interface"
+        );
+
+        // without CAP_NET_RAW, binding to a device is refused before the device is looked up
+        let e = bind_error("127.0.0.1:0", Some("nonexistent0"));
+        assert!(matches!(&e, send::Error::BindDevice(device, _) if device == "nonexistent0"));
+        let message = e.to_string();
+        assert!(
+            message == "cannot bind UDP socket to device nonexistent0: no such device"
+                || message
+                    == "cannot bind UDP socket to device nonexistent0: \
+                        the CAP_NET_RAW capability is required",
+            "{message}"
+        );
+
+        let e = send::Error::BindDevice("eth1".into(), io::Error::from_raw_os_error(libc::EPERM));
+        assert_eq!(
+            e.to_string(),
+            "cannot bind UDP socket to device eth1: the CAP_NET_RAW capability is required"
+        );
+    }
+
+    const CHILD_LOG: &str = "LIDI_TEST_BIND_LOG";
+
+    /// Binds sockets in a child process logging to a file, in which the actual bound address
+    /// must appear rather than the requested ephemeral port
+    #[test]
+    fn bound_address_logged() {
+        if let Some(log) = env::var_os(CHILD_LOG) {
+            crate::init_logger_with(crate::LogFormat::Text, Some(log.as_ref()), false)
+                .expect("logger");
+            let socket = bind_to("127.0.0.1:0".parse().expect("address"), None)
+                .unwrap_or_else(|e| panic!("{e}"));
+            println!("bound {}", socket.local_addr().expect("local address"));
+            if let Ok(socket) = bind_to("127.0.0.1:0".parse().expect("address"), Some("lo")) {
+                println!(
+                    "bound {} on lo",
+                    socket.local_addr().expect("local address")
+                );
+            }
+            return;
+        }
+
+        let dir = TempDir::new("bind");
+        let log = dir.path().join("log");
+        let output = process::Command::new(env::current_exe().expect("test executable"))
+            .args([
+                "--exact",
+                "send::udp::tests::bound_address_logged",
+                "--test-threads=1",
+                "--nocapture",
+            ])
+            .env(CHILD_LOG, &log)
+            .env("RUST_LOG", "info")
+            .output()
+            .expect("run child");
+        assert!(output.status.success());
+
+        let log = fs::read_to_string(log).expect("read log");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let bound: Vec<_> = stdout
+            .lines()
+            .filter_map(|line| line.strip_prefix("bound "))
+            .collect();
+        assert!(!bound.is_empty());
+        for bound in bound {
+            let (addr, device) = match bound.split_once(" on ") {
+                Some((addr, device)) => (addr, Some(device)),
+                None => (bound, None),
+            };
+            assert_ne!(addr, "127.0.0.1:0");
+            let banner = match device {
+                Some(device) => format!("UDP socket bound to {addr} on device {device}"),
+                None => format!("UDP socket bound to {addr}"),
+            };
+            assert!(
+                log.lines().any(|line| line.ends_with(&banner)),
+                "{banner} not in {log}"
+            );
+        }
     }
 }
@@ -0,0 +1,94 @@
+//! Worker that checks the sending pipeline makes progress and notifies the systemd watchdog
+//!
+//! A stage is considered stalled when items are waiting in its input queue but it did not consume
+//! any of them since the previous check. As soon as a stage is stalled, `WATCHDOG=1`
+//! notifications stop and the service manager eventually restarts the process. An idle pipeline,
+//! with empty queues, is healthy, and so is a paused one, whose queues fill up on purpose.
+
+use crate::{sd_notify, send};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread, time,
+};
+
+/// Progress counters of the pipeline stages, incremented by workers each time they consume an
+/// item from their input queue
+#[derive(Default)]
+pub(crate) struct Progress {
+    pub(crate) encoding: AtomicU64,
+    pub(crate) udp: AtomicU64,
+}
+
+impl Progress {
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of messages taken by the encoding workers, each of them being encoded in a block
+    pub(crate) fn encoded(&self) -> u64 {
+        self.encoding.load(Ordering::Relaxed)
+    }
+
+    fn snapshot(&self) -> [u64; 2] {
+        [
+            self.encoding.load(Ordering::Relaxed),
+            self.udp.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+const STAGES: [&str; 2] = ["encoding", "udp"];
+
+/// Returns the name of a stalled stage, if any
+fn stalled_stage<C>(
+    sender: &send::Sender<C>,
+    previous: &[u64; 2],
+    current: &[u64; 2],
+) -> Option<&'static str> {
+    if sender.is_paused() {
+        return None;
+    }
+
+    let pending = [sender.encoding_queue.len(), sender.for_send.len()];
+
+    (0..STAGES.len())
+        .find(|&i| 0 < pending[i] && current[i] == previous[i])
+        .map(|i| STAGES[i])
+}
+
+pub(crate) fn start<C>(
+    sender: &send::Sender<C>,
+    interval: time::Duration,
+) -> Result<(), send::Error> {
+    log::info!(
+        "notifying systemd watchdog every {} ms",
+        interval.as_millis()
+    );
+
+    let mut previous = sender.progress.snapshot();
+    let mut stalled = false;
+
+    loop {
+        thread::sleep(interval);
+
+        let current = sender.progress.snapshot();
+
+        match stalled_stage(sender, &previous, &current) {
+            Some(stage) => {
+                if !stalled {
+                    log::error!("pipeline stalled ({stage}), stopping watchdog notifications");
+                    stalled = true;
+                }
+            }
+            None => {
+                if stalled {
+                    log::warn!("pipeline progressing again, resuming watchdog notifications");
+                    stalled = false;
+                }
+                sd_notify::notify("WATCHDOG=1")?;
+            }
+        }
+
+        previous = current;
+    }
+}
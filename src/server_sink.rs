@@ -0,0 +1,352 @@
+//! Delivery of received sessions to consumers connecting to the receiver
+//!
+//! Instead of connecting to a destination, the receiver listens for TCP connections. Each
+//! session is given one end of a Unix socket pair, the other end being read by a relay thread
+//! which buffers the session data until a consumer is connected, then forwards it. A consumer
+//! receives a single session: its connection is closed once the session ended and all its data
+//! was delivered. Consumers may connect before or after the session started, and several
+//! consumers may be connected to receive concurrent sessions.
+//!
+//! While no consumer is connected, at most `buffer_size` bytes of a session are kept. When this
+//! buffer is full, the [Policy] decides whether the session is blocked (the reception pipeline
+//! then slows down as with a slow destination) or whether the oldest buffered data is dropped.
+
+use crate::{metrics, scrub};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    net,
+    os::unix,
+    str::FromStr,
+    thread, time,
+};
+
+/// How long the relay waits for session data before checking for a new consumer
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+const READ_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Stop reading the session until a consumer connects
+    Block,
+    /// Drop the oldest buffered data to keep reading the session
+    DropOldest,
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Block => write!(fmt, "block"),
+            Self::DropOldest => write!(fmt, "drop_oldest"),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop_oldest" => Ok(Self::DropOldest),
+            _ => Err(format!("unknown buffer policy \"{s}\"")),
+        }
+    }
+}
+
+pub struct ServerSink {
+    address: net::SocketAddr,
+    buffer_size: usize,
+    policy: Policy,
+    for_relays: crossbeam_channel::Receiver<net::TcpStream>,
+}
+
+impl fmt::Display for ServerSink {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", scrub::addr(&self.address))
+    }
+}
+
+impl ServerSink {
+    /// Listens on `address` and starts the thread accepting consumers
+    pub fn new(
+        address: net::SocketAddr,
+        buffer_size: usize,
+        policy: Policy,
+    ) -> Result<Self, io::Error> {
+        let listener = net::TcpListener::bind(address)?;
+        // the port actually bound when 0 was given
+        let address = listener.local_addr()?;
+        let (to_relays, for_relays) = crossbeam_channel::unbounded();
+
+        thread::Builder::new()
+            .name("serve_accept".to_string())
+            .spawn(move || {
+                for consumer in listener.incoming() {
+                    match consumer {
+                        Err(e) => log::warn!("failed to accept consumer: {e}"),
+                        Ok(consumer) => {
+                            if let Ok(peer) = consumer.peer_addr() {
+                                log::info!("consumer connected from {}", scrub::addr(&peer));
+                            }
+                            if to_relays.send(consumer).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            address,
+            buffer_size,
+            policy,
+            for_relays,
+        })
+    }
+
+    /// Returns the socket to which a new session is written
+    pub fn session(&self) -> Result<unix::net::UnixStream, io::Error> {
+        let (writer, reader) = unix::net::UnixStream::pair()?;
+        reader.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let relay = Relay {
+            session: reader,
+            consumers: self.for_relays.clone(),
+            buffer: VecDeque::new(),
+            buffer_size: self.buffer_size,
+            policy: self.policy,
+        };
+
+        thread::Builder::new()
+            .name("serve_relay".to_string())
+            .spawn(move || relay.start())?;
+
+        Ok(writer)
+    }
+}
+
+struct Relay {
+    session: unix::net::UnixStream,
+    consumers: crossbeam_channel::Receiver<net::TcpStream>,
+    buffer: VecDeque<u8>,
+    buffer_size: usize,
+    policy: Policy,
+}
+
+impl Relay {
+    fn start(mut self) {
+        let dropped = metrics::counter("rx_serve_dropped_bytes");
+        let mut consumer: Option<net::TcpStream> = None;
+        let mut served = false;
+        let mut ended = false;
+        let mut read_buffer = vec![0; READ_SIZE];
+
+        loop {
+            if consumer.is_none() {
+                let full = self.policy == Policy::Block && self.buffer_size <= self.buffer.len();
+                consumer = if ended || full {
+                    // nothing else to do than waiting for a consumer
+                    match self.consumers.recv() {
+                        Ok(consumer) => Some(consumer),
+                        Err(_) => return,
+                    }
+                } else {
+                    self.consumers.try_recv().ok()
+                };
+                served |= consumer.is_some();
+            }
+
+            if let Some(stream) = &mut consumer {
+                if !self.buffer.is_empty() {
+                    let (front, back) = self.buffer.as_slices();
+                    if let Err(e) = stream
+                        .write_all(front)
+                        .and_then(|()| stream.write_all(back))
+                    {
+                        log::warn!(
+                            "consumer disconnected, {} buffered bytes lost: {e}",
+                            self.buffer.len()
+                        );
+                        dropped.add(self.buffer.len() as u64);
+                        consumer = None;
+                    }
+                    self.buffer.clear();
+                }
+            }
+
+            if ended {
+                if served && self.buffer.is_empty() {
+                    // closing the consumer connection marks the end of the session
+                    return;
+                }
+                continue;
+            }
+
+            let len = match self.policy {
+                Policy::Block => READ_SIZE.min(self.buffer_size - self.buffer.len()),
+                Policy::DropOldest => READ_SIZE,
+            };
+            if len == 0 {
+                continue;
+            }
+
+            match self.session.read(&mut read_buffer[..len]) {
+                Ok(0) => ended = true,
+                Ok(nread) => {
+                    let data = &read_buffer[..nread];
+                    // with a consumer, the buffer is written out before the next read
+                    let overflow = if consumer.is_some() {
+                        0
+                    } else {
+                        (self.buffer.len() + nread).saturating_sub(self.buffer_size)
+                    };
+                    if 0 < overflow {
+                        // only happens with the drop oldest policy
+                        let from_buffer = overflow.min(self.buffer.len());
+                        self.buffer.drain(..from_buffer);
+                        dropped.add(overflow as u64);
+                        self.buffer.extend(&data[overflow - from_buffer..]);
+                    } else {
+                        self.buffer.extend(data);
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    log::warn!("failed to read session: {e}");
+                    ended = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, ServerSink};
+    use crate::metrics;
+    use std::{
+        io::{Read, Write},
+        net,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread, time,
+    };
+
+    const BUFFER_SIZE: usize = 10_000;
+    const TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+    fn sink(policy: Policy) -> ServerSink {
+        let address = net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 0));
+        ServerSink::new(address, BUFFER_SIZE, policy).expect("server sink")
+    }
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    fn consume(sink: &ServerSink) -> Vec<u8> {
+        let mut consumer = net::TcpStream::connect(sink.address).expect("connect consumer");
+        consumer
+            .set_read_timeout(Some(TIMEOUT))
+            .expect("read timeout");
+        let mut received = Vec::new();
+        consumer.read_to_end(&mut received).expect("read session");
+        received
+    }
+
+    #[test]
+    fn policies() {
+        for policy in [Policy::Block, Policy::DropOldest] {
+            assert!(policy.to_string().parse::<Policy>() == Ok(policy));
+        }
+        assert!("drop".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn late_consumer() {
+        for policy in [Policy::Block, Policy::DropOldest] {
+            let sink = sink(policy);
+            let data = data(BUFFER_SIZE, 1);
+            let mut session = sink.session().expect("session");
+            session.write_all(&data).expect("write session");
+            drop(session);
+            thread::sleep(time::Duration::from_millis(200));
+            assert_eq!(consume(&sink), data);
+        }
+    }
+
+    #[test]
+    fn full_buffer_blocks() {
+        let sink = sink(Policy::Block);
+        let data = data(4 << 20, 2);
+        let written = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut session = sink.session().expect("session");
+                for chunk in data.chunks(1024) {
+                    session.write_all(chunk).expect("write session");
+                    written.fetch_add(chunk.len(), Ordering::Relaxed);
+                }
+            });
+
+            // blocked once the buffer and the socket are full
+            thread::sleep(time::Duration::from_millis(500));
+            let blocked = written.load(Ordering::Relaxed);
+            thread::sleep(time::Duration::from_millis(200));
+            assert_eq!(written.load(Ordering::Relaxed), blocked);
+            assert!(blocked < data.len());
+
+            assert_eq!(consume(&sink), data);
+        });
+    }
+
+    #[test]
+    fn full_buffer_drops_oldest() {
+        let sink = sink(Policy::DropOldest);
+        let data = data(1 << 20, 3);
+        let dropped = metrics::counter("rx_serve_dropped_bytes");
+        let nb_dropped = dropped.get();
+
+        let mut session = sink.session().expect("session");
+        session.write_all(&data).expect("not blocked");
+        drop(session);
+
+        let received = consume(&sink);
+        assert!(BUFFER_SIZE <= received.len() && received.len() < data.len());
+        assert!(data.ends_with(&received));
+        assert_eq!(
+            dropped.get() - nb_dropped,
+            (data.len() - received.len()) as u64
+        );
+    }
+
+    #[test]
+    fn concurrent_sessions() {
+        let sink = sink(Policy::Block);
+        let sent = [data(50_000, 4), data(70_000, 5)];
+
+        // a consumer connected before the sessions start
+        let early = thread::scope(|scope| {
+            let early = scope.spawn(|| consume(&sink));
+            thread::sleep(time::Duration::from_millis(100));
+            for data in &sent {
+                let mut session = sink.session().expect("session");
+                scope.spawn(move || session.write_all(data).expect("write session"));
+            }
+            early.join().expect("early consumer")
+        });
+        let late = consume(&sink);
+
+        assert!(early != late);
+        for received in [early, late] {
+            assert!(sent.contains(&received));
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Minimal POSIX signals handling
+//!
+//! Installed handlers only record the received signal in an atomic bit set (which is
+//! async-signal-safe). Pending signals are then consumed from a regular thread with [take].
+
+use std::{
+    io, mem, ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn record(signum: libc::c_int) {
+    if (0..64).contains(&signum) {
+        PENDING.fetch_or(1 << signum, Ordering::SeqCst);
+    }
+}
+
+/// Installs the recording handler for each of the given signals
+///
+/// Handlers are installed with `SA_RESTART` so that blocking system calls of the workers are not
+/// interrupted.
+pub fn install(signals: &[libc::c_int]) -> Result<(), io::Error> {
+    for &signum in signals {
+        let res = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = record as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signum, &action, ptr::null_mut())
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `signum` was received since the last call, and clears it
+pub fn take(signum: libc::c_int) -> bool {
+    if !(0..64).contains(&signum) {
+        return false;
+    }
+    let mask = 1 << signum;
+    PENDING.fetch_and(!mask, Ordering::SeqCst) & mask != 0
+}
@@ -1,7 +1,9 @@
-//! Bindings and wrappers for socket buffer size libc functions
+//! Bindings and wrappers for socket buffer size, timeout, drops, peer address and multicast libc
+//! functions
 
-use std::os::fd::AsRawFd;
-use std::{io, mem, ptr};
+use crate::udp;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::{ffi, fs, io, mem, net, ptr, time};
 
 pub fn set_socket_send_buffer_size<S: AsRawFd>(socket: &S, size: i32) -> Result<(), io::Error> {
     unsafe { setsockopt_buffer_size(socket.as_raw_fd(), size, libc::SO_SNDBUF) }
@@ -50,3 +52,426 @@ unsafe fn getsockopt_buffer_size(fd: i32, option_name: i32) -> Result<i32, io::E
         Err(io::Error::new(io::ErrorKind::Other, "libc::getsockopt"))
     }
 }
+
+/// Sets the receive timeout of the socket, `None` meaning that read operations will block
+/// indefinitely
+pub fn set_socket_recv_timeout<S: AsRawFd>(
+    socket: &S,
+    timeout: Option<time::Duration>,
+) -> Result<(), io::Error> {
+    let timeval = timeout.map_or(
+        libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        |timeout| libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: libc::suseconds_t::from(timeout.subsec_micros()),
+        },
+    );
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            ptr::addr_of!(timeval).cast::<libc::c_void>(),
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Returns the IP address and port of the peer of the socket, or `None` if it is not an IP
+/// socket
+pub fn get_peer_addr<S: AsRawFd>(socket: &S) -> Option<net::SocketAddr> {
+    // the socket is only borrowed, it must not be closed when dropping the stream
+    let stream = mem::ManuallyDrop::new(unsafe { net::TcpStream::from_raw_fd(socket.as_raw_fd()) });
+    stream.peer_addr().ok()
+}
+
+/// Asks the kernel to attach to received datagrams the number of datagrams dropped so far because
+/// the socket receive queue was full (`SO_RXQ_OVFL`)
+pub fn set_socket_rxq_ovfl<S: AsRawFd>(socket: &S) -> Result<(), io::Error> {
+    let enable: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RXQ_OVFL,
+            ptr::addr_of!(enable).cast::<libc::c_void>(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Lets datagrams sent on the socket carry their transmit time in a `SCM_TXTIME` control
+/// message (`SO_TXTIME`), expressed on the `clockid` clock, for the qdisc or the network card to
+/// send them at this time
+pub fn set_socket_txtime<S: AsRawFd>(
+    socket: &S,
+    clockid: libc::clockid_t,
+) -> Result<(), io::Error> {
+    let txtime = libc::sock_txtime { clockid, flags: 0 };
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            ptr::addr_of!(txtime).cast::<libc::c_void>(),
+            mem::size_of::<libc::sock_txtime>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Checks that the kernel supports segmenting datagrams sent on the socket (`UDP_SEGMENT`)
+pub fn probe_socket_udp_gso<S: AsRawFd>(socket: &S) -> Result<(), io::Error> {
+    let mut segment_size: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            ptr::addr_of_mut!(segment_size).cast::<libc::c_void>(),
+            &mut len,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Asks the kernel to coalesce the datagrams received on the socket into larger ones, whose
+/// segment size is given in a `UDP_GRO` control message (`UDP_GRO`)
+pub fn set_socket_udp_gro<S: AsRawFd>(socket: &S) -> Result<(), io::Error> {
+    let enable: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            ptr::addr_of!(enable).cast::<libc::c_void>(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sets the number of microseconds the kernel busy polls the device queue when no datagram is
+/// available on a receive call (`SO_BUSY_POLL`), values above `net.core.busy_read` requiring the
+/// `CAP_NET_ADMIN` capability
+pub fn set_socket_busy_poll<S: AsRawFd>(socket: &S, usecs: u32) -> Result<(), io::Error> {
+    let usecs = libc::c_int::try_from(usecs).unwrap_or(libc::c_int::MAX);
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BUSY_POLL,
+            ptr::addr_of!(usecs).cast::<libc::c_void>(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Restricts the socket to the network interface named `device` (`SO_BINDTODEVICE`), which
+/// requires the `CAP_NET_RAW` capability
+pub fn set_socket_bind_device<S: AsRawFd>(socket: &S, device: &str) -> Result<(), io::Error> {
+    if libc::IFNAMSIZ <= device.len() || device.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid interface name",
+        ));
+    }
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr().cast::<libc::c_void>(),
+            device.len() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Binds a UDP socket to `addr` with `SO_REUSEADDR` if `reuse_addr` is set, so that several
+/// processes of the host can bind the same multicast group and port, and with `SO_REUSEPORT` if
+/// `reuse_port` is set, so that several sockets of the process share the datagrams sent to
+/// `addr`
+pub fn bind_udp_reuse(
+    addr: net::SocketAddr,
+    reuse_addr: bool,
+    reuse_port: bool,
+) -> Result<net::UdpSocket, io::Error> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the socket owns the descriptor, closing it on errors
+    let socket = unsafe { net::UdpSocket::from_raw_fd(fd) };
+
+    let enable: libc::c_int = 1;
+    if reuse_addr {
+        unsafe { setsockopt(&socket, libc::SOL_SOCKET, libc::SO_REUSEADDR, &enable)? };
+    }
+    if reuse_port {
+        unsafe { setsockopt(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, &enable)? };
+    }
+
+    let (sockaddr, len) = udp::sockaddr(addr);
+    let res = unsafe { libc::bind(fd, ptr::addr_of!(*sockaddr).cast::<libc::sockaddr>(), len) };
+    if res == 0 {
+        Ok(socket)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Return value source of the `BPF_RET` instruction, missing from libc
+const BPF_A: u32 = 0x10;
+
+/// Makes the kernel spread the datagrams received by the `SO_REUSEPORT` group of the socket
+/// randomly over its `nb_sockets` sockets, rather than by flow, a single flow being otherwise
+/// always received by the same socket
+pub fn set_socket_reuseport_random<S: AsRawFd>(
+    socket: &S,
+    nb_sockets: u32,
+) -> Result<(), io::Error> {
+    // the program returns the index in the group of the socket receiving the datagram
+    let mut program = [
+        libc::sock_filter {
+            code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+            jt: 0,
+            jf: 0,
+            k: (libc::SKF_AD_OFF + libc::SKF_AD_RANDOM) as u32,
+        },
+        libc::sock_filter {
+            code: (libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: nb_sockets,
+        },
+        libc::sock_filter {
+            code: (libc::BPF_RET | BPF_A) as u16,
+            jt: 0,
+            jf: 0,
+            k: 0,
+        },
+    ];
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    unsafe {
+        setsockopt(
+            socket,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            &fprog,
+        )
+    }
+}
+
+/// Returns the index of the network interface named `name`
+pub fn interface_index(name: &str) -> Result<u32, io::Error> {
+    let name = ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(index)
+    }
+}
+
+/// Sets the TTL, or hop limit, of the multicast datagrams sent by the socket to `group` and, if
+/// `interface` is not 0, the index of the network interface they are sent from
+pub fn set_socket_multicast_send<S: AsRawFd>(
+    socket: &S,
+    group: &net::IpAddr,
+    ttl: u8,
+    interface: u32,
+) -> Result<(), io::Error> {
+    let ttl = libc::c_int::from(ttl);
+    match group {
+        net::IpAddr::V4(_) => {
+            unsafe { setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_TTL, &ttl)? };
+            if interface != 0 {
+                let mreqn = libc::ip_mreqn {
+                    imr_multiaddr: libc::in_addr { s_addr: 0 },
+                    imr_address: libc::in_addr { s_addr: 0 },
+                    imr_ifindex: interface as libc::c_int,
+                };
+                unsafe { setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &mreqn)? };
+            }
+        }
+        net::IpAddr::V6(_) => {
+            unsafe {
+                setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS, &ttl)?;
+            }
+            if interface != 0 {
+                let interface = interface as libc::c_int;
+                unsafe {
+                    setsockopt(
+                        socket,
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_MULTICAST_IF,
+                        &interface,
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Joins the multicast `group` on the network interface of index `interface`, 0 letting the
+/// kernel choose it
+pub fn join_socket_multicast<S: AsRawFd>(
+    socket: &S,
+    group: &net::IpAddr,
+    interface: u32,
+) -> Result<(), io::Error> {
+    match group {
+        net::IpAddr::V4(group) => {
+            let mreqn = libc::ip_mreqn {
+                imr_multiaddr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(group.octets()),
+                },
+                imr_address: libc::in_addr { s_addr: 0 },
+                imr_ifindex: interface as libc::c_int,
+            };
+            unsafe { setsockopt(socket, libc::IPPROTO_IP, libc::IP_ADD_MEMBERSHIP, &mreqn) }
+        }
+        net::IpAddr::V6(group) => {
+            let mreq = libc::ipv6_mreq {
+                ipv6mr_multiaddr: libc::in6_addr {
+                    s6_addr: group.octets(),
+                },
+                ipv6mr_interface: interface,
+            };
+            unsafe { setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_ADD_MEMBERSHIP, &mreq) }
+        }
+    }
+}
+
+unsafe fn setsockopt<S: AsRawFd, T>(
+    socket: &S,
+    level: libc::c_int,
+    option_name: libc::c_int,
+    value: &T,
+) -> Result<(), io::Error> {
+    let res = libc::setsockopt(
+        socket.as_raw_fd(),
+        level,
+        option_name,
+        ptr::addr_of!(*value).cast::<libc::c_void>(),
+        mem::size_of::<T>() as libc::socklen_t,
+    );
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Returns the number of datagrams dropped by the kernel for the UDP socket, as reported in
+/// `/proc/net/udp` or `/proc/net/udp6`
+pub fn get_socket_drops<S: AsRawFd>(socket: &S) -> Result<u64, io::Error> {
+    let mut stat = unsafe { mem::zeroed::<libc::stat>() };
+    if unsafe { libc::fstat(socket.as_raw_fd(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for table in ["/proc/net/udp", "/proc/net/udp6"] {
+        if let Some(drops) = parse_proc_net_udp_drops(&fs::read_to_string(table)?, stat.st_ino) {
+            return Ok(drops);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "socket not found in /proc/net/udp",
+    ))
+}
+
+/// Returns the `drops` column of the line of a `/proc/net/udp` table describing the socket with
+/// the given inode
+fn parse_proc_net_udp_drops(table: &str, inode: u64) -> Option<u64> {
+    // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+    // ref pointer drops
+    table.lines().skip(1).find_map(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 13 || columns[9].parse() != Ok(inode) {
+            return None;
+        }
+        columns[12].parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_socket_drops, parse_proc_net_udp_drops};
+    use std::net;
+
+    const PROC_NET_UDP: &str = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  128: 0100007F:1388 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 40211 2 0000000000000000 0
+  305: 00000000:1B58 00000000:0000 07 00000000:00034000 00:00000000 00000000   998        0 52733 2 0000000000000000 1742
+ 1021: 0100007F:9C41 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 52734
+";
+
+    #[test]
+    fn proc_net_udp_drops() {
+        assert_eq!(parse_proc_net_udp_drops(PROC_NET_UDP, 40211), Some(0));
+        assert_eq!(parse_proc_net_udp_drops(PROC_NET_UDP, 52733), Some(1742));
+        // truncated line, unknown inode, header only
+        assert_eq!(parse_proc_net_udp_drops(PROC_NET_UDP, 52734), None);
+        assert_eq!(parse_proc_net_udp_drops(PROC_NET_UDP, 2), None);
+        assert_eq!(
+            parse_proc_net_udp_drops(PROC_NET_UDP.lines().next().expect("header"), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn socket_drops() {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        assert_eq!(get_socket_drops(&socket).expect("drops in /proc"), 0);
+
+        // not a UDP socket
+        let tcp = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        assert!(get_socket_drops(&tcp).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+//! Status of a running end of the diode as a single line JSON object, answered to the
+//! `status_json` command of the control sockets
+//!
+//! Each status object contains the `version` of the status format and the `end` of the diode it
+//! describes, `send` or `receive`, followed by the fields of that end, a `sessions` array with an
+//! object per session in progress, giving its labels and per-session metrics, and a `metrics`
+//! object with the value of every unlabeled metric of that end, error counters included.
+
+use crate::{events, metrics};
+use std::fmt::{self, Write as _};
+
+/// Version of the status format, to be incremented on incompatible changes
+pub const VERSION: u32 = 1;
+
+/// JSON object being written, fields being appended in order
+pub(crate) struct Object {
+    json: String,
+}
+
+impl Object {
+    /// Starts the status object of the `end` of the diode
+    pub(crate) fn new(end: &str) -> Self {
+        Self {
+            json: format!("{{\"version\":{VERSION},\"end\":\"{end}\""),
+        }
+    }
+
+    /// Appends a field whose value is already valid JSON, such as a number or a boolean
+    pub(crate) fn field(&mut self, name: &str, value: impl fmt::Display) -> &mut Self {
+        // writing into a String cannot fail
+        let _ = write!(self.json, ",\"{name}\":{value}");
+        self
+    }
+
+    pub(crate) fn string(&mut self, name: &str, value: &str) -> &mut Self {
+        self.field(name, events::json_string(value))
+    }
+
+    /// Appends the `sessions` array and the `metrics` object, from the metrics whose name starts
+    /// with `prefix` and `session_prefix`, the prefix being removed from the field names
+    pub(crate) fn finish(mut self, prefix: &str, session_prefix: &str) -> String {
+        let sessions = metrics::scopes()
+            .into_iter()
+            .filter(|(_, values)| {
+                values
+                    .iter()
+                    .any(|(name, _)| name.starts_with(session_prefix))
+            })
+            .map(|(labels, values)| {
+                let labels = labels
+                    .iter()
+                    .map(|(label, value)| format!("\"{label}\":{}", events::json_string(value)));
+                let values = values.iter().filter_map(|(name, value)| {
+                    name.strip_prefix(session_prefix)
+                        .map(|name| format!("\"{name}\":{value}"))
+                });
+                format!("{{{}}}", labels.chain(values).collect::<Vec<_>>().join(","))
+            })
+            .collect::<Vec<_>>();
+        let _ = write!(self.json, ",\"sessions\":[{}]", sessions.join(","));
+
+        let values = metrics::snapshot()
+            .into_iter()
+            .filter(|(name, _, _)| !name.contains('{'))
+            .filter_map(|(name, _, value)| {
+                name.strip_prefix(prefix)
+                    .map(|name| format!("\"{name}\":{value}"))
+            })
+            .collect::<Vec<_>>();
+        let _ = write!(self.json, ",\"metrics\":{{{}}}}}", values.join(","));
+
+        self.json
+    }
+}
@@ -0,0 +1,233 @@
+//! Helpers shared by the unit tests
+
+use crate::{impair, loopback, receive, send};
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net,
+    os::unix,
+    path, process,
+    sync::{
+        self,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread, time,
+};
+
+/// Directory created empty for a test and removed with its content when dropped
+pub(crate) struct TempDir(path::PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "lidi-test-{name}-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("create test directory");
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Configuration of a [Diode] with small blocks and short timeouts, over an unimpaired link
+pub(crate) fn loopback_config() -> loopback::Config {
+    loopback::Config {
+        from_tcp: net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 0)),
+        to_tcp: net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, 0)),
+        mtu: 1500,
+        nb_clients: 2,
+        encoding_block_size: 14_600,
+        repair_block_size: 2920,
+        udp_buffer_size: 4 * 1024 * 1024,
+        nb_encoding_threads: 1,
+        nb_decoding_threads: 1,
+        flush_timeout: time::Duration::from_millis(50),
+        low_latency: None,
+        heartbeat_interval: Some(time::Duration::from_secs(1)),
+        bandwidth_limit: 0.0,
+        impairment: impair::Config {
+            seed: Some(0),
+            loss: 0.0,
+            burst: None,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: time::Duration::ZERO,
+            delay: time::Duration::ZERO,
+            jitter: time::Duration::ZERO,
+        },
+    }
+}
+
+/// Value of the field `name` of the JSON object `line`, as written by the events and index
+/// streams, without escaped quotes in strings
+pub(crate) fn json_field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{name}\":"))? + name.len() + 3;
+    let value = &line[start..];
+    let end = if let Some(string) = value.strip_prefix('"') {
+        string.find('"')? + 2
+    } else {
+        value.find([',', '}'])?
+    };
+    Some(value[..end].trim_matches('"'))
+}
+
+/// Both ends of a diode run by [loopback::run_adjusted] in a background thread, left running
+/// until the end of the tests, with a destination accepting the sessions it delivers
+pub(crate) struct Diode {
+    from_tcp: net::SocketAddr,
+    destination: net::TcpListener,
+    pub(crate) sender: sync::Arc<send::Sender<net::TcpStream>>,
+}
+
+impl Diode {
+    /// Starts a diode configured by `config`, whose TCP addresses are ignored, the configurations
+    /// of its ends being adjusted by `adjust_send` and `adjust_receive`
+    pub(crate) fn start<S, R>(config: loopback::Config, adjust_send: S, adjust_receive: R) -> Self
+    where
+        S: FnOnce(&mut send::Config) + Send + 'static,
+        R: FnOnce(&mut receive::Config) + Send + 'static,
+    {
+        let from_tcp = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let destination = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        destination.set_nonblocking(true).expect("non blocking");
+        let from_tcp_addr = from_tcp.local_addr().expect("address");
+        let config = loopback::Config {
+            to_tcp: destination.local_addr().expect("address"),
+            ..config
+        };
+        let (to_started, started) = sync::mpsc::channel();
+        thread::spawn(move || {
+            let started = |sender| to_started.send(sender).expect("diode started");
+            if let Err(e) =
+                loopback::run_adjusted(config, from_tcp, adjust_send, adjust_receive, started)
+            {
+                panic!("diode failed: {e}");
+            }
+        });
+        Self {
+            from_tcp: from_tcp_addr,
+            destination,
+            sender: started.recv().expect("diode started"),
+        }
+    }
+
+    /// Connects a client to the sender, which starts a session
+    pub(crate) fn connect(&self) -> net::TcpStream {
+        net::TcpStream::connect(self.from_tcp).expect("connect to sender")
+    }
+
+    /// Sends `data` in a session of its own
+    pub(crate) fn send(&self, data: &[u8]) {
+        let mut client = self.connect();
+        client.write_all(data).expect("send data");
+    }
+
+    /// Returns the next session delivered to the destination, with reads timing out at
+    /// `deadline`, or `None` if none is delivered by then
+    pub(crate) fn accept(&self, deadline: time::Instant) -> Option<net::TcpStream> {
+        let session = loop {
+            match self.destination.accept() {
+                Ok((session, _)) => break session,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if deadline < time::Instant::now() {
+                        return None;
+                    }
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                Err(e) => panic!("accept session: {e}"),
+            }
+        };
+        session.set_nonblocking(false).expect("blocking");
+        session
+            .set_read_timeout(Some(
+                deadline
+                    .saturating_duration_since(time::Instant::now())
+                    .max(time::Duration::from_millis(1)),
+            ))
+            .expect("read timeout");
+        Some(session)
+    }
+
+    /// Returns the data of the next session delivered to the destination, or `None` if none is
+    /// delivered within `timeout`
+    pub(crate) fn receive(&self, timeout: time::Duration) -> Option<Vec<u8>> {
+        let mut session = self.accept(time::Instant::now() + timeout)?;
+        let mut data = Vec::new();
+        session.read_to_end(&mut data).ok()?;
+        Some(data)
+    }
+}
+
+/// Datagram socket standing for the service manager, named by `NOTIFY_SOCKET` until dropped
+///
+/// The environment is shared by the tests: a lock is held for the lifetime of the socket, which
+/// also serializes the tests setting the other variables of the systemd protocols.
+pub(crate) struct NotifySocket {
+    socket: unix::net::UnixDatagram,
+    _dir: TempDir,
+    _env: sync::MutexGuard<'static, ()>,
+}
+
+impl NotifySocket {
+    pub(crate) fn bind() -> Self {
+        let env = Self::lock_env();
+        let dir = TempDir::new("notify");
+        let path = dir.path().join("notify.sock");
+        let socket = unix::net::UnixDatagram::bind(&path).expect("bind notify socket");
+        env::set_var("NOTIFY_SOCKET", &path);
+        Self {
+            socket,
+            _dir: dir,
+            _env: env,
+        }
+    }
+
+    /// Locks the environment variables of the systemd protocols
+    pub(crate) fn lock_env() -> sync::MutexGuard<'static, ()> {
+        static ENV: sync::Mutex<()> = sync::Mutex::new(());
+        ENV.lock().unwrap_or_else(sync::PoisonError::into_inner)
+    }
+
+    /// Returns the next notification, or `None` if none is received within `timeout`
+    pub(crate) fn receive(&self, timeout: time::Duration) -> Option<String> {
+        self.socket
+            .set_read_timeout(Some(timeout.max(time::Duration::from_millis(1))))
+            .expect("read timeout");
+        let mut buffer = [0; 256];
+        let len = self.socket.recv(&mut buffer).ok()?;
+        Some(String::from_utf8_lossy(&buffer[..len]).into_owned())
+    }
+
+    /// Returns the number of `WATCHDOG=1` notifications received during `duration`
+    pub(crate) fn pings(&self, duration: time::Duration) -> usize {
+        let deadline = time::Instant::now() + duration;
+        let mut pings = 0;
+        loop {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                return pings;
+            }
+            if self.receive(remaining).as_deref() == Some("WATCHDOG=1") {
+                pings += 1;
+            }
+        }
+    }
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        env::remove_var("NOTIFY_SOCKET");
+    }
+}
@@ -1,9 +1,10 @@
 //! Functions and wrappers over libc's UDP socket multiple messages receive and send
 
+use crate::sock_utils;
 use std::marker::PhantomData;
 use std::os::fd::AsRawFd;
 use std::time::{Duration, Instant};
-use std::{io, mem, net, thread};
+use std::{io, mem, net, ptr, thread};
 
 pub struct UdpRecv;
 pub struct UdpSend;
@@ -16,12 +17,35 @@ pub struct UdpSend;
 pub struct UdpMessages<D> {
     socket: net::UdpSocket,
     vlen: usize,
-    _sockaddr: Option<Box<libc::sockaddr>>,
+    _sockaddrs: Vec<(Box<libc::sockaddr_storage>, libc::socklen_t)>,
+    /// Sender side: number of destinations each datagram is sent to, the messages sending a
+    /// datagram to each destination being consecutive in `msgvec` and sharing its iovec
+    nb_dests: usize,
     msgvec: Vec<libc::mmsghdr>,
     iovecs: Vec<libc::iovec>,
     buffers: Vec<Vec<u8>>,
+    /// Ancillary data buffers, `u64` ensuring the alignment of control message headers
+    controls: Vec<Vec<u64>>,
+    /// Receiver side: source addresses of the received datagrams
+    sources: Vec<libc::sockaddr_storage>,
+    /// Number of datagrams dropped by the kernel, as last reported by `SO_RXQ_OVFL`
+    kernel_drops: Option<u32>,
+    /// Number of datagrams received by the last receive call
+    nb_received: usize,
     marker: PhantomData<D>,
-    bandwidth_limit: f64,
+    /// Rate limiter of sent datagrams, if a bandwidth limit is set
+    pacer: Option<Pacer>,
+    /// Transmit times given to sent datagrams, replacing `pacer` once enabled
+    tx_timer: Option<TxTimer>,
+    /// Sender side: consecutive datagrams of the same length are sent as one message segmented
+    /// by the kernel (`UDP_SEGMENT`); receiver side: the kernel coalesces received datagrams
+    /// (`UDP_GRO`)
+    offload: bool,
+    /// Sender side: number of datagrams of each message of the last send call; receiver side:
+    /// segment size of each message of the last receive call, 0 if not coalesced
+    segments: Vec<usize>,
+    /// Largest datagram expected by receivers
+    msglen: usize,
 }
 
 impl<D> UdpMessages<D> {
@@ -29,13 +53,14 @@ impl<D> UdpMessages<D> {
         socket: net::UdpSocket,
         vlen: usize,
         msglen: Option<usize>,
-        addr: Option<net::SocketAddr>,
+        addrs: &[net::SocketAddr],
         bandwidth_limit: f64,
     ) -> Self {
         let (mut msgvec, mut iovecs, mut buffers);
+        let nb_dests = addrs.len().max(1);
 
         unsafe {
-            msgvec = vec![mem::zeroed::<libc::mmsghdr>(); vlen];
+            msgvec = vec![mem::zeroed::<libc::mmsghdr>(); vlen * nb_dests];
             iovecs = vec![mem::zeroed::<libc::iovec>(); vlen];
             if let Some(msglen) = msglen {
                 buffers = vec![vec![mem::zeroed::<u8>(); msglen]; vlen];
@@ -44,166 +69,759 @@ impl<D> UdpMessages<D> {
             }
         }
 
-        let mut sockaddr: Option<Box<libc::sockaddr>> = addr.map(|addr| match addr {
-            net::SocketAddr::V4(addr4) => {
-                let sockaddr_in = Box::new(libc::sockaddr_in {
-                    sin_family: libc::AF_INET as libc::sa_family_t,
-                    sin_addr: libc::in_addr {
-                        s_addr: u32::from_le_bytes(addr4.ip().octets()),
-                    },
-                    sin_port: addr4.port().to_be(),
-                    ..unsafe { mem::zeroed() }
-                });
-                unsafe {
-                    mem::transmute::<
-                        std::boxed::Box<libc::sockaddr_in>,
-                        std::boxed::Box<libc::sockaddr>,
-                    >(sockaddr_in)
-                }
-            }
-            net::SocketAddr::V6(addr6) => {
-                let sockaddr_in6 = Box::new(libc::sockaddr_in6 {
-                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
-                    sin6_port: addr6.port().to_be(),
-                    sin6_flowinfo: addr6.flowinfo(),
-                    sin6_addr: libc::in6_addr {
-                        s6_addr: addr6.ip().octets(),
-                    },
-                    sin6_scope_id: addr6.scope_id(),
-                });
-                unsafe {
-                    mem::transmute::<
-                        std::boxed::Box<libc::sockaddr_in6>,
-                        std::boxed::Box<libc::sockaddr>,
-                    >(sockaddr_in6)
-                }
-            }
-        });
+        let mut sockaddrs: Vec<_> = addrs.iter().copied().map(sockaddr).collect();
 
         for i in 0..vlen {
             if let Some(msglen) = msglen {
                 iovecs[i].iov_base = buffers[i].as_mut_ptr().cast::<libc::c_void>();
                 iovecs[i].iov_len = msglen;
             }
-            if let Some(sockaddr) = &mut sockaddr {
-                msgvec[i].msg_hdr.msg_name =
-                    (sockaddr.as_mut() as *mut libc::sockaddr).cast::<libc::c_void>();
-                msgvec[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_in>() as u32;
+            for d in 0..nb_dests {
+                let msghdr = &mut msgvec[i * nb_dests + d].msg_hdr;
+                if let Some((sockaddr, namelen)) = sockaddrs.get_mut(d) {
+                    msghdr.msg_name =
+                        (sockaddr.as_mut() as *mut libc::sockaddr_storage).cast::<libc::c_void>();
+                    msghdr.msg_namelen = *namelen;
+                }
+                msghdr.msg_iov = &mut iovecs[i];
+                msghdr.msg_iovlen = 1;
             }
-            msgvec[i].msg_hdr.msg_iov = &mut iovecs[i];
-            msgvec[i].msg_hdr.msg_iovlen = 1;
         }
 
         Self {
             socket,
             vlen,
-            _sockaddr: sockaddr,
+            _sockaddrs: sockaddrs,
+            nb_dests,
             msgvec,
             iovecs,
             buffers,
+            controls: Vec::new(),
+            sources: Vec::new(),
+            kernel_drops: None,
+            nb_received: 0,
             marker: PhantomData,
-            bandwidth_limit,
+            pacer: (bandwidth_limit > 0.0).then(|| Pacer::new(bandwidth_limit)),
+            tx_timer: None,
+            offload: false,
+            segments: vec![0; vlen * nb_dests],
+            msglen: msglen.unwrap_or(0),
         }
     }
 }
 
+/// Converts `addr` to the socket address structure of its family, returned with its length
+pub(crate) fn sockaddr(addr: net::SocketAddr) -> (Box<libc::sockaddr_storage>, libc::socklen_t) {
+    let mut storage = Box::new(unsafe { mem::zeroed::<libc::sockaddr_storage>() });
+    let storage_ptr = ptr::addr_of_mut!(*storage);
+    let len = match addr {
+        net::SocketAddr::V4(addr4) => {
+            let sockaddr_in = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr4.ip().octets()),
+                },
+                sin_port: addr4.port().to_be(),
+                ..unsafe { mem::zeroed() }
+            };
+            // sockaddr_storage is large and aligned enough for any socket address structure
+            unsafe { storage_ptr.cast::<libc::sockaddr_in>().write(sockaddr_in) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        net::SocketAddr::V6(addr6) => {
+            let sockaddr_in6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr6.port().to_be(),
+                sin6_flowinfo: addr6.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr6.ip().octets(),
+                },
+                sin6_scope_id: addr6.scope_id(),
+            };
+            unsafe { storage_ptr.cast::<libc::sockaddr_in6>().write(sockaddr_in6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Converts the socket address structure `storage` back to an address, the inverse of
+/// [sockaddr]
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<net::SocketAddr> {
+    let storage_ptr = ptr::addr_of!(*storage);
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sockaddr_in = unsafe { storage_ptr.cast::<libc::sockaddr_in>().read() };
+            Some(net::SocketAddr::from((
+                sockaddr_in.sin_addr.s_addr.to_ne_bytes(),
+                u16::from_be(sockaddr_in.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sockaddr_in6 = unsafe { storage_ptr.cast::<libc::sockaddr_in6>().read() };
+            Some(net::SocketAddr::V6(net::SocketAddrV6::new(
+                sockaddr_in6.sin6_addr.s6_addr.into(),
+                u16::from_be(sockaddr_in6.sin6_port),
+                sockaddr_in6.sin6_flowinfo,
+                sockaddr_in6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
 impl UdpMessages<UdpRecv> {
     pub fn new_receiver(socket: net::UdpSocket, vlen: usize, msglen: usize) -> Self {
         log::info!("UDP configured to receive {vlen} messages (datagrams)");
-        Self::new(socket, vlen, Some(msglen), None, 0.0)
+        let mut messages = Self::new(socket, vlen, Some(msglen), &[], 0.0);
+        // room for SO_RXQ_OVFL and UDP_GRO control messages
+        let control_len = 2 * unsafe { libc::CMSG_SPACE(mem::size_of::<u32>() as u32) } as usize;
+        messages.controls = vec![vec![0; control_len.div_ceil(mem::size_of::<u64>())]; vlen];
+        for (msghdr, control) in messages.msgvec.iter_mut().zip(&mut messages.controls) {
+            msghdr.msg_hdr.msg_control = control.as_mut_ptr().cast::<libc::c_void>();
+        }
+        messages.sources = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; vlen];
+        for (msghdr, source) in messages.msgvec.iter_mut().zip(&mut messages.sources) {
+            msghdr.msg_hdr.msg_name =
+                (source as *mut libc::sockaddr_storage).cast::<libc::c_void>();
+        }
+        messages
+    }
+
+    /// Number of datagrams dropped by the kernel because the socket receive queue was full, if
+    /// reported since `SO_RXQ_OVFL` was enabled on the socket
+    ///
+    /// The kernel counter is 32 bits wide and wraps around.
+    pub const fn kernel_drops(&self) -> Option<u32> {
+        self.kernel_drops
+    }
+
+    /// Lets the kernel coalesce received datagrams (`UDP_GRO`), [Self::received] splitting them
+    /// back, so that fewer receive calls are needed
+    ///
+    /// If the kernel does not support it, an error is returned and datagrams keep being received
+    /// one by one.
+    pub fn enable_gro(&mut self) -> Result<(), io::Error> {
+        sock_utils::set_socket_udp_gro(&self.socket)?;
+        for (buffer, iovec) in self.buffers.iter_mut().zip(&mut self.iovecs) {
+            *buffer = vec![0; usize::from(u16::MAX)];
+            iovec.iov_base = buffer.as_mut_ptr().cast::<libc::c_void>();
+            iovec.iov_len = buffer.len();
+        }
+        self.offload = true;
+        Ok(())
+    }
+
+    /// Receives datagrams, waiting for at least one, and returns the number of datagrams received,
+    /// available through [Self::received]
+    pub fn recv_mmsg(&mut self) -> Result<usize, io::Error> {
+        self.recv_mmsg_flags(libc::MSG_WAITFORONE)
+    }
+
+    /// Same as [Self::recv_mmsg] without waiting, 0 being returned if no datagram is available
+    pub fn try_recv_mmsg(&mut self) -> Result<usize, io::Error> {
+        match self.recv_mmsg_flags(libc::MSG_DONTWAIT) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
     }
 
-    pub fn recv_mmsg(&mut self) -> Result<impl Iterator<Item = &[u8]>, io::Error> {
+    fn recv_mmsg_flags(&mut self, flags: libc::c_int) -> Result<usize, io::Error> {
+        // the kernel sets msg_controllen and msg_namelen to the length of the received ancillary
+        // data and source address
+        for (msghdr, control) in self.msgvec.iter_mut().zip(&self.controls) {
+            msghdr.msg_hdr.msg_controllen = control.len() * mem::size_of::<u64>();
+            msghdr.msg_hdr.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        }
+
+        // with MSG_TRUNC, msg_len is the real length of the datagram even if truncated
         let nb_msg = unsafe {
             libc::recvmmsg(
                 self.socket.as_raw_fd(),
                 self.msgvec.as_mut_ptr(),
                 self.vlen as u32,
-                libc::MSG_WAITFORONE,
+                flags | libc::MSG_TRUNC,
                 std::ptr::null_mut(),
             )
         };
 
         if nb_msg == -1 {
-            Err(io::Error::new(io::ErrorKind::Other, "libc::recvmmsg"))
-        } else {
-            Ok(self
-                .buffers
+            self.nb_received = 0;
+            return Err(io::Error::last_os_error());
+        }
+        self.nb_received = nb_msg as usize;
+
+        if !self.controls.is_empty() {
+            for (msghdr, segment_size) in self
+                .msgvec
                 .iter()
-                .take(nb_msg as usize)
-                .zip(self.msgvec.iter())
-                .map(|(buffer, msghdr)| &buffer[..msghdr.msg_len as usize]))
+                .zip(&mut self.segments)
+                .take(self.nb_received)
+            {
+                if let Some(drops) =
+                    control_u32(&msghdr.msg_hdr, libc::SOL_SOCKET, libc::SO_RXQ_OVFL)
+                {
+                    self.kernel_drops = Some(drops);
+                }
+                if self.offload {
+                    *segment_size = control_u32(&msghdr.msg_hdr, libc::SOL_UDP, libc::UDP_GRO)
+                        .map_or(0, |size| size as usize);
+                }
+            }
         }
+
+        Ok(self.nb_received)
+    }
+
+    /// Datagrams received by the last call to [Self::recv_mmsg] or [Self::try_recv_mmsg], a
+    /// datagram larger than the expected length being returned as `Err` with its real length
+    ///
+    /// Datagrams coalesced by the kernel are split back into their segments. Each datagram comes
+    /// with its source address, if of a known family.
+    pub fn received(
+        &self,
+    ) -> impl Iterator<Item = (Option<net::SocketAddr>, Result<&[u8], usize>)> {
+        self.buffers
+            .iter()
+            .take(self.nb_received)
+            .zip(self.msgvec.iter())
+            .zip(self.segments.iter())
+            .zip(self.sources.iter())
+            .flat_map(|(((buffer, msghdr), &segment_size), source)| {
+                let source = socket_addr(source);
+                let len = msghdr.msg_len as usize;
+                let (segments, datagram) =
+                    if msghdr.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 || buffer.len() < len {
+                        (None, Some(Err(len)))
+                    } else if segment_size == 0 || len <= segment_size {
+                        if self.msglen < len {
+                            (None, Some(Err(len)))
+                        } else {
+                            (None, Some(Ok(&buffer[..len])))
+                        }
+                    } else if self.msglen < segment_size {
+                        (None, Some(Err(segment_size)))
+                    } else {
+                        (Some(buffer[..len].chunks(segment_size)), None)
+                    };
+                segments
+                    .into_iter()
+                    .flatten()
+                    .map(Ok)
+                    .chain(datagram)
+                    .map(move |datagram| (source, datagram))
+            })
     }
 }
 
+/// Returns the 32 bits value of the first control message of `level` and `kind` found in the
+/// ancillary data of a received message
+fn control_u32(msg_hdr: &libc::msghdr, level: libc::c_int, kind: libc::c_int) -> Option<u32> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg_hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == level && (*cmsg).cmsg_type == kind {
+                return Some(std::ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg).cast::<u32>(),
+                ));
+            }
+            cmsg = libc::CMSG_NXTHDR(msg_hdr, cmsg);
+        }
+    }
+    None
+}
+
+/// Largest UDP payload of a datagram segmented by the kernel
+const OFFLOAD_MAX_BYTES: usize = 65507;
+/// Largest number of segments of a datagram segmented by the kernel (`UDP_MAX_SEGMENTS`)
+const OFFLOAD_MAX_SEGMENTS: usize = 64;
+
 impl UdpMessages<UdpSend> {
+    /// Prepares to send up to `vlen` datagrams at a time, each of them to every destination of
+    /// `dests`, the payload of a datagram being shared by the messages sending it and not copied
+    ///
+    /// The bandwidth limit applies to the datagrams sent, whatever the number of destinations.
     pub fn new_sender(
         socket: net::UdpSocket,
         vlen: usize,
-        dest: net::SocketAddr,
+        dests: &[net::SocketAddr],
         bandwidth_limit: f64,
     ) -> UdpMessages<UdpSend> {
         log::info!("UDP configured to send {vlen} messages (datagrams) at a time");
-        Self::new(socket, vlen, None, Some(dest), bandwidth_limit)
+        Self::new(socket, vlen, None, dests, bandwidth_limit)
     }
 
-    pub fn send_mmsg(&mut self, mut buffers: Vec<Vec<u8>>) -> Result<(), io::Error> {
-        for bufchunk in buffers.chunks_mut(self.vlen) {
-            if self.bandwidth_limit > 0.0 {
-                for (i, buf) in bufchunk.iter_mut().enumerate() {
-                    self.msgvec[i].msg_len = buf.len() as u32;
-                    self.iovecs[i].iov_base = buf.as_mut_ptr().cast::<libc::c_void>();
-                    self.iovecs[i].iov_len = buf.len();
+    /// Offloads pacing to the qdisc or the network card, each datagram carrying the time it must
+    /// be sent at (`SO_TXTIME`) on the `CLOCK_TAI` clock, as expected by the `etf` qdisc
+    ///
+    /// Without a bandwidth limit, or if the kernel does not support `SO_TXTIME`, an error is
+    /// returned and datagrams keep being paced in software.
+    pub fn enable_txtime(&mut self) -> Result<(), io::Error> {
+        let Some(pacer) = &self.pacer else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "transmit times require a bandwidth limit",
+            ));
+        };
+        sock_utils::set_socket_txtime(&self.socket, libc::CLOCK_TAI)?;
 
-                    let start_time = Instant::now();
-                    let nb_msg;
-                    unsafe {
-                        nb_msg = libc::sendmmsg(self.socket.as_raw_fd(), &mut self.msgvec[i], 1, 0);
-                    }
+        let control_len = unsafe { libc::CMSG_SPACE(mem::size_of::<u64>() as u32) } as usize;
+        self.controls =
+            vec![vec![0; control_len.div_ceil(mem::size_of::<u64>())]; self.msgvec.len()];
+        for (msghdr, control) in self.msgvec.iter_mut().zip(&mut self.controls) {
+            msghdr.msg_hdr.msg_control = control.as_mut_ptr().cast::<libc::c_void>();
+            msghdr.msg_hdr.msg_controllen = control_len;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msghdr.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_TXTIME;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u64>() as u32) as usize;
+            }
+        }
 
-                    if nb_msg == -1 {
-                        return Err(io::Error::new(io::ErrorKind::Other, "libc::sendmmsg"));
-                    }
+        self.tx_timer = Some(TxTimer::new(pacer.rate));
+        self.pacer = None;
+        Ok(())
+    }
 
-                    let send_duration = start_time.elapsed().as_secs_f64();
-                    let bytes_sent = buf.len() as f64;
-                    let ideal_time_per_byte = 1.0 / self.bandwidth_limit;
-                    let ideal_send_duration = bytes_sent * ideal_time_per_byte;
-                    let sleep_duration = if ideal_send_duration > send_duration {
-                        Duration::from_secs_f64(ideal_send_duration - send_duration)
-                    } else {
-                        Duration::from_secs(0)
-                    };
+    /// Changes the bandwidth limit, in bytes per second, of the datagrams sent from now on, 0
+    /// removing it
+    ///
+    /// Once transmit times are enabled, the limit cannot be removed and an error is returned.
+    pub fn set_bandwidth_limit(&mut self, bandwidth_limit: f64) -> Result<(), io::Error> {
+        if let Some(tx_timer) = &mut self.tx_timer {
+            if bandwidth_limit <= 0.0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "transmit times require a bandwidth limit",
+                ));
+            }
+            tx_timer.rate = bandwidth_limit;
+        } else if bandwidth_limit <= 0.0 {
+            self.pacer = None;
+        } else if let Some(pacer) = &mut self.pacer {
+            pacer.set_rate(bandwidth_limit);
+        } else {
+            self.pacer = Some(Pacer::new(bandwidth_limit));
+        }
+        Ok(())
+    }
 
-                    thread::sleep(sleep_duration);
-                }
-            } else {
-                let to_send = bufchunk.len();
+    /// Lets the kernel segment messages made of consecutive datagrams of the same length
+    /// (`UDP_SEGMENT`), so that fewer messages go through the network stack
+    ///
+    /// If the kernel does not support it, or if transmit times are enabled, which are given per
+    /// message, an error is returned and datagrams keep being sent one by one.
+    pub fn enable_gso(&mut self) -> Result<(), io::Error> {
+        if self.tx_timer.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "segmentation cannot be combined with transmit times",
+            ));
+        }
+        sock_utils::probe_socket_udp_gso(&self.socket)?;
 
-                for (i, buf) in bufchunk.iter_mut().enumerate() {
-                    self.msgvec[i].msg_len = buf.len() as u32;
-                    self.iovecs[i].iov_base = buf.as_mut_ptr().cast::<libc::c_void>();
-                    self.iovecs[i].iov_len = buf.len();
-                }
+        let control_len = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as usize;
+        self.controls =
+            vec![vec![0; control_len.div_ceil(mem::size_of::<u64>())]; self.msgvec.len()];
+        for (msghdr, control) in self.msgvec.iter_mut().zip(&mut self.controls) {
+            msghdr.msg_hdr.msg_control = control.as_mut_ptr().cast::<libc::c_void>();
+            msghdr.msg_hdr.msg_controllen = control_len;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msghdr.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as usize;
+            }
+        }
+        self.offload = true;
+        Ok(())
+    }
 
-                let nb_msg;
-                unsafe {
-                    nb_msg = libc::sendmmsg(
-                        self.socket.as_raw_fd(),
-                        self.msgvec.as_mut_ptr(),
-                        to_send as u32,
-                        0,
-                    );
-                }
-                if nb_msg == -1 {
-                    return Err(io::Error::new(io::ErrorKind::Other, "libc::sendmmsg"));
+    /// Sends every buffer of `buffers` as a datagram, without copying them, returning the number
+    /// of datagrams the kernel accepted
+    pub fn send_mmsg<'a>(
+        &mut self,
+        buffers: impl Iterator<Item = &'a [u8]>,
+    ) -> Result<usize, io::Error> {
+        let mut to_send = 0;
+        let mut nb_sent = 0;
+
+        for buf in buffers {
+            self.msgvec[to_send].msg_len = buf.len() as u32;
+            // sendmmsg only reads the buffers, mut is only required by the iovec structure
+            self.iovecs[to_send].iov_base = buf.as_ptr().cast_mut().cast::<libc::c_void>();
+            self.iovecs[to_send].iov_len = buf.len();
+            to_send += 1;
+
+            if to_send == self.vlen {
+                nb_sent += self.send_prepared(to_send)?;
+                to_send = 0;
+            }
+        }
+
+        if 0 < to_send {
+            nb_sent += self.send_prepared(to_send)?;
+        }
+
+        Ok(nb_sent)
+    }
+
+    /// Sends the `to_send` first prepared datagrams to every destination, returning the number
+    /// of datagrams sent to all of them
+    fn send_prepared(&mut self, to_send: usize) -> Result<usize, io::Error> {
+        if let Some(pacer) = &mut self.pacer {
+            let bytes = self.iovecs[..to_send]
+                .iter()
+                .map(|iovec| iovec.iov_len)
+                .sum();
+            pacer.consume(bytes);
+        }
+        if let Some(tx_timer) = &mut self.tx_timer {
+            let now = clock_ns(libc::CLOCK_TAI);
+            let copies = self.msgvec.chunks(self.nb_dests);
+            for (msghdrs, iovec) in copies.zip(&self.iovecs).take(to_send) {
+                // every destination is sent the datagram at the same time
+                let txtime = tx_timer.schedule(now, iovec.iov_len);
+                for msghdr in msghdrs {
+                    unsafe {
+                        let cmsg = libc::CMSG_FIRSTHDR(&msghdr.msg_hdr);
+                        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u64>(), txtime);
+                    }
                 }
-                if nb_msg as usize != to_send {
-                    log::warn!("nb prepared messages doesn't match with nb sent messages");
+            }
+            tx_timer.throttle(now);
+        }
+        if self.offload {
+            return self.send_segmented(to_send);
+        }
+
+        let nb_msg;
+        unsafe {
+            nb_msg = libc::sendmmsg(
+                self.socket.as_raw_fd(),
+                self.msgvec.as_mut_ptr(),
+                (to_send * self.nb_dests) as u32,
+                0,
+            );
+        }
+        if nb_msg == -1 {
+            return Err(io::Error::new(io::ErrorKind::Other, "libc::sendmmsg"));
+        }
+        if nb_msg as usize != to_send * self.nb_dests {
+            log::warn!("nb prepared messages doesn't match with nb sent messages");
+        }
+        Ok(nb_msg as usize / self.nb_dests)
+    }
+
+    /// Sends the `to_send` first prepared datagrams, consecutive datagrams of the same length
+    /// being grouped in messages segmented by the kernel, each group being sent to every
+    /// destination, returning the number of datagrams sent to all of them
+    ///
+    /// If the kernel refuses segmented messages, for instance because the network interface
+    /// does not offload checksums, segmentation is disabled and datagrams are sent one by one.
+    fn send_segmented(&mut self, to_send: usize) -> Result<usize, io::Error> {
+        let mut nb_msgs = 0;
+        let mut first = 0;
+        while first < to_send {
+            let segment_size = self.iovecs[first].iov_len;
+            // the kernel does not segment empty datagrams
+            let max_segments = OFFLOAD_MAX_BYTES
+                .checked_div(segment_size)
+                .map_or(1, |max_segments| max_segments.min(OFFLOAD_MAX_SEGMENTS));
+            let nb_segments = self.iovecs[first..to_send]
+                .iter()
+                .take(max_segments)
+                .take_while(|iovec| iovec.iov_len == segment_size)
+                .count();
+
+            // messages keep the destination they were given, the group being sent by the next
+            // message to each destination
+            for _ in 0..self.nb_dests {
+                let msg_hdr = &mut self.msgvec[nb_msgs].msg_hdr;
+                msg_hdr.msg_iov = &mut self.iovecs[first];
+                msg_hdr.msg_iovlen = nb_segments;
+                if 1 < nb_segments {
+                    msg_hdr.msg_controllen = self.controls[nb_msgs].len() * mem::size_of::<u64>();
+                    unsafe {
+                        let cmsg = libc::CMSG_FIRSTHDR(msg_hdr);
+                        ptr::write_unaligned(
+                            libc::CMSG_DATA(cmsg).cast::<u16>(),
+                            segment_size as u16,
+                        );
+                    }
+                } else {
+                    msg_hdr.msg_controllen = 0;
                 }
+                self.segments[nb_msgs] = nb_segments;
+                nb_msgs += 1;
             }
+            first += nb_segments;
         }
-        Ok(())
+
+        let nb_msg = unsafe {
+            libc::sendmmsg(
+                self.socket.as_raw_fd(),
+                self.msgvec.as_mut_ptr(),
+                nb_msgs as u32,
+                0,
+            )
+        };
+        if nb_msg == -1 {
+            let e = io::Error::last_os_error();
+            if !matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL)) {
+                return Err(io::Error::new(io::ErrorKind::Other, "libc::sendmmsg"));
+            }
+            log::warn!("kernel refused segmented datagrams ({e}), sending them one by one");
+            self.offload = false;
+            for (i, msghdr) in self.msgvec.iter_mut().enumerate() {
+                msghdr.msg_hdr.msg_iov = &mut self.iovecs[i / self.nb_dests];
+                msghdr.msg_hdr.msg_iovlen = 1;
+                msghdr.msg_hdr.msg_controllen = 0;
+            }
+            return self.send_prepared(to_send);
+        }
+
+        let nb_sent = self.segments[..nb_msg as usize].iter().sum::<usize>() / self.nb_dests;
+        if nb_sent != to_send {
+            log::warn!("nb prepared messages doesn't match with nb sent messages");
+        }
+        Ok(nb_sent)
+    }
+}
+
+/// Delay between the scheduling of a datagram and the earliest transmit time it can be given,
+/// leaving time for it to reach the qdisc, which drops datagrams whose time has passed
+const TXTIME_LEAD: Duration = Duration::from_micros(500);
+/// Longest time datagrams may be scheduled ahead of the current time, the sender sleeping
+/// beyond so that the qdisc queue does not grow without bound
+const TXTIME_HORIZON: Duration = Duration::from_millis(10);
+
+/// Reads the `clockid` clock, in nanoseconds
+fn clock_ns(clockid: libc::clockid_t) -> u64 {
+    let mut ts = unsafe { mem::zeroed::<libc::timespec>() };
+    unsafe { libc::clock_gettime(clockid, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Gives datagrams consecutive transmit times, spaced according to the rate limit
+struct TxTimer {
+    /// Bytes per second
+    rate: f64,
+    /// Transmit time of the next datagram, in nanoseconds on `CLOCK_TAI`
+    next: u64,
+}
+
+impl TxTimer {
+    const fn new(rate: f64) -> Self {
+        Self { rate, next: 0 }
+    }
+
+    /// Returns the transmit time of a datagram of `bytes` bytes scheduled at `now`
+    fn schedule(&mut self, now: u64, bytes: usize) -> u64 {
+        // idle periods are not made up for by sending faster afterwards
+        self.next = self.next.max(now + TXTIME_LEAD.as_nanos() as u64);
+        let txtime = self.next;
+        self.next += (bytes as f64 * 1e9 / self.rate).round() as u64;
+        txtime
+    }
+
+    /// Sleeps while datagrams are scheduled beyond [TXTIME_HORIZON] from `now`
+    fn throttle(&self, now: u64) {
+        let ahead = Duration::from_nanos(self.next.saturating_sub(now));
+        if TXTIME_HORIZON < ahead {
+            thread::sleep(ahead - TXTIME_HORIZON);
+        }
+    }
+}
+
+/// Longest burst sent at full speed after an idle period
+const PACING_BURST: Duration = Duration::from_millis(2);
+/// Shortest sleep of the pacer, shorter debts being carried over to the next batches since
+/// sleeping is not accurate enough below
+const PACING_MIN_SLEEP: Duration = Duration::from_millis(1);
+
+/// Token bucket limiting the rate of sent bytes, whole `sendmmsg` batches being sent at once
+///
+/// Tokens are bytes, refilled at the rate limit up to [PACING_BURST] worth of bytes. Each batch
+/// consumes its size, the bucket going into debt when it holds less, and the debt is slept off
+/// once it amounts to [PACING_MIN_SLEEP]. Oversleeping is credited back on the next refill, so
+/// the average rate stays accurate whatever the batch size and the sleep precision.
+struct Pacer {
+    /// Bytes per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    fn new(rate: f64) -> Self {
+        let capacity = rate * PACING_BURST.as_secs_f64();
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Changes the rate limit, the tokens beyond the new capacity being dropped
+    fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.capacity = rate * PACING_BURST.as_secs_f64();
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    /// Takes `bytes` tokens, sleeping first if the debt of the bucket is large enough
+    fn consume(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        let debt = Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate);
+        if PACING_MIN_SLEEP <= debt {
+            thread::sleep(debt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{control_u32, UdpMessages};
+    use crate::sock_utils;
+    use std::{mem, net, ptr, time};
+
+    #[test]
+    fn truncated_datagrams() {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        socket
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .expect("read timeout");
+        let addr = socket.local_addr().expect("address");
+        let mut messages = UdpMessages::new_receiver(socket, 4, 1472);
+
+        let sender = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        for len in [1000, 8972, 1472, 1473] {
+            sender.send_to(&vec![len as u8; len], addr).expect("send");
+        }
+
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            messages.recv_mmsg().expect("receive");
+            received.extend(
+                messages
+                    .received()
+                    .map(|(source, datagram)| (source, datagram.map(<[u8]>::to_vec))),
+            );
+        }
+
+        let source = sender.local_addr().expect("address");
+        assert!(received.iter().all(|(from, _)| *from == Some(source)));
+        let datagrams = received
+            .into_iter()
+            .map(|(_, datagram)| datagram)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            datagrams,
+            [
+                Ok(vec![232; 1000]),
+                Err(8972),
+                Ok(vec![192; 1472]),
+                Err(1473)
+            ]
+        );
+    }
+
+    #[test]
+    fn control_messages() {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<u32>() as u32) } as usize;
+        let mut control = vec![0u64; (2 * space).div_ceil(mem::size_of::<u64>())];
+        let mut msg_hdr = unsafe { mem::zeroed::<libc::msghdr>() };
+        msg_hdr.msg_control = control.as_mut_ptr().cast::<libc::c_void>();
+        msg_hdr.msg_controllen = 2 * space;
+
+        // no control message received
+        let empty = libc::msghdr {
+            msg_controllen: 0,
+            ..msg_hdr
+        };
+        assert_eq!(
+            control_u32(&empty, libc::SOL_SOCKET, libc::SO_RXQ_OVFL),
+            None
+        );
+
+        unsafe {
+            let gro = libc::CMSG_FIRSTHDR(&msg_hdr);
+            (*gro).cmsg_level = libc::SOL_UDP;
+            (*gro).cmsg_type = libc::UDP_GRO;
+            (*gro).cmsg_len = libc::CMSG_LEN(mem::size_of::<u32>() as u32) as usize;
+            ptr::write_unaligned(libc::CMSG_DATA(gro).cast::<u32>(), 1464);
+            let rxq_ovfl = libc::CMSG_NXTHDR(&msg_hdr, gro);
+            assert!(!rxq_ovfl.is_null());
+            (*rxq_ovfl).cmsg_level = libc::SOL_SOCKET;
+            (*rxq_ovfl).cmsg_type = libc::SO_RXQ_OVFL;
+            (*rxq_ovfl).cmsg_len = libc::CMSG_LEN(mem::size_of::<u32>() as u32) as usize;
+            ptr::write_unaligned(libc::CMSG_DATA(rxq_ovfl).cast::<u32>(), u32::MAX);
+        }
+
+        assert_eq!(
+            control_u32(&msg_hdr, libc::SOL_UDP, libc::UDP_GRO),
+            Some(1464)
+        );
+        assert_eq!(
+            control_u32(&msg_hdr, libc::SOL_SOCKET, libc::SO_RXQ_OVFL),
+            Some(u32::MAX)
+        );
+        // same type at another level
+        assert_eq!(
+            control_u32(&msg_hdr, libc::SOL_UDP, libc::SO_RXQ_OVFL),
+            None
+        );
+
+        // only the first control message fits in the length received
+        msg_hdr.msg_controllen = space;
+        assert_eq!(
+            control_u32(&msg_hdr, libc::SOL_UDP, libc::UDP_GRO),
+            Some(1464)
+        );
+        assert_eq!(
+            control_u32(&msg_hdr, libc::SOL_SOCKET, libc::SO_RXQ_OVFL),
+            None
+        );
+    }
+
+    #[test]
+    fn kernel_drops() {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        socket
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .expect("read timeout");
+        sock_utils::set_socket_rxq_ovfl(&socket).expect("SO_RXQ_OVFL");
+        sock_utils::set_socket_recv_buffer_size(&socket, 4096).expect("receive buffer size");
+        let addr = socket.local_addr().expect("address");
+        let proc_socket = socket.try_clone().expect("clone");
+        let mut messages = UdpMessages::new_receiver(socket, 16, 1472);
+
+        // overflows the receive queue, nothing being read
+        let sender = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        for _ in 0..100 {
+            sender.send_to(&[0; 1000], addr).expect("send");
+        }
+        let proc_drops = sock_utils::get_socket_drops(&proc_socket).expect("drops in /proc");
+        assert!(0 < proc_drops && proc_drops < 100, "{proc_drops} drops");
+
+        // the counter of drops comes with the datagrams queued after the drops
+        let nb_received = messages.recv_mmsg().expect("receive");
+        assert!(nb_received as u64 <= 100 - proc_drops);
+        assert_eq!(messages.kernel_drops(), None);
+        sender.send_to(&[0; 1000], addr).expect("send");
+        while messages.kernel_drops().is_none() {
+            messages.recv_mmsg().expect("receive");
+        }
+        assert_eq!(messages.kernel_drops(), Some(proc_drops as u32));
     }
 }